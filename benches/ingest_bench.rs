@@ -1,7 +1,8 @@
 use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
 use duckdb::Connection;
 use mallard_metrics::ingest::buffer::{Event, EventBuffer};
-use mallard_metrics::storage::parquet::ParquetStorage;
+use mallard_metrics::query::QueryConn;
+use mallard_metrics::storage::parquet::{ParquetCompression, ParquetStorage, ParquetWriteOptions};
 use mallard_metrics::storage::schema;
 use parking_lot::Mutex;
 use std::sync::Arc;
@@ -40,6 +41,11 @@ fn make_event(i: usize) -> Event {
         props: None,
         revenue_amount: None,
         revenue_currency: None,
+        dimensions: [const { None }; mallard_metrics::config::MAX_CUSTOM_DIMENSIONS],
+        prev_pathname: None,
+        user_id: None,
+        device_brand: None,
+        device_model: None,
     }
 }
 
@@ -120,6 +126,105 @@ fn bench_flush(c: &mut Criterion) {
     group.finish();
 }
 
+/// Benchmark flushing 10 000 events under different Parquet write options.
+///
+/// Measured like `parquet_flush`, so the numbers are comparable; the size of
+/// each variant's file is printed once so CPU cost can be weighed against
+/// storage.
+fn bench_write_options(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parquet_write_options");
+    let size = 10_000;
+    let variants = [
+        ("zstd", ParquetWriteOptions::default()),
+        (
+            "zstd_level_9",
+            ParquetWriteOptions {
+                compression_level: 9,
+                ..ParquetWriteOptions::default()
+            },
+        ),
+        (
+            "snappy",
+            ParquetWriteOptions {
+                compression: ParquetCompression::Snappy,
+                ..ParquetWriteOptions::default()
+            },
+        ),
+        (
+            "uncompressed",
+            ParquetWriteOptions {
+                compression: ParquetCompression::Uncompressed,
+                ..ParquetWriteOptions::default()
+            },
+        ),
+        (
+            "small_row_groups",
+            ParquetWriteOptions {
+                row_group_size: 2_048,
+                ..ParquetWriteOptions::default()
+            },
+        ),
+        (
+            "no_dictionary",
+            ParquetWriteOptions {
+                dictionary: false,
+                ..ParquetWriteOptions::default()
+            },
+        ),
+    ];
+
+    for (name, options) in variants {
+        let dir = tempfile::TempDir::new().unwrap();
+        let dir_path = dir.path().to_path_buf();
+        let make_buffer = || {
+            let conn = Connection::open_in_memory().unwrap();
+            schema::init_schema(&conn).unwrap();
+            let storage = ParquetStorage::new(&dir_path).with_write_options(options);
+            let buffer = EventBuffer::new(size + 1, Arc::new(Mutex::new(conn)), storage);
+            for i in 0..size {
+                buffer.push(make_event(i)).unwrap();
+            }
+            buffer
+        };
+
+        make_buffer().flush().unwrap();
+        let bytes: u64 = walk_files(&dir_path)
+            .iter()
+            .map(std::fs::Metadata::len)
+            .sum();
+        println!("parquet_write_options/{name}: {bytes} bytes for {size} events");
+
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                make_buffer,
+                |buffer| {
+                    buffer.flush().unwrap();
+                },
+                BatchSize::SmallInput,
+            );
+        });
+    }
+
+    group.finish();
+}
+
+/// Metadata of every file under `dir`.
+fn walk_files(dir: &std::path::Path) -> Vec<std::fs::Metadata> {
+    std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .flat_map(|entry| {
+            let path = entry.path();
+            if path.is_dir() {
+                walk_files(&path)
+            } else {
+                entry.metadata().into_iter().collect()
+            }
+        })
+        .collect()
+}
+
 fn bench_query_metrics(c: &mut Criterion) {
     let mut group = c.benchmark_group("query_metrics");
 
@@ -142,11 +247,13 @@ fn bench_query_metrics(c: &mut Criterion) {
     group.bench_function("core_metrics_10k", |b| {
         b.iter(|| {
             let conn = arc_conn.lock();
+            let conn = QueryConn::new(&conn);
             mallard_metrics::query::metrics::query_core_metrics(
                 &conn,
                 "bench.example.com",
                 "2024-01-01",
                 "2024-02-01",
+                mallard_metrics::query::sessions::SessionSql::Primary,
             )
             .unwrap();
         });
@@ -155,6 +262,7 @@ fn bench_query_metrics(c: &mut Criterion) {
     group.bench_function("timeseries_10k", |b| {
         b.iter(|| {
             let conn = arc_conn.lock();
+            let conn = QueryConn::new(&conn);
             mallard_metrics::query::timeseries::query_timeseries(
                 &conn,
                 "bench.example.com",
@@ -169,12 +277,14 @@ fn bench_query_metrics(c: &mut Criterion) {
     group.bench_function("breakdown_pages_10k", |b| {
         b.iter(|| {
             let conn = arc_conn.lock();
+            let conn = QueryConn::new(&conn);
             mallard_metrics::query::breakdowns::query_breakdown(
                 &conn,
                 "bench.example.com",
                 "2024-01-01",
                 "2024-02-01",
                 mallard_metrics::query::breakdowns::Dimension::Page,
+                None,
                 10,
             )
             .unwrap();
@@ -184,5 +294,11 @@ fn bench_query_metrics(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, bench_buffer_push, bench_flush, bench_query_metrics);
+criterion_group!(
+    benches,
+    bench_buffer_push,
+    bench_flush,
+    bench_write_options,
+    bench_query_metrics
+);
 criterion_main!(benches);
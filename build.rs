@@ -0,0 +1,41 @@
+//! Extract the Swagger UI served at `/api/docs/` from the vendored release
+//! archive into `OUT_DIR/swagger-ui`, where `api::openapi` includes it.
+
+use std::io::Read;
+use std::path::Path;
+
+/// Files of the release's `dist` folder that the page loads.
+const FILES: &[&str] = &[
+    "index.html",
+    "index.css",
+    "swagger-ui.css",
+    "swagger-ui-bundle.js",
+    "swagger-ui-standalone-preset.js",
+    "favicon-16x16.png",
+    "favicon-32x32.png",
+];
+
+fn main() {
+    println!("cargo::rerun-if-changed=build.rs");
+    let out_dir = std::env::var("OUT_DIR").expect("cargo sets OUT_DIR");
+    let target = Path::new(&out_dir).join("swagger-ui");
+    std::fs::create_dir_all(&target).expect("create swagger-ui output directory");
+
+    let archive = std::io::Cursor::new(utoipa_swagger_ui_vendored::SWAGGER_UI_VENDORED);
+    let mut archive = zip::ZipArchive::new(archive).expect("read vendored Swagger UI archive");
+    for name in FILES {
+        // Entries are named `swagger-ui-<version>/dist/<file>`.
+        let suffix = format!("/dist/{name}");
+        let entry = archive
+            .file_names()
+            .find(|entry| entry.ends_with(&suffix))
+            .unwrap_or_else(|| panic!("{name} missing from the Swagger UI archive"))
+            .to_string();
+        let mut bytes = Vec::new();
+        archive
+            .by_name(&entry)
+            .and_then(|mut file| Ok(file.read_to_end(&mut bytes)?))
+            .unwrap_or_else(|e| panic!("extract {entry}: {e}"));
+        std::fs::write(target.join(name), bytes).expect("write Swagger UI file");
+    }
+}
@@ -0,0 +1,134 @@
+use crate::api::errors::ApiError;
+use crate::api::extract::Query;
+use crate::api::stats::validate_site_id;
+use crate::ingest::handler::AppState;
+use crate::ingest::stats::SiteIngestStats;
+use crate::query::slow::SlowQuery;
+use crate::storage::maintenance::{verify_partitions, VerifyReport};
+use crate::storage::parquet::{ParquetStorage, PartitionFile};
+use crate::storage::schema::EVENT_SCHEMA_VERSION;
+use axum::extract::State;
+use axum::Json;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+#[derive(Debug, Deserialize)]
+pub struct PartitionsParams {
+    pub site_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PartitionsResponse {
+    /// Schema version of files written by this server.
+    pub schema_version: u32,
+    pub base_dir: String,
+    pub files: Vec<PartitionFile>,
+}
+
+/// List the Parquet files backing `events_all`, so external tools can read
+/// the data lake directly.
+pub async fn get_partitions(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<PartitionsParams>,
+) -> Result<Json<PartitionsResponse>, ApiError> {
+    if let Some(site_id) = &params.site_id {
+        validate_site_id(site_id)?;
+    }
+
+    let events_dir = state.events_dir.clone();
+    let files = tokio::task::spawn_blocking(move || {
+        let storage = ParquetStorage::new(&state.events_dir);
+        let conn = state.query_conn();
+        storage.list_files(&conn, params.site_id.as_deref())
+    })
+    .await??;
+
+    Ok(Json(PartitionsResponse {
+        schema_version: EVENT_SCHEMA_VERSION,
+        base_dir: events_dir.to_string_lossy().into_owned(),
+        files,
+    }))
+}
+
+/// GET /api/admin/verify — Check the Parquet files for corruption, schema
+/// drift, misplaced or duplicated rows and timestamp outliers.
+pub async fn verify_data(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<VerifyReport>, ApiError> {
+    let report = tokio::task::spawn_blocking(move || {
+        let conn = state.query_conn();
+        verify_partitions(&conn, &state.events_dir)
+    })
+    .await?
+    .map_err(|e| ApiError::Internal(format!("Verification failed: {e}")))?;
+    if !report.problems.is_empty() {
+        tracing::warn!(
+            problems = report.problems.len(),
+            "Data verification found problems"
+        );
+    }
+    Ok(Json(report))
+}
+
+#[derive(Debug, Serialize)]
+pub struct SlowQueriesResponse {
+    /// Queries taking at least this long are kept; 0 when disabled.
+    pub threshold_ms: u64,
+    /// Most recent first.
+    pub queries: Vec<SlowQuery>,
+}
+
+/// GET /api/admin/slow-queries — The most recent analytics queries that took
+/// at least `logging.slow_query_ms`, with their SQL shape and site.
+pub async fn get_slow_queries(State(state): State<Arc<AppState>>) -> Json<SlowQueriesResponse> {
+    Json(SlowQueriesResponse {
+        threshold_ms: state.slow_queries.threshold_ms(),
+        queries: state.slow_queries.recent(),
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub struct IngestStatsResponse {
+    /// When counting started: the server's start time.
+    pub since: DateTime<Utc>,
+    /// Busiest first, by requests received.
+    pub sites: Vec<SiteIngestStats>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DrainResponse {
+    pub status: &'static str,
+    /// Buffered events written to Parquet.
+    pub flushed: usize,
+}
+
+/// POST /api/admin/drain — Hand over to a new binary: fail readiness, close
+/// keep-alive connections, flush buffered events and shut down gracefully.
+pub async fn drain(State(state): State<Arc<AppState>>) -> Result<Json<DrainResponse>, ApiError> {
+    if state.drain.start() {
+        tracing::warn!("Drain requested through the API; shutting down");
+    }
+    let flushed = tokio::task::spawn_blocking(move || {
+        if let Some(queue) = &state.ingest_queue {
+            queue.wait_idle();
+        }
+        state.buffer.flush()
+    })
+    .await?
+    .map_err(|e| ApiError::Internal(format!("Flush failed: {e}")))?;
+    Ok(Json(DrainResponse {
+        status: "draining",
+        flushed,
+    }))
+}
+
+/// GET /api/admin/ingest-stats — Per-site ingestion counters since startup:
+/// events accepted, rejected by validation, rate-limited and bot-filtered,
+/// and request bytes.
+pub async fn get_ingest_stats(State(state): State<Arc<AppState>>) -> Json<IngestStatsResponse> {
+    Json(IngestStatsResponse {
+        since: state.ingest_stats.started_at(),
+        sites: state.ingest_stats.snapshot(),
+    })
+}
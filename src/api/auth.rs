@@ -8,8 +8,10 @@ use axum::Json;
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
+use utoipa::ToSchema;
 
 /// Per-IP login attempt tracker for brute-force protection.
 ///
@@ -135,7 +137,7 @@ impl LoginAttemptTracker {
 }
 
 /// Anonymize an IP address for logging (replaces the last octet/segment).
-fn anonymize_ip(ip: &str) -> String {
+pub fn anonymize_ip(ip: &str) -> String {
     if ip.contains(':') {
         // IPv6 — keep only first 4 groups
         let groups: Vec<&str> = ip.split(':').collect();
@@ -202,6 +204,28 @@ pub fn verify_password(password: &str, hash: &str) -> bool {
         .is_ok()
 }
 
+/// Read the admin password hash persisted at `path`.
+///
+/// Returns `None` when the file is missing or does not hold a valid hash.
+pub fn load_password_hash(path: &Path) -> Option<String> {
+    let hash = std::fs::read_to_string(path).ok()?.trim().to_string();
+    if PasswordHash::new(&hash).is_err() {
+        tracing::warn!(path = %path.display(), "Ignoring invalid persisted admin password hash");
+        return None;
+    }
+    Some(hash)
+}
+
+/// Persist the admin password hash to `path`, readable only by its owner.
+pub fn save_password_hash(path: &Path, hash: &str) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    std::io::Write::write_all(&mut file, hash.as_bytes())
+}
+
 /// Generate a cryptographically random session token (256 bits).
 pub fn generate_session_token() -> String {
     use rand::Rng;
@@ -239,12 +263,16 @@ pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
 }
 
 /// API key scope defining access level.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize, utoipa::ToSchema,
+)]
 pub enum ApiKeyScope {
     /// Read-only access to stats queries.
     ReadOnly,
     /// Full admin access (user management, config).
     Admin,
+    /// Event submission to `POST /api/event` only, for server-side senders.
+    WriteEvents,
 }
 
 /// Stored API key metadata.
@@ -255,12 +283,54 @@ pub struct StoredApiKey {
     pub scope: ApiKeyScope,
     pub created_at: chrono::NaiveDateTime,
     pub revoked: bool,
+    /// Site group the key is limited to.  Only read-only keys can be limited.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Site a write key may submit events for.  Unlimited when `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub site_id: Option<String>,
+    /// Tenant whose sites a read-only or write key is limited to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// The key is rejected from this time (UTC) on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<chrono::NaiveDateTime>,
+    /// Last successful use, to within [`LAST_USED_RESOLUTION_SECS`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<chrono::NaiveDateTime>,
+}
+
+/// What a new key is limited to, for [`ApiKeyStore::add_key`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ApiKeyLimits<'a> {
+    /// Site group of a read-only key.
+    pub group: Option<&'a str>,
+    /// Site of a write key.
+    pub site_id: Option<&'a str>,
+    /// Tenant of a read-only or write key.
+    pub tenant: Option<&'a str>,
+}
+
+/// How stale `last_used_at` may get before a use updates it.  Each update is
+/// written to disk, so busy keys must not update it on every request.
+pub const LAST_USED_RESOLUTION_SECS: i64 = 60;
+
+impl StoredApiKey {
+    /// Whether the key has passed its expiry time.
+    pub fn is_expired(&self, now: chrono::NaiveDateTime) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
+
+    /// Whether the key may be used: neither revoked nor expired.
+    fn is_active(&self, now: chrono::NaiveDateTime) -> bool {
+        !self.revoked && !self.is_expired(now)
+    }
 }
 
 /// Thread-safe session store for dashboard authentication.
 #[derive(Clone)]
 pub struct SessionStore {
-    /// Maps session token → (username, expiry).
+    /// Maps session token → session details.
     sessions: Arc<Mutex<HashMap<String, SessionEntry>>>,
     ttl: Duration,
 }
@@ -268,6 +338,32 @@ pub struct SessionStore {
 struct SessionEntry {
     username: String,
     expires_at: Instant,
+    created_at: chrono::DateTime<chrono::Utc>,
+    last_seen: chrono::DateTime<chrono::Utc>,
+    ip_prefix: String,
+    user_agent: Option<String>,
+}
+
+/// Longest user agent kept per session.
+const MAX_SESSION_USER_AGENT_LEN: usize = 256;
+
+/// An active session as listed by `GET /api/auth/sessions`.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SessionInfo {
+    /// Public identifier; unlike the token, it cannot be used to log in.
+    pub id: String,
+    pub created_at: String,
+    pub last_seen: String,
+    pub ip_prefix: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    /// The session making the request.
+    pub current: bool,
+}
+
+/// The public identifier of the session with `token`.
+fn session_id(token: &str) -> String {
+    hash_api_key(token)[..16].to_string()
 }
 
 impl SessionStore {
@@ -283,12 +379,18 @@ impl SessionStore {
         self.ttl.as_secs()
     }
 
-    /// Create a new session for a user. Returns the session token.
-    pub fn create_session(&self, username: &str) -> String {
+    /// Create a new session for a user logging in from `ip`. Returns the
+    /// session token.
+    pub fn create_session(&self, username: &str, ip: &str, user_agent: Option<&str>) -> String {
         let token = generate_session_token();
+        let now = chrono::Utc::now();
         let entry = SessionEntry {
             username: username.to_string(),
             expires_at: Instant::now() + self.ttl,
+            created_at: now,
+            last_seen: now,
+            ip_prefix: anonymize_ip(ip),
+            user_agent: user_agent.map(|ua| ua.chars().take(MAX_SESSION_USER_AGENT_LEN).collect()),
         };
         self.sessions.lock().insert(token.clone(), entry);
         token
@@ -297,8 +399,9 @@ impl SessionStore {
     /// Validate a session token. Returns the username if valid and not expired.
     pub fn validate_session(&self, token: &str) -> Option<String> {
         let mut sessions = self.sessions.lock();
-        if let Some(entry) = sessions.get(token) {
+        if let Some(entry) = sessions.get_mut(token) {
             if entry.expires_at > Instant::now() {
+                entry.last_seen = chrono::Utc::now();
                 return Some(entry.username.clone());
             }
             // Expired — remove it
@@ -312,6 +415,49 @@ impl SessionStore {
         self.sessions.lock().remove(token);
     }
 
+    /// Active sessions, most recently seen first.  `current_token` marks the
+    /// caller's own session.
+    pub fn list_sessions(&self, current_token: Option<&str>) -> Vec<SessionInfo> {
+        let now = Instant::now();
+        let sessions = self.sessions.lock();
+        let mut entries: Vec<_> = sessions
+            .iter()
+            .filter(|(_, entry)| entry.expires_at > now)
+            .collect();
+        entries.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.last_seen));
+        let infos = entries
+            .into_iter()
+            .map(|(token, entry)| SessionInfo {
+                id: session_id(token),
+                created_at: entry.created_at.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+                last_seen: entry.last_seen.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+                ip_prefix: entry.ip_prefix.clone(),
+                user_agent: entry.user_agent.clone(),
+                current: Some(token.as_str()) == current_token,
+            })
+            .collect();
+        drop(sessions);
+        infos
+    }
+
+    /// Remove the session with public identifier `id`. Returns `false` if
+    /// there is none.
+    pub fn revoke_session(&self, id: &str) -> bool {
+        let mut sessions = self.sessions.lock();
+        let before = sessions.len();
+        sessions.retain(|token, _| session_id(token) != id);
+        sessions.len() < before
+    }
+
+    /// Remove every session except `current_token`'s. Returns how many were
+    /// removed.
+    pub fn revoke_other_sessions(&self, current_token: &str) -> usize {
+        let mut sessions = self.sessions.lock();
+        let before = sessions.len();
+        sessions.retain(|token, _| token == current_token);
+        before - sessions.len()
+    }
+
     /// Remove all expired sessions (housekeeping).
     pub fn cleanup_expired(&self) {
         let now = Instant::now();
@@ -398,8 +544,21 @@ impl ApiKeyStore {
         }
     }
 
-    /// Store a new API key (hashed). Returns the key hash for identification.
-    pub fn add_key(&self, name: &str, plaintext_key: &str, scope: ApiKeyScope) -> String {
+    /// Store a new API key (hashed) with its `limits`, valid until
+    /// `expires_at` if given.  Returns the key hash for identification.
+    ///
+    /// The key is written to disk once, with all of its limits, so no key is
+    /// ever stored broader than it was created.  The caller checks that the
+    /// limits suit `scope`: a group only for read-only keys, a site only for
+    /// write keys and a tenant for anything but admin keys.
+    pub fn add_key(
+        &self,
+        name: &str,
+        plaintext_key: &str,
+        scope: ApiKeyScope,
+        limits: ApiKeyLimits<'_>,
+        expires_at: Option<chrono::NaiveDateTime>,
+    ) -> String {
         let key_hash = hash_api_key(plaintext_key);
         let stored = StoredApiKey {
             key_hash: key_hash.clone(),
@@ -407,21 +566,80 @@ impl ApiKeyStore {
             scope,
             created_at: chrono::Utc::now().naive_utc(),
             revoked: false,
+            group: limits.group.map(str::to_string),
+            site_id: limits.site_id.map(str::to_string),
+            tenant: limits.tenant.map(str::to_string),
+            expires_at,
+            last_used_at: None,
         };
         self.keys.lock().push(stored);
         self.persist();
         key_hash
     }
 
-    /// Validate an API key. Returns the scope if valid and not revoked.
+    /// Validate an API key. Returns the scope if valid, not revoked and not
+    /// expired, and records the use in `last_used_at`.
     ///
     /// Uses constant-time comparison of hash digests to prevent timing attacks.
     pub fn validate_key(&self, plaintext_key: &str) -> Option<ApiKeyScope> {
         let key_hash = hash_api_key(plaintext_key);
+        let now = chrono::Utc::now().naive_utc();
+        let (scope, touched) = {
+            let mut keys = self.keys.lock();
+            let key = keys.iter_mut().find(|k| {
+                constant_time_eq(k.key_hash.as_bytes(), key_hash.as_bytes()) && k.is_active(now)
+            })?;
+            let touched = key.last_used_at.is_none_or(|last_used| {
+                (now - last_used).num_seconds() >= LAST_USED_RESOLUTION_SECS
+            });
+            if touched {
+                key.last_used_at = Some(now);
+            }
+            let scope = key.scope;
+            drop(keys);
+            (scope, touched)
+        };
+        if touched {
+            self.persist();
+        }
+        Some(scope)
+    }
+
+    /// The site group a valid key is limited to, if any.
+    pub fn key_group(&self, plaintext_key: &str) -> Option<String> {
+        let key_hash = hash_api_key(plaintext_key);
+        let now = chrono::Utc::now().naive_utc();
         let keys = self.keys.lock();
         keys.iter()
-            .find(|k| constant_time_eq(k.key_hash.as_bytes(), key_hash.as_bytes()) && !k.revoked)
-            .map(|k| k.scope)
+            .find(|k| {
+                constant_time_eq(k.key_hash.as_bytes(), key_hash.as_bytes()) && k.is_active(now)
+            })
+            .and_then(|k| k.group.clone())
+    }
+
+    /// The tenant a valid key is limited to, if any.
+    pub fn key_tenant(&self, plaintext_key: &str) -> Option<String> {
+        let key_hash = hash_api_key(plaintext_key);
+        let now = chrono::Utc::now().naive_utc();
+        let keys = self.keys.lock();
+        keys.iter()
+            .find(|k| {
+                constant_time_eq(k.key_hash.as_bytes(), key_hash.as_bytes()) && k.is_active(now)
+            })
+            .and_then(|k| k.tenant.clone())
+    }
+
+    /// Whether `plaintext_key` is a valid write key that may submit events
+    /// for `site_id`.
+    pub fn may_write_events(&self, plaintext_key: &str, site_id: &str) -> bool {
+        if self.validate_key(plaintext_key) != Some(ApiKeyScope::WriteEvents) {
+            return false;
+        }
+        let key_hash = hash_api_key(plaintext_key);
+        let keys = self.keys.lock();
+        keys.iter()
+            .find(|k| constant_time_eq(k.key_hash.as_bytes(), key_hash.as_bytes()))
+            .is_some_and(|k| k.site_id.as_deref().is_none_or(|s| s == site_id))
     }
 
     /// Revoke an API key by hash.
@@ -458,72 +676,106 @@ impl ApiKeyStore {
 // --- HTTP Handler Types ---
 
 /// Request body for login and setup endpoints.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct PasswordRequest {
     pub password: String,
 }
 
 /// Response from the auth status endpoint.
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct AuthStatusResponse {
     pub setup_required: bool,
     pub authenticated: bool,
 }
 
 /// Response from login/setup containing session info.
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 struct LoginResponse {
     token: String,
 }
 
+/// Response from logout and API key revocation.
+#[derive(Debug, Serialize, ToSchema)]
+struct StatusResponse {
+    /// `logged_out` or `revoked`.
+    status: &'static str,
+}
+
+/// Response from session revocation.
+#[derive(Debug, Serialize, ToSchema)]
+struct RevokedResponse {
+    status: &'static str,
+    /// Sessions revoked.
+    revoked: usize,
+}
+
 // --- HTTP Handlers ---
 
+use crate::api::errors::ApiError;
+use crate::api::openapi;
 use crate::ingest::handler::AppState;
 
 /// POST /api/auth/setup — Set the initial admin password.
 ///
 /// Only works when no admin password has been configured yet.
 /// After setup, all stats/dashboard routes require authentication.
+#[utoipa::path(
+    post,
+    path = "/api/auth/setup",
+    tag = openapi::AUTH,
+    summary = "Set the initial admin password",
+    request_body = PasswordRequest,
+    responses(
+        (
+            status = 200,
+            description = "Logged in, with the session cookie set",
+            body = LoginResponse,
+        ),
+    ),
+    security(()),
+)]
 pub async fn auth_setup(
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
     Json(body): Json<PasswordRequest>,
 ) -> impl IntoResponse {
     if body.password.len() < 8 {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(serde_json::json!({"error": "Password must be at least 8 characters"})),
-        )
+        return ApiError::invalid_field("password", "Password must be at least 8 characters")
             .into_response();
     }
 
     let mut hash_guard = state.admin_password_hash.lock();
     if hash_guard.is_some() {
-        return (
-            StatusCode::CONFLICT,
-            Json(serde_json::json!({"error": "Admin password already configured"})),
-        )
-            .into_response();
+        return ApiError::Conflict("Admin password already configured".to_string()).into_response();
     }
 
     let hash = match hash_password(&body.password) {
         Ok(h) => h,
         Err(e) => {
             tracing::error!(error = %e, "Failed to hash password during setup");
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(serde_json::json!({"error": "Internal error"})),
-            )
-                .into_response();
+            return ApiError::Internal("Internal error".to_string()).into_response();
         }
     };
 
+    if let Some(path) = &state.admin_password_path {
+        if let Err(e) = save_password_hash(path, &hash) {
+            tracing::warn!(
+                path = %path.display(),
+                error = %e,
+                "Failed to persist admin password; setup will be required again after restart"
+            );
+        }
+    }
     *hash_guard = Some(hash);
     drop(hash_guard);
 
     tracing::info!("Admin password configured via setup endpoint");
 
     // Create a session for the newly set-up admin
-    let token = state.sessions.create_session("admin");
+    let token =
+        state
+            .sessions
+            .create_session("admin", &extract_client_ip(&headers), user_agent(&headers));
     let secure = state.secure_cookies
         || state
             .dashboard_origin
@@ -542,6 +794,21 @@ pub async fn auth_setup(
 /// POST /api/auth/login — Authenticate with the admin password.
 ///
 /// Returns a session cookie on success. Applies per-IP brute-force protection.
+#[utoipa::path(
+    post,
+    path = "/api/auth/login",
+    tag = openapi::AUTH,
+    summary = "Log in",
+    request_body = PasswordRequest,
+    responses(
+        (
+            status = 200,
+            description = "Logged in, with the session cookie set",
+            body = LoginResponse,
+        ),
+    ),
+    security(()),
+)]
 pub async fn auth_login(
     State(state): State<Arc<AppState>>,
     headers: HeaderMap,
@@ -560,11 +827,10 @@ pub async fn auth_login(
             remaining_secs = remaining,
             "Login attempt from locked-out IP"
         );
-        let mut response = (
-            StatusCode::TOO_MANY_REQUESTS,
-            Json(serde_json::json!({"error": "Too many failed login attempts. Try again later."})),
+        let mut response = ApiError::TooManyRequests(
+            "Too many failed login attempts. Try again later.".to_string(),
         )
-            .into_response();
+        .into_response();
         if let Ok(retry_val) = axum::http::HeaderValue::from_str(&remaining.to_string()) {
             response.headers_mut().insert("retry-after", retry_val);
         }
@@ -573,11 +839,10 @@ pub async fn auth_login(
 
     let hash_guard = state.admin_password_hash.lock();
     let Some(ref stored_hash) = *hash_guard else {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(serde_json::json!({"error": "No admin password configured. Use /api/auth/setup first."})),
+        return ApiError::BadRequest(
+            "No admin password configured. Use /api/auth/setup first.".to_string(),
         )
-            .into_response();
+        .into_response();
     };
 
     if !verify_password(&body.password, stored_hash) {
@@ -591,18 +856,17 @@ pub async fn auth_login(
             fail_count,
             "Admin login failed: invalid password"
         );
-        return (
-            StatusCode::UNAUTHORIZED,
-            Json(serde_json::json!({"error": "Invalid password"})),
-        )
-            .into_response();
+        return ApiError::Unauthorized("Invalid password".to_string()).into_response();
     }
     drop(hash_guard);
 
     state.login_attempt_tracker.record_success(&ip);
 
-    let token = state.sessions.create_session("admin");
-    tracing::info!(ip_prefix = %anonymize_ip(&ip), "Admin login successful");
+    let token = state
+        .sessions
+        .create_session("admin", &ip, user_agent(&headers));
+    // No IP prefix: client addresses are only logged, anonymized, in warnings.
+    tracing::info!("Admin login successful");
 
     let secure = state.secure_cookies
         || state
@@ -620,6 +884,14 @@ pub async fn auth_login(
 }
 
 /// POST /api/auth/logout — Invalidate the current session.
+#[utoipa::path(
+    post,
+    path = "/api/auth/logout",
+    tag = openapi::AUTH,
+    summary = "Log out",
+    responses((status = 200, description = "Logged out", body = StatusResponse)),
+    security(()),
+)]
 pub async fn auth_logout(
     State(state): State<Arc<AppState>>,
     headers: HeaderMap,
@@ -634,13 +906,23 @@ pub async fn auth_logout(
     (
         StatusCode::OK,
         [(axum::http::header::SET_COOKIE, cookie)],
-        Json(serde_json::json!({"status": "logged_out"})),
+        Json(StatusResponse {
+            status: "logged_out",
+        }),
     )
 }
 
 /// GET /api/auth/status — Check authentication state.
 ///
 /// Returns whether setup is needed and whether the current request is authenticated.
+#[utoipa::path(
+    get,
+    path = "/api/auth/status",
+    tag = openapi::AUTH,
+    summary = "Whether setup is required and the caller is logged in",
+    responses((status = 200, description = "Authentication state", body = AuthStatusResponse)),
+    security(()),
+)]
 pub async fn auth_status(
     State(state): State<Arc<AppState>>,
     headers: HeaderMap,
@@ -661,48 +943,150 @@ pub async fn auth_status(
 // --- API Key Management Handlers ---
 
 /// Request body for creating an API key.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct CreateApiKeyRequest {
     pub name: String,
     pub scope: ApiKeyScope,
+    /// Limit a read-only key to one site group.
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Limit a write key to one site.
+    #[serde(default)]
+    pub site_id: Option<String>,
+    /// Limit a read-only or write key to one tenant's sites.
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// Reject the key from this time on.
+    #[serde(default)]
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 /// Response from API key creation (includes plaintext key, shown only once).
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 struct CreateApiKeyResponse {
     key: String,
     key_hash: String,
     name: String,
     scope: ApiKeyScope,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    site_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tenant: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<String>,
 }
 
 /// Response item for listing API keys (no plaintext).
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 struct ApiKeyListItem {
     key_hash: String,
     name: String,
     scope: ApiKeyScope,
     created_at: String,
     revoked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    site_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tenant: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<String>,
+    expired: bool,
+    /// `null` if the key has never been used.
+    last_used_at: Option<String>,
+}
+
+/// Format a stored UTC timestamp for API responses.
+fn format_timestamp(timestamp: chrono::NaiveDateTime) -> String {
+    timestamp.format("%Y-%m-%dT%H:%M:%SZ").to_string()
 }
 
 /// POST /api/keys — Create a new API key (requires admin session).
+#[utoipa::path(
+    post,
+    path = "/api/keys",
+    tag = openapi::AUTH,
+    summary = "Create an API key",
+    request_body = CreateApiKeyRequest,
+    responses(
+        (status = 201, description = "The key, shown only this once", body = CreateApiKeyResponse),
+    ),
+)]
 pub async fn create_api_key(
     State(state): State<Arc<AppState>>,
     Json(body): Json<CreateApiKeyRequest>,
 ) -> impl IntoResponse {
     if body.name.is_empty() || body.name.len() > 128 {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(serde_json::json!({"error": "Key name must be 1-128 characters"})),
-        )
+        return ApiError::invalid_field("name", "Key name must be 1-128 characters")
+            .into_response();
+    }
+
+    if let Some(group) = &body.group {
+        let error = if body.scope != ApiKeyScope::ReadOnly {
+            Some("Only ReadOnly keys can be limited to a group")
+        } else if !state
+            .sites
+            .values()
+            .any(|s| s.group.as_deref() == Some(group.as_str()))
+        {
+            Some("Unknown site group")
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            return ApiError::invalid_field("group", error).into_response();
+        }
+    }
+
+    if let Some(site_id) = &body.site_id {
+        let error = if body.scope != ApiKeyScope::WriteEvents {
+            Some("Only WriteEvents keys can be limited to a site")
+        } else if !state.sites.contains_key(site_id) {
+            Some("Unknown site")
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            return ApiError::invalid_field("site_id", error).into_response();
+        }
+    }
+
+    if let Some(tenant) = &body.tenant {
+        let error = if body.scope == ApiKeyScope::Admin {
+            Some("Admin keys cannot be limited to a tenant")
+        } else if !state
+            .sites
+            .values()
+            .any(|s| s.tenant.as_deref() == Some(tenant.as_str()))
+        {
+            Some("Unknown tenant")
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            return ApiError::invalid_field("tenant", error).into_response();
+        }
+    }
+
+    let expires_at = body.expires_at.map(|t| t.naive_utc());
+    if expires_at.is_some_and(|t| t <= chrono::Utc::now().naive_utc()) {
+        return ApiError::invalid_field("expires_at", "Expiry must be in the future")
             .into_response();
     }
 
     let plaintext_key = generate_api_key();
-    let key_hash = state
-        .api_keys
-        .add_key(&body.name, &plaintext_key, body.scope);
+    let limits = ApiKeyLimits {
+        group: body.group.as_deref(),
+        site_id: body.site_id.as_deref(),
+        tenant: body.tenant.as_deref(),
+    };
+    let key_hash =
+        state
+            .api_keys
+            .add_key(&body.name, &plaintext_key, body.scope, limits, expires_at);
 
     tracing::info!(
         name = %body.name,
@@ -718,43 +1102,136 @@ pub async fn create_api_key(
             key_hash,
             name: body.name,
             scope: body.scope,
+            group: body.group,
+            site_id: body.site_id,
+            tenant: body.tenant,
+            expires_at: expires_at.map(format_timestamp),
         })),
     )
         .into_response()
 }
 
 /// GET /api/keys — List all API keys (requires admin session).
+#[utoipa::path(
+    get,
+    path = "/api/keys",
+    tag = openapi::AUTH,
+    summary = "List API keys",
+    responses(
+        (
+            status = 200,
+            description = "Every key, without its plaintext",
+            body = Vec<ApiKeyListItem>,
+        ),
+    ),
+)]
 pub async fn list_api_keys(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let now = chrono::Utc::now().naive_utc();
     let keys: Vec<ApiKeyListItem> = state
         .api_keys
         .list_keys()
         .into_iter()
         .map(|k| ApiKeyListItem {
+            expired: k.is_expired(now),
             key_hash: k.key_hash,
             name: k.name,
             scope: k.scope,
-            created_at: k.created_at.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+            created_at: format_timestamp(k.created_at),
             revoked: k.revoked,
+            group: k.group,
+            site_id: k.site_id,
+            tenant: k.tenant,
+            expires_at: k.expires_at.map(format_timestamp),
+            last_used_at: k.last_used_at.map(format_timestamp),
         })
         .collect();
     Json(keys)
 }
 
 /// DELETE /api/keys/:key_hash — Revoke an API key (requires admin session).
+#[utoipa::path(
+    delete,
+    path = "/api/keys/{key_hash}",
+    tag = openapi::AUTH,
+    summary = "Revoke an API key",
+    params(("key_hash" = String, Path, description = "Hash of the key.")),
+    responses((status = 200, description = "Revoked", body = StatusResponse)),
+)]
 pub async fn revoke_api_key_handler(
     State(state): State<Arc<AppState>>,
     axum::extract::Path(key_hash): axum::extract::Path<String>,
-) -> Result<impl IntoResponse, crate::api::errors::ApiError> {
+) -> Result<impl IntoResponse, ApiError> {
     if state.api_keys.revoke_key(&key_hash) {
         tracing::info!(key_hash_prefix = %key_hash.get(..8).unwrap_or(&key_hash), "API key revoked");
-        Ok((
-            StatusCode::OK,
-            Json(serde_json::json!({"status": "revoked"})),
-        ))
+        Ok((StatusCode::OK, Json(StatusResponse { status: "revoked" })))
     } else {
-        Err(crate::api::errors::ApiError::NotFound(
-            "Key not found".to_string(),
-        ))
+        Err(ApiError::NotFound("Key not found".to_string()))
+    }
+}
+
+// --- Session Management Handlers ---
+
+/// Path segment of `DELETE /api/auth/sessions/{id}` that revokes every
+/// session but the caller's.
+const OTHER_SESSIONS: &str = "others";
+
+/// GET /api/auth/sessions — List active dashboard sessions (requires admin).
+#[utoipa::path(
+    get,
+    path = "/api/auth/sessions",
+    tag = openapi::AUTH,
+    summary = "List active sessions",
+    responses((status = 200, description = "Active sessions", body = Vec<SessionInfo>)),
+)]
+pub async fn list_sessions(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Json<Vec<SessionInfo>> {
+    let current = extract_session_token(&headers);
+    Json(state.sessions.list_sessions(current.as_deref()))
+}
+
+/// DELETE /api/auth/sessions/:id — Revoke one session, or with `others`,
+/// every session except the caller's (requires admin).
+#[utoipa::path(
+    delete,
+    path = "/api/auth/sessions/{id}",
+    tag = openapi::AUTH,
+    summary = "Revoke a session, or every other session",
+    params(("id" = String, Path, description = "Session ID, or `others`.")),
+    responses((status = 200, description = "Revoked", body = RevokedResponse)),
+)]
+pub async fn revoke_session_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    if id == OTHER_SESSIONS {
+        // Without a session of its own, e.g. with an API key, the caller
+        // would revoke every session.
+        let current = extract_session_token(&headers)
+            .filter(|token| state.sessions.validate_session(token).is_some())
+            .ok_or_else(|| {
+                ApiError::BadRequest(
+                    "Revoking other sessions requires a session; revoke sessions by ID instead"
+                        .to_string(),
+                )
+            })?;
+        let revoked = state.sessions.revoke_other_sessions(&current);
+        tracing::info!(revoked, "Other admin sessions revoked");
+        return Ok(Json(RevokedResponse {
+            status: "revoked",
+            revoked,
+        }));
+    }
+    if state.sessions.revoke_session(&id) {
+        tracing::info!(session_id = %id, "Admin session revoked");
+        Ok(Json(RevokedResponse {
+            status: "revoked",
+            revoked: 1,
+        }))
+    } else {
+        Err(ApiError::NotFound("Session not found".to_string()))
     }
 }
 
@@ -822,18 +1299,19 @@ fn extract_origin_from_referer(referer: &str) -> Option<&str> {
     Some(&referer[..scheme_len + host_len])
 }
 
-/// Validate that the request Origin or Referer matches the configured dashboard origin.
+/// Validate that the request Origin or Referer matches one of the configured
+/// dashboard origins.
 ///
 /// This prevents CSRF attacks on session-authenticated state-changing endpoints.
-/// Only enforced when `dashboard_origin` is configured.
-fn validate_csrf_origin(headers: &HeaderMap, dashboard_origin: Option<&String>) -> bool {
-    let Some(expected) = dashboard_origin else {
+/// Only enforced when `dashboard_origin` or `dashboard_origins` is configured.
+fn validate_csrf_origin(headers: &HeaderMap, allowed_origins: &[&str]) -> bool {
+    if allowed_origins.is_empty() {
         return true; // No restriction configured
-    };
+    }
 
     if let Some(origin) = headers.get("origin") {
         if let Ok(origin_str) = origin.to_str() {
-            return origin_str == expected.as_str();
+            return allowed_origins.contains(&origin_str);
         }
         return false;
     }
@@ -844,7 +1322,7 @@ fn validate_csrf_origin(headers: &HeaderMap, dashboard_origin: Option<&String>)
             // Using starts_with() would allow "https://example.com.evil.com/…" to bypass
             // a rule for "https://example.com".
             let referer_origin = extract_origin_from_referer(referer_str).unwrap_or("");
-            return referer_origin == expected.as_str();
+            return allowed_origins.contains(&referer_origin);
         }
         return false;
     }
@@ -857,6 +1335,8 @@ fn validate_csrf_origin(headers: &HeaderMap, dashboard_origin: Option<&String>)
 ///
 /// Authentication is bypassed when no admin password is configured (open access mode).
 /// Accepts a session cookie (`mm_session`), `Authorization: Bearer mm_...`, or `X-API-Key: mm_...`.
+/// Read-only API keys may not query `site_id=__all__`, and group- or
+/// tenant-limited keys may only query sites in their group or tenant.
 pub async fn require_auth(
     State(state): State<Arc<AppState>>,
     headers: HeaderMap,
@@ -868,17 +1348,198 @@ pub async fn require_auth(
         return Ok(next.run(request).await);
     }
 
-    if get_auth_info(&state, &headers) != AuthInfo::None {
-        return Ok(next.run(request).await);
+    match get_auth_info(&state, &headers) {
+        AuthInfo::None => Err(StatusCode::UNAUTHORIZED),
+        AuthInfo::ApiKey(ApiKeyScope::ReadOnly) => {
+            let group = api_key_group(&state, &headers);
+            let tenant = api_key_tenant(&state, &headers);
+            let allowed = requested_site_ids(request.uri()).iter().all(|site_id| {
+                read_only_may_query(&state, site_id, group.as_deref())
+                    && tenant_may_access(&state, site_id, tenant.as_deref())
+            });
+            if allowed {
+                Ok(next.run(request).await)
+            } else {
+                tracing::warn!(group = ?group, tenant = ?tenant, "ReadOnly API key attempted to query a site outside its scope");
+                Err(StatusCode::FORBIDDEN)
+            }
+        }
+        AuthInfo::ApiKey(ApiKeyScope::WriteEvents) => {
+            tracing::warn!("WriteEvents API key attempted to read protected data");
+            Err(StatusCode::FORBIDDEN)
+        }
+        AuthInfo::Session | AuthInfo::ApiKey(ApiKeyScope::Admin) => Ok(next.run(request).await),
+    }
+}
+
+/// Every `site_id` in the query string.
+///
+/// Decodes the query the same way the `Query` extractor does, so a
+/// percent-encoded `__all__` is caught too.
+fn requested_site_ids(uri: &axum::http::Uri) -> Vec<String> {
+    axum::extract::Query::<Vec<(String, String)>>::try_from_uri(uri)
+        .map(|q| {
+            q.0.into_iter()
+                .filter(|(k, _)| k == "site_id")
+                .map(|(_, v)| v)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Which sites the request may read, as `require_auth` checks its `site_id`
+/// parameters: a read-only key only those in its group and tenant.
+pub fn site_reader<'a>(state: &'a AppState, headers: &HeaderMap) -> impl Fn(&str) -> bool + 'a {
+    let open = state.admin_password_hash.lock().is_none();
+    let limits = (!open
+        && matches!(
+            get_auth_info(state, headers),
+            AuthInfo::ApiKey(ApiKeyScope::ReadOnly)
+        ))
+    .then(|| {
+        (
+            api_key_group(state, headers),
+            api_key_tenant(state, headers),
+        )
+    });
+    move |site_id| {
+        limits.as_ref().is_none_or(|(group, tenant)| {
+            read_only_may_query(state, site_id, group.as_deref())
+                && tenant_may_access(state, site_id, tenant.as_deref())
+        })
     }
+}
+
+/// Whether a read-only key limited to `group` (or unlimited, when `None`) may
+/// query `site_id`.
+///
+/// The all-sites roll-up exposes every site's data, so it is kept to admins.
+/// A group-limited key may query its own `@group` and the sites in it.
+fn read_only_may_query(state: &AppState, site_id: &str, group: Option<&str>) -> bool {
+    if site_id == crate::query::ALL_SITES {
+        return false;
+    }
+    let Some(group) = group else {
+        return true;
+    };
+    site_id
+        .strip_prefix(crate::query::GROUP_PREFIX)
+        .map_or_else(
+            || {
+                state
+                    .sites
+                    .get(site_id)
+                    .is_some_and(|s| s.group.as_deref() == Some(group))
+            },
+            |requested| requested == group,
+        )
+}
+
+/// Whether a key limited to `tenant` (or unlimited, when `None`) may access
+/// `site_id`: a site of the tenant, or a group whose sites all belong to it.
+fn tenant_may_access(state: &AppState, site_id: &str, tenant: Option<&str>) -> bool {
+    let Some(tenant) = tenant else {
+        return true;
+    };
+    let in_tenant = |site: &crate::config::SiteConfig| site.tenant.as_deref() == Some(tenant);
+    site_id
+        .strip_prefix(crate::query::GROUP_PREFIX)
+        .map_or_else(
+            || state.sites.get(site_id).is_some_and(in_tenant),
+            |group| {
+                let mut sites = state
+                    .sites
+                    .values()
+                    .filter(|s| s.group.as_deref() == Some(group))
+                    .peekable();
+                sites.peek().is_some() && sites.all(in_tenant)
+            },
+        )
+}
+
+/// The tenant of the API key that authenticated the request.
+fn api_key_tenant(state: &AppState, headers: &HeaderMap) -> Option<String> {
+    accepted_api_key(state, headers).and_then(|key| state.api_keys.key_tenant(key))
+}
 
-    Err(StatusCode::UNAUTHORIZED)
+/// The site group of the API key that authenticated the request.
+fn api_key_group(state: &AppState, headers: &HeaderMap) -> Option<String> {
+    accepted_api_key(state, headers).and_then(|key| state.api_keys.key_group(key))
+}
+
+/// The API keys presented by the request: `Authorization: Bearer`, then
+/// `X-API-Key`.
+fn presented_api_keys(headers: &HeaderMap) -> impl Iterator<Item = &str> {
+    let bearer = headers
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let x_api_key = headers.get("x-api-key").and_then(|v| v.to_str().ok());
+    [bearer, x_api_key].into_iter().flatten()
+}
+
+/// The API key that authenticated the request.
+///
+/// Checks the headers in the same order as `get_auth_info`, so this is always
+/// the key that was accepted.
+fn accepted_api_key<'a>(state: &AppState, headers: &'a HeaderMap) -> Option<&'a str> {
+    presented_api_keys(headers).find(|key| state.api_keys.validate_key(key).is_some())
+}
+
+/// Whether the request carries a write API key allowed to submit events for
+/// `site_id`.
+pub fn has_write_key(state: &AppState, headers: &HeaderMap, site_id: &str) -> bool {
+    presented_api_keys(headers).any(|key| {
+        state.api_keys.may_write_events(key, site_id)
+            && tenant_may_access(state, site_id, state.api_keys.key_tenant(key).as_deref())
+    })
+}
+
+/// Middleware that limits `/api/stats/*` requests per caller.
+///
+/// Each session, API key, or (in open access mode) client IP gets
+/// `stats_rate_limit_per_minute` requests per minute.  Must run after
+/// [`require_auth`] so only authenticated callers consume a bucket.
+pub async fn rate_limit_stats(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    request: Request<axum::body::Body>,
+    next: Next,
+) -> Response {
+    let caller = stats_caller(&state, &headers);
+    match state.stats_rate_limiter.acquire(&caller) {
+        Some(status) if !status.allowed => {
+            state
+                .stats_rate_limit_rejections_total
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            (
+                status.headers(),
+                ApiError::TooManyRequests("Stats rate limit exceeded".to_string()),
+            )
+                .into_response()
+        }
+        _ => next.run(request).await,
+    }
+}
+
+/// Identity of a stats caller: the rate-limit bucket key, also recorded in
+/// audit logs.  Credentials are hashed so neither holds a usable token.
+pub fn stats_caller(state: &AppState, headers: &HeaderMap) -> String {
+    if let Some(token) = extract_session_token(headers) {
+        if state.sessions.validate_session(&token).is_some() {
+            return format!("session:{}", hash_api_key(&token));
+        }
+    }
+    accepted_api_key(state, headers).map_or_else(
+        || format!("ip:{}", extract_client_ip(headers)),
+        |key| format!("key:{}", hash_api_key(key)),
+    )
 }
 
 /// Middleware that requires **admin-level** authentication for key management routes.
 ///
 /// - Read-only API keys are rejected with 403 Forbidden.
-/// - Session-authenticated requests are CSRF-checked against `dashboard_origin`.
+/// - Session-authenticated requests are CSRF-checked against the dashboard origins.
 /// - Open-access mode (no password configured) bypasses all checks.
 pub async fn require_admin_auth(
     State(state): State<Arc<AppState>>,
@@ -893,16 +1554,16 @@ pub async fn require_admin_auth(
     match get_auth_info(&state, &headers) {
         AuthInfo::None => Err(StatusCode::UNAUTHORIZED),
         AuthInfo::Session => {
-            // CSRF check: Origin must match dashboard_origin when configured
-            if !validate_csrf_origin(&headers, state.dashboard_origin.as_ref()) {
+            // CSRF check: Origin must match a dashboard origin when configured
+            if !validate_csrf_origin(&headers, &state.allowed_origins()) {
                 tracing::warn!("CSRF check failed on admin endpoint");
                 return Err(StatusCode::FORBIDDEN);
             }
             Ok(next.run(request).await)
         }
         AuthInfo::ApiKey(ApiKeyScope::Admin) => Ok(next.run(request).await),
-        AuthInfo::ApiKey(ApiKeyScope::ReadOnly) => {
-            tracing::warn!("ReadOnly API key attempted to access admin-only endpoint");
+        AuthInfo::ApiKey(scope @ (ApiKeyScope::ReadOnly | ApiKeyScope::WriteEvents)) => {
+            tracing::warn!(scope = ?scope, "Non-admin API key attempted to access admin-only endpoint");
             Err(StatusCode::FORBIDDEN)
         }
     }
@@ -910,12 +1571,16 @@ pub async fn require_admin_auth(
 
 // --- Helper Functions ---
 
-/// Check if a request is authenticated (any valid credential).
+/// Check if a request is authenticated for the dashboard.
 ///
-/// Returns true for sessions and any valid API key (read-only or admin).
-/// Use `get_auth_info` when scope information is needed.
+/// Returns true for sessions and any valid read-only or admin API key; write
+/// keys cannot read anything.  Use `get_auth_info` when scope information is
+/// needed.
 fn is_authenticated(state: &AppState, headers: &HeaderMap) -> bool {
-    get_auth_info(state, headers) != AuthInfo::None
+    !matches!(
+        get_auth_info(state, headers),
+        AuthInfo::None | AuthInfo::ApiKey(ApiKeyScope::WriteEvents)
+    )
 }
 
 /// Re-export of the shared IP extraction helper.
@@ -923,6 +1588,11 @@ fn extract_client_ip(headers: &HeaderMap) -> String {
     crate::ingest::handler::extract_ip(headers)
 }
 
+/// The request's `User-Agent`, if present and valid.
+fn user_agent(headers: &HeaderMap) -> Option<&str> {
+    headers.get("user-agent")?.to_str().ok()
+}
+
 /// Extract session token from cookie header.
 fn extract_session_token(headers: &HeaderMap) -> Option<String> {
     let cookie = headers.get("cookie")?.to_str().ok()?;
@@ -1038,11 +1708,29 @@ mod tests {
         assert!(!verify_password("any", "not-a-valid-hash"));
     }
 
+    #[test]
+    fn test_persisted_password_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("admin_password");
+        assert_eq!(load_password_hash(&path), None);
+
+        let hash = hash_password("first-password").unwrap();
+        save_password_hash(&path, &hash).unwrap();
+        let replacement = hash_password("second-password").unwrap();
+        save_password_hash(&path, &replacement).unwrap();
+        let loaded = load_password_hash(&path).unwrap();
+        assert!(verify_password("second-password", &loaded));
+        assert!(!verify_password("first-password", &loaded));
+
+        std::fs::write(&path, "garbage").unwrap();
+        assert_eq!(load_password_hash(&path), None);
+    }
+
     // Session management tests
     #[test]
     fn test_session_create_and_validate() {
         let store = SessionStore::new(3600);
-        let token = store.create_session("admin");
+        let token = store.create_session("admin", "127.0.0.1", None);
         assert!(store.validate_session(&token).is_some());
         assert_eq!(store.validate_session(&token).unwrap(), "admin");
     }
@@ -1056,7 +1744,7 @@ mod tests {
     #[test]
     fn test_session_remove() {
         let store = SessionStore::new(3600);
-        let token = store.create_session("admin");
+        let token = store.create_session("admin", "127.0.0.1", None);
         store.remove_session(&token);
         assert!(store.validate_session(&token).is_none());
     }
@@ -1064,7 +1752,7 @@ mod tests {
     #[test]
     fn test_session_expiry() {
         let store = SessionStore::new(0); // 0 second TTL
-        let token = store.create_session("admin");
+        let token = store.create_session("admin", "127.0.0.1", None);
         // Session should be expired immediately
         std::thread::sleep(std::time::Duration::from_millis(10));
         assert!(store.validate_session(&token).is_none());
@@ -1096,10 +1784,69 @@ mod tests {
     fn test_api_key_store_add_and_validate() {
         let store = ApiKeyStore::default();
         let key = generate_api_key();
-        store.add_key("test-key", &key, ApiKeyScope::ReadOnly);
+        store.add_key(
+            "test-key",
+            &key,
+            ApiKeyScope::ReadOnly,
+            ApiKeyLimits::default(),
+            None,
+        );
         assert_eq!(store.validate_key(&key), Some(ApiKeyScope::ReadOnly));
     }
 
+    #[test]
+    fn test_api_key_store_group_key() {
+        let store = ApiKeyStore::default();
+        let grouped = generate_api_key();
+        let plain = generate_api_key();
+        store.add_key(
+            "client",
+            &grouped,
+            ApiKeyScope::ReadOnly,
+            ApiKeyLimits {
+                group: Some("acme"),
+                ..ApiKeyLimits::default()
+            },
+            None,
+        );
+        store.add_key(
+            "ci",
+            &plain,
+            ApiKeyScope::ReadOnly,
+            ApiKeyLimits::default(),
+            None,
+        );
+        assert_eq!(store.validate_key(&grouped), Some(ApiKeyScope::ReadOnly));
+        assert_eq!(store.key_group(&grouped).as_deref(), Some("acme"));
+        assert!(store.key_group(&plain).is_none());
+    }
+
+    #[test]
+    fn test_api_key_store_tenant_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("api_keys.json");
+        let store = ApiKeyStore::load_from_disk(path.clone());
+        let key = generate_api_key();
+        let plain = generate_api_key();
+        store.add_key(
+            "ci",
+            &plain,
+            ApiKeyScope::ReadOnly,
+            ApiKeyLimits::default(),
+            None,
+        );
+        assert!(store.key_tenant(&plain).is_none());
+        let limits = ApiKeyLimits {
+            tenant: Some("acme"),
+            ..ApiKeyLimits::default()
+        };
+        store.add_key("customer", &key, ApiKeyScope::ReadOnly, limits, None);
+        assert_eq!(store.key_tenant(&key).as_deref(), Some("acme"));
+        // Stored with its tenant by the one write.
+        let reloaded = ApiKeyStore::load_from_disk(path);
+        assert_eq!(reloaded.key_tenant(&key).as_deref(), Some("acme"));
+    }
+
     #[test]
     fn test_api_key_store_invalid_key() {
         let store = ApiKeyStore::default();
@@ -1110,7 +1857,13 @@ mod tests {
     fn test_api_key_store_revoke() {
         let store = ApiKeyStore::default();
         let key = generate_api_key();
-        let key_hash = store.add_key("test-key", &key, ApiKeyScope::Admin);
+        let key_hash = store.add_key(
+            "test-key",
+            &key,
+            ApiKeyScope::Admin,
+            ApiKeyLimits::default(),
+            None,
+        );
         assert!(store.validate_key(&key).is_some());
         store.revoke_key(&key_hash);
         assert!(store.validate_key(&key).is_none());
@@ -1121,8 +1874,20 @@ mod tests {
         let store = ApiKeyStore::default();
         let readonly_key = generate_api_key();
         let admin_key = generate_api_key();
-        store.add_key("read", &readonly_key, ApiKeyScope::ReadOnly);
-        store.add_key("admin", &admin_key, ApiKeyScope::Admin);
+        store.add_key(
+            "read",
+            &readonly_key,
+            ApiKeyScope::ReadOnly,
+            ApiKeyLimits::default(),
+            None,
+        );
+        store.add_key(
+            "admin",
+            &admin_key,
+            ApiKeyScope::Admin,
+            ApiKeyLimits::default(),
+            None,
+        );
         assert_eq!(
             store.validate_key(&readonly_key),
             Some(ApiKeyScope::ReadOnly)
@@ -1134,24 +1899,155 @@ mod tests {
     fn test_api_key_store_list() {
         let store = ApiKeyStore::default();
         let key = generate_api_key();
-        store.add_key("my-key", &key, ApiKeyScope::ReadOnly);
+        store.add_key(
+            "my-key",
+            &key,
+            ApiKeyScope::ReadOnly,
+            ApiKeyLimits::default(),
+            None,
+        );
         let keys = store.list_keys();
         assert_eq!(keys.len(), 1);
         assert_eq!(keys[0].name, "my-key");
         assert!(!keys[0].revoked);
     }
 
+    #[test]
+    fn test_api_key_store_expiry() {
+        let store = ApiKeyStore::default();
+        let now = chrono::Utc::now().naive_utc();
+        let (expired, current) = (generate_api_key(), generate_api_key());
+        store.add_key(
+            "old",
+            &expired,
+            ApiKeyScope::Admin,
+            ApiKeyLimits::default(),
+            Some(now - chrono::Duration::seconds(1)),
+        );
+        store.add_key(
+            "client",
+            &current,
+            ApiKeyScope::ReadOnly,
+            ApiKeyLimits {
+                group: Some("acme"),
+                ..ApiKeyLimits::default()
+            },
+            Some(now + chrono::Duration::days(1)),
+        );
+        assert_eq!(store.validate_key(&expired), None);
+        assert_eq!(store.validate_key(&current), Some(ApiKeyScope::ReadOnly));
+        assert_eq!(store.key_group(&current).as_deref(), Some("acme"));
+
+        let keys = store.list_keys();
+        assert!(keys[0].is_expired(now));
+        assert!(!keys[1].is_expired(now));
+    }
+
+    #[test]
+    fn test_api_key_store_write_key() {
+        let store = ApiKeyStore::default();
+        let (any_site, one_site, admin) =
+            (generate_api_key(), generate_api_key(), generate_api_key());
+        store.add_key(
+            "any",
+            &any_site,
+            ApiKeyScope::WriteEvents,
+            ApiKeyLimits {
+                site_id: None,
+                ..ApiKeyLimits::default()
+            },
+            None,
+        );
+        store.add_key(
+            "one",
+            &one_site,
+            ApiKeyScope::WriteEvents,
+            ApiKeyLimits {
+                site_id: Some("a.com"),
+                ..ApiKeyLimits::default()
+            },
+            None,
+        );
+        store.add_key(
+            "admin",
+            &admin,
+            ApiKeyScope::Admin,
+            ApiKeyLimits::default(),
+            None,
+        );
+
+        assert_eq!(
+            store.validate_key(&one_site),
+            Some(ApiKeyScope::WriteEvents)
+        );
+        assert!(store.may_write_events(&any_site, "b.com"));
+        assert!(store.may_write_events(&one_site, "a.com"));
+        assert!(!store.may_write_events(&one_site, "b.com"));
+        assert!(!store.may_write_events(&admin, "a.com"));
+        store.revoke_key(&hash_api_key(&one_site));
+        assert!(!store.may_write_events(&one_site, "a.com"));
+    }
+
+    #[test]
+    fn test_api_key_store_last_used() {
+        let store = ApiKeyStore::default();
+        let key = generate_api_key();
+        store.add_key(
+            "ci",
+            &key,
+            ApiKeyScope::ReadOnly,
+            ApiKeyLimits::default(),
+            None,
+        );
+        assert_eq!(store.list_keys()[0].last_used_at, None);
+
+        store.validate_key(&key);
+        let first_use = store.list_keys()[0].last_used_at.unwrap();
+        // Uses within the resolution window leave the timestamp alone.
+        store.validate_key(&key);
+        assert_eq!(store.list_keys()[0].last_used_at, Some(first_use));
+
+        store.keys.lock()[0].last_used_at =
+            Some(first_use - chrono::Duration::seconds(LAST_USED_RESOLUTION_SECS));
+        store.validate_key(&key);
+        assert!(store.list_keys()[0].last_used_at.unwrap() >= first_use);
+    }
+
     #[test]
     fn test_session_cleanup_expired() {
         let store = SessionStore::new(0); // 0 second TTL
-        store.create_session("user1");
-        store.create_session("user2");
+        store.create_session("user1", "127.0.0.1", None);
+        store.create_session("user2", "127.0.0.1", None);
         std::thread::sleep(std::time::Duration::from_millis(10));
         store.cleanup_expired();
         // All sessions should be cleaned up
         assert_eq!(store.sessions.lock().len(), 0);
     }
 
+    #[test]
+    fn test_session_list_and_revoke() {
+        let store = SessionStore::new(3600);
+        let mine = store.create_session("admin", "203.0.113.7", Some("Firefox"));
+        let other = store.create_session("admin", "2001:db8::1", None);
+        assert!(store.validate_session(&mine).is_some());
+
+        let sessions = store.list_sessions(Some(&mine));
+        assert_eq!(sessions.len(), 2);
+        assert!(sessions.iter().all(|s| s.id.len() == 16 && s.id != mine));
+        let other_info = sessions.iter().find(|s| !s.current).unwrap();
+        assert_eq!(other_info.ip_prefix, "2001:...");
+        assert_eq!(other_info.user_agent, None);
+
+        assert!(store.revoke_session(&other_info.id));
+        assert!(!store.revoke_session(&other_info.id));
+        assert!(store.validate_session(&other).is_none());
+        assert!(store.validate_session(&mine).is_some());
+
+        store.create_session("admin", "198.51.100.1", None);
+        assert_eq!(store.revoke_other_sessions(&mine), 1);
+        assert_eq!(store.list_sessions(None).len(), 1);
+    }
+
     // Session cookie Secure flag tests
     #[test]
     fn test_session_cookie_includes_secure_when_flag_is_true() {
@@ -1177,8 +2073,20 @@ mod tests {
         let store = ApiKeyStore::default();
         let key1 = generate_api_key();
         let key2 = generate_api_key();
-        let hash1 = store.add_key("key1", &key1, ApiKeyScope::ReadOnly);
-        store.add_key("key2", &key2, ApiKeyScope::Admin);
+        let hash1 = store.add_key(
+            "key1",
+            &key1,
+            ApiKeyScope::ReadOnly,
+            ApiKeyLimits::default(),
+            None,
+        );
+        store.add_key(
+            "key2",
+            &key2,
+            ApiKeyScope::Admin,
+            ApiKeyLimits::default(),
+            None,
+        );
         // Revoke key1
         store.revoke_key(&hash1);
         assert_eq!(store.list_keys().len(), 2);
@@ -1198,8 +2106,20 @@ mod tests {
         let store1 = ApiKeyStore::load_from_disk(path.clone());
         let key_a = generate_api_key();
         let key_b = generate_api_key();
-        store1.add_key("alpha", &key_a, ApiKeyScope::ReadOnly);
-        store1.add_key("beta", &key_b, ApiKeyScope::Admin);
+        store1.add_key(
+            "alpha",
+            &key_a,
+            ApiKeyScope::ReadOnly,
+            ApiKeyLimits::default(),
+            None,
+        );
+        store1.add_key(
+            "beta",
+            &key_b,
+            ApiKeyScope::Admin,
+            ApiKeyLimits::default(),
+            None,
+        );
         assert!(
             path.exists(),
             "api_keys.json should be created after add_key"
@@ -1329,7 +2249,7 @@ mod tests {
     #[test]
     fn test_csrf_validate_no_dashboard_origin_allows_all() {
         let headers = HeaderMap::new();
-        assert!(validate_csrf_origin(&headers, None));
+        assert!(validate_csrf_origin(&headers, &[]));
     }
 
     #[test]
@@ -1338,7 +2258,7 @@ mod tests {
         headers.insert("origin", "https://analytics.example.com".parse().unwrap());
         assert!(validate_csrf_origin(
             &headers,
-            Some(&"https://analytics.example.com".to_string())
+            &["https://analytics.example.com"]
         ));
     }
 
@@ -1348,17 +2268,27 @@ mod tests {
         headers.insert("origin", "https://evil.com".parse().unwrap());
         assert!(!validate_csrf_origin(
             &headers,
-            Some(&"https://analytics.example.com".to_string())
+            &["https://analytics.example.com"]
         ));
     }
 
+    #[test]
+    fn test_csrf_validate_any_configured_origin_allowed() {
+        let allowed = ["https://analytics.example.com", "https://admin.example.com"];
+        let mut headers = HeaderMap::new();
+        headers.insert("origin", "https://admin.example.com".parse().unwrap());
+        assert!(validate_csrf_origin(&headers, &allowed));
+        headers.insert("origin", "https://example.com".parse().unwrap());
+        assert!(!validate_csrf_origin(&headers, &allowed));
+    }
+
     #[test]
     fn test_csrf_validate_no_origin_or_referer_allows() {
         // Server-side requests without Origin/Referer should be allowed
         let headers = HeaderMap::new();
         assert!(validate_csrf_origin(
             &headers,
-            Some(&"https://analytics.example.com".to_string())
+            &["https://analytics.example.com"]
         ));
     }
 
@@ -1374,7 +2304,7 @@ mod tests {
         );
         assert!(validate_csrf_origin(
             &headers,
-            Some(&"https://analytics.example.com".to_string())
+            &["https://analytics.example.com"]
         ));
     }
 
@@ -1390,7 +2320,7 @@ mod tests {
         );
         assert!(!validate_csrf_origin(
             &headers,
-            Some(&"https://analytics.example.com".to_string())
+            &["https://analytics.example.com"]
         ));
     }
 
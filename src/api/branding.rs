@@ -0,0 +1,34 @@
+//! White-label settings for the dashboard, from the `[branding]` table of the
+//! config file.
+
+use crate::ingest::handler::AppState;
+use axum::extract::State;
+use axum::Json;
+use serde::Serialize;
+use std::sync::Arc;
+
+/// Product name when `branding.product_name` is not set.
+pub const DEFAULT_PRODUCT_NAME: &str = "Mallard Metrics";
+
+#[derive(Debug, Serialize)]
+pub struct BrandingResponse {
+    pub product_name: String,
+    pub logo_url: Option<String>,
+    pub accent_color: Option<String>,
+    pub secondary_color: Option<String>,
+}
+
+/// GET /api/meta/branding — Product name, logo and colours for the
+/// dashboard.  Public, because the sign-in screen is branded too.
+pub async fn get_branding(State(state): State<Arc<AppState>>) -> Json<BrandingResponse> {
+    let branding = &state.branding;
+    Json(BrandingResponse {
+        product_name: branding
+            .product_name
+            .clone()
+            .unwrap_or_else(|| DEFAULT_PRODUCT_NAME.to_string()),
+        logo_url: branding.logo_url.clone(),
+        accent_color: branding.accent_color.clone(),
+        secondary_color: branding.secondary_color.clone(),
+    })
+}
@@ -0,0 +1,66 @@
+use crate::ingest::handler::AppState;
+use axum::extract::State;
+use axum::Json;
+use serde::Serialize;
+use std::sync::Arc;
+
+/// Reports driven by the behavioral extension, each with a pure-SQL fallback.
+const BEHAVIORAL_FEATURES: &[&str] = &["sessions", "funnel", "sequences", "flow", "paths"];
+
+/// Whether one feature works, and how well.
+#[derive(Debug, Serialize)]
+pub struct Feature {
+    pub name: &'static str,
+    /// The endpoints return data.
+    pub available: bool,
+    /// Available, but with reduced accuracy or performance.
+    pub degraded: bool,
+    /// Why the feature is unavailable or degraded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CapabilitiesResponse {
+    pub version: &'static str,
+    pub behavioral_extension_loaded: bool,
+    pub geoip_loaded: bool,
+    pub features: Vec<Feature>,
+}
+
+/// What this server can actually do, for the dashboard to flag reports that
+/// are missing or running in a fallback mode.
+pub fn capabilities(state: &AppState) -> CapabilitiesResponse {
+    let behavioral = state.behavioral_extension_loaded;
+    let geoip = state.geoip.is_loaded();
+    let mut features: Vec<Feature> = BEHAVIORAL_FEATURES
+        .iter()
+        .map(|&name| Feature {
+            name,
+            available: true,
+            degraded: !behavioral,
+            reason: (!behavioral).then_some(
+                "Behavioral extension not loaded; using slower SQL fallbacks that \
+                 split sessions on a fixed 30-minute gap",
+            ),
+        })
+        .collect();
+    features.push(Feature {
+        name: "geo",
+        available: geoip,
+        degraded: false,
+        reason: (!geoip)
+            .then_some("No GeoIP database loaded; countries, regions and cities are not recorded"),
+    });
+    CapabilitiesResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        behavioral_extension_loaded: behavioral,
+        geoip_loaded: geoip,
+        features,
+    }
+}
+
+/// GET /api/capabilities — Which analytics features are functional.
+pub async fn get_capabilities(State(state): State<Arc<AppState>>) -> Json<CapabilitiesResponse> {
+    Json(capabilities(&state))
+}
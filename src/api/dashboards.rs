@@ -0,0 +1,379 @@
+use crate::api::auth::site_reader;
+use crate::api::errors::ApiError;
+use crate::api::stats::validate_site_id;
+use crate::ingest::handler::AppState;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use axum::Json;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Maximum number of widgets on one dashboard.
+const MAX_WIDGETS: usize = 50;
+
+/// Maximum number of stored dashboards.
+const MAX_DASHBOARDS: usize = 200;
+
+/// Metrics a widget may display.
+const METRICS: &[&str] = &[
+    "visitors",
+    "pageviews",
+    "bounce_rate",
+    "visit_duration",
+    "pages_per_visit",
+    "timeseries",
+];
+
+/// Breakdowns a widget may group by, matching `/api/stats/breakdown/*`.
+const BREAKDOWNS: &[&str] = &[
+    "pages",
+    "sources",
+    "browsers",
+    "os",
+    "devices",
+    "countries",
+    "custom",
+    "search_terms",
+];
+
+/// Periods accepted by the stats API.
+const PERIODS: &[&str] = &["day", "today", "7d", "30d", "90d"];
+
+/// One tile on a dashboard.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Widget {
+    pub metric: String,
+    pub site_id: String,
+    pub period: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub breakdown: Option<String>,
+}
+
+/// A saved dashboard layout.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Dashboard {
+    pub id: String,
+    pub name: String,
+    pub widgets: Vec<Widget>,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+/// Thread-safe dashboard store with optional disk persistence.
+///
+/// Persists the same way as [`crate::api::auth::ApiKeyStore`]: every mutation
+/// rewrites the JSON file at `persist_path`.
+#[derive(Clone, Default)]
+pub struct DashboardStore {
+    dashboards: Arc<Mutex<Vec<Dashboard>>>,
+    persist_path: Option<Arc<std::path::PathBuf>>,
+}
+
+impl DashboardStore {
+    /// Create a store that loads dashboards from `path` and persists mutations
+    /// back to the same file.  Missing file is treated as empty.
+    pub fn load_from_disk(path: std::path::PathBuf) -> Self {
+        let dashboards = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str::<Vec<Dashboard>>(&contents).unwrap_or_else(|e| {
+                tracing::warn!(
+                    path = %path.display(),
+                    error = %e,
+                    "Failed to parse dashboards.json; starting with no dashboards"
+                );
+                Vec::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                tracing::warn!(
+                    path = %path.display(),
+                    error = %e,
+                    "Could not read dashboards.json; starting with no dashboards"
+                );
+                Vec::new()
+            }
+        };
+        Self {
+            dashboards: Arc::new(Mutex::new(dashboards)),
+            persist_path: Some(Arc::new(path)),
+        }
+    }
+
+    /// Persist current dashboards to disk.  Logs a warning on failure.
+    fn persist(&self, dashboards: &[Dashboard]) {
+        let Some(path) = &self.persist_path else {
+            return;
+        };
+        match serde_json::to_string_pretty(dashboards) {
+            Ok(json) => {
+                if let Err(e) = std::fs::write(path.as_ref(), json) {
+                    tracing::warn!(
+                        path = %path.display(),
+                        error = %e,
+                        "Failed to persist dashboards to disk"
+                    );
+                }
+            }
+            Err(e) => tracing::warn!(error = %e, "Failed to serialize dashboards"),
+        }
+    }
+
+    pub fn list(&self) -> Vec<Dashboard> {
+        self.dashboards.lock().clone()
+    }
+
+    pub fn get(&self, id: &str) -> Option<Dashboard> {
+        self.dashboards.lock().iter().find(|d| d.id == id).cloned()
+    }
+
+    /// Store a new dashboard.  Returns `None` when the store is full.
+    pub fn create(&self, name: String, widgets: Vec<Widget>) -> Option<Dashboard> {
+        let mut dashboards = self.dashboards.lock();
+        if dashboards.len() >= MAX_DASHBOARDS {
+            return None;
+        }
+        let now = chrono::Utc::now().naive_utc();
+        let dashboard = Dashboard {
+            id: uuid::Uuid::new_v4().to_string(),
+            name,
+            widgets,
+            created_at: now,
+            updated_at: now,
+        };
+        dashboards.push(dashboard.clone());
+        self.persist(&dashboards);
+        drop(dashboards);
+        Some(dashboard)
+    }
+
+    /// Replace a dashboard's name and widgets.  Returns `None` if not found.
+    pub fn update(&self, id: &str, name: String, widgets: Vec<Widget>) -> Option<Dashboard> {
+        let mut dashboards = self.dashboards.lock();
+        let dashboard = dashboards.iter_mut().find(|d| d.id == id)?;
+        dashboard.name = name;
+        dashboard.widgets = widgets;
+        dashboard.updated_at = chrono::Utc::now().naive_utc();
+        let updated = dashboard.clone();
+        self.persist(&dashboards);
+        drop(dashboards);
+        Some(updated)
+    }
+
+    pub fn delete(&self, id: &str) -> bool {
+        let mut dashboards = self.dashboards.lock();
+        let before = dashboards.len();
+        dashboards.retain(|d| d.id != id);
+        let removed = dashboards.len() != before;
+        if removed {
+            self.persist(&dashboards);
+        }
+        drop(dashboards);
+        removed
+    }
+}
+
+/// Request body for creating or replacing a dashboard.
+#[derive(Debug, Deserialize)]
+pub struct DashboardRequest {
+    pub name: String,
+    #[serde(default)]
+    pub widgets: Vec<Widget>,
+}
+
+impl DashboardRequest {
+    fn validate(&self) -> Result<(), ApiError> {
+        if self.name.trim().is_empty() || self.name.len() > 128 {
+            return Err(ApiError::BadRequest(
+                "Dashboard name must be 1-128 characters".to_string(),
+            ));
+        }
+        if self.widgets.len() > MAX_WIDGETS {
+            return Err(ApiError::BadRequest(format!(
+                "A dashboard may have at most {MAX_WIDGETS} widgets"
+            )));
+        }
+        for widget in &self.widgets {
+            if !METRICS.contains(&widget.metric.as_str()) {
+                return Err(ApiError::BadRequest(format!(
+                    "Invalid metric: '{}'. Use one of: {}",
+                    widget.metric,
+                    METRICS.join(", ")
+                )));
+            }
+            validate_site_id(&widget.site_id)?;
+            if !PERIODS.contains(&widget.period.as_str()) {
+                return Err(ApiError::BadRequest(format!(
+                    "Invalid period: '{}'. Use one of: {}",
+                    widget.period,
+                    PERIODS.join(", ")
+                )));
+            }
+            if let Some(breakdown) = &widget.breakdown {
+                if !BREAKDOWNS.contains(&breakdown.as_str()) {
+                    return Err(ApiError::BadRequest(format!(
+                        "Invalid breakdown: '{breakdown}'. Use one of: {}",
+                        BREAKDOWNS.join(", ")
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// GET /api/dashboards — List the saved dashboards whose sites the caller
+/// may read.
+pub async fn list_dashboards(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Json<Vec<Dashboard>> {
+    let may_read = site_reader(&state, &headers);
+    let mut dashboards = state.dashboards.list();
+    dashboards.retain(|d| d.widgets.iter().all(|w| may_read(&w.site_id)));
+    Json(dashboards)
+}
+
+/// GET /api/dashboards/{id} — Fetch one dashboard.
+///
+/// A dashboard showing a site the caller may not read is reported as not
+/// found, as it is left out of the list.
+pub async fn get_dashboard(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<Dashboard>, ApiError> {
+    let may_read = site_reader(&state, &headers);
+    state
+        .dashboards
+        .get(&id)
+        .filter(|d| d.widgets.iter().all(|w| may_read(&w.site_id)))
+        .map(Json)
+        .ok_or_else(|| ApiError::NotFound("Dashboard not found".to_string()))
+}
+
+/// POST /api/dashboards — Create a dashboard (requires admin).
+pub async fn create_dashboard(
+    State(state): State<Arc<AppState>>,
+    Json(body): Json<DashboardRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    body.validate()?;
+    let dashboard = state
+        .dashboards
+        .create(body.name, body.widgets)
+        .ok_or_else(|| {
+            ApiError::BadRequest(format!(
+                "Dashboard limit of {MAX_DASHBOARDS} reached; delete one first"
+            ))
+        })?;
+    tracing::info!(id = %dashboard.id, name = %dashboard.name, "Dashboard created");
+    Ok((StatusCode::CREATED, Json(dashboard)))
+}
+
+/// PUT /api/dashboards/{id} — Replace a dashboard's name and widgets (requires admin).
+pub async fn update_dashboard(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Json(body): Json<DashboardRequest>,
+) -> Result<Json<Dashboard>, ApiError> {
+    body.validate()?;
+    state
+        .dashboards
+        .update(&id, body.name, body.widgets)
+        .map(Json)
+        .ok_or_else(|| ApiError::NotFound("Dashboard not found".to_string()))
+}
+
+/// DELETE /api/dashboards/{id} — Delete a dashboard (requires admin).
+pub async fn delete_dashboard(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    if state.dashboards.delete(&id) {
+        tracing::info!(id = %id, "Dashboard deleted");
+        Ok(Json(serde_json::json!({"status": "deleted"})))
+    } else {
+        Err(ApiError::NotFound("Dashboard not found".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn widget(metric: &str) -> Widget {
+        Widget {
+            metric: metric.to_string(),
+            site_id: "example.com".to_string(),
+            period: "30d".to_string(),
+            breakdown: None,
+        }
+    }
+
+    #[test]
+    fn test_store_crud_persists() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dashboards.json");
+        let store = DashboardStore::load_from_disk(path.clone());
+
+        let created = store
+            .create("Marketing".to_string(), vec![widget("visitors")])
+            .unwrap();
+        let updated = store
+            .update(
+                &created.id,
+                "Marketing view".to_string(),
+                vec![widget("visitors"), widget("bounce_rate")],
+            )
+            .unwrap();
+        assert_eq!(updated.widgets.len(), 2);
+
+        let reloaded = DashboardStore::load_from_disk(path.clone());
+        let loaded = reloaded.get(&created.id).unwrap();
+        assert_eq!(loaded.name, "Marketing view");
+        assert_eq!(loaded.widgets, updated.widgets);
+
+        assert!(reloaded.delete(&created.id));
+        assert!(!reloaded.delete(&created.id));
+        assert!(DashboardStore::load_from_disk(path).list().is_empty());
+    }
+
+    #[test]
+    fn test_update_missing_dashboard() {
+        let store = DashboardStore::default();
+        assert!(store.update("nope", "x".to_string(), vec![]).is_none());
+    }
+
+    #[test]
+    fn test_request_validation() {
+        let ok = DashboardRequest {
+            name: "Engineering".to_string(),
+            widgets: vec![Widget {
+                breakdown: Some("browsers".to_string()),
+                ..widget("pageviews")
+            }],
+        };
+        assert!(ok.validate().is_ok());
+
+        let bad_metric = DashboardRequest {
+            name: "x".to_string(),
+            widgets: vec![widget("revenue_per_moon")],
+        };
+        assert!(bad_metric.validate().is_err());
+
+        let bad_breakdown = DashboardRequest {
+            name: "x".to_string(),
+            widgets: vec![Widget {
+                breakdown: Some("shoe_size".to_string()),
+                ..widget("visitors")
+            }],
+        };
+        assert!(bad_breakdown.validate().is_err());
+
+        let no_name = DashboardRequest {
+            name: "  ".to_string(),
+            widgets: vec![],
+        };
+        assert!(no_name.validate().is_err());
+    }
+}
@@ -1,56 +1,210 @@
+use axum::extract::rejection::{JsonRejection, QueryRejection};
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use axum::Json;
+use serde::Serialize;
+use std::fmt::Write;
+
+tokio::task_local! {
+    /// ID of the request being handled, set by the server's request-ID
+    /// middleware and echoed in error bodies so users can quote it.
+    pub static REQUEST_ID: String;
+}
+
+/// One problem with a request field.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, utoipa::ToSchema)]
+pub struct FieldError {
+    /// Parameter or payload key the error refers to; `None` when the request
+    /// could not be parsed far enough to tell.
+    pub field: Option<String>,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &str, message: impl Into<String>) -> Self {
+        Self {
+            field: Some(field.to_string()),
+            message: message.into(),
+        }
+    }
+}
+
+/// JSON body of an error response.
+#[derive(Debug, Serialize, utoipa::ToSchema)]
+pub struct ErrorBody {
+    /// Human-readable description.
+    pub error: String,
+    /// Stable, machine-readable identifier, e.g. `invalid_field`.
+    pub code: &'static str,
+    /// The invalid fields of a validation error.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errors: Option<Vec<FieldError>>,
+    /// ID of the request, as in its `X-Request-ID` header.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
 
 /// API error type with HTTP status code mapping.
-#[derive(Debug)]
+///
+/// Failures from lower layers convert with `?`.  They are logged and reported
+/// to clients as a generic `500 internal_error`, never with their details.
+#[derive(Debug, thiserror::Error)]
 pub enum ApiError {
+    #[error("Bad request: {0}")]
     BadRequest(String),
+    /// One or more request fields are invalid.  `code` is a stable,
+    /// machine-readable identifier for clients.
+    #[error("Invalid request:{}", describe_fields(.errors))]
+    Validation {
+        status: StatusCode,
+        code: &'static str,
+        errors: Vec<FieldError>,
+    },
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("Not found: {0}")]
     NotFound(String),
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    #[error("Internal error: {0}")]
     Internal(String),
-    DatabaseError(duckdb::Error),
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] duckdb::Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    /// A `spawn_blocking` task panicked or was cancelled.
+    #[error("Background task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
     /// Returned (429) when the concurrent query semaphore is exhausted.
+    #[error("Too many requests: {0}")]
     TooManyRequests(String),
 }
 
-impl std::fmt::Display for ApiError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::BadRequest(msg) => write!(f, "Bad request: {msg}"),
-            Self::NotFound(msg) => write!(f, "Not found: {msg}"),
-            Self::Internal(msg) => write!(f, "Internal error: {msg}"),
-            Self::DatabaseError(e) => write!(f, "Database error: {e}"),
-            Self::TooManyRequests(msg) => write!(f, "Too many requests: {msg}"),
+/// `Display` text for a list of field errors.
+fn describe_fields(errors: &[FieldError]) -> String {
+    let mut out = String::new();
+    for e in errors {
+        if let Some(field) = &e.field {
+            let _ = write!(out, " {field}:");
         }
+        let _ = write!(out, " {}.", e.message);
     }
+    out
 }
 
-impl std::error::Error for ApiError {}
+impl ApiError {
+    /// A 400 `invalid_field` error for a single field.
+    pub fn invalid_field(field: &str, message: impl Into<String>) -> Self {
+        Self::Validation {
+            status: StatusCode::BAD_REQUEST,
+            code: "invalid_field",
+            errors: vec![FieldError::new(field, message)],
+        }
+    }
+
+    /// Machine-readable error code included in the response body.
+    pub const fn code(&self) -> &'static str {
+        match self {
+            Self::BadRequest(_) => "bad_request",
+            Self::Validation { code, .. } => code,
+            Self::Unauthorized(_) => "unauthorized",
+            Self::NotFound(_) => "not_found",
+            Self::Conflict(_) => "conflict",
+            Self::Internal(_) | Self::DatabaseError(_) | Self::Io(_) | Self::Task(_) => {
+                "internal_error"
+            }
+            Self::TooManyRequests(_) => "too_many_requests",
+        }
+    }
 
-impl IntoResponse for ApiError {
-    fn into_response(self) -> Response {
-        let (status, message) = match &self {
-            Self::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg.clone()),
-            Self::NotFound(msg) => (StatusCode::NOT_FOUND, msg.clone()),
-            Self::Internal(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg.clone()),
-            Self::DatabaseError(e) => {
-                tracing::error!(error = %e, "Database error");
+    /// Status code and JSON [`ErrorBody`] for the response.
+    ///
+    /// Every body has `error` (human-readable) and `code`; validation errors
+    /// add `errors`, a list of `{field, message}` objects.
+    pub fn status_and_body(&self) -> (StatusCode, serde_json::Value) {
+        let (status, message, errors) = match self {
+            Self::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg.clone(), None),
+            Self::Validation { status, errors, .. } => {
+                let message = errors
+                    .iter()
+                    .map(|e| e.message.as_str())
+                    .collect::<Vec<_>>()
+                    .join("; ");
+                (*status, message, Some(errors.clone()))
+            }
+            Self::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, msg.clone(), None),
+            Self::NotFound(msg) => (StatusCode::NOT_FOUND, msg.clone(), None),
+            Self::Conflict(msg) => (StatusCode::CONFLICT, msg.clone(), None),
+            Self::Internal(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg.clone(), None),
+            Self::DatabaseError(_) | Self::Io(_) | Self::Task(_) => {
+                tracing::error!(error = %self, "Request failed");
                 (
                     StatusCode::INTERNAL_SERVER_ERROR,
                     "Internal server error".to_string(),
+                    None,
                 )
             }
-            Self::TooManyRequests(msg) => (StatusCode::TOO_MANY_REQUESTS, msg.clone()),
+            Self::TooManyRequests(msg) => (StatusCode::TOO_MANY_REQUESTS, msg.clone(), None),
         };
+        let body = ErrorBody {
+            error: message,
+            code: self.code(),
+            errors,
+            request_id: None,
+        };
+        (status, serde_json::to_value(body).unwrap_or_default())
+    }
+}
 
-        let body = serde_json::json!({ "error": message });
+impl IntoResponse for ApiError {
+    /// [`Self::status_and_body`], plus the `request_id` of the request being
+    /// handled, matching its `X-Request-ID` header and log lines.
+    fn into_response(self) -> Response {
+        let (status, mut body) = self.status_and_body();
+        if let Ok(id) = REQUEST_ID.try_with(Clone::clone) {
+            body["request_id"] = id.into();
+        }
         (status, Json(body)).into_response()
     }
 }
 
-impl From<duckdb::Error> for ApiError {
-    fn from(e: duckdb::Error) -> Self {
-        Self::DatabaseError(e)
+/// The field named in a serde error such as ``missing field `site_id` ``.
+fn serde_error_field(message: &str) -> Option<String> {
+    let rest = message.split("field `").nth(1)?;
+    rest.split('`').next().map(str::to_string)
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        let message = rejection.body_text();
+        Self::Validation {
+            status: rejection.status(),
+            code: "invalid_query",
+            errors: vec![FieldError {
+                field: serde_error_field(&message),
+                message,
+            }],
+        }
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        let message = rejection.body_text();
+        let code = match &rejection {
+            JsonRejection::JsonDataError(_) => "invalid_field",
+            JsonRejection::JsonSyntaxError(_) => "malformed_json",
+            JsonRejection::MissingJsonContentType(_) => "unsupported_media_type",
+            _ => "invalid_body",
+        };
+        Self::Validation {
+            status: rejection.status(),
+            code,
+            errors: vec![FieldError {
+                field: serde_error_field(&message),
+                message,
+            }],
+        }
     }
 }
 
@@ -80,9 +234,63 @@ mod tests {
         assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
     }
 
+    #[test]
+    fn test_validation_body() {
+        let err = ApiError::invalid_field("site_id", "site_id must not be empty");
+        let (status, body) = err.status_and_body();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "invalid_field");
+        assert_eq!(body["error"], "site_id must not be empty");
+        assert_eq!(body["errors"][0]["field"], "site_id");
+    }
+
+    #[tokio::test]
+    async fn test_request_id_in_body() {
+        let response = REQUEST_ID
+            .scope("req-1".to_string(), async {
+                ApiError::NotFound("no such site".to_string()).into_response()
+            })
+            .await;
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], "not_found");
+        assert_eq!(body["request_id"], "req-1");
+
+        // Outside a request there is no ID to add.
+        let (_, body) = ApiError::NotFound("no such site".to_string()).status_and_body();
+        assert!(body.get("request_id").is_none());
+    }
+
+    #[test]
+    fn test_serde_error_field() {
+        assert_eq!(
+            serde_error_field("Failed to deserialize query string: missing field `site_id`")
+                .as_deref(),
+            Some("site_id")
+        );
+        assert!(serde_error_field("expected value at line 1 column 1").is_none());
+    }
+
     #[test]
     fn test_display() {
         let err = ApiError::BadRequest("test".to_string());
         assert_eq!(format!("{err}"), "Bad request: test");
+        let err = ApiError::invalid_field("limit", "limit must be positive");
+        assert_eq!(
+            format!("{err}"),
+            "Invalid request: limit: limit must be positive."
+        );
+    }
+
+    #[test]
+    fn test_lower_layer_errors_are_internal_and_opaque() {
+        let err = ApiError::from(std::io::Error::other("disk on fire"));
+        let (status, body) = err.status_and_body();
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body["code"], "internal_error");
+        assert_eq!(body["error"], "Internal server error");
+        assert!(std::error::Error::source(&err).is_some());
     }
 }
@@ -0,0 +1,214 @@
+//! `GET /api/admin/explain` — DuckDB query plans of a stats report.
+
+use crate::api::errors::ApiError;
+use crate::api::extract::Query;
+use crate::api::stats::{timeseries_granularity, BreakdownParams, StatsParams};
+use crate::ingest::handler::AppState;
+use crate::query::explain::ExplainedQuery;
+use crate::query::sessions::SessionSql;
+use crate::query::{breakdowns, metrics, sessions, timeseries, QueryConn};
+use axum::extract::State;
+use axum::http::Uri;
+use axum::Json;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+
+/// Stats endpoints that can be explained, relative to `/api/stats/`.
+pub const EXPLAINABLE: &[&str] = &[
+    "main",
+    "timeseries",
+    "breakdown/pages",
+    "breakdown/sources",
+    "breakdown/campaigns",
+    "breakdown/browsers",
+    "breakdown/os",
+    "breakdown/devices",
+    "breakdown/devices_models",
+    "breakdown/countries",
+    "breakdown/continents",
+    "breakdown/email_opens",
+    "events",
+    "sessions",
+    "time_on_page",
+];
+
+#[derive(Debug, Deserialize)]
+pub struct ExplainParams {
+    /// Stats endpoint, e.g. `main` or `breakdown/pages`.
+    pub endpoint: String,
+    /// Run the queries and report actual timings and row counts.
+    #[serde(default)]
+    pub analyze: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExplainResponse {
+    pub endpoint: String,
+    pub analyze: bool,
+    /// Every statement the report runs, in order.
+    pub queries: Vec<ExplainedQuery>,
+}
+
+/// A report ready to run against the database, reading sessions as the
+/// [`SessionSql`] says, and returning its result as the endpoint would serve
+/// it.
+pub type Report =
+    Box<dyn FnOnce(&QueryConn<'_>, SessionSql) -> Result<Value, duckdb::Error> + Send>;
+
+/// GET /api/admin/explain — The `EXPLAIN` (or with `analyze=true`,
+/// `EXPLAIN ANALYZE`) output of each statement a stats endpoint runs.
+///
+/// Takes the endpoint's own query parameters alongside `endpoint`.  Results
+/// are computed but not returned, and the query cache is bypassed.
+pub async fn explain_report(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<ExplainParams>,
+    uri: Uri,
+) -> Result<Json<ExplainResponse>, ApiError> {
+    let report = report(&params.endpoint, &uri)?;
+
+    let semaphore = Arc::clone(&state.query_semaphore);
+    let _permit = semaphore.try_acquire().map_err(|_| {
+        ApiError::TooManyRequests(
+            "Too many concurrent queries. Please retry in a moment.".to_string(),
+        )
+    })?;
+
+    let analyze = params.analyze;
+    let (result, queries) = tokio::task::spawn_blocking(move || {
+        let conn = state.query_conn().explained(analyze);
+        let result = report(&conn, SessionSql::Primary);
+        (result, conn.explained_queries())
+    })
+    .await?;
+    result?;
+
+    Ok(Json(ExplainResponse {
+        endpoint: params.endpoint,
+        analyze,
+        queries,
+    }))
+}
+
+/// The report behind `endpoint`, with its parameters read from `uri` and
+/// validated as the endpoint itself would.
+pub fn report(endpoint: &str, uri: &Uri) -> Result<Report, ApiError> {
+    let dimension = match endpoint {
+        "main" => {
+            let params: StatsParams = parse(uri)?;
+            let (start, end) = params.validate_and_date_range()?;
+            return Ok(Box::new(move |conn, sql| {
+                metrics::query_core_metrics(conn, &params.site_id, &start, &end, sql).map(json)
+            }));
+        }
+        "timeseries" => {
+            let params: StatsParams = parse(uri)?;
+            let (start, end) = params.validate_and_date_range()?;
+            let granularity = timeseries_granularity(&params.period);
+            return Ok(Box::new(move |conn, _| {
+                timeseries::query_timeseries(conn, &params.site_id, &start, &end, granularity)
+                    .map(json)
+            }));
+        }
+        "sessions" => {
+            let params: StatsParams = parse(uri)?;
+            let (start, end) = params.validate_and_date_range()?;
+            return Ok(Box::new(move |conn, sql| {
+                sessions::query_session_metrics(conn, &params.site_id, &start, &end, sql).map(json)
+            }));
+        }
+        "time_on_page" => {
+            let params: BreakdownParams = parse(uri)?;
+            let (start, end) = params.date_range()?;
+            return Ok(Box::new(move |conn, _| {
+                sessions::query_time_on_page(conn, &params.site_id, &start, &end, params.limit)
+                    .map(json)
+            }));
+        }
+        "breakdown/email_opens" => {
+            let params: BreakdownParams = parse(uri)?;
+            let (start, end) = params.date_range()?;
+            return Ok(Box::new(move |conn, _| {
+                breakdowns::query_email_opens(conn, &params.site_id, &start, &end, params.limit)
+                    .map(json)
+            }));
+        }
+        "events" => {
+            let params: BreakdownParams = parse(uri)?;
+            let (start, end) = params.date_range()?;
+            return Ok(Box::new(move |conn, _| {
+                breakdowns::query_custom_events(conn, &params.site_id, &start, &end, params.limit)
+                    .map(json)
+            }));
+        }
+        "breakdown/devices_models" => {
+            let params: BreakdownParams = parse(uri)?;
+            let (start, end) = params.date_range()?;
+            return Ok(Box::new(move |conn, _| {
+                breakdowns::query_device_model_breakdown(
+                    conn,
+                    &params.site_id,
+                    &start,
+                    &end,
+                    params.limit,
+                )
+                .map(json)
+            }));
+        }
+        "breakdown/continents" => {
+            let params: BreakdownParams = parse(uri)?;
+            let (start, end) = params.date_range()?;
+            return Ok(Box::new(move |conn, _| {
+                breakdowns::query_continent_breakdown(conn, &params.site_id, &start, &end).map(json)
+            }));
+        }
+        "breakdown/pages" => breakdowns::Dimension::Page,
+        "breakdown/sources" => breakdowns::Dimension::ReferrerSource,
+        "breakdown/campaigns" => breakdowns::Dimension::UtmCampaign,
+        "breakdown/browsers" => breakdowns::Dimension::Browser,
+        "breakdown/os" => breakdowns::Dimension::Os,
+        "breakdown/devices" => breakdowns::Dimension::DeviceType,
+        "breakdown/countries" => breakdowns::Dimension::CountryCode,
+        other => {
+            return Err(ApiError::invalid_field(
+                "endpoint",
+                format!(
+                    "Cannot explain '{other}'. Use one of: {}.",
+                    EXPLAINABLE.join(", ")
+                ),
+            ));
+        }
+    };
+    breakdown_report(dimension, uri)
+}
+
+/// A `breakdown/*` report grouped by `dimension`.
+fn breakdown_report(dimension: breakdowns::Dimension, uri: &Uri) -> Result<Report, ApiError> {
+    let params: BreakdownParams = parse(uri)?;
+    let (start, end) = params.date_range()?;
+    let goal = params.goal_condition()?;
+    Ok(Box::new(move |conn, _| {
+        breakdowns::query_breakdown(
+            conn,
+            &params.site_id,
+            &start,
+            &end,
+            dimension,
+            goal.as_deref(),
+            params.limit,
+        )
+        .map(json)
+    }))
+}
+
+/// A report result as JSON.
+fn json<T: Serialize>(result: T) -> Value {
+    serde_json::to_value(result).unwrap_or_default()
+}
+
+/// The endpoint's own parameters, from the same query string.
+fn parse<T: DeserializeOwned>(uri: &Uri) -> Result<T, ApiError> {
+    Ok(axum::extract::Query::try_from_uri(uri)?.0)
+}
@@ -0,0 +1,116 @@
+use crate::api::errors::ApiError;
+use crate::api::extract::Query;
+use crate::api::stats::{default_period, StatsParams};
+use crate::ingest::handler::AppState;
+use crate::query::events::{query_events_page, EventFilter};
+use axum::body::{Body, Bytes};
+use axum::extract::State;
+use axum::http::header;
+use axum::response::IntoResponse;
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// Events read per DuckDB lock acquisition.
+const EXPORT_CHUNK_EVENTS: usize = 1000;
+
+/// Chunks buffered ahead of a slow client before the reader waits.
+const EXPORT_CHUNKS_IN_FLIGHT: usize = 4;
+
+/// Query parameters for `GET /api/export/ndjson`.
+#[derive(Debug, Deserialize)]
+pub struct NdjsonExportParams {
+    pub site_id: String,
+    #[serde(default = "default_period")]
+    pub period: String,
+    pub start_date: Option<String>,
+    pub end_date: Option<String>,
+    /// Only events with this name.
+    pub event_name: Option<String>,
+    /// Only events from this ISO 3166-1 alpha-2 country.
+    pub country: Option<String>,
+}
+
+/// GET /api/export/ndjson — Stream raw events as newline-delimited JSON,
+/// oldest first.
+///
+/// Events are read in chunks, each under its own short hold of the database
+/// lock, and only a few chunks are buffered ahead of the client, so a slow
+/// consumer neither stalls ingestion nor grows memory.
+pub async fn export_ndjson(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<NdjsonExportParams>,
+) -> Result<impl IntoResponse, ApiError> {
+    let (start, end) = StatsParams {
+        site_id: params.site_id.clone(),
+        period: params.period,
+        start_date: params.start_date,
+        end_date: params.end_date,
+    }
+    .validate_and_date_range()?;
+    let country = match params.country {
+        Some(c) if c.len() == 2 && c.bytes().all(|b| b.is_ascii_alphabetic()) => {
+            Some(c.to_ascii_uppercase())
+        }
+        Some(_) => {
+            return Err(ApiError::invalid_field(
+                "country",
+                "country must be a two-letter ISO 3166-1 code",
+            ))
+        }
+        None => None,
+    };
+
+    let (tx, mut rx) =
+        tokio::sync::mpsc::channel::<Result<Bytes, std::io::Error>>(EXPORT_CHUNKS_IN_FLIGHT);
+    tokio::task::spawn_blocking(move || {
+        let filter = EventFilter {
+            site_id: &params.site_id,
+            start_date: &start,
+            end_date: &end,
+            event_name: params.event_name.as_deref(),
+            country_code: country.as_deref(),
+        };
+        let mut after: Option<String> = None;
+        loop {
+            let conn = state.query_conn();
+            let page = query_events_page(&conn, &filter, after.as_deref(), EXPORT_CHUNK_EVENTS);
+            drop(conn);
+            let page = match page {
+                Ok(page) => page,
+                Err(e) => {
+                    // The status line is already sent; abort the body so the
+                    // client sees a truncated response rather than a clean end.
+                    tracing::error!(error = %e, "NDJSON export failed");
+                    let _ = tx.blocking_send(Err(std::io::Error::other(e)));
+                    return;
+                }
+            };
+            let mut chunk = Vec::new();
+            for event in &page.events {
+                if serde_json::to_writer(&mut chunk, event).is_ok() {
+                    chunk.push(b'\n');
+                }
+            }
+            // Blocks while the client is behind; fails once it has gone away.
+            if !chunk.is_empty() && tx.blocking_send(Ok(Bytes::from(chunk))).is_err() {
+                return;
+            }
+            match page.next {
+                Some(next) => after = Some(next),
+                None => return,
+            }
+        }
+    });
+
+    let stream = futures_util::stream::poll_fn(move |cx| rx.poll_recv(cx));
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/x-ndjson"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"events.ndjson\"",
+            ),
+        ],
+        Body::from_stream(stream),
+    ))
+}
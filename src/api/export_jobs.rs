@@ -0,0 +1,418 @@
+//! Export jobs.
+//!
+//! `GET /api/stats/export` renders the file within the request, which a year
+//! or more of data can outlast.  `POST /api/exports` instead queues the same
+//! export as a job: a background task renders it into `data_dir/exports`,
+//! one job at a time, and `GET /api/exports/{id}` reports its status and,
+//! once it is ready, serves the file.
+//!
+//! Jobs are kept in memory.  A finished job and its file are removed
+//! `export_job_ttl_secs` after it finishes, and files left by an earlier run
+//! are removed at startup.
+
+use crate::api::errors::ApiError;
+use crate::api::locale::Locale;
+use crate::api::stats::{export_attachment, render_export, ExportParams};
+use crate::ingest::handler::{percent_encode, AppState};
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Most jobs kept, finished or not.
+const MAX_JOBS: usize = 100;
+
+/// Longest explicit date range of an export job, about five years.
+pub const MAX_EXPORT_JOB_DAYS: i64 = 1830;
+
+/// Where a job is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportJobStatus {
+    /// Waiting for an earlier job to finish.
+    Queued,
+    Running,
+    /// The file can be downloaded.
+    Ready,
+    Failed,
+}
+
+/// An export job, as reported by `GET /api/exports/{id}`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportJob {
+    pub id: String,
+    pub site_id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub format: String,
+    pub start_date: String,
+    pub end_date: String,
+    pub status: ExportJobStatus,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// When the job and its file are removed; set once it has finished.
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Size of the file in bytes, once ready.
+    pub size: Option<u64>,
+    /// Where to download the file, once ready.
+    pub download_url: Option<String>,
+    /// Why the job failed.
+    pub error: Option<String>,
+}
+
+/// Export jobs and the directory their files are written to.
+#[derive(Clone)]
+pub struct ExportJobs {
+    dir: PathBuf,
+    ttl: Duration,
+    jobs: Arc<Mutex<HashMap<String, ExportJob>>>,
+    /// Held by the running job, so jobs run one at a time.
+    running: Arc<tokio::sync::Semaphore>,
+}
+
+impl std::fmt::Debug for ExportJobs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExportJobs")
+            .field("dir", &self.dir)
+            .field("ttl", &self.ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ExportJobs {
+    /// Jobs writing to `dir`, kept for `ttl` after they finish.  Files left
+    /// in `dir` by an earlier run, whose jobs are gone, are removed.
+    pub fn new(dir: PathBuf, ttl: Duration) -> Self {
+        match std::fs::read_dir(&dir) {
+            Ok(entries) => {
+                for entry in entries.flatten() {
+                    if let Err(e) = std::fs::remove_file(entry.path()) {
+                        tracing::warn!(
+                            path = %entry.path().display(),
+                            error = %e,
+                            "Failed to remove old export file"
+                        );
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => tracing::warn!(
+                path = %dir.display(),
+                error = %e,
+                "Could not read exports directory"
+            ),
+        }
+        Self {
+            dir,
+            ttl,
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+            running: Arc::new(tokio::sync::Semaphore::new(1)),
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<ExportJob> {
+        self.jobs.lock().get(id).cloned()
+    }
+
+    /// The file of `job`.
+    fn path(&self, job: &ExportJob) -> PathBuf {
+        self.dir.join(format!("{}.{}", job.id, job.format))
+    }
+
+    /// Record a queued job for `params` over `start`..`end`.  Returns `None`
+    /// when [`MAX_JOBS`] are kept.
+    fn insert(&self, params: &ExportParams, start: &str, end: &str) -> Option<ExportJob> {
+        let mut jobs = self.jobs.lock();
+        if jobs.len() >= MAX_JOBS {
+            return None;
+        }
+        let job = ExportJob {
+            id: uuid::Uuid::new_v4().to_string(),
+            site_id: params.site_id.clone(),
+            kind: params.kind.clone(),
+            format: params.format.clone(),
+            start_date: start.to_string(),
+            end_date: end.to_string(),
+            status: ExportJobStatus::Queued,
+            created_at: Utc::now(),
+            finished_at: None,
+            expires_at: None,
+            size: None,
+            download_url: None,
+            error: None,
+        };
+        jobs.insert(job.id.clone(), job.clone());
+        drop(jobs);
+        Some(job)
+    }
+
+    fn set_running(&self, id: &str) {
+        if let Some(job) = self.jobs.lock().get_mut(id) {
+            job.status = ExportJobStatus::Running;
+        }
+    }
+
+    /// Mark a job ready with a file of `result` bytes, or failed.
+    fn finish(&self, id: &str, result: Result<u64, String>) {
+        let mut jobs = self.jobs.lock();
+        let Some(job) = jobs.get_mut(id) else {
+            return;
+        };
+        let now = Utc::now();
+        job.finished_at = Some(now);
+        job.expires_at = chrono::TimeDelta::from_std(self.ttl)
+            .ok()
+            .and_then(|ttl| now.checked_add_signed(ttl));
+        match result {
+            Ok(size) => {
+                job.status = ExportJobStatus::Ready;
+                job.size = Some(size);
+                job.download_url = Some(format!(
+                    "/api/exports/{}?site_id={}&download=true",
+                    percent_encode(&job.id),
+                    percent_encode(&job.site_id)
+                ));
+            }
+            Err(error) => {
+                job.status = ExportJobStatus::Failed;
+                job.error = Some(error);
+            }
+        }
+        drop(jobs);
+    }
+
+    /// Queue an export of `params` over `start`..`end`, already validated,
+    /// and run it in the background.  Returns `None` when [`MAX_JOBS`] are
+    /// kept.
+    pub fn spawn(
+        &self,
+        state: Arc<AppState>,
+        params: ExportParams,
+        start: String,
+        end: String,
+        locale: Locale,
+    ) -> Option<ExportJob> {
+        let job = self.insert(&params, &start, &end)?;
+        let jobs = self.clone();
+        let queued = job.clone();
+        tokio::spawn(async move {
+            let Ok(_permit) = jobs.running.acquire().await else {
+                return;
+            };
+            jobs.set_running(&job.id);
+            let result = match render_export(state, &params, start, end, locale).await {
+                Ok(body) => jobs.write(&job, body).await,
+                Err(e) => Err(e.to_string()),
+            };
+            match &result {
+                Ok(size) => tracing::info!(id = %job.id, size, "Export job finished"),
+                Err(error) => tracing::warn!(id = %job.id, %error, "Export job failed"),
+            }
+            jobs.finish(&job.id, result);
+        });
+        Some(queued)
+    }
+
+    /// Write `body` to the file of `job`.  Returns its size in bytes.
+    async fn write(&self, job: &ExportJob, body: String) -> Result<u64, String> {
+        let dir = self.dir.clone();
+        let path = self.path(job);
+        tokio::task::spawn_blocking(move || {
+            std::fs::create_dir_all(&dir)?;
+            std::fs::write(&path, body.as_bytes())?;
+            Ok::<_, std::io::Error>(u64::try_from(body.len()).unwrap_or(u64::MAX))
+        })
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+    }
+
+    /// Remove jobs past their expiry, and their files.
+    pub fn cleanup(&self) {
+        let now = Utc::now();
+        let expired: Vec<ExportJob> = {
+            let mut jobs = self.jobs.lock();
+            let ids: Vec<String> = jobs
+                .values()
+                .filter(|job| job.expires_at.is_some_and(|at| at <= now))
+                .map(|job| job.id.clone())
+                .collect();
+            let expired = ids.iter().filter_map(|id| jobs.remove(id)).collect();
+            drop(jobs);
+            expired
+        };
+        for job in expired {
+            let path = self.path(&job);
+            match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    tracing::warn!(
+                        path = %path.display(),
+                        error = %e,
+                        "Failed to remove export file"
+                    );
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Query parameters for `GET /api/exports/{id}`.
+#[derive(Debug, Deserialize)]
+pub struct ExportJobParams {
+    /// Site the job was created for; a job of another site is not found.
+    pub site_id: String,
+    /// Serve the file instead of the job's status.
+    #[serde(default)]
+    pub download: bool,
+}
+
+/// POST /api/exports — Queue an export job.
+///
+/// Takes the query parameters of `GET /api/stats/export`, with explicit
+/// ranges of up to [`MAX_EXPORT_JOB_DAYS`] days, and answers 202 with the
+/// queued job.
+pub async fn create_export_job(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<ExportParams>,
+) -> Result<impl IntoResponse, ApiError> {
+    let (start, end) = params.date_range_within(MAX_EXPORT_JOB_DAYS)?;
+    params.validate_output()?;
+    let locale = Locale::negotiate(params.locale.as_deref(), &headers, state.locale);
+    let job = state
+        .export_jobs
+        .spawn(Arc::clone(&state), params, start, end, locale)
+        .ok_or_else(|| {
+            ApiError::TooManyRequests(format!(
+                "Export job limit of {MAX_JOBS} reached; try again once older jobs expire"
+            ))
+        })?;
+    tracing::info!(id = %job.id, site_id = %job.site_id, kind = %job.kind, "Export job queued");
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// GET /api/exports/{id} — An export job's status, or with `download=true`
+/// its file once ready.
+pub async fn get_export_job(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(params): Query<ExportJobParams>,
+) -> Result<Response, ApiError> {
+    let job = state
+        .export_jobs
+        .get(&id)
+        .filter(|job| job.site_id == params.site_id)
+        .ok_or_else(|| ApiError::NotFound("Export job not found".to_string()))?;
+    if !params.download {
+        return Ok(Json(job).into_response());
+    }
+    if job.status != ExportJobStatus::Ready {
+        return Err(ApiError::Conflict("Export job is not ready".to_string()));
+    }
+    let path = state.export_jobs.path(&job);
+    let body = tokio::fs::read(&path).await.map_err(|e| {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            ApiError::NotFound("Export file not found".to_string())
+        } else {
+            ApiError::Io(e)
+        }
+    })?;
+    Ok(export_attachment(&job.format, "export", body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> ExportParams {
+        ExportParams {
+            site_id: "a.com".to_string(),
+            period: "30d".to_string(),
+            start_date: None,
+            end_date: None,
+            format: "csv".to_string(),
+            locale: None,
+            kind: "timeseries".to_string(),
+            limit: 100,
+        }
+    }
+
+    #[test]
+    fn test_download_url_is_encoded() {
+        let dir = tempfile::tempdir().unwrap();
+        let jobs = ExportJobs::new(dir.path().to_path_buf(), Duration::from_secs(3600));
+        let params = ExportParams {
+            site_id: "@acme&download=false#".to_string(),
+            ..params()
+        };
+        let job = jobs.insert(&params, "2024-01-01", "2025-01-01").unwrap();
+        jobs.finish(&job.id, Ok(5));
+        assert_eq!(
+            jobs.get(&job.id).unwrap().download_url.unwrap(),
+            format!(
+                "/api/exports/{}?site_id=%40acme%26download%3Dfalse%23&download=true",
+                job.id
+            )
+        );
+    }
+
+    #[test]
+    fn test_jobs_finish_and_expire() {
+        let dir = tempfile::tempdir().unwrap();
+        let exports = dir.path().join("exports");
+        std::fs::create_dir_all(&exports).unwrap();
+        std::fs::write(exports.join("left-over.csv"), "x").unwrap();
+
+        let jobs = ExportJobs::new(exports.clone(), Duration::from_secs(3600));
+        assert!(!exports.join("left-over.csv").exists());
+
+        let job = jobs.insert(&params(), "2024-01-01", "2025-01-01").unwrap();
+        assert_eq!(job.status, ExportJobStatus::Queued);
+        std::fs::write(jobs.path(&job), "date\n").unwrap();
+        jobs.finish(&job.id, Ok(5));
+        let ready = jobs.get(&job.id).unwrap();
+        assert_eq!(ready.status, ExportJobStatus::Ready);
+        assert_eq!(ready.size, Some(5));
+        assert!(ready.expires_at.unwrap() > Utc::now());
+        assert_eq!(
+            ready.download_url.unwrap(),
+            format!("/api/exports/{}?site_id=a.com&download=true", job.id)
+        );
+
+        // Not yet expired.
+        jobs.cleanup();
+        assert!(jobs.get(&job.id).is_some());
+
+        let expiring = ExportJobs::new(exports, Duration::ZERO);
+        let job = expiring
+            .insert(&params(), "2024-01-01", "2025-01-01")
+            .unwrap();
+        std::fs::write(expiring.path(&job), "date\n").unwrap();
+        expiring.finish(&job.id, Err("failed".to_string()));
+        assert_eq!(
+            expiring.get(&job.id).unwrap().status,
+            ExportJobStatus::Failed
+        );
+        expiring.cleanup();
+        assert!(expiring.get(&job.id).is_none());
+        assert!(!expiring.path(&job).exists());
+    }
+
+    #[test]
+    fn test_job_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let jobs = ExportJobs::new(dir.path().join("exports"), Duration::from_secs(60));
+        for _ in 0..MAX_JOBS {
+            assert!(jobs.insert(&params(), "2024-01-01", "2025-01-01").is_some());
+        }
+        assert!(jobs.insert(&params(), "2024-01-01", "2025-01-01").is_none());
+    }
+}
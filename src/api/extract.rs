@@ -0,0 +1,8 @@
+use crate::api::errors::ApiError;
+use axum::extract::FromRequestParts;
+
+/// `axum::extract::Query` that rejects with a structured [`ApiError`]
+/// instead of a plain-text body.
+#[derive(Debug, FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(ApiError))]
+pub struct Query<T>(pub T);
@@ -0,0 +1,593 @@
+//! Import jobs.
+//!
+//! The server-side counterpart of `mallard-metrics import`.  `POST
+//! /api/imports` takes a CSV, JSON or Parquet file as a multipart upload,
+//! stores it under `data_dir/imports` and answers with the job, whose file
+//! is then checked in the background as a dry run: row count, detected
+//! columns and what would make the import fail.  Nothing is inserted until
+//! `POST /api/imports/{id}/confirm`, after which the events are inserted in
+//! the background, in parts so queries get the connection in between, and
+//! `GET /api/imports/{id}` reports the validation and progress.
+//!
+//! Jobs are kept in memory, as export jobs are.  Uploads are removed once
+//! imported, when cut short, and with their jobs `import_job_ttl_secs` after
+//! they were validated or finished.
+
+use crate::api::errors::ApiError;
+use crate::ingest::handler::AppState;
+use crate::storage::maintenance::{
+    import_events_part, preview_import, ImportPreview, IMPORT_EXTENSIONS,
+};
+use axum::extract::multipart::{Field, MultipartError};
+use axum::extract::{Multipart, Path, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+/// Most jobs kept, finished or not.
+const MAX_JOBS: usize = 100;
+
+/// Largest upload accepted, in bytes.
+pub const MAX_UPLOAD_BYTES: usize = 1024 * 1024 * 1024;
+
+/// How long an upload may take to arrive, in place of the usual request
+/// timeout.
+pub const UPLOAD_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// Rows inserted per part of an import.
+const PART_ROWS: u64 = 100_000;
+
+/// Where a job is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportJobStatus {
+    /// Uploaded; the file is being checked.
+    Validating,
+    /// Checked without errors; waiting to be confirmed.
+    Validated,
+    /// Checked with errors; cannot be confirmed.
+    Invalid,
+    /// Confirmed; events are being inserted.
+    Importing,
+    Completed,
+    Failed,
+}
+
+/// An import job, as reported by `GET /api/imports/{id}`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportJob {
+    pub id: String,
+    /// Name of the uploaded file.
+    pub filename: String,
+    /// Size of the upload in bytes.
+    pub size: u64,
+    pub status: ImportJobStatus,
+    /// The dry-run validation of the file; unset while validating.
+    pub preview: Option<ImportPreview>,
+    /// Events inserted so far.
+    pub imported: u64,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// When the job is removed; unset while it validates or imports.
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Why the import failed.
+    pub error: Option<String>,
+}
+
+/// Import jobs and the directory their uploads are stored in.
+#[derive(Clone)]
+pub struct ImportJobs {
+    dir: PathBuf,
+    ttl: Duration,
+    jobs: Arc<Mutex<HashMap<String, ImportJob>>>,
+    /// Held by the importing job, so imports run one at a time.
+    running: Arc<tokio::sync::Semaphore>,
+}
+
+impl std::fmt::Debug for ImportJobs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ImportJobs")
+            .field("dir", &self.dir)
+            .field("ttl", &self.ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The lowercase extension of `filename`.
+fn extension(filename: &str) -> Option<String> {
+    std::path::Path::new(filename)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase)
+}
+
+impl ImportJobs {
+    /// Jobs storing uploads in `dir`, kept for `ttl` after they are
+    /// validated or finish.  Uploads left in `dir` by an earlier run, whose
+    /// jobs are gone, are removed.
+    pub fn new(dir: PathBuf, ttl: Duration) -> Self {
+        match std::fs::read_dir(&dir) {
+            Ok(entries) => {
+                for entry in entries.flatten() {
+                    if let Err(e) = std::fs::remove_file(entry.path()) {
+                        tracing::warn!(
+                            path = %entry.path().display(),
+                            error = %e,
+                            "Failed to remove old upload"
+                        );
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => tracing::warn!(
+                path = %dir.display(),
+                error = %e,
+                "Could not read imports directory"
+            ),
+        }
+        Self {
+            dir,
+            ttl,
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+            running: Arc::new(tokio::sync::Semaphore::new(1)),
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<ImportJob> {
+        self.jobs.lock().get(id).cloned()
+    }
+
+    fn is_full(&self) -> bool {
+        self.jobs.lock().len() >= MAX_JOBS
+    }
+
+    /// The upload of job `id` with `extension`.
+    fn upload_path(&self, id: &str, extension: &str) -> PathBuf {
+        self.dir.join(format!("{id}.{extension}"))
+    }
+
+    /// The upload of `job`.
+    fn path(&self, job: &ImportJob) -> PathBuf {
+        self.upload_path(&job.id, &extension(&job.filename).unwrap_or_default())
+    }
+
+    fn expiry(&self) -> Option<DateTime<Utc>> {
+        chrono::TimeDelta::from_std(self.ttl)
+            .ok()
+            .and_then(|ttl| Utc::now().checked_add_signed(ttl))
+    }
+
+    /// Record a job whose upload is being validated.  Returns `None` when
+    /// [`MAX_JOBS`] are kept.
+    fn insert(&self, id: String, filename: String, size: u64) -> Option<ImportJob> {
+        let mut jobs = self.jobs.lock();
+        if jobs.len() >= MAX_JOBS {
+            return None;
+        }
+        let job = ImportJob {
+            id,
+            filename,
+            size,
+            status: ImportJobStatus::Validating,
+            preview: None,
+            imported: 0,
+            created_at: Utc::now(),
+            finished_at: None,
+            expires_at: None,
+            error: None,
+        };
+        jobs.insert(job.id.clone(), job.clone());
+        drop(jobs);
+        Some(job)
+    }
+
+    /// Record the validation of a job: validated without errors, invalid
+    /// with them.  Returns the new status.
+    fn validated(&self, id: &str, preview: ImportPreview) -> Option<ImportJobStatus> {
+        let expires_at = self.expiry();
+        let mut jobs = self.jobs.lock();
+        let job = jobs.get_mut(id)?;
+        job.status = if preview.errors.is_empty() {
+            ImportJobStatus::Validated
+        } else {
+            ImportJobStatus::Invalid
+        };
+        job.preview = Some(preview);
+        job.expires_at = expires_at;
+        let status = job.status;
+        drop(jobs);
+        Some(status)
+    }
+
+    /// Move a validated job to importing.
+    fn confirm(&self, id: &str) -> Result<ImportJob, ApiError> {
+        let mut jobs = self.jobs.lock();
+        let job = jobs
+            .get_mut(id)
+            .ok_or_else(|| ApiError::NotFound("Import job not found".to_string()))?;
+        if job.status != ImportJobStatus::Validated {
+            return Err(ApiError::Conflict(
+                "Only a validated import can be confirmed".to_string(),
+            ));
+        }
+        job.status = ImportJobStatus::Importing;
+        job.expires_at = None;
+        let job = job.clone();
+        drop(jobs);
+        Ok(job)
+    }
+
+    fn add_imported(&self, id: &str, events: u64) {
+        if let Some(job) = self.jobs.lock().get_mut(id) {
+            job.imported += events;
+        }
+    }
+
+    /// Mark a job completed, or failed with `result`'s error.
+    fn finish(&self, id: &str, result: Result<(), String>) {
+        let expires_at = self.expiry();
+        let mut jobs = self.jobs.lock();
+        let Some(job) = jobs.get_mut(id) else {
+            return;
+        };
+        job.finished_at = Some(Utc::now());
+        job.expires_at = expires_at;
+        match result {
+            Ok(()) => job.status = ImportJobStatus::Completed,
+            Err(error) => {
+                job.status = ImportJobStatus::Failed;
+                job.error = Some(error);
+            }
+        }
+        drop(jobs);
+    }
+
+    /// Check the upload of `job`, just stored at `path`, in the background.
+    /// An invalid upload is removed.
+    fn spawn_validation(&self, state: &Arc<AppState>, job: &ImportJob, path: PathBuf) {
+        let jobs = self.clone();
+        let conn = Arc::clone(state.buffer.conn());
+        let id = job.id.clone();
+        tokio::spawn(async move {
+            let preview_path = path.clone();
+            let preview = tokio::task::spawn_blocking(move || {
+                let conn = conn.lock();
+                preview_import(&conn, &preview_path).map_err(|e| e.to_string())
+            })
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|preview| preview)
+            .unwrap_or_else(|error| ImportPreview {
+                rows: 0,
+                columns: Vec::new(),
+                ignored_columns: Vec::new(),
+                sites: Vec::new(),
+                errors: vec![error],
+            });
+            let rows = preview.rows;
+            let status = jobs.validated(&id, preview);
+            if status != Some(ImportJobStatus::Validated) {
+                remove_upload(&path);
+            }
+            tracing::info!(%id, rows, ?status, "Import validated");
+        });
+    }
+
+    /// Insert the events of confirmed `job` in the background.
+    fn spawn(&self, state: Arc<AppState>, job: ImportJob) {
+        let jobs = self.clone();
+        tokio::spawn(async move {
+            let Ok(_permit) = jobs.running.acquire().await else {
+                return;
+            };
+            let path = jobs.path(&job);
+            let result = jobs.import(&state, &job, &path).await;
+            remove_upload(&path);
+            // Cached reports of the sites may predate the new events.
+            let sites: HashSet<&str> = job
+                .preview
+                .iter()
+                .flat_map(|preview| &preview.sites)
+                .map(String::as_str)
+                .collect();
+            state.query_cache.invalidate_sites(&sites);
+            match &result {
+                Ok(()) => tracing::info!(id = %job.id, "Import job finished"),
+                Err(error) => tracing::warn!(id = %job.id, %error, "Import job failed"),
+            }
+            jobs.finish(&job.id, result);
+        });
+    }
+
+    /// Insert the events of `job` from `path`, [`PART_ROWS`] at a time.
+    async fn import(
+        &self,
+        state: &Arc<AppState>,
+        job: &ImportJob,
+        path: &std::path::Path,
+    ) -> Result<(), String> {
+        let rows = job.preview.as_ref().map_or(0, |preview| preview.rows);
+        let mut offset = 0;
+        while offset < rows {
+            let conn = Arc::clone(state.buffer.conn());
+            let path = path.to_path_buf();
+            let inserted = tokio::task::spawn_blocking(move || {
+                let conn = conn.lock();
+                import_events_part(&conn, &path, offset, PART_ROWS)
+            })
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|e| e.to_string())?;
+            if inserted == 0 {
+                break;
+            }
+            self.add_imported(&job.id, u64::try_from(inserted).unwrap_or(u64::MAX));
+            offset += PART_ROWS;
+        }
+        Ok(())
+    }
+
+    /// Remove jobs past their expiry, and their uploads.
+    pub fn cleanup(&self) {
+        let now = Utc::now();
+        let expired: Vec<ImportJob> = {
+            let mut jobs = self.jobs.lock();
+            let ids: Vec<String> = jobs
+                .values()
+                .filter(|job| job.expires_at.is_some_and(|at| at <= now))
+                .map(|job| job.id.clone())
+                .collect();
+            let expired = ids.iter().filter_map(|id| jobs.remove(id)).collect();
+            drop(jobs);
+            expired
+        };
+        for job in expired {
+            remove_upload(&self.path(&job));
+        }
+    }
+}
+
+/// Remove an upload, if it is still there.
+fn remove_upload(path: &std::path::Path) {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            tracing::warn!(path = %path.display(), error = %e, "Failed to remove upload");
+        }
+        _ => {}
+    }
+}
+
+/// An upload being stored, removed when dropped unless kept: when the
+/// request fails, times out or the client goes away.
+struct PartialUpload {
+    path: PathBuf,
+    kept: bool,
+}
+
+impl PartialUpload {
+    const fn new(path: PathBuf) -> Self {
+        Self { path, kept: false }
+    }
+
+    /// Keep the upload, now owned by its job.
+    fn keep(mut self) -> PathBuf {
+        self.kept = true;
+        std::mem::take(&mut self.path)
+    }
+}
+
+impl Drop for PartialUpload {
+    fn drop(&mut self) {
+        if !self.kept {
+            remove_upload(&self.path);
+        }
+    }
+}
+
+fn multipart_error(e: &MultipartError) -> ApiError {
+    ApiError::BadRequest(e.body_text())
+}
+
+/// Write the contents of `field` to `path`.  Returns the bytes written.
+async fn save_upload(field: &mut Field<'_>, path: &std::path::Path) -> Result<u64, ApiError> {
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut size = 0;
+    while let Some(chunk) = field.chunk().await.map_err(|e| multipart_error(&e))? {
+        file.write_all(&chunk).await?;
+        size += u64::try_from(chunk.len()).unwrap_or(u64::MAX);
+    }
+    file.flush().await?;
+    Ok(size)
+}
+
+/// POST /api/imports — Upload a file to import and validate it (requires
+/// admin).
+///
+/// Takes a multipart form with the file in a field named `file`, whose name
+/// must end in `.csv`, `.json`, `.jsonl`, `.ndjson` or `.parquet`.  Answers
+/// 202 with the job, `validating`; it becomes `validated` or `invalid`, with
+/// its preview, once the file is checked.  Nothing is imported until the job
+/// is confirmed.
+pub async fn create_import_job(
+    State(state): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, ApiError> {
+    let jobs = &state.import_jobs;
+    if jobs.is_full() {
+        return Err(ApiError::TooManyRequests(format!(
+            "Import job limit of {MAX_JOBS} reached; try again once older jobs expire"
+        )));
+    }
+    let id = uuid::Uuid::new_v4().to_string();
+    let mut upload = None;
+    while let Some(mut field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| multipart_error(&e))?
+    {
+        if field.name() != Some("file") {
+            continue;
+        }
+        let filename = field.file_name().unwrap_or_default().to_string();
+        let Some(extension) =
+            extension(&filename).filter(|ext| IMPORT_EXTENSIONS.contains(&ext.as_str()))
+        else {
+            return Err(ApiError::invalid_field(
+                "file",
+                format!(
+                    "Unsupported file '{filename}'; expected .{}",
+                    IMPORT_EXTENSIONS.join(", .")
+                ),
+            ));
+        };
+        tokio::fs::create_dir_all(&jobs.dir).await?;
+        let partial = PartialUpload::new(jobs.upload_path(&id, &extension));
+        let size = save_upload(&mut field, &partial.path).await?;
+        upload = Some((filename, partial, size));
+        break;
+    }
+    let Some((filename, partial, size)) = upload else {
+        return Err(ApiError::invalid_field(
+            "file",
+            "Upload the file in a multipart field named 'file'",
+        ));
+    };
+
+    let Some(job) = jobs.insert(id, filename, size) else {
+        return Err(ApiError::TooManyRequests(format!(
+            "Import job limit of {MAX_JOBS} reached; try again once older jobs expire"
+        )));
+    };
+    jobs.spawn_validation(&state, &job, partial.keep());
+    tracing::info!(
+        id = %job.id,
+        filename = %job.filename,
+        size = job.size,
+        "Import uploaded"
+    );
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// POST /api/imports/{id}/confirm — Start importing a validated upload
+/// (requires admin).  Answers 202 with the job.
+pub async fn confirm_import_job(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    let job = state.import_jobs.confirm(&id)?;
+    state.import_jobs.spawn(Arc::clone(&state), job.clone());
+    tracing::info!(id = %job.id, "Import confirmed");
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// GET /api/imports/{id} — An import job's validation report and progress
+/// (requires admin).
+pub async fn get_import_job(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<ImportJob>, ApiError> {
+    state
+        .import_jobs
+        .get(&id)
+        .map(Json)
+        .ok_or_else(|| ApiError::NotFound("Import job not found".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preview(errors: Vec<String>) -> ImportPreview {
+        ImportPreview {
+            rows: 2,
+            columns: vec!["site_id".to_string()],
+            ignored_columns: Vec::new(),
+            sites: vec!["a.com".to_string()],
+            errors,
+        }
+    }
+
+    #[test]
+    fn test_confirm_only_validated_jobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let jobs = ImportJobs::new(dir.path().join("imports"), Duration::from_secs(60));
+        let validating = jobs
+            .insert("a".to_string(), "events.csv".to_string(), 10)
+            .unwrap();
+        assert_eq!(validating.status, ImportJobStatus::Validating);
+        assert_eq!(validating.expires_at, None);
+        assert!(matches!(jobs.confirm("a"), Err(ApiError::Conflict(_))));
+        assert_eq!(
+            jobs.validated("a", preview(vec![])),
+            Some(ImportJobStatus::Validated)
+        );
+        assert!(jobs.get("a").unwrap().expires_at.is_some());
+        jobs.insert("b".to_string(), "events.csv".to_string(), 10)
+            .unwrap();
+        assert_eq!(
+            jobs.validated(
+                "b",
+                preview(vec!["Missing required column: site_id".to_string()])
+            ),
+            Some(ImportJobStatus::Invalid)
+        );
+
+        assert!(matches!(jobs.confirm("b"), Err(ApiError::Conflict(_))));
+        assert!(matches!(jobs.confirm("c"), Err(ApiError::NotFound(_))));
+        let importing = jobs.confirm("a").unwrap();
+        assert_eq!(importing.status, ImportJobStatus::Importing);
+        assert_eq!(importing.expires_at, None);
+        assert!(matches!(jobs.confirm("a"), Err(ApiError::Conflict(_))));
+
+        jobs.add_imported("a", 2);
+        jobs.finish("a", Ok(()));
+        let done = jobs.get("a").unwrap();
+        assert_eq!(done.status, ImportJobStatus::Completed);
+        assert_eq!(done.imported, 2);
+        assert_eq!(jobs.path(&done), dir.path().join("imports").join("a.csv"));
+    }
+
+    #[test]
+    fn test_expired_jobs_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let imports = dir.path().join("imports");
+        std::fs::create_dir_all(&imports).unwrap();
+        std::fs::write(imports.join("left-over.csv"), "x").unwrap();
+
+        let jobs = ImportJobs::new(imports.clone(), Duration::ZERO);
+        assert!(!imports.join("left-over.csv").exists());
+        let job = jobs
+            .insert("a".to_string(), "events.csv".to_string(), 1)
+            .unwrap();
+        // Not removed while it validates.
+        jobs.cleanup();
+        assert!(jobs.get("a").is_some());
+        jobs.validated("a", preview(vec![]));
+        std::fs::write(jobs.path(&job), "x").unwrap();
+        jobs.cleanup();
+        assert!(jobs.get("a").is_none());
+        assert!(!imports.join("a.csv").exists());
+    }
+
+    #[test]
+    fn test_partial_upload_removed_unless_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let dropped = dir.path().join("dropped.csv");
+        std::fs::write(&dropped, "x").unwrap();
+        drop(PartialUpload::new(dropped.clone()));
+        assert!(!dropped.exists());
+
+        let kept = dir.path().join("kept.csv");
+        std::fs::write(&kept, "x").unwrap();
+        assert_eq!(PartialUpload::new(kept.clone()).keep(), kept);
+        assert!(kept.exists());
+    }
+}
@@ -0,0 +1,311 @@
+use crate::api::extract::Query;
+use crate::ingest::handler::AppState;
+use axum::extract::State;
+use axum::http::{header, HeaderMap};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// A locale that exports can be formatted for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Locale {
+    #[default]
+    En,
+    De,
+    Es,
+    Fr,
+    It,
+    Ja,
+    Nl,
+    Pt,
+}
+
+impl Locale {
+    /// Every supported locale.
+    pub const ALL: [Self; 8] = [
+        Self::En,
+        Self::De,
+        Self::Es,
+        Self::Fr,
+        Self::It,
+        Self::Ja,
+        Self::Nl,
+        Self::Pt,
+    ];
+
+    /// BCP 47 primary language subtag.
+    pub const fn tag(self) -> &'static str {
+        match self {
+            Self::En => "en",
+            Self::De => "de",
+            Self::Es => "es",
+            Self::Fr => "fr",
+            Self::It => "it",
+            Self::Ja => "ja",
+            Self::Nl => "nl",
+            Self::Pt => "pt",
+        }
+    }
+
+    /// Match a language tag by its primary subtag, so `de-AT` selects
+    /// [`Locale::De`].
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        let primary = tag.split(['-', '_']).next()?.trim();
+        Self::ALL
+            .into_iter()
+            .find(|l| l.tag().eq_ignore_ascii_case(primary))
+    }
+
+    /// The supported locale the client prefers most in an `Accept-Language`
+    /// header, honouring `q` weights.
+    pub fn from_accept_language(header: &str) -> Option<Self> {
+        let mut ranges: Vec<(&str, f32)> = header
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let tag = parts.next()?.trim();
+                let q = parts
+                    .find_map(|p| p.trim().strip_prefix("q="))
+                    .map_or(Some(1.0), |q| q.trim().parse().ok())?;
+                (q > 0.0).then_some((tag, q))
+            })
+            .collect();
+        // Stable sort keeps the header's order among equal weights.
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranges.into_iter().find_map(|(tag, _)| Self::from_tag(tag))
+    }
+
+    /// Pick the locale for a request: an explicit `locale` parameter, then
+    /// `Accept-Language`, then the configured default.
+    pub fn negotiate(requested: Option<&str>, headers: &HeaderMap, default: Self) -> Self {
+        requested
+            .and_then(Self::from_tag)
+            .or_else(|| {
+                headers
+                    .get(header::ACCEPT_LANGUAGE)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(Self::from_accept_language)
+            })
+            .unwrap_or(default)
+    }
+
+    /// chrono format string for calendar dates.
+    pub const fn date_format(self) -> &'static str {
+        match self {
+            Self::En => "%Y-%m-%d",
+            Self::De => "%d.%m.%Y",
+            Self::Es | Self::Fr | Self::It | Self::Pt => "%d/%m/%Y",
+            Self::Ja => "%Y/%m/%d",
+            Self::Nl => "%d-%m-%Y",
+        }
+    }
+
+    pub const fn decimal_separator(self) -> char {
+        match self {
+            Self::En | Self::Ja => '.',
+            _ => ',',
+        }
+    }
+
+    pub const fn thousands_separator(self) -> char {
+        match self {
+            Self::En | Self::Ja => ',',
+            Self::Fr => '\u{202f}',
+            _ => '.',
+        }
+    }
+
+    /// Field separator for CSV files.  Spreadsheets in locales that write
+    /// decimals with a comma expect `;`.
+    pub const fn csv_delimiter(self) -> char {
+        if self.decimal_separator() == ',' {
+            ';'
+        } else {
+            ','
+        }
+    }
+
+    /// Column headers of `GET /api/stats/export` CSV files: date, visitors,
+    /// pageviews, top page, top source.
+    pub const fn export_headers(self) -> [&'static str; 5] {
+        match self {
+            Self::En => ["date", "visitors", "pageviews", "top_page", "top_source"],
+            Self::De => [
+                "Datum",
+                "Besucher",
+                "Seitenaufrufe",
+                "Top-Seite",
+                "Top-Quelle",
+            ],
+            Self::Es => [
+                "fecha",
+                "visitantes",
+                "páginas vistas",
+                "página principal",
+                "fuente principal",
+            ],
+            Self::Fr => [
+                "date",
+                "visiteurs",
+                "pages vues",
+                "page principale",
+                "source principale",
+            ],
+            Self::It => [
+                "data",
+                "visitatori",
+                "visualizzazioni di pagina",
+                "pagina principale",
+                "fonte principale",
+            ],
+            Self::Ja => [
+                "日付",
+                "訪問者数",
+                "ページビュー数",
+                "トップページ",
+                "トップ参照元",
+            ],
+            Self::Nl => [
+                "datum",
+                "bezoekers",
+                "paginaweergaven",
+                "toppagina",
+                "topbron",
+            ],
+            Self::Pt => [
+                "data",
+                "visitantes",
+                "visualizações de página",
+                "página principal",
+                "fonte principal",
+            ],
+        }
+    }
+
+    /// First-column headers of breakdown exports: page, source, country,
+    /// event; then the event count column.  Visitors and pageviews reuse
+    /// [`Locale::export_headers`].
+    pub const fn breakdown_headers(self) -> [&'static str; 5] {
+        match self {
+            Self::En => ["page", "source", "country", "event", "events"],
+            Self::De => ["Seite", "Quelle", "Land", "Ereignis", "Ereignisse"],
+            Self::Es => ["página", "fuente", "país", "evento", "eventos"],
+            Self::Fr => ["page", "source", "pays", "événement", "événements"],
+            Self::It => ["pagina", "fonte", "paese", "evento", "eventi"],
+            Self::Ja => ["ページ", "参照元", "国", "イベント", "イベント数"],
+            Self::Nl => ["pagina", "bron", "land", "gebeurtenis", "gebeurtenissen"],
+            Self::Pt => ["página", "fonte", "país", "evento", "eventos"],
+        }
+    }
+
+    /// Format a number with this locale's decimal separator and no
+    /// thousands separator, for CSV files.
+    pub fn format_decimal(self, value: f64) -> String {
+        value
+            .to_string()
+            .replace('.', &self.decimal_separator().to_string())
+    }
+
+    /// Reformat a `YYYY-MM-DD` date for this locale.  Anything else is
+    /// returned unchanged.
+    pub fn format_date(self, date: &str) -> String {
+        chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").map_or_else(
+            |_| date.to_string(),
+            |d| d.format(self.date_format()).to_string(),
+        )
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LocaleParams {
+    pub locale: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LocaleResponse {
+    /// The locale negotiated for this request.
+    pub locale: &'static str,
+    /// The server's configured default.
+    pub default_locale: &'static str,
+    pub available: Vec<&'static str>,
+    pub date_format: &'static str,
+    pub decimal_separator: char,
+    pub thousands_separator: char,
+    pub csv_delimiter: char,
+}
+
+/// GET /api/meta/locale — The locale used for exports, and its formatting
+/// conventions.
+pub async fn get_locale(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<LocaleParams>,
+) -> Json<LocaleResponse> {
+    let locale = Locale::negotiate(params.locale.as_deref(), &headers, state.locale);
+    Json(LocaleResponse {
+        locale: locale.tag(),
+        default_locale: state.locale.tag(),
+        available: Locale::ALL.iter().map(|l| l.tag()).collect(),
+        date_format: locale.date_format(),
+        decimal_separator: locale.decimal_separator(),
+        thousands_separator: locale.thousands_separator(),
+        csv_delimiter: locale.csv_delimiter(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_tag() {
+        assert_eq!(Locale::from_tag("de"), Some(Locale::De));
+        assert_eq!(Locale::from_tag("pt-BR"), Some(Locale::Pt));
+        assert_eq!(Locale::from_tag("FR_ca"), Some(Locale::Fr));
+        assert_eq!(Locale::from_tag("zz"), None);
+    }
+
+    #[test]
+    fn test_from_accept_language() {
+        assert_eq!(
+            Locale::from_accept_language("zz-ZZ, fr;q=0.5, de;q=0.8"),
+            Some(Locale::De)
+        );
+        assert_eq!(
+            Locale::from_accept_language("nl-BE,nl;q=0.9,en;q=0.8"),
+            Some(Locale::Nl)
+        );
+        assert_eq!(Locale::from_accept_language("de;q=0, *"), None);
+        assert_eq!(Locale::from_accept_language(""), None);
+    }
+
+    #[test]
+    fn test_negotiate_precedence() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(Locale::negotiate(None, &headers, Locale::Ja), Locale::Ja);
+        headers.insert(header::ACCEPT_LANGUAGE, "es-ES".parse().unwrap());
+        assert_eq!(Locale::negotiate(None, &headers, Locale::Ja), Locale::Es);
+        assert_eq!(
+            Locale::negotiate(Some("it"), &headers, Locale::Ja),
+            Locale::It
+        );
+    }
+
+    #[test]
+    fn test_format_date() {
+        assert_eq!(Locale::En.format_date("2024-03-07"), "2024-03-07");
+        assert_eq!(Locale::De.format_date("2024-03-07"), "07.03.2024");
+        assert_eq!(Locale::Fr.format_date("2024-03-07"), "07/03/2024");
+        assert_eq!(Locale::Ja.format_date("2024-03-07"), "2024/03/07");
+        assert_eq!(Locale::De.format_date("2024-03"), "2024-03");
+        assert_eq!(Locale::De.csv_delimiter(), ';');
+        assert_eq!(Locale::En.csv_delimiter(), ',');
+    }
+
+    #[test]
+    fn test_format_decimal() {
+        assert_eq!(Locale::En.format_decimal(12.5), "12.5");
+        assert_eq!(Locale::De.format_decimal(-33.33), "-33,33");
+        assert_eq!(Locale::Fr.format_decimal(100.0), "100");
+    }
+}
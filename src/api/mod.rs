@@ -1,3 +1,20 @@
+pub mod admin;
 pub mod auth;
+pub mod branding;
+pub mod capabilities;
+pub mod dashboards;
 pub mod errors;
+pub mod explain;
+pub mod export;
+pub mod export_jobs;
+pub mod extract;
+pub mod import_jobs;
+pub mod locale;
+pub mod notifications;
+pub mod openapi;
+pub mod public;
+pub mod query;
+pub mod script;
+pub mod shadow;
 pub mod stats;
+pub mod warm;
@@ -0,0 +1,373 @@
+use crate::api::errors::ApiError;
+use crate::config::NotificationChannel;
+use crate::ingest::handler::AppState;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Maximum number of channels created through the API.
+const MAX_CHANNELS: usize = 50;
+
+/// A notification channel created through the API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredChannel {
+    pub id: String,
+    pub name: String,
+    #[serde(flatten)]
+    pub channel: NotificationChannel,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+/// A channel as listed by `GET /api/notifications`, its webhook URL reduced
+/// to the host.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ChannelView {
+    /// The table name for channels of the config file.
+    pub id: String,
+    pub name: String,
+    /// `config` or `api`.
+    pub source: &'static str,
+    pub kind: String,
+    pub url: String,
+    pub events: Vec<String>,
+    pub sites: Vec<String>,
+    pub goals: Vec<String>,
+    pub templates: HashMap<String, String>,
+}
+
+impl ChannelView {
+    fn new(id: &str, name: &str, source: &'static str, channel: &NotificationChannel) -> Self {
+        Self {
+            id: id.to_string(),
+            name: name.to_string(),
+            source,
+            kind: channel.kind.clone(),
+            url: masked_url(&channel.url),
+            events: channel.events.clone(),
+            sites: channel.sites.clone(),
+            goals: channel.goals.clone(),
+            templates: channel.templates.clone(),
+        }
+    }
+}
+
+/// `https://host/…`: webhook URLs carry their secret in the path.
+fn masked_url(url: &str) -> String {
+    let host_end = url
+        .match_indices('/')
+        .nth(2)
+        .map_or(url.len(), |(index, _)| index);
+    format!("{}/…", &url[..host_end])
+}
+
+/// Thread-safe store of notification channels: those of the config file,
+/// read-only, and those created through the API.
+///
+/// Created channels persist the same way as
+/// [`crate::api::dashboards::DashboardStore`]: every mutation rewrites the
+/// JSON file at `persist_path`.
+#[derive(Clone, Default)]
+pub struct NotificationStore {
+    configured: Arc<Vec<(String, NotificationChannel)>>,
+    stored: Arc<Mutex<Vec<StoredChannel>>>,
+    persist_path: Option<Arc<std::path::PathBuf>>,
+}
+
+impl NotificationStore {
+    /// Create a store of the `configured` channels that loads created
+    /// channels from `path` and persists mutations back to the same file.
+    /// Missing file is treated as empty.
+    pub fn load_from_disk(
+        configured: Vec<(String, NotificationChannel)>,
+        path: std::path::PathBuf,
+    ) -> Self {
+        let stored = match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                serde_json::from_str::<Vec<StoredChannel>>(&contents).unwrap_or_else(|e| {
+                    tracing::warn!(
+                        path = %path.display(),
+                        error = %e,
+                        "Failed to parse notifications.json; starting with no API channels"
+                    );
+                    Vec::new()
+                })
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                tracing::warn!(
+                    path = %path.display(),
+                    error = %e,
+                    "Could not read notifications.json; starting with no API channels"
+                );
+                Vec::new()
+            }
+        };
+        Self {
+            configured: Arc::new(configured),
+            stored: Arc::new(Mutex::new(stored)),
+            persist_path: Some(Arc::new(path)),
+        }
+    }
+
+    /// Persist created channels to disk.  Logs a warning on failure.
+    fn persist(&self, stored: &[StoredChannel]) {
+        let Some(path) = &self.persist_path else {
+            return;
+        };
+        match serde_json::to_string_pretty(stored) {
+            Ok(json) => {
+                if let Err(e) = std::fs::write(path.as_ref(), json) {
+                    tracing::warn!(
+                        path = %path.display(),
+                        error = %e,
+                        "Failed to persist notification channels to disk"
+                    );
+                }
+            }
+            Err(e) => tracing::warn!(error = %e, "Failed to serialize notification channels"),
+        }
+    }
+
+    /// Every channel by ID, those of the config file first.
+    pub fn channels(&self) -> Vec<(String, NotificationChannel)> {
+        let mut channels = self.configured.as_ref().clone();
+        channels.extend(
+            self.stored
+                .lock()
+                .iter()
+                .map(|stored| (stored.id.clone(), stored.channel.clone())),
+        );
+        channels
+    }
+
+    pub fn list(&self) -> Vec<ChannelView> {
+        let mut views: Vec<ChannelView> = self
+            .configured
+            .iter()
+            .map(|(name, channel)| ChannelView::new(name, name, "config", channel))
+            .collect();
+        views.extend(
+            self.stored
+                .lock()
+                .iter()
+                .map(|s| ChannelView::new(&s.id, &s.name, "api", &s.channel)),
+        );
+        views
+    }
+
+    pub fn get(&self, id: &str) -> Option<NotificationChannel> {
+        self.channels()
+            .into_iter()
+            .find(|(channel_id, _)| channel_id == id)
+            .map(|(_, channel)| channel)
+    }
+
+    /// Whether `id` is a channel of the config file.
+    pub fn is_configured(&self, id: &str) -> bool {
+        self.configured.iter().any(|(name, _)| name == id)
+    }
+
+    /// Store a new channel.  Returns `None` when the store is full.
+    pub fn create(&self, name: String, channel: NotificationChannel) -> Option<StoredChannel> {
+        let mut stored = self.stored.lock();
+        if stored.len() >= MAX_CHANNELS {
+            return None;
+        }
+        let created = StoredChannel {
+            id: uuid::Uuid::new_v4().to_string(),
+            name,
+            channel,
+            created_at: chrono::Utc::now().naive_utc(),
+        };
+        stored.push(created.clone());
+        self.persist(&stored);
+        drop(stored);
+        Some(created)
+    }
+
+    pub fn delete(&self, id: &str) -> bool {
+        let mut stored = self.stored.lock();
+        let before = stored.len();
+        stored.retain(|s| s.id != id);
+        let removed = stored.len() != before;
+        if removed {
+            self.persist(&stored);
+        }
+        drop(stored);
+        removed
+    }
+}
+
+/// Request body for creating a channel.
+#[derive(Debug, Deserialize)]
+pub struct NotificationRequest {
+    pub name: String,
+    #[serde(flatten)]
+    pub channel: NotificationChannel,
+}
+
+impl NotificationRequest {
+    fn validate(&self) -> Result<(), ApiError> {
+        if self.name.trim().is_empty() || self.name.len() > 128 {
+            return Err(ApiError::BadRequest(
+                "Channel name must be 1-128 characters".to_string(),
+            ));
+        }
+        self.channel.validate().map_err(ApiError::BadRequest)
+    }
+}
+
+/// GET /api/notifications — List notification channels (requires admin).
+pub async fn list_notifications(State(state): State<Arc<AppState>>) -> Json<Vec<ChannelView>> {
+    Json(state.notifications.list())
+}
+
+/// POST /api/notifications — Create a notification channel (requires admin).
+pub async fn create_notification(
+    State(state): State<Arc<AppState>>,
+    Json(body): Json<NotificationRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    body.validate()?;
+    let created = state
+        .notifications
+        .create(body.name, body.channel)
+        .ok_or_else(|| {
+            ApiError::BadRequest(format!(
+                "Notification channel limit of {MAX_CHANNELS} reached; delete one first"
+            ))
+        })?;
+    tracing::info!(id = %created.id, name = %created.name, "Notification channel created");
+    Ok((
+        StatusCode::CREATED,
+        Json(ChannelView::new(
+            &created.id,
+            &created.name,
+            "api",
+            &created.channel,
+        )),
+    ))
+}
+
+/// DELETE /api/notifications/{id} — Delete a channel created through the API
+/// (requires admin).
+pub async fn delete_notification(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    if state.notifications.is_configured(&id) {
+        return Err(ApiError::BadRequest(
+            "Channel is defined in the config file; remove it there".to_string(),
+        ));
+    }
+    if state.notifications.delete(&id) {
+        tracing::info!(id = %id, "Notification channel deleted");
+        Ok(Json(serde_json::json!({"status": "deleted"})))
+    } else {
+        Err(ApiError::NotFound(
+            "Notification channel not found".to_string(),
+        ))
+    }
+}
+
+/// POST /api/notifications/{id}/test — Post a test message to a channel
+/// once, without retries (requires admin).
+pub async fn test_notification(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    let channel = state
+        .notifications
+        .get(&id)
+        .ok_or_else(|| ApiError::NotFound("Notification channel not found".to_string()))?;
+    crate::notify::post(
+        &reqwest::Client::new(),
+        &channel,
+        "Test notification from Mallard Metrics.",
+    )
+    .await
+    .map_err(|e| ApiError::BadRequest(format!("Webhook rejected the message: {e}")))?;
+    Ok(Json(serde_json::json!({"status": "sent"})))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel() -> NotificationChannel {
+        NotificationChannel {
+            kind: "discord".to_string(),
+            url: "https://discord.com/api/webhooks/1/secret".to_string(),
+            events: vec!["anomaly".to_string()],
+            ..NotificationChannel::default()
+        }
+    }
+
+    #[test]
+    fn test_store_crud_persists() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notifications.json");
+        let configured = vec![("team".to_string(), channel())];
+        let store = NotificationStore::load_from_disk(configured.clone(), path.clone());
+
+        let created = store.create("Ops".to_string(), channel()).unwrap();
+        let reloaded = NotificationStore::load_from_disk(configured.clone(), path.clone());
+        let ids: Vec<String> = reloaded.channels().into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, ["team".to_string(), created.id.clone()]);
+        assert_eq!(reloaded.get(&created.id), Some(channel()));
+        assert!(reloaded.is_configured("team"));
+        assert!(!reloaded.is_configured(&created.id));
+
+        let views = reloaded.list();
+        assert_eq!(views[1].name, "Ops");
+        assert_eq!(views[1].source, "api");
+        assert_eq!(views[1].url, "https://discord.com/…");
+
+        assert!(reloaded.delete(&created.id));
+        assert!(!reloaded.delete(&created.id));
+        assert_eq!(
+            NotificationStore::load_from_disk(configured, path)
+                .channels()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_masked_url() {
+        assert_eq!(
+            masked_url("https://hooks.slack.com/services/T0/B0/x"),
+            "https://hooks.slack.com/…"
+        );
+        assert_eq!(
+            masked_url("https://hooks.slack.com"),
+            "https://hooks.slack.com/…"
+        );
+    }
+
+    #[test]
+    fn test_request_validation() {
+        let ok = NotificationRequest {
+            name: "Ops".to_string(),
+            channel: channel(),
+        };
+        assert!(ok.validate().is_ok());
+        let no_name = NotificationRequest {
+            name: " ".to_string(),
+            channel: channel(),
+        };
+        assert!(no_name.validate().is_err());
+        let bad_kind = NotificationRequest {
+            name: "Ops".to_string(),
+            channel: NotificationChannel {
+                kind: "email".to_string(),
+                ..channel()
+            },
+        };
+        assert!(bad_kind.validate().is_err());
+    }
+}
@@ -0,0 +1,315 @@
+//! OpenAPI description of the ingestion, auth and stats API.
+//!
+//! `GET /api/openapi.json` serves an OpenAPI 3.1 document that client
+//! generators and API tools read; `GET /api/docs/` serves Swagger UI on it.
+//! Both require authentication like the stats API.
+//!
+//! The document is derived from the handlers' `#[utoipa::path]` attributes
+//! and from the `ToSchema` and `IntoParams` derives of the types they read
+//! and return, so a field added to one of them is documented with it.
+
+use crate::api::errors::ErrorBody;
+use crate::api::{auth, stats};
+use crate::ingest::handler;
+use crate::query::breakdowns;
+use axum::extract::Path;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Redirect, Response};
+use std::sync::LazyLock;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::openapi::{ContentBuilder, Ref, ResponseBuilder};
+use utoipa::{Modify, OpenApi};
+
+/// Tag of the event ingestion operations.
+pub const INGESTION: &str = "Ingestion";
+/// Tag of the login, session and API key operations.
+pub const AUTH: &str = "Authentication";
+/// Tag of the stats reports.
+pub const STATS: &str = "Stats";
+
+/// Swagger UI's file `$name` from the vendored release, extracted by
+/// `build.rs`.
+macro_rules! swagger_ui_file {
+    ($name:literal) => {
+        include_bytes!(concat!(env!("OUT_DIR"), "/swagger-ui/", $name))
+    };
+}
+
+/// Swagger UI's page and assets: name, content type and bytes.  The page
+/// loads `swagger-initializer.js`, ours, to open the document.
+const SWAGGER_UI: &[(&str, &str, &[u8])] = &[
+    (
+        "index.html",
+        "text/html; charset=utf-8",
+        swagger_ui_file!("index.html"),
+    ),
+    ("index.css", "text/css", swagger_ui_file!("index.css")),
+    (
+        "swagger-ui.css",
+        "text/css",
+        swagger_ui_file!("swagger-ui.css"),
+    ),
+    (
+        "swagger-ui-bundle.js",
+        "application/javascript",
+        swagger_ui_file!("swagger-ui-bundle.js"),
+    ),
+    (
+        "swagger-ui-standalone-preset.js",
+        "application/javascript",
+        swagger_ui_file!("swagger-ui-standalone-preset.js"),
+    ),
+    (
+        "swagger-initializer.js",
+        "application/javascript",
+        include_bytes!("docs/swagger-initializer.js"),
+    ),
+    (
+        "favicon-16x16.png",
+        "image/png",
+        swagger_ui_file!("favicon-16x16.png"),
+    ),
+    (
+        "favicon-32x32.png",
+        "image/png",
+        swagger_ui_file!("favicon-32x32.png"),
+    ),
+];
+
+/// The OpenAPI document.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Mallard Metrics API",
+        description = "Event ingestion, authentication and analytics stats."
+    ),
+    paths(
+        handler::ingest_event,
+        crate::server::pixel_track,
+        crate::server::email_pixel,
+        handler::validate_event,
+        auth::auth_setup,
+        auth::auth_login,
+        auth::auth_logout,
+        auth::auth_status,
+        auth::list_sessions,
+        auth::revoke_session_handler,
+        auth::create_api_key,
+        auth::list_api_keys,
+        auth::revoke_api_key_handler,
+        stats::get_main_stats,
+        stats::get_timeseries,
+        stats::get_compare,
+        stats::get_digest,
+        stats::get_pages_breakdown,
+        stats::get_sources_breakdown,
+        stats::get_campaigns_breakdown,
+        stats::get_browsers_breakdown,
+        stats::get_os_breakdown,
+        stats::get_devices_breakdown,
+        stats::get_device_models_breakdown,
+        stats::get_countries_breakdown,
+        stats::get_continents_breakdown,
+        stats::get_custom_breakdown,
+        stats::get_search_terms_breakdown,
+        stats::get_email_opens_breakdown,
+        stats::get_custom_events,
+        stats::get_source_conversions,
+        stats::get_sessions,
+        stats::get_time_on_page,
+        stats::get_funnel,
+        stats::get_retention,
+        stats::get_sequences,
+        stats::get_flow,
+        stats::get_paths,
+        stats::get_anomalies,
+        stats::get_export,
+        stats::get_sites,
+        stats::get_visitor_profile,
+        stats::gdpr_erase,
+    ),
+    // Rows of breakdowns requested with `others` or `attribution`, which
+    // the breakdown parameters refer to.
+    components(schemas(ErrorBody, breakdowns::BreakdownTotal, breakdowns::AttributedRow)),
+    modifiers(&SecuritySchemes, &ErrorResponses),
+    tags((name = INGESTION), (name = AUTH), (name = STATS)),
+    security(("bearer" = []), ("apiKey" = []), ("session" = []))
+)]
+pub struct ApiDoc;
+
+/// The ways to authenticate: an API key as a bearer token or in
+/// `X-API-Key`, or the dashboard's session cookie.
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .description(Some("An API key."))
+                    .build(),
+            ),
+        );
+        components.add_security_scheme(
+            "apiKey",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-API-Key"))),
+        );
+        components.add_security_scheme(
+            "session",
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new("mm_session"))),
+        );
+    }
+}
+
+/// Every operation answers failures with an [`ErrorBody`].
+struct ErrorResponses;
+
+impl Modify for ErrorResponses {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let error = ResponseBuilder::new()
+            .description("Error")
+            .content(
+                "application/json",
+                ContentBuilder::new()
+                    .schema(Some(Ref::from_schema_name("ErrorBody")))
+                    .build(),
+            )
+            .build();
+        for item in openapi.paths.paths.values_mut() {
+            let operations = [&mut item.get, &mut item.post, &mut item.delete];
+            for operation in operations.into_iter().flatten() {
+                operation
+                    .responses
+                    .responses
+                    .insert("default".to_string(), error.clone().into());
+            }
+        }
+    }
+}
+
+/// The document, serialized once.
+static SPEC: LazyLock<String> = LazyLock::new(|| ApiDoc::openapi().to_json().unwrap_or_default());
+
+/// GET /api/openapi.json — The OpenAPI document.
+pub async fn get_openapi() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "application/json")], SPEC.as_str())
+}
+
+/// GET /api/docs — Redirect to Swagger UI, whose page loads its assets
+/// relative to `/api/docs/`.
+pub async fn get_docs() -> Redirect {
+    Redirect::to("/api/docs/")
+}
+
+/// GET /api/docs/{*file} — Swagger UI's page and assets.
+pub async fn get_docs_file(file: Option<Path<String>>) -> Response {
+    let name = file
+        .as_ref()
+        .map_or("index.html", |Path(file)| file.as_str());
+    SWAGGER_UI
+        .iter()
+        .find(|(file, _, _)| *file == name)
+        .map_or_else(
+            || StatusCode::NOT_FOUND.into_response(),
+            |(_, content_type, bytes)| {
+                ([(header::CONTENT_TYPE, *content_type)], *bytes).into_response()
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn spec() -> Value {
+        serde_json::from_str(&SPEC).unwrap()
+    }
+
+    #[test]
+    fn test_spec_documents_every_route() {
+        // Routes of the ingestion, auth and stats API as declared in
+        // `build_router`, e.g. `.route("/stats/main", get(...))`.
+        let source = include_str!("../server.rs");
+        let source = &source[..source.find("#[cfg(test)]").unwrap_or(source.len())];
+        let route = regex::Regex::new(r#"\.route\(\s*"(/[^"]*)",\s*([^\n]*)"#).unwrap();
+        let method = regex::Regex::new(r"\b(get|post|put|delete)\(").unwrap();
+        let spec = spec();
+        let mut checked = 0;
+        for found in route.captures_iter(source) {
+            let path = &found[1];
+            if !["/stats/", "/auth/", "/keys", "/gdpr/", "/event", "/pixel/"]
+                .iter()
+                .any(|prefix| path.starts_with(prefix))
+            {
+                continue;
+            }
+            let documented = &spec["paths"][format!("/api{path}")];
+            for handler in method.captures_iter(&found[2]) {
+                assert!(
+                    documented.get(&handler[1]).is_some(),
+                    "{} /api{path} is not documented",
+                    handler[1].to_uppercase()
+                );
+                checked += 1;
+            }
+        }
+        assert!(checked > 30, "only {checked} routes found in server.rs");
+    }
+
+    #[test]
+    fn test_spec_references_resolve() {
+        let spec = spec();
+        let text = spec.to_string();
+        let schemas = spec["components"]["schemas"].as_object().unwrap();
+        for reference in text.split("#/components/schemas/").skip(1) {
+            let name = reference.split('"').next().unwrap();
+            assert!(schemas.contains_key(name), "missing schema {name}");
+        }
+        let main = &spec["paths"]["/api/stats/main"]["get"];
+        assert_eq!(main["parameters"][0]["name"], "site_id");
+        assert_eq!(main["parameters"][0]["required"], true);
+        assert_eq!(main["parameters"][1]["required"], false);
+        assert_eq!(
+            main["responses"]["default"]["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/ErrorBody"
+        );
+        assert_eq!(spec["paths"]["/api/event"]["post"]["security"], json!([{}]));
+        assert!(spec["components"]["securitySchemes"]["session"].is_object());
+        // Every templated path segment is a path parameter.
+        for (path, item) in spec["paths"].as_object().unwrap() {
+            for operation in item.as_object().unwrap().values() {
+                let path_params: Vec<&Value> = operation["parameters"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter(|param| param["in"] == "path")
+                    .map(|param| &param["name"])
+                    .collect();
+                for segment in path.split('/') {
+                    if let Some(name) = segment.strip_prefix('{') {
+                        let name = name.trim_end_matches('}');
+                        assert!(path_params.contains(&&json!(name)), "{path}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_swagger_ui_loads_the_spec() {
+        let page = get_docs_file(None).await;
+        assert_eq!(page.status(), StatusCode::OK);
+        let script = get_docs_file(Some(Path("swagger-initializer.js".to_string()))).await;
+        assert_eq!(script.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(script.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("/api/openapi.json"));
+        let missing = get_docs_file(Some(Path("missing.js".to_string()))).await;
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+    }
+}
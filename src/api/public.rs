@@ -0,0 +1,170 @@
+//! Public site summaries for CMS embeds.
+//!
+//! Sites with `public_summary = true` have their visitors this month and
+//! total pageviews served at `GET /api/public/summary` without
+//! authentication and with open CORS, so a WordPress or Ghost plugin can show
+//! them on the site itself.  The full stats API stays protected.  Summaries
+//! are cached for `public_summary_cache_secs`, on the server and through
+//! `Cache-Control` by browsers and CDNs, so embeds on busy pages cost about
+//! one query per site per period.
+
+use crate::api::errors::ApiError;
+use crate::api::extract::Query;
+use crate::ingest::handler::AppState;
+use crate::query::metrics;
+use axum::extract::State;
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use chrono::Datelike;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Longest JSON-P callback name accepted.
+const MAX_CALLBACK_LEN: usize = 64;
+
+/// Start of the range counted for total pageviews.
+const ALL_TIME_START: &str = "1970-01-01";
+
+#[derive(Debug, Deserialize)]
+pub struct PublicSummaryParams {
+    pub site_id: String,
+    /// Wrap the summary in a call to this function (JSON-P).
+    pub callback: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublicSummary {
+    pub site_id: String,
+    /// Month of `visitors_this_month`, as `YYYY-MM` in UTC.
+    pub month: String,
+    pub visitors_this_month: u64,
+    /// Pageviews since tracking began.
+    pub total_pageviews: u64,
+}
+
+/// Whether `callback` is safe to use as a JSON-P function name: a
+/// JavaScript identifier, optionally dotted (`MallardEmbed.render`).
+pub fn valid_callback(callback: &str) -> bool {
+    callback.len() <= MAX_CALLBACK_LEN
+        && callback.split('.').all(|part| {
+            let mut chars = part.chars();
+            chars
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic() || matches!(c, '_' | '$'))
+                && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '$'))
+        })
+}
+
+/// GET /api/public/summary — Visitors this month and total pageviews of a
+/// site with `public_summary` enabled.
+///
+/// Served as JSON, or as JSON-P with `callback`.  Other sites are reported as
+/// not found, so the endpoint does not reveal which sites are tracked.
+pub async fn get_public_summary(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<PublicSummaryParams>,
+) -> Result<Response, ApiError> {
+    if let Some(callback) = &params.callback {
+        if !valid_callback(callback) {
+            return Err(ApiError::invalid_field(
+                "callback",
+                "callback must be a JavaScript function name",
+            ));
+        }
+    }
+    if !state
+        .sites
+        .get(&params.site_id)
+        .is_some_and(|site| site.public_summary)
+    {
+        return Err(ApiError::NotFound("Site not found".to_string()));
+    }
+
+    let today = chrono::Utc::now().date_naive();
+    let month_start = today.with_day(1).unwrap_or(today);
+    let tomorrow = (today + chrono::Days::new(1)).to_string();
+    let month = month_start.format("%Y-%m").to_string();
+    let cache_key = format!("public:{}:{month}", params.site_id);
+
+    let cache = &state.public_summary_cache;
+    let body = if let Some(cached) = cache.get(&cache_key) {
+        cached
+    } else {
+        let site_id = params.site_id.clone();
+        let state2 = Arc::clone(&state);
+        let (visitors_this_month, total_pageviews) = tokio::task::spawn_blocking(move || {
+            let conn = state2.query_conn();
+            let visitors = metrics::query_unique_visitors(
+                &conn,
+                &site_id,
+                &month_start.to_string(),
+                &tomorrow,
+            )?;
+            let pageviews =
+                metrics::query_total_pageviews(&conn, &site_id, ALL_TIME_START, &tomorrow)?;
+            drop(conn);
+            Ok::<_, duckdb::Error>((visitors, pageviews))
+        })
+        .await??;
+        let summary = PublicSummary {
+            site_id: params.site_id.clone(),
+            month,
+            visitors_this_month,
+            total_pageviews,
+        };
+        let body =
+            serde_json::to_string(&summary).map_err(|e| ApiError::Internal(e.to_string()))?;
+        cache.insert(cache_key, body.clone());
+        body
+    };
+
+    let cache_control = format!("public, max-age={}", cache.ttl().as_secs());
+    Ok(match params.callback {
+        // The leading comment keeps the response from starting with
+        // attacker-chosen bytes.
+        Some(callback) => (
+            [
+                (
+                    header::CONTENT_TYPE,
+                    "application/javascript; charset=utf-8".to_string(),
+                ),
+                (header::CACHE_CONTROL, cache_control),
+            ],
+            format!("/**/{callback}({body});"),
+        )
+            .into_response(),
+        None => (
+            [
+                (header::CONTENT_TYPE, "application/json".to_string()),
+                (header::CACHE_CONTROL, cache_control),
+            ],
+            body,
+        )
+            .into_response(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_callback() {
+        for callback in ["render", "_cb1", "$", "MallardEmbed.render", "a.b_c.$d"] {
+            assert!(valid_callback(callback), "{callback}");
+        }
+        for callback in [
+            "",
+            "1cb",
+            "cb()",
+            "a..b",
+            "a.",
+            "alert(1);cb",
+            "cb-name",
+            "<script>",
+            &"a".repeat(MAX_CALLBACK_LEN + 1),
+        ] {
+            assert!(!valid_callback(callback), "{callback}");
+        }
+    }
+}
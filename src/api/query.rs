@@ -0,0 +1,653 @@
+use crate::api::errors::ApiError;
+use crate::api::stats::escape_csv_field;
+use crate::ingest::handler::AppState;
+use crate::query::{QueryConn, ALL_SITES};
+use axum::extract::State;
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use duckdb::Connection;
+use serde::Deserialize;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Maximum accepted SQL statement length in bytes.
+const MAX_SQL_LEN: usize = 10_000;
+
+/// Default and maximum number of rows returned by `/api/query`.
+const DEFAULT_ROW_LIMIT: usize = 1000;
+const MAX_ROW_LIMIT: usize = 10_000;
+
+/// Queries running longer than this are interrupted.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Keywords that may not appear anywhere in an ad-hoc query.  The statement
+/// must also start with `SELECT` or `WITH`; this list additionally rejects
+/// data-modifying CTEs and statements, such as `SUMMARIZE`, that can be
+/// nested in a query.
+const FORBIDDEN_KEYWORDS: &[&str] = &[
+    "alter",
+    "attach",
+    "call",
+    "checkpoint",
+    "copy",
+    "create",
+    "delete",
+    "describe",
+    "detach",
+    "drop",
+    "export",
+    "grant",
+    "import",
+    "insert",
+    "install",
+    "load",
+    "pragma",
+    "reset",
+    "revoke",
+    "set",
+    "show",
+    "summarize",
+    "truncate",
+    "update",
+    "use",
+    "vacuum",
+];
+
+/// Functions that read files, environment, or settings, or run nested SQL.
+const FORBIDDEN_FUNCTIONS: &[&str] = &[
+    "csv_scan",
+    "current_setting",
+    "getenv",
+    "glob",
+    "parquet_scan",
+    "query",
+    "query_table",
+    "sniff_csv",
+];
+
+/// Function name prefixes rejected for the same reasons (`read_parquet`,
+/// `read_csv_auto`, `parquet_metadata`, `duckdb_settings`, ...).
+const FORBIDDEN_PREFIXES: &[&str] = &["read_", "parquet_", "duckdb_", "pragma_"];
+
+/// The only table or view ad-hoc queries may read.
+const QUERYABLE_VIEW: &str = "events_all";
+
+/// Table functions allowed in `FROM`; they generate rows without reading
+/// anything.
+const TABLE_FUNCTIONS: &[&str] = &["generate_series", "range", "unnest"];
+
+/// Keywords ending a `FROM` clause, after which a comma no longer separates
+/// relations.
+const RELATION_CLAUSE_END: &[&str] = &[
+    "except",
+    "group",
+    "having",
+    "intersect",
+    "limit",
+    "offset",
+    "order",
+    "qualify",
+    "union",
+    "where",
+    "window",
+];
+
+/// Request body for `POST /api/query`.
+#[derive(Debug, Deserialize)]
+pub struct SqlQueryRequest {
+    pub sql: String,
+    /// `json` (default) or `csv`.
+    #[serde(default = "default_format")]
+    pub format: String,
+    /// Maximum rows to return (default 1000, max 10000).
+    pub limit: Option<usize>,
+}
+
+fn default_format() -> String {
+    "json".to_string()
+}
+
+/// JSON response body for `POST /api/query`.
+#[derive(Debug, serde::Serialize)]
+pub struct SqlQueryResponse {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<serde_json::Value>>,
+    /// True when the result had more rows than the limit.
+    pub truncated: bool,
+}
+
+/// How a result column is read back from DuckDB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColumnKind {
+    Integer,
+    Float,
+    Bool,
+    Text,
+}
+
+impl ColumnKind {
+    fn from_duckdb_type(ty: &str) -> Self {
+        match ty {
+            "TINYINT" | "SMALLINT" | "INTEGER" | "BIGINT" | "UTINYINT" | "USMALLINT"
+            | "UINTEGER" => Self::Integer,
+            "UBIGINT" | "HUGEINT" | "UHUGEINT" | "FLOAT" | "DOUBLE" => Self::Float,
+            "BOOLEAN" => Self::Bool,
+            t if t.starts_with("DECIMAL") => Self::Float,
+            _ => Self::Text,
+        }
+    }
+
+    /// SQL cast applied to the column in the outer query.
+    const fn cast_type(self) -> &'static str {
+        match self {
+            Self::Integer => "BIGINT",
+            Self::Float => "DOUBLE",
+            Self::Bool => "BOOLEAN",
+            Self::Text => "VARCHAR",
+        }
+    }
+}
+
+/// A lexical token of an ad-hoc query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    /// A keyword or identifier, lowercased; double-quoted identifiers are
+    /// unquoted.
+    Word(String),
+    /// A string literal, whose contents are not checked.
+    Literal,
+    Open,
+    Close,
+    Comma,
+}
+
+impl Token {
+    fn is(&self, word: &str) -> bool {
+        matches!(self, Self::Word(w) if w == word)
+    }
+}
+
+/// Split SQL into tokens.  Double-quoted identifiers are returned unquoted
+/// so `"read_csv"(...)` cannot sneak past the checks.
+///
+/// Returns an error for comments, unterminated literals, and `;` anywhere but
+/// the end of the statement.
+fn tokenize(sql: &str) -> Result<Vec<Token>, &'static str> {
+    let mut tokens = Vec::new();
+    let mut chars = sql.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                loop {
+                    match chars.next() {
+                        Some('\'') if chars.peek() == Some(&'\'') => {
+                            chars.next();
+                        }
+                        Some('\'') => break,
+                        Some(_) => {}
+                        None => return Err("unterminated string literal"),
+                    }
+                }
+                tokens.push(Token::Literal);
+            }
+            '"' => {
+                let mut ident = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            ident.push('"');
+                        }
+                        Some('"') => break,
+                        Some(ch) => ident.push(ch),
+                        None => return Err("unterminated quoted identifier"),
+                    }
+                }
+                tokens.push(Token::Word(ident.to_lowercase()));
+            }
+            '-' if chars.peek() == Some(&'-') => return Err("comments are not allowed"),
+            '/' if chars.peek() == Some(&'*') => return Err("comments are not allowed"),
+            ';' => {
+                if chars.any(|ch| !ch.is_whitespace()) {
+                    return Err("only a single statement is allowed");
+                }
+            }
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            ',' => tokens.push(Token::Comma),
+            c if c.is_alphabetic() || c == '_' => {
+                let mut word = String::from(c);
+                while let Some(&ch) = chars.peek() {
+                    if ch.is_alphanumeric() || ch == '_' || ch == '$' {
+                        word.push(ch);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push(Token::Word(word.to_lowercase()));
+            }
+            _ => {}
+        }
+    }
+    Ok(tokens)
+}
+
+/// One level of parentheses while scanning for relations.
+#[derive(Debug, Default)]
+#[allow(clippy::struct_excessive_bools)]
+struct Scope {
+    /// Holds a query rather than, say, the arguments of `EXTRACT(... FROM
+    /// ...)`.
+    query: bool,
+    /// Inside the `FROM` clause, where a comma starts another relation.
+    from: bool,
+    /// Inside a `WITH` list, where a comma starts another CTE.
+    with: bool,
+    /// The next word names a CTE.
+    cte_name: bool,
+}
+
+/// Check that every relation the query reads is `events_all`, a CTE or one of
+/// [`TABLE_FUNCTIONS`].  String literals and unknown names in a relation
+/// position are rejected: DuckDB reads them as files through replacement
+/// scans.
+fn check_relations(tokens: &[Token]) -> Result<(), String> {
+    let mut ctes: Vec<&str> = Vec::new();
+    let mut scopes = vec![Scope {
+        query: true,
+        ..Scope::default()
+    }];
+    let mut relation_next = false;
+    for (i, token) in tokens.iter().enumerate() {
+        let previous = i.checked_sub(1).map(|p| &tokens[p]);
+        let first_in_scope = matches!(previous, None | Some(Token::Open));
+        if relation_next && !token.is("lateral") {
+            relation_next = false;
+            match token {
+                Token::Literal => {
+                    return Err("Files cannot be queried; query events_all instead".to_string())
+                }
+                Token::Word(name) if tokens.get(i + 1) == Some(&Token::Open) => {
+                    if !TABLE_FUNCTIONS.contains(&name.as_str()) {
+                        return Err(format!("'{name}' is not allowed in ad-hoc queries"));
+                    }
+                }
+                Token::Word(name) => {
+                    if name != QUERYABLE_VIEW && !ctes.contains(&name.as_str()) {
+                        return Err(format!(
+                            "Only {QUERYABLE_VIEW} can be queried, not '{name}'"
+                        ));
+                    }
+                }
+                _ => {}
+            }
+        }
+        match token {
+            Token::Open => {
+                scopes.push(Scope::default());
+                continue;
+            }
+            Token::Close => {
+                scopes.pop();
+                continue;
+            }
+            _ => {}
+        }
+        let scope = scopes
+            .last_mut()
+            .ok_or_else(|| "Invalid sql: unbalanced parentheses".to_string())?;
+        match token {
+            Token::Comma if scope.with => scope.cte_name = true,
+            Token::Comma if scope.from => relation_next = true,
+            Token::Word(word) if scope.cte_name => {
+                if word != "recursive" {
+                    ctes.push(word.as_str());
+                    scope.cte_name = false;
+                }
+            }
+            Token::Word(word) => match word.as_str() {
+                "with" => {
+                    scope.query = true;
+                    scope.with = true;
+                    scope.cte_name = true;
+                }
+                "select" => {
+                    scope.query = true;
+                    scope.with = false;
+                    scope.from = false;
+                }
+                // `IS [NOT] DISTINCT FROM` compares values.
+                "from"
+                    if previous.is_some_and(|p| p.is("distinct"))
+                        && i >= 2
+                        && (tokens[i - 2].is("is") || tokens[i - 2].is("not")) => {}
+                "from" if scope.query || first_in_scope => {
+                    scope.query = true;
+                    scope.with = false;
+                    scope.from = true;
+                    relation_next = true;
+                }
+                "join" | "table" | "pivot" | "unpivot" => relation_next = true,
+                w if RELATION_CLAUSE_END.contains(&w) => scope.from = false,
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Check that `sql` is a single read-only `SELECT` (or `WITH ... SELECT`)
+/// statement over `events_all` and return it without any trailing semicolon.
+pub fn validate_select(sql: &str) -> Result<String, ApiError> {
+    let trimmed = sql.trim().trim_end_matches(';').trim_end();
+    if trimmed.is_empty() {
+        return Err(ApiError::BadRequest("sql must not be empty".to_string()));
+    }
+    if trimmed.len() > MAX_SQL_LEN {
+        return Err(ApiError::BadRequest(format!(
+            "sql must be at most {MAX_SQL_LEN} bytes"
+        )));
+    }
+
+    let tokens =
+        tokenize(trimmed).map_err(|e| ApiError::BadRequest(format!("Invalid sql: {e}")))?;
+    if !matches!(tokens.first(), Some(t) if t.is("select") || t.is("with")) {
+        return Err(ApiError::BadRequest(
+            "Only SELECT statements are allowed".to_string(),
+        ));
+    }
+    for token in &tokens {
+        let Token::Word(word) = token else {
+            continue;
+        };
+        if FORBIDDEN_KEYWORDS.contains(&word.as_str())
+            || FORBIDDEN_FUNCTIONS.contains(&word.as_str())
+            || FORBIDDEN_PREFIXES.iter().any(|p| word.starts_with(p))
+        {
+            return Err(ApiError::BadRequest(format!(
+                "'{word}' is not allowed in ad-hoc queries"
+            )));
+        }
+    }
+    check_relations(&tokens).map_err(ApiError::BadRequest)?;
+    Ok(trimmed.to_string())
+}
+
+/// Column names and read kinds of a validated query, via `DESCRIBE`.
+fn describe(conn: &Connection, sql: &str) -> Result<Vec<(String, ColumnKind)>, duckdb::Error> {
+    let mut stmt = conn.prepare(&format!("DESCRIBE {sql}"))?;
+    let columns = stmt
+        .query_map([], |row| {
+            let name: String = row.get(0)?;
+            let ty: String = row.get(1)?;
+            Ok((name, ColumnKind::from_duckdb_type(&ty)))
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(columns)
+}
+
+/// Run a validated query, returning at most `limit` rows plus whether more
+/// rows existed.  The query is interrupted after [`QUERY_TIMEOUT`].
+fn execute(
+    conn: &QueryConn<'_>,
+    sql: &str,
+    limit: usize,
+) -> Result<SqlQueryResponse, duckdb::Error> {
+    let started = Instant::now();
+    let columns = describe(conn, sql)?;
+    let select_list = columns
+        .iter()
+        .map(|(name, kind)| {
+            format!(
+                "CAST(\"{}\" AS {})",
+                name.replace('"', "\"\""),
+                kind.cast_type()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    let wrapped = format!("SELECT {select_list} FROM ({sql}) AS q LIMIT {}", limit + 1);
+
+    // Watchdog: interrupt the query unless it finishes (dropping `done`) first.
+    let (done, finished) = std::sync::mpsc::channel::<()>();
+    let interrupt = conn.interrupt_handle();
+    std::thread::spawn(move || {
+        if finished.recv_timeout(QUERY_TIMEOUT) == Err(std::sync::mpsc::RecvTimeoutError::Timeout) {
+            interrupt.interrupt();
+        }
+    });
+
+    // Timed as written: ad-hoc queries can read every site.
+    let mut stmt = conn.prepare_timed(&wrapped, sql, ALL_SITES, started)?;
+    let mut rows = stmt
+        .query_map([], |row| {
+            columns
+                .iter()
+                .enumerate()
+                .map(|(i, (_, kind))| {
+                    Ok(match kind {
+                        ColumnKind::Integer => row
+                            .get::<_, Option<i64>>(i)?
+                            .map_or(serde_json::Value::Null, Into::into),
+                        ColumnKind::Float => row
+                            .get::<_, Option<f64>>(i)?
+                            .map_or(serde_json::Value::Null, Into::into),
+                        ColumnKind::Bool => row
+                            .get::<_, Option<bool>>(i)?
+                            .map_or(serde_json::Value::Null, Into::into),
+                        ColumnKind::Text => row
+                            .get::<_, Option<String>>(i)?
+                            .map_or(serde_json::Value::Null, Into::into),
+                    })
+                })
+                .collect::<Result<Vec<_>, duckdb::Error>>()
+        })?
+        .collect::<Result<Vec<_>, duckdb::Error>>()?;
+    drop(done);
+
+    let truncated = rows.len() > limit;
+    rows.truncate(limit);
+    Ok(SqlQueryResponse {
+        columns: columns.into_iter().map(|(name, _)| name).collect(),
+        rows,
+        truncated,
+    })
+}
+
+/// Render a result as CSV with a header row.  Text cells are escaped with
+/// [`escape_csv_field`]; numbers and booleans are written as-is.
+fn to_csv(result: &SqlQueryResponse) -> String {
+    let mut csv = result
+        .columns
+        .iter()
+        .map(String::as_str)
+        .map(escape_csv_field)
+        .collect::<Vec<_>>()
+        .join(",");
+    csv.push('\n');
+    for row in &result.rows {
+        let line = row
+            .iter()
+            .map(|v| match v {
+                serde_json::Value::Null => String::new(),
+                serde_json::Value::String(s) => escape_csv_field(s),
+                other => other.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let _ = writeln!(csv, "{line}");
+    }
+    csv
+}
+
+/// POST /api/query — Run a read-only ad-hoc SQL query (admin only).
+///
+/// The statement must be a single `SELECT`; DDL, DML, and file-reading table
+/// functions are rejected before execution.  Results are capped at `limit`
+/// rows and the query is interrupted after ten seconds.
+pub async fn run_query(
+    State(state): State<Arc<AppState>>,
+    Json(body): Json<SqlQueryRequest>,
+) -> Result<Response, ApiError> {
+    let sql = validate_select(&body.sql)?;
+    let limit = body
+        .limit
+        .unwrap_or(DEFAULT_ROW_LIMIT)
+        .clamp(1, MAX_ROW_LIMIT);
+    if !matches!(body.format.as_str(), "json" | "csv") {
+        return Err(ApiError::BadRequest(format!(
+            "Invalid format: '{}'. Use 'json' or 'csv'.",
+            body.format
+        )));
+    }
+
+    let semaphore = Arc::clone(&state.query_semaphore);
+    let _permit = semaphore.try_acquire().map_err(|_| {
+        ApiError::TooManyRequests(
+            "Too many concurrent queries. Please retry in a moment.".to_string(),
+        )
+    })?;
+
+    let result = tokio::task::spawn_blocking(move || {
+        let conn = state.query_conn();
+        execute(&conn, &sql, limit)
+    })
+    .await?
+    .map_err(|e| {
+        // Errors here are almost always mistakes in the submitted SQL, so
+        // report them to the caller instead of masking them as a 500.
+        ApiError::BadRequest(format!("Query failed: {e}"))
+    })?;
+
+    if body.format == "csv" {
+        Ok(([(header::CONTENT_TYPE, "text/csv")], to_csv(&result)).into_response())
+    } else {
+        Ok(Json(result).into_response())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup_test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::storage::schema::init_schema(&conn).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        crate::storage::schema::setup_query_view(&conn, dir.path()).unwrap();
+        drop(dir);
+        conn
+    }
+
+    #[test]
+    fn test_validate_select_accepts_queries() {
+        assert_eq!(
+            validate_select("  SELECT pathname FROM events_all; ").unwrap(),
+            "SELECT pathname FROM events_all"
+        );
+        validate_select("WITH x AS (SELECT 1 AS n) SELECT n FROM x").unwrap();
+        // Keywords inside string literals are fine.
+        validate_select("SELECT * FROM events_all WHERE pathname = '/delete; drop'").unwrap();
+    }
+
+    #[test]
+    fn test_validate_select_rejects_writes_and_file_access() {
+        for sql in [
+            "DELETE FROM events",
+            "SELECT 1; DROP TABLE events",
+            "WITH d AS (DELETE FROM events RETURNING *) SELECT * FROM d",
+            "SELECT * FROM read_csv('/etc/passwd')",
+            "SELECT * FROM \"READ_PARQUET\"('/data/*.parquet')",
+            "SELECT getenv('MALLARD_SECRET')",
+            "SELECT * FROM glob('/')",
+            "SELECT 1 -- comment",
+            "SELECT 1 /* comment */",
+            "SELECT 'unterminated",
+            "COPY events TO 'out.csv'",
+            "",
+        ] {
+            assert!(validate_select(sql).is_err(), "accepted: {sql}");
+        }
+    }
+
+    #[test]
+    fn test_validate_select_only_reads_events_all() {
+        for sql in [
+            "SELECT * FROM '/etc/passwd.csv'",
+            "SELECT * FROM 'data/events/**/*.parquet'",
+            "SELECT * FROM \"/etc/passwd.csv\"",
+            "SELECT * FROM events_all, 'other.csv'",
+            "SELECT * FROM events_all a JOIN events_all b ON a.visitor_id = b.visitor_id, 'x.csv'",
+            "SELECT * FROM events_all JOIN 'x.parquet' USING (visitor_id)",
+            "SELECT * FROM (FROM '/etc/passwd.csv')",
+            "SELECT * FROM (SUMMARIZE '/etc/passwd.csv')",
+            "SELECT * FROM visitor_users",
+            "SELECT * FROM events",
+            "SELECT * FROM information_schema.tables",
+            "SELECT * FROM events_all WHERE visitor_id IN (SELECT visitor_id FROM visitor_users)",
+            "SELECT * FROM events_all, LATERAL (SELECT * FROM api_keys)",
+            "WITH x AS (SELECT * FROM sessions) SELECT * FROM x",
+            "SELECT * FROM sqlite_scan('db', 't')",
+        ] {
+            assert!(validate_select(sql).is_err(), "accepted: {sql}");
+        }
+        for sql in [
+            "WITH a AS (SELECT * FROM events_all), b(n) AS (SELECT 1) SELECT * FROM a, b",
+            "WITH RECURSIVE t(n) AS (SELECT 1 UNION ALL SELECT n + 1 FROM t WHERE n < 3) \
+             SELECT * FROM t",
+            "SELECT EXTRACT(hour FROM timestamp) AS h, COUNT(*) FROM events_all GROUP BY h, 1",
+            "SELECT * FROM events_all WHERE referrer IS DISTINCT FROM pathname",
+            "SELECT SUM(i) FROM range(10) t(i)",
+            "SELECT e.pathname FROM events_all AS e JOIN (SELECT 1 AS n) q ON true",
+        ] {
+            validate_select(sql).unwrap_or_else(|e| panic!("rejected: {sql}: {e:?}"));
+        }
+    }
+
+    #[test]
+    fn test_execute_types_and_limit() {
+        let conn = setup_test_db();
+        for path in ["/", "/a", "/b"] {
+            conn.execute(
+                "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+                 VALUES ('test.com', 'v1', '2024-01-15 10:00:00', 'pageview', ?)",
+                [path],
+            )
+            .unwrap();
+        }
+
+        let result = execute(
+            &QueryConn::new(&conn),
+            "SELECT pathname, COUNT(*) AS n, COUNT(*) > 0 AS seen, MIN(timestamp) AS first
+             FROM events_all GROUP BY pathname ORDER BY pathname",
+            2,
+        )
+        .unwrap();
+        assert_eq!(result.columns, vec!["pathname", "n", "seen", "first"]);
+        assert_eq!(result.rows.len(), 2);
+        assert!(result.truncated);
+        assert_eq!(
+            result.rows[0],
+            vec![
+                serde_json::json!("/"),
+                serde_json::json!(1),
+                serde_json::json!(true),
+                serde_json::json!("2024-01-15 10:00:00"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_to_csv() {
+        let result = SqlQueryResponse {
+            columns: vec!["page".to_string(), "n".to_string()],
+            rows: vec![vec![serde_json::json!("=cmd"), serde_json::json!(-3)]],
+            truncated: false,
+        };
+        assert_eq!(to_csv(&result), "\"page\",\"n\"\n\"'=cmd\",-3\n");
+    }
+}
@@ -0,0 +1,158 @@
+//! The tracking script, served at `GET /mallard.js`, and its Subresource
+//! Integrity hash, so sites can pin the script with an `integrity` attribute.
+
+use axum::extract::Query;
+use axum::http::{header, HeaderMap};
+use axum::response::IntoResponse;
+use axum::Json;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha384};
+use std::sync::LazyLock;
+
+/// The tracking script, embedded at build time.
+pub const TRACKING_SCRIPT: &str = include_str!("../../tracking/script.js");
+
+/// Path the tracking script is served at.
+pub const SCRIPT_PATH: &str = "/mallard.js";
+
+/// `data-domain` of the snippet when no `site_id` is given.
+const PLACEHOLDER_SITE: &str = "your-site.com";
+
+/// Integrity of [`TRACKING_SCRIPT`], hashed once.
+static SCRIPT_INTEGRITY: LazyLock<String> = LazyLock::new(|| integrity(TRACKING_SCRIPT.as_bytes()));
+
+/// Subresource Integrity value of `content`: `sha384-` and the base64 digest.
+pub fn integrity(content: &[u8]) -> String {
+    format!(
+        "sha384-{}",
+        base64::engine::general_purpose::STANDARD.encode(Sha384::digest(content))
+    )
+}
+
+/// GET /mallard.js — The tracking script.
+///
+/// Sent with `Access-Control-Allow-Origin: *`: browsers only check an
+/// `integrity` attribute on a script from another origin when it is loaded
+/// with `crossorigin="anonymous"`, which needs a CORS response.
+pub async fn serve_script() -> impl IntoResponse {
+    (
+        [
+            (
+                header::CONTENT_TYPE,
+                "application/javascript; charset=utf-8",
+            ),
+            (header::CACHE_CONTROL, "public, max-age=3600"),
+            (header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"),
+        ],
+        TRACKING_SCRIPT,
+    )
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScriptIntegrityQuery {
+    /// Site ID for the snippet's `data-domain`.
+    pub site_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScriptIntegrityResponse {
+    /// URL of the tracking script on this server.
+    pub src: String,
+    pub algorithm: &'static str,
+    /// Value for the `integrity` attribute.
+    pub integrity: String,
+    /// `<script>` tag loading the script pinned to `integrity`.
+    pub snippet: String,
+}
+
+/// GET /api/meta/script-integrity — SRI hash of the served tracking script
+/// and an embed snippet using it.  Public, like the script itself.
+///
+/// The hash changes whenever an upgrade changes the script, so pinned
+/// snippets must be updated along with the server.
+pub async fn get_script_integrity(
+    Query(query): Query<ScriptIntegrityQuery>,
+    headers: HeaderMap,
+) -> Json<ScriptIntegrityResponse> {
+    let src = format!("{}{SCRIPT_PATH}", request_origin(&headers));
+    let site_id = query
+        .site_id
+        .as_deref()
+        .filter(|s| !s.is_empty())
+        .unwrap_or(PLACEHOLDER_SITE);
+    let snippet = format!(
+        "<script async defer src=\"{}\" integrity=\"{}\" crossorigin=\"anonymous\" data-domain=\"{}\"></script>",
+        escape_attr(&src),
+        *SCRIPT_INTEGRITY,
+        escape_attr(site_id)
+    );
+    Json(ScriptIntegrityResponse {
+        src,
+        algorithm: "sha384",
+        integrity: SCRIPT_INTEGRITY.clone(),
+        snippet,
+    })
+}
+
+/// Origin the request was sent to, from the `Host` header.  The server does
+/// not terminate TLS itself, so the scheme is `http` unless a proxy reports
+/// `https` in `X-Forwarded-Proto`.  Empty without a `Host` header, leaving
+/// `src` relative.
+fn request_origin(headers: &HeaderMap) -> String {
+    let Some(host) = headers.get(header::HOST).and_then(|v| v.to_str().ok()) else {
+        return String::new();
+    };
+    let https = headers
+        .get("x-forwarded-proto")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').next())
+        .is_some_and(|proto| proto.trim().eq_ignore_ascii_case("https"));
+    format!("{}://{host}", if https { "https" } else { "http" })
+}
+
+/// `value` escaped for a double-quoted HTML attribute.
+fn escape_attr(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_integrity() {
+        assert_eq!(
+            integrity(b""),
+            "sha384-OLBgp1GsljhM2TJ+sbHjaiH9txEUvgdDTAzHv2P24donTt6/529l+9Ua0vFImLlb"
+        );
+    }
+
+    #[test]
+    fn test_request_origin() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(request_origin(&headers), "");
+        headers.insert(header::HOST, "localhost:8000".parse().unwrap());
+        assert_eq!(request_origin(&headers), "http://localhost:8000");
+        headers.insert("x-forwarded-proto", "https, http".parse().unwrap());
+        assert_eq!(request_origin(&headers), "https://localhost:8000");
+    }
+
+    #[test]
+    fn test_escape_attr() {
+        assert_eq!(
+            escape_attr("a.com\"><script>&"),
+            "a.com&quot;&gt;&lt;script&gt;&amp;"
+        );
+    }
+}
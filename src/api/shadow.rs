@@ -0,0 +1,107 @@
+//! Shadow runs of stats reports, and `GET /api/admin/shadow-queries`.
+//!
+//! See [`crate::query::shadow`].
+
+use crate::api::explain;
+use crate::ingest::handler::AppState;
+use crate::query::shadow::{ShadowCounts, ShadowMismatch, Variant};
+use axum::body::Body;
+use axum::extract::{Request, State};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+#[derive(Debug, Deserialize)]
+struct SiteParam {
+    site_id: String,
+}
+
+/// Middleware on the stats routes: once a shadowed report has been served
+/// successfully, run its alternative implementation in the background and
+/// compare the result with the response.
+///
+/// Skipped when every query slot is busy, so shadow runs never hold up
+/// dashboard queries.
+pub async fn shadow_report(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let uri = request.uri().clone();
+    let started = Instant::now();
+    let response = next.run(request).await;
+    let served_in = started.elapsed();
+    if !response.status().is_success() {
+        return response;
+    }
+    let path = uri.path();
+    let Some(endpoint) = path
+        .strip_prefix("/api")
+        .unwrap_or(path)
+        .strip_prefix("/stats/")
+    else {
+        return response;
+    };
+    let Some(variant) = state.shadow_queries.sample(endpoint) else {
+        return response;
+    };
+    let Ok(alternative) = explain::report(endpoint, &uri) else {
+        return response;
+    };
+    let Ok(permit) = Arc::clone(&state.query_semaphore).try_acquire_owned() else {
+        return response;
+    };
+
+    // The report is already in memory as JSON; keep a copy to compare.
+    let (parts, body) = response.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, usize::MAX).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let served = serde_json::from_slice::<Value>(&bytes);
+    let response = Response::from_parts(parts, Body::from(bytes));
+    let Ok(served) = served else {
+        return response;
+    };
+
+    let site_id = axum::extract::Query::<SiteParam>::try_from_uri(&uri)
+        .map(|q| q.0.site_id)
+        .unwrap_or_default();
+    let endpoint = endpoint.to_string();
+    tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        let conn = state.query_conn();
+        let started = Instant::now();
+        let alternative = (alternative(&conn, variant.session_sql()), started.elapsed());
+        drop(conn);
+        state
+            .shadow_queries
+            .record(&endpoint, &site_id, (&served, served_in), alternative);
+    });
+    response
+}
+
+#[derive(Debug, Serialize)]
+pub struct ShadowQueriesResponse {
+    /// Alternative implementation compared with the current one.
+    pub variant: Variant,
+    /// Counters per stats endpoint.
+    pub reports: BTreeMap<String, ShadowCounts>,
+    /// Most recent first.
+    pub mismatches: Vec<ShadowMismatch>,
+}
+
+/// GET /api/admin/shadow-queries — Comparisons of shadowed stats reports
+/// since startup, with the most recent mismatches.
+pub async fn get_shadow_queries(State(state): State<Arc<AppState>>) -> Json<ShadowQueriesResponse> {
+    Json(ShadowQueriesResponse {
+        variant: state.shadow_queries.variant(),
+        reports: state.shadow_queries.counts(),
+        mismatches: state.shadow_queries.recent_mismatches(),
+    })
+}
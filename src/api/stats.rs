@@ -1,26 +1,39 @@
 use crate::api::errors::ApiError;
+use crate::api::extract::Query;
+use crate::api::locale::Locale;
+use crate::api::openapi;
 use crate::ingest::handler::AppState;
-use crate::query::{breakdowns, flow, funnel, metrics, retention, sequences, sessions, timeseries};
-use axum::extract::{Query, State};
-use axum::http::header;
+use crate::query::sessions::SessionSql;
+use crate::query::{
+    anomalies, breakdowns, digest, flow, funnel, metrics, paths, retention, sequences, sessions,
+    timeseries, visitor, Identity, ALL_SITES, GROUP_PREFIX,
+};
+use crate::storage::parquet::{remove_partition, ParquetStorage};
+use axum::extract::{Path, State};
+use axum::http::{header, HeaderMap};
 use axum::response::IntoResponse;
 use axum::Json;
 use chrono::NaiveDate;
 use serde::{Deserialize, Serialize};
-use std::fmt::Write;
 use std::sync::Arc;
+use utoipa::{IntoParams, ToSchema};
 
 /// Query parameters for stats endpoints.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct StatsParams {
+    /// The site, `all` for every site, or `@<group>` for a site group.
     pub site_id: String,
+    /// Range ending today: `day`, `today`, `7d`, `30d` (default) or `90d`.
     #[serde(default = "default_period")]
     pub period: String,
+    /// First day, `YYYY-MM-DD`.  Overrides `period` together with `end_date`.
     pub start_date: Option<String>,
+    /// Day after the last, `YYYY-MM-DD`.
     pub end_date: Option<String>,
 }
 
-fn default_period() -> String {
+pub fn default_period() -> String {
     "30d".to_string()
 }
 
@@ -33,32 +46,60 @@ fn default_period() -> String {
 /// domain accepted at ingestion is also queryable through the stats API.
 pub fn validate_site_id(site_id: &str) -> Result<(), ApiError> {
     if site_id.is_empty() {
-        return Err(ApiError::BadRequest(
-            "site_id must not be empty".to_string(),
+        return Err(ApiError::invalid_field(
+            "site_id",
+            "site_id must not be empty",
         ));
     }
     if site_id.len() > 256 {
-        return Err(ApiError::BadRequest(
-            "site_id must be at most 256 characters".to_string(),
+        return Err(ApiError::invalid_field(
+            "site_id",
+            "site_id must be at most 256 characters",
         ));
     }
     let valid = site_id
         .chars()
         .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | ':'));
     if !valid {
-        return Err(ApiError::BadRequest(
-            "site_id may only contain alphanumeric characters, '.', '-', '_', ':'".to_string(),
+        return Err(ApiError::invalid_field(
+            "site_id",
+            "site_id may only contain alphanumeric characters, '.', '-', '_', ':'",
+        ));
+    }
+    if site_id == ALL_SITES {
+        return Err(ApiError::invalid_field(
+            "site_id",
+            format!("site_id '{ALL_SITES}' is reserved"),
         ));
     }
     Ok(())
 }
 
+/// Like [`validate_site_id`], but also accepts [`ALL_SITES`] for the
+/// cross-site roll-up and `@<group>` for a site group.  Access to both is
+/// checked by `require_auth`.
+fn validate_stats_site_id(site_id: &str) -> Result<(), ApiError> {
+    if site_id == ALL_SITES {
+        return Ok(());
+    }
+    if let Some(group) = site_id.strip_prefix(GROUP_PREFIX) {
+        if !crate::config::is_valid_group_name(group) {
+            return Err(ApiError::invalid_field(
+                "site_id",
+                format!("Invalid site group: '{group}'"),
+            ));
+        }
+        return Ok(());
+    }
+    validate_site_id(site_id)
+}
+
 impl StatsParams {
     /// Resolve the start and end dates from the period or explicit params.
     ///
     /// Also validates `site_id` format.
     pub fn validate_and_date_range(&self) -> Result<(String, String), ApiError> {
-        validate_site_id(&self.site_id)?;
+        validate_stats_site_id(&self.site_id)?;
         self.date_range()
     }
 
@@ -75,11 +116,14 @@ impl StatsParams {
         if let (Some(start_str), Some(end_str)) = (&self.start_date, &self.end_date) {
             let start_date =
                 chrono::NaiveDate::parse_from_str(start_str, "%Y-%m-%d").map_err(|_| {
-                    ApiError::BadRequest("Invalid start_date format. Use YYYY-MM-DD.".to_string())
+                    ApiError::invalid_field(
+                        "start_date",
+                        "Invalid start_date format. Use YYYY-MM-DD.",
+                    )
                 })?;
             let end_date =
                 chrono::NaiveDate::parse_from_str(end_str, "%Y-%m-%d").map_err(|_| {
-                    ApiError::BadRequest("Invalid end_date format. Use YYYY-MM-DD.".to_string())
+                    ApiError::invalid_field("end_date", "Invalid end_date format. Use YYYY-MM-DD.")
                 })?;
             let days = (end_date - start_date).num_days();
             if days < 0 {
@@ -104,10 +148,13 @@ impl StatsParams {
             "30d" => (now - chrono::Days::new(30), now + chrono::Days::new(1)),
             "90d" => (now - chrono::Days::new(90), now + chrono::Days::new(1)),
             _ => {
-                return Err(ApiError::BadRequest(format!(
-                    "Invalid period: {}. Use 'day', '7d', '30d', '90d', or provide start_date and end_date.",
-                    self.period
-                )));
+                return Err(ApiError::invalid_field(
+                    "period",
+                    format!(
+                        "Invalid period: {}. Use 'day', '7d', '30d', '90d', or provide start_date and end_date.",
+                        self.period
+                    ),
+                ));
             }
         };
 
@@ -115,13 +162,46 @@ impl StatsParams {
     }
 }
 
+/// Query cache key of `GET /api/stats/main`.
+pub fn main_cache_key(site_id: &str, start: &str, end: &str) -> String {
+    format!("main:{site_id}:{start}:{end}")
+}
+
+/// Query cache key of `GET /api/stats/timeseries`.
+pub fn timeseries_cache_key(
+    site_id: &str,
+    start: &str,
+    end: &str,
+    granularity: timeseries::Granularity,
+) -> String {
+    format!("ts:{site_id}:{start}:{end}:{granularity:?}")
+}
+
+/// Buckets of `GET /api/stats/timeseries` for `period`: hours for a single
+/// day, days otherwise.
+pub fn timeseries_granularity(period: &str) -> timeseries::Granularity {
+    if period == "day" || period == "today" {
+        timeseries::Granularity::Hour
+    } else {
+        timeseries::Granularity::Day
+    }
+}
+
 /// GET /api/stats/main — Core metrics (visitors, pageviews, bounce rate, etc.)
+#[utoipa::path(
+    get,
+    path = "/api/stats/main",
+    tag = openapi::STATS,
+    summary = "Core metrics",
+    params(StatsParams),
+    responses((status = 200, description = "The report", body = metrics::CoreMetrics)),
+)]
 pub async fn get_main_stats(
     State(state): State<Arc<AppState>>,
     Query(params): Query<StatsParams>,
 ) -> Result<Json<metrics::CoreMetrics>, ApiError> {
     let (start, end) = params.validate_and_date_range()?;
-    let cache_key = format!("main:{}:{}:{}", params.site_id, start, end);
+    let cache_key = main_cache_key(&params.site_id, &start, &end);
 
     if let Some(cached) = state.query_cache.get(&cache_key) {
         if let Ok(val) = serde_json::from_str(&cached) {
@@ -132,11 +212,10 @@ pub async fn get_main_stats(
     let site_id = params.site_id.clone();
     let state2 = Arc::clone(&state);
     let result = tokio::task::spawn_blocking(move || {
-        let conn = state2.buffer.conn().lock();
-        metrics::query_core_metrics(&conn, &site_id, &start, &end)
+        let conn = state2.query_conn();
+        metrics::query_core_metrics(&conn, &site_id, &start, &end, SessionSql::Primary)
     })
-    .await
-    .map_err(|e| ApiError::Internal(format!("Query task panicked: {e}")))??;
+    .await??;
 
     if let Ok(serialized) = serde_json::to_string(&result) {
         state.query_cache.insert(cache_key, serialized);
@@ -145,20 +224,28 @@ pub async fn get_main_stats(
 }
 
 /// GET /api/stats/timeseries — Time-bucketed visitor/pageview counts.
+#[utoipa::path(
+    get,
+    path = "/api/stats/timeseries",
+    tag = openapi::STATS,
+    summary = "Visitors and pageviews over time",
+    params(StatsParams),
+    responses(
+        (
+            status = 200,
+            description = "Hourly buckets for a single day, daily otherwise",
+            body = Vec<timeseries::TimeBucket>,
+        ),
+    ),
+)]
 pub async fn get_timeseries(
     State(state): State<Arc<AppState>>,
     Query(params): Query<StatsParams>,
 ) -> Result<Json<Vec<timeseries::TimeBucket>>, ApiError> {
     let (start, end) = params.validate_and_date_range()?;
 
-    // Choose granularity based on range
-    let granularity = if params.period == "day" || params.period == "today" {
-        timeseries::Granularity::Hour
-    } else {
-        timeseries::Granularity::Day
-    };
-
-    let cache_key = format!("ts:{}:{}:{}:{granularity:?}", params.site_id, start, end);
+    let granularity = timeseries_granularity(&params.period);
+    let cache_key = timeseries_cache_key(&params.site_id, &start, &end, granularity);
     if let Some(cached) = state.query_cache.get(&cache_key) {
         if let Ok(val) = serde_json::from_str(&cached) {
             return Ok(Json(val));
@@ -168,11 +255,10 @@ pub async fn get_timeseries(
     let site_id = params.site_id.clone();
     let state2 = Arc::clone(&state);
     let result = tokio::task::spawn_blocking(move || {
-        let conn = state2.buffer.conn().lock();
+        let conn = state2.query_conn();
         timeseries::query_timeseries(&conn, &site_id, &start, &end, granularity)
     })
-    .await
-    .map_err(|e| ApiError::Internal(format!("Query task panicked: {e}")))??;
+    .await??;
 
     if let Ok(serialized) = serde_json::to_string(&result) {
         state.query_cache.insert(cache_key, serialized);
@@ -180,16 +266,202 @@ pub async fn get_timeseries(
     Ok(Json(result))
 }
 
+/// Query parameters for the traffic digest endpoint.
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DigestParams {
+    /// The site, `all` for every site, or `@<group>` for a site group.
+    pub site_id: String,
+    /// Range ending today: `day`, `today`, `7d` (default), `30d` or `90d`.
+    #[serde(default = "default_digest_period")]
+    pub period: String,
+    /// First day, `YYYY-MM-DD`.  Overrides `period` together with `end_date`.
+    pub start_date: Option<String>,
+    /// Day after the last, `YYYY-MM-DD`.
+    pub end_date: Option<String>,
+}
+
+fn default_digest_period() -> String {
+    "7d".to_string()
+}
+
+/// GET /api/stats/digest — Pre-worded traffic summary for chat integrations.
+#[utoipa::path(
+    get,
+    path = "/api/stats/digest",
+    tag = openapi::STATS,
+    summary = "Plain-language summary of a period",
+    params(DigestParams),
+    responses((status = 200, description = "The report", body = digest::Digest)),
+)]
+pub async fn get_digest(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<DigestParams>,
+) -> Result<Json<digest::Digest>, ApiError> {
+    let (start, end) = StatsParams {
+        site_id: params.site_id.clone(),
+        period: params.period,
+        start_date: params.start_date,
+        end_date: params.end_date,
+    }
+    .validate_and_date_range()?;
+
+    let site_id = params.site_id;
+    let result = tokio::task::spawn_blocking(move || {
+        let conn = state.query_conn();
+        digest::query_digest(&conn, &site_id, &start, &end)
+    })
+    .await??;
+    Ok(Json(result))
+}
+
+/// Query parameters for the segment comparison endpoint.
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CompareParams {
+    /// The site, `all` for every site, or `@<group>` for a site group.
+    pub site_id: String,
+    /// Range ending today: `day`, `today`, `7d`, `30d` (default) or `90d`.
+    #[serde(default = "default_period")]
+    pub period: String,
+    /// First day, `YYYY-MM-DD`.  Overrides `period` together with `end_date`.
+    pub start_date: Option<String>,
+    /// Day after the last, `YYYY-MM-DD`.
+    pub end_date: Option<String>,
+    /// First segment, e.g. `device:mobile` or `source:Google;country:US`.
+    pub a: String,
+    /// Second segment, compared with the first.
+    pub b: String,
+}
+
+/// GET /api/stats/compare — Core metrics of two segments side by side.
+#[utoipa::path(
+    get,
+    path = "/api/stats/compare",
+    tag = openapi::STATS,
+    summary = "Core metrics of two segments",
+    params(CompareParams),
+    responses((status = 200, description = "The report", body = metrics::SegmentComparison)),
+)]
+pub async fn get_compare(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<CompareParams>,
+) -> Result<Json<metrics::SegmentComparison>, ApiError> {
+    let (start, end) = StatsParams {
+        site_id: params.site_id.clone(),
+        period: params.period.clone(),
+        start_date: params.start_date.clone(),
+        end_date: params.end_date.clone(),
+    }
+    .validate_and_date_range()?;
+    let a = parse_segment("a", &params.a)?;
+    let b = parse_segment("b", &params.b)?;
+
+    let site_id = params.site_id.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        let conn = state.query_conn();
+        let a = metrics::query_segment_metrics(&conn, &site_id, &start, &end, &a)?;
+        let b = metrics::query_segment_metrics(&conn, &site_id, &start, &end, &b)?;
+        drop(conn);
+        Ok::<_, duckdb::Error>(metrics::SegmentComparison::new(a, b))
+    })
+    .await??;
+    Ok(Json(result))
+}
+
+/// Dimensions a segment filter can match exactly, and their columns.
+const SEGMENT_DIMENSIONS: [(&str, &str); 8] = [
+    ("source", "referrer_source"),
+    ("utm_source", "utm_source"),
+    ("utm_medium", "utm_medium"),
+    ("utm_campaign", "utm_campaign"),
+    ("browser", "browser"),
+    ("os", "os"),
+    ("device", "device_type"),
+    ("country", "country_code"),
+];
+
+/// Most filters one segment may combine.
+const MAX_SEGMENT_FILTERS: usize = 10;
+
+/// Parse a segment into a SQL condition on events.
+///
+/// A segment is one or more `;`-separated filters that one event must all
+/// match: `dimension:value` for a dimension of [`SEGMENT_DIMENSIONS`], or a
+/// funnel step such as `page:/pricing` or `event:signup`.
+fn parse_segment(field: &str, segment: &str) -> Result<String, ApiError> {
+    let filters: Vec<&str> = segment.split(';').map(str::trim).collect();
+    if filters.len() > MAX_SEGMENT_FILTERS {
+        return Err(ApiError::invalid_field(
+            field,
+            format!("A segment may combine at most {MAX_SEGMENT_FILTERS} filters"),
+        ));
+    }
+    let invalid = |filter: &str| {
+        ApiError::invalid_field(
+            field,
+            format!(
+                "Invalid filter: '{filter}'. Use 'source:', 'utm_source:', 'utm_medium:', \
+                 'utm_campaign:', 'browser:', 'os:', 'device:' or 'country:' followed by a \
+                 value, or a funnel step such as 'page:/path' or 'event:name'."
+            ),
+        )
+    };
+    let mut conditions = Vec::with_capacity(filters.len());
+    for filter in filters {
+        let dimension = filter.split_once(':').and_then(|(name, value)| {
+            SEGMENT_DIMENSIONS
+                .iter()
+                .find(|(dimension, _)| *dimension == name)
+                .map(|(_, column)| (column, value))
+        });
+        let condition = match dimension {
+            Some((column, value)) => {
+                if value.is_empty() || value.len() > 256 {
+                    return Err(invalid(filter));
+                }
+                // Escape single quotes to prevent injection
+                format!("{column} = '{}'", value.replace('\'', "''"))
+            }
+            None => parse_funnel_step(filter).map_err(|_| invalid(filter))?,
+        };
+        conditions.push(condition);
+    }
+    Ok(conditions.join(" AND "))
+}
+
 /// Query parameters for breakdown endpoints.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct BreakdownParams {
+    /// The site, `all` for every site, or `@<group>` for a site group.
     pub site_id: String,
+    /// Range ending today: `day`, `today`, `7d`, `30d` (default) or `90d`.
     #[serde(default = "default_period")]
     pub period: String,
+    /// First day, `YYYY-MM-DD`.  Overrides `period` together with `end_date`.
     pub start_date: Option<String>,
+    /// Day after the last, `YYYY-MM-DD`.
     pub end_date: Option<String>,
+    /// Maximum rows. Default 10.
     #[serde(default = "default_limit")]
     pub limit: usize,
+    /// Fold the values beyond `limit` into an "(other)" row and answer with
+    /// `{rows, total}`, adding the breakdown's `BreakdownTotal`.
+    #[serde(default)]
+    pub others: bool,
+    /// Add each row's `previous_visitors` in the previous period of equal
+    /// length, and their percent `change`.
+    #[serde(default)]
+    pub compare: bool,
+    /// Only count visitors who reached this goal in the range, in funnel
+    /// step syntax, e.g. `event:signup` or `page:/thanks`.
+    pub goal: Option<String>,
+    /// Download the rows as a `csv` or `json` attachment.
+    pub format: Option<String>,
+    /// Locale of a CSV download; defaults to the `Accept-Language` header,
+    /// then the configured locale.
+    pub locale: Option<String>,
 }
 
 const fn default_limit() -> usize {
@@ -203,12 +475,13 @@ const fn default_limit() -> usize {
 const MAX_BREAKDOWN_LIMIT: usize = 1000;
 
 impl BreakdownParams {
-    fn date_range(&self) -> Result<(String, String), ApiError> {
-        validate_site_id(&self.site_id)?;
+    pub fn date_range(&self) -> Result<(String, String), ApiError> {
+        validate_stats_site_id(&self.site_id)?;
         if self.limit > MAX_BREAKDOWN_LIMIT {
-            return Err(ApiError::BadRequest(format!(
-                "limit must not exceed {MAX_BREAKDOWN_LIMIT}"
-            )));
+            return Err(ApiError::invalid_field(
+                "limit",
+                format!("limit must not exceed {MAX_BREAKDOWN_LIMIT}"),
+            ));
         }
         let stats_params = StatsParams {
             site_id: self.site_id.clone(),
@@ -218,153 +491,702 @@ impl BreakdownParams {
         };
         stats_params.date_range()
     }
+
+    /// The SQL condition of the `goal` filter, if any.
+    pub fn goal_condition(&self) -> Result<Option<String>, ApiError> {
+        self.goal.as_deref().map(parse_goal).transpose()
+    }
+}
+
+/// Run the breakdown by `dimension` with rows of type `R`, as a
+/// [`breakdowns::BreakdownCoverage`] when the request sets `others`, and with
+/// [`breakdowns::ComparedRow`]s when it sets `compare`.  A download holds
+/// the plain rows, with the "(other)" row but without totals.
+async fn dimension_breakdown<R>(
+    state: Arc<AppState>,
+    headers: &HeaderMap,
+    params: BreakdownParams,
+    dimension: breakdowns::Dimension,
+) -> Result<axum::response::Response, ApiError>
+where
+    R: From<breakdowns::BreakdownRow> + Serialize,
+{
+    let (start, end) = params.date_range()?;
+    let goal = params.goal_condition()?;
+    let download = Download::requested(
+        params.format.as_deref(),
+        params.locale.as_deref(),
+        headers,
+        state.locale,
+    )?;
+    let BreakdownParams {
+        site_id,
+        limit,
+        others,
+        compare,
+        ..
+    } = params;
+    let (coverage, previous) = tokio::task::spawn_blocking(move || {
+        let conn = state.query_conn();
+        let goal = goal.as_deref();
+        let coverage = if others {
+            breakdowns::query_breakdown_coverage(
+                &conn, &site_id, &start, &end, dimension, goal, limit,
+            )?
+        } else {
+            breakdowns::BreakdownCoverage {
+                rows: breakdowns::query_breakdown(
+                    &conn, &site_id, &start, &end, dimension, goal, limit,
+                )?,
+                total: breakdowns::BreakdownTotal::default(),
+            }
+        };
+        let previous = if compare {
+            Some(breakdowns::query_previous_visitors(
+                &conn, &site_id, &start, &end, dimension, goal, limit,
+            )?)
+        } else {
+            None
+        };
+        Ok::<_, duckdb::Error>((coverage, previous))
+    })
+    .await??;
+    if let Some(download) = download {
+        return download_breakdown(&download, coverage.rows, previous.as_ref());
+    }
+    let Some(previous) = previous else {
+        return Ok(breakdown_response(coverage.map(R::from), others));
+    };
+    Ok(breakdown_response(
+        coverage.map(|row| breakdowns::ComparedRow::<R>::new(row, &previous)),
+        others,
+    ))
+}
+
+/// Breakdown rows as a file, with their previous visitors and change when
+/// compared.
+fn download_breakdown(
+    download: &Download,
+    rows: Vec<breakdowns::BreakdownRow>,
+    previous: Option<&std::collections::HashMap<String, u64>>,
+) -> Result<axum::response::Response, ApiError> {
+    let Some(previous) = previous else {
+        return download.file(
+            "breakdown",
+            &["value", "visitors", "pageviews"],
+            &rows,
+            |row| {
+                vec![
+                    escape_csv_field(&row.value),
+                    row.visitors.to_string(),
+                    row.pageviews.to_string(),
+                ]
+            },
+        );
+    };
+    let rows: Vec<breakdowns::ComparedRow<breakdowns::BreakdownRow>> = rows
+        .into_iter()
+        .map(|row| breakdowns::ComparedRow::new(row, previous))
+        .collect();
+    download.file(
+        "breakdown",
+        &[
+            "value",
+            "visitors",
+            "pageviews",
+            "previous_visitors",
+            "change",
+        ],
+        &rows,
+        |row| {
+            vec![
+                escape_csv_field(&row.row.value),
+                row.row.visitors.to_string(),
+                row.row.pageviews.to_string(),
+                row.previous_visitors.to_string(),
+                row.change
+                    .map(|change| download.locale.format_decimal(change))
+                    .unwrap_or_default(),
+            ]
+        },
+    )
+}
+
+/// The breakdown, or only its rows unless the request set `others`.
+fn breakdown_response<T: Serialize>(
+    coverage: breakdowns::BreakdownCoverage<T>,
+    others: bool,
+) -> axum::response::Response {
+    if others {
+        Json(coverage).into_response()
+    } else {
+        Json(coverage.rows).into_response()
+    }
 }
 
 /// GET /api/stats/breakdown/pages — Top pages breakdown.
+#[utoipa::path(
+    get,
+    path = "/api/stats/breakdown/pages",
+    tag = openapi::STATS,
+    summary = "Top pages",
+    params(BreakdownParams),
+    responses((status = 200, description = "The rows", body = Vec<breakdowns::BreakdownRow>)),
+)]
 pub async fn get_pages_breakdown(
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
     Query(params): Query<BreakdownParams>,
-) -> Result<Json<Vec<breakdowns::BreakdownRow>>, ApiError> {
-    let (start, end) = params.date_range()?;
-    let site_id = params.site_id.clone();
+) -> Result<axum::response::Response, ApiError> {
+    dimension_breakdown::<breakdowns::BreakdownRow>(
+        state,
+        &headers,
+        params,
+        breakdowns::Dimension::Page,
+    )
+    .await
+}
+
+/// Query parameters for breakdowns that support an attribution model.
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AttributedBreakdownParams {
+    /// The site, `all` for every site, or `@<group>` for a site group.
+    pub site_id: String,
+    /// Range ending today: `day`, `today`, `7d`, `30d` (default) or `90d`.
+    #[serde(default = "default_period")]
+    pub period: String,
+    /// First day, `YYYY-MM-DD`.  Overrides `period` together with `end_date`.
+    pub start_date: Option<String>,
+    /// Day after the last, `YYYY-MM-DD`.
+    pub end_date: Option<String>,
+    /// Maximum rows. Default 10.
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+    /// `first_touch`, `last_touch` or `linear`, answering with
+    /// `AttributedRow`s.  Without it every event counts toward its own value.
+    pub attribution: Option<String>,
+    /// With `attribution`, event name credited as a conversion.  Without it,
+    /// only visitors who reached this goal are counted.
+    pub goal: Option<String>,
+    /// Fold the values beyond `limit` into an "(other)" row and add totals;
+    /// not supported with `attribution`.
+    #[serde(default)]
+    pub others: bool,
+    /// Add each row's visitors in the previous period; not supported with
+    /// `attribution`.
+    #[serde(default)]
+    pub compare: bool,
+    /// Download the rows as a `csv` or `json` attachment; not supported with
+    /// `attribution`.
+    pub format: Option<String>,
+    /// Locale of a CSV download.
+    pub locale: Option<String>,
+}
+
+/// Run a breakdown by `dimension`, attributed when the request asks for it.
+async fn attributed_breakdown(
+    state: Arc<AppState>,
+    headers: &HeaderMap,
+    params: AttributedBreakdownParams,
+    dimension: breakdowns::Dimension,
+) -> Result<axum::response::Response, ApiError> {
+    let breakdown = BreakdownParams {
+        site_id: params.site_id.clone(),
+        period: params.period.clone(),
+        start_date: params.start_date.clone(),
+        end_date: params.end_date.clone(),
+        limit: params.limit,
+        others: params.others,
+        compare: params.compare,
+        goal: params.goal.clone(),
+        format: params.format.clone(),
+        locale: params.locale.clone(),
+    };
+    let (start, end) = breakdown.date_range()?;
+    let model = match params.attribution.as_deref() {
+        None | Some("") => None,
+        Some("first_touch") => Some(breakdowns::Attribution::FirstTouch),
+        Some("last_touch") => Some(breakdowns::Attribution::LastTouch),
+        Some("linear") => Some(breakdowns::Attribution::Linear),
+        Some(other) => {
+            return Err(ApiError::invalid_field(
+                "attribution",
+                format!(
+                    "Invalid attribution: '{other}'. Use 'first_touch', 'last_touch' or 'linear'."
+                ),
+            ));
+        }
+    };
+
+    let Some(model) = model else {
+        return dimension_breakdown::<breakdowns::BreakdownRow>(
+            state, headers, breakdown, dimension,
+        )
+        .await;
+    };
+    if let Some(goal) = &params.goal {
+        if goal.is_empty() || goal.len() > 256 {
+            return Err(ApiError::invalid_field("goal", "Invalid event name"));
+        }
+    }
+    for (field, set) in [
+        ("others", params.others),
+        ("compare", params.compare),
+        ("format", params.format.is_some()),
+    ] {
+        if set {
+            return Err(ApiError::invalid_field(
+                field,
+                format!("{field} is not supported with an attribution model"),
+            ));
+        }
+    }
+    let site_id = params.site_id;
     let limit = params.limit;
+    let goal = params.goal;
     let result = tokio::task::spawn_blocking(move || {
-        let conn = state.buffer.conn().lock();
-        breakdowns::query_breakdown(
+        let conn = state.query_conn();
+        breakdowns::query_attributed_breakdown(
             &conn,
             &site_id,
             &start,
             &end,
-            breakdowns::Dimension::Page,
+            dimension,
+            breakdowns::AttributionQuery {
+                model,
+                goal: goal.as_deref(),
+            },
             limit,
         )
     })
-    .await
-    .map_err(|e| ApiError::Internal(format!("Query task panicked: {e}")))??;
-    Ok(Json(result))
+    .await??;
+    Ok(Json(result).into_response())
 }
 
 /// GET /api/stats/breakdown/sources — Top referrer sources breakdown.
+#[utoipa::path(
+    get,
+    path = "/api/stats/breakdown/sources",
+    tag = openapi::STATS,
+    summary = "Top referrer sources",
+    params(AttributedBreakdownParams),
+    responses((status = 200, description = "The rows", body = Vec<breakdowns::BreakdownRow>)),
+)]
 pub async fn get_sources_breakdown(
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<AttributedBreakdownParams>,
+) -> Result<axum::response::Response, ApiError> {
+    attributed_breakdown(
+        state,
+        &headers,
+        params,
+        breakdowns::Dimension::ReferrerSource,
+    )
+    .await
+}
+
+/// GET /api/stats/breakdown/campaigns — Top UTM campaigns breakdown.
+#[utoipa::path(
+    get,
+    path = "/api/stats/breakdown/campaigns",
+    tag = openapi::STATS,
+    summary = "Top UTM campaigns",
+    params(AttributedBreakdownParams),
+    responses((status = 200, description = "The rows", body = Vec<breakdowns::BreakdownRow>)),
+)]
+pub async fn get_campaigns_breakdown(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<AttributedBreakdownParams>,
+) -> Result<axum::response::Response, ApiError> {
+    attributed_breakdown(state, &headers, params, breakdowns::Dimension::UtmCampaign).await
+}
+
+/// GET /api/stats/breakdown/browsers — Browser breakdown.
+#[utoipa::path(
+    get,
+    path = "/api/stats/breakdown/browsers",
+    tag = openapi::STATS,
+    summary = "Browsers",
+    params(BreakdownParams),
+    responses((status = 200, description = "The rows", body = Vec<breakdowns::BreakdownRow>)),
+)]
+pub async fn get_browsers_breakdown(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
     Query(params): Query<BreakdownParams>,
-) -> Result<Json<Vec<breakdowns::BreakdownRow>>, ApiError> {
+) -> Result<axum::response::Response, ApiError> {
+    dimension_breakdown::<breakdowns::BreakdownRow>(
+        state,
+        &headers,
+        params,
+        breakdowns::Dimension::Browser,
+    )
+    .await
+}
+
+/// GET /api/stats/breakdown/os — OS breakdown.
+#[utoipa::path(
+    get,
+    path = "/api/stats/breakdown/os",
+    tag = openapi::STATS,
+    summary = "Operating systems",
+    params(BreakdownParams),
+    responses((status = 200, description = "The rows", body = Vec<breakdowns::BreakdownRow>)),
+)]
+pub async fn get_os_breakdown(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<BreakdownParams>,
+) -> Result<axum::response::Response, ApiError> {
+    dimension_breakdown::<breakdowns::BreakdownRow>(
+        state,
+        &headers,
+        params,
+        breakdowns::Dimension::Os,
+    )
+    .await
+}
+
+/// GET /api/stats/breakdown/devices — Device type breakdown.
+#[utoipa::path(
+    get,
+    path = "/api/stats/breakdown/devices",
+    tag = openapi::STATS,
+    summary = "Device types",
+    params(BreakdownParams),
+    responses((status = 200, description = "The rows", body = Vec<breakdowns::BreakdownRow>)),
+)]
+pub async fn get_devices_breakdown(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<BreakdownParams>,
+) -> Result<axum::response::Response, ApiError> {
+    dimension_breakdown::<breakdowns::BreakdownRow>(
+        state,
+        &headers,
+        params,
+        breakdowns::Dimension::DeviceType,
+    )
+    .await
+}
+
+/// GET /api/stats/breakdown/devices_models — Mobile device brand and model
+/// breakdown.
+#[utoipa::path(
+    get,
+    path = "/api/stats/breakdown/devices_models",
+    tag = openapi::STATS,
+    summary = "Mobile device models",
+    params(BreakdownParams),
+    responses((status = 200, description = "The rows", body = Vec<breakdowns::DeviceModelRow>)),
+)]
+pub async fn get_device_models_breakdown(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<BreakdownParams>,
+) -> Result<Json<Vec<breakdowns::DeviceModelRow>>, ApiError> {
     let (start, end) = params.date_range()?;
     let site_id = params.site_id.clone();
     let limit = params.limit;
     let result = tokio::task::spawn_blocking(move || {
-        let conn = state.buffer.conn().lock();
-        breakdowns::query_breakdown(
-            &conn,
-            &site_id,
-            &start,
-            &end,
-            breakdowns::Dimension::ReferrerSource,
-            limit,
-        )
+        let conn = state.query_conn();
+        breakdowns::query_device_model_breakdown(&conn, &site_id, &start, &end, limit)
     })
-    .await
-    .map_err(|e| ApiError::Internal(format!("Query task panicked: {e}")))??;
+    .await??;
     Ok(Json(result))
 }
 
-/// GET /api/stats/breakdown/browsers — Browser breakdown.
-pub async fn get_browsers_breakdown(
+/// GET /api/stats/breakdown/countries — Country breakdown, with each
+/// country's name, flag and continent.
+#[utoipa::path(
+    get,
+    path = "/api/stats/breakdown/countries",
+    tag = openapi::STATS,
+    summary = "Countries",
+    params(BreakdownParams),
+    responses((status = 200, description = "The rows", body = Vec<breakdowns::CountryRow>)),
+)]
+pub async fn get_countries_breakdown(
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
     Query(params): Query<BreakdownParams>,
-) -> Result<Json<Vec<breakdowns::BreakdownRow>>, ApiError> {
+) -> Result<axum::response::Response, ApiError> {
+    dimension_breakdown::<breakdowns::CountryRow>(
+        state,
+        &headers,
+        params,
+        breakdowns::Dimension::CountryCode,
+    )
+    .await
+}
+
+/// GET /api/stats/breakdown/continents — Visitors per continent.
+#[utoipa::path(
+    get,
+    path = "/api/stats/breakdown/continents",
+    tag = openapi::STATS,
+    summary = "Continents",
+    params(BreakdownParams),
+    responses((status = 200, description = "The rows", body = Vec<breakdowns::ContinentRow>)),
+)]
+pub async fn get_continents_breakdown(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<BreakdownParams>,
+) -> Result<Json<Vec<breakdowns::ContinentRow>>, ApiError> {
     let (start, end) = params.date_range()?;
     let site_id = params.site_id.clone();
-    let limit = params.limit;
     let result = tokio::task::spawn_blocking(move || {
-        let conn = state.buffer.conn().lock();
-        breakdowns::query_breakdown(
-            &conn,
-            &site_id,
-            &start,
-            &end,
-            breakdowns::Dimension::Browser,
-            limit,
-        )
+        let conn = state.query_conn();
+        breakdowns::query_continent_breakdown(&conn, &site_id, &start, &end)
     })
-    .await
-    .map_err(|e| ApiError::Internal(format!("Query task panicked: {e}")))??;
+    .await??;
     Ok(Json(result))
 }
 
-/// GET /api/stats/breakdown/os — OS breakdown.
-pub async fn get_os_breakdown(
+/// GET /api/stats/breakdown/search_terms — Top site-search terms.
+#[utoipa::path(
+    get,
+    path = "/api/stats/breakdown/search_terms",
+    tag = openapi::STATS,
+    summary = "Search engine keywords",
+    params(BreakdownParams),
+    responses((status = 200, description = "The rows", body = Vec<breakdowns::BreakdownRow>)),
+)]
+pub async fn get_search_terms_breakdown(
     State(state): State<Arc<AppState>>,
     Query(params): Query<BreakdownParams>,
 ) -> Result<Json<Vec<breakdowns::BreakdownRow>>, ApiError> {
     let (start, end) = params.date_range()?;
+    let search_param = state
+        .sites
+        .get(&params.site_id)
+        .and_then(|site| site.search_param.clone())
+        .ok_or_else(|| {
+            ApiError::NotFound("No search_param is configured for this site".to_string())
+        })?;
     let site_id = params.site_id.clone();
     let limit = params.limit;
     let result = tokio::task::spawn_blocking(move || {
-        let conn = state.buffer.conn().lock();
-        breakdowns::query_breakdown(
-            &conn,
-            &site_id,
-            &start,
-            &end,
-            breakdowns::Dimension::Os,
-            limit,
-        )
+        let conn = state.query_conn();
+        breakdowns::query_search_terms(&conn, &site_id, &start, &end, &search_param, limit)
     })
-    .await
-    .map_err(|e| ApiError::Internal(format!("Query task panicked: {e}")))??;
+    .await??;
     Ok(Json(result))
 }
 
-/// GET /api/stats/breakdown/devices — Device type breakdown.
-pub async fn get_devices_breakdown(
+/// GET /api/stats/breakdown/email_opens — Email opens per campaign.
+#[utoipa::path(
+    get,
+    path = "/api/stats/breakdown/email_opens",
+    tag = openapi::STATS,
+    summary = "Email opens per campaign",
+    params(BreakdownParams),
+    responses((status = 200, description = "The rows", body = Vec<breakdowns::EmailOpenRow>)),
+)]
+pub async fn get_email_opens_breakdown(
     State(state): State<Arc<AppState>>,
     Query(params): Query<BreakdownParams>,
-) -> Result<Json<Vec<breakdowns::BreakdownRow>>, ApiError> {
+) -> Result<Json<Vec<breakdowns::EmailOpenRow>>, ApiError> {
     let (start, end) = params.date_range()?;
     let site_id = params.site_id.clone();
     let limit = params.limit;
     let result = tokio::task::spawn_blocking(move || {
-        let conn = state.buffer.conn().lock();
-        breakdowns::query_breakdown(
-            &conn,
-            &site_id,
-            &start,
-            &end,
-            breakdowns::Dimension::DeviceType,
-            limit,
-        )
+        let conn = state.query_conn();
+        breakdowns::query_email_opens(&conn, &site_id, &start, &end, limit)
     })
-    .await
-    .map_err(|e| ApiError::Internal(format!("Query task panicked: {e}")))??;
+    .await??;
     Ok(Json(result))
 }
 
-/// GET /api/stats/breakdown/countries — Country breakdown.
-pub async fn get_countries_breakdown(
+/// GET /api/stats/events — Custom event names with their counts and the
+/// property keys they are sent with.
+#[utoipa::path(
+    get,
+    path = "/api/stats/events",
+    tag = openapi::STATS,
+    summary = "Custom events with the props keys they are sent with",
+    params(BreakdownParams),
+    responses((status = 200, description = "The events", body = Vec<breakdowns::CustomEventRow>)),
+)]
+pub async fn get_custom_events(
     State(state): State<Arc<AppState>>,
     Query(params): Query<BreakdownParams>,
-) -> Result<Json<Vec<breakdowns::BreakdownRow>>, ApiError> {
+) -> Result<Json<Vec<breakdowns::CustomEventRow>>, ApiError> {
     let (start, end) = params.date_range()?;
     let site_id = params.site_id.clone();
     let limit = params.limit;
     let result = tokio::task::spawn_blocking(move || {
-        let conn = state.buffer.conn().lock();
-        breakdowns::query_breakdown(
-            &conn,
-            &site_id,
-            &start,
-            &end,
-            breakdowns::Dimension::CountryCode,
-            limit,
-        )
+        let conn = state.query_conn();
+        breakdowns::query_custom_events(&conn, &site_id, &start, &end, limit)
     })
-    .await
-    .map_err(|e| ApiError::Internal(format!("Query task panicked: {e}")))??;
+    .await??;
+    Ok(Json(result))
+}
+
+/// Query parameters for the conversions-by-source report.
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SourceConversionsParams {
+    /// The site, `all` for every site, or `@<group>` for a site group.
+    pub site_id: String,
+    /// Range ending today: `day`, `today`, `7d`, `30d` (default) or `90d`.
+    #[serde(default = "default_period")]
+    pub period: String,
+    /// First day, `YYYY-MM-DD`.  Overrides `period` together with `end_date`.
+    pub start_date: Option<String>,
+    /// Day after the last, `YYYY-MM-DD`.
+    pub end_date: Option<String>,
+    /// Maximum rows. Default 10.
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+    /// Goal in funnel step syntax, e.g. `event:signup` or `page:/thanks`.
+    pub goal: String,
+    /// Channel to group by: `source` (default), `utm_source`, `utm_medium` or
+    /// `utm_campaign`.
+    pub breakdown: Option<String>,
+}
+
+/// GET /api/stats/sources/conversions — Goal conversions per acquisition channel.
+#[utoipa::path(
+    get,
+    path = "/api/stats/sources/conversions",
+    tag = openapi::STATS,
+    summary = "Goal conversions per acquisition channel",
+    params(SourceConversionsParams),
+    responses((status = 200, description = "The channels", body = Vec<breakdowns::ConversionRow>)),
+)]
+pub async fn get_source_conversions(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<SourceConversionsParams>,
+) -> Result<Json<Vec<breakdowns::ConversionRow>>, ApiError> {
+    let (start, end) = BreakdownParams {
+        site_id: params.site_id.clone(),
+        period: params.period.clone(),
+        start_date: params.start_date.clone(),
+        end_date: params.end_date.clone(),
+        limit: params.limit,
+        others: false,
+        compare: false,
+        goal: None,
+        format: None,
+        locale: None,
+    }
+    .date_range()?;
+    let goal = parse_goal(&params.goal)?;
+    let dimension = match params.breakdown.as_deref() {
+        None | Some("" | "source") => breakdowns::Dimension::ReferrerSource,
+        Some("utm_source") => breakdowns::Dimension::UtmSource,
+        Some("utm_medium") => breakdowns::Dimension::UtmMedium,
+        Some("utm_campaign") => breakdowns::Dimension::UtmCampaign,
+        Some(other) => {
+            return Err(ApiError::invalid_field(
+                "breakdown",
+                format!(
+                    "Invalid breakdown: '{other}'. Use 'source', 'utm_source', 'utm_medium' or 'utm_campaign'."
+                ),
+            ));
+        }
+    };
+
+    let site_id = params.site_id.clone();
+    let limit = params.limit;
+    let result = tokio::task::spawn_blocking(move || {
+        let conn = state.query_conn();
+        breakdowns::query_source_conversions(&conn, &site_id, &start, &end, dimension, &goal, limit)
+    })
+    .await??;
     Ok(Json(result))
 }
 
-/// GET /api/stats/sessions — Session metrics (requires behavioral extension).
+/// Query parameters for the custom dimension breakdown endpoint.
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CustomBreakdownParams {
+    /// The site, `all` for every site, or `@<group>` for a site group.
+    pub site_id: String,
+    /// Range ending today: `day`, `today`, `7d`, `30d` (default) or `90d`.
+    #[serde(default = "default_period")]
+    pub period: String,
+    /// First day, `YYYY-MM-DD`.  Overrides `period` together with `end_date`.
+    pub start_date: Option<String>,
+    /// Day after the last, `YYYY-MM-DD`.
+    pub end_date: Option<String>,
+    /// Maximum rows. Default 10.
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+    /// Name of a custom dimension declared in the site registry.
+    pub name: String,
+    /// Fold the values beyond `limit` into an "(other)" row and add totals.
+    #[serde(default)]
+    pub others: bool,
+    /// Add each row's visitors in the previous period.
+    #[serde(default)]
+    pub compare: bool,
+    /// Only count visitors who reached this goal, in funnel step syntax.
+    pub goal: Option<String>,
+    /// Download the rows as a `csv` or `json` attachment.
+    pub format: Option<String>,
+    /// Locale of a CSV download.
+    pub locale: Option<String>,
+}
+
+/// GET /api/stats/breakdown/custom — Breakdown by a declared custom dimension.
+#[utoipa::path(
+    get,
+    path = "/api/stats/breakdown/custom",
+    tag = openapi::STATS,
+    summary = "Values of a custom dimension",
+    params(CustomBreakdownParams),
+    responses((status = 200, description = "The rows", body = Vec<breakdowns::BreakdownRow>)),
+)]
+pub async fn get_custom_breakdown(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<CustomBreakdownParams>,
+) -> Result<axum::response::Response, ApiError> {
+    let breakdown = BreakdownParams {
+        site_id: params.site_id.clone(),
+        period: params.period.clone(),
+        start_date: params.start_date.clone(),
+        end_date: params.end_date.clone(),
+        limit: params.limit,
+        others: params.others,
+        compare: params.compare,
+        goal: params.goal.clone(),
+        format: params.format.clone(),
+        locale: params.locale.clone(),
+    };
+    breakdown.date_range()?;
+    let (slot, _) = state
+        .sites
+        .get(&params.site_id)
+        .and_then(|site| site.dimension_slot(&params.name))
+        .ok_or_else(|| {
+            ApiError::NotFound(format!(
+                "No custom dimension named '{}' is declared for this site",
+                params.name
+            ))
+        })?;
+    dimension_breakdown::<breakdowns::BreakdownRow>(
+        state,
+        &headers,
+        breakdown,
+        breakdowns::Dimension::Custom(slot),
+    )
+    .await
+}
+
+/// GET /api/stats/sessions — Session metrics.
+#[utoipa::path(
+    get,
+    path = "/api/stats/sessions",
+    tag = openapi::STATS,
+    summary = "Session metrics",
+    params(StatsParams),
+    responses((status = 200, description = "The report", body = sessions::SessionMetrics)),
+)]
 pub async fn get_sessions(
     State(state): State<Arc<AppState>>,
     Query(params): Query<StatsParams>,
@@ -372,32 +1194,63 @@ pub async fn get_sessions(
     let (start, end) = params.validate_and_date_range()?;
     let site_id = params.site_id.clone();
     let result = tokio::task::spawn_blocking(move || {
-        let conn = state.buffer.conn().lock();
-        sessions::query_session_metrics(&conn, &site_id, &start, &end).unwrap_or(
-            sessions::SessionMetrics {
-                total_sessions: 0,
-                avg_session_duration_secs: 0.0,
-                avg_pages_per_session: 0.0,
-            },
-        )
+        let conn = state.query_conn();
+        sessions::query_session_metrics(&conn, &site_id, &start, &end, SessionSql::Primary)
     })
-    .await
-    .map_err(|e| ApiError::Internal(format!("Query task panicked: {e}")))?;
+    .await??;
+    Ok(Json(result))
+}
+
+/// GET /api/stats/time_on_page — Average time spent on each page.
+#[utoipa::path(
+    get,
+    path = "/api/stats/time_on_page",
+    tag = openapi::STATS,
+    summary = "Time spent on each page",
+    params(BreakdownParams),
+    responses((status = 200, description = "The pages", body = Vec<sessions::PageTime>)),
+)]
+pub async fn get_time_on_page(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<BreakdownParams>,
+) -> Result<Json<Vec<sessions::PageTime>>, ApiError> {
+    let (start, end) = params.date_range()?;
+    let site_id = params.site_id.clone();
+    let limit = params.limit;
+    let result = tokio::task::spawn_blocking(move || {
+        let conn = state.query_conn();
+        sessions::query_time_on_page(&conn, &site_id, &start, &end, limit)
+    })
+    .await??;
     Ok(Json(result))
 }
 
 /// Query parameters for the funnel endpoint.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct FunnelParams {
+    /// The site, `all` for every site, or `@<group>` for a site group.
     pub site_id: String,
+    /// Range ending today: `day`, `today`, `7d`, `30d` (default) or `90d`.
     #[serde(default = "default_period")]
     pub period: String,
+    /// First day, `YYYY-MM-DD`.  Overrides `period` together with `end_date`.
     pub start_date: Option<String>,
+    /// Day after the last, `YYYY-MM-DD`.
     pub end_date: Option<String>,
+    /// Time to complete the funnel, e.g. `30 minutes`.
     #[serde(default = "default_window")]
     pub window: String,
     /// Comma-separated list of step types. Each step is `page:<path>` or `event:<name>`.
     pub steps: String,
+    /// Optional per-step breakdown: `source` or `device`.
+    pub breakdown: Option<String>,
+    /// Who moves through the steps: `visitor` (default), `user` or `stitched`.
+    pub identity: Option<String>,
+    /// Download the steps as a `csv` or `json` attachment.
+    pub format: Option<String>,
+    /// Locale of a CSV download.
+    pub locale: Option<String>,
 }
 
 fn default_window() -> String {
@@ -406,7 +1259,7 @@ fn default_window() -> String {
 
 impl FunnelParams {
     fn date_range(&self) -> Result<(String, String), ApiError> {
-        validate_site_id(&self.site_id)?;
+        validate_stats_site_id(&self.site_id)?;
         let stats_params = StatsParams {
             site_id: self.site_id.clone(),
             period: self.period.clone(),
@@ -417,44 +1270,107 @@ impl FunnelParams {
     }
 }
 
+/// Parse a goal given in funnel step syntax into a SQL condition.
+pub fn parse_goal(goal: &str) -> Result<String, ApiError> {
+    parse_funnel_step(goal).map_err(|_| {
+        ApiError::invalid_field(
+            "goal",
+            "Invalid goal. Use 'page:/path', 'page~:/glob/*', 'pageprefix:/path' or 'event:name'.",
+        )
+    })
+}
+
 /// Parse a safe funnel step condition from a structured step definition.
 ///
-/// Accepts `page:/path` or `event:name` formats only. Returns a SQL boolean
+/// Accepts `page:/path`, `page~:/glob/*` (`*` matches any characters),
+/// `pageprefix:/path` or `event:name` formats only. Returns a SQL boolean
 /// expression using only safe, known column comparisons.
 fn parse_funnel_step(step: &str) -> Result<String, ApiError> {
     let step = step.trim();
-    if let Some(path) = step.strip_prefix("page:") {
+    if let Some(glob) = step.strip_prefix("page~:") {
+        if glob.is_empty() || glob.len() > 256 {
+            return Err(ApiError::invalid_field("steps", "Invalid page pattern"));
+        }
+        let like = like_escape(glob).replace('*', "%").replace('\'', "''");
+        Ok(format!("pathname LIKE '{like}' ESCAPE '\\'"))
+    } else if let Some(prefix) = step.strip_prefix("pageprefix:") {
+        if prefix.is_empty() || prefix.len() > 256 {
+            return Err(ApiError::invalid_field("steps", "Invalid page prefix"));
+        }
+        let like = like_escape(prefix).replace('\'', "''");
+        Ok(format!("pathname LIKE '{like}%' ESCAPE '\\'"))
+    } else if let Some(path) = step.strip_prefix("page:") {
         if path.is_empty() || path.len() > 256 {
-            return Err(ApiError::BadRequest("Invalid page path".to_string()));
+            return Err(ApiError::invalid_field("steps", "Invalid page path"));
         }
         // Escape single quotes to prevent injection
         let escaped = path.replace('\'', "''");
         Ok(format!("pathname = '{escaped}'"))
     } else if let Some(name) = step.strip_prefix("event:") {
         if name.is_empty() || name.len() > 256 {
-            return Err(ApiError::BadRequest("Invalid event name".to_string()));
+            return Err(ApiError::invalid_field("steps", "Invalid event name"));
         }
         let escaped = name.replace('\'', "''");
         Ok(format!("event_name = '{escaped}'"))
     } else {
-        Err(ApiError::BadRequest(format!(
-            "Invalid step format: '{step}'. Use 'page:/path' or 'event:name'."
-        )))
+        Err(ApiError::invalid_field(
+            "steps",
+            format!(
+                "Invalid step format: '{step}'. Use 'page:/path', 'page~:/glob/*', 'pageprefix:/path' or 'event:name'."
+            ),
+        ))
     }
 }
 
-/// GET /api/stats/funnel — Funnel analysis (requires behavioral extension).
+/// Parse the `identity` parameter of funnel and retention queries.
+fn parse_identity(identity: Option<&str>) -> Result<Identity, ApiError> {
+    match identity {
+        None | Some("" | "visitor") => Ok(Identity::Visitor),
+        Some("user") => Ok(Identity::User),
+        Some("stitched") => Ok(Identity::Stitched),
+        Some(other) => Err(ApiError::invalid_field(
+            "identity",
+            format!("Invalid identity: '{other}'. Use 'visitor', 'user' or 'stitched'."),
+        )),
+    }
+}
+
+/// Escape LIKE wildcards (`%`, `_`) and the `\` escape character so `s`
+/// matches literally in a `LIKE ... ESCAPE '\'` pattern.
+fn like_escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// GET /api/stats/funnel — Funnel analysis.
+#[utoipa::path(
+    get,
+    path = "/api/stats/funnel",
+    tag = openapi::STATS,
+    summary = "Conversion funnel",
+    params(FunnelParams),
+    responses((status = 200, description = "The steps", body = Vec<funnel::FunnelStep>)),
+)]
 pub async fn get_funnel(
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
     Query(params): Query<FunnelParams>,
-) -> Result<Json<Vec<funnel::FunnelStep>>, ApiError> {
+) -> Result<axum::response::Response, ApiError> {
     let (start, end) = params.date_range()?;
+    let download = Download::requested(
+        params.format.as_deref(),
+        params.locale.as_deref(),
+        &headers,
+        state.locale,
+    )?;
 
     // Validate window interval format (only allow simple intervals)
     let window = params.window.trim().to_string();
     if !is_safe_interval(&window) {
-        return Err(ApiError::BadRequest(
-            "Invalid window interval. Use e.g. '1 day', '2 hours', '30 minutes'.".to_string(),
+        return Err(ApiError::invalid_field(
+            "window",
+            "Invalid window interval. Use e.g. '1 day', '2 hours', '30 minutes'.",
         ));
     }
 
@@ -466,9 +1382,22 @@ pub async fn get_funnel(
         .collect::<Result<Vec<_>, _>>()?;
 
     if step_strs.is_empty() {
-        return Ok(Json(Vec::new()));
+        return funnel_response(download.as_ref(), &[]);
     }
 
+    let breakdown = match params.breakdown.as_deref() {
+        None | Some("") => None,
+        Some("source") => Some(breakdowns::Dimension::ReferrerSource),
+        Some("device") => Some(breakdowns::Dimension::DeviceType),
+        Some(other) => {
+            return Err(ApiError::invalid_field(
+                "breakdown",
+                format!("Invalid breakdown: '{other}'. Use 'source' or 'device'."),
+            ));
+        }
+    };
+    let identity = parse_identity(params.identity.as_deref())?;
+
     // Limit concurrent heavy queries.  Clone the semaphore Arc so the permit
     // does not borrow `state`, allowing `state` to be moved into the closure.
     let semaphore = Arc::clone(&state.query_semaphore);
@@ -480,13 +1409,45 @@ pub async fn get_funnel(
 
     let site_id = params.site_id.clone();
     let result = tokio::task::spawn_blocking(move || {
-        let conn = state.buffer.conn().lock();
+        let conn = state.query_conn();
         let step_refs: Vec<&str> = step_strs.iter().map(String::as_str).collect();
-        funnel::query_funnel(&conn, &site_id, &start, &end, &window, &step_refs).unwrap_or_default()
+        funnel::query_funnel(
+            &conn, &site_id, &start, &end, &window, &step_refs, breakdown, identity,
+        )
     })
-    .await
-    .map_err(|e| ApiError::Internal(format!("Query task panicked: {e}")))?;
-    Ok(Json(result))
+    .await??;
+    funnel_response(download.as_ref(), &result)
+}
+
+/// Funnel steps as JSON, or as a file when downloaded.  CSV files leave out
+/// step breakdowns.
+fn funnel_response(
+    download: Option<&Download>,
+    steps: &[funnel::FunnelStep],
+) -> Result<axum::response::Response, ApiError> {
+    let Some(download) = download else {
+        return Ok(Json(steps).into_response());
+    };
+    download.file(
+        "funnel",
+        &[
+            "step",
+            "visitors",
+            "drop_off_pct",
+            "median_secs_from_previous",
+        ],
+        steps,
+        |step| {
+            vec![
+                step.step.to_string(),
+                step.visitors.to_string(),
+                download.locale.format_decimal(step.drop_off_pct),
+                step.median_secs_from_previous
+                    .map(|secs| download.locale.format_decimal(secs))
+                    .unwrap_or_default(),
+            ]
+        },
+    )
 }
 
 /// Validate that an interval string is a safe, simple DuckDB interval.
@@ -517,15 +1478,36 @@ fn is_safe_interval(s: &str) -> bool {
 }
 
 /// Query parameters for the retention endpoint.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct RetentionParams {
+    /// The site, `all` for every site, or `@<group>` for a site group.
     pub site_id: String,
+    /// Range ending today: `day`, `today`, `7d`, `30d` (default) or `90d`.
     #[serde(default = "default_period")]
     pub period: String,
+    /// First day, `YYYY-MM-DD`.  Overrides `period` together with `end_date`.
     pub start_date: Option<String>,
+    /// Day after the last, `YYYY-MM-DD`.
     pub end_date: Option<String>,
+    /// Legacy name of `periods`.
     #[serde(default = "default_num_weeks")]
     pub weeks: u32,
+    /// Cohort granularity: `day`, `week` (default), or `month`.
+    #[serde(default = "default_cohort")]
+    pub cohort: String,
+    /// Number of periods per cohort. Defaults to `weeks`.
+    pub periods: Option<u32>,
+    /// Who is counted: `visitor` (default), `user` or `stitched`.
+    pub identity: Option<String>,
+    /// Download the cohorts as a `csv` or `json` attachment.
+    pub format: Option<String>,
+    /// Locale of a CSV download.
+    pub locale: Option<String>,
+}
+
+fn default_cohort() -> String {
+    "week".to_string()
 }
 
 const fn default_num_weeks() -> u32 {
@@ -534,7 +1516,7 @@ const fn default_num_weeks() -> u32 {
 
 impl RetentionParams {
     fn date_range(&self) -> Result<(String, String), ApiError> {
-        validate_site_id(&self.site_id)?;
+        validate_stats_site_id(&self.site_id)?;
         let stats_params = StatsParams {
             site_id: self.site_id.clone(),
             period: self.period.clone(),
@@ -545,18 +1527,58 @@ impl RetentionParams {
     }
 }
 
-/// GET /api/stats/retention — Retention cohort analysis (requires behavioral extension).
+/// GET /api/stats/retention — Retention cohort analysis by day, week, or month.
+#[utoipa::path(
+    get,
+    path = "/api/stats/retention",
+    tag = openapi::STATS,
+    summary = "Cohort retention",
+    params(RetentionParams),
+    responses((status = 200, description = "The cohorts", body = Vec<retention::RetentionCohort>)),
+)]
 pub async fn get_retention(
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
     Query(params): Query<RetentionParams>,
-) -> Result<Json<Vec<retention::RetentionCohort>>, ApiError> {
+) -> Result<axum::response::Response, ApiError> {
     let (start, end) = params.date_range()?;
-
-    if params.weeks == 0 || params.weeks > 52 {
-        return Err(ApiError::BadRequest(
-            "weeks must be between 1 and 52".to_string(),
-        ));
+    let download = Download::requested(
+        params.format.as_deref(),
+        params.locale.as_deref(),
+        &headers,
+        state.locale,
+    )?;
+
+    let granularity = match params.cohort.as_str() {
+        "day" => retention::CohortGranularity::Day,
+        "week" => retention::CohortGranularity::Week,
+        "month" => retention::CohortGranularity::Month,
+        other => {
+            return Err(ApiError::invalid_field(
+                "cohort",
+                format!("Invalid cohort: '{other}'. Use 'day', 'week', or 'month'."),
+            ));
+        }
+    };
+    let periods = params.periods.unwrap_or(params.weeks);
+    let max_periods = granularity.max_periods();
+    if periods == 0 || periods > max_periods {
+        return Err(if params.periods.is_some() {
+            ApiError::invalid_field(
+                "periods",
+                format!(
+                    "periods must be between 1 and {max_periods} for {} cohorts",
+                    params.cohort
+                ),
+            )
+        } else {
+            ApiError::invalid_field(
+                "weeks",
+                format!("weeks must be between 1 and {max_periods}"),
+            )
+        });
     }
+    let identity = parse_identity(params.identity.as_deref())?;
 
     // Limit concurrent heavy queries.  Clone the semaphore Arc so the permit
     // does not borrow `state`, allowing `state` to be moved into the closure.
@@ -568,23 +1590,55 @@ pub async fn get_retention(
     })?;
 
     let site_id = params.site_id.clone();
-    let weeks = params.weeks;
     let result = tokio::task::spawn_blocking(move || {
-        let conn = state.buffer.conn().lock();
-        retention::query_retention(&conn, &site_id, &start, &end, weeks).unwrap_or_default()
+        let conn = state.query_conn();
+        retention::query_retention(
+            &conn,
+            &site_id,
+            &start,
+            &end,
+            granularity,
+            periods,
+            identity,
+        )
+    })
+    .await??;
+    let Some(download) = download else {
+        return Ok(Json(result).into_response());
+    };
+
+    // One count and one percentage column per period after the cohort's.
+    let mut columns = vec!["cohort_date".to_string(), "cohort_size".to_string()];
+    for period in 0..periods {
+        columns.push(format!("period_{period}"));
+        columns.push(format!("period_{period}_pct"));
+    }
+    let columns: Vec<&str> = columns.iter().map(String::as_str).collect();
+    download.file("retention", &columns, &result, |cohort| {
+        let mut fields = vec![
+            download.locale.format_date(&cohort.cohort_date),
+            cohort.cohort_size.to_string(),
+        ];
+        for (count, pct) in cohort.counts.iter().zip(&cohort.percentages) {
+            fields.push(count.to_string());
+            fields.push(download.locale.format_decimal(*pct));
+        }
+        fields
     })
-    .await
-    .map_err(|e| ApiError::Internal(format!("Query task panicked: {e}")))?;
-    Ok(Json(result))
 }
 
 /// Query parameters for the sequence endpoint.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct SequenceParams {
+    /// The site, `all` for every site, or `@<group>` for a site group.
     pub site_id: String,
+    /// Range ending today: `day`, `today`, `7d`, `30d` (default) or `90d`.
     #[serde(default = "default_period")]
     pub period: String,
+    /// First day, `YYYY-MM-DD`.  Overrides `period` together with `end_date`.
     pub start_date: Option<String>,
+    /// Day after the last, `YYYY-MM-DD`.
     pub end_date: Option<String>,
     /// Comma-separated steps in `page:/path` or `event:name` format.
     pub steps: String,
@@ -592,7 +1646,7 @@ pub struct SequenceParams {
 
 impl SequenceParams {
     fn date_range(&self) -> Result<(String, String), ApiError> {
-        validate_site_id(&self.site_id)?;
+        validate_stats_site_id(&self.site_id)?;
         let stats_params = StatsParams {
             site_id: self.site_id.clone(),
             period: self.period.clone(),
@@ -604,14 +1658,22 @@ impl SequenceParams {
 }
 
 /// Sequence match result for API response.
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct SequenceMatchResponse {
     pub converting_visitors: u64,
     pub total_visitors: u64,
     pub conversion_rate: f64,
 }
 
-/// GET /api/stats/sequences — Sequence match analysis (requires behavioral extension).
+/// GET /api/stats/sequences — Sequence match analysis.
+#[utoipa::path(
+    get,
+    path = "/api/stats/sequences",
+    tag = openapi::STATS,
+    summary = "Visitors completing a sequence of steps",
+    params(SequenceParams),
+    responses((status = 200, description = "The report", body = SequenceMatchResponse)),
+)]
 pub async fn get_sequences(
     State(state): State<Arc<AppState>>,
     Query(params): Query<SequenceParams>,
@@ -626,8 +1688,9 @@ pub async fn get_sequences(
         .collect::<Result<Vec<_>, _>>()?;
 
     if step_strs.len() < 2 {
-        return Err(ApiError::BadRequest(
-            "At least 2 steps required for sequence analysis".to_string(),
+        return Err(ApiError::invalid_field(
+            "steps",
+            "At least 2 steps required for sequence analysis",
         ));
     }
 
@@ -642,18 +1705,11 @@ pub async fn get_sequences(
 
     let site_id = params.site_id.clone();
     let result = tokio::task::spawn_blocking(move || {
-        let conn = state.buffer.conn().lock();
+        let conn = state.query_conn();
         let step_refs: Vec<&str> = step_strs.iter().map(String::as_str).collect();
-        sequences::execute_sequence_match(&conn, &site_id, &start, &end, &step_refs).unwrap_or(
-            sequences::SequenceMatchResult {
-                converting_visitors: 0,
-                total_visitors: 0,
-                conversion_rate: 0.0,
-            },
-        )
+        sequences::execute_sequence_match(&conn, &site_id, &start, &end, &step_refs)
     })
-    .await
-    .map_err(|e| ApiError::Internal(format!("Query task panicked: {e}")))?;
+    .await??;
     Ok(Json(SequenceMatchResponse {
         converting_visitors: result.converting_visitors,
         total_visitors: result.total_visitors,
@@ -662,12 +1718,17 @@ pub async fn get_sequences(
 }
 
 /// Query parameters for the flow endpoint.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct FlowParams {
+    /// The site, `all` for every site, or `@<group>` for a site group.
     pub site_id: String,
+    /// Range ending today: `day`, `today`, `7d`, `30d` (default) or `90d`.
     #[serde(default = "default_period")]
     pub period: String,
+    /// First day, `YYYY-MM-DD`.  Overrides `period` together with `end_date`.
     pub start_date: Option<String>,
+    /// Day after the last, `YYYY-MM-DD`.
     pub end_date: Option<String>,
     /// The page to analyze flow from.
     pub page: String,
@@ -675,7 +1736,7 @@ pub struct FlowParams {
 
 impl FlowParams {
     fn date_range(&self) -> Result<(String, String), ApiError> {
-        validate_site_id(&self.site_id)?;
+        validate_stats_site_id(&self.site_id)?;
         let stats_params = StatsParams {
             site_id: self.site_id.clone(),
             period: self.period.clone(),
@@ -686,7 +1747,15 @@ impl FlowParams {
     }
 }
 
-/// GET /api/stats/flow — Flow analysis showing next pages (requires behavioral extension).
+/// GET /api/stats/flow — Flow analysis showing next pages.
+#[utoipa::path(
+    get,
+    path = "/api/stats/flow",
+    tag = openapi::STATS,
+    summary = "Pages visited after a page",
+    params(FlowParams),
+    responses((status = 200, description = "The next pages", body = Vec<flow::FlowNode>)),
+)]
 pub async fn get_flow(
     State(state): State<Arc<AppState>>,
     Query(params): Query<FlowParams>,
@@ -694,7 +1763,7 @@ pub async fn get_flow(
     let (start, end) = params.date_range()?;
 
     if params.page.is_empty() || params.page.len() > 256 {
-        return Err(ApiError::BadRequest("Invalid page path".to_string()));
+        return Err(ApiError::invalid_field("page", "Invalid page path"));
     }
 
     // Limit concurrent heavy queries. Clone the semaphore Arc so the permit
@@ -709,11 +1778,284 @@ pub async fn get_flow(
     let site_id = params.site_id.clone();
     let page = params.page.clone();
     let result = tokio::task::spawn_blocking(move || {
-        let conn = state.buffer.conn().lock();
-        flow::query_flow(&conn, &site_id, &start, &end, &page).unwrap_or_default()
+        let conn = state.query_conn();
+        flow::query_flow(&conn, &site_id, &start, &end, &page)
     })
-    .await
-    .map_err(|e| ApiError::Internal(format!("Query task panicked: {e}")))?;
+    .await??;
+    Ok(Json(result))
+}
+
+/// GET /api/stats/anomalies — Anomalous traffic hours recorded by the detector.
+#[utoipa::path(
+    get,
+    path = "/api/stats/anomalies",
+    tag = openapi::STATS,
+    summary = "Hours with unusual traffic",
+    params(StatsParams),
+    responses((status = 200, description = "The anomalies", body = Vec<anomalies::Anomaly>)),
+)]
+pub async fn get_anomalies(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<StatsParams>,
+) -> Result<Json<Vec<anomalies::Anomaly>>, ApiError> {
+    let (start, end) = params.validate_and_date_range()?;
+    let site_id = params.site_id.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        let conn = state.query_conn();
+        anomalies::query_anomalies(&conn, &site_id, &start, &end)
+    })
+    .await??;
+    Ok(Json(result))
+}
+
+/// Most events returned in a visitor profile's timeline.
+const MAX_VISITOR_EVENTS: usize = 1000;
+
+/// Query parameters for the visitor profile endpoint.
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct VisitorParams {
+    /// The visitor's site.
+    pub site_id: String,
+}
+
+/// GET /api/stats/visitor/{visitor_id} — One visitor's timeline, sessions and
+/// first-touch attribution (requires admin).
+///
+/// Every request is logged with the hashed caller identity, since it exposes
+/// individual behaviour rather than aggregates.
+#[utoipa::path(
+    get,
+    path = "/api/stats/visitor/{visitor_id}",
+    tag = openapi::STATS,
+    summary = "One visitor's timeline, sessions and attribution",
+    params(
+        ("visitor_id" = String, Path, description = "The visitor's ID."),
+        VisitorParams,
+    ),
+    responses((status = 200, description = "The profile", body = visitor::VisitorProfile)),
+)]
+pub async fn get_visitor_profile(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(visitor_id): Path<String>,
+    Query(params): Query<VisitorParams>,
+) -> Result<Json<visitor::VisitorProfile>, ApiError> {
+    validate_site_id(&params.site_id)?;
+    if visitor_id.is_empty()
+        || visitor_id.len() > 128
+        || !visitor_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        return Err(ApiError::invalid_field(
+            "visitor_id",
+            "visitor_id must be 1-128 alphanumeric or '-' characters",
+        ));
+    }
+
+    tracing::warn!(
+        caller = %crate::api::auth::stats_caller(&state, &headers),
+        site_id = %params.site_id,
+        visitor_id = %visitor_id,
+        "Visitor profile accessed"
+    );
+
+    let site_id = params.site_id.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        let conn = state.query_conn();
+        visitor::query_visitor_profile(&conn, &site_id, &visitor_id, MAX_VISITOR_EVENTS)
+    })
+    .await??;
+    result
+        .map(Json)
+        .ok_or_else(|| ApiError::NotFound("No events for this visitor".to_string()))
+}
+
+/// Query parameters for the sites overview endpoint.
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SitesParams {
+    /// Range ending today: `day`, `today`, `7d`, `30d` (default) or `90d`.
+    #[serde(default = "default_period")]
+    pub period: String,
+    /// First day, `YYYY-MM-DD`.  Overrides `period` together with `end_date`.
+    pub start_date: Option<String>,
+    /// Day after the last, `YYYY-MM-DD`.
+    pub end_date: Option<String>,
+    /// Only list sites in this site group.
+    pub group: Option<String>,
+    /// Read the Parquet files' summary sidecars instead of their events,
+    /// estimating visitors.
+    #[serde(default)]
+    pub estimate: bool,
+}
+
+impl SitesParams {
+    fn date_range(&self) -> Result<(String, String), ApiError> {
+        StatsParams {
+            site_id: ALL_SITES.to_string(),
+            period: self.period.clone(),
+            start_date: self.start_date.clone(),
+            end_date: self.end_date.clone(),
+        }
+        .date_range()
+    }
+}
+
+/// GET /api/stats/sites — Per-site traffic summary for the multi-site overview
+/// (requires admin).
+///
+/// Sites from the allowlist or site registry without traffic in the range are
+/// listed with zero counts.  `group` limits the list to one site group.  With
+/// `estimate=true` the totals come from the summary sidecars written at flush
+/// time, which is much faster over long ranges.
+#[utoipa::path(
+    get,
+    path = "/api/stats/sites",
+    tag = openapi::STATS,
+    summary = "Traffic of every site",
+    params(SitesParams),
+    responses((status = 200, description = "The sites", body = Vec<metrics::SiteSummary>)),
+)]
+pub async fn get_sites(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<SitesParams>,
+) -> Result<Json<Vec<metrics::SiteSummary>>, ApiError> {
+    let (start, end) = params.date_range()?;
+    let estimate = params.estimate;
+    let state2 = Arc::clone(&state);
+    let mut result = tokio::task::spawn_blocking(move || {
+        let conn = state2.query_conn();
+        if estimate {
+            metrics::query_site_summary_estimates(&conn, &start, &end)
+        } else {
+            metrics::query_site_summaries(&conn, &start, &end)
+        }
+    })
+    .await??;
+
+    let mut configured: Vec<&String> = state
+        .allowed_sites
+        .iter()
+        .chain(state.sites.keys())
+        .collect();
+    configured.sort();
+    configured.dedup();
+    for site_id in configured {
+        if !result.iter().any(|s| &s.site_id == site_id) {
+            result.push(metrics::SiteSummary {
+                site_id: site_id.clone(),
+                visitors: 0,
+                pageviews: 0,
+                events: 0,
+            });
+        }
+    }
+    if let Some(group) = &params.group {
+        result.retain(|s| {
+            state
+                .sites
+                .get(&s.site_id)
+                .is_some_and(|site| site.group.as_ref() == Some(group))
+        });
+    }
+    Ok(Json(result))
+}
+
+/// Query parameters for the paths endpoint.
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PathsParams {
+    /// The site, `all` for every site, or `@<group>` for a site group.
+    pub site_id: String,
+    /// Range ending today: `day`, `today`, `7d`, `30d` (default) or `90d`.
+    #[serde(default = "default_period")]
+    pub period: String,
+    /// First day, `YYYY-MM-DD`.  Overrides `period` together with `end_date`.
+    pub start_date: Option<String>,
+    /// Day after the last, `YYYY-MM-DD`.
+    pub end_date: Option<String>,
+    /// Start paths at this page instead of each session's entry page.
+    pub page: Option<String>,
+    /// Maximum pages per path (2–5).
+    #[serde(default = "default_path_depth")]
+    pub depth: u32,
+    /// Maximum paths (1–100).
+    #[serde(default = "default_path_limit")]
+    pub limit: u32,
+}
+
+const fn default_path_depth() -> u32 {
+    3
+}
+
+const fn default_path_limit() -> u32 {
+    10
+}
+
+/// Maximum number of paths returned by `/api/stats/paths`.
+const MAX_PATH_LIMIT: u32 = 100;
+
+impl PathsParams {
+    fn date_range(&self) -> Result<(String, String), ApiError> {
+        validate_stats_site_id(&self.site_id)?;
+        let stats_params = StatsParams {
+            site_id: self.site_id.clone(),
+            period: self.period.clone(),
+            start_date: self.start_date.clone(),
+            end_date: self.end_date.clone(),
+        };
+        stats_params.date_range()
+    }
+}
+
+/// GET /api/stats/paths — Most common multi-step page paths within sessions.
+#[utoipa::path(
+    get,
+    path = "/api/stats/paths",
+    tag = openapi::STATS,
+    summary = "Most common navigation paths",
+    params(PathsParams),
+    responses((status = 200, description = "The paths", body = Vec<paths::PathRow>)),
+)]
+pub async fn get_paths(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<PathsParams>,
+) -> Result<Json<Vec<paths::PathRow>>, ApiError> {
+    let (start, end) = params.date_range()?;
+
+    if !(2..=5).contains(&params.depth) {
+        return Err(ApiError::invalid_field(
+            "depth",
+            "depth must be between 2 and 5",
+        ));
+    }
+    let page = match params.page.as_deref() {
+        None | Some("") => None,
+        Some(p) if p.len() > 256 => {
+            return Err(ApiError::invalid_field("page", "Invalid page path"));
+        }
+        Some(p) => Some(p.to_string()),
+    };
+    let limit = params.limit.clamp(1, MAX_PATH_LIMIT);
+
+    // Limit concurrent heavy queries. Clone the semaphore Arc so the permit
+    // does not borrow `state`, allowing `state` to be moved into the closure.
+    let semaphore = Arc::clone(&state.query_semaphore);
+    let _permit = semaphore.try_acquire().map_err(|_| {
+        ApiError::TooManyRequests(
+            "Too many concurrent queries. Please retry in a moment.".to_string(),
+        )
+    })?;
+
+    let site_id = params.site_id.clone();
+    let depth = params.depth;
+    let result = tokio::task::spawn_blocking(move || {
+        let conn = state.query_conn();
+        paths::query_paths(&conn, &site_id, &start, &end, page.as_deref(), depth, limit)
+    })
+    .await??;
     Ok(Json(result))
 }
 
@@ -725,37 +2067,102 @@ pub async fn get_flow(
 /// need this guard.
 const MAX_EXPORT_DAYS: i64 = 366;
 
-/// Query parameters for the export endpoint.
-#[derive(Debug, Deserialize)]
+/// Query parameters for the export endpoint, and the body of an export job.
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct ExportParams {
+    /// The site, `all` for every site, or `@<group>` for a site group.
     pub site_id: String,
+    /// Range ending today: `day`, `today`, `7d`, `30d` (default) or `90d`.
     #[serde(default = "default_period")]
     pub period: String,
+    /// First day, `YYYY-MM-DD`.  Overrides `period` together with `end_date`.
     pub start_date: Option<String>,
+    /// Day after the last, `YYYY-MM-DD`.
     pub end_date: Option<String>,
     /// Export format: "csv" (default) or "json"
     #[serde(default = "default_export_format")]
     pub format: String,
+    /// Locale for CSV headers, dates and delimiter; defaults to the
+    /// `Accept-Language` header, then the configured locale.
+    pub locale: Option<String>,
+    /// What to export: "timeseries" (default), "pages", "sources",
+    /// "countries" or "events".
+    #[serde(rename = "type", default = "default_export_type")]
+    pub kind: String,
+    /// Rows of a breakdown export.
+    #[serde(default = "default_export_limit")]
+    pub limit: usize,
 }
 
 fn default_export_format() -> String {
     "csv".to_string()
 }
 
+fn default_export_type() -> String {
+    "timeseries".to_string()
+}
+
+const fn default_export_limit() -> usize {
+    100
+}
+
+/// Values of the export `type` parameter.
+const EXPORT_TYPES: [&str; 5] = ["timeseries", "pages", "sources", "countries", "events"];
+
+fn invalid_export_type(kind: &str) -> ApiError {
+    ApiError::invalid_field(
+        "type",
+        format!(
+            "Invalid type: '{kind}'. Use 'timeseries', 'pages', 'sources', \
+             'countries' or 'events'."
+        ),
+    )
+}
+
 impl ExportParams {
     fn date_range(&self) -> Result<(String, String), ApiError> {
-        validate_site_id(&self.site_id)?;
+        self.date_range_within(MAX_EXPORT_DAYS)
+    }
+
+    /// Validate the format and type of export asked for.
+    pub fn validate_output(&self) -> Result<(), ApiError> {
+        if !matches!(self.format.as_str(), "csv" | "json") {
+            return Err(ApiError::invalid_field(
+                "format",
+                format!("Invalid format: '{}'. Use 'csv' or 'json'.", self.format),
+            ));
+        }
+        if !EXPORT_TYPES.contains(&self.kind.as_str()) {
+            return Err(invalid_export_type(&self.kind));
+        }
+        Ok(())
+    }
+
+    /// Validate the site and limit and resolve the date range, allowing
+    /// explicit ranges of up to `max_days`.
+    pub fn date_range_within(&self, max_days: i64) -> Result<(String, String), ApiError> {
+        validate_stats_site_id(&self.site_id)?;
+        if self.limit > MAX_BREAKDOWN_LIMIT {
+            return Err(ApiError::invalid_field(
+                "limit",
+                format!("limit must not exceed {MAX_BREAKDOWN_LIMIT}"),
+            ));
+        }
 
         // When explicit dates are provided, validate their format and enforce the
         // maximum range to prevent building an arbitrarily large in-memory result.
         if let (Some(start_str), Some(end_str)) = (&self.start_date, &self.end_date) {
             let start_date =
                 chrono::NaiveDate::parse_from_str(start_str, "%Y-%m-%d").map_err(|_| {
-                    ApiError::BadRequest("Invalid start_date format. Use YYYY-MM-DD.".to_string())
+                    ApiError::invalid_field(
+                        "start_date",
+                        "Invalid start_date format. Use YYYY-MM-DD.",
+                    )
                 })?;
             let end_date =
                 chrono::NaiveDate::parse_from_str(end_str, "%Y-%m-%d").map_err(|_| {
-                    ApiError::BadRequest("Invalid end_date format. Use YYYY-MM-DD.".to_string())
+                    ApiError::invalid_field("end_date", "Invalid end_date format. Use YYYY-MM-DD.")
                 })?;
             let days = (end_date - start_date).num_days();
             if days < 0 {
@@ -763,12 +2170,13 @@ impl ExportParams {
                     "end_date must be on or after start_date".to_string(),
                 ));
             }
-            if days > MAX_EXPORT_DAYS {
+            if days > max_days {
                 return Err(ApiError::BadRequest(format!(
-                    "Export date range must not exceed {MAX_EXPORT_DAYS} days. \
+                    "Export date range must not exceed {max_days} days. \
                      Use the period parameter or a shorter explicit range."
                 )));
             }
+            return Ok((start_str.clone(), end_str.clone()));
         }
 
         let stats_params = StatsParams {
@@ -792,17 +2200,127 @@ struct ExportRow {
 }
 
 /// GET /api/stats/export — Export analytics data as CSV or JSON.
+///
+/// `type` selects the data: daily totals (`timeseries`, the default) or the
+/// top `limit` rows of the `pages`, `sources`, `countries` or `events`
+/// breakdowns over the whole range.
+#[utoipa::path(
+    get,
+    path = "/api/stats/export",
+    tag = openapi::STATS,
+    summary = "Download a report",
+    params(ExportParams),
+    responses(
+        (
+            status = 200,
+            description = "The report as an attachment",
+            content(("text/csv"), ("application/json")),
+        ),
+    ),
+)]
 pub async fn get_export(
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
     Query(params): Query<ExportParams>,
 ) -> Result<impl IntoResponse, ApiError> {
     let (start, end) = params.date_range()?;
+    params.validate_output()?;
+    let locale = Locale::negotiate(params.locale.as_deref(), &headers, state.locale);
+    let body = render_export(state, &params, start, end, locale).await?;
+    Ok(export_attachment(&params.format, "export", body))
+}
+
+/// Render the export `params` asks for over `start`..`end`, already
+/// validated, as the body of a CSV or JSON file.  Shared by
+/// `GET /api/stats/export` and export jobs.
+pub async fn render_export(
+    state: Arc<AppState>,
+    params: &ExportParams,
+    start: String,
+    end: String,
+    locale: Locale,
+) -> Result<String, ApiError> {
+    let [date, visitors, pageviews, top_page, top_source] = locale.export_headers();
+    let [page, source, country, event, events] = locale.breakdown_headers();
     let site_id = params.site_id.clone();
+    let limit = params.limit;
+
+    let (dimension, value_header) = match params.kind.as_str() {
+        "timeseries" => {
+            let rows = export_timeseries(state, site_id, start, end).await?;
+            return export_body(
+                &params.format,
+                locale,
+                &[date, visitors, pageviews, top_page, top_source],
+                &rows,
+                |row| {
+                    vec![
+                        locale.format_date(&row.date),
+                        row.visitors.to_string(),
+                        row.pageviews.to_string(),
+                        escape_csv_field(&row.top_page),
+                        escape_csv_field(&row.top_source),
+                    ]
+                },
+            );
+        }
+        "events" => {
+            let rows = tokio::task::spawn_blocking(move || {
+                let conn = state.query_conn();
+                breakdowns::query_event_counts(&conn, &site_id, &start, &end, limit)
+            })
+            .await??;
+            return export_body(
+                &params.format,
+                locale,
+                &[event, visitors, events],
+                &rows,
+                |row| {
+                    vec![
+                        escape_csv_field(&row.name),
+                        row.visitors.to_string(),
+                        row.events.to_string(),
+                    ]
+                },
+            );
+        }
+        "pages" => (breakdowns::Dimension::Page, page),
+        "sources" => (breakdowns::Dimension::ReferrerSource, source),
+        "countries" => (breakdowns::Dimension::CountryCode, country),
+        other => return Err(invalid_export_type(other)),
+    };
+
+    let rows = tokio::task::spawn_blocking(move || {
+        let conn = state.query_conn();
+        breakdowns::query_breakdown(&conn, &site_id, &start, &end, dimension, None, limit)
+    })
+    .await??;
+    export_body(
+        &params.format,
+        locale,
+        &[value_header, visitors, pageviews],
+        &rows,
+        |row| {
+            vec![
+                escape_csv_field(&row.value),
+                row.visitors.to_string(),
+                row.pageviews.to_string(),
+            ]
+        },
+    )
+}
 
+/// Daily totals, each with the range's top page and source.
+async fn export_timeseries(
+    state: Arc<AppState>,
+    site_id: String,
+    start: String,
+    end: String,
+) -> Result<Vec<ExportRow>, ApiError> {
     // Run all three queries together on a blocking thread so the DuckDB mutex
     // is acquired once and no Tokio worker is blocked.
     let (ts_data, top_pages, top_sources) = tokio::task::spawn_blocking(move || {
-        let conn = state.buffer.conn().lock();
+        let conn = state.query_conn();
         let ts = timeseries::query_timeseries(
             &conn,
             &site_id,
@@ -816,6 +2334,7 @@ pub async fn get_export(
             &start,
             &end,
             breakdowns::Dimension::Page,
+            None,
             1,
         )?;
         let sources = breakdowns::query_breakdown(
@@ -824,13 +2343,13 @@ pub async fn get_export(
             &start,
             &end,
             breakdowns::Dimension::ReferrerSource,
+            None,
             1,
         )?;
         drop(conn);
         Ok::<_, ApiError>((ts, pages, sources))
     })
-    .await
-    .map_err(|e| ApiError::Internal(format!("Export task panicked: {e}")))??;
+    .await??;
 
     let top_page = top_pages
         .first()
@@ -841,7 +2360,7 @@ pub async fn get_export(
         .map_or("(direct)", |r| r.value.as_str())
         .to_string();
 
-    let rows: Vec<ExportRow> = ts_data
+    Ok(ts_data
         .iter()
         .map(|b| ExportRow {
             date: b.date.clone(),
@@ -850,64 +2369,139 @@ pub async fn get_export(
             top_page: top_page.clone(),
             top_source: top_source.clone(),
         })
-        .collect();
+        .collect())
+}
 
-    match params.format.as_str() {
-        "json" => {
-            let body = serde_json::to_string(&rows)
-                .map_err(|e| ApiError::Internal(format!("JSON serialization failed: {e}")))?;
-            Ok((
-                [
-                    (header::CONTENT_TYPE, "application/json"),
-                    (
-                        header::CONTENT_DISPOSITION,
-                        "attachment; filename=\"export.json\"",
-                    ),
-                ],
-                body,
-            )
-                .into_response())
-        }
-        "csv" => {
-            let mut csv = String::from("date,visitors,pageviews,top_page,top_source\n");
-            for row in &rows {
-                let _ = writeln!(
-                    csv,
-                    "{},{},{},{},{}",
-                    row.date,
-                    row.visitors,
-                    row.pageviews,
-                    escape_csv_field(&row.top_page),
-                    escape_csv_field(&row.top_source),
-                );
+/// Render export rows as a JSON array, or as CSV with `headers` and the
+/// already-escaped fields from `fields`, separated by the locale's delimiter,
+/// as an attachment named `name` with the format's extension.
+fn export_file<T: Serialize>(
+    format: &str,
+    locale: Locale,
+    name: &str,
+    headers: &[&str],
+    rows: &[T],
+    fields: impl Fn(&T) -> Vec<String>,
+) -> Result<axum::response::Response, ApiError> {
+    let body = export_body(format, locale, headers, rows, fields)?;
+    Ok(export_attachment(format, name, body))
+}
+
+/// The body of [`export_file`].
+fn export_body<T: Serialize>(
+    format: &str,
+    locale: Locale,
+    headers: &[&str],
+    rows: &[T],
+    fields: impl Fn(&T) -> Vec<String>,
+) -> Result<String, ApiError> {
+    if format == "json" {
+        return serde_json::to_string(rows)
+            .map_err(|e| ApiError::Internal(format!("JSON serialization failed: {e}")));
+    }
+
+    let sep = locale.csv_delimiter().to_string();
+    let mut csv = headers.join(&sep);
+    csv.push('\n');
+    for row in rows {
+        csv.push_str(&fields(row).join(&sep));
+        csv.push('\n');
+    }
+    Ok(csv)
+}
+
+/// Serve `body`, a `csv` or `json` export, as an attachment named `name`
+/// with the format's extension.
+pub fn export_attachment(
+    format: &str,
+    name: &str,
+    body: impl IntoResponse,
+) -> axum::response::Response {
+    let (content_type, extension) = if format == "json" {
+        ("application/json", "json")
+    } else {
+        ("text/csv", "csv")
+    };
+    (
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{name}.{extension}\""),
+            ),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+/// A report requested as a file with `format=csv` or `format=json`.
+struct Download {
+    format: &'static str,
+    locale: Locale,
+}
+
+impl Download {
+    /// The download the request asks for, if any.  The locale is negotiated
+    /// as for `GET /api/stats/export`.
+    fn requested(
+        format: Option<&str>,
+        locale: Option<&str>,
+        headers: &HeaderMap,
+        default_locale: Locale,
+    ) -> Result<Option<Self>, ApiError> {
+        let format = match format {
+            None => return Ok(None),
+            Some("csv") => "csv",
+            Some("json") => "json",
+            Some(other) => {
+                return Err(ApiError::invalid_field(
+                    "format",
+                    format!("Invalid format: '{other}'. Use 'csv' or 'json'."),
+                ));
             }
-            Ok((
-                [
-                    (header::CONTENT_TYPE, "text/csv"),
-                    (
-                        header::CONTENT_DISPOSITION,
-                        "attachment; filename=\"export.csv\"",
-                    ),
-                ],
-                csv,
-            )
-                .into_response())
-        }
-        other => Err(ApiError::BadRequest(format!(
-            "Invalid format: '{other}'. Use 'csv' or 'json'."
-        ))),
+        };
+        Ok(Some(Self {
+            format,
+            locale: Locale::negotiate(locale, headers, default_locale),
+        }))
+    }
+
+    /// See [`export_file`].
+    fn file<T: Serialize>(
+        &self,
+        name: &str,
+        headers: &[&str],
+        rows: &[T],
+        fields: impl Fn(&T) -> Vec<String>,
+    ) -> Result<axum::response::Response, ApiError> {
+        export_file(self.format, self.locale, name, headers, rows, fields)
     }
 }
 
 /// Query parameters for the GDPR data erasure endpoint.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct GdprEraseParams {
     /// Site ID to erase data for (must pass the same validation as stats endpoints).
     pub site_id: String,
-    /// Start date (inclusive), YYYY-MM-DD.
+    /// Start date (inclusive), `YYYY-MM-DD`.
+    pub start_date: String,
+    /// End date (inclusive), `YYYY-MM-DD`.
+    pub end_date: String,
+}
+
+/// Response from `DELETE /api/gdpr/erase`.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Erasure {
+    pub status: &'static str,
+    pub site_id: String,
     pub start_date: String,
-    /// End date (inclusive), YYYY-MM-DD.
     pub end_date: String,
+    /// Events deleted from the DuckDB table.
+    pub db_records_deleted: i64,
+    /// Parquet partition directories removed.
+    pub parquet_partitions_deleted: u64,
 }
 
 /// DELETE /api/gdpr/erase — GDPR right-to-erasure endpoint.
@@ -926,16 +2520,26 @@ pub struct GdprEraseParams {
 /// This endpoint therefore operates on a **site + date-range** basis, which is the
 /// granularity operators can reasonably act on in response to a GDPR Art. 17 request.
 /// Operators should document this limitation in their privacy notice.
+#[allow(clippy::too_many_lines)]
+#[utoipa::path(
+    delete,
+    path = "/api/gdpr/erase",
+    tag = openapi::STATS,
+    summary = "Permanently erase a site's events in a date range",
+    params(GdprEraseParams),
+    responses((status = 200, description = "Erased", body = Erasure)),
+)]
 pub async fn gdpr_erase(
     State(state): State<Arc<AppState>>,
     Query(params): Query<GdprEraseParams>,
 ) -> Result<impl IntoResponse, ApiError> {
     validate_site_id(&params.site_id)?;
 
-    let start_date = NaiveDate::parse_from_str(&params.start_date, "%Y-%m-%d")
-        .map_err(|_| ApiError::BadRequest("Invalid start_date. Use YYYY-MM-DD.".to_string()))?;
+    let start_date = NaiveDate::parse_from_str(&params.start_date, "%Y-%m-%d").map_err(|_| {
+        ApiError::invalid_field("start_date", "Invalid start_date. Use YYYY-MM-DD.")
+    })?;
     let end_date = NaiveDate::parse_from_str(&params.end_date, "%Y-%m-%d")
-        .map_err(|_| ApiError::BadRequest("Invalid end_date. Use YYYY-MM-DD.".to_string()))?;
+        .map_err(|_| ApiError::invalid_field("end_date", "Invalid end_date. Use YYYY-MM-DD."))?;
     if end_date < start_date {
         return Err(ApiError::BadRequest(
             "end_date must be on or after start_date".to_string(),
@@ -956,24 +2560,28 @@ pub async fn gdpr_erase(
 
     let (db_records_deleted, parquet_partitions_deleted) =
         tokio::task::spawn_blocking(move || -> Result<(i64, u64), duckdb::Error> {
-            // Step 1: SQL deletions — hold the DuckDB lock only for these two queries.
+            // Step 1: SQL deletions — hold the DuckDB lock only for these queries.
             let db_count: i64 = {
                 let guard = conn.lock();
-                let count: i64 = guard
-                    .query_row(
-                        "SELECT COUNT(*) FROM events \
+                let count: i64 = guard.query_row(
+                    "SELECT COUNT(*) FROM events \
                          WHERE site_id = ? \
                          AND STRFTIME(CAST(timestamp AS DATE), '%Y-%m-%d') BETWEEN ? AND ?",
-                        duckdb::params![site_id, start_str, end_str],
-                        |row| row.get(0),
-                    )
-                    .unwrap_or(0);
+                    duckdb::params![site_id, start_str, end_str],
+                    |row| row.get(0),
+                )?;
                 guard.execute(
                     "DELETE FROM events \
                      WHERE site_id = ? \
                      AND STRFTIME(CAST(timestamp AS DATE), '%Y-%m-%d') BETWEEN ? AND ?",
                     duckdb::params![site_id, start_str, end_str],
                 )?;
+                guard.execute(
+                    "DELETE FROM visitor_users \
+                     WHERE site_id = ? \
+                     AND STRFTIME(CAST(last_seen AS DATE), '%Y-%m-%d') BETWEEN ? AND ?",
+                    duckdb::params![site_id, start_str, end_str],
+                )?;
                 // `guard` is dropped here, releasing the DuckDB mutex before the
                 // (potentially slow) filesystem operations below.
                 count
@@ -981,19 +2589,19 @@ pub async fn gdpr_erase(
 
             // Step 2: Remove on-disk Parquet partition directories for the date range.
             //
-            // Partition layout: {events_dir}/site_id={site_id}/date={YYYY-MM-DD}/
+            // Partition layout: {events_dir}/[tenant_id={tenant}/]site_id={site_id}/date={YYYY-MM-DD}/
             // Because data is partitioned by both site_id and date, removing a date
             // directory deletes only that site's data for that day — other sites are
-            // unaffected.
+            // unaffected.  Every tenant directory is checked, so files written
+            // before the site changed tenant are erased too.  The files are
+            // unlisted from the site's manifest before the directory goes.
+            let storage = ParquetStorage::new(&events_dir);
             let mut parquet_removed: u64 = 0;
             let mut current = start_date;
             while current <= end_date {
                 let date_str = current.format("%Y-%m-%d").to_string();
-                let partition_dir = events_dir
-                    .join(format!("site_id={site_id}"))
-                    .join(format!("date={date_str}"));
-                if partition_dir.exists() {
-                    match std::fs::remove_dir_all(&partition_dir) {
+                for partition_dir in storage.existing_partition_dirs(&site_id, &date_str) {
+                    match remove_partition(&partition_dir) {
                         Ok(()) => {
                             parquet_removed += 1;
                             tracing::info!(
@@ -1018,14 +2626,16 @@ pub async fn gdpr_erase(
                 };
             }
 
-            // Step 3: Refresh the events_all VIEW — re-acquire the lock for this brief op.
-            crate::storage::schema::setup_query_view(&conn.lock(), &events_dir)?;
+            // Step 3: Refresh the events_all VIEW — re-acquire the lock for this brief op —
+            // and remove materialized sessions of the erased days.
+            let guard = conn.lock();
+            crate::storage::schema::setup_query_view(&guard, &events_dir)?;
+            crate::query::materialized_sessions::prune(&guard)?;
+            drop(guard);
 
             Ok((db_count, parquet_removed))
         })
-        .await
-        .map_err(|e| ApiError::Internal(format!("Erasure task panicked: {e}")))?
-        .map_err(ApiError::DatabaseError)?;
+        .await??;
 
     tracing::warn!(
         site_id = %params.site_id,
@@ -1036,14 +2646,14 @@ pub async fn gdpr_erase(
         "GDPR erasure completed"
     );
 
-    Ok(Json(serde_json::json!({
-        "status": "erased",
-        "site_id": params.site_id,
-        "start_date": params.start_date,
-        "end_date": params.end_date,
-        "db_records_deleted": db_records_deleted,
-        "parquet_partitions_deleted": parquet_partitions_deleted,
-    })))
+    Ok(Json(Erasure {
+        status: "erased",
+        site_id: params.site_id,
+        start_date: params.start_date,
+        end_date: params.end_date,
+        db_records_deleted,
+        parquet_partitions_deleted,
+    }))
 }
 
 /// Escape a CSV field to prevent CSV injection attacks.
@@ -1051,7 +2661,7 @@ pub async fn gdpr_erase(
 /// Wraps the field in double quotes and escapes internal double quotes.
 /// Prefixes fields starting with formula-triggering characters (`=`, `+`, `-`, `@`)
 /// with a single quote to neutralize them in spreadsheet applications.
-fn escape_csv_field(field: &str) -> String {
+pub fn escape_csv_field(field: &str) -> String {
     let escaped = field.replace('"', "\"\"");
     // Prefix formula-triggering characters to prevent CSV injection in spreadsheets
     if escaped.starts_with('=')
@@ -1140,6 +2750,40 @@ mod tests {
         assert_eq!(result, "pathname = '/it''s'");
     }
 
+    #[test]
+    fn test_parse_funnel_step_wildcards() {
+        assert_eq!(
+            parse_funnel_step("page~:/blog/*").unwrap(),
+            r"pathname LIKE '/blog/%' ESCAPE '\'"
+        );
+        assert_eq!(
+            parse_funnel_step("pageprefix:/docs").unwrap(),
+            r"pathname LIKE '/docs%' ESCAPE '\'"
+        );
+        // LIKE metacharacters in the path match literally.
+        assert_eq!(
+            parse_funnel_step("page~:/100%_off/*'s").unwrap(),
+            r"pathname LIKE '/100\%\_off/%''s' ESCAPE '\'"
+        );
+        assert!(parse_funnel_step("pageprefix:").is_err());
+    }
+
+    #[test]
+    fn test_parse_segment() {
+        assert_eq!(
+            parse_segment("a", "device:mobile").unwrap(),
+            "device_type = 'mobile'"
+        );
+        assert_eq!(
+            parse_segment("a", "source:google; page:/it's").unwrap(),
+            "referrer_source = 'google' AND pathname = '/it''s'"
+        );
+        assert!(parse_segment("a", "").is_err());
+        assert!(parse_segment("a", "country:").is_err());
+        assert!(parse_segment("b", "site_id:x").is_err());
+        assert!(parse_segment("b", &"os:Linux;".repeat(11)).is_err());
+    }
+
     #[test]
     fn test_parse_funnel_step_invalid_format() {
         assert!(parse_funnel_step("invalid").is_err());
@@ -1171,6 +2815,16 @@ mod tests {
         assert!(validate_site_id("my_analytics_site").is_ok());
     }
 
+    #[test]
+    fn test_all_sites_is_reserved() {
+        assert!(validate_site_id(ALL_SITES).is_err());
+        assert!(validate_stats_site_id(ALL_SITES).is_ok());
+        assert!(validate_stats_site_id("bad site").is_err());
+        assert!(validate_stats_site_id("@acme").is_ok());
+        assert!(validate_stats_site_id("@").is_err());
+        assert!(validate_stats_site_id("@bad group").is_err());
+    }
+
     #[test]
     fn test_validate_site_id_empty() {
         assert!(validate_site_id("").is_err());
@@ -1216,6 +2870,9 @@ mod tests {
             start_date: None,
             end_date: None,
             format: "xml".to_string(),
+            locale: None,
+            kind: "timeseries".to_string(),
+            limit: 100,
         };
         let date_range = params.date_range();
         assert!(date_range.is_ok());
@@ -1234,6 +2891,9 @@ mod tests {
             start_date: Some("2000-01-01".to_string()),
             end_date: Some("2030-01-01".to_string()),
             format: "csv".to_string(),
+            locale: None,
+            kind: "timeseries".to_string(),
+            limit: 100,
         };
         let err = params.date_range().unwrap_err();
         assert!(
@@ -1250,6 +2910,9 @@ mod tests {
             start_date: Some("2024-01-01".to_string()),
             end_date: Some("2024-06-30".to_string()),
             format: "csv".to_string(),
+            locale: None,
+            kind: "timeseries".to_string(),
+            limit: 100,
         };
         assert!(
             params.date_range().is_ok(),
@@ -1265,6 +2928,9 @@ mod tests {
             start_date: Some("2024-06-30".to_string()),
             end_date: Some("2024-01-01".to_string()),
             format: "csv".to_string(),
+            locale: None,
+            kind: "timeseries".to_string(),
+            limit: 100,
         };
         assert!(
             params.date_range().is_err(),
@@ -1280,6 +2946,9 @@ mod tests {
             start_date: Some("not-a-date".to_string()),
             end_date: Some("2024-01-01".to_string()),
             format: "csv".to_string(),
+            locale: None,
+            kind: "timeseries".to_string(),
+            limit: 100,
         };
         assert!(
             params.date_range().is_err(),
@@ -1297,6 +2966,9 @@ mod tests {
             start_date: None,
             end_date: None,
             format: "csv".to_string(),
+            locale: None,
+            kind: "timeseries".to_string(),
+            limit: 100,
         };
         assert!(
             params.date_range().is_ok(),
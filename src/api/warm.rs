@@ -0,0 +1,163 @@
+//! Query cache warming.
+//!
+//! After a background flush, the main stats and timeseries the dashboard
+//! loads first are computed for the day's busiest sites and stored in the
+//! query cache under the keys their endpoints use.  The first dashboard load
+//! after a quiet period is then served from the cache instead of waiting for
+//! cold queries over weeks of Parquet files.
+
+use crate::api::stats::{
+    main_cache_key, timeseries_cache_key, timeseries_granularity, StatsParams,
+};
+use crate::ingest::handler::AppState;
+use crate::query::cache::QueryCache;
+use crate::query::sessions::SessionSql;
+use crate::query::slow::SlowQueryLog;
+use crate::query::{metrics, timeseries, QueryConn};
+use duckdb::Connection;
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Periods warmed, as the dashboard requests them.
+pub const PERIODS: [&str; 2] = ["7d", "30d"];
+
+/// Warm the cache for up to `max_sites` sites on a blocking thread, unless a
+/// run is already in progress for this server.  0 disables warming.
+pub fn spawn(state: Arc<AppState>, max_sites: usize) {
+    if max_sites == 0 || state.cache_warming.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    tokio::task::spawn_blocking(move || {
+        let _done = Done(&state.cache_warming);
+        match warm_cache(
+            state.buffer.conn(),
+            &state.slow_queries,
+            &state.query_cache,
+            max_sites,
+        ) {
+            Ok(entries) => tracing::debug!(entries, "Warmed query cache"),
+            Err(e) => tracing::warn!(error = %e, "Query cache warming failed"),
+        }
+    });
+}
+
+/// Clears the running flag when dropped, even if the run panics.
+struct Done<'a>(&'a AtomicBool);
+
+impl Drop for Done<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// Compute the main stats and timeseries of every period in [`PERIODS`] for
+/// the `max_sites` sites with the most visitors today into `cache`.
+///
+/// Blocking.  The connection is locked for one query at a time, so requests
+/// are not held up for the whole run.  Slow queries are recorded in
+/// `slow_queries`.  Returns the number of entries cached.
+pub fn warm_cache(
+    conn: &Mutex<Connection>,
+    slow_queries: &Arc<SlowQueryLog>,
+    cache: &QueryCache,
+    max_sites: usize,
+) -> Result<usize, duckdb::Error> {
+    let today = chrono::Utc::now().date_naive();
+    let tomorrow = today + chrono::Days::new(1);
+    let sites = {
+        let conn = QueryConn::lock(conn, slow_queries);
+        metrics::query_site_summary_estimates(&conn, &today.to_string(), &tomorrow.to_string())?
+    };
+
+    let mut cached = 0;
+    for site in sites.into_iter().take(max_sites) {
+        for period in PERIODS {
+            let params = StatsParams {
+                site_id: site.site_id.clone(),
+                period: period.to_string(),
+                start_date: None,
+                end_date: None,
+            };
+            let Ok((start, end)) = params.validate_and_date_range() else {
+                continue;
+            };
+
+            let main = {
+                let conn = QueryConn::lock(conn, slow_queries);
+                metrics::query_core_metrics(
+                    &conn,
+                    &site.site_id,
+                    &start,
+                    &end,
+                    SessionSql::Primary,
+                )?
+            };
+            if let Ok(serialized) = serde_json::to_string(&main) {
+                cache.insert(main_cache_key(&site.site_id, &start, &end), serialized);
+                cached += 1;
+            }
+
+            let granularity = timeseries_granularity(period);
+            let series = {
+                let conn = QueryConn::lock(conn, slow_queries);
+                timeseries::query_timeseries(&conn, &site.site_id, &start, &end, granularity)?
+            };
+            if let Ok(serialized) = serde_json::to_string(&series) {
+                cache.insert(
+                    timeseries_cache_key(&site.site_id, &start, &end, granularity),
+                    serialized,
+                );
+                cached += 1;
+            }
+        }
+    }
+    Ok(cached)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_warm_cache_fills_dashboard_entries() {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::storage::schema::init_schema(&conn).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        crate::storage::schema::setup_query_view(&conn, dir.path()).unwrap();
+        for (site_id, visitor_id) in [("a.com", "v1"), ("a.com", "v2"), ("b.com", "v3")] {
+            conn.execute(
+                "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+                 VALUES (?, ?, current_timestamp, 'pageview', '/')",
+                duckdb::params![site_id, visitor_id],
+            )
+            .unwrap();
+        }
+        let conn = Mutex::new(conn);
+        let cache = QueryCache::new(60, 0);
+
+        // Only the busiest site: main and timeseries for each period.
+        assert_eq!(warm_cache(&conn, &Arc::default(), &cache, 1).unwrap(), 4);
+        let (start, end) = StatsParams {
+            site_id: "a.com".to_string(),
+            period: "7d".to_string(),
+            start_date: None,
+            end_date: None,
+        }
+        .date_range()
+        .unwrap();
+        let main: metrics::CoreMetrics =
+            serde_json::from_str(&cache.get(&main_cache_key("a.com", &start, &end)).unwrap())
+                .unwrap();
+        assert_eq!(main.unique_visitors, 2);
+        assert!(cache
+            .get(&timeseries_cache_key(
+                "a.com",
+                &start,
+                &end,
+                timeseries::Granularity::Day
+            ))
+            .is_some());
+        assert_eq!(cache.len(), 4);
+    }
+}
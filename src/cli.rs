@@ -0,0 +1,456 @@
+//! Command-line interface: `serve` (the default) and one-off admin commands
+//! that share the server's storage code.
+
+use crate::api::auth::{
+    generate_api_key, hash_password, save_password_hash, ApiKeyLimits, ApiKeyScope, ApiKeyStore,
+};
+use crate::config::Config;
+use crate::ingest::buffer::EventBuffer;
+use crate::loadgen::{self, LoadOptions, TrafficGenerator};
+use crate::storage::maintenance::{self, ExportFilter};
+use chrono::NaiveDate;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use duckdb::Connection;
+use parking_lot::Mutex;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "mallard-metrics",
+    version,
+    about,
+    args_conflicts_with_subcommands = true
+)]
+pub struct Cli {
+    /// Config file for the server; shorthand for `serve --config CONFIG`.
+    #[arg(value_name = "CONFIG")]
+    config_file: Option<PathBuf>,
+
+    /// Path to the TOML config file.
+    #[arg(short, long, global = true, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub serve: ServeArgs,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+impl Cli {
+    /// The config file, however it was given.
+    pub fn config_path(&self) -> Option<&Path> {
+        self.config.as_deref().or(self.config_file.as_deref())
+    }
+}
+
+/// Commands other than `serve` open the database directly, so the server
+/// must be stopped first.
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the analytics server (the default).
+    Serve(ServeArgs),
+    /// Write events still buffered in the database to Parquet.
+    Flush,
+    /// Merge the Parquet files of each partition into one.
+    Compact {
+        /// Only compact this site's partitions.
+        #[arg(long)]
+        site_id: Option<String>,
+    },
+    /// Import events from a .csv, .json/.ndjson or .parquet file.
+    Import { file: PathBuf },
+    /// Export events to a .csv, .json/.ndjson or .parquet file.
+    Export {
+        file: PathBuf,
+        #[arg(long)]
+        site_id: Option<String>,
+        /// Export the events of every site of this tenant.
+        #[arg(long, conflicts_with = "site_id")]
+        tenant: Option<String>,
+        /// First day to export, YYYY-MM-DD.
+        #[arg(long)]
+        start_date: Option<NaiveDate>,
+        /// Last day to export, YYYY-MM-DD.
+        #[arg(long)]
+        end_date: Option<NaiveDate>,
+    },
+    /// Create an API key and print it.
+    CreateApiKey {
+        #[arg(long)]
+        name: String,
+        #[arg(long, value_enum, default_value_t = Scope::ReadOnly)]
+        scope: Scope,
+        /// Limit a read-only key to one site group.
+        #[arg(long)]
+        group: Option<String>,
+        /// Limit a write-events key to one site.
+        #[arg(long)]
+        site_id: Option<String>,
+        /// Limit a read-only or write-events key to one tenant's sites.
+        #[arg(long)]
+        tenant: Option<String>,
+        /// Reject the key this many days from now.
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+        expires_in_days: Option<u32>,
+    },
+    /// Read a password from stdin and print its hash for
+    /// MALLARD_ADMIN_PASSWORD_HASH.
+    HashPassword,
+    /// Read a new dashboard password from stdin and replace the one set up
+    /// through the dashboard. Takes effect on the next server start.
+    ResetPassword,
+    /// Apply pending database migrations.
+    Migrate,
+    /// Check every Parquet file for damage, schema drift, misplaced or
+    /// duplicated rows and timestamp outliers; exits non-zero on problems.
+    VerifyData,
+    /// Generate synthetic traffic: days of history written straight to
+    /// Parquet, or with --url, load sent to a running server.
+    Generate {
+        /// Sites to generate traffic for; defaults to `site_ids` or
+        /// example.com.
+        #[arg(long, value_delimiter = ',')]
+        sites: Vec<String>,
+        /// Send events to the server at this base URL instead of writing
+        /// history, e.g. http://localhost:8000.
+        #[arg(long)]
+        url: Option<String>,
+        /// Days of history, ending today.
+        #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u32).range(1..))]
+        days: u32,
+        /// Visitors per site on a weekday.
+        #[arg(long, default_value_t = 500)]
+        visitors: u32,
+        /// Events to send with --url.
+        #[arg(long, default_value_t = 10_000)]
+        events: usize,
+        /// Events per second to send with --url; 0 for as fast as possible.
+        #[arg(long, default_value_t = 100)]
+        rate: u32,
+        /// Requests in flight at once with --url.
+        #[arg(long, default_value_t = 16)]
+        concurrency: usize,
+        /// Seed for repeatable traffic; random by default.
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+}
+
+#[derive(Debug, Default, Args)]
+pub struct ServeArgs {
+    /// Fill the demo site with several weeks of sample traffic, unless it
+    /// already has events.
+    #[arg(long)]
+    pub seed_demo_data: bool,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Scope {
+    ReadOnly,
+    Admin,
+    WriteEvents,
+}
+
+impl From<Scope> for ApiKeyScope {
+    fn from(scope: Scope) -> Self {
+        match scope {
+            Scope::ReadOnly => Self::ReadOnly,
+            Scope::Admin => Self::Admin,
+            Scope::WriteEvents => Self::WriteEvents,
+        }
+    }
+}
+
+/// Open the server's database, migrated and with `events_all` set up.
+fn open_database(config: &Config) -> Result<Connection, Box<dyn Error>> {
+    std::fs::create_dir_all(config.events_dir())?;
+    let conn = Connection::open(config.db_path()).map_err(|e| {
+        format!(
+            "cannot open {} ({e}); is the server still running?",
+            config.db_path().display()
+        )
+    })?;
+    crate::storage::resources::apply_limits(
+        &conn,
+        config.duckdb_memory_limit.as_deref(),
+        config.duckdb_threads,
+    )?;
+    crate::storage::resources::apply_temp_directory(
+        &conn,
+        &config.duckdb_temp_dir(),
+        config.duckdb_max_temp_size.as_deref(),
+    )?;
+    crate::storage::migrations::run_migrations(&conn)?;
+    config.parquet_storage().reconcile_flushes(&conn)?;
+    crate::storage::schema::setup_query_view(&conn, &config.events_dir())?;
+    Ok(conn)
+}
+
+/// Run a one-off command other than `serve`.
+pub async fn run(command: Command, config: &Config) -> Result<(), Box<dyn Error>> {
+    match command {
+        Command::Serve(_) => unreachable!("serve is handled by main"),
+        Command::Flush => {
+            let conn = open_database(config)?;
+            let count = config.parquet_storage().flush_events(&conn)?;
+            println!("Flushed {count} events");
+        }
+        Command::Compact { site_id } => {
+            let conn = open_database(config)?;
+            let count = maintenance::compact_partitions(
+                &conn,
+                &config.parquet_storage(),
+                site_id.as_deref(),
+            )?;
+            println!("Compacted {count} partitions");
+        }
+        Command::Import { file } => {
+            let conn = open_database(config)?;
+            let count = maintenance::import_events(&conn, &file)?;
+            config.parquet_storage().flush_events(&conn)?;
+            println!("Imported {count} events");
+        }
+        Command::Export {
+            file,
+            site_id,
+            tenant,
+            start_date,
+            end_date,
+        } => {
+            let tenant_sites = tenant
+                .map(|tenant| tenant_sites(config, &tenant))
+                .transpose()?;
+            let conn = open_database(config)?;
+            let filter = ExportFilter {
+                site_id: site_id.as_deref(),
+                site_ids: tenant_sites.as_deref(),
+                start_date,
+                end_date,
+            };
+            let count = maintenance::export_events(&conn, &file, &filter)?;
+            println!("Exported {count} events to {}", file.display());
+        }
+        Command::CreateApiKey {
+            name,
+            scope,
+            group,
+            site_id,
+            tenant,
+            expires_in_days,
+        } => {
+            create_api_key(
+                config,
+                &name,
+                scope,
+                group.as_deref(),
+                site_id.as_deref(),
+                tenant.as_deref(),
+                expires_in_days,
+            )?;
+        }
+        Command::HashPassword => {
+            println!("{}", read_password_hash()?);
+        }
+        Command::ResetPassword => reset_password(config)?,
+        Command::Migrate => migrate(config)?,
+        Command::VerifyData => verify_data(config)?,
+        Command::Generate {
+            sites,
+            url,
+            days,
+            visitors,
+            events,
+            rate,
+            concurrency,
+            seed,
+        } => {
+            let sites = generate_sites(config, sites)?;
+            let mut generator = TrafficGenerator::new(seed.unwrap_or_else(rand::random));
+            if let Some(url) = url {
+                let options = LoadOptions {
+                    url,
+                    sites,
+                    events,
+                    rate,
+                    concurrency,
+                };
+                println!("{}", loadgen::send_load(&mut generator, &options).await?);
+            } else {
+                generate_history(config, &mut generator, &sites, days, visitors)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Bring the database schema up to date.
+fn migrate(config: &Config) -> Result<(), Box<dyn Error>> {
+    let conn = Connection::open(config.db_path())?;
+    let before = crate::storage::migrations::get_current_version(&conn)?;
+    crate::storage::migrations::run_migrations(&conn)?;
+    let after = crate::storage::migrations::get_current_version(&conn)?;
+    if before == after {
+        println!("Database schema already at version {after}");
+    } else {
+        println!("Migrated database schema from version {before} to {after}");
+    }
+    Ok(())
+}
+
+/// Check the stored Parquet files, failing if any problem is found.
+fn verify_data(config: &Config) -> Result<(), Box<dyn Error>> {
+    let conn = open_database(config)?;
+    let report = maintenance::verify_partitions(&conn, &config.events_dir())?;
+    for problem in &report.problems {
+        println!("{problem}");
+    }
+    if !report.problems.is_empty() {
+        return Err(format!("{} problems found", report.problems.len()).into());
+    }
+    println!(
+        "All {} Parquet files OK ({} rows)",
+        report.files_checked, report.rows_checked
+    );
+    Ok(())
+}
+
+/// Write `days` days of synthetic history for `sites`, up to today.
+fn generate_history(
+    config: &Config,
+    generator: &mut TrafficGenerator,
+    sites: &[String],
+    days: u32,
+    visitors: u32,
+) -> Result<(), Box<dyn Error>> {
+    let conn = open_database(config)?;
+    let buffer = EventBuffer::new(50_000, Arc::new(Mutex::new(conn)), config.parquet_storage());
+    let end = chrono::Utc::now().date_naive();
+    let start = end - chrono::Duration::days(i64::from(days - 1));
+    let count = loadgen::write_history(generator, &buffer, sites, start, end, visitors)?;
+    println!(
+        "Generated {count} events for {} sites from {start} to {end}",
+        sites.len()
+    );
+    Ok(())
+}
+
+/// The sites to generate traffic for: `sites`, else the configured site IDs,
+/// else example.com.
+fn generate_sites(config: &Config, mut sites: Vec<String>) -> Result<Vec<String>, Box<dyn Error>> {
+    if sites.is_empty() {
+        sites.clone_from(&config.site_ids);
+    }
+    if sites.is_empty() {
+        sites.push("example.com".to_string());
+    }
+    for site_id in &sites {
+        if crate::api::stats::validate_site_id(site_id).is_err() {
+            return Err(format!("invalid site ID {site_id:?}").into());
+        }
+    }
+    Ok(sites)
+}
+
+/// Read a password from stdin, with the same checks as `POST /api/auth/setup`,
+/// and hash it.
+fn read_password_hash() -> Result<String, Box<dyn Error>> {
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.len() < 8 {
+        return Err("password must be at least 8 characters".into());
+    }
+    hash_password(password).map_err(|e| e.to_string().into())
+}
+
+/// Overwrite the persisted admin password hash.  Sessions are held in memory,
+/// so restarting the server to pick up the new password also logs everyone
+/// out.
+fn reset_password(config: &Config) -> Result<(), Box<dyn Error>> {
+    let hash = read_password_hash()?;
+    std::fs::create_dir_all(&config.data_dir)?;
+    let path = config.admin_password_path();
+    save_password_hash(&path, &hash)?;
+    tracing::warn!(path = %path.display(), "Admin password reset from the command line");
+    for var in ["MALLARD_ADMIN_PASSWORD_HASH", "MALLARD_ADMIN_PASSWORD"] {
+        if std::env::var(var).is_ok_and(|v| !v.is_empty()) {
+            eprintln!("Warning: {var} is set and takes precedence over the reset password");
+        }
+    }
+    println!("Admin password reset; restart the server to apply it");
+    Ok(())
+}
+
+/// Add a key to the store on disk, with the same checks as `POST /api/keys`.
+fn create_api_key(
+    config: &Config,
+    name: &str,
+    scope: Scope,
+    group: Option<&str>,
+    site_id: Option<&str>,
+    tenant: Option<&str>,
+    expires_in_days: Option<u32>,
+) -> Result<(), Box<dyn Error>> {
+    if name.is_empty() || name.len() > 128 {
+        return Err("key name must be 1-128 characters".into());
+    }
+    let scope = ApiKeyScope::from(scope);
+    if let Some(group) = group {
+        if scope != ApiKeyScope::ReadOnly {
+            return Err("only read-only keys can be limited to a group".into());
+        }
+        if !config
+            .sites
+            .values()
+            .any(|s| s.group.as_deref() == Some(group))
+        {
+            return Err(format!("unknown site group {group:?}").into());
+        }
+    }
+    if let Some(site_id) = site_id {
+        if scope != ApiKeyScope::WriteEvents {
+            return Err("only write-events keys can be limited to a site".into());
+        }
+        if !config.sites.contains_key(site_id) {
+            return Err(format!("unknown site {site_id:?}").into());
+        }
+    }
+    if let Some(tenant) = tenant {
+        if scope == ApiKeyScope::Admin {
+            return Err("admin keys cannot be limited to a tenant".into());
+        }
+        tenant_sites(config, tenant)?;
+    }
+
+    let expires_at = expires_in_days
+        .map(|days| chrono::Utc::now().naive_utc() + chrono::Duration::days(i64::from(days)));
+
+    let store = ApiKeyStore::load_from_disk(config.api_keys_path());
+    let key = generate_api_key();
+    let limits = ApiKeyLimits {
+        group,
+        site_id,
+        tenant,
+    };
+    store.add_key(name, &key, scope, limits, expires_at);
+    println!("{key}");
+    Ok(())
+}
+
+/// The sites of `tenant`, sorted; an error if it has none.
+fn tenant_sites(config: &Config, tenant: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut sites: Vec<String> = config
+        .sites
+        .iter()
+        .filter(|(_, site)| site.tenant.as_deref() == Some(tenant))
+        .map(|(site_id, _)| site_id.clone())
+        .collect();
+    if sites.is_empty() {
+        return Err(format!("unknown tenant {tenant:?}").into());
+    }
+    sites.sort();
+    Ok(sites)
+}
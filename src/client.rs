@@ -0,0 +1,522 @@
+//! Typed HTTP client for a Mallard Metrics server (`client` feature).
+//!
+//! [`MallardClient`] submits events and reads the stats API with the same
+//! parameter and response types the server's handlers use, so Rust
+//! consumers need not hand-roll requests or mirror the JSON:
+//!
+//! ```text
+//! let client = MallardClient::new("https://analytics.example.com").api_key(key);
+//! let metrics = client
+//!     .stats_main(&StatsParams {
+//!         site_id: "example.com".to_string(),
+//!         period: "7d".to_string(),
+//!         start_date: None,
+//!         end_date: None,
+//!     })
+//!     .await?;
+//! ```
+//!
+//! Breakdown methods return the plain rows.  With `others` or `compare` set
+//! the server answers with a [`breakdowns::BreakdownCoverage`] or
+//! [`breakdowns::ComparedRow`]s instead; fetch those with
+//! [`MallardClient::get`].  File downloads (`format`, `/api/stats/export`)
+//! are not covered.
+
+use crate::api::stats::{
+    AttributedBreakdownParams, BreakdownParams, CompareParams, CustomBreakdownParams, DigestParams,
+    FlowParams, FunnelParams, PathsParams, RetentionParams, SequenceMatchResponse, SequenceParams,
+    SitesParams, SourceConversionsParams, StatsParams, VisitorParams,
+};
+use crate::ingest::handler::EventPayload;
+use crate::query::anomalies::Anomaly;
+use crate::query::breakdowns::{
+    BreakdownRow, ContinentRow, ConversionRow, CountryRow, CustomEventRow, DeviceModelRow,
+    EmailOpenRow,
+};
+use crate::query::digest::Digest;
+use crate::query::flow::FlowNode;
+use crate::query::funnel::FunnelStep;
+use crate::query::metrics::{CoreMetrics, SegmentComparison, SiteSummary};
+use crate::query::paths::PathRow;
+use crate::query::retention::RetentionCohort;
+use crate::query::sessions::{PageTime, SessionMetrics};
+use crate::query::timeseries::TimeBucket;
+use crate::query::visitor::VisitorProfile;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Longest wait for the server to answer a request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Why a [`MallardClient`] request failed.
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    /// The server could not be reached, or the request timed out.
+    #[error("request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    /// The server refused the request.
+    #[error("server answered {status}: {message}")]
+    Api {
+        status: u16,
+        /// The error's `code`, e.g. `validation_error`, when the server sent
+        /// one.
+        code: Option<String>,
+        message: String,
+    },
+    #[error("invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid visitor ID: {0}")]
+    InvalidVisitorId(String),
+}
+
+/// Error body of the API.
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: String,
+    code: Option<String>,
+}
+
+/// Client for the ingestion and stats API of one Mallard Metrics server.
+#[derive(Clone)]
+pub struct MallardClient {
+    http: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+}
+
+impl MallardClient {
+    /// Client for the server at `base_url`, e.g.
+    /// `https://analytics.example.com`.
+    pub fn new(base_url: impl Into<String>) -> Self {
+        let mut base_url = base_url.into();
+        while base_url.ends_with('/') {
+            base_url.pop();
+        }
+        Self {
+            http: reqwest::Client::new(),
+            base_url,
+            api_key: None,
+        }
+    }
+
+    /// Send `key` as a bearer API key: a read key for the stats API, or a
+    /// write key for sites with `require_ingest_key`.
+    #[must_use]
+    pub fn api_key(mut self, key: impl Into<String>) -> Self {
+        self.api_key = Some(key.into());
+        self
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let request = self
+            .http
+            .request(method, format!("{}{path}", self.base_url))
+            .timeout(REQUEST_TIMEOUT);
+        match &self.api_key {
+            Some(key) => request.bearer_auth(key),
+            None => request,
+        }
+    }
+
+    /// POST /api/event — Submit an event.
+    pub async fn send_event(&self, event: &EventPayload) -> Result<(), ClientError> {
+        self.post_event(event, None).await
+    }
+
+    /// POST /api/event — Submit an event on behalf of the visitor at `ip`
+    /// using `user_agent`, as a backend recording its own visitors does, so
+    /// each visitor is counted apart.
+    pub async fn send_event_as(
+        &self,
+        event: &EventPayload,
+        ip: &str,
+        user_agent: &str,
+    ) -> Result<(), ClientError> {
+        self.post_event(event, Some((ip, user_agent))).await
+    }
+
+    async fn post_event(
+        &self,
+        event: &EventPayload,
+        visitor: Option<(&str, &str)>,
+    ) -> Result<(), ClientError> {
+        let mut request = self
+            .request(reqwest::Method::POST, "/api/event")
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_string(event)?);
+        if let Some((ip, user_agent)) = visitor {
+            request = request
+                .header("x-forwarded-for", ip)
+                .header(reqwest::header::USER_AGENT, user_agent);
+        }
+        check(request.send().await?).await.map(drop)
+    }
+
+    /// GET `path` with `params` as its query string, decoding the JSON
+    /// response as `T`.
+    pub async fn get<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        params: &(impl Serialize + Sync + ?Sized),
+    ) -> Result<T, ClientError> {
+        let response = self
+            .request(reqwest::Method::GET, path)
+            .query(params)
+            .send()
+            .await?;
+        let body = check(response).await?.bytes().await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// GET /api/stats/main — Core metrics.
+    pub async fn stats_main(&self, params: &StatsParams) -> Result<CoreMetrics, ClientError> {
+        self.get("/api/stats/main", params).await
+    }
+
+    /// GET /api/stats/timeseries — Visitors and pageviews over time.
+    pub async fn stats_timeseries(
+        &self,
+        params: &StatsParams,
+    ) -> Result<Vec<TimeBucket>, ClientError> {
+        self.get("/api/stats/timeseries", params).await
+    }
+
+    /// GET /api/stats/compare — Core metrics of two segments.
+    pub async fn stats_compare(
+        &self,
+        params: &CompareParams,
+    ) -> Result<SegmentComparison, ClientError> {
+        self.get("/api/stats/compare", params).await
+    }
+
+    /// GET /api/stats/digest — Plain-language summary of a period.
+    pub async fn stats_digest(&self, params: &DigestParams) -> Result<Digest, ClientError> {
+        self.get("/api/stats/digest", params).await
+    }
+
+    /// GET /api/stats/breakdown/pages — Top pages.
+    pub async fn stats_pages(
+        &self,
+        params: &BreakdownParams,
+    ) -> Result<Vec<BreakdownRow>, ClientError> {
+        self.get("/api/stats/breakdown/pages", params).await
+    }
+
+    /// GET /api/stats/breakdown/sources — Top referrer sources.  With an
+    /// `attribution` model the rows are `AttributedRow`s; fetch them with
+    /// [`MallardClient::get`].
+    pub async fn stats_sources(
+        &self,
+        params: &AttributedBreakdownParams,
+    ) -> Result<Vec<BreakdownRow>, ClientError> {
+        self.get("/api/stats/breakdown/sources", params).await
+    }
+
+    /// GET /api/stats/breakdown/campaigns — Top UTM campaigns.  As
+    /// [`MallardClient::stats_sources`] for `attribution`.
+    pub async fn stats_campaigns(
+        &self,
+        params: &AttributedBreakdownParams,
+    ) -> Result<Vec<BreakdownRow>, ClientError> {
+        self.get("/api/stats/breakdown/campaigns", params).await
+    }
+
+    /// GET /api/stats/breakdown/browsers — Browsers.
+    pub async fn stats_browsers(
+        &self,
+        params: &BreakdownParams,
+    ) -> Result<Vec<BreakdownRow>, ClientError> {
+        self.get("/api/stats/breakdown/browsers", params).await
+    }
+
+    /// GET /api/stats/breakdown/os — Operating systems.
+    pub async fn stats_os(
+        &self,
+        params: &BreakdownParams,
+    ) -> Result<Vec<BreakdownRow>, ClientError> {
+        self.get("/api/stats/breakdown/os", params).await
+    }
+
+    /// GET /api/stats/breakdown/devices — Device types.
+    pub async fn stats_devices(
+        &self,
+        params: &BreakdownParams,
+    ) -> Result<Vec<BreakdownRow>, ClientError> {
+        self.get("/api/stats/breakdown/devices", params).await
+    }
+
+    /// GET /api/stats/breakdown/devices_models — Mobile device models.
+    pub async fn stats_device_models(
+        &self,
+        params: &BreakdownParams,
+    ) -> Result<Vec<DeviceModelRow>, ClientError> {
+        self.get("/api/stats/breakdown/devices_models", params)
+            .await
+    }
+
+    /// GET /api/stats/breakdown/countries — Countries.
+    pub async fn stats_countries(
+        &self,
+        params: &BreakdownParams,
+    ) -> Result<Vec<CountryRow>, ClientError> {
+        self.get("/api/stats/breakdown/countries", params).await
+    }
+
+    /// GET /api/stats/breakdown/continents — Continents.
+    pub async fn stats_continents(
+        &self,
+        params: &BreakdownParams,
+    ) -> Result<Vec<ContinentRow>, ClientError> {
+        self.get("/api/stats/breakdown/continents", params).await
+    }
+
+    /// GET /api/stats/breakdown/search_terms — Search engine keywords.
+    pub async fn stats_search_terms(
+        &self,
+        params: &BreakdownParams,
+    ) -> Result<Vec<BreakdownRow>, ClientError> {
+        self.get("/api/stats/breakdown/search_terms", params).await
+    }
+
+    /// GET /api/stats/breakdown/email_opens — Email opens per campaign.
+    pub async fn stats_email_opens(
+        &self,
+        params: &BreakdownParams,
+    ) -> Result<Vec<EmailOpenRow>, ClientError> {
+        self.get("/api/stats/breakdown/email_opens", params).await
+    }
+
+    /// GET /api/stats/breakdown/custom — Values of a custom dimension.
+    pub async fn stats_custom(
+        &self,
+        params: &CustomBreakdownParams,
+    ) -> Result<Vec<BreakdownRow>, ClientError> {
+        self.get("/api/stats/breakdown/custom", params).await
+    }
+
+    /// GET /api/stats/events — Custom events with their property keys.
+    pub async fn stats_events(
+        &self,
+        params: &BreakdownParams,
+    ) -> Result<Vec<CustomEventRow>, ClientError> {
+        self.get("/api/stats/events", params).await
+    }
+
+    /// GET /api/stats/sources/conversions — Goal conversions per channel.
+    pub async fn stats_source_conversions(
+        &self,
+        params: &SourceConversionsParams,
+    ) -> Result<Vec<ConversionRow>, ClientError> {
+        self.get("/api/stats/sources/conversions", params).await
+    }
+
+    /// GET /api/stats/sessions — Session metrics.
+    pub async fn stats_sessions(
+        &self,
+        params: &StatsParams,
+    ) -> Result<SessionMetrics, ClientError> {
+        self.get("/api/stats/sessions", params).await
+    }
+
+    /// GET /api/stats/time_on_page — Time spent on each page.
+    pub async fn stats_time_on_page(
+        &self,
+        params: &BreakdownParams,
+    ) -> Result<Vec<PageTime>, ClientError> {
+        self.get("/api/stats/time_on_page", params).await
+    }
+
+    /// GET /api/stats/funnel — Conversion funnel.
+    pub async fn stats_funnel(
+        &self,
+        params: &FunnelParams,
+    ) -> Result<Vec<FunnelStep>, ClientError> {
+        self.get("/api/stats/funnel", params).await
+    }
+
+    /// GET /api/stats/retention — Cohort retention.
+    pub async fn stats_retention(
+        &self,
+        params: &RetentionParams,
+    ) -> Result<Vec<RetentionCohort>, ClientError> {
+        self.get("/api/stats/retention", params).await
+    }
+
+    /// GET /api/stats/sequences — Visitors completing a sequence of events.
+    pub async fn stats_sequences(
+        &self,
+        params: &SequenceParams,
+    ) -> Result<SequenceMatchResponse, ClientError> {
+        self.get("/api/stats/sequences", params).await
+    }
+
+    /// GET /api/stats/flow — Pages visited after a page.
+    pub async fn stats_flow(&self, params: &FlowParams) -> Result<Vec<FlowNode>, ClientError> {
+        self.get("/api/stats/flow", params).await
+    }
+
+    /// GET /api/stats/paths — Most common navigation paths.
+    pub async fn stats_paths(&self, params: &PathsParams) -> Result<Vec<PathRow>, ClientError> {
+        self.get("/api/stats/paths", params).await
+    }
+
+    /// GET /api/stats/anomalies — Unusual hourly traffic.
+    pub async fn stats_anomalies(&self, params: &StatsParams) -> Result<Vec<Anomaly>, ClientError> {
+        self.get("/api/stats/anomalies", params).await
+    }
+
+    /// GET /api/stats/sites — Traffic of every site.
+    pub async fn stats_sites(&self, params: &SitesParams) -> Result<Vec<SiteSummary>, ClientError> {
+        self.get("/api/stats/sites", params).await
+    }
+
+    /// GET /api/stats/visitor/{visitor_id} — One visitor's profile (requires
+    /// admin).
+    pub async fn stats_visitor(
+        &self,
+        visitor_id: &str,
+        params: &VisitorParams,
+    ) -> Result<VisitorProfile, ClientError> {
+        // Kept to the characters the server accepts, so the ID is a single
+        // path segment.
+        if visitor_id.is_empty()
+            || !visitor_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            return Err(ClientError::InvalidVisitorId(visitor_id.to_string()));
+        }
+        self.get(&format!("/api/stats/visitor/{visitor_id}"), params)
+            .await
+    }
+}
+
+/// `response`, or the error it carries.
+async fn check(response: reqwest::Response) -> Result<reqwest::Response, ClientError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.bytes().await.unwrap_or_default();
+    let (code, message) = match serde_json::from_slice::<ErrorBody>(&body) {
+        Ok(error) => (error.code, error.error),
+        Err(_) => (
+            None,
+            status
+                .canonical_reason()
+                .unwrap_or("unknown error")
+                .to_string(),
+        ),
+    };
+    Err(ClientError::Api {
+        status: status.as_u16(),
+        code,
+        message,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embed::MallardServer;
+
+    fn params(site_id: &str) -> StatsParams {
+        StatsParams {
+            site_id: site_id.to_string(),
+            period: "7d".to_string(),
+            start_date: None,
+            end_date: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sends_events_and_reads_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = crate::config::Config {
+            data_dir: dir.path().to_path_buf(),
+            ..crate::config::Config::default()
+        };
+        let server = MallardServer::builder()
+            .config(config)
+            .build()
+            .await
+            .unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = server.router();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        let client = MallardClient::new(format!("http://{addr}/"));
+
+        for (ip, page) in [("203.0.113.1", "/"), ("203.0.113.2", "/pricing")] {
+            let event = EventPayload {
+                domain: "example.com".to_string(),
+                name: "pageview".to_string(),
+                url: format!("https://example.com{page}"),
+                referrer: None,
+                screen_width: None,
+                props: None,
+                revenue_amount: None,
+                revenue_currency: None,
+                event_id: None,
+                prev_pathname: None,
+                user_id: None,
+                timestamp: None,
+            };
+            client
+                .send_event_as(
+                    &event,
+                    ip,
+                    "Mozilla/5.0 (X11; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0",
+                )
+                .await
+                .unwrap();
+        }
+        for _ in 0..500 {
+            if server.state().buffer.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        server.state().buffer.flush().unwrap();
+
+        let metrics = client.stats_main(&params("example.com")).await.unwrap();
+        assert_eq!(metrics.unique_visitors, 2);
+        assert_eq!(metrics.total_pageviews, 2);
+        let pages = client
+            .stats_pages(&BreakdownParams {
+                site_id: "example.com".to_string(),
+                period: "7d".to_string(),
+                start_date: None,
+                end_date: None,
+                limit: 10,
+                others: false,
+                compare: false,
+                goal: None,
+                format: None,
+                locale: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(pages.len(), 2);
+        assert!(pages.iter().all(|row| row.visitors == 1));
+
+        let error = client.stats_main(&params("not a site")).await.unwrap_err();
+        assert!(
+            matches!(error, ClientError::Api { status: 400, .. }),
+            "{error}"
+        );
+        assert!(matches!(
+            client
+                .stats_visitor(
+                    "../keys",
+                    &VisitorParams {
+                        site_id: "example.com".to_string()
+                    }
+                )
+                .await,
+            Err(ClientError::InvalidVisitorId(_))
+        ));
+        server.shutdown().await;
+    }
+}
@@ -1,5 +1,854 @@
-use serde::Deserialize;
+use crate::ingest::queue::OverloadPolicy;
+use crate::storage::parquet::{ParquetCompression, ParquetStorage, ParquetWriteOptions, Tenants};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Maximum number of custom dimensions a single site may declare.
+///
+/// Each dimension occupies one of the fixed `dim_1` … `dim_5` columns in the
+/// events table, so raising this limit requires a schema migration.
+pub const MAX_CUSTOM_DIMENSIONS: usize = 5;
+
+/// Value type of a custom dimension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DimensionType {
+    String,
+    Number,
+    Bool,
+}
+
+/// A typed custom dimension extracted from the event `props` JSON at ingest time.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CustomDimension {
+    /// Key looked up in the top level of the props object.
+    pub name: String,
+    /// Declared value type. Values that do not match are dropped.
+    #[serde(rename = "type")]
+    pub kind: DimensionType,
+}
+
+/// A regex rewrite applied to pathnames at ingest, e.g. `^/users/\d+` →
+/// `/users/:id`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PathRule {
+    /// Regular expression matched against the pathname.
+    #[serde(deserialize_with = "deserialize_regex")]
+    pub pattern: regex::Regex,
+    /// Replacement for every match; `$1` and `${name}` refer to capture groups.
+    pub replace: String,
+}
+
+fn deserialize_regex<'de, D: serde::Deserializer<'de>>(d: D) -> Result<regex::Regex, D::Error> {
+    let pattern = String::deserialize(d)?;
+    regex::Regex::new(&pattern).map_err(serde::de::Error::custom)
+}
+
+/// What happens to events caught by a site's geographic blocking rules.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum GeoBlockAction {
+    /// Discard the event.
+    #[default]
+    Drop,
+    /// Store the event under `<site_id>:geo-restricted`, outside the site's
+    /// own stats.
+    Segregate,
+}
+
+/// Suffix of the site ID that segregated events are stored under.
+pub const GEO_RESTRICTED_SUFFIX: &str = ":geo-restricted";
+
+/// Per-site settings from the `[sites."<site_id>"]` tables of the config file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct SiteConfig {
+    /// Custom dimensions, stored in declaration order in `dim_1` … `dim_N`.
+    #[serde(default)]
+    pub custom_dimensions: Vec<CustomDimension>,
+    /// Site group, e.g. one per agency client.  Stats for a whole group are
+    /// queried with `site_id=@<group>`, and API keys can be limited to a group.
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Tenant the site belongs to, for hosting many customers on one
+    /// instance.  Its Parquet files are stored under `tenant_id=<tenant>/`,
+    /// and API keys can be limited to a tenant.
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// Events per second for this site, overriding `rate_limit_per_site`.
+    /// 0 exempts the site from rate limiting.
+    #[serde(default)]
+    pub rate_limit: Option<u32>,
+    /// Burst size for this site, overriding `rate_limit_burst`.
+    #[serde(default)]
+    pub rate_limit_burst: Option<u32>,
+    /// Keep `#/route` fragments as part of the pathname, for sites with a
+    /// hash-based client-side router.
+    #[serde(default)]
+    pub hash_mode: bool,
+    /// Lowercase pathnames at ingest.
+    #[serde(default)]
+    pub lowercase_paths: bool,
+    /// Drop the trailing slash from pathnames other than `/` at ingest.
+    #[serde(default)]
+    pub strip_trailing_slash: bool,
+    /// Rewrites applied to pathnames at ingest, in order.
+    #[serde(default)]
+    pub path_rules: Vec<PathRule>,
+    /// Query parameters kept in stored pathnames, e.g. `["page"]`.  All other
+    /// query parameters are stripped.
+    #[serde(default)]
+    pub query_params: Vec<String>,
+    /// Query parameter carrying site-search terms, e.g. `"q"`.  Kept in
+    /// stored pathnames and reported by `/api/stats/breakdown/search_terms`.
+    #[serde(default)]
+    pub search_param: Option<String>,
+    /// ISO 3166-1 country codes whose traffic is blocked, e.g. `["RU"]`.
+    #[serde(default)]
+    pub blocked_countries: Vec<String>,
+    /// Continent codes whose traffic is blocked, e.g. `["EU"]`.
+    #[serde(default)]
+    pub blocked_continents: Vec<String>,
+    /// What happens to blocked traffic (default: drop).
+    #[serde(default)]
+    pub geo_block_action: GeoBlockAction,
+    /// Accept events only with a `WriteEvents` API key for this site, for
+    /// sites tracked from a backend.  Browser tracking stops working.
+    #[serde(default)]
+    pub require_ingest_key: bool,
+    /// Serve this month's visitors and the total pageviews without
+    /// authentication at `GET /api/public/summary`, for CMS embeds.
+    #[serde(default)]
+    pub public_summary: bool,
+}
+
+impl SiteConfig {
+    /// Returns the 1-based storage slot and type of the named dimension.
+    pub fn dimension_slot(&self, name: &str) -> Option<(usize, DimensionType)> {
+        self.custom_dimensions
+            .iter()
+            .position(|d| d.name == name)
+            .map(|i| (i + 1, self.custom_dimensions[i].kind))
+    }
+
+    /// Whether the query parameter `name` is kept in stored pathnames.
+    pub fn keeps_query_param(&self, name: &str) -> bool {
+        self.search_param.as_deref() == Some(name) || self.query_params.iter().any(|p| p == name)
+    }
+
+    /// Whether traffic located in `country` / `continent` is blocked.  Traffic
+    /// that could not be located is never blocked.
+    pub fn geo_blocked(&self, country: Option<&str>, continent: Option<&str>) -> bool {
+        let listed = |codes: &[String], code: Option<&str>| {
+            code.is_some_and(|code| codes.iter().any(|c| c.eq_ignore_ascii_case(code)))
+        };
+        listed(&self.blocked_countries, country) || listed(&self.blocked_continents, continent)
+    }
+
+    /// Apply the site's path normalization: lowercasing, then each of
+    /// `path_rules`, then trailing-slash removal.
+    pub fn normalize_path(&self, path: &str) -> String {
+        let mut path = if self.lowercase_paths {
+            path.to_lowercase()
+        } else {
+            path.to_string()
+        };
+        for rule in &self.path_rules {
+            if let std::borrow::Cow::Owned(rewritten) =
+                rule.pattern.replace_all(&path, rule.replace.as_str())
+            {
+                path = rewritten;
+            }
+        }
+        if self.strip_trailing_slash {
+            let trimmed = path.trim_end_matches('/');
+            path = if trimmed.is_empty() {
+                "/".to_string()
+            } else {
+                trimmed.to_string()
+            };
+        }
+        path
+    }
+}
+
+/// Per-tenant settings from the `[tenants."<tenant>"]` tables of the config
+/// file.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TenantConfig {
+    /// Days to keep the tenant's events, overriding `retention_days`.
+    /// 0 keeps them forever.
+    #[serde(default)]
+    pub retention_days: Option<u32>,
+}
+
+/// Dashboard white-labelling from the `[branding]` table of the config file.
+/// Unset fields keep the Mallard Metrics defaults.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct BrandingConfig {
+    /// Shown in the dashboard header, sign-in screen and page title.
+    #[serde(default)]
+    pub product_name: Option<String>,
+    /// Image shown before the product name: a path on this server or an
+    /// `https://` URL.
+    #[serde(default)]
+    pub logo_url: Option<String>,
+    /// Colour of buttons, headline numbers and the visitors line, as `#rgb`
+    /// or `#rrggbb`.
+    #[serde(default)]
+    pub accent_color: Option<String>,
+    /// Colour of the pageviews line.
+    #[serde(default)]
+    pub secondary_color: Option<String>,
+}
+
+impl BrandingConfig {
+    /// Origin of an `https://` logo, which the dashboard's
+    /// Content-Security-Policy must allow images from.
+    pub fn logo_origin(&self) -> Option<&str> {
+        let url = self.logo_url.as_deref()?;
+        let rest = url.strip_prefix("https://")?;
+        let end = rest.find('/').map_or(url.len(), |i| "https://".len() + i);
+        Some(&url[..end])
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if let Some(name) = &self.product_name {
+            if name.trim().is_empty()
+                || name.chars().count() > 64
+                || name.contains(char::is_control)
+            {
+                return Err(format!(
+                    "branding.product_name must be 1-64 printable characters (got {name:?})"
+                ));
+            }
+        }
+        if let Some(url) = &self.logo_url {
+            let host = url
+                .strip_prefix("https://")
+                .map(|rest| rest.split('/').next().unwrap_or(""));
+            let well_formed = host.map_or_else(
+                || url.starts_with('/') && !url.starts_with("//"),
+                |host| !host.is_empty(),
+            );
+            // The origin goes into a Content-Security-Policy header.
+            let safe = url.len() <= 2048
+                && url
+                    .chars()
+                    .all(|c| c.is_ascii_graphic() && !matches!(c, ';' | ',' | '\'' | '"'));
+            if !well_formed || !safe {
+                return Err(format!(
+                    "branding.logo_url must be a path starting with '/' or an https:// URL (got {url:?})"
+                ));
+            }
+        }
+        for (field, color) in [
+            ("accent_color", &self.accent_color),
+            ("secondary_color", &self.secondary_color),
+        ] {
+            if let Some(color) = color {
+                if !is_hex_color(color) {
+                    return Err(format!(
+                        "branding.{field} must be a #rgb or #rrggbb colour (got {color:?})"
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Extra Content-Security-Policy sources for dashboard pages, from the
+/// `[csp]` table of the config file.  `'self'` is always allowed.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CspConfig {
+    #[serde(default)]
+    pub script_src: Vec<String>,
+    #[serde(default)]
+    pub style_src: Vec<String>,
+    #[serde(default)]
+    pub img_src: Vec<String>,
+    #[serde(default)]
+    pub connect_src: Vec<String>,
+    /// Allow inline scripts and styles carrying a fresh nonce per page,
+    /// filled in where dashboard HTML has `{{csp_nonce}}`.
+    #[serde(default)]
+    pub nonce: bool,
+}
+
+impl CspConfig {
+    fn validate(&self) -> Result<(), String> {
+        for (directive, sources) in [
+            ("script_src", &self.script_src),
+            ("style_src", &self.style_src),
+            ("img_src", &self.img_src),
+            ("connect_src", &self.connect_src),
+        ] {
+            if let Some(source) = sources.iter().find(|s| !is_valid_csp_source(s)) {
+                return Err(format!(
+                    "csp.{directive}: sources must be 1-512 printable ASCII characters without spaces, ';' or ',' (got {source:?})"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether `source` can be added to a Content-Security-Policy directive.
+/// Sources are joined into the header, where a ';' or ',' would start
+/// another directive or policy.
+fn is_valid_csp_source(source: &str) -> bool {
+    !source.is_empty()
+        && source.len() <= 512
+        && source
+            .chars()
+            .all(|c| c.is_ascii_graphic() && c != ';' && c != ',')
+}
+
+/// Whether `origin` is written the way browsers send the `Origin` header:
+/// `http(s)://host[:port]`, lowercase, without a path or trailing slash.
+/// Cross-origin requests are matched against it exactly.
+fn is_valid_origin(origin: &str) -> bool {
+    let Some(authority) = origin
+        .strip_prefix("https://")
+        .or_else(|| origin.strip_prefix("http://"))
+    else {
+        return false;
+    };
+    // A ':' after an IPv6 address's closing bracket starts the port.
+    let (host, port) = match authority.rfind(':') {
+        Some(i) if !authority[i..].contains(']') => (&authority[..i], Some(&authority[i + 1..])),
+        _ => (authority, None),
+    };
+    let host_ok = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .map_or_else(
+            || {
+                !host.is_empty()
+                    && host.chars().all(|c| {
+                        c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '.'
+                    })
+            },
+            |ipv6| {
+                !ipv6.is_empty()
+                    && ipv6
+                        .chars()
+                        .all(|c| matches!(c, ':' | '.' | '0'..='9' | 'a'..='f'))
+            },
+        );
+    host_ok
+        && port.is_none_or(|p| {
+            p.bytes().all(|b| b.is_ascii_digit()) && p.parse::<u16>().is_ok_and(|p| p > 0)
+        })
+}
+
+/// Security response headers, from the `[security_headers]` table of the
+/// config file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SecurityHeadersConfig {
+    /// Send Strict-Transport-Security (default: true).  Browsers ignore it
+    /// over plain HTTP.
+    #[serde(default = "default_hsts")]
+    pub hsts: bool,
+    /// HSTS `max-age` (default: 31536000, one year).
+    #[serde(default = "default_hsts_max_age_secs")]
+    pub hsts_max_age_secs: u64,
+    /// Add `includeSubDomains` to HSTS (default: true).
+    #[serde(default = "default_hsts_include_subdomains")]
+    pub hsts_include_subdomains: bool,
+    /// Add `preload` to HSTS (default: true).  Needs `includeSubDomains`
+    /// and a `max-age` of at least a year.
+    #[serde(default = "default_hsts_preload")]
+    pub hsts_preload: bool,
+    /// Permissions-Policy value; empty omits the header.
+    #[serde(default = "default_permissions_policy")]
+    pub permissions_policy: String,
+    /// Cross-Origin-Opener-Policy value (default: "same-origin"); empty
+    /// omits the header.
+    #[serde(default = "default_cross_origin_opener_policy")]
+    pub cross_origin_opener_policy: String,
+    /// Origins allowed to embed the dashboard in a frame, as CSP
+    /// `frame-ancestors` sources.  Empty keeps `X-Frame-Options: DENY`.
+    #[serde(default)]
+    pub frame_ancestors: Vec<String>,
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        Self {
+            hsts: default_hsts(),
+            hsts_max_age_secs: default_hsts_max_age_secs(),
+            hsts_include_subdomains: default_hsts_include_subdomains(),
+            hsts_preload: default_hsts_preload(),
+            permissions_policy: default_permissions_policy(),
+            cross_origin_opener_policy: default_cross_origin_opener_policy(),
+            frame_ancestors: Vec::new(),
+        }
+    }
+}
+
+/// Cross-Origin-Opener-Policy values browsers understand.
+const CROSS_ORIGIN_OPENER_POLICIES: [&str; 4] = [
+    "same-origin",
+    "same-origin-allow-popups",
+    "noopener-allow-popups",
+    "unsafe-none",
+];
+
+impl SecurityHeadersConfig {
+    /// The Strict-Transport-Security value, or `None` if disabled.
+    pub fn hsts_value(&self) -> Option<String> {
+        self.hsts.then(|| {
+            let mut value = format!("max-age={}", self.hsts_max_age_secs);
+            if self.hsts_include_subdomains {
+                value.push_str("; includeSubDomains");
+            }
+            if self.hsts_preload {
+                value.push_str("; preload");
+            }
+            value
+        })
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.hsts
+            && self.hsts_preload
+            && (!self.hsts_include_subdomains || self.hsts_max_age_secs < 31_536_000)
+        {
+            return Err(
+                "security_headers.hsts_preload needs hsts_include_subdomains and hsts_max_age_secs of at least 31536000"
+                    .to_string(),
+            );
+        }
+        if !self
+            .permissions_policy
+            .chars()
+            .all(|c| c == ' ' || c.is_ascii_graphic())
+        {
+            return Err(format!(
+                "security_headers.permissions_policy must be printable ASCII (got {:?})",
+                self.permissions_policy
+            ));
+        }
+        let coop = self.cross_origin_opener_policy.as_str();
+        if !coop.is_empty() && !CROSS_ORIGIN_OPENER_POLICIES.contains(&coop) {
+            return Err(format!(
+                "security_headers.cross_origin_opener_policy must be empty or one of: {} (got {coop:?})",
+                CROSS_ORIGIN_OPENER_POLICIES.join(", ")
+            ));
+        }
+        if let Some(source) = self
+            .frame_ancestors
+            .iter()
+            .find(|s| !is_valid_csp_source(s))
+        {
+            return Err(format!(
+                "security_headers.frame_ancestors: sources must be 1-512 printable ASCII characters without spaces, ';' or ',' (got {source:?})"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Log output, from the `[logging]` table of the config file.  Client IPs
+/// and User-Agents are always redacted; see [`crate::logging`].
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoggingConfig {
+    /// Per-target levels, in `RUST_LOG` syntax.  `RUST_LOG` takes precedence.
+    #[serde(default = "default_log_filter")]
+    pub filter: String,
+    /// Write one in this many info, debug and trace events from the ingest
+    /// pipeline (default: 1, all of them).  Warnings and errors are always
+    /// written.
+    #[serde(default = "default_ingest_sample_every")]
+    pub ingest_sample_every: u64,
+    /// Log analytics queries taking at least this many milliseconds, and keep
+    /// them for `GET /api/admin/slow-queries` (default: 1000, 0 = disabled).
+    #[serde(default = "default_slow_query_ms")]
+    pub slow_query_ms: u64,
+    /// Number of recent slow queries kept (default: 100).
+    #[serde(default = "default_slow_query_log_size")]
+    pub slow_query_log_size: usize,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            filter: default_log_filter(),
+            ingest_sample_every: default_ingest_sample_every(),
+            slow_query_ms: default_slow_query_ms(),
+            slow_query_log_size: default_slow_query_log_size(),
+        }
+    }
+}
+
+impl LoggingConfig {
+    fn validate(&self) -> Result<(), String> {
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.filter) {
+            return Err(format!("logging.filter is invalid: {e}"));
+        }
+        if self.ingest_sample_every == 0 {
+            return Err(
+                "logging.ingest_sample_every must be > 0; use 1 to write every event".to_string(),
+            );
+        }
+        if self.slow_query_log_size == 0 || self.slow_query_log_size > 10_000 {
+            return Err("logging.slow_query_log_size must be between 1 and 10000".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Background consumer of events from a Kafka topic or NATS subject, from
+/// the `[event_bus]` table of the config file.  See
+/// [`crate::ingest::bus`].
+#[derive(Debug, Clone, Deserialize)]
+pub struct EventBusConfig {
+    /// `kafka` or `nats`; empty disables the consumer (default).  Needs a
+    /// build with the feature of the same name.
+    #[serde(default)]
+    pub kind: String,
+    /// Kafka bootstrap servers or NATS server URLs, comma-separated.
+    #[serde(default)]
+    pub servers: String,
+    /// Kafka topic or NATS subject to read events from.
+    #[serde(default)]
+    pub topic: String,
+    /// Kafka consumer group or NATS queue group, shared by the instances
+    /// splitting the stream between them (default: `mallard-metrics`).
+    #[serde(default = "default_event_bus_group")]
+    pub group: String,
+}
+
+impl Default for EventBusConfig {
+    fn default() -> Self {
+        Self {
+            kind: String::new(),
+            servers: String::new(),
+            topic: String::new(),
+            group: default_event_bus_group(),
+        }
+    }
+}
+
+impl EventBusConfig {
+    fn validate(&self) -> Result<(), String> {
+        let built = match self.kind.as_str() {
+            "" => return Ok(()),
+            "kafka" => cfg!(feature = "kafka"),
+            "nats" => cfg!(feature = "nats"),
+            kind => {
+                return Err(format!(
+                    "event_bus.kind must be \"kafka\" or \"nats\" (got {kind:?})"
+                ));
+            }
+        };
+        if !built {
+            return Err(format!(
+                "event_bus.kind is {:?} but this build lacks the `{}` feature",
+                self.kind, self.kind
+            ));
+        }
+        if self.servers.is_empty() || self.topic.is_empty() {
+            return Err("event_bus.servers and event_bus.topic must be set".to_string());
+        }
+        if self.group.is_empty() {
+            return Err("event_bus.group must not be empty".to_string());
+        }
+        Ok(())
+    }
+}
+
+fn default_event_bus_group() -> String {
+    "mallard-metrics".to_string()
+}
+
+/// Mirroring of ingested events to an external sink, from the
+/// `[forwarding]` table of the config file.  See
+/// [`crate::ingest::forward`].
+#[derive(Debug, Clone, Deserialize)]
+pub struct ForwardingConfig {
+    /// `http`, `kafka` or `s3`; empty disables forwarding (default).
+    #[serde(default)]
+    pub sink: String,
+    /// URL batches are POSTed to (`http`), or the bucket URL objects are
+    /// written under (`s3`), e.g. `https://s3.eu-west-1.amazonaws.com/bucket`.
+    #[serde(default)]
+    pub url: String,
+    /// Extra request headers for the `http` sink, e.g. `Authorization`.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Kafka bootstrap servers, comma-separated (`kafka`).
+    #[serde(default)]
+    pub servers: String,
+    /// Kafka topic (`kafka`).
+    #[serde(default)]
+    pub topic: String,
+    /// Bucket region (`s3`, default: `us-east-1`).
+    #[serde(default = "default_forwarding_region")]
+    pub region: String,
+    /// Prefix of the object keys (`s3`).
+    #[serde(default)]
+    pub prefix: String,
+    /// S3 credentials; `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` are
+    /// used when unset.
+    #[serde(default)]
+    pub access_key_id: Option<String>,
+    #[serde(default)]
+    pub secret_access_key: Option<String>,
+    /// Forward only events of these sites (default: all).
+    #[serde(default)]
+    pub sites: Vec<String>,
+    /// Forward only events with these names (default: all).
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// Most events sent in one batch (default: 500).
+    #[serde(default = "default_forwarding_batch_size")]
+    pub batch_size: usize,
+    /// Longest an event waits for its batch to fill, in seconds (default: 5).
+    #[serde(default = "default_forwarding_batch_interval_secs")]
+    pub batch_interval_secs: u64,
+    /// Attempts after the first before a batch goes to the dead-letter file
+    /// (default: 5).
+    #[serde(default = "default_forwarding_max_retries")]
+    pub max_retries: u32,
+    /// Events waiting to be forwarded; further events are not forwarded
+    /// while it is full (default: 10000).
+    #[serde(default = "default_forwarding_queue_size")]
+    pub queue_size: usize,
+}
+
+impl Default for ForwardingConfig {
+    fn default() -> Self {
+        Self {
+            sink: String::new(),
+            url: String::new(),
+            headers: HashMap::new(),
+            servers: String::new(),
+            topic: String::new(),
+            region: default_forwarding_region(),
+            prefix: String::new(),
+            access_key_id: None,
+            secret_access_key: None,
+            sites: Vec::new(),
+            events: Vec::new(),
+            batch_size: default_forwarding_batch_size(),
+            batch_interval_secs: default_forwarding_batch_interval_secs(),
+            max_retries: default_forwarding_max_retries(),
+            queue_size: default_forwarding_queue_size(),
+        }
+    }
+}
+
+impl ForwardingConfig {
+    fn validate(&self) -> Result<(), String> {
+        match self.sink.as_str() {
+            "" => return Ok(()),
+            "http" | "s3" => {
+                if !self.url.starts_with("https://") && !self.url.starts_with("http://") {
+                    return Err(format!(
+                        "forwarding.url must be an http:// or https:// URL (got {:?})",
+                        self.url
+                    ));
+                }
+            }
+            "kafka" => {
+                if !cfg!(feature = "kafka") {
+                    return Err(
+                        "forwarding.sink is \"kafka\" but this build lacks the `kafka` feature"
+                            .to_string(),
+                    );
+                }
+                if self.servers.is_empty() || self.topic.is_empty() {
+                    return Err(
+                        "forwarding.servers and forwarding.topic must be set for Kafka".to_string(),
+                    );
+                }
+            }
+            sink => {
+                return Err(format!(
+                    "forwarding.sink must be \"http\", \"kafka\" or \"s3\" (got {sink:?})"
+                ));
+            }
+        }
+        if self.batch_size == 0 || self.batch_interval_secs == 0 || self.queue_size == 0 {
+            return Err(
+                "forwarding.batch_size, batch_interval_secs and queue_size must be > 0".to_string(),
+            );
+        }
+        Ok(())
+    }
+}
+
+fn default_forwarding_region() -> String {
+    "us-east-1".to_string()
+}
+
+const fn default_forwarding_batch_size() -> usize {
+    500
+}
+
+const fn default_forwarding_batch_interval_secs() -> u64 {
+    5
+}
+
+const fn default_forwarding_max_retries() -> u32 {
+    5
+}
+
+const fn default_forwarding_queue_size() -> usize {
+    10_000
+}
+
+/// What a notification channel can be told about.
+pub const NOTIFICATION_EVENTS: [&str; 4] = ["anomaly", "goal", "digest", "flush"];
+
+/// A Slack or Discord webhook notifications are posted to, from a
+/// `[notifications."<name>"]` table of the config file or created with
+/// `POST /api/notifications`.  See [`crate::notify`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct NotificationChannel {
+    /// `slack` or `discord`.
+    pub kind: String,
+    /// Incoming webhook URL.
+    pub url: String,
+    /// What to post: `anomaly`, `goal`, `digest` and `flush` (default: all).
+    #[serde(default = "default_notification_events")]
+    pub events: Vec<String>,
+    /// Post only about these sites (default: all).
+    #[serde(default)]
+    pub sites: Vec<String>,
+    /// Goals in funnel step syntax, e.g. `event:signup`, whose conversions
+    /// are posted hourly with the `goal` event.
+    #[serde(default)]
+    pub goals: Vec<String>,
+    /// Message templates by event, replacing the built-in wording, e.g.
+    /// `anomaly = "{site_id}: {visitors} visitors at {hour}"`.
+    #[serde(default)]
+    pub templates: HashMap<String, String>,
+}
+
+fn default_notification_events() -> Vec<String> {
+    NOTIFICATION_EVENTS
+        .iter()
+        .map(ToString::to_string)
+        .collect()
+}
+
+impl NotificationChannel {
+    pub fn validate(&self) -> Result<(), String> {
+        if !matches!(self.kind.as_str(), "slack" | "discord") {
+            return Err(format!(
+                "kind must be \"slack\" or \"discord\" (got {:?})",
+                self.kind
+            ));
+        }
+        if !self.url.starts_with("https://") {
+            return Err("url must be an https:// URL".to_string());
+        }
+        let known = |event: &String| NOTIFICATION_EVENTS.contains(&event.as_str());
+        if let Some(event) = self.events.iter().find(|e| !known(e)) {
+            return Err(format!(
+                "unknown event {event:?}; use {}",
+                NOTIFICATION_EVENTS.join(", ")
+            ));
+        }
+        if let Some(event) = self.templates.keys().find(|e| !known(e)) {
+            return Err(format!("template for unknown event {event:?}"));
+        }
+        if let Some(goal) = self
+            .goals
+            .iter()
+            .find(|goal| crate::api::stats::parse_goal(goal).is_err())
+        {
+            return Err(format!(
+                "invalid goal {goal:?}; use 'page:/path', 'page~:/glob/*', \
+                 'pageprefix:/path' or 'event:name'"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Shadow runs of stats reports against an alternative implementation, from
+/// the `[shadow_queries]` table of the config file.  See
+/// [`crate::query::shadow`].
+#[derive(Debug, Clone, Deserialize)]
+pub struct ShadowQueriesConfig {
+    /// Stats endpoints to shadow, as named by `GET /api/admin/explain`, e.g.
+    /// `main`; only those the variant changes (default: none).
+    #[serde(default)]
+    pub reports: Vec<String>,
+    /// Alternative implementation to compare with (default: `live_sessions`).
+    #[serde(default)]
+    pub variant: crate::query::shadow::Variant,
+    /// Shadow one in this many requests for those endpoints (default: 1).
+    #[serde(default = "default_shadow_sample_every")]
+    pub sample_every: u64,
+}
+
+impl Default for ShadowQueriesConfig {
+    fn default() -> Self {
+        Self {
+            reports: Vec::new(),
+            variant: crate::query::shadow::Variant::default(),
+            sample_every: default_shadow_sample_every(),
+        }
+    }
+}
+
+impl ShadowQueriesConfig {
+    fn validate(&self) -> Result<(), String> {
+        let shadowable = self.variant.reports();
+        if let Some(report) = self
+            .reports
+            .iter()
+            .find(|r| !shadowable.contains(&r.as_str()))
+        {
+            return Err(format!(
+                "shadow_queries.reports: {report:?} cannot be shadowed; use any of: {}",
+                shadowable.join(", ")
+            ));
+        }
+        if self.sample_every == 0 {
+            return Err(
+                "shadow_queries.sample_every must be > 0; use 1 to shadow every request"
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Whether `color` is a CSS hex colour, `#rgb` or `#rrggbb`.
+fn is_hex_color(color: &str) -> bool {
+    color
+        .strip_prefix('#')
+        .is_some_and(|hex| matches!(hex.len(), 3 | 6) && hex.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Whether `name` is usable as a site group name: 1-64 ASCII alphanumeric,
+/// `-` or `_` characters.
+pub fn is_valid_group_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Whether `name` is usable as a kept query parameter name: 1-64 ASCII
+/// alphanumeric, `-`, `_` or `.` characters.
+pub fn is_valid_query_param_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
 
 /// Application configuration loaded from environment variables or TOML file.
 #[derive(Debug, Clone, Deserialize)]
@@ -25,6 +874,14 @@ pub struct Config {
     /// If not set, stats routes allow same-origin only.
     #[serde(default)]
     pub dashboard_origin: Option<String>,
+    /// Further origins allowed to call the dashboard and stats routes with
+    /// credentials, alongside `dashboard_origin`.
+    #[serde(default)]
+    pub dashboard_origins: Vec<String>,
+    /// Directory of a custom dashboard build.  Files found there are served
+    /// in preference to the embedded dashboard.
+    #[serde(default)]
+    pub dashboard_dir: Option<PathBuf>,
     /// Whether to filter bot traffic from analytics (default: true).
     #[serde(default = "default_filter_bots")]
     pub filter_bots: bool,
@@ -37,9 +894,77 @@ pub struct Config {
     /// Graceful shutdown timeout in seconds (default: 30).
     #[serde(default = "default_shutdown_timeout_secs")]
     pub shutdown_timeout_secs: u64,
+    /// Bind the port with `SO_REUSEPORT`, so a new binary can bind it while
+    /// the old one still serves, and wait for the old one to release the
+    /// database (default: false). Unix only.
+    #[serde(default)]
+    pub reuse_port: bool,
     /// Maximum events per second per site_id for rate limiting. 0 = no limit.
     #[serde(default)]
     pub rate_limit_per_site: u32,
+    /// Token-bucket capacity per site_id, i.e. how many events a site may send
+    /// in a burst. 0 = same as `rate_limit_per_site`.
+    #[serde(default)]
+    pub rate_limit_burst: u32,
+    /// Maximum `/api/stats/*` requests per minute per session or API key. 0 = no limit.
+    #[serde(default)]
+    pub stats_rate_limit_per_minute: u32,
+    /// How long a client-supplied event ID (`eid`) is remembered for dropping
+    /// duplicates, in seconds (default: 60). 0 = no deduplication.
+    #[serde(default = "default_dedupe_window_secs")]
+    pub dedupe_window_secs: u64,
+    /// How far, in hours, a client-supplied event time (`t`) may be from the
+    /// time of receipt (default: 48). Events outside the window are rejected.
+    /// 0 = `t` is not accepted.
+    #[serde(default = "default_max_event_time_skew_hours")]
+    pub max_event_time_skew_hours: u32,
+    /// How long the outcome of an ingestion request sent with an
+    /// `Idempotency-Key` header is remembered for replays, in seconds
+    /// (default: 3600). 0 = the header is ignored.
+    #[serde(default = "default_idempotency_window_secs")]
+    pub idempotency_window_secs: u64,
+    /// Worker threads that enrich (User-Agent, GeoIP) and buffer ingested
+    /// events (default: 2). 0 = enrich in the request handler.
+    #[serde(default = "default_ingest_workers")]
+    pub ingest_workers: usize,
+    /// Events the ingest queue holds before new ones are rejected with 503
+    /// (default: 10000).
+    #[serde(default = "default_ingest_queue_size")]
+    pub ingest_queue_size: usize,
+    /// What to do when the ingest queue fills up: "reject" (default) answers
+    /// 503 once it is full; "shed" also drops plain pageviews once it is 90%
+    /// full, keeping room for custom and revenue events.
+    #[serde(default = "default_ingest_overload")]
+    pub ingest_overload: String,
+    /// Events held in memory while flushing is failing or paused before new
+    /// ones are rejected with 503 (default: 100000). 0 = unbounded.
+    /// `ingest_overload = "shed"` drops plain pageviews once it is 90% full.
+    #[serde(default = "default_max_buffered_events")]
+    pub max_buffered_events: usize,
+    /// Free space, in MiB, the data volume must have for events to be
+    /// flushed (default: 512). Below it flushing pauses and events stay
+    /// buffered. 0 = no check.
+    #[serde(default = "default_min_free_disk_mb")]
+    pub min_free_disk_mb: u64,
+    /// Longest time, in seconds, a site's events wait in memory for
+    /// `flush_event_count` to be reached before they are flushed anyway
+    /// (default: 60). 0 = flush only on the count threshold.
+    #[serde(default = "default_max_buffer_age_secs")]
+    pub max_buffer_age_secs: u64,
+    /// Retries of a failed periodic flush before it waits for the next
+    /// interval (default: 3). 0 = no retries.
+    #[serde(default = "default_flush_retries")]
+    pub flush_retries: u32,
+    /// Wait before the first flush retry, in milliseconds (default: 1000).
+    /// Doubled for each further retry, up to `flush_interval_secs`, with
+    /// random jitter.
+    #[serde(default = "default_flush_retry_base_ms")]
+    pub flush_retry_base_ms: u64,
+    /// Periodic flushes failing in a row, each after its retries, before an
+    /// alert is logged and posted to notification channels subscribed to
+    /// `flush` (default: 3). 0 = no alert.
+    #[serde(default = "default_flush_alert_after")]
+    pub flush_alert_after: u32,
     /// Query cache TTL in seconds (default: 60). 0 = no caching.
     #[serde(default = "default_cache_ttl_secs")]
     pub cache_ttl_secs: u64,
@@ -55,14 +980,92 @@ pub struct Config {
     /// Maximum number of cached query results (0 = unlimited, default: 10000).
     #[serde(default = "default_cache_max_entries")]
     pub cache_max_entries: usize,
+    /// Sites whose main stats and timeseries for 7 and 30 days are cached
+    /// after each background flush, busiest first (default: 5).  0 = no
+    /// warming.
+    #[serde(default = "default_cache_warm_sites")]
+    pub cache_warm_sites: usize,
+    /// Seconds a public site summary is cached, on the server and by
+    /// browsers and CDNs (default: 3600).  0 = no caching.
+    #[serde(default = "default_public_summary_cache_secs")]
+    pub public_summary_cache_secs: u64,
     /// Maximum concurrent analytics queries (0 = unlimited, default: 10).
     #[serde(default = "default_max_concurrent_queries")]
     pub max_concurrent_queries: usize,
+    /// How long a finished export job and its file are kept, in seconds
+    /// (default: 86400).
+    #[serde(default = "default_export_job_ttl_secs")]
+    pub export_job_ttl_secs: u64,
+    /// How long an uploaded import job is kept after it was validated or
+    /// finished, in seconds (default: 86400).
+    #[serde(default = "default_import_job_ttl_secs")]
+    pub import_job_ttl_secs: u64,
+    /// DuckDB memory limit, e.g. "2GB" or "512MiB".  Queries needing more
+    /// spill to disk.  Default: 75% of the container's memory limit, or
+    /// DuckDB's default of 80% of RAM outside a container.
+    #[serde(default)]
+    pub duckdb_memory_limit: Option<String>,
+    /// DuckDB worker threads (0 = the CPUs available to the process, default).
+    #[serde(default)]
+    pub duckdb_threads: usize,
+    /// Directory DuckDB spills to when a query exceeds its memory limit.
+    /// Default: `data_dir/duckdb_tmp`.
+    #[serde(default)]
+    pub duckdb_temp_directory: Option<PathBuf>,
+    /// Most data DuckDB may spill, e.g. "20GB".  Default: 90% of the free
+    /// space on the temp directory's file system.
+    #[serde(default)]
+    pub duckdb_max_temp_size: Option<String>,
     /// Force the Secure flag on session cookies regardless of dashboard_origin.
     /// Set to true when the server is deployed behind a TLS-terminating reverse proxy.
     #[serde(default)]
     pub secure_cookies: bool,
 
+    /// Run the hourly anomaly detector (default: false).
+    #[serde(default)]
+    pub anomaly_detection: bool,
+
+    /// Echo the parsed payload in ingest validation errors (default: false).
+    /// Useful while building a custom tracker; leave off in production.
+    #[serde(default)]
+    pub debug_ingest: bool,
+    /// Minimum percentage deviation from the baseline that counts as an
+    /// anomaly, in either direction (default: 50).
+    #[serde(default = "default_anomaly_threshold_pct")]
+    pub anomaly_threshold_pct: f64,
+    /// Hours whose baseline is below this many visitors are never flagged,
+    /// so quiet sites do not alert on noise (default: 10).
+    #[serde(default = "default_anomaly_min_visitors")]
+    pub anomaly_min_visitors: u64,
+    /// Run the hourly job that maps visitors to the logged-in users they were
+    /// seen with, for `identity=stitched` queries (default: false).
+    #[serde(default)]
+    pub identity_stitching: bool,
+    /// Materialize each day's sessions into Parquet after flushes, so
+    /// session metrics and bounce rate read them instead of sessionizing
+    /// events per query (default: false).
+    #[serde(default)]
+    pub materialize_sessions: bool,
+    /// StatsD server (`host:port`) to push per-site visitor and pageview
+    /// gauges to. Unset (default) disables the exporter.
+    #[serde(default)]
+    pub statsd_addr: Option<String>,
+    /// Metric name prefix for the StatsD exporter (default: "mallard").
+    #[serde(default = "default_statsd_prefix")]
+    pub statsd_prefix: String,
+    /// Seconds between StatsD pushes (default: 60).
+    #[serde(default = "default_statsd_interval_secs")]
+    pub statsd_interval_secs: u64,
+    /// Slack incoming webhook URL to post each site's traffic digest of the
+    /// last 7 days to; shorthand for a `[notifications]` channel with
+    /// `events = ["digest"]`. Unset (default) adds no channel.
+    #[serde(default)]
+    pub digest_slack_webhook: Option<String>,
+    /// Hours between digest posts to notification channels (default: 168,
+    /// weekly).
+    #[serde(default = "default_digest_interval_hours")]
+    pub digest_interval_hours: u64,
+
     // ── Privacy / GDPR configuration ─────────────────────────────────────
     /// GDPR-friendly mode: convenience preset that enables the full privacy bundle.
     ///
@@ -114,91 +1117,405 @@ pub struct Config {
     #[serde(default)]
     pub suppress_browser_version: bool,
 
-    /// Store OS name only, omitting OS version.
-    ///
-    /// Similar to `suppress_browser_version`: "Windows 10.0" is more identifying than
-    /// "Windows". Default: false. Enabled by `gdpr_mode = true`.
-    #[serde(default)]
-    pub suppress_os_version: bool,
+    /// Store OS name only, omitting OS version.
+    ///
+    /// Similar to `suppress_browser_version`: "Windows 10.0" is more identifying than
+    /// "Windows". Default: false. Enabled by `gdpr_mode = true`.
+    #[serde(default)]
+    pub suppress_os_version: bool,
+
+    /// Do not store the screen_size or device_type fields.
+    ///
+    /// Screen width contributes to fingerprinting. Setting this to true stores
+    /// neither the raw width nor the derived device category (mobile/tablet/desktop).
+    /// Default: false. Enabled by `gdpr_mode = true`.
+    #[serde(default)]
+    pub suppress_screen_size: bool,
+
+    /// Geographic precision for IP geolocation. Valid values:
+    /// - `"city"` (default): stores `country_code`, `region`, and `city`.
+    /// - `"region"`: stores `country_code` and `region` only.
+    /// - `"country"`: stores `country_code` only.
+    /// - `"none"`: stores no geographic data.
+    ///
+    /// `gdpr_mode = true` promotes `"city"` → `"country"` (more permissive settings
+    /// such as `"region"` or `"none"` are left unchanged).
+    #[serde(default = "default_geoip_precision")]
+    pub geoip_precision: String,
+
+    /// Default locale for CSV exports when the request names none and its
+    /// `Accept-Language` matches no supported locale (default: "en").
+    #[serde(default = "default_locale")]
+    pub locale: String,
+
+    /// Compression codec for Parquet files: uncompressed, snappy, gzip, zstd,
+    /// brotli or lz4 (default: "zstd").
+    #[serde(default = "default_parquet_compression")]
+    pub parquet_compression: String,
+    /// ZSTD compression level, 1 (fastest) to 22 (smallest); 0 keeps the
+    /// codec default (default: 0).
+    #[serde(default)]
+    pub parquet_compression_level: u32,
+    /// Rows per Parquet row group (default: 122880).
+    #[serde(default = "default_parquet_row_group_size")]
+    pub parquet_row_group_size: u64,
+    /// Dictionary-encode low-cardinality Parquet columns (default: true).
+    #[serde(default = "default_parquet_dictionary")]
+    pub parquet_dictionary: bool,
+
+    /// Drop events whose referrer is a known referral spam domain (default: true).
+    #[serde(default = "default_block_spam_referrers")]
+    pub block_spam_referrers: bool,
+    /// Extra referral spam domains, blocked along with the built-in list.
+    #[serde(default)]
+    pub spam_referrers: Vec<String>,
+    /// HTTPS URL of a referral spam list (one domain per line), downloaded at
+    /// startup and every `spam_list_refresh_secs`.
+    #[serde(default)]
+    pub spam_list_url: Option<String>,
+    /// Seconds between downloads of `spam_list_url` (default: 86400).
+    #[serde(default = "default_spam_list_refresh_secs")]
+    pub spam_list_refresh_secs: u64,
+
+    /// Referrer classification overrides, applied at ingest: referrer
+    /// hostname (matching subdomains too) → source name.
+    ///
+    /// ```toml
+    /// [referrer_sources]
+    /// "news.ycombinator.com" = "Hacker News"
+    /// "intranet.example.com" = "Internal"
+    /// ```
+    #[serde(default)]
+    pub referrer_sources: HashMap<String, String>,
+
+    /// WASM module run on every event to drop or annotate it; reloaded when
+    /// the file changes.  Needs a build with the `wasm-filters` feature.
+    #[serde(default)]
+    pub wasm_filter: Option<PathBuf>,
+
+    /// Site registry: per-site settings keyed by site_id.
+    ///
+    /// ```toml
+    /// [sites."example.com"]
+    /// group = "acme"
+    /// custom_dimensions = [{ name = "plan", type = "string" }]
+    /// ```
+    #[serde(default)]
+    pub sites: HashMap<String, SiteConfig>,
+
+    /// Tenant settings keyed by tenant ID.  Sites join a tenant with their
+    /// `tenant` setting; a tenant needs no table of its own.
+    ///
+    /// ```toml
+    /// [tenants.acme]
+    /// retention_days = 30
+    /// ```
+    #[serde(default)]
+    pub tenants: HashMap<String, TenantConfig>,
+
+    /// Dashboard product name, logo and colours.
+    ///
+    /// ```toml
+    /// [branding]
+    /// product_name = "Acme Analytics"
+    /// logo_url = "https://cdn.acme.example/logo.svg"
+    /// accent_color = "#e4572e"
+    /// ```
+    #[serde(default)]
+    pub branding: BrandingConfig,
+
+    /// Extra Content-Security-Policy sources for the dashboard, and nonces
+    /// for inline scripts and styles.
+    ///
+    /// ```toml
+    /// [csp]
+    /// script_src = ["https://cdn.example.com"]
+    /// connect_src = ["https://api.example.com"]
+    /// nonce = true
+    /// ```
+    #[serde(default)]
+    pub csp: CspConfig,
+
+    /// HSTS, Permissions-Policy, Cross-Origin-Opener-Policy and framing.
+    ///
+    /// ```toml
+    /// [security_headers]
+    /// hsts_preload = false
+    /// frame_ancestors = ["https://intranet.example.com"]
+    /// ```
+    #[serde(default)]
+    pub security_headers: SecurityHeadersConfig,
+
+    /// Log levels, ingest log sampling and the slow query log.
+    ///
+    /// ```toml
+    /// [logging]
+    /// filter = "mallard_metrics=info,mallard_metrics::ingest=debug,tower_http=warn"
+    /// ingest_sample_every = 100
+    /// slow_query_ms = 500
+    /// ```
+    #[serde(default)]
+    pub logging: LoggingConfig,
+
+    /// Compare stats reports with an alternative implementation, to check a
+    /// query redesign on production data before switching to it.
+    ///
+    /// ```toml
+    /// [shadow_queries]
+    /// reports = ["main", "breakdown/pages"]
+    /// sample_every = 10
+    /// ```
+    #[serde(default)]
+    pub shadow_queries: ShadowQueriesConfig,
+
+    /// Ingest events read from a Kafka topic or NATS subject, alongside
+    /// `POST /api/event`.
+    ///
+    /// ```toml
+    /// [event_bus]
+    /// kind = "kafka"
+    /// servers = "kafka-1:9092,kafka-2:9092"
+    /// topic = "analytics-events"
+    /// ```
+    #[serde(default)]
+    pub event_bus: EventBusConfig,
+
+    /// Mirror ingested events to an HTTP endpoint, Kafka topic or S3 bucket
+    /// for downstream systems.
+    ///
+    /// ```toml
+    /// [forwarding]
+    /// sink = "http"
+    /// url = "https://collector.example.com/events"
+    /// events = ["signup", "purchase"]
+    /// ```
+    #[serde(default)]
+    pub forwarding: ForwardingConfig,
+
+    /// Slack and Discord webhooks that anomalies, goal conversions and
+    /// traffic digests are posted to, keyed by channel name.
+    ///
+    /// ```toml
+    /// [notifications.team]
+    /// kind = "slack"
+    /// url = "https://hooks.slack.com/services/T000/B000/XXXX"
+    /// events = ["anomaly", "goal"]
+    /// goals = ["event:signup"]
+    /// ```
+    #[serde(default)]
+    pub notifications: HashMap<String, NotificationChannel>,
+}
+
+fn default_host() -> String {
+    "0.0.0.0".to_string()
+}
+
+const fn default_port() -> u16 {
+    8000
+}
+
+fn default_data_dir() -> PathBuf {
+    PathBuf::from("data")
+}
+
+const fn default_flush_count() -> usize {
+    1000
+}
+
+const fn default_flush_interval_secs() -> u64 {
+    60
+}
+
+const fn default_filter_bots() -> bool {
+    true
+}
+
+const fn default_session_ttl_secs() -> u64 {
+    86400
+}
+
+const fn default_shutdown_timeout_secs() -> u64 {
+    30
+}
+
+const fn default_cache_ttl_secs() -> u64 {
+    60
+}
+
+const fn default_dedupe_window_secs() -> u64 {
+    60
+}
+
+const fn default_max_event_time_skew_hours() -> u32 {
+    48
+}
+
+const fn default_idempotency_window_secs() -> u64 {
+    3600
+}
+
+const fn default_ingest_workers() -> usize {
+    2
+}
+
+const fn default_ingest_queue_size() -> usize {
+    10_000
+}
+
+const fn default_max_buffered_events() -> usize {
+    100_000
+}
+
+const fn default_min_free_disk_mb() -> u64 {
+    512
+}
+
+const fn default_max_buffer_age_secs() -> u64 {
+    60
+}
+
+const fn default_flush_retries() -> u32 {
+    3
+}
+
+const fn default_flush_retry_base_ms() -> u64 {
+    1000
+}
+
+const fn default_flush_alert_after() -> u32 {
+    3
+}
+
+fn default_ingest_overload() -> String {
+    "reject".to_string()
+}
+
+fn default_log_format() -> String {
+    "text".to_string()
+}
+
+const fn default_max_login_attempts() -> u32 {
+    5
+}
+
+const fn default_login_lockout_secs() -> u64 {
+    300
+}
+
+const fn default_cache_max_entries() -> usize {
+    10_000
+}
+
+const fn default_cache_warm_sites() -> usize {
+    5
+}
+
+const fn default_public_summary_cache_secs() -> u64 {
+    3600
+}
+
+const fn default_max_concurrent_queries() -> usize {
+    10
+}
+
+const fn default_export_job_ttl_secs() -> u64 {
+    86400
+}
+
+const fn default_import_job_ttl_secs() -> u64 {
+    86400
+}
+
+const fn default_anomaly_threshold_pct() -> f64 {
+    50.0
+}
+
+const fn default_anomaly_min_visitors() -> u64 {
+    10
+}
+
+fn default_statsd_prefix() -> String {
+    "mallard".to_string()
+}
 
-    /// Do not store the screen_size or device_type fields.
-    ///
-    /// Screen width contributes to fingerprinting. Setting this to true stores
-    /// neither the raw width nor the derived device category (mobile/tablet/desktop).
-    /// Default: false. Enabled by `gdpr_mode = true`.
-    #[serde(default)]
-    pub suppress_screen_size: bool,
+const fn default_statsd_interval_secs() -> u64 {
+    60
+}
 
-    /// Geographic precision for IP geolocation. Valid values:
-    /// - `"city"` (default): stores `country_code`, `region`, and `city`.
-    /// - `"region"`: stores `country_code` and `region` only.
-    /// - `"country"`: stores `country_code` only.
-    /// - `"none"`: stores no geographic data.
-    ///
-    /// `gdpr_mode = true` promotes `"city"` → `"country"` (more permissive settings
-    /// such as `"region"` or `"none"` are left unchanged).
-    #[serde(default = "default_geoip_precision")]
-    pub geoip_precision: String,
+const fn default_digest_interval_hours() -> u64 {
+    168
 }
 
-fn default_host() -> String {
-    "0.0.0.0".to_string()
+const fn default_block_spam_referrers() -> bool {
+    true
 }
 
-const fn default_port() -> u16 {
-    8000
+const fn default_spam_list_refresh_secs() -> u64 {
+    24 * 60 * 60
 }
 
-fn default_data_dir() -> PathBuf {
-    PathBuf::from("data")
+fn default_locale() -> String {
+    "en".to_string()
 }
 
-const fn default_flush_count() -> usize {
-    1000
+fn default_parquet_compression() -> String {
+    "zstd".to_string()
 }
 
-const fn default_flush_interval_secs() -> u64 {
-    60
+const fn default_parquet_row_group_size() -> u64 {
+    crate::storage::parquet::DEFAULT_ROW_GROUP_SIZE
 }
 
-const fn default_filter_bots() -> bool {
+const fn default_parquet_dictionary() -> bool {
     true
 }
 
-const fn default_session_ttl_secs() -> u64 {
-    86400
+fn default_geoip_precision() -> String {
+    "city".to_string()
 }
 
-const fn default_shutdown_timeout_secs() -> u64 {
-    30
+const fn default_hsts() -> bool {
+    true
 }
 
-const fn default_cache_ttl_secs() -> u64 {
-    60
+const fn default_hsts_max_age_secs() -> u64 {
+    31_536_000
 }
 
-fn default_log_format() -> String {
-    "text".to_string()
+const fn default_hsts_include_subdomains() -> bool {
+    true
 }
 
-const fn default_max_login_attempts() -> u32 {
-    5
+const fn default_hsts_preload() -> bool {
+    true
 }
 
-const fn default_login_lockout_secs() -> u64 {
-    300
+fn default_permissions_policy() -> String {
+    "geolocation=(), microphone=(), camera=()".to_string()
 }
 
-const fn default_cache_max_entries() -> usize {
-    10_000
+fn default_cross_origin_opener_policy() -> String {
+    "same-origin".to_string()
 }
 
-const fn default_max_concurrent_queries() -> usize {
-    10
+pub fn default_log_filter() -> String {
+    "mallard_metrics=info,tower_http=info".to_string()
 }
 
-fn default_geoip_precision() -> String {
-    "city".to_string()
+const fn default_ingest_sample_every() -> u64 {
+    1
+}
+
+const fn default_slow_query_ms() -> u64 {
+    1000
+}
+
+const fn default_slow_query_log_size() -> usize {
+    100
+}
+
+const fn default_shadow_sample_every() -> u64 {
+    1
 }
 
 impl Default for Config {
@@ -212,18 +1529,54 @@ impl Default for Config {
             site_ids: Vec::new(),
             geoip_db_path: None,
             dashboard_origin: None,
+            dashboard_origins: Vec::new(),
+            dashboard_dir: None,
             filter_bots: default_filter_bots(),
             retention_days: 0,
             session_ttl_secs: default_session_ttl_secs(),
             shutdown_timeout_secs: default_shutdown_timeout_secs(),
+            reuse_port: false,
             rate_limit_per_site: 0,
+            rate_limit_burst: 0,
+            stats_rate_limit_per_minute: 0,
+            dedupe_window_secs: default_dedupe_window_secs(),
+            max_event_time_skew_hours: default_max_event_time_skew_hours(),
+            idempotency_window_secs: default_idempotency_window_secs(),
+            ingest_workers: default_ingest_workers(),
+            ingest_queue_size: default_ingest_queue_size(),
+            max_buffered_events: default_max_buffered_events(),
+            min_free_disk_mb: default_min_free_disk_mb(),
+            max_buffer_age_secs: default_max_buffer_age_secs(),
+            flush_retries: default_flush_retries(),
+            flush_retry_base_ms: default_flush_retry_base_ms(),
+            flush_alert_after: default_flush_alert_after(),
+            ingest_overload: default_ingest_overload(),
             cache_ttl_secs: default_cache_ttl_secs(),
             log_format: default_log_format(),
             max_login_attempts: default_max_login_attempts(),
             login_lockout_secs: default_login_lockout_secs(),
             cache_max_entries: default_cache_max_entries(),
+            cache_warm_sites: default_cache_warm_sites(),
+            public_summary_cache_secs: default_public_summary_cache_secs(),
             max_concurrent_queries: default_max_concurrent_queries(),
+            export_job_ttl_secs: default_export_job_ttl_secs(),
+            import_job_ttl_secs: default_import_job_ttl_secs(),
+            duckdb_memory_limit: None,
+            duckdb_threads: 0,
+            duckdb_temp_directory: None,
+            duckdb_max_temp_size: None,
             secure_cookies: false,
+            anomaly_detection: false,
+            debug_ingest: false,
+            anomaly_threshold_pct: default_anomaly_threshold_pct(),
+            anomaly_min_visitors: default_anomaly_min_visitors(),
+            identity_stitching: false,
+            materialize_sessions: false,
+            statsd_addr: None,
+            statsd_prefix: default_statsd_prefix(),
+            statsd_interval_secs: default_statsd_interval_secs(),
+            digest_slack_webhook: None,
+            digest_interval_hours: default_digest_interval_hours(),
             gdpr_mode: false,
             strip_referrer_query: false,
             round_timestamps: false,
@@ -232,6 +1585,27 @@ impl Default for Config {
             suppress_os_version: false,
             suppress_screen_size: false,
             geoip_precision: default_geoip_precision(),
+            locale: default_locale(),
+            parquet_compression: default_parquet_compression(),
+            parquet_compression_level: 0,
+            parquet_row_group_size: default_parquet_row_group_size(),
+            parquet_dictionary: default_parquet_dictionary(),
+            block_spam_referrers: default_block_spam_referrers(),
+            spam_referrers: Vec::new(),
+            spam_list_url: None,
+            spam_list_refresh_secs: default_spam_list_refresh_secs(),
+            referrer_sources: HashMap::new(),
+            wasm_filter: None,
+            sites: HashMap::new(),
+            tenants: HashMap::new(),
+            branding: BrandingConfig::default(),
+            csp: CspConfig::default(),
+            security_headers: SecurityHeadersConfig::default(),
+            logging: LoggingConfig::default(),
+            shadow_queries: ShadowQueriesConfig::default(),
+            event_bus: EventBusConfig::default(),
+            forwarding: ForwardingConfig::default(),
+            notifications: HashMap::new(),
         }
     }
 }
@@ -247,13 +1621,44 @@ impl Config {
     /// - `MALLARD_FLUSH_INTERVAL` → flush_interval_secs
     /// - `MALLARD_GEOIP_DB` → geoip_db_path
     /// - `MALLARD_DASHBOARD_ORIGIN` → dashboard_origin
+    /// - `MALLARD_DASHBOARD_ORIGINS` → dashboard_origins (comma-separated)
+    /// - `MALLARD_DASHBOARD_DIR` → dashboard_dir
     /// - `MALLARD_FILTER_BOTS` → filter_bots
     /// - `MALLARD_RETENTION_DAYS` → retention_days
     /// - `MALLARD_SESSION_TTL` → session_ttl_secs
     /// - `MALLARD_SHUTDOWN_TIMEOUT` → shutdown_timeout_secs
+    /// - `MALLARD_REUSE_PORT` → reuse_port
     /// - `MALLARD_RATE_LIMIT` → rate_limit_per_site
+    /// - `MALLARD_RATE_LIMIT_BURST` → rate_limit_burst
+    /// - `MALLARD_STATS_RATE_LIMIT` → stats_rate_limit_per_minute
+    /// - `MALLARD_DEDUPE_WINDOW` → dedupe_window_secs
+    /// - `MALLARD_MAX_EVENT_TIME_SKEW` → max_event_time_skew_hours
+    /// - `MALLARD_IDEMPOTENCY_WINDOW` → idempotency_window_secs
+    /// - `MALLARD_INGEST_WORKERS` → ingest_workers
+    /// - `MALLARD_INGEST_QUEUE_SIZE` → ingest_queue_size
+    /// - `MALLARD_INGEST_OVERLOAD` → ingest_overload
+    /// - `MALLARD_MAX_BUFFERED_EVENTS` → max_buffered_events
+    /// - `MALLARD_MIN_FREE_DISK_MB` → min_free_disk_mb
+    /// - `MALLARD_MAX_BUFFER_AGE` → max_buffer_age_secs
+    /// - `MALLARD_FLUSH_RETRIES` → flush_retries
+    /// - `MALLARD_FLUSH_RETRY_BASE_MS` → flush_retry_base_ms
+    /// - `MALLARD_FLUSH_ALERT_AFTER` → flush_alert_after
     /// - `MALLARD_CACHE_TTL` → cache_ttl_secs
+    /// - `MALLARD_CACHE_WARM_SITES` → cache_warm_sites
+    /// - `MALLARD_PUBLIC_SUMMARY_CACHE_TTL` → public_summary_cache_secs
+    /// - `MALLARD_EXPORT_JOB_TTL` → export_job_ttl_secs
+    /// - `MALLARD_IMPORT_JOB_TTL` → import_job_ttl_secs
     /// - `MALLARD_LOG_FORMAT` → log_format
+    /// - `MALLARD_LOG_SAMPLE` → logging.ingest_sample_every
+    /// - `MALLARD_SLOW_QUERY_MS` → logging.slow_query_ms
+    /// - `MALLARD_SHADOW_REPORTS` → shadow_queries.reports (comma-separated)
+    /// - `MALLARD_SHADOW_SAMPLE_EVERY` → shadow_queries.sample_every
+    /// - `MALLARD_EVENT_BUS` → event_bus.kind
+    /// - `MALLARD_EVENT_BUS_SERVERS` → event_bus.servers
+    /// - `MALLARD_EVENT_BUS_TOPIC` → event_bus.topic
+    /// - `MALLARD_EVENT_BUS_GROUP` → event_bus.group
+    /// - `MALLARD_FORWARD_SINK` → forwarding.sink
+    /// - `MALLARD_FORWARD_URL` → forwarding.url
     #[allow(clippy::too_many_lines)]
     pub fn load(config_path: Option<&Path>) -> Self {
         let mut config =
@@ -303,6 +1708,29 @@ impl Config {
         if let Ok(origin) = std::env::var("MALLARD_DASHBOARD_ORIGIN") {
             config.dashboard_origin = Some(origin);
         }
+        if let Ok(val) = std::env::var("MALLARD_DASHBOARD_ORIGINS") {
+            config.dashboard_origins = val
+                .split(',')
+                .map(str::trim)
+                .filter(|o| !o.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        if let Ok(dir) = std::env::var("MALLARD_DASHBOARD_DIR") {
+            config.dashboard_dir = Some(PathBuf::from(dir));
+        }
+        if let Ok(val) = std::env::var("MALLARD_HSTS") {
+            config.security_headers.hsts = val != "0" && val.to_lowercase() != "false";
+        }
+        parse_env_num!(
+            "MALLARD_HSTS_MAX_AGE",
+            config.security_headers.hsts_max_age_secs,
+            u64
+        );
+        if let Ok(val) = std::env::var("MALLARD_FRAME_ANCESTORS") {
+            config.security_headers.frame_ancestors =
+                val.split_whitespace().map(str::to_string).collect();
+        }
         if let Ok(val) = std::env::var("MALLARD_FILTER_BOTS") {
             config.filter_bots = val != "0" && val.to_lowercase() != "false";
         }
@@ -313,22 +1741,153 @@ impl Config {
             config.shutdown_timeout_secs,
             u64
         );
+        if let Ok(val) = std::env::var("MALLARD_REUSE_PORT") {
+            config.reuse_port = val != "0" && val.to_lowercase() != "false";
+        }
         parse_env_num!("MALLARD_RATE_LIMIT", config.rate_limit_per_site, u32);
+        parse_env_num!("MALLARD_RATE_LIMIT_BURST", config.rate_limit_burst, u32);
+        parse_env_num!(
+            "MALLARD_STATS_RATE_LIMIT",
+            config.stats_rate_limit_per_minute,
+            u32
+        );
+        parse_env_num!("MALLARD_DEDUPE_WINDOW", config.dedupe_window_secs, u64);
+        parse_env_num!(
+            "MALLARD_MAX_EVENT_TIME_SKEW",
+            config.max_event_time_skew_hours,
+            u32
+        );
+        parse_env_num!(
+            "MALLARD_IDEMPOTENCY_WINDOW",
+            config.idempotency_window_secs,
+            u64
+        );
+        parse_env_num!("MALLARD_INGEST_WORKERS", config.ingest_workers, usize);
+        parse_env_num!("MALLARD_INGEST_QUEUE_SIZE", config.ingest_queue_size, usize);
+        if let Ok(val) = std::env::var("MALLARD_INGEST_OVERLOAD") {
+            config.ingest_overload = val;
+        }
+        parse_env_num!(
+            "MALLARD_MAX_BUFFERED_EVENTS",
+            config.max_buffered_events,
+            usize
+        );
+        parse_env_num!("MALLARD_MIN_FREE_DISK_MB", config.min_free_disk_mb, u64);
+        parse_env_num!("MALLARD_MAX_BUFFER_AGE", config.max_buffer_age_secs, u64);
+        parse_env_num!("MALLARD_FLUSH_RETRIES", config.flush_retries, u32);
+        parse_env_num!(
+            "MALLARD_FLUSH_RETRY_BASE_MS",
+            config.flush_retry_base_ms,
+            u64
+        );
+        parse_env_num!("MALLARD_FLUSH_ALERT_AFTER", config.flush_alert_after, u32);
         parse_env_num!("MALLARD_CACHE_TTL", config.cache_ttl_secs, u64);
         if let Ok(val) = std::env::var("MALLARD_LOG_FORMAT") {
             config.log_format = val;
         }
+        parse_env_num!(
+            "MALLARD_LOG_SAMPLE",
+            config.logging.ingest_sample_every,
+            u64
+        );
+        parse_env_num!("MALLARD_SLOW_QUERY_MS", config.logging.slow_query_ms, u64);
+        if let Ok(val) = std::env::var("MALLARD_SHADOW_REPORTS") {
+            config.shadow_queries.reports = val
+                .split(',')
+                .map(str::trim)
+                .filter(|r| !r.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        parse_env_num!(
+            "MALLARD_SHADOW_SAMPLE_EVERY",
+            config.shadow_queries.sample_every,
+            u64
+        );
+        if let Ok(val) = std::env::var("MALLARD_EVENT_BUS") {
+            config.event_bus.kind = val;
+        }
+        if let Ok(val) = std::env::var("MALLARD_EVENT_BUS_SERVERS") {
+            config.event_bus.servers = val;
+        }
+        if let Ok(val) = std::env::var("MALLARD_EVENT_BUS_TOPIC") {
+            config.event_bus.topic = val;
+        }
+        if let Ok(val) = std::env::var("MALLARD_EVENT_BUS_GROUP") {
+            config.event_bus.group = val;
+        }
+        if let Ok(val) = std::env::var("MALLARD_FORWARD_SINK") {
+            config.forwarding.sink = val;
+        }
+        if let Ok(val) = std::env::var("MALLARD_FORWARD_URL") {
+            config.forwarding.url = val;
+        }
         parse_env_num!("MALLARD_MAX_LOGIN_ATTEMPTS", config.max_login_attempts, u32);
         parse_env_num!("MALLARD_LOGIN_LOCKOUT", config.login_lockout_secs, u64);
         parse_env_num!("MALLARD_CACHE_MAX_ENTRIES", config.cache_max_entries, usize);
+        parse_env_num!("MALLARD_CACHE_WARM_SITES", config.cache_warm_sites, usize);
+        parse_env_num!(
+            "MALLARD_PUBLIC_SUMMARY_CACHE_TTL",
+            config.public_summary_cache_secs,
+            u64
+        );
         parse_env_num!(
             "MALLARD_MAX_CONCURRENT_QUERIES",
             config.max_concurrent_queries,
             usize
         );
+        parse_env_num!("MALLARD_EXPORT_JOB_TTL", config.export_job_ttl_secs, u64);
+        parse_env_num!("MALLARD_IMPORT_JOB_TTL", config.import_job_ttl_secs, u64);
+        if let Ok(val) = std::env::var("MALLARD_DUCKDB_MEMORY_LIMIT") {
+            config.duckdb_memory_limit = Some(val);
+        }
+        parse_env_num!("MALLARD_DUCKDB_THREADS", config.duckdb_threads, usize);
+        if let Ok(val) = std::env::var("MALLARD_DUCKDB_TEMP_DIR") {
+            config.duckdb_temp_directory = Some(PathBuf::from(val));
+        }
+        if let Ok(val) = std::env::var("MALLARD_DUCKDB_MAX_TEMP_SIZE") {
+            config.duckdb_max_temp_size = Some(val);
+        }
         if let Ok(val) = std::env::var("MALLARD_SECURE_COOKIES") {
             config.secure_cookies = val != "0" && val.to_lowercase() != "false";
         }
+        if let Ok(val) = std::env::var("MALLARD_ANOMALY_DETECTION") {
+            config.anomaly_detection = val != "0" && val.to_lowercase() != "false";
+        }
+        if let Ok(val) = std::env::var("MALLARD_DEBUG_INGEST") {
+            config.debug_ingest = val != "0" && val.to_lowercase() != "false";
+        }
+        parse_env_num!(
+            "MALLARD_ANOMALY_THRESHOLD",
+            config.anomaly_threshold_pct,
+            f64
+        );
+        parse_env_num!(
+            "MALLARD_ANOMALY_MIN_VISITORS",
+            config.anomaly_min_visitors,
+            u64
+        );
+        if let Ok(val) = std::env::var("MALLARD_IDENTITY_STITCHING") {
+            config.identity_stitching = val != "0" && val.to_lowercase() != "false";
+        }
+        if let Ok(val) = std::env::var("MALLARD_MATERIALIZE_SESSIONS") {
+            config.materialize_sessions = val != "0" && val.to_lowercase() != "false";
+        }
+        if let Ok(addr) = std::env::var("MALLARD_STATSD_ADDR") {
+            config.statsd_addr = Some(addr).filter(|a| !a.is_empty());
+        }
+        if let Ok(prefix) = std::env::var("MALLARD_STATSD_PREFIX") {
+            config.statsd_prefix = prefix;
+        }
+        parse_env_num!("MALLARD_STATSD_INTERVAL", config.statsd_interval_secs, u64);
+        if let Ok(url) = std::env::var("MALLARD_DIGEST_SLACK_WEBHOOK") {
+            config.digest_slack_webhook = Some(url).filter(|u| !u.is_empty());
+        }
+        parse_env_num!(
+            "MALLARD_DIGEST_INTERVAL_HOURS",
+            config.digest_interval_hours,
+            u64
+        );
 
         // Privacy / GDPR configuration env vars
         if let Ok(val) = std::env::var("MALLARD_GDPR_MODE") {
@@ -355,6 +1914,37 @@ impl Config {
         if let Ok(val) = std::env::var("MALLARD_GEOIP_PRECISION") {
             config.geoip_precision = val;
         }
+        if let Ok(val) = std::env::var("MALLARD_LOCALE") {
+            config.locale = val;
+        }
+        if let Ok(val) = std::env::var("MALLARD_BRAND_NAME") {
+            config.branding.product_name = Some(val);
+        }
+        if let Ok(val) = std::env::var("MALLARD_BRAND_LOGO_URL") {
+            config.branding.logo_url = Some(val);
+        }
+        if let Ok(val) = std::env::var("MALLARD_BRAND_ACCENT_COLOR") {
+            config.branding.accent_color = Some(val);
+        }
+        if let Ok(val) = std::env::var("MALLARD_BRAND_SECONDARY_COLOR") {
+            config.branding.secondary_color = Some(val);
+        }
+        if let Ok(val) = std::env::var("MALLARD_PARQUET_COMPRESSION") {
+            config.parquet_compression = val;
+        }
+        parse_env_num!(
+            "MALLARD_PARQUET_COMPRESSION_LEVEL",
+            config.parquet_compression_level,
+            u32
+        );
+        parse_env_num!(
+            "MALLARD_PARQUET_ROW_GROUP_SIZE",
+            config.parquet_row_group_size,
+            u64
+        );
+        if let Ok(val) = std::env::var("MALLARD_PARQUET_DICTIONARY") {
+            config.parquet_dictionary = val != "0" && val.to_lowercase() != "false";
+        }
 
         // Apply gdpr_mode bundle AFTER all other env vars are resolved.
         // gdpr_mode is a convenience preset: it forces privacy-enhancing flags on.
@@ -381,6 +1971,49 @@ impl Config {
         self.data_dir.join("events")
     }
 
+    /// Returns the directory export jobs write their files to.
+    pub fn exports_dir(&self) -> PathBuf {
+        self.data_dir.join("exports")
+    }
+
+    /// Returns the directory materialized sessions are written to.
+    pub fn sessions_dir(&self) -> PathBuf {
+        self.data_dir.join("sessions")
+    }
+
+    /// Returns the directory import jobs store their uploads in.
+    pub fn imports_dir(&self) -> PathBuf {
+        self.data_dir.join("imports")
+    }
+
+    /// Returns the notification channels of the config file by name, with
+    /// `digest_slack_webhook` as a Slack channel posting only digests.
+    pub fn notification_channels(&self) -> Vec<(String, NotificationChannel)> {
+        let mut channels: Vec<(String, NotificationChannel)> = self
+            .notifications
+            .iter()
+            .map(|(name, channel)| (name.clone(), channel.clone()))
+            .collect();
+        channels.sort_by(|a, b| a.0.cmp(&b.0));
+        if let Some(url) = &self.digest_slack_webhook {
+            channels.push((
+                "digest_slack_webhook".to_string(),
+                NotificationChannel {
+                    kind: "slack".to_string(),
+                    url: url.clone(),
+                    events: vec!["digest".to_string()],
+                    ..NotificationChannel::default()
+                },
+            ));
+        }
+        channels
+    }
+
+    /// Returns the file batches that could not be forwarded are appended to.
+    pub fn forwarding_dead_letter_path(&self) -> PathBuf {
+        self.data_dir.join("forwarding-dead-letter.ndjson")
+    }
+
     /// Returns the path to the DuckDB database file.
     ///
     /// Using a disk-based file instead of an in-memory database allows events
@@ -391,9 +2024,62 @@ impl Config {
         self.data_dir.join("mallard.duckdb")
     }
 
+    /// Returns the directory DuckDB spills to.
+    pub fn duckdb_temp_dir(&self) -> PathBuf {
+        self.duckdb_temp_directory
+            .clone()
+            .unwrap_or_else(|| self.data_dir.join("duckdb_tmp"))
+    }
+
+    /// Returns the path to the persisted API key store.
+    pub fn api_keys_path(&self) -> PathBuf {
+        self.data_dir.join("api_keys.json")
+    }
+
+    /// Returns the path to the admin password hash persisted by first-run
+    /// setup or `mallard-metrics reset-password`.
+    pub fn admin_password_path(&self) -> PathBuf {
+        self.data_dir.join("admin_password")
+    }
+
+    /// The data lake under [`Config::events_dir`], laid out by tenant and
+    /// writing files as configured.
+    pub fn parquet_storage(&self) -> ParquetStorage {
+        ParquetStorage::new(&self.events_dir())
+            .with_write_options(self.parquet_write_options())
+            .with_tenants(Arc::new(self.storage_tenants()))
+    }
+
+    /// Each site's tenant, and each tenant's retention override.
+    pub fn storage_tenants(&self) -> Tenants {
+        Tenants::new(
+            self.sites
+                .iter()
+                .filter_map(|(site_id, site)| Some((site_id.clone(), site.tenant.clone()?)))
+                .collect(),
+            self.tenants
+                .iter()
+                .filter_map(|(tenant, t)| Some((tenant.clone(), t.retention_days?)))
+                .collect(),
+        )
+    }
+
+    /// How Parquet files are written.  Assumes [`Config::validate`] passed;
+    /// an unknown codec falls back to zstd.
+    pub fn parquet_write_options(&self) -> ParquetWriteOptions {
+        ParquetWriteOptions {
+            compression: ParquetCompression::from_name(&self.parquet_compression)
+                .unwrap_or_default(),
+            compression_level: self.parquet_compression_level,
+            row_group_size: self.parquet_row_group_size,
+            dictionary: self.parquet_dictionary,
+        }
+    }
+
     /// Validate that configuration values are internally consistent.
     ///
     /// Called at startup to catch misconfiguration before the server binds.
+    #[allow(clippy::too_many_lines)]
     pub fn validate(&self) -> Result<(), String> {
         if self.flush_event_count == 0 {
             return Err(
@@ -413,6 +2099,47 @@ impl Config {
                     .to_string(),
             );
         }
+        if let Some(origin) = self
+            .dashboard_origin
+            .iter()
+            .chain(&self.dashboard_origins)
+            .find(|o| !is_valid_origin(o))
+        {
+            return Err(format!(
+                "dashboard origins must be a lowercase scheme, host and optional port without a path, such as \"https://analytics.example.com\" (got {origin:?})"
+            ));
+        }
+        if let Some(limit) = &self.duckdb_memory_limit {
+            if !crate::storage::resources::is_valid_size(limit) {
+                return Err(format!(
+                    "duckdb_memory_limit must be a size such as \"2GB\" or \"512MiB\" (got {limit:?})"
+                ));
+            }
+        }
+        if let Some(size) = &self.duckdb_max_temp_size {
+            if !crate::storage::resources::is_valid_size(size) {
+                return Err(format!(
+                    "duckdb_max_temp_size must be a size such as \"20GB\" (got {size:?})"
+                ));
+            }
+        }
+        if !(self.anomaly_threshold_pct.is_finite() && self.anomaly_threshold_pct > 0.0) {
+            return Err(format!(
+                "anomaly_threshold_pct must be a positive number (got {})",
+                self.anomaly_threshold_pct
+            ));
+        }
+        if self.statsd_addr.is_some() && self.statsd_interval_secs == 0 {
+            return Err("statsd_interval_secs must be > 0 when statsd_addr is set".to_string());
+        }
+        if let Some(url) = &self.digest_slack_webhook {
+            if !url.starts_with("https://") {
+                return Err("digest_slack_webhook must be an https:// URL".to_string());
+            }
+        }
+        if self.digest_interval_hours == 0 {
+            return Err("digest_interval_hours must be > 0".to_string());
+        }
         if !matches!(
             self.geoip_precision.as_str(),
             "city" | "region" | "country" | "none"
@@ -422,6 +2149,174 @@ impl Config {
                 self.geoip_precision
             ));
         }
+        if crate::api::locale::Locale::from_tag(&self.locale).is_none() {
+            let available: Vec<_> = crate::api::locale::Locale::ALL
+                .iter()
+                .map(|l| l.tag())
+                .collect();
+            return Err(format!(
+                "locale must be one of: {} (got {:?})",
+                available.join(", "),
+                self.locale
+            ));
+        }
+        let Some(compression) = ParquetCompression::from_name(&self.parquet_compression) else {
+            let available: Vec<_> = ParquetCompression::ALL.iter().map(|c| c.name()).collect();
+            return Err(format!(
+                "parquet_compression must be one of: {} (got {:?})",
+                available.join(", "),
+                self.parquet_compression
+            ));
+        };
+        if self.parquet_compression_level != 0 {
+            if compression != ParquetCompression::Zstd {
+                return Err(format!(
+                    "parquet_compression_level is only supported with zstd compression (got {})",
+                    compression.name()
+                ));
+            }
+            if self.parquet_compression_level > 22 {
+                return Err(format!(
+                    "parquet_compression_level must be between 1 and 22, or 0 for the default (got {})",
+                    self.parquet_compression_level
+                ));
+            }
+        }
+        if self.parquet_row_group_size == 0 {
+            return Err("parquet_row_group_size must be > 0".to_string());
+        }
+        if self.ingest_workers > 0 && self.ingest_queue_size == 0 {
+            return Err(
+                "ingest_queue_size must be > 0 when ingest_workers is set; set ingest_workers = 0 to enrich events in the request handler"
+                    .to_string(),
+            );
+        }
+        if OverloadPolicy::from_name(&self.ingest_overload).is_none() {
+            let available: Vec<_> = OverloadPolicy::ALL.iter().map(|p| p.name()).collect();
+            return Err(format!(
+                "ingest_overload must be one of: {} (got {:?})",
+                available.join(", "),
+                self.ingest_overload
+            ));
+        }
+        if self.max_buffered_events != 0 && self.max_buffered_events < self.flush_event_count {
+            return Err(format!(
+                "max_buffered_events ({}) must be 0 or at least flush_event_count ({})",
+                self.max_buffered_events, self.flush_event_count
+            ));
+        }
+        if self.rate_limit_burst != 0 && self.rate_limit_burst < self.rate_limit_per_site {
+            return Err(format!(
+                "rate_limit_burst ({}) must be 0 or at least rate_limit_per_site ({})",
+                self.rate_limit_burst, self.rate_limit_per_site
+            ));
+        }
+        if let Some(url) = &self.spam_list_url {
+            if !url.starts_with("https://") {
+                return Err(format!(
+                    "spam_list_url must be an https:// URL (got {url:?})"
+                ));
+            }
+            if self.spam_list_refresh_secs == 0 {
+                return Err(
+                    "spam_list_refresh_secs must be > 0 when spam_list_url is set".to_string(),
+                );
+            }
+        }
+        if self.wasm_filter.is_some() && !cfg!(feature = "wasm-filters") {
+            return Err(
+                "wasm_filter is set but this build lacks the `wasm-filters` feature".to_string(),
+            );
+        }
+        for (host, source) in &self.referrer_sources {
+            if host.is_empty() || source.is_empty() || source.len() > 256 {
+                return Err(format!(
+                    "referrer_sources.{host:?}: hostname and source name must be non-empty, source at most 256 characters"
+                ));
+            }
+        }
+        self.branding.validate()?;
+        self.csp.validate()?;
+        self.security_headers.validate()?;
+        self.logging.validate()?;
+        self.shadow_queries.validate()?;
+        self.event_bus.validate()?;
+        self.forwarding.validate()?;
+        for (name, channel) in &self.notifications {
+            channel
+                .validate()
+                .map_err(|e| format!("notifications.{name:?}: {e}"))?;
+        }
+        if let Some(tenant) = self.tenants.keys().find(|t| !is_valid_group_name(t)) {
+            return Err(format!(
+                "tenants.{tenant:?}: tenant IDs must be 1-64 alphanumeric, '-' or '_' characters"
+            ));
+        }
+        for (site_id, site) in &self.sites {
+            if let (Some(rate), Some(burst)) = (site.rate_limit, site.rate_limit_burst) {
+                if burst != 0 && burst < rate {
+                    return Err(format!(
+                        "sites.{site_id:?}: rate_limit_burst ({burst}) must be 0 or at least rate_limit ({rate})"
+                    ));
+                }
+            }
+            if let Some(group) = &site.group {
+                if !is_valid_group_name(group) {
+                    return Err(format!(
+                        "sites.{site_id:?}: group must be 1-64 alphanumeric, '-' or '_' characters (got {group:?})"
+                    ));
+                }
+            }
+            if let Some(tenant) = &site.tenant {
+                if !is_valid_group_name(tenant) {
+                    return Err(format!(
+                        "sites.{site_id:?}: tenant must be 1-64 alphanumeric, '-' or '_' characters (got {tenant:?})"
+                    ));
+                }
+            }
+            if let Some(name) = site
+                .query_params
+                .iter()
+                .chain(&site.search_param)
+                .find(|name| !is_valid_query_param_name(name))
+            {
+                return Err(format!(
+                    "sites.{site_id:?}: query parameter names must be 1-64 alphanumeric, '-', '_' or '.' characters (got {name:?})"
+                ));
+            }
+            if let Some(code) = site
+                .blocked_countries
+                .iter()
+                .chain(&site.blocked_continents)
+                .find(|code| code.len() != 2 || !code.chars().all(|c| c.is_ascii_alphabetic()))
+            {
+                return Err(format!(
+                    "sites.{site_id:?}: blocked country and continent codes must be two letters (got {code:?})"
+                ));
+            }
+            if site.custom_dimensions.len() > MAX_CUSTOM_DIMENSIONS {
+                return Err(format!(
+                    "sites.{site_id:?}: at most {MAX_CUSTOM_DIMENSIONS} custom_dimensions are supported (got {})",
+                    site.custom_dimensions.len()
+                ));
+            }
+            for (i, dim) in site.custom_dimensions.iter().enumerate() {
+                if dim.name.is_empty() || dim.name.len() > 64 {
+                    return Err(format!(
+                        "sites.{site_id:?}: custom dimension names must be 1-64 characters"
+                    ));
+                }
+                if site.custom_dimensions[..i]
+                    .iter()
+                    .any(|other| other.name == dim.name)
+                {
+                    return Err(format!(
+                        "sites.{site_id:?}: duplicate custom dimension {:?}",
+                        dim.name
+                    ));
+                }
+            }
+        }
         Ok(())
     }
 }
@@ -448,12 +2343,22 @@ mod tests {
         assert!(config.site_ids.is_empty());
         assert!(config.geoip_db_path.is_none());
         assert!(config.dashboard_origin.is_none());
+        assert!(config.dashboard_dir.is_none());
         assert!(config.filter_bots);
         assert_eq!(config.retention_days, 0);
         assert_eq!(config.session_ttl_secs, 86400);
         assert_eq!(config.shutdown_timeout_secs, 30);
         assert_eq!(config.rate_limit_per_site, 0);
         assert_eq!(config.cache_ttl_secs, 60);
+        assert_eq!(config.dedupe_window_secs, 60);
+        assert_eq!(config.max_event_time_skew_hours, 48);
+        assert_eq!(config.idempotency_window_secs, 3600);
+        assert_eq!(config.export_job_ttl_secs, 86400);
+        assert_eq!(config.public_summary_cache_secs, 3600);
+        assert_eq!(config.import_job_ttl_secs, 86400);
+        assert_eq!(config.ingest_workers, 2);
+        assert_eq!(config.ingest_queue_size, 10_000);
+        assert_eq!(config.ingest_overload, "reject");
         assert_eq!(config.log_format, "text");
         assert_eq!(config.max_login_attempts, 5);
         assert_eq!(config.login_lockout_secs, 300);
@@ -466,13 +2371,70 @@ mod tests {
     }
 
     #[test]
-    fn test_validate_zero_flush_count() {
+    fn test_validate_zero_flush_count() {
+        let config = Config {
+            flush_event_count: 0,
+            ..Config::default()
+        };
+        let err = config.validate().unwrap_err();
+        assert!(err.contains("flush_event_count"));
+    }
+
+    #[test]
+    fn test_validate_zero_ingest_queue_size() {
+        let config = Config {
+            ingest_queue_size: 0,
+            ..Config::default()
+        };
+        let err = config.validate().unwrap_err();
+        assert!(err.contains("ingest_queue_size"));
+
+        let inline = Config {
+            ingest_workers: 0,
+            ingest_queue_size: 0,
+            ..Config::default()
+        };
+        assert!(inline.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_ingest_overload() {
+        let config = Config {
+            ingest_overload: "shed".to_string(),
+            ..Config::default()
+        };
+        assert!(config.validate().is_ok());
+        let config = Config {
+            ingest_overload: "drop".to_string(),
+            ..Config::default()
+        };
+        let err = config.validate().unwrap_err();
+        assert!(err.contains("ingest_overload"));
+    }
+
+    #[test]
+    fn test_validate_max_buffered_events() {
+        let config = Config::default();
+        assert_eq!(config.max_buffered_events, 100_000);
+        assert_eq!(config.min_free_disk_mb, 512);
+        assert_eq!(config.max_buffer_age_secs, 60);
+        assert_eq!(config.flush_retries, 3);
+        assert_eq!(config.flush_retry_base_ms, 1000);
+        assert_eq!(config.flush_alert_after, 3);
         let config = Config {
-            flush_event_count: 0,
+            max_buffered_events: 10,
+            flush_event_count: 100,
             ..Config::default()
         };
-        let err = config.validate().unwrap_err();
-        assert!(err.contains("flush_event_count"));
+        assert!(config
+            .validate()
+            .unwrap_err()
+            .contains("max_buffered_events"));
+        let unbounded = Config {
+            max_buffered_events: 0,
+            ..Config::default()
+        };
+        assert!(unbounded.validate().is_ok());
     }
 
     #[test]
@@ -495,6 +2457,50 @@ mod tests {
         assert!(err.contains("session_ttl_secs"));
     }
 
+    #[test]
+    fn test_validate_zero_statsd_interval() {
+        let config = Config {
+            statsd_addr: Some("127.0.0.1:8125".to_string()),
+            statsd_interval_secs: 0,
+            ..Config::default()
+        };
+        let err = config.validate().unwrap_err();
+        assert!(err.contains("statsd_interval_secs"));
+    }
+
+    #[test]
+    fn test_validate_digest_slack_webhook() {
+        let config = Config {
+            digest_slack_webhook: Some("https://hooks.slack.com/services/T0/B0/x".to_string()),
+            ..Config::default()
+        };
+        assert!(config.validate().is_ok());
+        let config = Config {
+            digest_slack_webhook: Some("http://hooks.slack.com/services/T0/B0/x".to_string()),
+            ..Config::default()
+        };
+        assert!(config.validate().unwrap_err().contains("https://"));
+        let config = Config {
+            digest_slack_webhook: Some("https://hooks.slack.com/services/T0/B0/x".to_string()),
+            digest_interval_hours: 0,
+            ..Config::default()
+        };
+        assert!(config
+            .validate()
+            .unwrap_err()
+            .contains("digest_interval_hours"));
+    }
+
+    #[test]
+    fn test_validate_anomaly_threshold() {
+        let config = Config {
+            anomaly_threshold_pct: 0.0,
+            ..Config::default()
+        };
+        let err = config.validate().unwrap_err();
+        assert!(err.contains("anomaly_threshold_pct"));
+    }
+
     #[test]
     fn test_load_from_toml() {
         let _guard = ENV_LOCK.lock().unwrap();
@@ -714,6 +2720,657 @@ session_ttl_secs = 3600
         }
     }
 
+    #[test]
+    fn test_load_site_registry_from_toml() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        std::fs::write(
+            &config_path,
+            r#"
+[sites."example.com"]
+custom_dimensions = [
+    { name = "plan", type = "string" },
+    { name = "seats", type = "number" },
+    { name = "trial", type = "bool" },
+]
+hash_mode = true
+path_rules = [{ pattern = '^/users/\d+', replace = "/users/:id" }]
+"#,
+        )
+        .unwrap();
+
+        let config = Config::load(Some(&config_path));
+        let site = config.sites.get("example.com").unwrap();
+        assert_eq!(site.custom_dimensions.len(), 3);
+        assert!(site.hash_mode);
+        assert_eq!(site.normalize_path("/users/42/edit"), "/users/:id/edit");
+        assert_eq!(
+            site.dimension_slot("seats"),
+            Some((2, DimensionType::Number))
+        );
+        assert_eq!(site.dimension_slot("missing"), None);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        let site = SiteConfig {
+            lowercase_paths: true,
+            strip_trailing_slash: true,
+            path_rules: vec![
+                PathRule {
+                    pattern: regex::Regex::new(r"^/orders/[0-9a-f-]{36}").unwrap(),
+                    replace: "/orders/:uuid".to_string(),
+                },
+                PathRule {
+                    pattern: regex::Regex::new(r"/(\d+)(/|$)").unwrap(),
+                    replace: "/:id$2".to_string(),
+                },
+            ],
+            ..SiteConfig::default()
+        };
+        assert_eq!(site.normalize_path("/Blog/Post/"), "/blog/post");
+        assert_eq!(site.normalize_path("/"), "/");
+        assert_eq!(
+            site.normalize_path("/orders/3F2504E0-4F89-11D3-9A0C-0305E82C3301/"),
+            "/orders/:uuid"
+        );
+        assert_eq!(
+            site.normalize_path("/teams/7/members/12"),
+            "/teams/:id/members/:id"
+        );
+        // No rules configured: unchanged.
+        assert_eq!(SiteConfig::default().normalize_path("/A/"), "/A/");
+    }
+
+    #[test]
+    fn test_validate_query_param_names() {
+        let mut config = Config::default();
+        config.sites.insert(
+            "example.com".to_string(),
+            SiteConfig {
+                query_params: vec!["page".to_string()],
+                search_param: Some("q".to_string()),
+                ..SiteConfig::default()
+            },
+        );
+        assert!(config.validate().is_ok());
+        let site = &config.sites["example.com"];
+        assert!(site.keeps_query_param("q"));
+        assert!(!site.keeps_query_param("utm_source"));
+
+        config.sites.get_mut("example.com").unwrap().search_param = Some("q=".to_string());
+        let err = config.validate().unwrap_err();
+        assert!(err.contains("query parameter"));
+    }
+
+    #[test]
+    fn test_validate_referrer_sources() {
+        let mut config = Config::default();
+        config.referrer_sources.insert(
+            "news.ycombinator.com".to_string(),
+            "Hacker News".to_string(),
+        );
+        assert!(config.validate().is_ok());
+
+        config
+            .referrer_sources
+            .insert("example.com".to_string(), String::new());
+        let err = config.validate().unwrap_err();
+        assert!(err.contains("referrer_sources"));
+    }
+
+    #[test]
+    fn test_validate_spam_list_url() {
+        let mut config = Config {
+            spam_list_url: Some("https://example.com/spammers.txt".to_string()),
+            ..Config::default()
+        };
+        assert!(config.validate().is_ok());
+        config.spam_list_refresh_secs = 0;
+        assert!(config.validate().is_err());
+        config.spam_list_refresh_secs = 3600;
+        config.spam_list_url = Some("http://example.com/spammers.txt".to_string());
+        assert!(config.validate().unwrap_err().contains("https"));
+    }
+
+    #[test]
+    fn test_validate_parquet_options() {
+        let mut config = Config {
+            parquet_compression: "LZ4".to_string(),
+            parquet_dictionary: false,
+            ..Config::default()
+        };
+        assert!(config.validate().is_ok());
+        let options = config.parquet_write_options();
+        assert_eq!(options.compression, ParquetCompression::Lz4);
+        assert!(!options.dictionary);
+
+        config.parquet_compression_level = 9;
+        assert!(config.validate().unwrap_err().contains("zstd"));
+        config.parquet_compression = "zstd".to_string();
+        assert!(config.validate().is_ok());
+        config.parquet_compression_level = 23;
+        assert!(config.validate().is_err());
+        config.parquet_compression_level = 0;
+        config.parquet_row_group_size = 0;
+        assert!(config.validate().is_err());
+        config.parquet_row_group_size = 1024;
+        config.parquet_compression = "lzo".to_string();
+        assert!(config
+            .validate()
+            .unwrap_err()
+            .contains("parquet_compression"));
+    }
+
+    #[test]
+    fn test_validate_wasm_filter_needs_feature() {
+        let config = Config {
+            wasm_filter: Some(PathBuf::from("filter.wasm")),
+            ..Config::default()
+        };
+        assert_eq!(config.validate().is_ok(), cfg!(feature = "wasm-filters"));
+    }
+
+    #[test]
+    fn test_site_geo_blocking() {
+        let site: SiteConfig = toml::from_str(
+            r#"
+            blocked_countries = ["RU"]
+            blocked_continents = ["an"]
+            geo_block_action = "segregate"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(site.geo_block_action, GeoBlockAction::Segregate);
+        assert!(site.geo_blocked(Some("RU"), Some("EU")));
+        assert!(site.geo_blocked(Some("ru"), None));
+        assert!(site.geo_blocked(None, Some("AN")));
+        assert!(!site.geo_blocked(Some("DE"), Some("EU")));
+        assert!(!site.geo_blocked(None, None));
+        assert_eq!(SiteConfig::default().geo_block_action, GeoBlockAction::Drop);
+
+        let mut config = Config::default();
+        config.sites.insert("example.com".to_string(), site);
+        assert!(config.validate().is_ok());
+        config
+            .sites
+            .get_mut("example.com")
+            .unwrap()
+            .blocked_countries = vec!["RUS".to_string()];
+        assert!(config.validate().unwrap_err().contains("two letters"));
+    }
+
+    #[test]
+    fn test_invalid_path_rule_rejected() {
+        let result = toml::from_str::<SiteConfig>(
+            r#"path_rules = [{ pattern = "(unclosed", replace = "/" }]"#,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_validate_too_many_custom_dimensions() {
+        let dims = (0..=MAX_CUSTOM_DIMENSIONS)
+            .map(|i| CustomDimension {
+                name: format!("d{i}"),
+                kind: DimensionType::String,
+            })
+            .collect();
+        let mut config = Config::default();
+        config.sites.insert(
+            "example.com".to_string(),
+            SiteConfig {
+                custom_dimensions: dims,
+                ..SiteConfig::default()
+            },
+        );
+        let err = config.validate().unwrap_err();
+        assert!(err.contains("custom_dimensions"));
+    }
+
+    #[test]
+    fn test_validate_site_group_name() {
+        let mut config = Config::default();
+        config.sites.insert(
+            "example.com".to_string(),
+            SiteConfig {
+                custom_dimensions: vec![],
+                group: Some("acme corp".to_string()),
+                ..SiteConfig::default()
+            },
+        );
+        let err = config.validate().unwrap_err();
+        assert!(err.contains("group"));
+
+        config.sites.get_mut("example.com").unwrap().group = Some("acme-corp".to_string());
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_tenants() {
+        let config: Config = toml::from_str(
+            r#"
+            retention_days = 90
+
+            [sites."acme.com"]
+            tenant = "acme"
+
+            [sites."other.com"]
+
+            [tenants.acme]
+            retention_days = 30
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        let tenants = config.storage_tenants();
+        assert_eq!(tenants.tenant_of("acme.com"), Some("acme"));
+        assert_eq!(tenants.tenant_of("other.com"), None);
+        assert_eq!(tenants.retention_days(Some("acme"), 90), 30);
+        assert_eq!(tenants.retention_days(None, 90), 90);
+
+        let mut config = config;
+        config.sites.get_mut("acme.com").unwrap().tenant = Some("../acme".to_string());
+        assert!(config.validate().unwrap_err().contains("tenant"));
+    }
+
+    #[test]
+    fn test_validate_dashboard_origins() {
+        for origin in [
+            "https://analytics.example.com",
+            "http://localhost:3000",
+            "http://127.0.0.1:8000",
+            "http://[::1]:8000",
+        ] {
+            let config = Config {
+                dashboard_origins: vec![origin.to_string()],
+                ..Config::default()
+            };
+            assert!(config.validate().is_ok(), "{origin}");
+        }
+        for origin in [
+            "*",
+            "null",
+            "analytics.example.com",
+            "https://analytics.example.com/",
+            "https://analytics.example.com/dashboard",
+            "https://Analytics.example.com",
+            "https://analytics.example.com:0",
+            "https://analytics.example.com:+443",
+            "ftp://analytics.example.com",
+            "https://",
+        ] {
+            let config = Config {
+                dashboard_origin: Some(origin.to_string()),
+                ..Config::default()
+            };
+            assert!(
+                config.validate().unwrap_err().contains("dashboard origins"),
+                "{origin}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate_csp_sources() {
+        let config: Config = toml::from_str(
+            r#"
+            [csp]
+            script_src = ["https://cdn.example.com", "'unsafe-eval'"]
+            connect_src = ["wss://live.example.com"]
+            nonce = true
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert!(config.csp.nonce);
+
+        for source in ["", "https://a.example.com; script-src *", "a b", "a,b"] {
+            let config = Config {
+                csp: CspConfig {
+                    style_src: vec![source.to_string()],
+                    ..CspConfig::default()
+                },
+                ..Config::default()
+            };
+            assert!(config.validate().unwrap_err().contains("csp.style_src"));
+        }
+    }
+
+    #[test]
+    fn test_security_headers() {
+        let config = Config::default();
+        assert_eq!(
+            config.security_headers.hsts_value().as_deref(),
+            Some("max-age=31536000; includeSubDomains; preload")
+        );
+
+        let config: Config = toml::from_str(
+            r#"
+            [security_headers]
+            hsts_max_age_secs = 86400
+            hsts_include_subdomains = false
+            hsts_preload = false
+            cross_origin_opener_policy = ""
+            frame_ancestors = ["https://intranet.example.com"]
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.security_headers.hsts_value().as_deref(),
+            Some("max-age=86400")
+        );
+
+        let mut preload = config.clone();
+        preload.security_headers.hsts_preload = true;
+        assert!(preload.validate().unwrap_err().contains("hsts_preload"));
+        // Preload settings do not matter while HSTS is off.
+        preload.security_headers.hsts = false;
+        assert!(preload.validate().is_ok());
+        assert_eq!(preload.security_headers.hsts_value(), None);
+
+        let mut coop = config.clone();
+        coop.security_headers.cross_origin_opener_policy = "same-site".to_string();
+        assert!(coop
+            .validate()
+            .unwrap_err()
+            .contains("cross_origin_opener_policy"));
+
+        let mut framing = config;
+        framing.security_headers.frame_ancestors = vec!["* ; script-src *".to_string()];
+        assert!(framing.validate().unwrap_err().contains("frame_ancestors"));
+    }
+
+    #[test]
+    fn test_duckdb_limits() {
+        let config: Config = toml::from_str(
+            r#"
+            duckdb_memory_limit = "2GB"
+            duckdb_threads = 4
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.duckdb_memory_limit.as_deref(), Some("2GB"));
+        assert_eq!(config.duckdb_threads, 4);
+        assert_eq!(Config::default().duckdb_memory_limit, None);
+
+        let mut invalid = config.clone();
+        invalid.duckdb_memory_limit = Some("lots".to_string());
+        assert!(invalid
+            .validate()
+            .unwrap_err()
+            .contains("duckdb_memory_limit"));
+        let mut invalid = config;
+        invalid.duckdb_max_temp_size = Some("20".to_string());
+        assert!(invalid
+            .validate()
+            .unwrap_err()
+            .contains("duckdb_max_temp_size"));
+    }
+
+    #[test]
+    fn test_duckdb_temp_dir() {
+        let mut config = Config {
+            data_dir: PathBuf::from("/var/lib/mallard"),
+            ..Config::default()
+        };
+        assert_eq!(
+            config.duckdb_temp_dir(),
+            PathBuf::from("/var/lib/mallard/duckdb_tmp")
+        );
+        config.duckdb_temp_directory = Some(PathBuf::from("/scratch/mallard"));
+        assert_eq!(config.duckdb_temp_dir(), PathBuf::from("/scratch/mallard"));
+    }
+
+    #[test]
+    fn test_logging() {
+        let config: Config = toml::from_str(
+            r#"
+            [logging]
+            filter = "mallard_metrics=info,mallard_metrics::ingest=debug,tower_http=warn"
+            ingest_sample_every = 100
+            slow_query_ms = 250
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.logging.ingest_sample_every, 100);
+        assert_eq!(config.logging.slow_query_ms, 250);
+        assert_eq!(config.logging.slow_query_log_size, 100);
+        assert_eq!(Config::default().logging.filter, default_log_filter());
+
+        let mut invalid = config.clone();
+        invalid.logging.filter = "mallard_metrics=loud".to_string();
+        assert!(invalid.validate().unwrap_err().contains("logging.filter"));
+        let mut invalid = config.clone();
+        invalid.logging.ingest_sample_every = 0;
+        assert!(invalid
+            .validate()
+            .unwrap_err()
+            .contains("logging.ingest_sample_every"));
+        let mut invalid = config;
+        invalid.logging.slow_query_log_size = 0;
+        assert!(invalid
+            .validate()
+            .unwrap_err()
+            .contains("logging.slow_query_log_size"));
+    }
+
+    #[test]
+    fn test_shadow_queries() {
+        let config: Config = toml::from_str(
+            r#"
+            [shadow_queries]
+            reports = ["main", "sessions"]
+            variant = "live_sessions"
+            sample_every = 10
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.shadow_queries.reports, ["main", "sessions"]);
+        assert_eq!(config.shadow_queries.sample_every, 10);
+        assert!(Config::default().shadow_queries.reports.is_empty());
+
+        let mut invalid = config.clone();
+        // The variant computes other reports no differently.
+        invalid
+            .shadow_queries
+            .reports
+            .push("breakdown/pages".to_string());
+        assert!(invalid
+            .validate()
+            .unwrap_err()
+            .contains("shadow_queries.reports"));
+        let mut invalid = config;
+        invalid.shadow_queries.sample_every = 0;
+        assert!(invalid
+            .validate()
+            .unwrap_err()
+            .contains("shadow_queries.sample_every"));
+    }
+
+    #[test]
+    fn test_event_bus() {
+        let config: Config = toml::from_str(
+            r#"
+            [event_bus]
+            kind = "kafka"
+            servers = "kafka:9092"
+            topic = "events"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.event_bus.group, "mallard-metrics");
+        assert_eq!(config.validate().is_ok(), cfg!(feature = "kafka"));
+        assert!(Config::default().event_bus.kind.is_empty());
+
+        let mut invalid = config.clone();
+        invalid.event_bus.kind = "rabbitmq".to_string();
+        assert!(invalid.validate().unwrap_err().contains("event_bus.kind"));
+        let mut invalid = config;
+        invalid.event_bus.kind = "nats".to_string();
+        invalid.event_bus.topic = String::new();
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn test_forwarding() {
+        let config: Config = toml::from_str(
+            r#"
+            [forwarding]
+            sink = "http"
+            url = "https://collector.example.com/events"
+            headers = { Authorization = "Bearer secret" }
+            events = ["signup"]
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.forwarding.batch_size, 500);
+        assert_eq!(config.forwarding.batch_interval_secs, 5);
+        assert_eq!(config.forwarding.max_retries, 5);
+        assert_eq!(config.forwarding.region, "us-east-1");
+        assert_eq!(config.forwarding.headers["Authorization"], "Bearer secret");
+        assert!(Config::default().forwarding.sink.is_empty());
+
+        let mut invalid = config.clone();
+        invalid.forwarding.url = "collector.example.com".to_string();
+        assert!(invalid.validate().unwrap_err().contains("forwarding.url"));
+        let mut invalid = config.clone();
+        invalid.forwarding.sink = "ftp".to_string();
+        assert!(invalid.validate().unwrap_err().contains("forwarding.sink"));
+        let mut invalid = config;
+        invalid.forwarding.batch_size = 0;
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn test_notifications() {
+        let config: Config = toml::from_str(
+            r#"
+            [notifications.team]
+            kind = "slack"
+            url = "https://hooks.slack.com/services/T000/B000/XXXX"
+            goals = ["event:signup"]
+            templates = { anomaly = "{site_id}: {visitors} visitors" }
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        let team = &config.notifications["team"];
+        assert_eq!(team.events, ["anomaly", "goal", "digest", "flush"]);
+        assert!(team.sites.is_empty());
+
+        let invalid = |edit: fn(&mut NotificationChannel)| {
+            let mut config = config.clone();
+            edit(config.notifications.get_mut("team").unwrap());
+            config.validate().unwrap_err()
+        };
+        assert!(invalid(|c| c.kind = "teams".to_string()).contains("notifications.\"team\""));
+        assert!(invalid(|c| c.url = "http://example.com".to_string()).contains("https://"));
+        assert!(invalid(|c| c.events = vec!["signup".to_string()]).contains("unknown event"));
+        assert!(invalid(|c| c.goals = vec!["signup".to_string()]).contains("invalid goal"));
+        assert!(invalid(|c| {
+            c.templates.insert("weekly".to_string(), String::new());
+        })
+        .contains("template"));
+
+        let config = Config {
+            digest_slack_webhook: Some("https://hooks.slack.com/services/T0/B0/x".to_string()),
+            ..config
+        };
+        let channels = config.notification_channels();
+        assert_eq!(channels[0].0, "team");
+        assert_eq!(channels[1].0, "digest_slack_webhook");
+        assert_eq!(channels[1].1.events, ["digest"]);
+    }
+
+    #[test]
+    fn test_branding() {
+        let config: Config = toml::from_str(
+            r##"
+            [branding]
+            product_name = "Acme Analytics"
+            logo_url = "https://cdn.acme.example/img/logo.svg"
+            accent_color = "#E4572E"
+            secondary_color = "#333"
+            "##,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.branding.logo_origin(),
+            Some("https://cdn.acme.example")
+        );
+
+        let branding = |logo_url: &str, accent_color: &str| Config {
+            branding: BrandingConfig {
+                logo_url: Some(logo_url.to_string()),
+                accent_color: Some(accent_color.to_string()),
+                ..BrandingConfig::default()
+            },
+            ..Config::default()
+        };
+        let local = branding("/logo.png", "#fff");
+        assert!(local.validate().is_ok());
+        assert_eq!(local.branding.logo_origin(), None);
+        for (logo_url, accent_color, field) in [
+            ("http://cdn.acme.example/logo.svg", "#fff", "logo_url"),
+            ("//cdn.acme.example/logo.svg", "#fff", "logo_url"),
+            ("https://a.example; script-src *", "#fff", "logo_url"),
+            ("/logo.png", "red", "accent_color"),
+            ("/logo.png", "#12345", "accent_color"),
+        ] {
+            let err = branding(logo_url, accent_color).validate().unwrap_err();
+            assert!(err.contains(field), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_validate_rate_limit_burst() {
+        let mut config = Config {
+            rate_limit_per_site: 10,
+            rate_limit_burst: 5,
+            ..Config::default()
+        };
+        assert!(config.validate().unwrap_err().contains("rate_limit_burst"));
+        config.rate_limit_burst = 50;
+        assert!(config.validate().is_ok());
+
+        config.sites.insert(
+            "example.com".to_string(),
+            SiteConfig {
+                rate_limit: Some(100),
+                rate_limit_burst: Some(20),
+                ..SiteConfig::default()
+            },
+        );
+        assert!(config.validate().unwrap_err().contains("example.com"));
+    }
+
+    #[test]
+    fn test_validate_duplicate_custom_dimension() {
+        let dim = CustomDimension {
+            name: "plan".to_string(),
+            kind: DimensionType::String,
+        };
+        let mut config = Config::default();
+        config.sites.insert(
+            "example.com".to_string(),
+            SiteConfig {
+                custom_dimensions: vec![dim.clone(), dim],
+                ..SiteConfig::default()
+            },
+        );
+        let err = config.validate().unwrap_err();
+        assert!(err.contains("duplicate"));
+    }
+
     #[test]
     fn test_secure_cookies_flag_overrides_http_origin() {
         // This test existed before; keep it to verify secure_cookies still works.
@@ -45,9 +45,9 @@ function TimeseriesChart({ data }) {
           `;
         })}
         <!-- Visitors line -->
-        <polyline fill="none" stroke="#4a90d9" stroke-width="2" points=${points('visitors')} />
+        <polyline class="visitors-stroke" fill="none" stroke-width="2" points=${points('visitors')} />
         <!-- Pageviews line -->
-        <polyline fill="none" stroke="#50c878" stroke-width="2" stroke-dasharray="4,2" points=${points('pageviews')} />
+        <polyline class="pageviews-stroke" fill="none" stroke-width="2" stroke-dasharray="4,2" points=${points('pageviews')} />
         <!-- X labels -->
         ${data.map((d, i) => {
           if (i % labelStep !== 0 && i !== data.length - 1) return null;
@@ -64,8 +64,17 @@ function TimeseriesChart({ data }) {
   `;
 }
 
+// Visitors change from the previous period; null for values new in this one.
+function formatChange(change) {
+  if (change === null || change === undefined) return 'new';
+  return `${change > 0 ? '▲' : change < 0 ? '▼' : ''}${Math.abs(change).toFixed(0)}%`;
+}
+
 function BreakdownTable({ title, data }) {
-  if (!data || data.length === 0) {
+  const rows = data && data.rows ? data.rows : [];
+  const totalPageviews = data && data.total ? data.total.pageviews : 0;
+  const compared = rows.some(row => 'previous_visitors' in row);
+  if (rows.length === 0) {
     return html`
       <div class="breakdown-card">
         <h3>${title}</h3>
@@ -83,14 +92,18 @@ function BreakdownTable({ title, data }) {
             <th>${title}</th>
             <th>Visitors</th>
             <th>Pageviews</th>
+            <th>%</th>
+            ${compared && html`<th>Change</th>`}
           </tr>
         </thead>
         <tbody>
-          ${data.map(row => html`
+          ${rows.map(row => html`
             <tr>
-              <td>${row.value || '(unknown)'}</td>
+              <td>${row.name ? `${row.flag || ''} ${row.name}`.trim() : row.value || '(unknown)'}</td>
               <td>${row.visitors}</td>
               <td>${row.pageviews}</td>
+              <td>${totalPageviews ? `${(100 * row.pageviews / totalPageviews).toFixed(1)}%` : '-'}</td>
+              ${compared && html`<td>${formatChange(row.change)}</td>`}
             </tr>
           `)}
         </tbody>
@@ -163,8 +176,8 @@ function RetentionGrid({ data }) {
           ${data.map(row => html`
             <tr>
               <td>${row.cohort_date}</td>
-              ${row.retained.map(r => html`
-                <td class=${r ? 'retained-yes' : 'retained-no'}>${r ? 'Y' : '-'}</td>
+              ${row.retained.map((r, i) => html`
+                <td class=${r ? 'retained-yes' : 'retained-no'}>${row.percentages ? `${row.percentages[i]}%` : (r ? 'Y' : '-')}</td>
               `)}
             </tr>
           `)}
@@ -216,9 +229,29 @@ function FlowTable({ data }) {
   `;
 }
 
+// --- Branding ---
+
+const DEFAULT_BRANDING = { product_name: 'Mallard Metrics', logo_url: null, accent_color: null, secondary_color: null };
+
+function applyBranding(branding) {
+  document.title = branding.product_name;
+  const root = document.documentElement.style;
+  if (branding.accent_color) root.setProperty('--accent', branding.accent_color);
+  if (branding.secondary_color) root.setProperty('--secondary', branding.secondary_color);
+}
+
+function Brand({ branding }) {
+  return html`
+    <h1 class="brand">
+      ${branding.logo_url && html`<img class="brand-logo" src=${branding.logo_url} alt="" />`}
+      ${branding.product_name}
+    </h1>
+  `;
+}
+
 // --- Authentication components ---
 
-function LoginForm({ onLogin, setupRequired }) {
+function LoginForm({ onLogin, setupRequired, branding }) {
   const handleSubmit = async (e) => {
     e.preventDefault();
     const password = e.target.elements.password.value;
@@ -249,7 +282,7 @@ function LoginForm({ onLogin, setupRequired }) {
   return html`
     <div class="auth-overlay">
       <div class="auth-card">
-        <h1>Mallard Metrics</h1>
+        <${Brand} branding=${branding} />
         <h2>${title}</h2>
         <p class="auth-hint">${hint}</p>
         <form onSubmit=${handleSubmit}>
@@ -283,7 +316,7 @@ class Dashboard extends Component {
       sequences: null,
       flow: null,
       period: '30d',
-      siteId: '',
+      siteId: new URLSearchParams(window.location.search).get('site_id') || '',
       loading: false,
       error: null,
       funnelSteps: 'page:/,page:/pricing,event:signup',
@@ -292,24 +325,30 @@ class Dashboard extends Component {
     };
   }
 
+  componentDidMount() {
+    if (this.state.siteId) this.fetchMetrics();
+  }
+
   async fetchMetrics() {
     const { siteId, period, funnelSteps, sequenceSteps, flowPage } = this.state;
     if (!siteId) return;
 
     this.setState({ loading: true, error: null });
     const qs = `site_id=${encodeURIComponent(siteId)}&period=${period}`;
+    // Breakdowns fold their long tail into an "(other)" row with totals.
+    const bqs = `${qs}&others=true`;
 
     try {
       const [mainRes, tsRes, pagesRes, sourcesRes, browsersRes, osRes, devicesRes, countriesRes, sessionsRes] =
         await Promise.all([
           fetch(`/api/stats/main?${qs}`),
           fetch(`/api/stats/timeseries?${qs}`),
-          fetch(`/api/stats/breakdown/pages?${qs}`),
-          fetch(`/api/stats/breakdown/sources?${qs}`),
-          fetch(`/api/stats/breakdown/browsers?${qs}`),
-          fetch(`/api/stats/breakdown/os?${qs}`),
-          fetch(`/api/stats/breakdown/devices?${qs}`),
-          fetch(`/api/stats/breakdown/countries?${qs}`),
+          fetch(`/api/stats/breakdown/pages?${bqs}&compare=true`),
+          fetch(`/api/stats/breakdown/sources?${bqs}`),
+          fetch(`/api/stats/breakdown/browsers?${bqs}`),
+          fetch(`/api/stats/breakdown/os?${bqs}`),
+          fetch(`/api/stats/breakdown/devices?${bqs}`),
+          fetch(`/api/stats/breakdown/countries?${bqs}`),
           fetch(`/api/stats/sessions?${qs}`),
         ]);
 
@@ -323,12 +362,12 @@ class Dashboard extends Component {
       const metrics = await mainRes.json();
       const timeseries = tsRes.ok ? await tsRes.json() : [];
       const breakdowns = {
-        pages: pagesRes.ok ? await pagesRes.json() : [],
-        sources: sourcesRes.ok ? await sourcesRes.json() : [],
-        browsers: browsersRes.ok ? await browsersRes.json() : [],
-        os: osRes.ok ? await osRes.json() : [],
-        devices: devicesRes.ok ? await devicesRes.json() : [],
-        countries: countriesRes.ok ? await countriesRes.json() : [],
+        pages: pagesRes.ok ? await pagesRes.json() : null,
+        sources: sourcesRes.ok ? await sourcesRes.json() : null,
+        browsers: browsersRes.ok ? await browsersRes.json() : null,
+        os: osRes.ok ? await osRes.json() : null,
+        devices: devicesRes.ok ? await devicesRes.json() : null,
+        countries: countriesRes.ok ? await countriesRes.json() : null,
       };
       const sessions = sessionsRes.ok ? await sessionsRes.json() : null;
 
@@ -358,7 +397,7 @@ class Dashboard extends Component {
     return html`
       <div class="dashboard">
         <header>
-          <h1>Mallard Metrics</h1>
+          <${Brand} branding=${this.props.branding} />
           <div class="controls">
             <input
               type="text"
@@ -503,13 +542,27 @@ class App extends Component {
       authChecked: false,
       authenticated: false,
       setupRequired: false,
+      branding: DEFAULT_BRANDING,
     };
   }
 
   async componentDidMount() {
+    this.loadBranding();
     await this.checkAuth();
   }
 
+  async loadBranding() {
+    try {
+      const res = await fetch('/api/meta/branding');
+      if (!res.ok) return;
+      const branding = await res.json();
+      applyBranding(branding);
+      this.setState({ branding });
+    } catch (_) {
+      // Keep the default branding
+    }
+  }
+
   async checkAuth() {
     try {
       const res = await fetch('/api/auth/status');
@@ -536,7 +589,7 @@ class App extends Component {
   }
 
   render() {
-    const { authChecked, authenticated, setupRequired } = this.state;
+    const { authChecked, authenticated, setupRequired, branding } = this.state;
 
     if (!authChecked) {
       return html`<div class="loading-screen">Loading...</div>`;
@@ -546,6 +599,7 @@ class App extends Component {
       return html`
         <${LoginForm}
           setupRequired=${setupRequired}
+          branding=${branding}
           onLogin=${() => this.handleLogin()}
         />
       `;
@@ -555,6 +609,7 @@ class App extends Component {
     const showLogout = !setupRequired; // only show logout when password is configured
     return html`
       <${Dashboard}
+        branding=${branding}
         onLogout=${showLogout ? () => this.handleLogout() : null}
         onAuthExpired=${() => this.setState({ authenticated: false })}
       />
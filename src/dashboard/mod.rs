@@ -1,34 +1,129 @@
+use crate::ingest::handler::AppState;
+use axum::extract::State;
 use axum::http::{header, StatusCode};
-use axum::response::IntoResponse;
+use axum::response::{IntoResponse, Response};
 use rust_embed::Embed;
+use std::borrow::Cow;
+use std::path::Path;
+use std::sync::Arc;
 
 #[derive(Embed)]
 #[folder = "src/dashboard/assets/"]
 struct Assets;
 
-/// Serve embedded static files for the dashboard SPA.
+/// Serve static files for the dashboard SPA.
 pub async fn serve_asset(
+    State(state): State<Arc<AppState>>,
     axum::extract::Path(path): axum::extract::Path<String>,
 ) -> impl IntoResponse {
-    serve_file(&path)
+    serve_file(state.dashboard_dir.as_deref(), &path).await
 }
 
 /// Serve the index.html for the root path.
-pub async fn serve_index() -> impl IntoResponse {
-    serve_file("index.html")
+pub async fn serve_index(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    serve_file(state.dashboard_dir.as_deref(), "index.html").await
 }
 
-fn serve_file(path: &str) -> impl IntoResponse {
-    match Assets::get(path) {
-        Some(content) => {
+/// Serve `path` from `dashboard_dir` if it is there, else from the embedded
+/// assets.
+async fn serve_file(dashboard_dir: Option<&Path>, path: &str) -> Response {
+    let custom = match dashboard_dir {
+        Some(dir) => read_from_dir(dir, path).await,
+        None => None,
+    };
+    let content = custom
+        .map(Cow::Owned)
+        .or_else(|| Assets::get(path).map(|file| file.data));
+    content.map_or_else(
+        || StatusCode::NOT_FOUND.into_response(),
+        |content| {
             let mime = mime_guess::from_path(path).first_or_octet_stream();
             (
                 StatusCode::OK,
                 [(header::CONTENT_TYPE, mime.as_ref().to_string())],
-                content.data.to_vec(),
+                content.into_owned(),
             )
                 .into_response()
+        },
+    )
+}
+
+/// Read `path` from `dir`, refusing anything that could resolve outside it:
+/// empty, hidden or `..` components, backslashes, and symlinks leading out
+/// of the directory.
+async fn read_from_dir(dir: &Path, path: &str) -> Option<Vec<u8>> {
+    let safe = path.split('/').all(|component| {
+        !component.is_empty()
+            && !component.starts_with('.')
+            && !component.contains(['\\', '\0', ':'])
+    });
+    if !safe {
+        return None;
+    }
+    let root = tokio::fs::canonicalize(dir).await.ok()?;
+    let file = tokio::fs::canonicalize(root.join(path)).await.ok()?;
+    if !file.starts_with(&root) || !tokio::fs::metadata(&file).await.ok()?.is_file() {
+        return None;
+    }
+    tokio::fs::read(file).await.ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[tokio::test]
+    async fn test_read_from_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("dashboard");
+        fs::create_dir_all(root.join("assets")).unwrap();
+        fs::write(root.join("index.html"), "custom").unwrap();
+        fs::write(root.join("assets/app.js"), "js").unwrap();
+        fs::write(root.join(".env"), "secret").unwrap();
+        fs::write(dir.path().join("outside.txt"), "outside").unwrap();
+
+        assert_eq!(read_from_dir(&root, "index.html").await.unwrap(), b"custom");
+        assert_eq!(read_from_dir(&root, "assets/app.js").await.unwrap(), b"js");
+        for path in [
+            "",
+            "missing.js",
+            "assets",
+            ".env",
+            "../outside.txt",
+            "assets/../../outside.txt",
+            "assets//app.js",
+            "assets\\app.js",
+            "/etc/passwd",
+        ] {
+            assert_eq!(read_from_dir(&root, path).await, None, "{path}");
         }
-        None => StatusCode::NOT_FOUND.into_response(),
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_read_from_dir_rejects_escaping_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("dashboard");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(dir.path().join("outside.txt"), "outside").unwrap();
+        std::os::unix::fs::symlink(dir.path().join("outside.txt"), root.join("link.txt")).unwrap();
+        assert_eq!(read_from_dir(&root, "link.txt").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_serve_file_falls_back_to_embedded() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("index.html"), "custom").unwrap();
+        let custom = serve_file(Some(dir.path()), "index.html").await;
+        assert_eq!(custom.status(), StatusCode::OK);
+        assert_eq!(
+            serve_file(Some(dir.path()), "app.js").await.status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            serve_file(Some(dir.path()), "missing.js").await.status(),
+            StatusCode::NOT_FOUND
+        );
     }
 }
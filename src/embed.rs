@@ -0,0 +1,1271 @@
+//! Running Mallard Metrics inside another Rust service.
+//!
+//! [`MallardServer::builder`] opens the database, starts the ingest workers
+//! and the background tasks, and returns the router for the host service to
+//! serve on a listener of its own or merge into its router.  The `serve`
+//! command starts its server the same way.
+//!
+//! Each server keeps its state, including its slow query log and shadow
+//! query counters, in its own [`AppState`], so several can run in one
+//! process.  Only one process can open the database in `data_dir` at a time,
+//! and the routes expect to be served from the root path: the tracking
+//! script posts to `/api/event` and the dashboard calls `/api/*`.
+
+use crate::api::auth::{ApiKeyStore, SessionStore};
+use crate::config::Config;
+use crate::ingest::buffer::{BufferError, EventBuffer};
+use crate::ingest::geoip::GeoIpReader;
+use crate::ingest::handler::AppState;
+use crate::{loadgen, storage};
+use axum::Router;
+use duckdb::Connection;
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Why a server could not start.
+#[derive(Debug, thiserror::Error)]
+pub enum StartError {
+    #[error("Configuration error: {0}")]
+    Config(String),
+    #[error("Failed to create data directory: {0}")]
+    DataDir(#[source] std::io::Error),
+    #[error("Failed to open DuckDB: {0}")]
+    Database(#[source] duckdb::Error),
+    #[error("Failed to run migrations: {0}")]
+    Migrations(#[source] duckdb::Error),
+    #[error("Failed to hash admin password: {0}")]
+    PasswordHash(argon2::password_hash::Error),
+    #[error("Failed to start event forwarding: {0}")]
+    Forwarding(String),
+}
+
+/// Builder of a [`MallardServer`].
+#[derive(Default)]
+pub struct MallardServerBuilder {
+    config: Config,
+    database_wait: Duration,
+    seed_demo_data: bool,
+}
+
+impl MallardServerBuilder {
+    /// The configuration to run with; [`Config::default`] when not set.
+    #[must_use]
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Keep retrying to open the database for up to `wait` while another
+    /// process holds it, as a server being upgraded does until it exits.
+    /// Default: fail at once.
+    #[must_use]
+    pub const fn database_wait(mut self, wait: Duration) -> Self {
+        self.database_wait = wait;
+        self
+    }
+
+    /// Write the demo site's sample traffic unless it has events already.
+    #[must_use]
+    pub const fn seed_demo_data(mut self, seed: bool) -> Self {
+        self.seed_demo_data = seed;
+        self
+    }
+
+    /// Validate the configuration, open the database, and start the ingest
+    /// workers and background tasks on the current Tokio runtime.
+    #[allow(clippy::too_many_lines)]
+    pub async fn build(self) -> Result<MallardServer, StartError> {
+        let config = self.config;
+        config.validate().map_err(StartError::Config)?;
+        std::fs::create_dir_all(config.events_dir()).map_err(StartError::DataDir)?;
+
+        // Initialize DuckDB using a disk-based file so that events buffered in the
+        // `events` table (not yet flushed to Parquet) survive a process crash.
+        // The WAL file written next to mallard.duckdb provides atomic batch inserts.
+        let conn = crate::upgrade::open_database(&config.db_path(), self.database_wait)
+            .await
+            .map_err(StartError::Database)?;
+        if let Err(e) = storage::resources::apply_limits(
+            &conn,
+            config.duckdb_memory_limit.as_deref(),
+            config.duckdb_threads,
+        ) {
+            tracing::warn!(error = %e, "Could not set DuckDB memory and thread limits");
+        }
+        if let Err(e) = storage::resources::apply_temp_directory(
+            &conn,
+            &config.duckdb_temp_dir(),
+            config.duckdb_max_temp_size.as_deref(),
+        ) {
+            tracing::warn!(error = %e, "Could not set the DuckDB temp directory");
+        }
+        storage::migrations::run_migrations(&conn).map_err(StartError::Migrations)?;
+
+        // A crash between writing a Parquet file and deleting its events from the
+        // table leaves them in both tiers, where events_all would count them
+        // twice.  Remove such files before the view picks them up.
+        match config.parquet_storage().reconcile_flushes(&conn) {
+            Ok(0) => {}
+            Ok(removed) => {
+                tracing::warn!(removed, "Removed Parquet files left by interrupted flushes");
+            }
+            Err(e) => tracing::warn!(error = %e, "Could not reconcile interrupted flushes"),
+        }
+
+        // Try to load the behavioral extension (non-fatal if unavailable)
+        let behavioral_extension_loaded = match storage::schema::load_behavioral_extension(&conn) {
+            Ok(()) => {
+                tracing::info!("Behavioral extension loaded");
+                true
+            }
+            Err(e) => {
+                tracing::warn!(
+                    error = %e,
+                    "Behavioral extension not available; using SQL fallbacks for sessions, funnels, sequences and flow"
+                );
+                false
+            }
+        };
+
+        // Create the events_all view that unions the hot events table with persisted
+        // Parquet files on disk.  This makes historical data queryable immediately,
+        // including data written by previous server runs.  Non-fatal: if no Parquet
+        // files exist yet the view falls back to a passthrough over the events table.
+        match storage::schema::setup_query_view(&conn, &config.events_dir()) {
+            Ok(()) => tracing::info!("Query view initialised"),
+            Err(e) => {
+                tracing::warn!(error = %e, "Could not create events_all view; queries limited to buffered events");
+            }
+        }
+
+        // Site groups from the registry, used by `site_id=@<group>` stats queries.
+        let site_groups = config
+            .sites
+            .iter()
+            .filter_map(|(site_id, site)| Some((site_id.as_str(), site.group.as_deref()?)));
+        if let Err(e) = storage::schema::sync_site_groups(&conn, site_groups) {
+            tracing::warn!(error = %e, "Could not load site groups; group stats queries will be empty");
+        }
+
+        if let Ok(usage) = storage::resources::read_usage(&conn) {
+            tracing::info!(
+                memory_limit = %usage.memory_limit,
+                threads = usage.threads,
+                temp_directory = %usage.temp_directory,
+                temp_directory_free_bytes = usage.temp_directory_free_bytes,
+                "DuckDB limits set"
+            );
+        }
+        let conn = Arc::new(Mutex::new(conn));
+        let storage = config.parquet_storage();
+        let disk = Arc::new(storage::resources::DiskGuard::new(
+            &config.data_dir,
+            config.min_free_disk_mb.saturating_mul(1024 * 1024),
+        ));
+        let _ = disk.check();
+        let buffer = EventBuffer::new(config.flush_event_count, Arc::clone(&conn), storage)
+            .with_capacity(
+                config.max_buffered_events,
+                crate::ingest::queue::OverloadPolicy::from_name(&config.ingest_overload)
+                    .unwrap_or_default(),
+            )
+            .with_disk_guard(disk);
+        if self.seed_demo_data {
+            seed_demo_data(&buffer);
+        }
+
+        if let Some(dir) = &config.dashboard_dir {
+            if dir.is_dir() {
+                tracing::info!(path = %dir.display(), "Serving dashboard files from directory");
+            } else {
+                tracing::warn!(path = %dir.display(), "dashboard_dir is not a directory; serving the embedded dashboard");
+            }
+        }
+
+        // Initialize GeoIP reader (gracefully degrades if .mmdb not available)
+        let geoip = GeoIpReader::open(config.geoip_db_path.as_deref());
+
+        let state = build_app_state(&config, buffer, geoip, behavioral_extension_loaded)?;
+        if let Some(queue) = &state.ingest_queue {
+            queue.spawn_workers(&state, config.ingest_workers);
+            tracing::info!(
+                workers = config.ingest_workers,
+                queue_size = config.ingest_queue_size,
+                overload = %config.ingest_overload,
+                "Ingest workers started"
+            );
+        }
+
+        // Spawn background tasks
+        spawn_background_tasks(&config, &conn, &state);
+        crate::ingest::bus::spawn(&state, &config.event_bus);
+
+        Ok(MallardServer {
+            router: crate::server::build_router(Arc::clone(&state)),
+            state,
+            shutdown_timeout: Duration::from_secs(config.shutdown_timeout_secs.max(1)),
+        })
+    }
+}
+
+/// A running analytics server: its router, state and background tasks.
+#[derive(Clone)]
+pub struct MallardServer {
+    router: Router,
+    state: Arc<AppState>,
+    shutdown_timeout: Duration,
+}
+
+impl MallardServer {
+    /// Start configuring a server.
+    pub fn builder() -> MallardServerBuilder {
+        MallardServerBuilder::default()
+    }
+
+    /// Every route of the server, with its middleware.
+    pub fn router(&self) -> Router {
+        self.router.clone()
+    }
+
+    /// The shared state; `state().tasks` holds the background tasks and
+    /// `state().drain` the drain mode.
+    pub const fn state(&self) -> &Arc<AppState> {
+        &self.state
+    }
+
+    /// Start draining, stop the background tasks and flush buffered events,
+    /// including those still in the ingest queue, bounded by
+    /// `shutdown_timeout_secs`.
+    ///
+    /// Requests still in flight may buffer events afterwards; call again
+    /// once the router stopped serving to flush those too.
+    pub async fn shutdown(&self) {
+        self.state.drain.start();
+        self.state.tasks.abort_all();
+
+        let flush = tokio::task::spawn_blocking({
+            let state = Arc::clone(&self.state);
+            move || {
+                if let Some(queue) = &state.ingest_queue {
+                    queue.wait_idle();
+                }
+                state.buffer.flush()
+            }
+        });
+        match tokio::time::timeout(self.shutdown_timeout, flush).await {
+            Ok(Ok(Ok(count))) if count > 0 => {
+                tracing::info!(count, "Flushed remaining events during shutdown");
+            }
+            Ok(Ok(Ok(_))) => {}
+            Ok(Ok(Err(e))) => {
+                tracing::error!(error = %e, "Failed to flush events during shutdown");
+            }
+            Ok(Err(e)) => {
+                tracing::error!(error = %e, "Flush task panicked during shutdown");
+            }
+            Err(_) => {
+                tracing::warn!(
+                    timeout_secs = self.shutdown_timeout.as_secs(),
+                    "Graceful shutdown flush timed out; some buffered events may be lost"
+                );
+            }
+        }
+    }
+}
+
+/// Write the demo site's sample traffic, unless it already has events from
+/// an earlier start.
+fn seed_demo_data(buffer: &EventBuffer) {
+    let seeded = buffer.conn().lock().query_row(
+        "SELECT COUNT(*) > 0 FROM events_all WHERE site_id = ?",
+        [loadgen::DEMO_SITE_ID],
+        |row| row.get::<_, bool>(0),
+    );
+    match seeded {
+        Ok(true) => {
+            tracing::info!(
+                site_id = loadgen::DEMO_SITE_ID,
+                "Demo site already has data"
+            );
+        }
+        Ok(false) => match loadgen::seed_demo(buffer) {
+            Ok(events) => tracing::info!(
+                site_id = loadgen::DEMO_SITE_ID,
+                events,
+                "Seeded demo data; open the dashboard at /?site_id={}",
+                loadgen::DEMO_SITE_ID
+            ),
+            Err(e) => tracing::warn!(error = %e, "Could not seed demo data"),
+        },
+        Err(e) => tracing::warn!(error = %e, "Could not check for demo data"),
+    }
+}
+
+#[allow(clippy::too_many_lines)]
+fn build_app_state(
+    config: &Config,
+    buffer: EventBuffer,
+    geoip: GeoIpReader,
+    behavioral_extension_loaded: bool,
+) -> Result<Arc<AppState>, StartError> {
+    let sessions = SessionStore::new(config.session_ttl_secs);
+    // Load API keys from disk so they survive server restarts.  Keys are
+    // written back to the same file on every add/revoke operation.
+    let api_keys = ApiKeyStore::load_from_disk(config.api_keys_path());
+    let query_cache =
+        crate::query::cache::QueryCache::new(config.cache_ttl_secs, config.cache_max_entries);
+    let rate_limiter = config.sites.iter().fold(
+        crate::ingest::ratelimit::RateLimiter::new(config.rate_limit_per_site)
+            .with_burst(config.rate_limit_burst),
+        |limiter, (site_id, site)| match (site.rate_limit, site.rate_limit_burst) {
+            (None, None) => limiter,
+            (rate, burst) => limiter.with_site_limit(
+                site_id,
+                rate.unwrap_or(config.rate_limit_per_site),
+                burst.unwrap_or(0),
+            ),
+        },
+    );
+    let login_attempt_tracker = crate::api::auth::LoginAttemptTracker::new(
+        config.max_login_attempts,
+        config.login_lockout_secs,
+    );
+
+    // A pre-hashed password (from `mallard-metrics hash-password`) keeps the
+    // plaintext out of the environment.
+    let admin_password_hash = match (
+        std::env::var("MALLARD_ADMIN_PASSWORD_HASH")
+            .ok()
+            .filter(|h| !h.is_empty()),
+        std::env::var("MALLARD_ADMIN_PASSWORD")
+            .ok()
+            .filter(|p| !p.is_empty()),
+    ) {
+        (Some(h), _) => {
+            if argon2::PasswordHash::new(&h).is_err() {
+                return Err(StartError::Config(
+                    "MALLARD_ADMIN_PASSWORD_HASH is not a valid password hash".to_string(),
+                ));
+            }
+            tracing::info!("Admin password configured from MALLARD_ADMIN_PASSWORD_HASH");
+            Some(h)
+        }
+        (None, Some(p)) => {
+            let hash = crate::api::auth::hash_password(&p).map_err(StartError::PasswordHash)?;
+            tracing::info!("Admin password configured from MALLARD_ADMIN_PASSWORD");
+            Some(hash)
+        }
+        (None, None) => {
+            let path = config.admin_password_path();
+            let hash = crate::api::auth::load_password_hash(&path);
+            if hash.is_some() {
+                tracing::info!(path = %path.display(), "Loaded persisted admin password");
+            }
+            hash
+        }
+    };
+
+    // Load or generate-and-persist the visitor-ID secret.
+    //
+    // MALLARD_SECRET (env var) takes highest priority.  If unset, we look for a
+    // previously-persisted secret at `data_dir/.secret`.  If that file does not
+    // exist we generate a fresh UUID, persist it, and emit an INFO log.
+    //
+    // This prevents the old behaviour where every restart silently generated a
+    // new random secret, permanently corrupting historical visitor deduplication.
+    let secret = std::env::var("MALLARD_SECRET").unwrap_or_else(|_| {
+        let secret_path = config.data_dir.join(".secret");
+        if let Ok(s) = std::fs::read_to_string(&secret_path) {
+            let s = s.trim().to_string();
+            if !s.is_empty() {
+                tracing::info!(path = %secret_path.display(), "Loaded persisted MALLARD_SECRET");
+                return s;
+            }
+        }
+        let secret = uuid::Uuid::new_v4().to_string();
+        match std::fs::write(&secret_path, &secret) {
+            Ok(()) => {
+                tracing::info!(
+                    path = %secret_path.display(),
+                    "Generated and persisted MALLARD_SECRET. \
+                     Set MALLARD_SECRET env var to use a custom value."
+                );
+            }
+            Err(e) => {
+                tracing::warn!(
+                    error = %e,
+                    "Could not persist MALLARD_SECRET to disk. \
+                     Visitor IDs will change on next restart unless MALLARD_SECRET is set."
+                );
+            }
+        }
+        secret
+    });
+
+    let metrics_token = std::env::var("MALLARD_METRICS_TOKEN")
+        .ok()
+        .filter(|t| !t.is_empty());
+    if metrics_token.is_some() {
+        tracing::info!("Metrics endpoint protected by bearer token (MALLARD_METRICS_TOKEN)");
+    }
+
+    // Limit concurrent heavy analytics queries to prevent a tight query loop from
+    // monopolising the single DuckDB connection.  0 in config → unlimited.
+    let max_concurrent = if config.max_concurrent_queries == 0 {
+        usize::MAX
+    } else {
+        config.max_concurrent_queries
+    };
+
+    if config.gdpr_mode {
+        tracing::info!(
+            "GDPR mode enabled: strip_referrer_query, round_timestamps, \
+             suppress_browser_version, suppress_os_version, suppress_screen_size active; \
+             geoip_precision={:?}",
+            config.geoip_precision
+        );
+        if config.retention_days == 0 {
+            tracing::warn!(
+                "GDPR mode is enabled but retention_days is 0 (unlimited). \
+                 Consider setting MALLARD_RETENTION_DAYS=30 for GDPR Art. 5(1)(e) storage limitation."
+            );
+        }
+    }
+
+    let geo_rules = config
+        .sites
+        .values()
+        .any(|site| !site.blocked_countries.is_empty() || !site.blocked_continents.is_empty());
+    if geo_rules && !geoip.is_loaded() {
+        tracing::warn!(
+            "Sites have blocked_countries/blocked_continents rules but no GeoIP database \
+             is loaded; the rules will not match any traffic"
+        );
+    }
+
+    let spam_blocklist = Arc::new(crate::ingest::spam::SpamBlocklist::new(
+        &config.spam_referrers,
+    ));
+    let transforms =
+        crate::ingest::transform::IngestTransforms::from_config(config, &spam_blocklist);
+    if !transforms.names().is_empty() {
+        tracing::info!(transforms = ?transforms.names(), "Ingest transforms registered");
+    }
+    let forwarder = crate::ingest::forward::Forwarder::start(
+        &config.forwarding,
+        config.forwarding_dead_letter_path(),
+    )
+    .map_err(StartError::Forwarding)?;
+    if forwarder.is_some() {
+        tracing::info!(sink = %config.forwarding.sink, "Event forwarding enabled");
+    }
+
+    Ok(Arc::new(AppState {
+        buffer,
+        secret,
+        allowed_sites: config.site_ids.clone(),
+        geoip,
+        filter_bots: config.filter_bots,
+        sessions,
+        api_keys,
+        admin_password_hash: Mutex::new(admin_password_hash),
+        admin_password_path: Some(config.admin_password_path()),
+        locale: crate::api::locale::Locale::from_tag(&config.locale).unwrap_or_default(),
+        branding: config.branding.clone(),
+        csp: config.csp.clone(),
+        security_headers: config.security_headers.clone(),
+        dashboard_origin: config.dashboard_origin.clone(),
+        dashboard_origins: config.dashboard_origins.clone(),
+        dashboard_dir: config.dashboard_dir.clone(),
+        query_cache,
+        cache_warming: std::sync::atomic::AtomicBool::new(false),
+        public_summary_cache: crate::query::cache::QueryCache::new(
+            config.public_summary_cache_secs,
+            config.cache_max_entries,
+        ),
+        slow_queries: Arc::new(crate::query::slow::SlowQueryLog::new(
+            config.logging.slow_query_ms,
+            config.logging.slow_query_log_size,
+        )),
+        duckdb_usage: crate::storage::resources::ResourceMonitor::default(),
+        shadow_queries: crate::query::shadow::ShadowQueries::new(
+            config.shadow_queries.reports.clone(),
+            config.shadow_queries.variant,
+            config.shadow_queries.sample_every,
+        ),
+        rate_limiter,
+        login_attempt_tracker,
+        events_ingested_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        flush_failures_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        flush_consecutive_failures: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        rate_limit_rejections_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        ingest_stats: crate::ingest::stats::IngestStats::new(),
+        login_failures_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        metrics_token,
+        query_semaphore: Arc::new(tokio::sync::Semaphore::new(max_concurrent)),
+        secure_cookies: config.secure_cookies,
+        behavioral_extension_loaded,
+        strip_referrer_query: config.strip_referrer_query,
+        round_timestamps: config.round_timestamps,
+        suppress_visitor_id: config.suppress_visitor_id,
+        suppress_browser_version: config.suppress_browser_version,
+        suppress_os_version: config.suppress_os_version,
+        suppress_screen_size: config.suppress_screen_size,
+        geoip_precision: config.geoip_precision.clone(),
+        events_dir: config.events_dir(),
+        sites: config.sites.clone(),
+        dashboards: crate::api::dashboards::DashboardStore::load_from_disk(
+            config.data_dir.join("dashboards.json"),
+        ),
+        notifications: crate::api::notifications::NotificationStore::load_from_disk(
+            config.notification_channels(),
+            config.data_dir.join("notifications.json"),
+        ),
+        export_jobs: crate::api::export_jobs::ExportJobs::new(
+            config.exports_dir(),
+            std::time::Duration::from_secs(config.export_job_ttl_secs),
+        ),
+        import_jobs: crate::api::import_jobs::ImportJobs::new(
+            config.imports_dir(),
+            std::time::Duration::from_secs(config.import_job_ttl_secs),
+        ),
+        stats_rate_limiter: crate::ingest::ratelimit::RateLimiter::per_minute(
+            config.stats_rate_limit_per_minute,
+        ),
+        stats_rate_limit_rejections_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(
+            0,
+        )),
+        debug_ingest: config.debug_ingest,
+        spam_blocklist,
+        geo_blocked_events_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        transforms,
+        dedupe: crate::ingest::dedupe::Deduplicator::new(std::time::Duration::from_secs(
+            config.dedupe_window_secs,
+        )),
+        max_event_time_skew: chrono::TimeDelta::hours(i64::from(config.max_event_time_skew_hours)),
+        idempotency: crate::ingest::idempotency::IdempotencyKeys::new(
+            std::time::Duration::from_secs(config.idempotency_window_secs),
+        ),
+        duplicate_events_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        ingest_queue: (config.ingest_workers > 0).then(|| {
+            crate::ingest::queue::IngestQueue::new(
+                config.ingest_queue_size,
+                crate::ingest::queue::OverloadPolicy::from_name(&config.ingest_overload)
+                    .unwrap_or_default(),
+            )
+        }),
+        forwarder,
+        tasks: crate::watchdog::TaskRegistry::default(),
+        drain: crate::upgrade::Drain::default(),
+    }))
+}
+
+#[allow(clippy::too_many_lines)]
+fn spawn_background_tasks(config: &Config, conn: &Arc<Mutex<Connection>>, state: &Arc<AppState>) {
+    use std::time::Duration;
+
+    // Each task runs under the watchdog, which restarts it when it gets
+    // stuck, and when it panics in builds that unwind; see `crate::watchdog`.
+    let tasks = &state.tasks;
+
+    // Periodic flush task.
+    //
+    // The flush involves blocking operations: parking_lot::Mutex::lock() (futex
+    // wait under contention) and DuckDB COPY TO Parquet (filesystem I/O).
+    // Running these on a Tokio async worker thread would starve the scheduler.
+    // Instead, we await the interval (non-blocking) then hand the blocking work
+    // to `tokio::task::spawn_blocking`, which runs it on a dedicated thread pool.
+    //
+    // A failed flush is retried with backoff before waiting for the next
+    // interval.  After `flush_alert_after` failed flushes in a row, channels
+    // subscribed to `flush` are alerted, and told again once one succeeds.
+    let flush_conn = Arc::clone(conn);
+    let flush_storage = config.parquet_storage();
+    let flush_failures = Arc::clone(&state.flush_failures_total);
+    let flush_disk = state.buffer.disk_guard().cloned();
+    // Sites whose dashboard queries are cached after each background flush.
+    let warm_sites = if config.cache_ttl_secs > 0 {
+        config.cache_warm_sites
+    } else {
+        0
+    };
+    let warm_state = Arc::downgrade(state);
+    let flush_period = Duration::from_secs(config.flush_interval_secs);
+    let flush_retry = crate::ingest::flush_retry::FlushRetry::new(
+        config.flush_retries,
+        Duration::from_millis(config.flush_retry_base_ms),
+        flush_period,
+        config.flush_alert_after,
+        Arc::clone(&state.flush_consecutive_failures),
+    );
+    let flush_channels = state.notifications.clone();
+    crate::watchdog::supervise(tasks, "flush", flush_period, move |heartbeat| {
+        let flush_conn = Arc::clone(&flush_conn);
+        let flush_storage = flush_storage.clone();
+        let flush_failures = Arc::clone(&flush_failures);
+        let flush_disk = flush_disk.clone();
+        let warm_state = warm_state.clone();
+        let flush_retry = flush_retry.clone();
+        let flush_channels = flush_channels.clone();
+        async move {
+            let client = reqwest::Client::new();
+            let flush = || {
+                let conn = Arc::clone(&flush_conn);
+                let storage = flush_storage.clone();
+                let disk = flush_disk.clone();
+                tokio::task::spawn_blocking(move || {
+                    // Also refreshes the free-space reading while nothing is
+                    // flushed.  The guard logs when space runs low.
+                    if let Some(disk) = &disk {
+                        disk.check()?;
+                    }
+                    let conn_guard = conn.lock();
+                    storage
+                        .flush_events(&conn_guard)
+                        .map_err(BufferError::Flush)
+                })
+            };
+            let mut interval = tokio::time::interval(flush_period);
+            loop {
+                interval.tick().await;
+                heartbeat.beat();
+                let mut result = flush().await;
+                for attempt in 0..flush_retry.retries() {
+                    let error = match &result {
+                        Ok(Ok(_) | Err(BufferError::DiskLow(_))) => break,
+                        Ok(Err(e)) => e.to_string(),
+                        Err(e) => e.to_string(),
+                    };
+                    flush_failures.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    let delay = flush_retry.delay(attempt);
+                    tracing::warn!(
+                        error = %error,
+                        attempt = attempt + 1,
+                        retry_in_ms = delay.as_millis(),
+                        "Periodic flush failed; retrying"
+                    );
+                    tokio::time::sleep(delay).await;
+                    heartbeat.beat();
+                    result = flush().await;
+                }
+                let error = match result {
+                    Ok(Ok(count)) => {
+                        heartbeat.success();
+                        if count > 0 {
+                            tracing::info!(count, "Periodic flush completed");
+                            if let Some(state) = warm_state.upgrade() {
+                                crate::api::warm::spawn(state, warm_sites);
+                            }
+                        }
+                        if let Some(failures) = flush_retry.succeeded() {
+                            tracing::info!(failures, "Periodic flush recovered");
+                            let alert = crate::notify::FlushAlert {
+                                consecutive_failures: failures,
+                                error: None,
+                            };
+                            crate::notify::notify(
+                                &client,
+                                flush_channels.channels(),
+                                &[crate::notify::Notification::Flush(&alert)],
+                            );
+                        }
+                        continue;
+                    }
+                    Ok(Err(BufferError::DiskLow(_))) => continue,
+                    Ok(Err(e)) => {
+                        tracing::error!(error = %e, "Periodic flush failed");
+                        e.to_string()
+                    }
+                    Err(e) => {
+                        tracing::error!(error = %e, "Periodic flush task panicked");
+                        e.to_string()
+                    }
+                };
+                flush_failures.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                if let Some(failures) = flush_retry.failed() {
+                    tracing::error!(
+                        failures,
+                        error = %error,
+                        "Periodic flush keeps failing; alerting notification channels"
+                    );
+                    let alert = crate::notify::FlushAlert {
+                        consecutive_failures: failures,
+                        error: Some(error),
+                    };
+                    crate::notify::notify(
+                        &client,
+                        flush_channels.channels(),
+                        &[crate::notify::Notification::Flush(&alert)],
+                    );
+                }
+            }
+        }
+    });
+
+    // Stale buffer flush.  Sites whose oldest buffered event has waited
+    // `max_buffer_age_secs` are flushed even though `flush_event_count` has
+    // not been reached, so low-traffic sites show up in reports promptly.
+    // Holds the state weakly so the task ends once the server has shut down.
+    if config.max_buffer_age_secs > 0 {
+        let max_age = std::time::Duration::from_secs(config.max_buffer_age_secs);
+        let stale_state = Arc::downgrade(state);
+        let check_period = Duration::from_secs(1);
+        crate::watchdog::supervise(tasks, "stale_flush", check_period, move |heartbeat| {
+            let stale_state = stale_state.clone();
+            async move {
+                let mut interval = tokio::time::interval(check_period);
+                loop {
+                    interval.tick().await;
+                    heartbeat.beat();
+                    let Some(state) = stale_state.upgrade() else {
+                        break;
+                    };
+                    if state.buffer.oldest_age().is_none_or(|age| age < max_age) {
+                        heartbeat.success();
+                        continue;
+                    }
+                    let flush_failures = Arc::clone(&state.flush_failures_total);
+                    let flushed_state = Arc::clone(&state);
+                    let result =
+                        tokio::task::spawn_blocking(move || state.buffer.flush_stale(max_age))
+                            .await;
+                    match result {
+                        Ok(Ok(count)) if count > 0 => {
+                            heartbeat.success();
+                            tracing::debug!(count, "Flushed stale buffered events");
+                            crate::api::warm::spawn(flushed_state, warm_sites);
+                        }
+                        Ok(Ok(_)) => heartbeat.success(),
+                        Ok(Err(BufferError::DiskLow(_))) => {}
+                        Ok(Err(e)) => {
+                            flush_failures.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                            tracing::error!(error = %e, "Stale buffer flush failed");
+                        }
+                        Err(e) => {
+                            flush_failures.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                            tracing::error!(error = %e, "Stale buffer flush task panicked");
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    // Data retention cleanup task (runs daily).
+    //
+    // `apply_retention` calls `std::fs::read_dir` and `std::fs::remove_dir_all`
+    // (blocking syscalls).  Wrapping with `spawn_blocking` matches the flush-task
+    // pattern (L19) and prevents starving the async worker pool under load.
+    let retention_storage = config.parquet_storage();
+    if config.retention_days > 0 || retention_storage.tenants().any_retention() {
+        let retention_days = config.retention_days;
+        let retention_conn = Arc::clone(conn);
+        let retention_cache = state.query_cache.clone();
+        let retention_period = Duration::from_secs(24 * 60 * 60);
+        crate::watchdog::supervise(tasks, "retention", retention_period, move |heartbeat| {
+            let retention_storage = retention_storage.clone();
+            let retention_conn = Arc::clone(&retention_conn);
+            let retention_cache = retention_cache.clone();
+            async move {
+                let mut interval = tokio::time::interval(retention_period);
+                loop {
+                    interval.tick().await;
+                    heartbeat.beat();
+                    let storage = retention_storage.clone();
+                    let conn = Arc::clone(&retention_conn);
+                    let cache = retention_cache.clone();
+                    let result = tokio::task::spawn_blocking(move || {
+                        // Queries wait while partitions are deleted, so none reads
+                        // a file the view still lists, and cached results for the
+                        // affected sites are dropped before the next one runs.
+                        let conn = conn.lock();
+                        let expired =
+                            storage::maintenance::apply_retention(&conn, &storage, retention_days)?;
+                        if !expired.is_empty() {
+                            crate::query::materialized_sessions::prune(&conn)?;
+                            cache.invalidate_sites(
+                                &expired.iter().map(|p| p.site_id.as_str()).collect(),
+                            );
+                        }
+                        drop(conn);
+                        Ok::<_, storage::maintenance::MaintenanceError>(expired.len())
+                    })
+                    .await;
+                    match result {
+                        Ok(Ok(0)) => heartbeat.success(),
+                        Ok(Ok(removed)) => {
+                            heartbeat.success();
+                            tracing::info!(
+                                removed,
+                                retention_days,
+                                "Data retention cleanup completed"
+                            );
+                        }
+                        Ok(Err(e)) => {
+                            tracing::error!(error = %e, "Data retention cleanup failed");
+                        }
+                        Err(e) => {
+                            tracing::error!(error = %e, "Data retention cleanup task panicked");
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    // Referral spam list refresh.  A failed download keeps the previous list.
+    if let Some(url) = config.spam_list_url.clone() {
+        let spam_blocklist = Arc::clone(&state.spam_blocklist);
+        let refresh_period = Duration::from_secs(config.spam_list_refresh_secs);
+        crate::watchdog::supervise(tasks, "spam_list", refresh_period, move |heartbeat| {
+            let url = url.clone();
+            let spam_blocklist = Arc::clone(&spam_blocklist);
+            async move {
+                let mut interval = tokio::time::interval(refresh_period);
+                loop {
+                    interval.tick().await;
+                    heartbeat.beat();
+                    match crate::ingest::spam::fetch_list(&url).await {
+                        Ok(domains) => {
+                            heartbeat.success();
+                            tracing::info!(domains = domains.len(), "Spam referrer list updated");
+                            spam_blocklist.replace_fetched(domains);
+                        }
+                        Err(e) => {
+                            tracing::warn!(
+                                error = %e,
+                                url = %url,
+                                "Failed to update spam referrer list"
+                            );
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    // Hourly anomaly detection.  Each run re-checks the last 24 completed hours;
+    // hours already recorded are skipped, so each anomaly is reported once.
+    if config.anomaly_detection {
+        let anomaly_conn = Arc::clone(conn);
+        let anomaly_slow_queries = Arc::clone(&state.slow_queries);
+        let anomaly_channels = state.notifications.clone();
+        let threshold_pct = config.anomaly_threshold_pct;
+        #[allow(clippy::cast_precision_loss)]
+        let min_baseline = config.anomaly_min_visitors as f64;
+        let anomaly_period = Duration::from_secs(60 * 60);
+        crate::watchdog::supervise(
+            tasks,
+            "anomaly_detection",
+            anomaly_period,
+            move |heartbeat| {
+                let anomaly_conn = Arc::clone(&anomaly_conn);
+                let anomaly_slow_queries = Arc::clone(&anomaly_slow_queries);
+                let anomaly_channels = anomaly_channels.clone();
+                async move {
+                    let client = reqwest::Client::new();
+                    let mut interval = tokio::time::interval(anomaly_period);
+                    loop {
+                        interval.tick().await;
+                        heartbeat.beat();
+                        let conn = Arc::clone(&anomaly_conn);
+                        let slow_queries = Arc::clone(&anomaly_slow_queries);
+                        let result = tokio::task::spawn_blocking(move || {
+                            let now = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
+                            let conn_guard = crate::query::QueryConn::lock(&conn, &slow_queries);
+                            let found = crate::query::anomalies::detect_anomalies(
+                                &conn_guard,
+                                &now,
+                                threshold_pct,
+                                min_baseline,
+                            )?;
+                            let recorded =
+                                crate::query::anomalies::record_anomalies(&conn_guard, &found);
+                            drop(conn_guard);
+                            recorded
+                        })
+                        .await;
+                        match result {
+                            Ok(Ok(new)) => {
+                                heartbeat.success();
+                                for a in &new {
+                                    tracing::warn!(
+                                        site_id = %a.site_id,
+                                        hour = %a.hour,
+                                        visitors = a.visitors,
+                                        baseline = a.baseline,
+                                        deviation_pct = a.deviation_pct,
+                                        "Traffic anomaly detected"
+                                    );
+                                }
+                                let notifications: Vec<_> = new
+                                    .iter()
+                                    .map(crate::notify::Notification::Anomaly)
+                                    .collect();
+                                crate::notify::notify(
+                                    &client,
+                                    anomaly_channels.channels(),
+                                    &notifications,
+                                );
+                            }
+                            Ok(Err(e)) => {
+                                tracing::error!(error = %e, "Anomaly detection failed");
+                            }
+                            Err(e) => {
+                                tracing::error!(error = %e, "Anomaly detection task panicked");
+                            }
+                        }
+                    }
+                }
+            },
+        );
+    }
+
+    // Hourly identity stitching.  The first run maps all stored events; later
+    // runs only the last two days, since visitor IDs rotate daily.
+    if config.identity_stitching {
+        let stitch_conn = Arc::clone(conn);
+        let retention_days = config.retention_days;
+        let stitch_period = Duration::from_secs(60 * 60);
+        crate::watchdog::supervise(
+            tasks,
+            "identity_stitching",
+            stitch_period,
+            move |heartbeat| {
+                let stitch_conn = Arc::clone(&stitch_conn);
+                async move {
+                    let mut interval = tokio::time::interval(stitch_period);
+                    let mut since = "1970-01-01 00:00:00".to_string();
+                    loop {
+                        interval.tick().await;
+                        heartbeat.beat();
+                        let conn = Arc::clone(&stitch_conn);
+                        let now = chrono::Utc::now().naive_utc();
+                        let from = since.clone();
+                        let result = tokio::task::spawn_blocking(move || {
+                            let conn_guard = conn.lock();
+                            let stitched =
+                                crate::query::stitching::stitch_visitors(&conn_guard, &from)?;
+                            if retention_days > 0 {
+                                let cutoff =
+                                    now - chrono::Duration::days(i64::from(retention_days));
+                                crate::query::stitching::prune_visitor_users(
+                                    &conn_guard,
+                                    &cutoff.format("%Y-%m-%d %H:%M:%S").to_string(),
+                                )?;
+                            }
+                            drop(conn_guard);
+                            Ok::<_, duckdb::Error>(stitched)
+                        })
+                        .await;
+                        match result {
+                            Ok(Ok(stitched)) => {
+                                heartbeat.success();
+                                tracing::debug!(stitched, "Identity stitching completed");
+                                since = (now - chrono::Duration::days(2))
+                                    .format("%Y-%m-%d %H:%M:%S")
+                                    .to_string();
+                            }
+                            Ok(Err(e)) => {
+                                tracing::error!(error = %e, "Identity stitching failed");
+                            }
+                            Err(e) => {
+                                tracing::error!(error = %e, "Identity stitching task panicked");
+                            }
+                        }
+                    }
+                }
+            },
+        );
+    }
+
+    // Session materialization.  Each run rebuilds the session files of the
+    // days whose flushed events changed since the last, a few days per hold
+    // of the connection so flushes and queries are not held up by a
+    // backfill.  The first run materializes every stored day.
+    if config.materialize_sessions {
+        let sessions_conn = Arc::clone(conn);
+        let sessions_dir = config.sessions_dir();
+        let run_period = Duration::from_secs(config.flush_interval_secs);
+        crate::watchdog::supervise(
+            tasks,
+            "materialize_sessions",
+            run_period,
+            move |heartbeat| {
+                let sessions_conn = Arc::clone(&sessions_conn);
+                let sessions_dir = sessions_dir.clone();
+                async move {
+                    let mut interval = tokio::time::interval(run_period);
+                    loop {
+                        interval.tick().await;
+                        heartbeat.beat();
+                        let conn = Arc::clone(&sessions_conn);
+                        let dir = sessions_dir.clone();
+                        let result = tokio::task::spawn_blocking(move || {
+                            use crate::query::materialized_sessions::{
+                                materialize, prune, DAYS_PER_BATCH,
+                            };
+                            prune(&conn.lock())?;
+                            let mut built = 0;
+                            loop {
+                                let days = materialize(&conn.lock(), &dir, DAYS_PER_BATCH)?;
+                                built += days;
+                                if days < DAYS_PER_BATCH {
+                                    break;
+                                }
+                            }
+                            Ok::<_, duckdb::Error>(built)
+                        })
+                        .await;
+                        match result {
+                            Ok(Ok(0)) => heartbeat.success(),
+                            Ok(Ok(built)) => {
+                                heartbeat.success();
+                                tracing::debug!(days = built, "Sessions materialized");
+                            }
+                            Ok(Err(e)) => {
+                                tracing::error!(error = %e, "Session materialization failed");
+                            }
+                            Err(e) => {
+                                tracing::error!(
+                                    error = %e,
+                                    "Session materialization task panicked"
+                                );
+                            }
+                        }
+                    }
+                }
+            },
+        );
+    }
+
+    // StatsD exporter: push today's per-site visitor and pageview gauges.
+    if let Some(addr) = config.statsd_addr.clone() {
+        match crate::statsd::StatsdClient::connect(&addr) {
+            Ok(client) => {
+                let client = Arc::new(client);
+                let statsd_conn = Arc::clone(conn);
+                let prefix = config.statsd_prefix.clone();
+                let push_interval = config.statsd_interval_secs;
+                tracing::info!(addr = %addr, interval_secs = push_interval, "StatsD exporter enabled");
+                let push_period = Duration::from_secs(push_interval);
+                crate::watchdog::supervise(tasks, "statsd", push_period, move |heartbeat| {
+                    let client = Arc::clone(&client);
+                    let statsd_conn = Arc::clone(&statsd_conn);
+                    let prefix = prefix.clone();
+                    async move {
+                        let mut interval = tokio::time::interval(push_period);
+                        loop {
+                            interval.tick().await;
+                            heartbeat.beat();
+                            let conn = Arc::clone(&statsd_conn);
+                            let client = Arc::clone(&client);
+                            let prefix = prefix.clone();
+                            let result = tokio::task::spawn_blocking(move || {
+                                let today = chrono::Utc::now().date_naive();
+                                let tomorrow = today + chrono::Days::new(1);
+                                let counts = crate::statsd::query_site_counts(
+                                    &conn.lock(),
+                                    &today.to_string(),
+                                    &tomorrow.to_string(),
+                                )
+                                .map_err(|e| e.to_string())?;
+                                client
+                                    .send(&crate::statsd::format_gauges(&prefix, &counts))
+                                    .map_err(|e| e.to_string())
+                            })
+                            .await;
+                            match result {
+                                Ok(Ok(())) => heartbeat.success(),
+                                Ok(Err(e)) => tracing::warn!(error = %e, "StatsD push failed"),
+                                Err(e) => tracing::error!(error = %e, "StatsD push task panicked"),
+                            }
+                        }
+                    }
+                });
+            }
+            Err(e) => {
+                tracing::warn!(addr = %addr, error = %e, "StatsD exporter disabled: could not resolve address");
+            }
+        }
+    }
+
+    // Traffic digests of the last week, for channels subscribed to them.
+    // Channels can be created at runtime, so this runs regardless.
+    let digest_conn = Arc::clone(conn);
+    let digest_channels = state.notifications.clone();
+    let digest_period = Duration::from_secs(config.digest_interval_hours.saturating_mul(3600));
+    crate::watchdog::supervise(tasks, "digest", digest_period, move |heartbeat| {
+        let digest_conn = Arc::clone(&digest_conn);
+        let digest_channels = digest_channels.clone();
+        async move {
+            let client = reqwest::Client::new();
+            // The first digest is posted one interval after startup, so restarts
+            // do not repeat it.
+            let mut interval = tokio::time::interval_at(
+                tokio::time::Instant::now() + digest_period,
+                digest_period,
+            );
+            loop {
+                interval.tick().await;
+                heartbeat.beat();
+                let channels: Vec<_> = digest_channels
+                    .channels()
+                    .into_iter()
+                    .filter(|(_, c)| c.events.iter().any(|e| e == "digest"))
+                    .collect();
+                if channels.is_empty() {
+                    continue;
+                }
+                let sites: Vec<String> = channels
+                    .iter()
+                    .flat_map(|(_, c)| c.sites.iter().cloned())
+                    .collect();
+                let conn = Arc::clone(&digest_conn);
+                let result = tokio::task::spawn_blocking(move || {
+                    let today = chrono::Utc::now().date_naive();
+                    crate::notify::query_digests(
+                        &crate::query::QueryConn::new(&conn.lock()),
+                        &sites,
+                        today,
+                    )
+                })
+                .await;
+                match result {
+                    Ok(Ok(digests)) => {
+                        heartbeat.success();
+                        let notifications: Vec<_> = digests
+                            .iter()
+                            .map(crate::notify::Notification::Digest)
+                            .collect();
+                        crate::notify::notify(&client, channels, &notifications);
+                    }
+                    Ok(Err(e)) => tracing::error!(error = %e, "Digest query failed"),
+                    Err(e) => tracing::error!(error = %e, "Digest task panicked"),
+                }
+            }
+        }
+    });
+
+    // Hourly goal alerts: conversions of each channel goal in the last
+    // completed hour.
+    let goal_conn = Arc::clone(conn);
+    let goal_channels = state.notifications.clone();
+    let goal_period = Duration::from_secs(60 * 60);
+    crate::watchdog::supervise(tasks, "goal_alerts", goal_period, move |heartbeat| {
+        let goal_conn = Arc::clone(&goal_conn);
+        let goal_channels = goal_channels.clone();
+        async move {
+            let client = reqwest::Client::new();
+            let mut interval = tokio::time::interval(goal_period);
+            loop {
+                interval.tick().await;
+                heartbeat.beat();
+                let channels: Vec<_> = goal_channels
+                    .channels()
+                    .into_iter()
+                    .filter(|(_, c)| c.events.iter().any(|e| e == "goal") && !c.goals.is_empty())
+                    .collect();
+                let mut goals: Vec<String> = channels
+                    .iter()
+                    .flat_map(|(_, c)| c.goals.iter().cloned())
+                    .collect();
+                goals.sort();
+                goals.dedup();
+                if goals.is_empty() {
+                    continue;
+                }
+                let conn = Arc::clone(&goal_conn);
+                let result = tokio::task::spawn_blocking(move || {
+                    let now = chrono::Utc::now().naive_utc();
+                    let hour_end = now
+                        .date()
+                        .and_hms_opt(chrono::Timelike::hour(&now), 0, 0)
+                        .unwrap_or(now);
+                    let hour_start = (hour_end - chrono::TimeDelta::hours(1))
+                        .format("%Y-%m-%d %H:%M:%S")
+                        .to_string();
+                    let hour_end = hour_end.format("%Y-%m-%d %H:%M:%S").to_string();
+                    let conn = conn.lock();
+                    let conn = crate::query::QueryConn::new(&conn);
+                    let mut conversions = Vec::new();
+                    for goal in &goals {
+                        // Validated with the channel.
+                        let Ok(condition) = crate::api::stats::parse_goal(goal) else {
+                            continue;
+                        };
+                        conversions.extend(crate::notify::query_goal_conversions(
+                            &conn,
+                            goal,
+                            &condition,
+                            &hour_start,
+                            &hour_end,
+                        )?);
+                    }
+                    Ok::<_, duckdb::Error>(conversions)
+                })
+                .await;
+                match result {
+                    Ok(Ok(conversions)) => {
+                        heartbeat.success();
+                        let notifications: Vec<_> = conversions
+                            .iter()
+                            .map(crate::notify::Notification::Goal)
+                            .collect();
+                        crate::notify::notify(&client, channels, &notifications);
+                    }
+                    Ok(Err(e)) => tracing::error!(error = %e, "Goal alert query failed"),
+                    Err(e) => tracing::error!(error = %e, "Goal alert task panicked"),
+                }
+            }
+        }
+    });
+
+    // Session, cache, rate limiter, login tracker, API key, export and import
+    // job cleanup (runs every 15 minutes)
+    let session_store = state.sessions.clone();
+    let cache = state.query_cache.clone();
+    let public_cache = state.public_summary_cache.clone();
+    let rl = state.rate_limiter.clone();
+    let stats_rl = state.stats_rate_limiter.clone();
+    let dedupe = state.dedupe.clone();
+    let idempotency = state.idempotency.clone();
+    let login_tracker = state.login_attempt_tracker.clone();
+    let api_keys = state.api_keys.clone();
+    let export_jobs = state.export_jobs.clone();
+    let import_jobs = state.import_jobs.clone();
+    let cleanup_period = Duration::from_secs(15 * 60);
+    crate::watchdog::supervise(tasks, "cleanup", cleanup_period, move |heartbeat| {
+        let session_store = session_store.clone();
+        let cache = cache.clone();
+        let public_cache = public_cache.clone();
+        let rl = rl.clone();
+        let stats_rl = stats_rl.clone();
+        let dedupe = dedupe.clone();
+        let idempotency = idempotency.clone();
+        let login_tracker = login_tracker.clone();
+        let api_keys = api_keys.clone();
+        let export_jobs = export_jobs.clone();
+        let import_jobs = import_jobs.clone();
+        async move {
+            let mut interval = tokio::time::interval(cleanup_period);
+            loop {
+                interval.tick().await;
+                heartbeat.beat();
+                session_store.cleanup_expired();
+                cache.cleanup_expired();
+                public_cache.cleanup_expired();
+                rl.cleanup();
+                stats_rl.cleanup();
+                dedupe.cleanup();
+                idempotency.cleanup();
+                login_tracker.cleanup();
+                api_keys.cleanup_revoked();
+                export_jobs.cleanup();
+                import_jobs.cleanup();
+                heartbeat.success();
+            }
+        }
+    });
+}
@@ -1,9 +1,14 @@
+use crate::ingest::queue::{OverloadPolicy, SHED_AT_PCT};
 use crate::storage::parquet::ParquetStorage;
+use crate::storage::resources::{DiskGuard, LowDiskSpace};
 use chrono::NaiveDateTime;
 use duckdb::Connection;
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 /// Represents a single analytics event ready for storage.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -37,15 +42,47 @@ pub struct Event {
     pub props: Option<String>,
     pub revenue_amount: Option<f64>,
     pub revenue_currency: Option<String>,
+    /// Typed custom dimension values, indexed by the site's declared slot order.
+    #[serde(default)]
+    pub dimensions: [Option<String>; crate::config::MAX_CUSTOM_DIMENSIONS],
+    /// Page the visitor navigated from, for client-side (SPA) navigations.
+    #[serde(default)]
+    pub prev_pathname: Option<String>,
+    /// Hashed ID of the logged-in user, stable across devices and days.
+    #[serde(default)]
+    pub user_id: Option<String>,
+    /// Mobile device maker, e.g. `Apple`.
+    #[serde(default)]
+    pub device_brand: Option<String>,
+    /// Mobile device model, e.g. `iPhone` or `Pixel 8`.
+    #[serde(default)]
+    pub device_model: Option<String>,
 }
 
 /// Thread-safe event buffer that accumulates events and flushes to Parquet
 /// when the count threshold is reached.
+///
+/// With a [`DiskGuard`], flushing pauses while the data volume is low on
+/// space and events stay in memory, up to the buffer's capacity.
+///
+/// The time each site's oldest buffered event arrived is tracked, so
+/// [`flush_stale`](Self::flush_stale) can bound how long a low-traffic
+/// site's events wait for the count threshold.
 pub struct EventBuffer {
     events: Mutex<Vec<Event>>,
+    /// When the oldest buffered event of each site arrived.  Locked after
+    /// `events` when both are needed.
+    buffered_since: Mutex<HashMap<String, Instant>>,
     flush_threshold: usize,
     conn: Arc<Mutex<Connection>>,
     storage: ParquetStorage,
+    /// Most events held in memory; 0 = unbounded.
+    capacity: usize,
+    /// What to do as the buffer fills up.
+    overload: OverloadPolicy,
+    disk: Option<Arc<DiskGuard>>,
+    /// Running total of events refused or shed because the buffer was full.
+    dropped: AtomicU64,
 }
 
 impl EventBuffer {
@@ -56,30 +93,83 @@ impl EventBuffer {
     ) -> Self {
         Self {
             events: Mutex::new(Vec::with_capacity(flush_threshold)),
+            buffered_since: Mutex::new(HashMap::new()),
             flush_threshold,
             conn,
             storage,
+            capacity: 0,
+            overload: OverloadPolicy::Reject,
+            disk: None,
+            dropped: AtomicU64::new(0),
         }
     }
 
+    /// Hold at most `capacity` events (0 = unbounded), handling a full buffer
+    /// by `overload`: [`OverloadPolicy::Shed`] drops plain pageviews once it
+    /// is [`SHED_AT_PCT`] percent full.
+    #[must_use]
+    pub const fn with_capacity(mut self, capacity: usize, overload: OverloadPolicy) -> Self {
+        self.capacity = capacity;
+        self.overload = overload;
+        self
+    }
+
+    /// Check `disk` before every flush.
+    #[must_use]
+    pub fn with_disk_guard(mut self, disk: Arc<DiskGuard>) -> Self {
+        self.disk = Some(disk);
+        self
+    }
+
     /// Returns a reference to the DuckDB connection for query access.
     pub const fn conn(&self) -> &Arc<Mutex<Connection>> {
         &self.conn
     }
 
+    /// The free-space check run before flushes, if any.
+    pub const fn disk_guard(&self) -> Option<&Arc<DiskGuard>> {
+        self.disk.as_ref()
+    }
+
     /// Add an event to the buffer. If the buffer reaches the threshold,
     /// automatically flushes to Parquet.
+    ///
+    /// Fails with [`BufferError::Full`] when the buffer is at capacity, or
+    /// [`BufferError::Shed`] when the event was dropped as low priority.
+    /// A flush postponed for lack of disk space is not an error: the event
+    /// is buffered.
     pub fn push(&self, event: Event) -> Result<Option<usize>, BufferError> {
         let should_flush;
         {
             let mut events = self.events.lock();
+            if self.capacity > 0 {
+                if events.len() >= self.capacity {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    return Err(BufferError::Full);
+                }
+                if self.overload == OverloadPolicy::Shed
+                    && events.len() >= self.capacity * SHED_AT_PCT / 100
+                    && event.event_name == "pageview"
+                    && event.revenue_amount.is_none()
+                {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    return Err(BufferError::Shed);
+                }
+            }
+            self.buffered_since
+                .lock()
+                .entry(event.site_id.clone())
+                .or_insert_with(Instant::now);
             events.push(event);
             should_flush = events.len() >= self.flush_threshold;
         }
 
         if should_flush {
-            let flushed = self.flush()?;
-            Ok(Some(flushed))
+            match self.flush() {
+                Ok(flushed) => Ok(Some(flushed)),
+                Err(BufferError::DiskLow(_)) => Ok(None),
+                Err(e) => Err(e),
+            }
         } else {
             Ok(None)
         }
@@ -90,6 +180,35 @@ impl EventBuffer {
         self.events.lock().len()
     }
 
+    /// Returns true if the buffer is at capacity and refuses new events.
+    pub fn is_full(&self) -> bool {
+        self.capacity > 0 && self.len() >= self.capacity
+    }
+
+    /// Fails with [`BufferError::Full`], counting the refused event, when the
+    /// buffer is at capacity; lets ingest refuse an event before enriching it.
+    pub fn check_capacity(&self) -> Result<(), BufferError> {
+        if self.is_full() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return Err(BufferError::Full);
+        }
+        Ok(())
+    }
+
+    /// How long the oldest buffered event has been waiting.
+    pub fn oldest_age(&self) -> Option<Duration> {
+        self.buffered_since
+            .lock()
+            .values()
+            .min()
+            .map(Instant::elapsed)
+    }
+
+    /// Events refused or shed because the buffer was full, since startup.
+    pub fn dropped_total(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
     /// Returns true if the buffer is empty.
     pub fn is_empty(&self) -> bool {
         self.events.lock().is_empty()
@@ -106,17 +225,100 @@ impl EventBuffer {
     /// If inserts succeed but the Parquet COPY TO fails the buffer is already cleared;
     /// the events remain visible in the DuckDB `events` table (and therefore through
     /// the `events_all` view) and will be written to Parquet on the next periodic flush.
+    ///
+    /// While the disk guard reports low space nothing is drained and the flush
+    /// fails with [`BufferError::DiskLow`], so a full disk neither loses events
+    /// nor grows the database.
     pub fn flush(&self) -> Result<usize, BufferError> {
+        if self.is_empty() {
+            return Ok(0);
+        }
+        if let Some(disk) = &self.disk {
+            disk.check()?;
+        }
+
         // Atomically drain the buffer.  Taking ownership here prevents any concurrent
         // flush from processing the same events twice.
-        let events: Vec<Event> = {
+        let (events, since) = {
             let mut buf = self.events.lock();
             if buf.is_empty() {
                 return Ok(0);
             }
-            std::mem::take(&mut *buf)
+            let since = std::mem::take(&mut *self.buffered_since.lock());
+            (std::mem::take(&mut *buf), since)
+        };
+        self.write(events, since)
+    }
+
+    /// Flush the events of every site whose oldest buffered event has waited
+    /// at least `max_age`, leaving other sites' events to fill up to the
+    /// count threshold.  Returns the number of events flushed.
+    ///
+    /// Fails like [`flush`](Self::flush); the drained events are restored
+    /// with their original age when they cannot be inserted.
+    pub fn flush_stale(&self, max_age: Duration) -> Result<usize, BufferError> {
+        if self.oldest_age().is_none_or(|age| age < max_age) {
+            return Ok(0);
+        }
+        if let Some(disk) = &self.disk {
+            disk.check()?;
+        }
+
+        let (events, since) = {
+            let mut buf = self.events.lock();
+            let mut since = self.buffered_since.lock();
+            let stale: HashSet<String> = since
+                .iter()
+                .filter(|(_, arrived)| arrived.elapsed() >= max_age)
+                .map(|(site_id, _)| site_id.clone())
+                .collect();
+            if stale.is_empty() {
+                return Ok(0);
+            }
+            let (events, rest): (Vec<Event>, Vec<Event>) = std::mem::take(&mut *buf)
+                .into_iter()
+                .partition(|event| stale.contains(&event.site_id));
+            *buf = rest;
+            drop(buf);
+            let mut drained = HashMap::new();
+            since.retain(|site_id, arrived| {
+                if stale.contains(site_id) {
+                    drained.insert(site_id.clone(), *arrived);
+                    false
+                } else {
+                    true
+                }
+            });
+            drop(since);
+            (events, drained)
         };
+        self.write(events, since)
+    }
+
+    /// Put drained events back at the front of the buffer, keeping the
+    /// earlier arrival time of each site.
+    fn restore(&self, mut events: Vec<Event>, since: HashMap<String, Instant>) {
+        let mut buf = self.events.lock();
+        events.append(&mut *buf);
+        *buf = events;
+        let mut current = self.buffered_since.lock();
+        for (site_id, arrived) in since {
+            current
+                .entry(site_id)
+                .and_modify(|t| *t = (*t).min(arrived))
+                .or_insert(arrived);
+        }
+        drop(current);
+        drop(buf);
+    }
 
+    /// Insert drained `events` into the `events` table and flush it to
+    /// Parquet, restoring them to the buffer if the insert fails.
+    fn write(
+        &self,
+        events: Vec<Event>,
+        since: HashMap<String, Instant>,
+    ) -> Result<usize, BufferError> {
         let count = events.len();
         let conn = self.conn.lock();
 
@@ -125,17 +327,14 @@ impl EventBuffer {
         // If the Appender fails we restore the drained events to the buffer so
         // they are retried on the next flush attempt.
         {
-            let mut appender = conn.appender("events").map_err(|e| {
-                // Restore events on Appender creation failure.
-                // Inner block tightens the MutexGuard drop point (L15).
-                {
-                    let mut buf = self.events.lock();
-                    let mut restored = events.clone();
-                    restored.append(&mut *buf);
-                    *buf = restored;
+            let mut appender = match conn.appender("events") {
+                Ok(appender) => appender,
+                Err(e) => {
+                    // Restore events on Appender creation failure.
+                    self.restore(events, since);
+                    return Err(BufferError::Insert(e));
                 }
-                BufferError::Insert(e)
-            })?;
+            };
 
             for event in &events {
                 if let Err(e) = appender.append_row(duckdb::params![
@@ -164,28 +363,27 @@ impl EventBuffer {
                     event.props,
                     event.revenue_amount,
                     event.revenue_currency,
+                    event.dimensions[0],
+                    event.dimensions[1],
+                    event.dimensions[2],
+                    event.dimensions[3],
+                    event.dimensions[4],
+                    event.prev_pathname,
+                    event.user_id,
+                    event.device_brand,
+                    event.device_model,
                 ]) {
                     // Restore all events (including any not yet appended) to the buffer
                     // so they are retried on the next flush.
                     drop(appender);
-                    {
-                        let mut buf = self.events.lock();
-                        let mut restored = events;
-                        restored.append(&mut *buf);
-                        *buf = restored;
-                    }
+                    self.restore(events, since);
                     return Err(BufferError::Insert(e));
                 }
             }
 
             if let Err(e) = appender.flush() {
                 drop(appender);
-                {
-                    let mut buf = self.events.lock();
-                    let mut restored = events;
-                    restored.append(&mut *buf);
-                    *buf = restored;
-                }
+                self.restore(events, since);
                 return Err(BufferError::Insert(e));
             }
             // appender drops here; the borrow of conn ends
@@ -205,23 +403,20 @@ impl EventBuffer {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum BufferError {
-    Insert(duckdb::Error),
-    Flush(crate::storage::parquet::FlushError),
-}
-
-impl std::fmt::Display for BufferError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Insert(e) => write!(f, "Insert error: {e}"),
-            Self::Flush(e) => write!(f, "Flush error: {e}"),
-        }
-    }
+    #[error("Insert error: {0}")]
+    Insert(#[source] duckdb::Error),
+    #[error("Flush error: {0}")]
+    Flush(#[from] crate::storage::parquet::FlushError),
+    #[error("Flush postponed: {0}")]
+    DiskLow(#[from] LowDiskSpace),
+    #[error("Event buffer is full")]
+    Full,
+    #[error("Event shed: the event buffer is nearly full")]
+    Shed,
 }
 
-impl std::error::Error for BufferError {}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -257,6 +452,11 @@ mod tests {
             props: None,
             revenue_amount: None,
             revenue_currency: None,
+            dimensions: [const { None }; crate::config::MAX_CUSTOM_DIMENSIONS],
+            prev_pathname: None,
+            user_id: None,
+            device_brand: None,
+            device_model: None,
         }
     }
 
@@ -379,6 +579,91 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_buffer_capacity() {
+        let (buffer, _dir) = setup_buffer(100);
+        let buffer = buffer.with_capacity(10, OverloadPolicy::Reject);
+        for i in 0..10 {
+            buffer
+                .push(make_test_event("example.com", &format!("/{i}")))
+                .unwrap();
+        }
+        assert!(buffer.is_full());
+        assert!(matches!(
+            buffer.push(make_test_event("example.com", "/")),
+            Err(BufferError::Full)
+        ));
+        assert_eq!(buffer.len(), 10);
+        assert_eq!(buffer.dropped_total(), 1);
+    }
+
+    #[test]
+    fn test_buffer_sheds_pageviews_when_nearly_full() {
+        let (buffer, _dir) = setup_buffer(100);
+        let buffer = buffer.with_capacity(10, OverloadPolicy::Shed);
+        for i in 0..9 {
+            buffer
+                .push(make_test_event("example.com", &format!("/{i}")))
+                .unwrap();
+        }
+        assert!(matches!(
+            buffer.push(make_test_event("example.com", "/")),
+            Err(BufferError::Shed)
+        ));
+        let mut signup = make_test_event("example.com", "/");
+        signup.event_name = "signup".to_string();
+        buffer.push(signup).unwrap();
+        assert_eq!(buffer.len(), 10);
+        assert_eq!(buffer.dropped_total(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_low_disk_space_postpones_flush() {
+        let (buffer, _dir) = setup_buffer(2);
+        let disk = Arc::new(DiskGuard::new(std::env::temp_dir(), u64::MAX));
+        let buffer = buffer.with_disk_guard(Arc::clone(&disk));
+
+        buffer.push(make_test_event("example.com", "/")).unwrap();
+        let result = buffer.push(make_test_event("example.com", "/about"));
+        assert!(matches!(result, Ok(None)), "flush must be postponed");
+        assert!(disk.is_low());
+        assert_eq!(buffer.len(), 2, "events must stay buffered");
+        assert!(matches!(buffer.flush(), Err(BufferError::DiskLow(_))));
+
+        let count: i64 = buffer
+            .conn()
+            .lock()
+            .query_row("SELECT COUNT(*) FROM events", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0, "nothing may be written while space is low");
+    }
+
+    #[test]
+    fn test_flush_stale_flushes_only_old_sites() {
+        let (buffer, dir) = setup_buffer(100);
+        buffer.push(make_test_event("old.com", "/")).unwrap();
+        buffer.push(make_test_event("old.com", "/about")).unwrap();
+        std::thread::sleep(Duration::from_millis(300));
+        buffer.push(make_test_event("new.com", "/")).unwrap();
+
+        assert!(buffer.oldest_age().unwrap() >= Duration::from_millis(300));
+        assert_eq!(buffer.flush_stale(Duration::from_secs(60)).unwrap(), 0);
+        assert_eq!(buffer.flush_stale(Duration::from_millis(300)).unwrap(), 2);
+        assert_eq!(buffer.len(), 1, "the recent site must stay buffered");
+        assert!(buffer.oldest_age().unwrap() < Duration::from_millis(300));
+
+        let storage = ParquetStorage::new(dir.path());
+        assert!(storage
+            .partition_dir("old.com", "2024-01-15")
+            .join("0001.parquet")
+            .exists());
+        assert!(!storage.partition_dir("new.com", "2024-01-15").exists());
+
+        buffer.flush().unwrap();
+        assert!(buffer.oldest_age().is_none());
+    }
+
     #[test]
     fn test_multiple_sites_in_buffer() {
         let (buffer, dir) = setup_buffer(100);
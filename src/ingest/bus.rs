@@ -0,0 +1,226 @@
+//! Event bus consumer.
+//!
+//! With `[event_bus]` configured, a background task reads events from a
+//! Kafka topic (`kafka` feature) or a NATS subject (`nats` feature) and
+//! feeds them through the same validation and enrichment as
+//! `POST /api/event`, so an organization already shipping events over a bus
+//! does not have to fan them in over HTTP.
+//!
+//! Each message is one event in the ingestion payload format (`d`, `n`, `u`,
+//! …), plus the optional `ip` and `ua` of the client it describes, which
+//! stand in for the request's address and User-Agent header.  Messages come
+//! from a trusted producer, so origin checks, `require_ingest_key` and the
+//! per-site rate limit do not apply; `eid` deduplication does.  Invalid
+//! messages are logged and skipped.  While the ingest queue or event buffer
+//! is full the consumer waits instead of dropping events, leaving the rest
+//! of the stream on the bus.
+//!
+//! Kafka offsets are committed after an event is buffered, so events are
+//! delivered at least once; send `eid` to have redeliveries dropped.  Core
+//! NATS has no acknowledgements, so events in flight when the server stops
+//! are lost.
+
+use crate::config::EventBusConfig;
+use crate::ingest::handler::{
+    check_event_time, stats_site, submit, validate_payload, AppState, EventPayload,
+};
+use crate::ingest::queue::RawEvent;
+use crate::ingest::stats::{IngestOutcome, OTHER_SITES};
+use axum::http::StatusCode;
+use chrono::Utc;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Wait before reconnecting after the consumer fails.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Wait before offering an event again while ingestion is full.
+const FULL_DELAY: Duration = Duration::from_millis(100);
+
+/// One event as published on the bus.
+#[derive(Debug, Deserialize)]
+pub struct BusMessage {
+    #[serde(flatten)]
+    pub payload: EventPayload,
+    /// Address of the client, for the visitor hash and GeoIP lookup only.
+    /// Never stored.
+    #[serde(default)]
+    pub ip: Option<String>,
+    /// User-Agent of the client.
+    #[serde(default)]
+    pub ua: Option<String>,
+}
+
+/// Start the consumer configured in `config`, if any.  It reconnects after
+/// failures until the server's background tasks are stopped.
+pub fn spawn(state: &Arc<AppState>, config: &EventBusConfig) {
+    if config.kind.is_empty() {
+        return;
+    }
+    let tasks = state.tasks.clone();
+    // Only the consumers use it, and a build may have neither.
+    #[cfg_attr(not(any(feature = "kafka", feature = "nats")), allow(unused_variables))]
+    let state = Arc::clone(state);
+    let config = config.clone();
+    let consumer = tokio::spawn(async move {
+        loop {
+            tracing::info!(
+                kind = %config.kind,
+                topic = %config.topic,
+                "Event bus consumer connecting"
+            );
+            // Only the fallback arm is left in a build without either feature.
+            #[allow(clippy::match_single_binding)]
+            let result: Result<(), String> = match config.kind.as_str() {
+                #[cfg(feature = "kafka")]
+                "kafka" => consume_kafka(&state, &config).await,
+                #[cfg(feature = "nats")]
+                "nats" => consume_nats(&state, &config).await,
+                // Refused by `Config::validate`.
+                kind => Err(format!("this build has no {kind:?} event bus consumer")),
+            };
+            if let Err(error) = result {
+                tracing::warn!(kind = %config.kind, %error, "Event bus consumer failed");
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    });
+    tasks.track(consumer.abort_handle());
+}
+
+#[cfg(feature = "kafka")]
+async fn consume_kafka(state: &Arc<AppState>, config: &EventBusConfig) -> Result<(), String> {
+    use rdkafka::consumer::{Consumer, StreamConsumer};
+    use rdkafka::message::Message;
+    use rdkafka::ClientConfig;
+
+    // Offsets are stored once a message is handled and committed in the
+    // background, so a restart resumes after the last buffered event.
+    let consumer: StreamConsumer = ClientConfig::new()
+        .set("bootstrap.servers", &config.servers)
+        .set("group.id", &config.group)
+        .set("enable.auto.commit", "true")
+        .set("enable.auto.offset.store", "false")
+        .set("auto.offset.reset", "earliest")
+        .create()
+        .map_err(|e| e.to_string())?;
+    consumer
+        .subscribe(&[config.topic.as_str()])
+        .map_err(|e| e.to_string())?;
+    loop {
+        // The client reconnects to brokers by itself; errors here are
+        // transient.
+        let message = match consumer.recv().await {
+            Ok(message) => message,
+            Err(error) => {
+                tracing::warn!(%error, "Kafka receive failed");
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        };
+        if let Some(payload) = message.payload() {
+            ingest(state, payload).await;
+        }
+        if let Err(error) = consumer.store_offset_from_message(&message) {
+            tracing::warn!(%error, "Failed to store Kafka offset");
+        }
+    }
+}
+
+#[cfg(feature = "nats")]
+async fn consume_nats(state: &Arc<AppState>, config: &EventBusConfig) -> Result<(), String> {
+    use futures_util::StreamExt;
+
+    let servers: Vec<&str> = config.servers.split(',').map(str::trim).collect();
+    let client = async_nats::connect(servers)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut subscriber = client
+        .queue_subscribe(config.topic.clone(), config.group.clone())
+        .await
+        .map_err(|e| e.to_string())?;
+    while let Some(message) = subscriber.next().await {
+        ingest(state, &message.payload).await;
+    }
+    Err("subscription closed".to_string())
+}
+
+/// Validate one message and submit its event for enrichment, waiting while
+/// the ingest queue or event buffer is full.  Returns once the event is
+/// buffered or queued, or dropped as invalid or a duplicate.
+#[cfg_attr(not(any(feature = "kafka", feature = "nats")), allow(dead_code))]
+pub async fn ingest(state: &Arc<AppState>, message: &[u8]) {
+    let message: BusMessage = match serde_json::from_slice(message) {
+        Ok(message) => message,
+        Err(error) => {
+            tracing::debug!(%error, "Skipped malformed event bus message");
+            state
+                .ingest_stats
+                .record(OTHER_SITES, IngestOutcome::Rejected);
+            return;
+        }
+    };
+    let mut errors = validate_payload(&message.payload);
+    errors.extend(check_event_time(
+        &message.payload,
+        state.max_event_time_skew,
+        Utc::now(),
+    ));
+    let site_id = stats_site(&message.payload, &errors).to_string();
+    if !errors.is_empty() {
+        tracing::debug!(site_id = %site_id, ?errors, "Skipped invalid event bus message");
+        state.ingest_stats.record(&site_id, IngestOutcome::Rejected);
+        return;
+    }
+    if let Some(event_id) = &message.payload.event_id {
+        if !state.dedupe.first_seen(&site_id, event_id) {
+            state
+                .duplicate_events_total
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            return;
+        }
+    }
+
+    let raw = RawEvent {
+        payload: message.payload,
+        ip: message.ip.unwrap_or_else(|| "unknown".to_string()),
+        user_agent: message.ua.unwrap_or_default(),
+        received_at: Utc::now(),
+    };
+    loop {
+        match submit(state, raw.clone()).await {
+            Ok(()) => {
+                state.ingest_stats.record(&site_id, IngestOutcome::Accepted);
+                return;
+            }
+            Err(StatusCode::SERVICE_UNAVAILABLE) => tokio::time::sleep(FULL_DELAY).await,
+            // Logged by `submit`; retrying would fail the same way.
+            Err(_) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bus_message_decodes_payload_and_client() {
+        let message: BusMessage = serde_json::from_str(
+            r#"{"d": "example.com", "n": "pageview", "u": "https://example.com/",
+                "w": 1280, "eid": "e1", "ip": "203.0.113.7", "ua": "Mozilla/5.0"}"#,
+        )
+        .unwrap();
+        assert_eq!(message.payload.domain, "example.com");
+        assert_eq!(message.payload.screen_width, Some(1280));
+        assert_eq!(message.payload.event_id.as_deref(), Some("e1"));
+        assert_eq!(message.ip.as_deref(), Some("203.0.113.7"));
+        assert_eq!(message.ua.as_deref(), Some("Mozilla/5.0"));
+
+        let message: BusMessage =
+            serde_json::from_str(r#"{"d": "example.com", "n": "signup", "u": "/"}"#).unwrap();
+        assert_eq!(message.ip, None);
+        assert!(serde_json::from_str::<BusMessage>(r#"{"ip": "203.0.113.7"}"#).is_err());
+    }
+}
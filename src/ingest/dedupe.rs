@@ -0,0 +1,188 @@
+use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Most event IDs remembered per site; the oldest are forgotten first.
+const MAX_IDS_PER_SITE: usize = 10_000;
+/// Most sites whose event IDs are remembered.  Events of further sites are
+/// not deduplicated until sites without recent IDs are dropped.
+const MAX_SITES: usize = 10_000;
+
+/// Remembers recent client-supplied event IDs (`eid`) per site so retried or
+/// double-fired events are stored once.
+///
+/// An ID is remembered for `window`, or until the site has seen
+/// [`MAX_IDS_PER_SITE`] newer IDs, whichever comes first.  At most
+/// [`MAX_SITES`] sites are tracked.
+#[derive(Clone)]
+pub struct Deduplicator {
+    sites: Arc<Mutex<HashMap<String, RecentIds>>>,
+    window: Duration,
+}
+
+#[derive(Default)]
+struct RecentIds {
+    /// IDs in arrival order, oldest first.
+    order: VecDeque<(Instant, String)>,
+    ids: HashSet<String>,
+}
+
+impl RecentIds {
+    /// Forget IDs older than `window`.
+    fn expire(&mut self, now: Instant, window: Duration) {
+        while self
+            .order
+            .front()
+            .is_some_and(|(seen, _)| now.duration_since(*seen) >= window)
+        {
+            self.pop_oldest();
+        }
+    }
+
+    /// Forget the oldest IDs until there is room for one more.
+    fn make_room(&mut self) {
+        while self.order.len() >= MAX_IDS_PER_SITE {
+            self.pop_oldest();
+        }
+    }
+
+    fn pop_oldest(&mut self) {
+        if let Some((_, id)) = self.order.pop_front() {
+            self.ids.remove(&id);
+        }
+    }
+}
+
+impl Deduplicator {
+    /// A window of zero disables deduplication.
+    pub fn new(window: Duration) -> Self {
+        Self {
+            sites: Arc::new(Mutex::new(HashMap::new())),
+            window,
+        }
+    }
+
+    /// Record `event_id` for `site_id`.  Returns `false` if the same ID was
+    /// already seen for the site within the window.
+    #[allow(clippy::significant_drop_tightening)]
+    pub fn first_seen(&self, site_id: &str, event_id: &str) -> bool {
+        if self.window.is_zero() {
+            return true;
+        }
+        let now = Instant::now();
+        let mut sites = self.sites.lock();
+        if sites.len() >= MAX_SITES && !sites.contains_key(site_id) {
+            Self::expire_sites(&mut sites, now, self.window);
+            if sites.len() >= MAX_SITES {
+                return true;
+            }
+        }
+        let recent = sites.entry(site_id.to_string()).or_default();
+        recent.expire(now, self.window);
+        if recent.ids.contains(event_id) {
+            return false;
+        }
+        recent.make_room();
+        recent.ids.insert(event_id.to_string());
+        recent.order.push_back((now, event_id.to_string()));
+        true
+    }
+
+    /// Forget `event_id` for `site_id`, so a retry of an event that was not
+    /// accepted after all is not taken for a duplicate.
+    pub fn forget(&self, site_id: &str, event_id: &str) {
+        if let Some(recent) = self.sites.lock().get_mut(site_id) {
+            if recent.ids.remove(event_id) {
+                recent.order.retain(|(_, id)| id != event_id);
+            }
+        }
+    }
+
+    /// Drop expired IDs and sites with none left.
+    pub fn cleanup(&self) {
+        Self::expire_sites(&mut self.sites.lock(), Instant::now(), self.window);
+    }
+
+    fn expire_sites(sites: &mut HashMap<String, RecentIds>, now: Instant, window: Duration) {
+        sites.retain(|_, recent| {
+            recent.expire(now, window);
+            !recent.order.is_empty()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicate_within_window() {
+        let dedupe = Deduplicator::new(Duration::from_secs(60));
+        assert!(dedupe.first_seen("a.com", "e1"));
+        assert!(!dedupe.first_seen("a.com", "e1"));
+        // IDs are scoped per site.
+        assert!(dedupe.first_seen("b.com", "e1"));
+        assert!(dedupe.first_seen("a.com", "e2"));
+    }
+
+    #[test]
+    fn test_expired_ids_are_forgotten() {
+        let dedupe = Deduplicator::new(Duration::from_millis(1));
+        assert!(dedupe.first_seen("a.com", "e1"));
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(dedupe.first_seen("a.com", "e1"));
+        std::thread::sleep(Duration::from_millis(5));
+        dedupe.cleanup();
+        assert!(dedupe.sites.lock().is_empty());
+    }
+
+    #[test]
+    fn test_oldest_ids_evicted_at_capacity() {
+        let dedupe = Deduplicator::new(Duration::from_secs(60));
+        for i in 0..MAX_IDS_PER_SITE {
+            assert!(dedupe.first_seen("a.com", &i.to_string()));
+        }
+        assert_eq!(dedupe.sites.lock()["a.com"].order.len(), MAX_IDS_PER_SITE);
+        assert!(!dedupe.first_seen("a.com", "0"));
+        // Inserting one more pushes "0" out.
+        assert!(dedupe.first_seen("a.com", "extra"));
+        let sites = dedupe.sites.lock();
+        assert_eq!(sites["a.com"].order.len(), MAX_IDS_PER_SITE);
+        assert_eq!(sites["a.com"].ids.len(), MAX_IDS_PER_SITE);
+        assert!(!sites["a.com"].ids.contains("0"));
+        drop(sites);
+    }
+
+    #[test]
+    fn test_sites_capped() {
+        let dedupe = Deduplicator::new(Duration::from_secs(60));
+        for i in 0..MAX_SITES {
+            assert!(dedupe.first_seen(&format!("site{i}.com"), "e1"));
+        }
+        // Further sites are not tracked, so their repeats get through.
+        assert!(dedupe.first_seen("new.com", "e1"));
+        assert!(dedupe.first_seen("new.com", "e1"));
+        assert_eq!(dedupe.sites.lock().len(), MAX_SITES);
+        // Tracked sites are still deduplicated.
+        assert!(!dedupe.first_seen("site0.com", "e1"));
+    }
+
+    #[test]
+    fn test_forget() {
+        let dedupe = Deduplicator::new(Duration::from_secs(60));
+        assert!(dedupe.first_seen("a.com", "e1"));
+        assert!(dedupe.first_seen("a.com", "e2"));
+        dedupe.forget("a.com", "e1");
+        dedupe.forget("b.com", "e2");
+        assert!(dedupe.first_seen("a.com", "e1"));
+        assert!(!dedupe.first_seen("a.com", "e2"));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let dedupe = Deduplicator::new(Duration::ZERO);
+        assert!(dedupe.first_seen("a.com", "e1"));
+        assert!(dedupe.first_seen("a.com", "e1"));
+    }
+}
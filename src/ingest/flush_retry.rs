@@ -0,0 +1,112 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How failed periodic flushes are retried, and when failing in a row calls
+/// for an alert.
+///
+/// Counts the flushes that failed in a row, each after its retries, in a
+/// shared counter so it can be exposed in metrics.
+#[derive(Clone)]
+pub struct FlushRetry {
+    retries: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    /// 0 = never alert.
+    alert_after: u64,
+    consecutive_failures: Arc<AtomicU64>,
+}
+
+impl FlushRetry {
+    pub fn new(
+        retries: u32,
+        base_delay: Duration,
+        max_delay: Duration,
+        alert_after: u32,
+        consecutive_failures: Arc<AtomicU64>,
+    ) -> Self {
+        Self {
+            retries,
+            base_delay,
+            max_delay,
+            alert_after: u64::from(alert_after),
+            consecutive_failures,
+        }
+    }
+
+    /// Retries of a failed flush.
+    pub const fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// Wait before retry `attempt`, counted from 0: the base delay doubled
+    /// per attempt up to the maximum, of which the upper half is random so
+    /// instances sharing storage do not retry in lockstep.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        delay / 2 + (delay / 2).mul_f64(rand::random::<f64>())
+    }
+
+    /// Record a flush that failed after its retries.  Returns the failures
+    /// in a row when they reach the alert threshold, once per streak.
+    pub fn failed(&self) -> Option<u64> {
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        (self.alert_after > 0 && failures == self.alert_after).then_some(failures)
+    }
+
+    /// Record a successful flush.  Returns the failures in a row it ended
+    /// when they had been alerted on.
+    pub fn succeeded(&self) -> Option<u64> {
+        let failures = self.consecutive_failures.swap(0, Ordering::Relaxed);
+        (self.alert_after > 0 && failures >= self.alert_after).then_some(failures)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flush_retry(alert_after: u32) -> FlushRetry {
+        FlushRetry::new(
+            3,
+            Duration::from_secs(1),
+            Duration::from_secs(5),
+            alert_after,
+            Arc::new(AtomicU64::new(0)),
+        )
+    }
+
+    #[test]
+    fn test_delay_backs_off_with_jitter() {
+        let retry = flush_retry(3);
+        for (attempt, full) in [(0, 1000), (1, 2000), (2, 4000), (3, 5000), (40, 5000)] {
+            let delay = retry.delay(attempt).as_millis();
+            assert!(
+                (full / 2..=full).contains(&delay),
+                "attempt {attempt}: {delay}ms"
+            );
+        }
+    }
+
+    #[test]
+    fn test_alerts_once_per_streak() {
+        let retry = flush_retry(3);
+        assert_eq!(retry.failed(), None);
+        assert_eq!(retry.succeeded(), None);
+
+        assert_eq!(retry.failed(), None);
+        assert_eq!(retry.failed(), None);
+        assert_eq!(retry.failed(), Some(3));
+        assert_eq!(retry.failed(), None);
+        assert_eq!(retry.consecutive_failures.load(Ordering::Relaxed), 4);
+        assert_eq!(retry.succeeded(), Some(4));
+        assert_eq!(retry.consecutive_failures.load(Ordering::Relaxed), 0);
+
+        let silent = flush_retry(0);
+        assert_eq!(silent.failed(), None);
+        assert_eq!(silent.succeeded(), None);
+    }
+}
@@ -6,6 +6,8 @@ use std::sync::Arc;
 #[derive(Debug, Clone, Default)]
 pub struct GeoInfo {
     pub country_code: Option<String>,
+    /// Two-letter continent code, e.g. `EU`.  Used for filtering, never stored.
+    pub continent_code: Option<String>,
     pub region: Option<String>,
     pub city: Option<String>,
 }
@@ -68,6 +70,7 @@ impl GeoIpReader {
         };
 
         let country_code = city.country.iso_code.map(String::from);
+        let continent_code = city.continent.code.map(String::from);
 
         let region = city
             .subdivisions
@@ -79,6 +82,7 @@ impl GeoIpReader {
 
         GeoInfo {
             country_code,
+            continent_code,
             region,
             city: city_name,
         }
@@ -1,16 +1,28 @@
 use crate::api::auth::{ApiKeyStore, LoginAttemptTracker, SessionStore};
-use crate::ingest::buffer::{Event, EventBuffer};
-use crate::ingest::geoip::GeoIpReader;
+use crate::api::errors::{ApiError, FieldError};
+use crate::api::openapi;
+use crate::config::{
+    DimensionType, GeoBlockAction, SiteConfig, GEO_RESTRICTED_SUFFIX, MAX_CUSTOM_DIMENSIONS,
+};
+use crate::ingest::buffer::{BufferError, Event, EventBuffer};
+use crate::ingest::geoip::{GeoInfo, GeoIpReader};
+use crate::ingest::idempotency::{Claim, IdempotencyKeys, MAX_KEY_LEN};
+use crate::ingest::queue::{Enqueued, IngestQueue, RawEvent};
+use crate::ingest::stats::{IngestOutcome, IngestStats, OTHER_SITES};
 use crate::ingest::useragent;
 use crate::ingest::visitor_id;
-use axum::extract::State;
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{Request, State};
 use axum::http::{HeaderMap, StatusCode};
-use axum::response::IntoResponse;
-use axum::Json;
-use chrono::{DateTime, Timelike, Utc};
-use serde::Deserialize;
-use std::sync::atomic::AtomicU64;
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use chrono::{DateTime, TimeDelta, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64};
 use std::sync::Arc;
+use utoipa::{IntoParams, ToSchema};
 
 /// Strip query string and fragment from a URL for privacy-preserving referrer storage.
 ///
@@ -32,7 +44,7 @@ pub fn round_to_hour(dt: DateTime<Utc>) -> chrono::NaiveDateTime {
 }
 
 /// UTM parameters tuple: (source, medium, campaign, content, term).
-type UtmParams = (
+pub type UtmParams = (
     Option<String>,
     Option<String>,
     Option<String>,
@@ -41,7 +53,7 @@ type UtmParams = (
 );
 
 /// Inbound event payload from the tracking script.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 pub struct EventPayload {
     /// Site domain (e.g., "example.com")
     #[serde(rename = "d")]
@@ -67,6 +79,82 @@ pub struct EventPayload {
     /// Revenue currency
     #[serde(rename = "rc")]
     pub revenue_currency: Option<String>,
+    /// Client-generated event ID; repeats within the dedupe window are dropped.
+    #[serde(rename = "eid", default, skip_serializing_if = "Option::is_none")]
+    pub event_id: Option<String>,
+    /// Previous pathname, sent by the tracker on client-side (SPA) navigations.
+    #[serde(rename = "pt", default, skip_serializing_if = "Option::is_none")]
+    pub prev_pathname: Option<String>,
+    /// The site's own ID for a logged-in user; stored only as a keyed hash.
+    #[serde(rename = "uid", default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    /// When the event happened, for events sent in batches or replayed
+    /// later; defaults to the time of receipt.
+    #[serde(rename = "t", default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<DateTime<Utc>>,
+}
+
+/// Check a payload against the ingest field limits.
+///
+/// Returns every problem found, keyed by the payload's wire field names
+/// (`d`, `n`, `u`, …), so a tracker author sees all of them at once.
+pub fn validate_payload(payload: &EventPayload) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    for (field, value, max) in [
+        ("d", &payload.domain, 256),
+        ("n", &payload.name, 256),
+        ("u", &payload.url, 2048),
+    ] {
+        if value.is_empty() {
+            errors.push(FieldError::new(field, format!("{field} must not be empty")));
+        } else if value.len() > max {
+            errors.push(FieldError::new(
+                field,
+                format!("{field} must be at most {max} bytes"),
+            ));
+        }
+    }
+    if payload.referrer.as_ref().is_some_and(|r| r.len() > 2048) {
+        errors.push(FieldError::new("r", "r must be at most 2048 bytes"));
+    }
+    if payload.props.as_ref().is_some_and(|p| p.len() > 4096) {
+        errors.push(FieldError::new("p", "p must be at most 4096 bytes"));
+    }
+    if payload
+        .prev_pathname
+        .as_ref()
+        .is_some_and(|p| p.len() > 2048)
+    {
+        errors.push(FieldError::new("pt", "pt must be at most 2048 bytes"));
+    }
+    if payload
+        .event_id
+        .as_ref()
+        .is_some_and(|id| id.is_empty() || id.len() > 128)
+    {
+        errors.push(FieldError::new("eid", "eid must be 1-128 bytes"));
+    }
+    if payload
+        .user_id
+        .as_ref()
+        .is_some_and(|id| id.is_empty() || id.len() > 256)
+    {
+        errors.push(FieldError::new("uid", "uid must be 1-256 bytes"));
+    }
+    if !payload.domain.is_empty() && payload.domain.len() <= 256 {
+        if let Err(ApiError::Validation {
+            errors: site_errors,
+            ..
+        }) = crate::api::stats::validate_site_id(&payload.domain)
+        {
+            errors.extend(
+                site_errors
+                    .into_iter()
+                    .map(|e| FieldError::new("d", e.message)),
+            );
+        }
+    }
+    errors
 }
 
 /// Shared application state.
@@ -79,19 +167,71 @@ pub struct AppState {
     pub filter_bots: bool,
     pub sessions: SessionStore,
     pub api_keys: ApiKeyStore,
+    /// Saved dashboard layouts.
+    pub dashboards: crate::api::dashboards::DashboardStore,
+    /// Slack and Discord channels notifications are posted to.
+    pub notifications: crate::api::notifications::NotificationStore,
+    /// Background exports queued by `POST /api/exports`.
+    pub export_jobs: crate::api::export_jobs::ExportJobs,
+    /// Uploads validated by `POST /api/imports`, and their imports.
+    pub import_jobs: crate::api::import_jobs::ImportJobs,
     /// Hashed admin password (Argon2id). None if no admin user set up yet.
     pub admin_password_hash: parking_lot::Mutex<Option<String>>,
+    /// Where a password set via `/api/auth/setup` is persisted. None keeps it
+    /// in memory only.
+    pub admin_password_path: Option<std::path::PathBuf>,
+    /// Locale for CSV exports when the request does not ask for one.
+    pub locale: crate::api::locale::Locale,
+    /// Dashboard white-labelling, served by `GET /api/meta/branding`.
+    pub branding: crate::config::BrandingConfig,
+    /// Extra Content-Security-Policy sources for dashboard pages.
+    pub csp: crate::config::CspConfig,
+    /// HSTS, Permissions-Policy, Cross-Origin-Opener-Policy and framing.
+    pub security_headers: crate::config::SecurityHeadersConfig,
     pub dashboard_origin: Option<String>,
+    /// Further origins allowed alongside `dashboard_origin`.
+    pub dashboard_origins: Vec<String>,
+    /// Custom dashboard build served in preference to the embedded one.
+    pub dashboard_dir: Option<std::path::PathBuf>,
     pub query_cache: crate::query::cache::QueryCache,
+    /// Set while a [cache warming](crate::api::warm) run is in progress, so
+    /// flushes in quick succession do not queue up runs.
+    pub cache_warming: AtomicBool,
+    /// Slow analytics queries, served by `GET /api/admin/slow-queries`.
+    pub slow_queries: Arc<crate::query::slow::SlowQueryLog>,
+    /// DuckDB memory use, reported by `/health/detailed` and `/metrics`.
+    pub duckdb_usage: crate::storage::resources::ResourceMonitor,
+    /// Shadow runs of stats reports, served by `GET /api/admin/shadow-queries`.
+    pub shadow_queries: crate::query::shadow::ShadowQueries,
+    /// Summaries served by `GET /api/public/summary`, kept for
+    /// `public_summary_cache_secs`.
+    pub public_summary_cache: crate::query::cache::QueryCache,
     pub rate_limiter: crate::ingest::ratelimit::RateLimiter,
+    /// Per-session / per-API-key limiter for `/api/stats/*`.
+    pub stats_rate_limiter: crate::ingest::ratelimit::RateLimiter,
     /// Per-IP login attempt tracker for brute-force protection.
     pub login_attempt_tracker: LoginAttemptTracker,
     /// Running total of events successfully buffered since startup.
     pub events_ingested_total: Arc<AtomicU64>,
     /// Running total of Parquet flush failures since startup.
     pub flush_failures_total: Arc<AtomicU64>,
+    /// Periodic flushes that failed in a row, each after its retries.
+    pub flush_consecutive_failures: Arc<AtomicU64>,
     /// Running total of rate-limited ingest requests since startup.
     pub rate_limit_rejections_total: Arc<AtomicU64>,
+    /// Per-site ingestion counters, served by `GET /api/admin/ingest-stats`.
+    pub ingest_stats: IngestStats,
+    /// Running total of rate-limited stats requests since startup.
+    pub stats_rate_limit_rejections_total: Arc<AtomicU64>,
+    /// Recently seen client event IDs, for dropping duplicates.
+    pub dedupe: crate::ingest::dedupe::Deduplicator,
+    /// How far a client-supplied event time may be from the time of
+    /// receipt.  Zero refuses any.
+    pub max_event_time_skew: TimeDelta,
+    /// Outcomes of requests sent with an `Idempotency-Key`, for replays.
+    pub idempotency: IdempotencyKeys,
+    /// Running total of duplicate events dropped since startup.
+    pub duplicate_events_total: Arc<AtomicU64>,
     /// Running total of failed login attempts since startup.
     pub login_failures_total: Arc<AtomicU64>,
     /// Optional bearer token required to access the `/metrics` endpoint.
@@ -125,13 +265,52 @@ pub struct AppState {
     pub geoip_precision: String,
     /// Path to the events directory; needed by the GDPR erasure endpoint.
     pub events_dir: std::path::PathBuf,
+    /// Per-site settings from the config file's site registry.
+    pub sites: HashMap<String, SiteConfig>,
+    /// Echo the parsed payload in ingest validation errors.
+    pub debug_ingest: bool,
+    /// Referral spam domains; also counts the events it drops.
+    pub spam_blocklist: Arc<crate::ingest::spam::SpamBlocklist>,
+    /// Events caught by a site's country/continent blocking rules.
+    pub geo_blocked_events_total: Arc<AtomicU64>,
+    /// Custom transforms run on each enriched event before it is buffered.
+    pub transforms: crate::ingest::transform::IngestTransforms,
+    /// Queue feeding the ingest workers.  `None` enriches and buffers events
+    /// in the request handler instead (`ingest_workers = 0`).
+    pub ingest_queue: Option<IngestQueue>,
+    /// Mirrors buffered events to the `[forwarding]` sink, if configured.
+    pub forwarder: Option<crate::ingest::forward::Forwarder>,
+    /// Liveness of the supervised background tasks.
+    pub tasks: crate::watchdog::TaskRegistry,
+    /// Set by `POST /api/admin/drain` to hand over to a new binary.
+    pub drain: crate::upgrade::Drain,
+}
+
+impl AppState {
+    /// Origins allowed to call the dashboard routes cross-origin with
+    /// credentials: `dashboard_origin`, then `dashboard_origins`.  Empty
+    /// keeps them same-origin.
+    pub fn allowed_origins(&self) -> Vec<&str> {
+        self.dashboard_origin
+            .iter()
+            .chain(&self.dashboard_origins)
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Lock the database connection for analytics queries, timed into this
+    /// server's slow query log.
+    pub fn query_conn(&self) -> crate::query::QueryConn<'_> {
+        crate::query::QueryConn::lock(self.buffer.conn(), &self.slow_queries)
+    }
 }
 
 /// Query parameters for the GET /api/event pixel-tracking endpoint.
 ///
 /// Subset of `EventPayload` — props and revenue fields are omitted because
 /// they cannot be safely validated in a plain query string.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct PixelParams {
     /// Site domain (e.g., "example.com")
     #[serde(rename = "d")]
@@ -154,12 +333,55 @@ fn default_event_name() -> String {
     "pageview".to_string()
 }
 
-/// Shared event-processing logic called by both the POST and GET endpoints.
+/// Event name recorded by the email open pixel.
+pub const EMAIL_OPEN_EVENT: &str = "email_open";
+
+/// Query parameters for the GET /api/pixel/{site_id}.gif email open pixel.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct EmailPixelParams {
+    /// Campaign name, stored as `utm_campaign`.
+    #[serde(rename = "c")]
+    pub campaign: Option<String>,
+}
+
+impl EmailPixelParams {
+    /// The pixel event recorded for one open: an [`EMAIL_OPEN_EVENT`] whose
+    /// URL carries `utm_medium=email` and the campaign, so the usual UTM
+    /// parsing stores them.
+    pub fn into_pixel_params(self, site_id: &str) -> PixelParams {
+        let mut url = "/?utm_medium=email".to_string();
+        if let Some(campaign) = self.campaign.filter(|c| !c.is_empty()) {
+            url.push_str("&utm_campaign=");
+            url.push_str(&percent_encode(&campaign));
+        }
+        PixelParams {
+            domain: site_id.to_string(),
+            name: EMAIL_OPEN_EVENT.to_string(),
+            url,
+            referrer: None,
+            screen_width: None,
+        }
+    }
+}
+
+/// Percent-encode every byte of `s` except unreserved URL characters.
+pub fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            out.push(char::from(byte));
+        } else {
+            let _ = write!(out, "%{byte:02X}");
+        }
+    }
+    out
+}
+
+/// Shared event-processing logic for the GET pixel endpoints.
 ///
-/// Validates the payload, applies rate limiting, builds an `Event`, and pushes
-/// it into the buffer.  Returns `false` if the event should be silently ignored
-/// (bot filtered, origin blocked, rate limited).
-#[allow(clippy::too_many_lines)]
+/// Runs the same guards as `ingest_event` and submits the event for
+/// enrichment.  Failures are not reported: the pixel is served either way.
 pub async fn process_pixel_event(state: &Arc<AppState>, headers: &HeaderMap, params: PixelParams) {
     // Convert PixelParams into the canonical EventPayload shape so we can
     // call the same validation / enrichment path.
@@ -172,216 +394,526 @@ pub async fn process_pixel_event(state: &Arc<AppState>, headers: &HeaderMap, par
         props: None,
         revenue_amount: None,
         revenue_currency: None,
+        event_id: None,
+        prev_pathname: None,
+        user_id: None,
+        timestamp: None,
     };
 
-    // Reuse the same guard sequence as ingest_event: origin, basic validation,
-    // length, site_id char-set, rate limit, bot filter.
+    // Reuse the same guard sequence as ingest_event: origin, field validation,
+    // rate limit.
     let origin = headers.get("origin").and_then(|v| v.to_str().ok());
     if !crate::api::auth::validate_origin(origin, &state.allowed_sites) {
         return;
     }
-    if payload.domain.is_empty() || payload.name.is_empty() || payload.url.is_empty() {
-        return;
-    }
-    if payload.domain.len() > 256
-        || payload.name.len() > 256
-        || payload.url.len() > 2048
-        || payload.referrer.as_ref().is_some_and(|r| r.len() > 2048)
-    {
+    let errors = validate_payload(&payload);
+    if !errors.is_empty() {
+        state
+            .ingest_stats
+            .record(stats_site(&payload, &errors), IngestOutcome::Rejected);
         return;
     }
-    if crate::api::stats::validate_site_id(&payload.domain).is_err() {
+    if missing_ingest_key(state, headers, &payload.domain) {
         return;
     }
     if !state.rate_limiter.check(&payload.domain) {
         state
             .rate_limit_rejections_total
             .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        state
+            .ingest_stats
+            .record(&payload.domain, IngestOutcome::RateLimited);
         return;
     }
 
-    let ip = extract_ip(headers);
-    let user_agent = headers
-        .get("user-agent")
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("");
-    let parsed_ua = useragent::parse_user_agent(user_agent);
-    if state.filter_bots && parsed_ua.is_bot {
-        return;
+    let site_id = payload.domain.clone();
+    if submit(state, RawEvent::new(payload, headers)).await.is_ok() {
+        state.ingest_stats.record(&site_id, IngestOutcome::Accepted);
     }
+}
 
-    let today = Utc::now().date_naive();
-    let salt = visitor_id::daily_salt(&state.secret, today);
-    // Privacy: suppress_visitor_id replaces the deterministic HMAC with a random UUID
-    // so that no cross-request linkability is possible.
-    let vid = if state.suppress_visitor_id {
-        uuid::Uuid::new_v4().to_string()
-    } else {
-        visitor_id::generate_visitor_id(&ip, user_agent, &salt)
-    };
-    let (utm_source, utm_medium, utm_campaign, utm_content, utm_term) =
-        parse_utm_params(&payload.url);
-    let referrer_source = payload
-        .referrer
-        .as_deref()
-        .and_then(extract_referrer_source);
-    let geo_info = state.geoip.lookup(&ip);
-    // Privacy: apply geoip_precision — strip city/region fields as configured.
-    let (country_code, region, city) = match state.geoip_precision.as_str() {
-        "none" => (None, None, None),
-        "country" => (geo_info.country_code, None, None),
-        "region" => (geo_info.country_code, geo_info.region, None),
-        _ => (geo_info.country_code, geo_info.region, geo_info.city), // "city" (default)
-    };
-    // Privacy: suppress_screen_size omits both screen width and derived device type.
-    let (screen_size, device_type) = if state.suppress_screen_size {
-        (None, None)
-    } else {
-        (
-            payload.screen_width.map(|w| format!("{w}")),
-            payload.screen_width.map(classify_device),
-        )
-    };
-    let pathname = sanitize_pathname(&payload.url);
-    // Privacy: round_timestamps reduces precision to the nearest hour.
-    let timestamp = if state.round_timestamps {
-        round_to_hour(Utc::now())
-    } else {
-        Utc::now().naive_utc()
-    };
-    // Privacy: strip_referrer_query removes query strings and fragments from referrer URLs.
-    let referrer = payload.referrer.as_deref().map(|r| {
-        let r = if state.strip_referrer_query {
-            strip_url_query_and_fragment(r)
-        } else {
-            r
-        };
-        sanitize_string(r, 2048)
-    });
-    // Privacy: suppress_browser_version / suppress_os_version reduce fingerprinting surface.
-    let browser_version = if state.suppress_browser_version {
-        None
-    } else {
-        parsed_ua.browser_version
-    };
-    let os_version = if state.suppress_os_version {
-        None
-    } else {
-        parsed_ua.os_version
-    };
-
-    let event = Event {
-        site_id: sanitize_string(&payload.domain, 256),
-        visitor_id: vid,
-        timestamp,
-        event_name: sanitize_string(&payload.name, 256),
-        pathname,
-        hostname: Some(sanitize_string(&payload.domain, 256)),
-        referrer,
-        referrer_source,
-        utm_source,
-        utm_medium,
-        utm_campaign,
-        utm_content,
-        utm_term,
-        browser: parsed_ua.browser,
-        browser_version,
-        os: parsed_ua.os,
-        os_version,
-        device_type,
-        screen_size,
-        country_code,
-        region,
-        city,
-        props: None,
-        revenue_amount: None,
-        revenue_currency: None,
-    };
-
-    let state2 = Arc::clone(state);
-    match tokio::task::spawn_blocking(move || state2.buffer.push(event)).await {
-        Ok(Ok(_)) => {
-            state
-                .events_ingested_total
-                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-        }
-        Ok(Err(e)) => tracing::error!(error = %e, "Failed to buffer pixel event"),
-        Err(e) => tracing::error!(error = %e, "Pixel event buffer task panicked"),
+/// Size of an ingestion request body as sent, before its `Content-Encoding`
+/// is decoded.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestBodySize(pub u64);
+
+/// Middleware recording the request's `Content-Length` as a
+/// [`RequestBodySize`] extension.  Runs ahead of request decompression, which
+/// removes the header.
+pub async fn record_body_size(mut request: Request) -> Request {
+    if let Some(size) = request
+        .headers()
+        .get(axum::http::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse().ok())
+    {
+        request.extensions_mut().insert(RequestBodySize(size));
     }
+    request
 }
 
 /// POST /api/event — Ingestion endpoint.
 ///
-/// Receives events from the tracking script, generates a privacy-safe visitor ID,
-/// and pushes the event into the buffer.
-#[allow(clippy::too_many_lines)]
+/// Receives events from the tracking script, runs the cheap checks (origin,
+/// fields, API key, rate limit, dedupe) and submits the event for enrichment
+/// on the ingest workers.  Responses to rate-limited sites carry
+/// `RateLimit-*` headers, plus `Retry-After` on 429.  A full ingest queue
+/// gets 503 with `Retry-After`.
+///
+/// Malformed or invalid payloads get a JSON error body listing each bad field;
+/// with `debug_ingest` enabled it also echoes the payload as parsed.
+///
+/// A retry of a request sent with an `Idempotency-Key` header is answered
+/// with the original status and `Idempotent-Replayed: true` instead of being
+/// ingested again.
+///
+/// Bodies may be sent with `Content-Encoding: gzip` or `br`; they are
+/// decompressed before parsing, and the body size limit applies to the
+/// decompressed payload.
+#[utoipa::path(
+    post,
+    path = "/api/event",
+    tag = openapi::INGESTION,
+    summary = "Record an event",
+    request_body = EventPayload,
+    params(
+        (
+            "Idempotency-Key" = Option<String>,
+            Header,
+            description = "Answer retries with the first status instead of recording again.",
+        ),
+    ),
+    responses((status = 202, description = "Accepted")),
+    security(()),
+)]
 pub async fn ingest_event(
     State(state): State<Arc<AppState>>,
     headers: HeaderMap,
-    Json(payload): Json<EventPayload>,
-) -> impl IntoResponse {
+    body_size: Option<Extension<RequestBodySize>>,
+    payload: Result<Json<EventPayload>, JsonRejection>,
+) -> Response {
+    let Json(payload) = match payload {
+        Ok(payload) => payload,
+        Err(rejection) => return ApiError::from(rejection).into_response(),
+    };
+
     // Validate origin against allowed sites
     let origin = headers.get("origin").and_then(|v| v.to_str().ok());
     if !crate::api::auth::validate_origin(origin, &state.allowed_sites) {
-        return StatusCode::FORBIDDEN;
-    }
-
-    // Validate required fields
-    if payload.domain.is_empty() || payload.name.is_empty() || payload.url.is_empty() {
-        return StatusCode::BAD_REQUEST;
+        return StatusCode::FORBIDDEN.into_response();
     }
 
-    // Length validation before further processing (and before rate limiting) to
-    // prevent allocating resources for clearly oversized inputs.
-    if payload.domain.len() > 256
-        || payload.name.len() > 256
-        || payload.url.len() > 2048
-        || payload.referrer.as_ref().is_some_and(|r| r.len() > 2048)
-        || payload.props.as_ref().is_some_and(|p| p.len() > 4096)
-    {
-        return StatusCode::BAD_REQUEST;
-    }
-
-    // Character-set validation for domain BEFORE rate limiting.
+    // Field validation (required fields, lengths, site_id character set) runs
+    // BEFORE rate limiting.
     //
     // Without this ordering an invalid domain (e.g. "my site.com" with a space)
     // would create a rate-limiter bucket for the invalid string and then return
     // 400 — wasting bucket memory for strings that can never be valid site IDs.
-    if crate::api::stats::validate_site_id(&payload.domain).is_err() {
-        return StatusCode::BAD_REQUEST;
+    let mut errors = validate_payload(&payload);
+    errors.extend(check_event_time(
+        &payload,
+        state.max_event_time_skew,
+        Utc::now(),
+    ));
+    let site_id = stats_site(&payload, &errors).to_string();
+    if let Some(Extension(RequestBodySize(bytes))) = body_size {
+        state.ingest_stats.add_bytes(&site_id, bytes);
+    }
+    if !errors.is_empty() {
+        state.ingest_stats.record(&site_id, IngestOutcome::Rejected);
+        return validation_error(errors, state.debug_ingest.then_some(&payload));
+    }
+
+    if missing_ingest_key(&state, &headers, &payload.domain) {
+        return ApiError::Unauthorized("This site requires a write API key".to_string())
+            .into_response();
     }
 
+    // Replays are answered before rate limiting, so a retried import does
+    // not spend the site's tokens twice.
+    let pending = match idempotency_key(&headers) {
+        Ok(None) => None,
+        Ok(Some(key)) => match state.idempotency.claim(&site_id, key) {
+            Claim::New(pending) => Some(pending),
+            Claim::InFlight => {
+                return ApiError::Conflict(
+                    "A request with this Idempotency-Key is still being processed".to_string(),
+                )
+                .into_response();
+            }
+            Claim::Replay(status) => {
+                return (status, [("idempotent-replayed", "true")]).into_response();
+            }
+        },
+        Err(e) => return e.into_response(),
+    };
+    let response = accept_event(&state, &headers, payload, &site_id).await;
+    if let Some(pending) = pending {
+        pending.complete(response.status());
+    }
+    response
+}
+
+/// The `Idempotency-Key` header: 1–255 visible ASCII characters.
+fn idempotency_key(headers: &HeaderMap) -> Result<Option<&str>, ApiError> {
+    let Some(value) = headers.get("idempotency-key") else {
+        return Ok(None);
+    };
+    match value.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LEN => Ok(Some(key)),
+        _ => Err(ApiError::invalid_field(
+            "Idempotency-Key",
+            format!("Idempotency-Key must be 1-{MAX_KEY_LEN} visible ASCII characters"),
+        )),
+    }
+}
+
+/// Rate limit, deduplicate and submit a validated event for `site_id`.
+async fn accept_event(
+    state: &Arc<AppState>,
+    headers: &HeaderMap,
+    payload: EventPayload,
+    site_id: &str,
+) -> Response {
     // Rate limiting per site (only reached for well-formed site IDs)
-    if !state.rate_limiter.check(&payload.domain) {
+    let rate_limit = state.rate_limiter.acquire(&payload.domain);
+    // Empty when the site is not rate limited.
+    let rate_headers = rate_limit
+        .map(|status| status.headers())
+        .unwrap_or_default();
+    if rate_limit.is_some_and(|status| !status.allowed) {
         state
             .rate_limit_rejections_total
             .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-        return StatusCode::TOO_MANY_REQUESTS;
+        state
+            .ingest_stats
+            .record(site_id, IngestOutcome::RateLimited);
+        return (StatusCode::TOO_MANY_REQUESTS, rate_headers).into_response();
     }
 
-    // Extract client IP and User-Agent for visitor ID
-    let ip = extract_ip(&headers);
-    let user_agent = headers
-        .get("user-agent")
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("");
+    // Drop retries and double-fires of an event already accepted.  The reply
+    // is still 202 so the client stops retrying.
+    if let Some(event_id) = &payload.event_id {
+        if !state.dedupe.first_seen(&payload.domain, event_id) {
+            state
+                .duplicate_events_total
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            return (StatusCode::ACCEPTED, rate_headers).into_response();
+        }
+    }
+
+    // UA parsing, bot filtering, GeoIP and the rest of enrichment happen
+    // after the request is queued; see `store_event`.
+    let dedupe_key = payload
+        .event_id
+        .clone()
+        .map(|event_id| (payload.domain.clone(), event_id));
+    match submit(state, RawEvent::new(payload, headers)).await {
+        Ok(()) => {
+            state.ingest_stats.record(site_id, IngestOutcome::Accepted);
+            (StatusCode::ACCEPTED, rate_headers).into_response()
+        }
+        Err(status) => {
+            // The client will retry a rejected event, so it must not be
+            // taken for a duplicate.
+            if let Some((site_id, event_id)) = dedupe_key {
+                state.dedupe.forget(&site_id, &event_id);
+            }
+            let mut headers = rate_headers;
+            if status == StatusCode::SERVICE_UNAVAILABLE {
+                headers.insert(
+                    axum::http::header::RETRY_AFTER,
+                    axum::http::HeaderValue::from_static("1"),
+                );
+            }
+            (status, headers).into_response()
+        }
+    }
+}
 
-    // Parse User-Agent for browser/OS information and bot detection
-    let parsed_ua = useragent::parse_user_agent(user_agent);
+/// Hand `raw` to the ingest workers, or enrich and buffer it on a blocking
+/// thread when there are none (`ingest_workers = 0`).
+///
+/// Errors with 503 when the ingest queue or the event buffer is full, or 500
+/// when the event could not be buffered.  An event shed as low priority
+/// counts as submitted: the client should not retry it.
+pub async fn submit(state: &Arc<AppState>, raw: RawEvent) -> Result<(), StatusCode> {
+    if state.buffer.check_capacity().is_err() {
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    if let Some(queue) = &state.ingest_queue {
+        return match queue.try_enqueue(raw) {
+            Enqueued::Queued | Enqueued::Shed => Ok(()),
+            Enqueued::Full => Err(StatusCode::SERVICE_UNAVAILABLE),
+        };
+    }
 
-    // Filter bot traffic if configured
+    // Store the event on a blocking thread so that a threshold-triggered
+    // flush (which acquires the DuckDB mutex and writes Parquet) does not
+    // hold a Tokio worker thread.
+    let state2 = Arc::clone(state);
+    match tokio::task::spawn_blocking(move || store_event(&state2, &raw)).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(BufferError::Full)) => Err(StatusCode::SERVICE_UNAVAILABLE),
+        Ok(Err(e)) => {
+            tracing::error!(error = %e, "Failed to buffer event");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Event buffer task panicked");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Enrich a submitted event and push it into the buffer.
+///
+/// Enrichment is User-Agent parsing and bot filtering, GeoIP lookup and
+/// blocking, [`build_event`], and the ingest transforms.  Events filtered out
+/// along the way are dropped silently.
+///
+/// Runs on an ingest worker, or on a blocking thread with `ingest_workers =
+/// 0`; it blocks while a threshold-triggered flush writes Parquet.
+pub fn store_event(state: &AppState, raw: &RawEvent) -> Result<(), BufferError> {
+    let parsed_ua = useragent::parse_user_agent(&raw.user_agent);
     if state.filter_bots && parsed_ua.is_bot {
-        return StatusCode::ACCEPTED;
+        state
+            .ingest_stats
+            .record(&raw.payload.domain, IngestOutcome::BotFiltered);
+        return Ok(());
+    }
+
+    let salt = visitor_id::daily_salt(&state.secret, raw.timestamp().date_naive());
+    let geo_info = state.geoip.lookup(&raw.ip);
+    let blocked = geo_block(state, &raw.payload.domain, &geo_info);
+    if blocked == Some(GeoBlockAction::Drop) {
+        return Ok(());
+    }
+    let mut event = build_event(state, raw, parsed_ua, geo_info, &salt);
+    if blocked == Some(GeoBlockAction::Segregate) {
+        event.site_id.push_str(GEO_RESTRICTED_SUFFIX);
+    }
+    if !state.transforms.apply(&mut event) {
+        return Ok(());
+    }
+
+    let forwarded = state
+        .forwarder
+        .as_ref()
+        .filter(|forwarder| forwarder.wants(&event))
+        .map(|forwarder| (forwarder, event.clone()));
+    match state.buffer.push(event) {
+        Err(BufferError::Shed) => return Ok(()),
+        result => {
+            result?;
+        }
+    }
+    if let Some((forwarder, event)) = forwarded {
+        forwarder.forward(event);
+    }
+    state
+        .events_ingested_total
+        .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    Ok(())
+}
+
+/// Error for an event time (`t`) further than `max_skew` from `now`.
+pub fn check_event_time(
+    payload: &EventPayload,
+    max_skew: TimeDelta,
+    now: DateTime<Utc>,
+) -> Option<FieldError> {
+    let timestamp = payload.timestamp?;
+    if max_skew.is_zero() {
+        return Some(FieldError::new("t", "t is not accepted by this server"));
+    }
+    ((timestamp - now).abs() > max_skew).then(|| {
+        FieldError::new(
+            "t",
+            format!(
+                "t must be within {} hours of the current time",
+                max_skew.num_hours()
+            ),
+        )
+    })
+}
+
+/// Site to count a request under in [`IngestStats`]: the payload's domain,
+/// or [`OTHER_SITES`] when the domain itself is invalid.
+pub fn stats_site<'a>(payload: &'a EventPayload, errors: &[FieldError]) -> &'a str {
+    if errors.iter().any(|e| e.field.as_deref() == Some("d")) {
+        OTHER_SITES
+    } else {
+        &payload.domain
+    }
+}
+
+/// 400 response listing `errors`, echoing `parsed` when given.
+fn validation_error(errors: Vec<FieldError>, parsed: Option<&EventPayload>) -> Response {
+    let (status, mut body) = ApiError::Validation {
+        status: StatusCode::BAD_REQUEST,
+        code: "invalid_field",
+        errors,
+    }
+    .status_and_body();
+    if let Some(payload) = parsed {
+        body["parsed"] = serde_json::to_value(payload).unwrap_or_default();
+    }
+    (status, Json(body)).into_response()
+}
+
+/// Response from `POST /api/event/validate`: the event as it would be
+/// stored, and whether it would be.
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Serialize, ToSchema)]
+pub struct EventValidation {
+    /// The enriched event.
+    #[schema(value_type = Object)]
+    pub event: Event,
+    /// The request's `Origin` is allowed for the site.
+    pub origin_allowed: bool,
+    /// The user agent is a known bot.
+    pub bot: bool,
+    /// What the site's geographic blocking does with the event, if it
+    /// applies.
+    pub geo_blocked: Option<GeoBlockAction>,
+    /// An ingest transform dropped the event.
+    pub transform_dropped: bool,
+    /// The site requires a write API key and none was sent.
+    pub ingest_key_missing: bool,
+    /// None of the above stops the event from being stored.
+    pub would_store: bool,
+}
+
+/// Salt for dry-run visitor IDs.  Real salts are hex HMAC digests, so a
+/// visitor ID derived from this can never collide with a stored one.
+const DRY_RUN_SALT: &str = "dry-run";
+
+/// POST /api/event/validate — Dry-run an event through the ingest pipeline.
+///
+/// Runs the same validation and enrichment as `POST /api/event` (UA parsing,
+/// GeoIP, UTM and referrer parsing, privacy settings, ingest transforms) and
+/// returns the event that would be stored, without storing it or consuming
+/// rate-limit tokens.
+/// The visitor ID uses [`DRY_RUN_SALT`] instead of today's salt.
+#[utoipa::path(
+    post,
+    path = "/api/event/validate",
+    tag = openapi::INGESTION,
+    summary = "Dry-run an event without storing it",
+    request_body = EventPayload,
+    responses(
+        (status = 200, description = "The event that would be stored", body = EventValidation),
+    ),
+)]
+pub async fn validate_event(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    payload: Result<Json<EventPayload>, JsonRejection>,
+) -> Response {
+    let Json(payload) = match payload {
+        Ok(payload) => payload,
+        Err(rejection) => return ApiError::from(rejection).into_response(),
+    };
+    let mut errors = validate_payload(&payload);
+    errors.extend(check_event_time(
+        &payload,
+        state.max_event_time_skew,
+        Utc::now(),
+    ));
+    if !errors.is_empty() {
+        return validation_error(errors, Some(&payload));
     }
 
-    let today = Utc::now().date_naive();
-    let salt = visitor_id::daily_salt(&state.secret, today);
+    let origin = headers.get("origin").and_then(|v| v.to_str().ok());
+    let origin_allowed = crate::api::auth::validate_origin(origin, &state.allowed_sites);
+    let ingest_key_missing = missing_ingest_key(&state, &headers, &payload.domain);
+    let raw = RawEvent::new(payload, &headers);
+    let parsed_ua = useragent::parse_user_agent(&raw.user_agent);
+    let is_bot = parsed_ua.is_bot;
+    let geo_info = state.geoip.lookup(&raw.ip);
+    let blocked = geo_block_action(&state, &raw.payload.domain, &geo_info);
+    let mut event = build_event(&state, &raw, parsed_ua, geo_info, DRY_RUN_SALT);
+    if blocked == Some(GeoBlockAction::Segregate) {
+        event.site_id.push_str(GEO_RESTRICTED_SUFFIX);
+    }
+    let transform_kept = state.transforms.apply(&mut event);
+    let bot_dropped = state.filter_bots && is_bot;
+    let geo_dropped = blocked == Some(GeoBlockAction::Drop);
+
+    Json(EventValidation {
+        event,
+        origin_allowed,
+        bot: is_bot,
+        geo_blocked: blocked,
+        transform_dropped: !transform_kept,
+        ingest_key_missing,
+        would_store: origin_allowed
+            && transform_kept
+            && !(ingest_key_missing || bot_dropped || geo_dropped),
+    })
+    .into_response()
+}
+
+/// Whether `site_id` only accepts events with a write API key and the request
+/// does not carry a valid one.
+fn missing_ingest_key(state: &AppState, headers: &HeaderMap, site_id: &str) -> bool {
+    state
+        .sites
+        .get(site_id)
+        .is_some_and(|s| s.require_ingest_key)
+        && !crate::api::auth::has_write_key(state, headers, site_id)
+}
+
+/// The action for traffic from `geo_info` under `site_id`'s geographic
+/// blocking rules, or `None` if it is not blocked.  Rules apply to the raw
+/// GeoIP result, before `geoip_precision` reductions.
+fn geo_block_action(state: &AppState, site_id: &str, geo_info: &GeoInfo) -> Option<GeoBlockAction> {
+    let site = state.sites.get(site_id)?;
+    site.geo_blocked(
+        geo_info.country_code.as_deref(),
+        geo_info.continent_code.as_deref(),
+    )
+    .then_some(site.geo_block_action)
+}
+
+/// [`geo_block_action`], counting blocked events in
+/// `geo_blocked_events_total`.
+fn geo_block(state: &AppState, site_id: &str, geo_info: &GeoInfo) -> Option<GeoBlockAction> {
+    let action = geo_block_action(state, site_id, geo_info);
+    if action.is_some() {
+        state
+            .geo_blocked_events_total
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+    action
+}
+
+/// Enrich a validated payload into the `Event` that is stored: visitor ID,
+/// UTM and referrer parsing, and the configured privacy reductions applied
+/// to `geo_info`.
+///
+/// PRIVACY: `raw.ip` is used for the visitor hash only and is never stored.
+#[allow(clippy::too_many_lines)]
+fn build_event(
+    state: &AppState,
+    raw: &RawEvent,
+    parsed_ua: useragent::ParsedUserAgent,
+    geo_info: GeoInfo,
+    salt: &str,
+) -> Event {
+    let payload = &raw.payload;
     // Privacy: suppress_visitor_id replaces the deterministic HMAC with a random UUID.
     let vid = if state.suppress_visitor_id {
         uuid::Uuid::new_v4().to_string()
     } else {
-        visitor_id::generate_visitor_id(&ip, user_agent, &salt)
+        visitor_id::generate_visitor_id(&raw.ip, &raw.user_agent, salt)
     };
+    // Privacy: suppress_visitor_id also drops the cross-device user hash.
+    let user_id = payload
+        .user_id
+        .as_deref()
+        .filter(|_| !state.suppress_visitor_id)
+        .map(|uid| visitor_id::hash_user_id(&state.secret, &payload.domain, uid));
 
     // Parse UTM parameters from URL
     let (utm_source, utm_medium, utm_campaign, utm_content, utm_term) =
@@ -393,8 +925,6 @@ pub async fn ingest_event(
         .as_deref()
         .and_then(extract_referrer_source);
 
-    // Look up geographic information from IP (PRIVACY: IP used only for lookup, never stored)
-    let geo_info = state.geoip.lookup(&ip);
     // Privacy: apply geoip_precision — strip city/region fields as configured.
     let (country_code, region, city) = match state.geoip_precision.as_str() {
         "none" => (None, None, None),
@@ -413,14 +943,20 @@ pub async fn ingest_event(
         )
     };
 
-    // Sanitize pathname
-    let pathname = sanitize_pathname(&payload.url);
+    // Sanitize pathname, applying the site's routing and normalization rules
+    let site = state.sites.get(&payload.domain);
+    let pathname = sanitize_pathname(&payload.url, site);
+    let prev_pathname = payload
+        .prev_pathname
+        .as_deref()
+        .filter(|p| !p.is_empty())
+        .map(|p| sanitize_pathname(p, site));
 
     // Privacy: round_timestamps reduces precision to the nearest hour.
     let timestamp = if state.round_timestamps {
-        round_to_hour(Utc::now())
+        round_to_hour(raw.timestamp())
     } else {
-        Utc::now().naive_utc()
+        raw.timestamp().naive_utc()
     };
 
     // Privacy: strip_referrer_query removes query strings and fragments from referrer URLs.
@@ -445,7 +981,10 @@ pub async fn ingest_event(
         parsed_ua.os_version
     };
 
-    let event = Event {
+    // Typed custom dimensions declared for this site, pulled out of props.
+    let dimensions = extract_dimensions(site, payload.props.as_deref());
+
+    Event {
         site_id: sanitize_string(&payload.domain, 256),
         visitor_id: vid,
         timestamp,
@@ -474,28 +1013,11 @@ pub async fn ingest_event(
             .revenue_currency
             .as_deref()
             .map(|c| sanitize_string(c, 3)),
-    };
-
-    // Push the event on a blocking thread so that a threshold-triggered flush
-    // (which acquires the DuckDB mutex and writes Parquet) does not hold a Tokio
-    // worker thread.  The counter is incremented from the async side after the
-    // blocking task completes.
-    let state2 = Arc::clone(&state);
-    match tokio::task::spawn_blocking(move || state2.buffer.push(event)).await {
-        Ok(Ok(_)) => {
-            state
-                .events_ingested_total
-                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-            StatusCode::ACCEPTED
-        }
-        Ok(Err(e)) => {
-            tracing::error!(error = %e, "Failed to buffer event");
-            StatusCode::INTERNAL_SERVER_ERROR
-        }
-        Err(e) => {
-            tracing::error!(error = %e, "Event buffer task panicked");
-            StatusCode::INTERNAL_SERVER_ERROR
-        }
+        dimensions,
+        prev_pathname,
+        user_id,
+        device_brand: parsed_ua.device_brand,
+        device_model: parsed_ua.device_model,
     }
 }
 
@@ -512,7 +1034,7 @@ pub fn extract_ip(headers: &HeaderMap) -> String {
 }
 
 /// Parse UTM parameters from a URL string.
-fn parse_utm_params(url: &str) -> UtmParams {
+pub fn parse_utm_params(url: &str) -> UtmParams {
     let query_start = url.find('?');
     let query = match query_start {
         Some(pos) => &url[pos + 1..],
@@ -546,14 +1068,9 @@ fn parse_utm_params(url: &str) -> UtmParams {
     (source, medium, campaign, content, term)
 }
 
-/// Extract a simplified referrer source name from a referrer URL.
-fn extract_referrer_source(referrer: &str) -> Option<String> {
-    if referrer.is_empty() {
-        return None;
-    }
-
-    // Extract hostname from referrer
-    let host = referrer
+/// Hostname part of a referrer URL: scheme, path and port removed.
+pub fn referrer_host(referrer: &str) -> &str {
+    referrer
         .strip_prefix("https://")
         .or_else(|| referrer.strip_prefix("http://"))
         .unwrap_or(referrer)
@@ -562,8 +1079,16 @@ fn extract_referrer_source(referrer: &str) -> Option<String> {
         .unwrap_or(referrer)
         .split(':')
         .next()
-        .unwrap_or(referrer);
+        .unwrap_or(referrer)
+}
+
+/// Extract a simplified referrer source name from a referrer URL.
+pub fn extract_referrer_source(referrer: &str) -> Option<String> {
+    if referrer.is_empty() {
+        return None;
+    }
 
+    let host = referrer_host(referrer);
     if host.is_empty() {
         return None;
     }
@@ -594,8 +1119,40 @@ fn extract_referrer_source(referrer: &str) -> Option<String> {
     Some(source.to_string())
 }
 
+/// Extract the site's declared custom dimensions from the props JSON.
+///
+/// Values are stored as strings in their slot; a value whose JSON type does not
+/// match the declared type is dropped rather than coerced.
+fn extract_dimensions(
+    site: Option<&SiteConfig>,
+    props: Option<&str>,
+) -> [Option<String>; MAX_CUSTOM_DIMENSIONS] {
+    let mut out = [const { None }; MAX_CUSTOM_DIMENSIONS];
+    let (Some(site), Some(props)) = (site, props) else {
+        return out;
+    };
+    if site.custom_dimensions.is_empty() {
+        return out;
+    }
+    let Ok(serde_json::Value::Object(map)) = serde_json::from_str::<serde_json::Value>(props)
+    else {
+        return out;
+    };
+    for (slot, dim) in out.iter_mut().zip(&site.custom_dimensions) {
+        *slot = match (dim.kind, map.get(&dim.name)) {
+            (DimensionType::String, Some(serde_json::Value::String(v))) => {
+                Some(sanitize_string(v, 256))
+            }
+            (DimensionType::Number, Some(serde_json::Value::Number(v))) => Some(v.to_string()),
+            (DimensionType::Bool, Some(serde_json::Value::Bool(v))) => Some(v.to_string()),
+            _ => None,
+        };
+    }
+    out
+}
+
 /// Classify device type based on screen width.
-fn classify_device(width: u32) -> String {
+pub fn classify_device(width: u32) -> String {
     if width < 768 {
         "mobile".to_string()
     } else if width < 1024 {
@@ -605,8 +1162,16 @@ fn classify_device(width: u32) -> String {
     }
 }
 
-/// Extract pathname from URL, stripping query string and fragment.
-fn sanitize_pathname(url: &str) -> String {
+/// Extract pathname from URL, stripping query string and fragment and
+/// applying the site's path settings from the site registry:
+///
+/// - `hash_mode`: a `#/route` or `#!/route` fragment is kept as the rest of
+///   the path (`/app/#/users?tab=1` → `/app/users`).  Plain anchors like
+///   `#section` are still dropped.
+/// - Path normalization, see [`SiteConfig::normalize_path`].
+/// - `query_params` and `search_param`: these query parameters are kept,
+///   sorted, as `/search?page=2&q=shoes`.
+fn sanitize_pathname(url: &str, site: Option<&SiteConfig>) -> String {
     let path = url
         .strip_prefix("https://")
         .or_else(|| url.strip_prefix("http://"))
@@ -615,11 +1180,52 @@ fn sanitize_pathname(url: &str) -> String {
     let path = path.split('/').skip(1).collect::<Vec<_>>().join("/");
     let path = format!("/{path}");
 
-    // Remove query string and fragment
-    let path = path.split('?').next().unwrap_or(&path);
-    let path = path.split('#').next().unwrap_or(path);
+    // Split off query string and fragment
+    let (path, fragment) = path.split_once('#').unwrap_or((path.as_str(), ""));
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
 
-    sanitize_string(path, 2048)
+    let Some(site) = site else {
+        return sanitize_string(path, 2048);
+    };
+    let (mut path, route_query) = match hash_route(fragment).filter(|_| site.hash_mode) {
+        Some((route, route_query)) => (
+            site.normalize_path(&format!("{}{route}", path.trim_end_matches('/'))),
+            route_query,
+        ),
+        None => (site.normalize_path(path), ""),
+    };
+    let kept = kept_query_params(site, &[query, route_query]);
+    if !kept.is_empty() {
+        path.push('?');
+        path.push_str(&kept);
+    }
+    sanitize_string(&path, 2048)
+}
+
+/// The route of a `#/route` or `#!/route` fragment and the route's own query
+/// string.
+fn hash_route(fragment: &str) -> Option<(&str, &str)> {
+    let route = fragment.strip_prefix('!').unwrap_or(fragment);
+    let (route, query) = route.split_once('?').unwrap_or((route, ""));
+    (route.len() > 1 && route.starts_with('/')).then_some((route, query))
+}
+
+/// The `name=value` pairs of `queries` that the site keeps, sorted and joined
+/// with `&`.  Empty values and repeated pairs are dropped, and `+` in values
+/// is rewritten to `%20` so both spellings of a space store alike.
+fn kept_query_params(site: &SiteConfig, queries: &[&str]) -> String {
+    let mut pairs: Vec<String> = queries
+        .iter()
+        .flat_map(|query| query.split('&'))
+        .filter_map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            (!value.is_empty() && site.keeps_query_param(name))
+                .then(|| format!("{name}={}", value.replace('+', "%20")))
+        })
+        .collect();
+    pairs.sort_unstable();
+    pairs.dedup();
+    pairs.join("&")
 }
 
 /// Sanitize a string by truncating to max length and removing control characters.
@@ -635,6 +1241,47 @@ fn sanitize_string(input: &str, max_len: usize) -> String {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_validate_payload_reports_every_field() {
+        let payload = EventPayload {
+            domain: "my site.com".to_string(),
+            name: String::new(),
+            url: "/".repeat(3000),
+            referrer: None,
+            screen_width: None,
+            props: None,
+            revenue_amount: None,
+            revenue_currency: None,
+            event_id: None,
+            prev_pathname: None,
+            user_id: None,
+            timestamp: None,
+        };
+        let fields: Vec<_> = validate_payload(&payload)
+            .into_iter()
+            .filter_map(|e| e.field)
+            .collect();
+        assert_eq!(fields, vec!["n", "u", "d"]);
+    }
+
+    #[test]
+    fn test_check_event_time() {
+        let now = Utc::now();
+        let mut payload: EventPayload =
+            serde_json::from_str(r#"{"d": "a.com", "n": "pageview", "u": "/"}"#).unwrap();
+        assert!(check_event_time(&payload, TimeDelta::hours(48), now).is_none());
+
+        payload.timestamp = Some(now - TimeDelta::hours(47));
+        assert!(check_event_time(&payload, TimeDelta::hours(48), now).is_none());
+        payload.timestamp = Some(now + TimeDelta::hours(49));
+        let error = check_event_time(&payload, TimeDelta::hours(48), now).unwrap();
+        assert_eq!(error.field.as_deref(), Some("t"));
+        assert!(error.message.contains("48 hours"), "{}", error.message);
+
+        payload.timestamp = Some(now);
+        assert!(check_event_time(&payload, TimeDelta::zero(), now).is_some());
+    }
+
     #[test]
     fn test_extract_ip_from_x_forwarded_for() {
         let mut headers = HeaderMap::new();
@@ -706,6 +1353,52 @@ mod tests {
         assert_eq!(extract_referrer_source(""), None);
     }
 
+    #[test]
+    fn test_extract_dimensions_typed() {
+        let site = SiteConfig {
+            custom_dimensions: vec![
+                crate::config::CustomDimension {
+                    name: "plan".to_string(),
+                    kind: DimensionType::String,
+                },
+                crate::config::CustomDimension {
+                    name: "seats".to_string(),
+                    kind: DimensionType::Number,
+                },
+                crate::config::CustomDimension {
+                    name: "trial".to_string(),
+                    kind: DimensionType::Bool,
+                },
+            ],
+            ..SiteConfig::default()
+        };
+        let dims = extract_dimensions(
+            Some(&site),
+            Some(r#"{"plan":"pro","seats":12,"trial":false,"other":"x"}"#),
+        );
+        assert_eq!(dims[0].as_deref(), Some("pro"));
+        assert_eq!(dims[1].as_deref(), Some("12"));
+        assert_eq!(dims[2].as_deref(), Some("false"));
+        assert!(dims[3].is_none());
+    }
+
+    #[test]
+    fn test_extract_dimensions_type_mismatch_dropped() {
+        let site = SiteConfig {
+            custom_dimensions: vec![crate::config::CustomDimension {
+                name: "seats".to_string(),
+                kind: DimensionType::Number,
+            }],
+            ..SiteConfig::default()
+        };
+        let dims = extract_dimensions(Some(&site), Some(r#"{"seats":"twelve"}"#));
+        assert!(dims[0].is_none());
+        let dims = extract_dimensions(Some(&site), Some("not json"));
+        assert!(dims[0].is_none());
+        let dims = extract_dimensions(None, Some(r#"{"seats":3}"#));
+        assert!(dims[0].is_none());
+    }
+
     #[test]
     fn test_classify_device_mobile() {
         assert_eq!(classify_device(375), "mobile");
@@ -724,20 +1417,84 @@ mod tests {
     #[test]
     fn test_sanitize_pathname() {
         assert_eq!(
-            sanitize_pathname("https://example.com/about?ref=1#section"),
+            sanitize_pathname("https://example.com/about?ref=1#section", None),
             "/about"
         );
     }
 
     #[test]
     fn test_sanitize_pathname_root() {
-        assert_eq!(sanitize_pathname("https://example.com/"), "/");
+        assert_eq!(sanitize_pathname("https://example.com/", None), "/");
+    }
+
+    #[test]
+    fn test_sanitize_pathname_bare_path() {
+        // `pt` carries a pathname rather than a full URL.
+        assert_eq!(sanitize_pathname("/pricing?plan=pro", None), "/pricing");
+    }
+
+    #[test]
+    fn test_sanitize_pathname_hash_mode() {
+        let site = SiteConfig {
+            hash_mode: true,
+            ..SiteConfig::default()
+        };
+        let site = Some(&site);
+        assert_eq!(
+            sanitize_pathname("https://example.com/#/users/42", site),
+            "/users/42"
+        );
+        assert_eq!(
+            sanitize_pathname("https://example.com/app/#!/settings?tab=1", site),
+            "/app/settings"
+        );
+        // Plain anchors and an empty route are not routes.
+        assert_eq!(
+            sanitize_pathname("https://example.com/docs#install", site),
+            "/docs"
+        );
+        assert_eq!(
+            sanitize_pathname("https://example.com/app/#/", site),
+            "/app/"
+        );
+        // Without hash mode the fragment is dropped as before.
+        assert_eq!(
+            sanitize_pathname("https://example.com/#/users/42", None),
+            "/"
+        );
+    }
+
+    #[test]
+    fn test_sanitize_pathname_keeps_allowed_query_params() {
+        let site = SiteConfig {
+            query_params: vec!["page".to_string()],
+            search_param: Some("q".to_string()),
+            hash_mode: true,
+            ..SiteConfig::default()
+        };
+        let site = Some(&site);
+        assert_eq!(
+            sanitize_pathname(
+                "https://example.com/search?utm_source=x&q=red+shoes&page=2",
+                site
+            ),
+            "/search?page=2&q=red%20shoes"
+        );
+        // Empty values are dropped; a hash route's own query counts too.
+        assert_eq!(
+            sanitize_pathname("https://example.com/?page=#/list?page=3&tab=a", site),
+            "/list?page=3"
+        );
+        assert_eq!(
+            sanitize_pathname("https://example.com/search?q=shoes", None),
+            "/search"
+        );
     }
 
     #[test]
     fn test_sanitize_pathname_deep() {
         assert_eq!(
-            sanitize_pathname("https://example.com/blog/post/123"),
+            sanitize_pathname("https://example.com/blog/post/123", None),
             "/blog/post/123"
         );
     }
@@ -0,0 +1,211 @@
+use axum::http::StatusCode;
+use parking_lot::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Most keys remembered across all sites; the oldest are forgotten first.
+const MAX_KEYS: usize = 100_000;
+
+/// Longest `Idempotency-Key` accepted, in bytes.
+pub const MAX_KEY_LEN: usize = 255;
+
+/// What to do with a request carrying an idempotency key.
+#[derive(Debug)]
+pub enum Claim {
+    /// First use of the key: process the request, then
+    /// [`complete`](Pending::complete) it.
+    New(Pending),
+    /// A request with the key is still being processed.
+    InFlight,
+    /// The key was already used; answer with the original status.
+    Replay(StatusCode),
+}
+
+/// Remembers the outcome of ingestion requests sent with an
+/// `Idempotency-Key` header, per site, so a retried import is answered with
+/// the original result instead of being ingested twice.
+///
+/// A key is remembered for `window`, or until [`MAX_KEYS`] newer keys have
+/// been seen, whichever comes first. Keys are held in memory only: a restart
+/// forgets them, so a retry that reaches the restarted server is ingested
+/// again.
+#[derive(Clone)]
+pub struct IdempotencyKeys {
+    keys: Arc<Mutex<Keys>>,
+    window: Duration,
+}
+
+impl std::fmt::Debug for IdempotencyKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IdempotencyKeys")
+            .field("window", &self.window)
+            .finish_non_exhaustive()
+    }
+}
+
+type Key = (String, String);
+
+#[derive(Default)]
+struct Keys {
+    /// Keys in arrival order, oldest first.
+    order: VecDeque<(Instant, Key)>,
+    /// Status of each key's request; `None` while it is in flight.
+    results: HashMap<Key, Option<StatusCode>>,
+}
+
+impl Keys {
+    fn expire(&mut self, now: Instant, window: Duration) {
+        while let Some((seen, _)) = self.order.front() {
+            if now.duration_since(*seen) < window && self.order.len() <= MAX_KEYS {
+                break;
+            }
+            if let Some((_, key)) = self.order.pop_front() {
+                self.results.remove(&key);
+            }
+        }
+    }
+}
+
+impl IdempotencyKeys {
+    /// A window of zero disables idempotency keys.
+    pub fn new(window: Duration) -> Self {
+        Self {
+            keys: Arc::new(Mutex::new(Keys::default())),
+            window,
+        }
+    }
+
+    /// Claim `key` for a request to `site_id`.
+    pub fn claim(&self, site_id: &str, key: &str) -> Claim {
+        let entry = (site_id.to_string(), key.to_string());
+        if !self.window.is_zero() {
+            let now = Instant::now();
+            let mut keys = self.keys.lock();
+            keys.expire(now, self.window);
+            match keys.results.get(&entry) {
+                Some(Some(status)) => return Claim::Replay(*status),
+                Some(None) => return Claim::InFlight,
+                None => {
+                    keys.results.insert(entry.clone(), None);
+                    keys.order.push_back((now, entry.clone()));
+                }
+            }
+        }
+        Claim::New(Pending {
+            keys: self.clone(),
+            entry,
+            completed: false,
+        })
+    }
+
+    /// Record `status` for `entry`, or forget the entry when `status` is
+    /// `None` or asks the client to retry (429 and 5xx).
+    fn finish(&self, entry: &Key, status: Option<StatusCode>) {
+        if self.window.is_zero() {
+            return;
+        }
+        let mut keys = self.keys.lock();
+        match status {
+            Some(status)
+                if status != StatusCode::TOO_MANY_REQUESTS && !status.is_server_error() =>
+            {
+                if let Some(result) = keys.results.get_mut(entry) {
+                    *result = Some(status);
+                }
+            }
+            _ => {
+                if keys.results.remove(entry).is_some() {
+                    keys.order.retain(|(_, k)| k != entry);
+                }
+            }
+        }
+    }
+
+    /// Drop expired keys.
+    pub fn cleanup(&self) {
+        self.keys.lock().expire(Instant::now(), self.window);
+    }
+}
+
+/// A newly claimed key whose request is being processed.  Dropping it
+/// without [`complete`](Self::complete), as when the client disconnects,
+/// releases the key so a retry is processed.
+#[derive(Debug)]
+pub struct Pending {
+    keys: IdempotencyKeys,
+    entry: Key,
+    completed: bool,
+}
+
+impl Pending {
+    /// Record the status the request was answered with, for replays.
+    /// Statuses asking the client to retry (429 and 5xx) release the key
+    /// instead.
+    pub fn complete(mut self, status: StatusCode) {
+        self.keys.finish(&self.entry, Some(status));
+        self.completed = true;
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.keys.finish(&self.entry, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn complete(claim: Claim, status: StatusCode) {
+        let Claim::New(pending) = claim else {
+            panic!("expected a new claim, got {claim:?}");
+        };
+        pending.complete(status);
+    }
+
+    #[test]
+    fn test_replay_returns_original_status() {
+        let keys = IdempotencyKeys::new(Duration::from_secs(60));
+        let claim = keys.claim("a.com", "batch-1");
+        assert!(matches!(keys.claim("a.com", "batch-1"), Claim::InFlight));
+        complete(claim, StatusCode::ACCEPTED);
+        assert!(matches!(
+            keys.claim("a.com", "batch-1"),
+            Claim::Replay(StatusCode::ACCEPTED)
+        ));
+        // Keys are scoped per site.
+        assert!(matches!(keys.claim("b.com", "batch-1"), Claim::New(_)));
+    }
+
+    #[test]
+    fn test_retryable_status_releases_key() {
+        let keys = IdempotencyKeys::new(Duration::from_secs(60));
+        complete(keys.claim("a.com", "k"), StatusCode::SERVICE_UNAVAILABLE);
+        complete(keys.claim("a.com", "k"), StatusCode::TOO_MANY_REQUESTS);
+        // Abandoned without completing.
+        drop(keys.claim("a.com", "k"));
+        assert!(keys.keys.lock().order.is_empty());
+        assert!(matches!(keys.claim("a.com", "k"), Claim::New(_)));
+    }
+
+    #[test]
+    fn test_expired_keys_are_forgotten() {
+        let keys = IdempotencyKeys::new(Duration::from_millis(1));
+        complete(keys.claim("a.com", "k"), StatusCode::ACCEPTED);
+        std::thread::sleep(Duration::from_millis(5));
+        keys.cleanup();
+        assert!(keys.keys.lock().results.is_empty());
+        assert!(matches!(keys.claim("a.com", "k"), Claim::New(_)));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let keys = IdempotencyKeys::new(Duration::ZERO);
+        complete(keys.claim("a.com", "k"), StatusCode::ACCEPTED);
+        assert!(matches!(keys.claim("a.com", "k"), Claim::New(_)));
+    }
+}
@@ -1,6 +1,17 @@
 pub mod buffer;
+pub mod bus;
+pub mod dedupe;
+pub mod flush_retry;
+pub mod forward;
 pub mod geoip;
 pub mod handler;
+pub mod idempotency;
+pub mod queue;
 pub mod ratelimit;
+pub mod spam;
+pub mod stats;
+pub mod transform;
 pub mod useragent;
 pub mod visitor_id;
+#[cfg(feature = "wasm-filters")]
+pub mod wasm_filter;
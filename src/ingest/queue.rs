@@ -0,0 +1,262 @@
+//! Bounded queue between the ingest endpoints and the enrichment workers.
+//!
+//! The request handlers run only the cheap checks (origin, field limits, API
+//! key, rate limit, dedupe) and enqueue the raw request.  A pool of worker
+//! threads parses the User-Agent, looks up GeoIP, builds the event and pushes
+//! it into the buffer, including any threshold-triggered flush.  When the
+//! queue is full the handler rejects the request with 503 rather than let
+//! ingest latency and memory grow without bound.  Under the
+//! [`OverloadPolicy::Shed`] policy, low-priority events are dropped before
+//! the queue fills, keeping its last slots for the events that matter most.
+
+use crate::ingest::handler::{extract_ip, store_event, AppState, EventPayload};
+use axum::http::HeaderMap;
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+/// How often an idle worker checks whether the server state is gone.
+const IDLE_CHECK: Duration = Duration::from_secs(1);
+
+/// Share of the queue (or event buffer), in percent, that low-priority
+/// events may fill under [`OverloadPolicy::Shed`].
+pub const SHED_AT_PCT: usize = 90;
+
+/// What to do with events when the ingest queue is filling up
+/// (`ingest_overload` in the config file).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverloadPolicy {
+    /// Answer every event with 503 once the queue is full.
+    #[default]
+    Reject,
+    /// Drop low-priority events (plain pageviews) once the queue is
+    /// [`SHED_AT_PCT`] percent full, answering them with 202; others are
+    /// rejected with 503 only when it is full.
+    Shed,
+}
+
+impl OverloadPolicy {
+    /// Every policy.
+    pub const ALL: [Self; 2] = [Self::Reject, Self::Shed];
+
+    /// Policy name as written in configuration.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Reject => "reject",
+            Self::Shed => "shed",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|p| p.name() == name)
+    }
+}
+
+/// Outcome of [`IngestQueue::try_enqueue`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Enqueued {
+    Queued,
+    /// Dropped as low priority under [`OverloadPolicy::Shed`].
+    Shed,
+    /// The queue is full.
+    Full,
+}
+
+/// A request that passed the handler's checks, waiting for enrichment.
+///
+/// PRIVACY: `ip` is used for the visitor hash only and is never stored.
+#[derive(Clone)]
+pub struct RawEvent {
+    pub payload: EventPayload,
+    pub ip: String,
+    pub user_agent: String,
+    /// When the request arrived; the event timestamp and daily salt use this
+    /// rather than the time a worker picks it up, unless the payload carries
+    /// its own time.
+    pub received_at: DateTime<Utc>,
+}
+
+impl RawEvent {
+    /// `payload` as received now, with the client IP and User-Agent from
+    /// `headers`.
+    pub fn new(payload: EventPayload, headers: &HeaderMap) -> Self {
+        Self {
+            payload,
+            ip: extract_ip(headers),
+            user_agent: headers
+                .get("user-agent")
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("")
+                .to_string(),
+            received_at: Utc::now(),
+        }
+    }
+
+    /// When the event happened: the payload's `t`, else the time of receipt.
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        self.payload.timestamp.unwrap_or(self.received_at)
+    }
+}
+
+/// Sending side of the queue, shared by the handlers, plus the receiving
+/// side the workers take turns on.
+#[derive(Clone)]
+pub struct IngestQueue {
+    sender: SyncSender<RawEvent>,
+    receiver: Arc<Mutex<Receiver<RawEvent>>>,
+    /// Events enqueued but not yet fully handled by a worker.
+    pending: Arc<AtomicUsize>,
+    /// Running total of requests rejected because the queue was full.
+    rejected: Arc<AtomicU64>,
+    /// Running total of low-priority events shed.
+    shed: Arc<AtomicU64>,
+    /// Depth from which low-priority events are shed; `None` never sheds.
+    shed_at: Option<usize>,
+}
+
+impl IngestQueue {
+    /// A queue holding at most `capacity` events, handling overload by
+    /// `policy`.
+    pub fn new(capacity: usize, policy: OverloadPolicy) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        Self {
+            sender,
+            receiver: Arc::new(Mutex::new(receiver)),
+            pending: Arc::new(AtomicUsize::new(0)),
+            rejected: Arc::new(AtomicU64::new(0)),
+            shed: Arc::new(AtomicU64::new(0)),
+            shed_at: (policy == OverloadPolicy::Shed).then_some(capacity * SHED_AT_PCT / 100),
+        }
+    }
+
+    /// Enqueue `event` without waiting.
+    pub fn try_enqueue(&self, event: RawEvent) -> Enqueued {
+        if self
+            .shed_at
+            .is_some_and(|shed_at| self.depth() >= shed_at && is_low_priority(&event.payload))
+        {
+            self.shed.fetch_add(1, Ordering::Relaxed);
+            return Enqueued::Shed;
+        }
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        match self.sender.try_send(event) {
+            Ok(()) => Enqueued::Queued,
+            Err(TrySendError::Full(_) | TrySendError::Disconnected(_)) => {
+                self.pending.fetch_sub(1, Ordering::SeqCst);
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                Enqueued::Full
+            }
+        }
+    }
+
+    /// Events waiting for or being handled by a worker.
+    pub fn depth(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    /// Requests rejected because the queue was full, since startup.
+    pub fn rejected_total(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Low-priority events shed under load, since startup.
+    pub fn shed_total(&self) -> u64 {
+        self.shed.load(Ordering::Relaxed)
+    }
+
+    /// Start `workers` threads that enrich and buffer queued events for
+    /// `state`.  The workers exit once `state` is dropped.
+    pub fn spawn_workers(&self, state: &Arc<AppState>, workers: usize) {
+        for i in 0..workers {
+            let queue = self.clone();
+            let state = Arc::downgrade(state);
+            let spawned = std::thread::Builder::new()
+                .name(format!("ingest-worker-{i}"))
+                .spawn(move || queue.work(&state));
+            if let Err(e) = spawned {
+                tracing::error!(error = %e, "Could not start ingest worker");
+            }
+        }
+    }
+
+    /// Block until every enqueued event has been handled, so a final flush
+    /// at shutdown includes them.
+    pub fn wait_idle(&self) {
+        while self.depth() > 0 {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn work(&self, state: &Weak<AppState>) {
+        loop {
+            let received = self.receiver.lock().recv_timeout(IDLE_CHECK);
+            let event = match received {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) if state.strong_count() > 0 => continue,
+                Err(_) => return,
+            };
+            let Some(state) = state.upgrade() else {
+                return;
+            };
+            if let Err(e) = store_event(&state, &event) {
+                tracing::error!(error = %e, "Failed to buffer event");
+            }
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Plain pageviews: the bulk of traffic, and the cheapest to lose under
+/// load.  Custom and revenue events are kept.
+fn is_low_priority(payload: &EventPayload) -> bool {
+    payload.name == "pageview" && payload.revenue_amount.is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw_event(name: &str) -> RawEvent {
+        let payload: EventPayload = serde_json::from_value(serde_json::json!({
+            "d": "a.com",
+            "n": name,
+            "u": "/",
+        }))
+        .unwrap();
+        RawEvent::new(payload, &HeaderMap::new())
+    }
+
+    #[test]
+    fn test_full_queue_rejects() {
+        let queue = IngestQueue::new(1, OverloadPolicy::Reject);
+        assert_eq!(queue.try_enqueue(raw_event("pageview")), Enqueued::Queued);
+        assert_eq!(queue.try_enqueue(raw_event("pageview")), Enqueued::Full);
+        assert_eq!(queue.depth(), 1);
+        assert_eq!(queue.rejected_total(), 1);
+        assert_eq!(queue.shed_total(), 0);
+    }
+
+    #[test]
+    fn test_shed_keeps_room_for_custom_events() {
+        let queue = IngestQueue::new(10, OverloadPolicy::Shed);
+        for _ in 0..9 {
+            assert_eq!(queue.try_enqueue(raw_event("pageview")), Enqueued::Queued);
+        }
+        assert_eq!(queue.try_enqueue(raw_event("pageview")), Enqueued::Shed);
+        assert_eq!(queue.try_enqueue(raw_event("signup")), Enqueued::Queued);
+        assert_eq!(queue.try_enqueue(raw_event("signup")), Enqueued::Full);
+        assert_eq!(queue.shed_total(), 1);
+        assert_eq!(queue.rejected_total(), 1);
+    }
+
+    #[test]
+    fn test_overload_policy_names() {
+        for policy in OverloadPolicy::ALL {
+            assert_eq!(OverloadPolicy::from_name(policy.name()), Some(policy));
+        }
+        assert_eq!(OverloadPolicy::from_name("drop"), None);
+    }
+}
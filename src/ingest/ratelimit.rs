@@ -1,16 +1,38 @@
+use axum::http::{HeaderMap, HeaderValue};
 use parking_lot::Mutex;
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Instant;
 
-/// Per-site token-bucket rate limiter.
+/// Token-bucket rate limiter keyed by site ID (or any other caller key).
 ///
-/// Each site gets `capacity` tokens per second. Tokens are refilled
-/// continuously based on elapsed time since the last check.
+/// Each key's bucket holds up to `burst` tokens and refills continuously at
+/// `rate` tokens per second, so a site can absorb a short spike of `burst`
+/// events while its sustained throughput stays at `rate`.  Individual sites
+/// can be given their own rate and burst.
 #[derive(Clone)]
 pub struct RateLimiter {
     buckets: Arc<Mutex<HashMap<String, Bucket>>>,
-    capacity: u32,
+    default_limit: Limit,
+    site_limits: HashMap<String, Limit>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Limit {
+    /// Tokens added per second.  0 disables limiting.
+    rate: f64,
+    /// Bucket capacity.
+    burst: u32,
+}
+
+impl Limit {
+    /// A burst of 0 means "same as the rate".
+    fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate: f64::from(rate),
+            burst: if burst == 0 { rate } else { burst },
+        }
+    }
 }
 
 struct Bucket {
@@ -18,45 +40,134 @@ struct Bucket {
     last_refill: Instant,
 }
 
+/// Result of a rate-limit check, with the values for the `RateLimit-*`
+/// response headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitStatus {
+    pub allowed: bool,
+    /// Bucket capacity.
+    pub limit: u32,
+    /// Whole tokens left after this request.
+    pub remaining: u32,
+    /// Seconds until the bucket is full again.
+    pub reset_secs: u64,
+    /// Seconds until the next request would be allowed; 0 when allowed.
+    pub retry_after_secs: u64,
+}
+
+impl RateLimitStatus {
+    /// `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset`, plus
+    /// `Retry-After` when the request was rejected.
+    pub fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("ratelimit-limit", HeaderValue::from(self.limit));
+        headers.insert("ratelimit-remaining", HeaderValue::from(self.remaining));
+        headers.insert("ratelimit-reset", HeaderValue::from(self.reset_secs));
+        if !self.allowed {
+            headers.insert("retry-after", HeaderValue::from(self.retry_after_secs));
+        }
+        headers
+    }
+}
+
 impl RateLimiter {
-    /// Create a new rate limiter.
+    /// Create a new rate limiter allowing `capacity` events per second with a
+    /// burst of the same size.
     /// A capacity of 0 disables rate limiting (all requests are allowed).
     pub fn new(capacity: u32) -> Self {
         Self {
             buckets: Arc::new(Mutex::new(HashMap::new())),
-            capacity,
+            default_limit: Limit::new(capacity, 0),
+            site_limits: HashMap::new(),
+        }
+    }
+
+    /// Create a rate limiter allowing `requests` per minute per key, all of
+    /// which may be spent at once.  0 disables rate limiting.
+    pub fn per_minute(requests: u32) -> Self {
+        Self {
+            default_limit: Limit {
+                rate: f64::from(requests) / 60.0,
+                burst: requests,
+            },
+            ..Self::new(0)
         }
     }
 
+    /// Set the default bucket capacity.  0 keeps it equal to the rate.
+    #[must_use]
+    pub const fn with_burst(mut self, burst: u32) -> Self {
+        if burst != 0 {
+            self.default_limit.burst = burst;
+        }
+        self
+    }
+
+    /// Override the rate and burst for one site.  A rate of 0 exempts the
+    /// site; a burst of 0 keeps it equal to the rate.
+    #[must_use]
+    pub fn with_site_limit(mut self, site_id: &str, rate: u32, burst: u32) -> Self {
+        self.site_limits
+            .insert(site_id.to_string(), Limit::new(rate, burst));
+        self
+    }
+
     /// Check if a request for the given site_id is allowed.
     /// Returns `true` if allowed, `false` if rate-limited.
-    #[allow(clippy::significant_drop_tightening)]
     pub fn check(&self, site_id: &str) -> bool {
-        if self.capacity == 0 {
-            return true;
+        self.acquire(site_id).is_none_or(|status| status.allowed)
+    }
+
+    /// Try to take a token for `site_id`.
+    ///
+    /// Returns `None` when the site is not rate limited.
+    #[allow(
+        clippy::significant_drop_tightening,
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss
+    )]
+    pub fn acquire(&self, site_id: &str) -> Option<RateLimitStatus> {
+        let limit = self
+            .site_limits
+            .get(site_id)
+            .copied()
+            .unwrap_or(self.default_limit);
+        if limit.rate <= 0.0 {
+            return None;
         }
 
         let mut buckets = self.buckets.lock();
         let now = Instant::now();
-        let cap = f64::from(self.capacity);
+        let rate = limit.rate;
+        let burst = f64::from(limit.burst);
 
         let bucket = buckets.entry(site_id.to_string()).or_insert(Bucket {
-            tokens: cap,
+            tokens: burst,
             last_refill: now,
         });
 
         // Refill tokens based on elapsed time
         let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
-        bucket.tokens = elapsed.mul_add(cap, bucket.tokens).min(cap);
+        bucket.tokens = elapsed.mul_add(rate, bucket.tokens).min(burst);
         bucket.last_refill = now;
 
         // Try to consume a token
-        if bucket.tokens >= 1.0 {
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
             bucket.tokens -= 1.0;
-            true
-        } else {
-            false
         }
+        let tokens = bucket.tokens;
+        Some(RateLimitStatus {
+            allowed,
+            limit: limit.burst,
+            remaining: tokens.floor() as u32,
+            reset_secs: ((burst - tokens) / rate).ceil() as u64,
+            retry_after_secs: if allowed {
+                0
+            } else {
+                ((1.0 - tokens) / rate).ceil().max(1.0) as u64
+            },
+        })
     }
 
     /// Remove stale buckets that haven't been accessed in over 5 minutes.
@@ -105,6 +216,63 @@ mod tests {
         assert!(!rl.check("site-b.com"));
     }
 
+    #[test]
+    fn test_burst_above_rate() {
+        let rl = RateLimiter::new(1).with_burst(3);
+        for _ in 0..3 {
+            assert!(rl.check("site.com"));
+        }
+        let status = rl.acquire("site.com").unwrap();
+        assert!(!status.allowed);
+        assert_eq!(status.limit, 3);
+        assert_eq!(status.remaining, 0);
+        assert_eq!(status.retry_after_secs, 1);
+        assert_eq!(status.reset_secs, 3);
+    }
+
+    #[test]
+    fn test_site_overrides() {
+        let rl = RateLimiter::new(1)
+            .with_site_limit("big.com", 5, 0)
+            .with_site_limit("exempt.com", 0, 0);
+        for _ in 0..5 {
+            assert!(rl.check("big.com"));
+        }
+        assert!(!rl.check("big.com"));
+        assert!(rl.check("small.com"));
+        assert!(!rl.check("small.com"));
+        for _ in 0..10 {
+            assert!(rl.acquire("exempt.com").is_none());
+        }
+    }
+
+    #[test]
+    fn test_per_minute() {
+        let rl = RateLimiter::per_minute(2);
+        assert!(rl.check("key"));
+        assert!(rl.check("key"));
+        let status = rl.acquire("key").unwrap();
+        assert!(!status.allowed);
+        assert_eq!(status.limit, 2);
+        // One token takes 30 s to refill at 2 per minute.
+        assert_eq!(status.retry_after_secs, 30);
+        assert!(RateLimiter::per_minute(0).acquire("key").is_none());
+    }
+
+    #[test]
+    fn test_status_headers() {
+        let rl = RateLimiter::new(2);
+        let headers = rl.acquire("site.com").unwrap().headers();
+        assert_eq!(headers["ratelimit-limit"], "2");
+        assert_eq!(headers["ratelimit-remaining"], "1");
+        assert_eq!(headers["ratelimit-reset"], "1");
+        assert!(!headers.contains_key("retry-after"));
+
+        rl.acquire("site.com");
+        let headers = rl.acquire("site.com").unwrap().headers();
+        assert_eq!(headers["retry-after"], "1");
+    }
+
     #[test]
     fn test_cleanup_stale_buckets() {
         let rl = RateLimiter::new(10);
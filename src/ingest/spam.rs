@@ -0,0 +1,221 @@
+use crate::ingest::buffer::Event;
+use crate::ingest::transform::{domain_suffixes, IngestTransform};
+use parking_lot::RwLock;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Well-known referral spam domains, blocked unless `block_spam_referrers`
+/// is turned off.  Subdomains are blocked too.
+const BUILTIN_SPAM_DOMAINS: &[&str] = &[
+    "4webmasters.org",
+    "7makemoneyonline.com",
+    "best-seo-offer.com",
+    "best-seo-solution.com",
+    "blackhatworth.com",
+    "buttons-for-website.com",
+    "buttons-for-your-website.com",
+    "buy-cheap-online.info",
+    "darodar.com",
+    "econom.co",
+    "event-tracking.com",
+    "floating-share-buttons.com",
+    "free-social-buttons.com",
+    "get-free-traffic-now.com",
+    "hulfingtonpost.com",
+    "ilovevitaly.com",
+    "kambasoft.com",
+    "o-o-6-o-o.com",
+    "priceg.com",
+    "rank-checker.online",
+    "ranksonic.info",
+    "savetubevideo.com",
+    "screentoolkit.com",
+    "semalt.com",
+    "seo-platform.com",
+    "simple-share-buttons.com",
+    "site-auditor.online",
+    "social-buttons.com",
+    "success-seo.com",
+    "traffic2money.com",
+    "trafficmonetize.org",
+    "videos-for-your-business.com",
+    "webmonetizer.net",
+];
+
+/// Largest spam list accepted from `spam_list_url`.
+const MAX_LIST_BYTES: usize = 4 * 1024 * 1024;
+
+/// Referral spam domains: the built-in list, `spam_referrers` from the config,
+/// and the last download of `spam_list_url`.
+///
+/// As an ingest transform it drops events whose referrer host, or any parent
+/// domain of it, is listed.
+pub struct SpamBlocklist {
+    /// Built-in and configured domains.
+    fixed: HashSet<String>,
+    /// Domains from the last successful download.
+    fetched: RwLock<HashSet<String>>,
+    /// Events dropped since startup.
+    dropped_total: AtomicU64,
+}
+
+impl Default for SpamBlocklist {
+    fn default() -> Self {
+        Self::new(&[])
+    }
+}
+
+impl SpamBlocklist {
+    /// The built-in list plus `extra` domains.
+    pub fn new(extra: &[String]) -> Self {
+        let fixed = BUILTIN_SPAM_DOMAINS
+            .iter()
+            .copied()
+            .chain(extra.iter().map(String::as_str))
+            .filter_map(normalize_domain)
+            .collect();
+        Self {
+            fixed,
+            fetched: RwLock::new(HashSet::new()),
+            dropped_total: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether `host` or one of its parent domains is listed.
+    pub fn is_spam(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        let fetched = self.fetched.read();
+        let listed = domain_suffixes(&host).any(|d| self.fixed.contains(d) || fetched.contains(d));
+        listed
+    }
+
+    /// Replace the downloaded domains, keeping the built-in and configured ones.
+    pub fn replace_fetched(&self, domains: HashSet<String>) {
+        *self.fetched.write() = domains;
+    }
+
+    /// Events dropped as referral spam since startup.
+    pub fn dropped_total(&self) -> u64 {
+        self.dropped_total.load(Ordering::Relaxed)
+    }
+}
+
+impl IngestTransform for SpamBlocklist {
+    fn name(&self) -> &'static str {
+        "spam_referrers"
+    }
+
+    fn apply(&self, event: &mut Event) -> bool {
+        let spam = event
+            .referrer
+            .as_deref()
+            .map(crate::ingest::handler::referrer_host)
+            .is_some_and(|host| self.is_spam(host));
+        if spam {
+            self.dropped_total.fetch_add(1, Ordering::Relaxed);
+        }
+        !spam
+    }
+}
+
+/// Lowercase a list entry and strip a leading `*.` or `.`.  Blank entries and
+/// `#` comments yield `None`.
+fn normalize_domain(entry: &str) -> Option<String> {
+    let entry = entry.trim();
+    let entry = entry
+        .strip_prefix("*.")
+        .or_else(|| entry.strip_prefix('.'))
+        .unwrap_or(entry);
+    (!entry.is_empty() && !entry.starts_with('#')).then(|| entry.to_ascii_lowercase())
+}
+
+/// Parse a spam list: one domain per line, `#` starting a comment line.
+pub fn parse_list(text: &str) -> HashSet<String> {
+    text.lines().filter_map(normalize_domain).collect()
+}
+
+/// Download and parse the spam list at `url`.
+pub async fn fetch_list(url: &str) -> Result<HashSet<String>, String> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()
+        .map_err(|e| e.to_string())?;
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| e.to_string())?;
+    if response
+        .content_length()
+        .is_some_and(|len| len > MAX_LIST_BYTES as u64)
+    {
+        return Err(format!("list is larger than {MAX_LIST_BYTES} bytes"));
+    }
+    let body = response.text().await.map_err(|e| e.to_string())?;
+    if body.len() > MAX_LIST_BYTES {
+        return Err(format!("list is larger than {MAX_LIST_BYTES} bytes"));
+    }
+    Ok(parse_list(&body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_and_configured_domains() {
+        let blocklist = SpamBlocklist::new(&["*.Spam.Example".to_string()]);
+        assert!(blocklist.is_spam("semalt.com"));
+        assert!(blocklist.is_spam("www.semalt.com"));
+        assert!(blocklist.is_spam("SEMALT.COM"));
+        assert!(blocklist.is_spam("spam.example"));
+        assert!(blocklist.is_spam("a.b.spam.example"));
+        assert!(!blocklist.is_spam("google.com"));
+        assert!(!blocklist.is_spam("notsemalt.com"));
+    }
+
+    #[test]
+    fn test_replace_fetched() {
+        let blocklist = SpamBlocklist::default();
+        blocklist.replace_fetched(parse_list("# spam list\nnew-spam.com\n\n  other.net  \n"));
+        assert!(blocklist.is_spam("new-spam.com"));
+        assert!(blocklist.is_spam("other.net"));
+
+        // A later download replaces the earlier one but never the built-ins.
+        blocklist.replace_fetched(parse_list("other.net"));
+        assert!(!blocklist.is_spam("new-spam.com"));
+        assert!(blocklist.is_spam("darodar.com"));
+    }
+
+    #[test]
+    fn test_drops_and_counts_spam_events() {
+        let blocklist = SpamBlocklist::default();
+        let mut event: Event = serde_json::from_value(serde_json::json!({
+            "site_id": "test.com",
+            "visitor_id": "v1",
+            "timestamp": "2024-01-15T10:00:00",
+            "event_name": "pageview",
+            "pathname": "/",
+            "hostname": null,
+            "referrer": "https://forum.semalt.com/page",
+            "referrer_source": "forum.semalt.com",
+            "utm_source": null, "utm_medium": null, "utm_campaign": null,
+            "utm_content": null, "utm_term": null,
+            "browser": null, "browser_version": null, "os": null, "os_version": null,
+            "device_type": null, "screen_size": null,
+            "country_code": null, "region": null, "city": null,
+            "props": null, "revenue_amount": null, "revenue_currency": null
+        }))
+        .unwrap();
+        assert!(!blocklist.apply(&mut event));
+        assert_eq!(blocklist.dropped_total(), 1);
+
+        event.referrer = Some("https://www.google.com/".to_string());
+        assert!(blocklist.apply(&mut event));
+        event.referrer = None;
+        assert!(blocklist.apply(&mut event));
+        assert_eq!(blocklist.dropped_total(), 1);
+    }
+}
@@ -0,0 +1,189 @@
+use chrono::{DateTime, Utc};
+use parking_lot::RwLock;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Most sites counted separately; events for further sites are counted
+/// under [`OTHER_SITES`].
+const MAX_SITES: usize = 10_000;
+
+/// Counter key for sites beyond [`MAX_SITES`] and for requests whose site ID
+/// is itself invalid.
+pub const OTHER_SITES: &str = "__other__";
+
+/// What happened to an ingestion request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IngestOutcome {
+    /// Handed to the ingest pipeline.
+    Accepted,
+    /// Failed field validation.
+    Rejected,
+    /// Refused by the per-site rate limiter.
+    RateLimited,
+    /// Dropped during enrichment as coming from a bot.
+    BotFiltered,
+}
+
+#[derive(Default)]
+struct SiteCounters {
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+    rate_limited: AtomicU64,
+    bot_filtered: AtomicU64,
+    bytes: AtomicU64,
+}
+
+/// Per-site ingestion counters since startup, for `GET /api/admin/ingest-stats`.
+///
+/// Counting takes a read lock and an atomic add; the write lock is only
+/// taken the first time a site is seen.
+#[derive(Clone)]
+pub struct IngestStats {
+    sites: Arc<RwLock<HashMap<String, Arc<SiteCounters>>>>,
+    started_at: DateTime<Utc>,
+}
+
+/// One site's counters.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SiteIngestStats {
+    pub site_id: String,
+    pub accepted: u64,
+    pub rejected: u64,
+    pub rate_limited: u64,
+    pub bot_filtered: u64,
+    /// Request body bytes as sent, before decompression.
+    pub bytes: u64,
+}
+
+impl SiteIngestStats {
+    /// Requests received for the site, whatever their outcome.
+    pub const fn requests(&self) -> u64 {
+        self.accepted + self.rejected + self.rate_limited
+    }
+}
+
+impl Default for IngestStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IngestStats {
+    pub fn new() -> Self {
+        Self {
+            sites: Arc::new(RwLock::new(HashMap::new())),
+            started_at: Utc::now(),
+        }
+    }
+
+    /// When counting started.
+    pub const fn started_at(&self) -> DateTime<Utc> {
+        self.started_at
+    }
+
+    /// Count one request for `site_id` with `outcome`.
+    pub fn record(&self, site_id: &str, outcome: IngestOutcome) {
+        let counters = self.counters(site_id);
+        let counter = match outcome {
+            IngestOutcome::Accepted => &counters.accepted,
+            IngestOutcome::Rejected => &counters.rejected,
+            IngestOutcome::RateLimited => &counters.rate_limited,
+            IngestOutcome::BotFiltered => &counters.bot_filtered,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count `bytes` of request body received for `site_id`.
+    pub fn add_bytes(&self, site_id: &str, bytes: u64) {
+        self.counters(site_id)
+            .bytes
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counters of every site, busiest first.
+    pub fn snapshot(&self) -> Vec<SiteIngestStats> {
+        let mut sites: Vec<SiteIngestStats> = self
+            .sites
+            .read()
+            .iter()
+            .map(|(site_id, c)| SiteIngestStats {
+                site_id: site_id.clone(),
+                accepted: c.accepted.load(Ordering::Relaxed),
+                rejected: c.rejected.load(Ordering::Relaxed),
+                rate_limited: c.rate_limited.load(Ordering::Relaxed),
+                bot_filtered: c.bot_filtered.load(Ordering::Relaxed),
+                bytes: c.bytes.load(Ordering::Relaxed),
+            })
+            .collect();
+        sites.sort_by(|a, b| {
+            b.requests()
+                .cmp(&a.requests())
+                .then_with(|| a.site_id.cmp(&b.site_id))
+        });
+        sites
+    }
+
+    fn counters(&self, site_id: &str) -> Arc<SiteCounters> {
+        if let Some(counters) = self.sites.read().get(site_id) {
+            return Arc::clone(counters);
+        }
+        let mut sites = self.sites.write();
+        let key = if sites.len() < MAX_SITES || sites.contains_key(site_id) {
+            site_id
+        } else {
+            OTHER_SITES
+        };
+        Arc::clone(sites.entry(key.to_string()).or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ingest_stats_per_site() {
+        let stats = IngestStats::new();
+        stats.record("a.com", IngestOutcome::Accepted);
+        stats.record("a.com", IngestOutcome::Accepted);
+        stats.record("a.com", IngestOutcome::BotFiltered);
+        stats.add_bytes("a.com", 300);
+        stats.record("b.com", IngestOutcome::Rejected);
+        stats.record("b.com", IngestOutcome::RateLimited);
+        stats.record("b.com", IngestOutcome::RateLimited);
+        stats.record("b.com", IngestOutcome::RateLimited);
+
+        let sites = stats.snapshot();
+        assert_eq!(sites.len(), 2);
+        assert_eq!(sites[0].site_id, "b.com", "busiest site first");
+        assert_eq!(sites[0].rejected, 1);
+        assert_eq!(sites[0].rate_limited, 3);
+        assert_eq!(
+            sites[1],
+            SiteIngestStats {
+                site_id: "a.com".to_string(),
+                accepted: 2,
+                rejected: 0,
+                rate_limited: 0,
+                bot_filtered: 1,
+                bytes: 300,
+            }
+        );
+    }
+
+    #[test]
+    fn test_ingest_stats_caps_sites() {
+        let stats = IngestStats::new();
+        for i in 0..=MAX_SITES {
+            stats.record(&format!("site-{i}.com"), IngestOutcome::Accepted);
+        }
+        stats.record("site-0.com", IngestOutcome::Accepted);
+        let sites = stats.snapshot();
+        assert_eq!(sites.len(), MAX_SITES + 1);
+        assert_eq!(sites[0].site_id, "site-0.com");
+        assert_eq!(sites[0].accepted, 2);
+        assert!(sites.iter().any(|s| s.site_id == OTHER_SITES));
+    }
+}
@@ -0,0 +1,273 @@
+use crate::config::Config;
+use crate::ingest::buffer::Event;
+use crate::ingest::spam::SpamBlocklist;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// A custom step in the ingest pipeline, run on every event after enrichment
+/// and before it is buffered.
+///
+/// Transforms are compiled in and registered at startup by
+/// [`IngestTransforms::from_config`].  They run on the request path, so they
+/// must be cheap and must not block.
+pub trait IngestTransform: Send + Sync {
+    /// Short name used in logs.
+    fn name(&self) -> &'static str;
+
+    /// Rewrite `event` in place.  Returning `false` drops the event.
+    fn apply(&self, event: &mut Event) -> bool;
+}
+
+impl<T: IngestTransform + ?Sized> IngestTransform for Arc<T> {
+    fn name(&self) -> &'static str {
+        (**self).name()
+    }
+
+    fn apply(&self, event: &mut Event) -> bool {
+        (**self).apply(event)
+    }
+}
+
+/// `host` followed by each of its parent domains: `a.b.com`, `b.com`, `com`.
+pub fn domain_suffixes(host: &str) -> impl Iterator<Item = &str> {
+    std::iter::successors(Some(host), |h| h.split_once('.').map(|(_, parent)| parent))
+}
+
+/// The registered transforms, applied in registration order.
+#[derive(Clone, Default)]
+pub struct IngestTransforms {
+    transforms: Vec<Arc<dyn IngestTransform>>,
+}
+
+impl IngestTransforms {
+    /// Build the transforms enabled by `config`, blocking referral spam with
+    /// `spam_blocklist`.
+    ///
+    /// Deployments with their own transforms register them here, after the
+    /// built-in ones.
+    pub fn from_config(config: &Config, spam_blocklist: &Arc<SpamBlocklist>) -> Self {
+        let mut transforms = Self::default();
+        if config.block_spam_referrers {
+            transforms.register(Arc::clone(spam_blocklist));
+        }
+        if !config.referrer_sources.is_empty() {
+            transforms.register(ReferrerSources::new(&config.referrer_sources));
+        }
+        #[cfg(feature = "wasm-filters")]
+        if let Some(path) = &config.wasm_filter {
+            transforms.register(crate::ingest::wasm_filter::WasmFilter::new(path));
+        }
+        transforms
+    }
+
+    /// Append `transform`, to run after those already registered.
+    pub fn register(&mut self, transform: impl IngestTransform + 'static) {
+        self.transforms.push(Arc::new(transform));
+    }
+
+    /// Names of the registered transforms, in order.
+    pub fn names(&self) -> Vec<&'static str> {
+        self.transforms.iter().map(IngestTransform::name).collect()
+    }
+
+    /// Run every transform on `event`.  Returns `false`, skipping the rest,
+    /// as soon as one drops it.
+    pub fn apply(&self, event: &mut Event) -> bool {
+        self.transforms.iter().all(|transform| {
+            let keep = transform.apply(event);
+            if !keep {
+                tracing::debug!(
+                    transform = transform.name(),
+                    site_id = %event.site_id,
+                    "Event dropped by ingest transform"
+                );
+            }
+            keep
+        })
+    }
+}
+
+/// Classifies referrers by hostname, overriding the built-in source names.
+///
+/// A hostname matches an entry for itself or for any parent domain, the most
+/// specific entry winning: with `"example.com" = "Example"`,
+/// `blog.example.com` is also reported as `Example`.
+pub struct ReferrerSources {
+    /// Lowercased hostname → source name.
+    hosts: HashMap<String, String>,
+}
+
+impl ReferrerSources {
+    /// Build from `(hostname, source name)` pairs.
+    pub fn new<'a>(hosts: impl IntoIterator<Item = (&'a String, &'a String)>) -> Self {
+        Self {
+            hosts: hosts
+                .into_iter()
+                .map(|(host, source)| (host.to_ascii_lowercase(), source.clone()))
+                .collect(),
+        }
+    }
+
+    fn lookup(&self, host: &str) -> Option<&str> {
+        let host = host.to_ascii_lowercase();
+        let source = domain_suffixes(&host).find_map(|d| self.hosts.get(d));
+        source.map(String::as_str)
+    }
+}
+
+impl IngestTransform for ReferrerSources {
+    fn name(&self) -> &'static str {
+        "referrer_sources"
+    }
+
+    fn apply(&self, event: &mut Event) -> bool {
+        let source = event
+            .referrer
+            .as_deref()
+            .map(crate::ingest::handler::referrer_host)
+            .and_then(|host| self.lookup(host));
+        if let Some(source) = source {
+            event.referrer_source = Some(source.to_string());
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MAX_CUSTOM_DIMENSIONS;
+
+    fn event(referrer: Option<&str>) -> Event {
+        Event {
+            site_id: "test.com".to_string(),
+            visitor_id: "v1".to_string(),
+            timestamp: chrono::Utc::now().naive_utc(),
+            event_name: "pageview".to_string(),
+            pathname: "/".to_string(),
+            hostname: None,
+            referrer: referrer.map(str::to_string),
+            referrer_source: referrer.map(|_| "unclassified".to_string()),
+            utm_source: None,
+            utm_medium: None,
+            utm_campaign: None,
+            utm_content: None,
+            utm_term: None,
+            browser: None,
+            browser_version: None,
+            os: None,
+            os_version: None,
+            device_type: None,
+            screen_size: None,
+            country_code: None,
+            region: None,
+            city: None,
+            props: None,
+            revenue_amount: None,
+            revenue_currency: None,
+            dimensions: [const { None }; MAX_CUSTOM_DIMENSIONS],
+            prev_pathname: None,
+            user_id: None,
+            device_brand: None,
+            device_model: None,
+        }
+    }
+
+    fn referrer_sources() -> ReferrerSources {
+        ReferrerSources::new(&HashMap::from([
+            (
+                "news.ycombinator.com".to_string(),
+                "Hacker News".to_string(),
+            ),
+            ("Example.com".to_string(), "Example".to_string()),
+            ("docs.example.com".to_string(), "Example Docs".to_string()),
+        ]))
+    }
+
+    #[test]
+    fn test_referrer_sources() {
+        let transform = referrer_sources();
+        for (referrer, expected) in [
+            ("https://news.ycombinator.com/item?id=1", "Hacker News"),
+            ("https://blog.example.com/post", "Example"),
+            ("http://EXAMPLE.com:8080/", "Example"),
+            ("https://docs.example.com/guide", "Example Docs"),
+            ("https://other.org/", "unclassified"),
+        ] {
+            let mut e = event(Some(referrer));
+            assert!(transform.apply(&mut e));
+            assert_eq!(e.referrer_source.as_deref(), Some(expected), "{referrer}");
+        }
+
+        let mut e = event(None);
+        assert!(transform.apply(&mut e));
+        assert_eq!(e.referrer_source, None);
+    }
+
+    struct DropInternal;
+
+    impl IngestTransform for DropInternal {
+        fn name(&self) -> &'static str {
+            "drop_internal"
+        }
+
+        fn apply(&self, event: &mut Event) -> bool {
+            !event.pathname.starts_with("/internal")
+        }
+    }
+
+    struct Uppercase;
+
+    impl IngestTransform for Uppercase {
+        fn name(&self) -> &'static str {
+            "uppercase"
+        }
+
+        fn apply(&self, event: &mut Event) -> bool {
+            event.pathname = event.pathname.to_uppercase();
+            true
+        }
+    }
+
+    #[test]
+    fn test_transforms_run_in_order_and_can_drop() {
+        let mut transforms = IngestTransforms::default();
+        transforms.register(DropInternal);
+        transforms.register(Uppercase);
+        assert_eq!(transforms.names(), vec!["drop_internal", "uppercase"]);
+
+        let mut e = event(None);
+        e.pathname = "/docs".to_string();
+        assert!(transforms.apply(&mut e));
+        assert_eq!(e.pathname, "/DOCS");
+
+        e.pathname = "/internal/health".to_string();
+        assert!(!transforms.apply(&mut e));
+        // Later transforms are skipped once an event is dropped.
+        assert_eq!(e.pathname, "/internal/health");
+    }
+
+    #[test]
+    fn test_from_config() {
+        let spam = Arc::new(SpamBlocklist::default());
+        assert_eq!(
+            IngestTransforms::from_config(&Config::default(), &spam).names(),
+            vec!["spam_referrers"]
+        );
+        let config = Config {
+            block_spam_referrers: false,
+            referrer_sources: HashMap::from([("t.co".to_string(), "X".to_string())]),
+            ..Config::default()
+        };
+        assert_eq!(
+            IngestTransforms::from_config(&config, &spam).names(),
+            vec!["referrer_sources"]
+        );
+    }
+
+    #[test]
+    fn test_domain_suffixes() {
+        let suffixes: Vec<_> = domain_suffixes("a.b.com").collect();
+        assert_eq!(suffixes, vec!["a.b.com", "b.com", "com"]);
+    }
+}
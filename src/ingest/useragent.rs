@@ -1,36 +1,62 @@
-/// Parsed User-Agent information with browser, OS, and bot detection.
+/// Parsed User-Agent information with browser, OS, device, and bot detection.
 #[derive(Debug, Clone, Default)]
 pub struct ParsedUserAgent {
     pub browser: Option<String>,
     pub browser_version: Option<String>,
     pub os: Option<String>,
     pub os_version: Option<String>,
+    /// Maker of a mobile device, e.g. `Apple` or `Samsung`.
+    pub device_brand: Option<String>,
+    /// Model of a mobile device as the UA names it, e.g. `iPhone`, `Pixel 8`
+    /// or `SM-S901B`.
+    pub device_model: Option<String>,
     pub is_bot: bool,
 }
 
+/// Longest device model kept, in characters.
+const MAX_MODEL_LEN: usize = 64;
+
+/// Android model prefixes (lowercase) and the brand they belong to.
+const ANDROID_BRANDS: &[(&str, &str)] = &[
+    ("pixel", "Google"),
+    ("nexus", "Google"),
+    ("sm-", "Samsung"),
+    ("samsung", "Samsung"),
+    ("galaxy", "Samsung"),
+    ("moto", "Motorola"),
+    ("xt", "Motorola"),
+    ("redmi", "Xiaomi"),
+    ("poco", "Xiaomi"),
+    ("mi ", "Xiaomi"),
+    ("xiaomi", "Xiaomi"),
+    ("oneplus", "OnePlus"),
+    ("huawei", "Huawei"),
+    ("honor", "Honor"),
+    ("nokia", "Nokia"),
+    ("lg-", "LG"),
+    ("lm-", "LG"),
+    ("oppo", "OPPO"),
+    ("vivo", "vivo"),
+    ("sony", "Sony"),
+    ("xperia", "Sony"),
+    ("kf", "Amazon"),
+];
+
 /// Parse a User-Agent string into browser, OS, and bot information.
 pub fn parse_user_agent(ua: &str) -> ParsedUserAgent {
     if ua.is_empty() {
         return ParsedUserAgent::default();
     }
 
-    // Check for bots first
-    if is_bot(ua) {
-        return ParsedUserAgent {
-            browser: detect_browser(ua),
-            browser_version: detect_browser_version(ua),
-            os: detect_os(ua),
-            os_version: detect_os_version(ua),
-            is_bot: true,
-        };
-    }
-
+    let (device_brand, device_model) = detect_device(ua);
     ParsedUserAgent {
         browser: detect_browser(ua),
         browser_version: detect_browser_version(ua),
         os: detect_os(ua),
         os_version: detect_os_version(ua),
-        is_bot: false,
+        device_brand,
+        device_model,
+        is_bot: is_bot(ua),
     }
 }
 
@@ -166,6 +192,63 @@ fn detect_os_version(ua: &str) -> Option<String> {
     }
 }
 
+/// Brand and model of a mobile device.  Apple devices are named by the UA;
+/// Android devices by the model token of the platform section, with the
+/// brand inferred from its prefix.
+fn detect_device(ua: &str) -> (Option<String>, Option<String>) {
+    let apple = ["iPad", "iPhone", "iPod"]
+        .into_iter()
+        .find(|device| ua.contains(&format!("({device};")));
+    if let Some(device) = apple {
+        let model = if device == "iPod" {
+            "iPod touch"
+        } else {
+            device
+        };
+        return (Some("Apple".to_string()), Some(model.to_string()));
+    }
+    let Some(model) = android_model(ua) else {
+        return (None, None);
+    };
+    let lower = model.to_lowercase();
+    let brand = ANDROID_BRANDS
+        .iter()
+        .find(|(prefix, _)| lower.starts_with(prefix))
+        .map(|(_, brand)| (*brand).to_string());
+    (brand, Some(model))
+}
+
+/// The model token following `Android <version>` in the platform section,
+/// e.g. `Pixel 8` in `(Linux; Android 14; Pixel 8)`, without its `Build/`
+/// suffix.  Locale, WebView and Firefox tokens are skipped, and the `K`
+/// placeholder sent by Chrome's reduced User-Agent is not a model.
+fn android_model(ua: &str) -> Option<String> {
+    let section = &ua[ua.find("Android")?..];
+    let section = &section[..section.find(')').unwrap_or(section.len())];
+    let model = section
+        .split(';')
+        .skip(1)
+        .map(|token| token.split(" Build/").next().unwrap_or(token).trim())
+        .find(|token| {
+            !token.is_empty()
+                && !matches!(*token, "U" | "wv" | "Mobile" | "Tablet" | "K")
+                && !token.starts_with("rv:")
+                && !is_locale(token)
+        })?;
+    Some(model.chars().take(MAX_MODEL_LEN).collect())
+}
+
+/// Whether `token` looks like a locale such as `en-us` or `ko_KR`.
+fn is_locale(token: &str) -> bool {
+    let bytes = token.as_bytes();
+    bytes.len() == 5
+        && matches!(bytes[2], b'-' | b'_')
+        && bytes[..2]
+            .iter()
+            .chain(&bytes[3..])
+            .all(u8::is_ascii_alphabetic)
+}
+
 fn extract_version_after(ua: &str, prefix: &str) -> Option<String> {
     let pos = ua.find(prefix)?;
     let start = pos + prefix.len();
@@ -319,6 +402,63 @@ mod tests {
         assert!(!parsed.is_bot);
     }
 
+    #[test]
+    fn test_detect_device() {
+        let cases = [
+            (
+                "Mozilla/5.0 (iPhone; CPU iPhone OS 17_2_1 like Mac OS X) AppleWebKit/605.1.15",
+                Some("Apple"),
+                Some("iPhone"),
+            ),
+            (
+                "Mozilla/5.0 (iPad; CPU OS 17_2 like Mac OS X) AppleWebKit/605.1.15",
+                Some("Apple"),
+                Some("iPad"),
+            ),
+            (
+                "Mozilla/5.0 (Linux; Android 14; Pixel 8 Pro) AppleWebKit/537.36 Chrome/120.0 Mobile Safari/537.36",
+                Some("Google"),
+                Some("Pixel 8 Pro"),
+            ),
+            (
+                "Mozilla/5.0 (Linux; Android 13; SM-S901B Build/TP1A.220624.014; wv) AppleWebKit/537.36",
+                Some("Samsung"),
+                Some("SM-S901B"),
+            ),
+            (
+                "Mozilla/5.0 (Linux; U; Android 4.0.3; ko-kr; LG-L160L Build/IML74K) AppleWebkit/534.30",
+                Some("LG"),
+                Some("LG-L160L"),
+            ),
+            (
+                "Mozilla/5.0 (Linux; Android 12; ABC-123) AppleWebKit/537.36",
+                None,
+                Some("ABC-123"),
+            ),
+            // Reduced UA, Firefox and desktops name no model.
+            (
+                "Mozilla/5.0 (Linux; Android 10; K) AppleWebKit/537.36 Chrome/120.0 Mobile Safari/537.36",
+                None,
+                None,
+            ),
+            (
+                "Mozilla/5.0 (Android 14; Mobile; rv:121.0) Gecko/121.0 Firefox/121.0",
+                None,
+                None,
+            ),
+            (
+                "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15",
+                None,
+                None,
+            ),
+        ];
+        for (ua, brand, model) in cases {
+            let parsed = parse_user_agent(ua);
+            assert_eq!(parsed.device_brand.as_deref(), brand, "{ua}");
+            assert_eq!(parsed.device_model.as_deref(), model, "{ua}");
+        }
+    }
+
     #[test]
     fn test_iphone_mac_os_x_edge_case() {
         // L7: iPhone UA strings contain "Mac OS X" — must detect iOS, not macOS
@@ -29,6 +29,19 @@ pub fn daily_salt(secret: &str, date: chrono::NaiveDate) -> String {
     hex::encode(mac.finalize().into_bytes())
 }
 
+/// Hashes a site's own user ID with HMAC-SHA256(site_id || uid, secret).
+///
+/// Unlike the visitor ID this does not rotate, so a logged-in user is counted
+/// once across devices and days. The raw ID is never stored.
+pub fn hash_user_id(secret: &str, site_id: &str, uid: &str) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(site_id.as_bytes());
+    mac.update(b"|");
+    mac.update(uid.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -99,6 +112,16 @@ mod tests {
         let s2 = daily_salt("secret2", date);
         assert_ne!(s1, s2);
     }
+
+    #[test]
+    fn test_hash_user_id() {
+        let id = hash_user_id("secret", "example.com", "user-42");
+        assert_eq!(id, hash_user_id("secret", "example.com", "user-42"));
+        assert_eq!(id.len(), 64);
+        assert_ne!(id, hash_user_id("secret", "other.com", "user-42"));
+        assert_ne!(id, hash_user_id("secret2", "example.com", "user-42"));
+        assert_ne!(id, hash_user_id("secret", "example.com", "user-43"));
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,374 @@
+use crate::ingest::buffer::Event;
+use crate::ingest::transform::IngestTransform;
+use parking_lot::Mutex;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use wasmi::{Engine, Linker, Memory, Module, Store, TypedFunc};
+
+/// How often the module file is checked for changes.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Fuel (roughly, WASM instructions) a module may spend on one event.  A
+/// module that runs out traps, and the event is kept.
+const FUEL_PER_EVENT: u64 = 1_000_000;
+
+/// Largest annotation a module may return, in bytes.
+const MAX_ANNOTATION_BYTES: usize = 4096;
+
+/// `filter` return value that keeps the event unchanged.
+const KEEP: i64 = 0;
+/// `filter` return value that drops the event.
+const DROP: i64 = -1;
+
+/// Runs a user-provided WASM module on every event.
+///
+/// ABI: the module exports `memory`, `alloc(len: i32) -> i32` and
+/// `filter(ptr: i32, len: i32) -> i64`.  For each event the host calls
+/// `alloc`, writes the event as UTF-8 JSON at the returned offset and calls
+/// `filter`, which returns
+///
+/// - `0` to keep the event,
+/// - `-1` to drop it,
+/// - otherwise `(ptr << 32) | len` of a JSON object in module memory whose
+///   keys are merged into the event's `props`.
+///
+/// The host never frees what it allocates; a module typically resets a bump
+/// allocator at the start of `filter`.  The module is reloaded when its file
+/// changes.  Errors and traps keep the event, so a broken module cannot stop
+/// ingestion.
+pub struct WasmFilter {
+    path: PathBuf,
+    engine: Engine,
+    state: Mutex<FilterState>,
+}
+
+struct FilterState {
+    loaded: Option<LoadedModule>,
+    /// Modification time of the loaded (or last failed) file.
+    modified: Option<SystemTime>,
+    checked: Instant,
+}
+
+struct LoadedModule {
+    store: Store<()>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    filter: TypedFunc<(i32, i32), i64>,
+}
+
+/// What a module decided for one event.
+#[derive(Debug, PartialEq, Eq)]
+enum Verdict {
+    Keep,
+    Drop,
+    Annotate(serde_json::Map<String, serde_json::Value>),
+}
+
+impl WasmFilter {
+    /// Create a filter for the module at `path` and load it.  A module that
+    /// fails to load is logged and retried when the file changes.
+    pub fn new(path: &Path) -> Self {
+        let mut config = wasmi::Config::default();
+        config.consume_fuel(true);
+        let filter = Self {
+            path: path.to_path_buf(),
+            engine: Engine::new(&config),
+            state: Mutex::new(FilterState {
+                loaded: None,
+                modified: None,
+                checked: Instant::now(),
+            }),
+        };
+        filter.reload_if_changed(&mut filter.state.lock());
+        filter
+    }
+
+    /// Whether a module is currently loaded.
+    #[cfg(test)]
+    fn is_loaded(&self) -> bool {
+        self.state.lock().loaded.is_some()
+    }
+
+    fn reload_if_changed(&self, state: &mut FilterState) {
+        state.checked = Instant::now();
+        let modified = std::fs::metadata(&self.path)
+            .and_then(|m| m.modified())
+            .ok();
+        if modified.is_some() && modified == state.modified {
+            return;
+        }
+        state.modified = modified;
+        match self.load() {
+            Ok(loaded) => {
+                tracing::info!(path = %self.path.display(), "WASM filter loaded");
+                state.loaded = Some(loaded);
+            }
+            Err(e) => {
+                // Keep running the previous module, if any.
+                tracing::error!(path = %self.path.display(), error = %e, "Failed to load WASM filter");
+            }
+        }
+    }
+
+    fn load(&self) -> Result<LoadedModule, String> {
+        let wasm = std::fs::read(&self.path).map_err(|e| e.to_string())?;
+        let module = Module::new(&self.engine, &wasm).map_err(|e| e.to_string())?;
+        let mut store = Store::new(&self.engine, ());
+        store.set_fuel(FUEL_PER_EVENT).map_err(|e| e.to_string())?;
+        // No imports: the module gets nothing from the host beyond its input.
+        let instance = Linker::<()>::new(&self.engine)
+            .instantiate(&mut store, &module)
+            .and_then(|pre| pre.start(&mut store))
+            .map_err(|e| e.to_string())?;
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or("module does not export `memory`")?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&store, "alloc")
+            .map_err(|e| format!("`alloc`: {e}"))?;
+        let filter = instance
+            .get_typed_func::<(i32, i32), i64>(&store, "filter")
+            .map_err(|e| format!("`filter`: {e}"))?;
+        Ok(LoadedModule {
+            store,
+            memory,
+            alloc,
+            filter,
+        })
+    }
+}
+
+impl LoadedModule {
+    fn run(&mut self, input: &[u8]) -> Result<Verdict, String> {
+        self.store
+            .set_fuel(FUEL_PER_EVENT)
+            .map_err(|e| e.to_string())?;
+        let len = i32::try_from(input.len()).map_err(|_| "event too large")?;
+        let ptr = self
+            .alloc
+            .call(&mut self.store, len)
+            .map_err(|e| e.to_string())?;
+        let offset = usize::try_from(ptr).map_err(|_| "alloc returned a negative offset")?;
+        self.memory
+            .write(&mut self.store, offset, input)
+            .map_err(|e| e.to_string())?;
+        let result = self
+            .filter
+            .call(&mut self.store, (ptr, len))
+            .map_err(|e| e.to_string())?;
+
+        match result {
+            KEEP => Ok(Verdict::Keep),
+            DROP => Ok(Verdict::Drop),
+            packed => {
+                let out_ptr = usize::try_from(packed >> 32).map_err(|_| "invalid result")?;
+                let out_len =
+                    usize::try_from(packed & 0xffff_ffff).map_err(|_| "invalid result")?;
+                if out_len > MAX_ANNOTATION_BYTES {
+                    return Err(format!("annotation exceeds {MAX_ANNOTATION_BYTES} bytes"));
+                }
+                let mut buf = vec![0; out_len];
+                self.memory
+                    .read(&self.store, out_ptr, &mut buf)
+                    .map_err(|e| e.to_string())?;
+                serde_json::from_slice(&buf)
+                    .map(Verdict::Annotate)
+                    .map_err(|e| format!("annotation is not a JSON object: {e}"))
+            }
+        }
+    }
+}
+
+/// Merge `annotation` into the props JSON object, replacing existing keys.
+/// Returns `None` when the result would exceed the props size limit.
+fn merge_props(
+    props: Option<&str>,
+    annotation: serde_json::Map<String, serde_json::Value>,
+) -> Option<String> {
+    let mut merged = props
+        .and_then(|p| serde_json::from_str::<serde_json::Map<_, _>>(p).ok())
+        .unwrap_or_default();
+    merged.extend(annotation);
+    let merged = serde_json::Value::Object(merged).to_string();
+    (merged.len() <= MAX_ANNOTATION_BYTES).then_some(merged)
+}
+
+impl IngestTransform for WasmFilter {
+    fn name(&self) -> &'static str {
+        "wasm_filter"
+    }
+
+    fn apply(&self, event: &mut Event) -> bool {
+        let mut state = self.state.lock();
+        if state.checked.elapsed() >= RELOAD_CHECK_INTERVAL {
+            self.reload_if_changed(&mut state);
+        }
+        let Some(loaded) = state.loaded.as_mut() else {
+            return true;
+        };
+        let Ok(input) = serde_json::to_vec(&*event) else {
+            return true;
+        };
+        match loaded.run(&input) {
+            Ok(Verdict::Keep) => true,
+            Ok(Verdict::Drop) => false,
+            Ok(Verdict::Annotate(annotation)) => {
+                match merge_props(event.props.as_deref(), annotation) {
+                    Some(props) => event.props = Some(props),
+                    None => tracing::warn!("WASM filter annotation dropped: props too large"),
+                }
+                true
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "WASM filter failed; keeping event");
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Drops events whose JSON contains `"spam"`, annotates events whose JSON
+    /// contains `"tag"` with `{"filtered":true}`, and keeps the rest.
+    const FILTER_WAT: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (data (i32.const 0) "{\"filtered\":true}")
+          (global $next (mut i32) (i32.const 1024))
+          (func (export "alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            (local.set $ptr (global.get $next))
+            (global.set $next (i32.add (global.get $next) (local.get $len)))
+            (local.get $ptr))
+          (func $contains (param $ptr i32) (param $len i32) (param $a i32) (param $b i32)
+                          (param $c i32) (param $d i32) (result i32)
+            (local $i i32)
+            (block $done
+              (loop $scan
+                (br_if $done (i32.gt_s (i32.add (local.get $i) (i32.const 4)) (local.get $len)))
+                (if (i32.and
+                      (i32.and
+                        (i32.eq (i32.load8_u (i32.add (local.get $ptr) (local.get $i))) (local.get $a))
+                        (i32.eq (i32.load8_u offset=1 (i32.add (local.get $ptr) (local.get $i))) (local.get $b)))
+                      (i32.and
+                        (i32.eq (i32.load8_u offset=2 (i32.add (local.get $ptr) (local.get $i))) (local.get $c))
+                        (i32.eq (i32.load8_u offset=3 (i32.add (local.get $ptr) (local.get $i))) (local.get $d))))
+                  (then (return (i32.const 1))))
+                (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                (br $scan)))
+            (i32.const 0))
+          (func (export "filter") (param $ptr i32) (param $len i32) (result i64)
+            (global.set $next (i32.const 1024))
+            ;; "spam"
+            (if (call $contains (local.get $ptr) (local.get $len)
+                  (i32.const 115) (i32.const 112) (i32.const 97) (i32.const 109))
+              (then (return (i64.const -1))))
+            ;; "/tag"
+            (if (call $contains (local.get $ptr) (local.get $len)
+                  (i32.const 47) (i32.const 116) (i32.const 97) (i32.const 103))
+              (then (return (i64.const 17))))
+            (i64.const 0)))
+    "#;
+
+    const LOOP_WAT: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "alloc") (param i32) (result i32) (i32.const 0))
+          (func (export "filter") (param i32 i32) (result i64)
+            (loop $forever (br $forever))
+            (i64.const -1)))
+    "#;
+
+    fn event(pathname: &str) -> Event {
+        Event {
+            site_id: "test.com".to_string(),
+            visitor_id: "v1".to_string(),
+            timestamp: chrono::Utc::now().naive_utc(),
+            event_name: "pageview".to_string(),
+            pathname: pathname.to_string(),
+            hostname: None,
+            referrer: None,
+            referrer_source: None,
+            utm_source: None,
+            utm_medium: None,
+            utm_campaign: None,
+            utm_content: None,
+            utm_term: None,
+            browser: None,
+            browser_version: None,
+            os: None,
+            os_version: None,
+            device_type: None,
+            screen_size: None,
+            country_code: None,
+            region: None,
+            city: None,
+            props: Some(r#"{"plan":"pro"}"#.to_string()),
+            revenue_amount: None,
+            revenue_currency: None,
+            dimensions: [const { None }; crate::config::MAX_CUSTOM_DIMENSIONS],
+            prev_pathname: None,
+            user_id: None,
+            device_brand: None,
+            device_model: None,
+        }
+    }
+
+    fn write_module(dir: &Path, wat: &str) -> PathBuf {
+        let path = dir.join("filter.wasm");
+        std::fs::write(&path, wat::parse_str(wat).unwrap()).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_keep_drop_annotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let filter = WasmFilter::new(&write_module(dir.path(), FILTER_WAT));
+        assert!(filter.is_loaded());
+
+        let mut e = event("/pricing");
+        assert!(filter.apply(&mut e));
+        assert_eq!(e.props.as_deref(), Some(r#"{"plan":"pro"}"#));
+
+        assert!(!filter.apply(&mut event("/spam-offer")));
+
+        let mut e = event("/tag/rust");
+        assert!(filter.apply(&mut e));
+        let props: serde_json::Value = serde_json::from_str(e.props.as_deref().unwrap()).unwrap();
+        assert_eq!(props["plan"], "pro");
+        assert_eq!(props["filtered"], true);
+    }
+
+    #[test]
+    fn test_runaway_module_keeps_event() {
+        let dir = tempfile::tempdir().unwrap();
+        let filter = WasmFilter::new(&write_module(dir.path(), LOOP_WAT));
+        assert!(filter.apply(&mut event("/")));
+    }
+
+    #[test]
+    fn test_invalid_module_keeps_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("filter.wasm");
+        std::fs::write(&path, b"not wasm").unwrap();
+        let filter = WasmFilter::new(&path);
+        assert!(!filter.is_loaded());
+        assert!(filter.apply(&mut event("/spam")));
+    }
+
+    #[test]
+    fn test_merge_props() {
+        let annotation = serde_json::Map::from_iter([("a".to_string(), serde_json::json!(1))]);
+        assert_eq!(
+            merge_props(None, annotation.clone()).as_deref(),
+            Some(r#"{"a":1}"#)
+        );
+        assert_eq!(
+            merge_props(Some(r#"{"a":0,"b":2}"#), annotation).as_deref(),
+            Some(r#"{"a":1,"b":2}"#)
+        );
+    }
+}
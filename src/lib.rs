@@ -1,7 +1,18 @@
 pub mod api;
+#[cfg(feature = "client")]
+pub mod client;
 pub mod config;
 pub mod dashboard;
+pub mod embed;
 pub mod ingest;
+pub mod loadgen;
+pub mod logging;
+pub mod notify;
 pub mod query;
 pub mod server;
+pub mod statsd;
 pub mod storage;
+#[cfg(feature = "tracking-layer")]
+pub mod track;
+pub mod upgrade;
+pub mod watchdog;
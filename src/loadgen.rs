@@ -0,0 +1,699 @@
+//! Synthetic traffic for load tests and demo data (`mallard-metrics generate`).
+//!
+//! Visits follow a rough model of real traffic: fewer visitors at night and
+//! at weekends, a few pages taking most of the views, a mix of search,
+//! social, campaign and direct visits, common browsers, devices and
+//! countries, sessions of a few pages with reading time between them, and
+//! the occasional signup or purchase.  The same seed always produces the
+//! same traffic.
+//!
+//! Visits are either written straight to Parquet through the event buffer
+//! ([`write_history`]), or sent to a running server's `POST /api/event` at a
+//! fixed rate ([`send_load`]), which reports the server's status codes and
+//! response latencies.
+
+use crate::ingest::buffer::{BufferError, Event, EventBuffer};
+use crate::ingest::handler::{
+    classify_device, extract_referrer_source, parse_utm_params, EventPayload,
+};
+use crate::ingest::useragent;
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, Weekday};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// Browsers on devices: User-Agent, screen width, weight.  Each list is
+/// picked from in proportion to the weights.
+const AGENTS: &[(&str, u32, u32)] = &[
+    (
+        "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36",
+        1920,
+        28,
+    ),
+    (
+        "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36",
+        1440,
+        12,
+    ),
+    (
+        "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.2 Safari/605.1.15",
+        1440,
+        8,
+    ),
+    (
+        "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:121.0) Gecko/20100101 Firefox/121.0",
+        1920,
+        6,
+    ),
+    (
+        "Mozilla/5.0 (X11; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0",
+        1920,
+        3,
+    ),
+    (
+        "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36 Edg/120.0.0.0",
+        1920,
+        6,
+    ),
+    (
+        "Mozilla/5.0 (iPhone; CPU iPhone OS 17_2 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.2 Mobile/15E148 Safari/604.1",
+        390,
+        18,
+    ),
+    (
+        "Mozilla/5.0 (Linux; Android 14; Pixel 8) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Mobile Safari/537.36",
+        412,
+        14,
+    ),
+    (
+        "Mozilla/5.0 (iPad; CPU OS 17_2 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.2 Mobile/15E148 Safari/604.1",
+        820,
+        3,
+    ),
+];
+
+/// Where visits come from: referrer, campaign query string of the landing
+/// page, weight.  Neither means a direct visit.
+const SOURCES: &[(Option<&str>, Option<&str>, u32)] = &[
+    (None, None, 35),
+    (Some("https://www.google.com/"), None, 30),
+    (Some("https://www.bing.com/"), None, 4),
+    (Some("https://duckduckgo.com/"), None, 4),
+    (Some("https://news.ycombinator.com/"), None, 6),
+    (Some("https://www.reddit.com/r/selfhosted/"), None, 5),
+    (Some("https://t.co/"), None, 5),
+    (Some("https://github.com/"), None, 5),
+    (
+        None,
+        Some("utm_source=newsletter&utm_medium=email&utm_campaign=launch"),
+        6,
+    ),
+];
+
+/// Country code, region, city, weight.
+const GEOS: &[(&str, &str, &str, u32)] = &[
+    ("US", "California", "San Francisco", 12),
+    ("US", "New York", "New York", 10),
+    ("US", "Texas", "Austin", 5),
+    ("GB", "England", "London", 9),
+    ("DE", "Berlin", "Berlin", 8),
+    ("DE", "Bavaria", "Munich", 4),
+    ("FR", "Ile-de-France", "Paris", 6),
+    ("NL", "North Holland", "Amsterdam", 4),
+    ("CA", "Ontario", "Toronto", 5),
+    ("IN", "Karnataka", "Bengaluru", 7),
+    ("BR", "Sao Paulo", "Sao Paulo", 4),
+    ("JP", "Tokyo", "Tokyo", 4),
+    ("AU", "New South Wales", "Sydney", 3),
+];
+
+/// Pathname, weight.
+const PAGES: &[(&str, u32)] = &[
+    ("/", 30),
+    ("/pricing", 12),
+    ("/blog", 10),
+    ("/blog/duckdb-analytics", 8),
+    ("/blog/privacy-first", 6),
+    ("/docs", 9),
+    ("/docs/getting-started", 7),
+    ("/features", 6),
+    ("/about", 4),
+    ("/contact", 3),
+    ("/signup", 5),
+];
+
+/// Relative share of a day's visits starting in each UTC hour.
+const HOURLY_WEIGHTS: [u32; 24] = [
+    2, 1, 1, 1, 1, 2, 3, 5, 7, 8, 9, 9, 9, 9, 9, 9, 8, 8, 7, 7, 6, 5, 4, 3,
+];
+
+/// Site that `--seed-demo-data` fills with sample traffic.
+pub const DEMO_SITE_ID: &str = "demo.example.com";
+/// Days of demo history, ending today.
+const DEMO_DAYS: i64 = 42;
+/// Demo visitors on a weekday.
+const DEMO_VISITORS: u32 = 300;
+/// Fixed so every installation shows the same demo.
+const DEMO_SEED: u64 = 42;
+
+/// Chance that a visitor goes on to another page.
+const CONTINUE_PROBABILITY: f64 = 0.55;
+/// Most pages in one visit.
+const MAX_PAGES: usize = 20;
+/// Mean reading time between pages, in seconds.
+const MEAN_READ_SECS: f64 = 45.0;
+/// Chance of a signup, and of a purchase, after each pageview.
+const SIGNUP_PROBABILITY: f64 = 0.02;
+const PURCHASE_PROBABILITY: f64 = 0.005;
+
+/// One event of a [`Visit`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hit {
+    pub timestamp: NaiveDateTime,
+    pub name: &'static str,
+    pub pathname: &'static str,
+    /// Only on the first pageview of a visit.
+    pub referrer: Option<&'static str>,
+    /// Campaign query string of the landing page.
+    pub utm_query: Option<&'static str>,
+    pub props: Option<String>,
+    pub revenue_amount: Option<f64>,
+}
+
+/// The events of one visitor's session on a site.
+pub struct Visit {
+    pub site_id: String,
+    /// Stands in for the HMAC the server would compute.
+    pub visitor_id: String,
+    /// Client IP to send with `X-Forwarded-For`, from the 198.18.0.0/15
+    /// benchmarking range.
+    pub ip: String,
+    pub user_agent: &'static str,
+    screen_width: u32,
+    /// Country code, region and city.
+    geo: (&'static str, &'static str, &'static str),
+    pub hits: Vec<Hit>,
+}
+
+impl Visit {
+    /// The events as stored, enriched the way the server would enrich them
+    /// (with `geoip_precision = "city"` and no privacy reductions).
+    pub fn events(&self) -> Vec<Event> {
+        let ua = useragent::parse_user_agent(self.user_agent);
+        self.hits
+            .iter()
+            .map(|hit| {
+                let (utm_source, utm_medium, utm_campaign, utm_content, utm_term) =
+                    parse_utm_params(&self.url(hit));
+                Event {
+                    site_id: self.site_id.clone(),
+                    visitor_id: self.visitor_id.clone(),
+                    timestamp: hit.timestamp,
+                    event_name: hit.name.to_string(),
+                    pathname: hit.pathname.to_string(),
+                    hostname: Some(self.site_id.clone()),
+                    referrer: hit.referrer.map(str::to_string),
+                    referrer_source: hit.referrer.and_then(extract_referrer_source),
+                    utm_source,
+                    utm_medium,
+                    utm_campaign,
+                    utm_content,
+                    utm_term,
+                    browser: ua.browser.clone(),
+                    browser_version: ua.browser_version.clone(),
+                    os: ua.os.clone(),
+                    os_version: ua.os_version.clone(),
+                    device_type: Some(classify_device(self.screen_width)),
+                    screen_size: Some(self.screen_width.to_string()),
+                    country_code: Some(self.geo.0.to_string()),
+                    region: Some(self.geo.1.to_string()),
+                    city: Some(self.geo.2.to_string()),
+                    props: hit.props.clone(),
+                    revenue_amount: hit.revenue_amount,
+                    revenue_currency: hit.revenue_amount.map(|_| "USD".to_string()),
+                    dimensions: [const { None }; crate::config::MAX_CUSTOM_DIMENSIONS],
+                    prev_pathname: None,
+                    user_id: None,
+                    device_brand: None,
+                    device_model: None,
+                }
+            })
+            .collect()
+    }
+
+    /// The `POST /api/event` payloads the tracking script would send.
+    pub fn payloads(&self) -> Vec<EventPayload> {
+        self.hits
+            .iter()
+            .map(|hit| EventPayload {
+                domain: self.site_id.clone(),
+                name: hit.name.to_string(),
+                url: self.url(hit),
+                referrer: hit.referrer.map(str::to_string),
+                screen_width: Some(self.screen_width),
+                props: hit.props.clone(),
+                revenue_amount: hit.revenue_amount,
+                revenue_currency: hit.revenue_amount.map(|_| "USD".to_string()),
+                event_id: Some(uuid::Uuid::new_v4().to_string()),
+                prev_pathname: None,
+                user_id: None,
+                timestamp: None,
+            })
+            .collect()
+    }
+
+    fn url(&self, hit: &Hit) -> String {
+        hit.utm_query.map_or_else(
+            || format!("https://{}{}", self.site_id, hit.pathname),
+            |query| format!("https://{}{}?{query}", self.site_id, hit.pathname),
+        )
+    }
+}
+
+/// Seeded source of synthetic visits.
+pub struct TrafficGenerator {
+    rng: StdRng,
+}
+
+impl TrafficGenerator {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// The visits to `site_id` on `day`: about `visitors` on a weekday, 40%
+    /// fewer at weekends, varying by up to 15% from day to day, spread over
+    /// the day like real traffic.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    pub fn day(&mut self, site_id: &str, day: NaiveDate, visitors: u32) -> Vec<Visit> {
+        let weekend = matches!(day.weekday(), Weekday::Sat | Weekday::Sun);
+        let base = if weekend { 0.6 } else { 1.0 };
+        let scale = base * self.rng.random_range(0.85..1.15);
+        let count = (f64::from(visitors) * scale).round() as usize;
+        let mut visits: Vec<Visit> = (0..count)
+            .map(|_| {
+                let hour = pick_index(&mut self.rng, &HOURLY_WEIGHTS);
+                let start = day.and_time(chrono::NaiveTime::MIN)
+                    + Duration::hours(i64::try_from(hour).unwrap_or_default())
+                    + Duration::seconds(self.rng.random_range(0..3600));
+                self.visit(site_id, start)
+            })
+            .collect();
+        visits.sort_by_key(|visit| visit.hits[0].timestamp);
+        visits
+    }
+
+    /// One visit to `site_id` starting at `start`.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn visit(&mut self, site_id: &str, start: NaiveDateTime) -> Visit {
+        let &(user_agent, screen_width, _) = pick(&mut self.rng, AGENTS, |a| a.2);
+        let &(country_code, region, city, _) = pick(&mut self.rng, GEOS, |g| g.3);
+        let &(referrer, utm_query, _) = pick(&mut self.rng, SOURCES, |s| s.2);
+        let ip = format!(
+            "198.{}.{}.{}",
+            self.rng.random_range(18..20),
+            self.rng.random_range(0..=255),
+            self.rng.random_range(1..=254)
+        );
+        let visitor_id = format!("{:016x}", self.rng.random::<u64>());
+
+        let mut hits = Vec::new();
+        let mut timestamp = start;
+        for page in 0..MAX_PAGES {
+            if page > 0 {
+                if !self.rng.random_bool(CONTINUE_PROBABILITY) {
+                    break;
+                }
+                // Exponentially distributed reading time, 2s to 15min.
+                let u: f64 = self.rng.random();
+                let secs = (-MEAN_READ_SECS * (1.0 - u).ln()).clamp(2.0, 900.0);
+                timestamp += Duration::seconds(secs as i64);
+            }
+            let &(pathname, _) = pick(&mut self.rng, PAGES, |p| p.1);
+            hits.push(Hit {
+                timestamp,
+                name: "pageview",
+                pathname,
+                referrer: referrer.filter(|_| page == 0),
+                utm_query: utm_query.filter(|_| page == 0),
+                props: None,
+                revenue_amount: None,
+            });
+            if self.rng.random_bool(SIGNUP_PROBABILITY) {
+                let plan = if self.rng.random_bool(0.2) {
+                    "pro"
+                } else {
+                    "free"
+                };
+                let hit = self.custom_hit(timestamp, "signup", pathname, Some(plan), None);
+                timestamp = hit.timestamp;
+                hits.push(hit);
+            }
+            if self.rng.random_bool(PURCHASE_PROBABILITY) {
+                let cents: u32 = self.rng.random_range(900..20_000);
+                let amount = f64::from(cents) / 100.0;
+                let hit = self.custom_hit(timestamp, "purchase", pathname, None, Some(amount));
+                timestamp = hit.timestamp;
+                hits.push(hit);
+            }
+        }
+
+        Visit {
+            site_id: site_id.to_string(),
+            visitor_id,
+            ip,
+            user_agent,
+            screen_width,
+            geo: (country_code, region, city),
+            hits,
+        }
+    }
+
+    fn custom_hit(
+        &mut self,
+        after: NaiveDateTime,
+        name: &'static str,
+        pathname: &'static str,
+        plan: Option<&str>,
+        revenue_amount: Option<f64>,
+    ) -> Hit {
+        Hit {
+            timestamp: after + Duration::seconds(self.rng.random_range(1..30)),
+            name,
+            pathname,
+            referrer: None,
+            utm_query: None,
+            props: plan.map(|plan| serde_json::json!({ "plan": plan }).to_string()),
+            revenue_amount,
+        }
+    }
+}
+
+/// An index into `weights`, in proportion to the weights.
+fn pick_index(rng: &mut StdRng, weights: &[u32]) -> usize {
+    let total: u32 = weights.iter().sum();
+    let mut roll = rng.random_range(0..total);
+    for (i, &weight) in weights.iter().enumerate() {
+        if roll < weight {
+            return i;
+        }
+        roll -= weight;
+    }
+    weights.len() - 1
+}
+
+/// One of `choices`, in proportion to `weight`.
+fn pick<T>(rng: &mut StdRng, choices: &'static [T], weight: impl Fn(&T) -> u32) -> &'static T {
+    let weights: Vec<u32> = choices.iter().map(weight).collect();
+    &choices[pick_index(rng, &weights)]
+}
+
+/// Write `visitors` a day for each of `sites` from `start` to `end`
+/// inclusive through `buffer`, flushing to Parquet as it fills.  Returns the
+/// number of events written.
+pub fn write_history(
+    generator: &mut TrafficGenerator,
+    buffer: &EventBuffer,
+    sites: &[String],
+    start: NaiveDate,
+    end: NaiveDate,
+    visitors: u32,
+) -> Result<usize, BufferError> {
+    let mut written = 0;
+    for day in start.iter_days().take_while(|day| *day <= end) {
+        for site_id in sites {
+            for visit in generator.day(site_id, day, visitors) {
+                for event in visit.events() {
+                    buffer.push(event)?;
+                    written += 1;
+                }
+            }
+        }
+    }
+    buffer.flush()?;
+    Ok(written)
+}
+
+/// Fill [`DEMO_SITE_ID`] with six weeks of traffic up to today.  Returns
+/// the number of events written.
+pub fn seed_demo(buffer: &EventBuffer) -> Result<usize, BufferError> {
+    let end = chrono::Utc::now().date_naive();
+    write_history(
+        &mut TrafficGenerator::new(DEMO_SEED),
+        buffer,
+        &[DEMO_SITE_ID.to_string()],
+        end - Duration::days(DEMO_DAYS - 1),
+        end,
+        DEMO_VISITORS,
+    )
+}
+
+/// How [`send_load`] sends events.
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    /// Base URL of the server, e.g. `http://localhost:8000`.
+    pub url: String,
+    pub sites: Vec<String>,
+    /// Events to send in total.
+    pub events: usize,
+    /// Events per second; 0 sends as fast as `concurrency` allows.
+    pub rate: u32,
+    /// Requests in flight at once.
+    pub concurrency: usize,
+}
+
+/// What the server made of the events [`send_load`] sent.
+#[derive(Debug, Default)]
+pub struct LoadReport {
+    pub sent: usize,
+    /// Responses by HTTP status.
+    pub statuses: BTreeMap<u16, usize>,
+    /// Requests that got no response.
+    pub errors: usize,
+    pub elapsed: std::time::Duration,
+    latencies: Vec<std::time::Duration>,
+}
+
+impl LoadReport {
+    fn record(&mut self, result: (Option<u16>, std::time::Duration)) {
+        match result.0 {
+            Some(status) => *self.statuses.entry(status).or_default() += 1,
+            None => self.errors += 1,
+        }
+        self.latencies.push(result.1);
+    }
+
+    /// Response latency at `pct` percent, e.g. 99 for p99.
+    pub fn latency_percentile(&self, pct: usize) -> std::time::Duration {
+        let mut latencies = self.latencies.clone();
+        latencies.sort_unstable();
+        let index = (latencies.len() * pct).div_ceil(100).saturating_sub(1);
+        latencies
+            .get(index.min(latencies.len().saturating_sub(1)))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+impl fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.elapsed.as_secs_f64().max(f64::EPSILON);
+        #[allow(clippy::cast_precision_loss)]
+        let throughput = self.sent as f64 / secs;
+        writeln!(
+            f,
+            "Sent {} events in {:.1}s ({throughput:.0}/s)",
+            self.sent, secs
+        )?;
+        for (status, count) in &self.statuses {
+            writeln!(f, "  HTTP {status}: {count}")?;
+        }
+        if self.errors > 0 {
+            writeln!(f, "  no response: {}", self.errors)?;
+        }
+        write!(
+            f,
+            "Latency p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+            self.latency_percentile(50),
+            self.latency_percentile(90),
+            self.latency_percentile(99),
+            self.latency_percentile(100)
+        )
+    }
+}
+
+/// Send `options.events` events to the server's `POST /api/event`, visit by
+/// visit, each from its own client IP and User-Agent.
+///
+/// Events are timestamped by the server on arrival, so sessions span the
+/// send time rather than the modelled reading time.
+pub async fn send_load(
+    generator: &mut TrafficGenerator,
+    options: &LoadOptions,
+) -> Result<LoadReport, reqwest::Error> {
+    let client = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(10))
+        .build()?;
+    let endpoint = format!("{}/api/event", options.url.trim_end_matches('/'));
+    let permits = Arc::new(tokio::sync::Semaphore::new(options.concurrency.max(1)));
+    let mut ticker = (options.rate > 0).then(|| {
+        tokio::time::interval(std::time::Duration::from_secs_f64(
+            1.0 / f64::from(options.rate),
+        ))
+    });
+    let mut tasks = tokio::task::JoinSet::new();
+    let mut report = LoadReport::default();
+    let started = std::time::Instant::now();
+
+    'send: for site_id in options.sites.iter().cycle() {
+        let visit = generator.visit(site_id, chrono::Utc::now().naive_utc());
+        for payload in visit.payloads() {
+            if report.sent == options.events {
+                break 'send;
+            }
+            if let Some(ticker) = &mut ticker {
+                ticker.tick().await;
+            }
+            let Ok(permit) = Arc::clone(&permits).acquire_owned().await else {
+                break 'send;
+            };
+            let request = client
+                .post(&endpoint)
+                .header("content-type", "application/json")
+                .header("user-agent", visit.user_agent)
+                .header("x-forwarded-for", &visit.ip)
+                .body(serde_json::to_vec(&payload).unwrap_or_default());
+            tasks.spawn(async move {
+                let sent_at = std::time::Instant::now();
+                let status = request.send().await.ok().map(|r| r.status().as_u16());
+                drop(permit);
+                (status, sent_at.elapsed())
+            });
+            report.sent += 1;
+            while let Some(done) = tasks.try_join_next() {
+                if let Ok(result) = done {
+                    report.record(result);
+                }
+            }
+        }
+    }
+    while let Some(done) = tasks.join_next().await {
+        if let Ok(result) = done {
+            report.record(result);
+        }
+    }
+    report.elapsed = started.elapsed();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::handler::validate_payload;
+    use crate::storage::parquet::ParquetStorage;
+    use duckdb::Connection;
+    use parking_lot::Mutex;
+
+    fn day() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 1, 15).unwrap()
+    }
+
+    #[test]
+    fn test_same_seed_same_traffic() {
+        let summary = |seed| {
+            TrafficGenerator::new(seed)
+                .day("a.com", day(), 50)
+                .iter()
+                .flat_map(|visit| visit.hits.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(summary(7), summary(7));
+        assert_ne!(summary(7), summary(8));
+    }
+
+    #[test]
+    fn test_day_shape() {
+        let mut generator = TrafficGenerator::new(1);
+        let weekday = generator.day("a.com", day(), 1000);
+        assert!((850..=1150).contains(&weekday.len()));
+        let saturday = generator.day("a.com", NaiveDate::from_ymd_opt(2024, 1, 20).unwrap(), 1000);
+        assert!(saturday.len() < weekday.len());
+
+        for visit in &weekday {
+            assert!(!visit.hits.is_empty() && visit.hits.len() <= 3 * MAX_PAGES);
+            assert_eq!(visit.hits[0].name, "pageview");
+            assert_eq!(visit.hits[0].timestamp.date(), day());
+            assert!(visit
+                .hits
+                .windows(2)
+                .all(|w| w[0].timestamp <= w[1].timestamp));
+        }
+        let pageviews = weekday
+            .iter()
+            .flat_map(|v| &v.hits)
+            .filter(|h| h.name == "pageview");
+        let bounces = weekday.iter().filter(|v| v.hits.len() == 1).count();
+        assert!(pageviews.count() > weekday.len());
+        assert!(bounces > weekday.len() / 4 && bounces < weekday.len() * 3 / 4);
+    }
+
+    #[test]
+    fn test_payloads_are_valid() {
+        let mut generator = TrafficGenerator::new(3);
+        for visit in generator.day("a.com", day(), 200) {
+            for payload in visit.payloads() {
+                assert!(validate_payload(&payload).is_empty(), "{payload:?}");
+            }
+            let events = visit.events();
+            assert!(events.iter().all(|e| e.browser.is_some() && e.os.is_some()));
+        }
+    }
+
+    #[test]
+    fn test_campaign_visits_carry_utm_fields() {
+        let mut generator = TrafficGenerator::new(5);
+        let events: Vec<Event> = generator
+            .day("a.com", day(), 500)
+            .iter()
+            .flat_map(Visit::events)
+            .collect();
+        assert!(events
+            .iter()
+            .any(|e| e.utm_campaign.as_deref() == Some("launch")));
+        assert!(events
+            .iter()
+            .any(|e| e.referrer_source.as_deref() == Some("Google")));
+    }
+
+    #[test]
+    fn test_write_history() {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::storage::schema::init_schema(&conn).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        crate::storage::schema::setup_query_view(&conn, dir.path()).unwrap();
+        let buffer = EventBuffer::new(
+            500,
+            Arc::new(Mutex::new(conn)),
+            ParquetStorage::new(dir.path()),
+        );
+        let sites = vec!["a.com".to_string(), "b.com".to_string()];
+        let written = write_history(
+            &mut TrafficGenerator::new(9),
+            &buffer,
+            &sites,
+            day(),
+            day() + Duration::days(2),
+            50,
+        )
+        .unwrap();
+
+        let conn = buffer.conn().lock();
+        let (rows, sites, days): (u64, u64, u64) = conn
+            .query_row(
+                "SELECT COUNT(*), COUNT(DISTINCT site_id), COUNT(DISTINCT CAST(timestamp AS DATE))
+                 FROM events_all",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        drop(conn);
+        assert_eq!(rows, u64::try_from(written).unwrap());
+        assert_eq!(sites, 2);
+        assert_eq!(days, 3);
+    }
+
+    #[test]
+    fn test_latency_percentile() {
+        let mut report = LoadReport::default();
+        for ms in 1..=100 {
+            report.record((Some(202), std::time::Duration::from_millis(ms)));
+        }
+        assert_eq!(report.latency_percentile(50).as_millis(), 50);
+        assert_eq!(report.latency_percentile(99).as_millis(), 99);
+        assert_eq!(report.latency_percentile(100).as_millis(), 100);
+        assert_eq!(report.statuses[&202], 100);
+    }
+}
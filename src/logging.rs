@@ -0,0 +1,384 @@
+//! Log output: per-target levels and ingest log sampling from the
+//! `[logging]` table of the config file, and redaction of client data.
+//!
+//! PRIVACY: every log line passes through [`LogWriter`] before it is
+//! written.  Client IP addresses are replaced with `[redacted]` in info,
+//! debug and trace lines, and anonymized (`203.0.113.x`) in warnings and
+//! errors.  Values of `user_agent` fields are always replaced.  Loopback
+//! and unspecified addresses, such as the listen address, are kept.
+
+use crate::config::LoggingConfig;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+/// Replacement for removed values.
+const REDACTED: &str = "[redacted]";
+
+/// Target prefix of the events [`LoggingConfig::ingest_sample_every`] samples.
+const INGEST_TARGET: &str = "mallard_metrics::ingest";
+
+/// Field whose values are always redacted.
+const USER_AGENT_FIELD: &str = "user_agent";
+
+/// Handle for switching to the configured settings once the config has
+/// loaded.
+pub struct LogHandle {
+    filter: reload::Handle<EnvFilter, Registry>,
+    sample_every: Arc<AtomicU64>,
+}
+
+/// Install the global subscriber: JSON lines when `MALLARD_LOG_FORMAT=json`,
+/// text otherwise, filtered by `RUST_LOG` or the default levels.
+///
+/// It starts before the config loads, so warnings about the config are
+/// written too.
+pub fn init() -> LogHandle {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(crate::config::default_log_filter()));
+    let (filter, filter_handle) = reload::Layer::new(filter);
+    let writer = LogWriter::new();
+    let sample_every = Arc::clone(&writer.sample_every);
+    let json = std::env::var("MALLARD_LOG_FORMAT").is_ok_and(|f| f == "json");
+    let (json_layer, text_layer) = if json {
+        (
+            Some(tracing_subscriber::fmt::layer().json().with_writer(writer)),
+            None,
+        )
+    } else {
+        (
+            None,
+            Some(tracing_subscriber::fmt::layer().with_writer(writer)),
+        )
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(json_layer)
+        .with(text_layer)
+        .init();
+    LogHandle {
+        filter: filter_handle,
+        sample_every,
+    }
+}
+
+impl LogHandle {
+    /// Switch to the `[logging]` settings: its filter, unless `RUST_LOG` is
+    /// set, and its ingest sampling.
+    pub fn apply(&self, config: &LoggingConfig) {
+        if std::env::var_os("RUST_LOG").is_none() {
+            match EnvFilter::try_new(&config.filter) {
+                Ok(filter) => {
+                    if let Err(e) = self.filter.reload(filter) {
+                        tracing::warn!(error = %e, "Could not apply logging.filter");
+                    }
+                }
+                Err(e) => tracing::warn!(error = %e, "Invalid logging.filter; keeping defaults"),
+            }
+        }
+        self.sample_every
+            .store(config.ingest_sample_every.max(1), Ordering::Relaxed);
+    }
+}
+
+/// Writes formatted log lines to stdout, redacted and sampled.
+pub struct LogWriter {
+    sample_every: Arc<AtomicU64>,
+    /// Ingest events seen, for sampling.
+    ingest_events: AtomicU64,
+}
+
+impl LogWriter {
+    fn new() -> Self {
+        Self {
+            sample_every: Arc::new(AtomicU64::new(1)),
+            ingest_events: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether to write an event at `level` from `target`: always, unless it
+    /// is an info, debug or trace event of the ingest pipeline that sampling
+    /// skips.
+    fn keep(&self, target: &str, level: Level) -> bool {
+        if level <= Level::WARN || !target.starts_with(INGEST_TARGET) {
+            return true;
+        }
+        let every = self.sample_every.load(Ordering::Relaxed).max(1);
+        self.ingest_events
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(every)
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogWriter {
+    type Writer = EventWriter;
+
+    /// Without metadata the level is unknown, so redact fully.
+    fn make_writer(&'a self) -> EventWriter {
+        EventWriter {
+            output: Some(false),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> EventWriter {
+        let level = *meta.level();
+        EventWriter {
+            output: self
+                .keep(meta.target(), level)
+                .then_some(level <= Level::WARN),
+        }
+    }
+}
+
+/// Writer for one formatted event.
+pub struct EventWriter {
+    /// `None` drops the event; `Some(anonymize)` writes it, anonymizing IPs
+    /// rather than removing them when `anonymize` is set.
+    output: Option<bool>,
+}
+
+impl Write for EventWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(anonymize) = self.output {
+            let line = redact(&String::from_utf8_lossy(buf), anonymize);
+            io::stdout().lock().write_all(line.as_bytes())?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().lock().flush()
+    }
+}
+
+/// `line` with `user_agent` values and client IPs redacted, or the IPs
+/// anonymized when `anonymize` is set.  ANSI colour codes are kept, and
+/// never read as part of a value.
+pub fn redact(line: &str, anonymize: bool) -> String {
+    let line = redact_user_agents(line);
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line.as_str();
+    while let Some(esc) = rest.find('\x1b') {
+        redact_ips(&rest[..esc], anonymize, &mut out);
+        let end = rest[esc..].find('m').map_or(rest.len(), |i| esc + i + 1);
+        out.push_str(&rest[esc..end]);
+        rest = &rest[end..];
+    }
+    redact_ips(rest, anonymize, &mut out);
+    out
+}
+
+/// Append `text` to `out`, replacing each client IP address, optionally
+/// with a port, that stands alone between separators.
+fn redact_ips(text: &str, anonymize: bool, out: &mut String) {
+    // Letters are part of tokens so that e.g. `crate::module` or a timestamp
+    // is one token that is not an address.
+    let is_token_char = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | ':' | '_' | '%');
+    let mut rest = text;
+    while let Some(start) = rest.find(is_token_char) {
+        out.push_str(&rest[..start]);
+        let token = &rest[start..];
+        let token = &token[..token.find(|c| !is_token_char(c)).unwrap_or(token.len())];
+        let address = token.trim_end_matches(['.', ':']);
+        match client_ip(address) {
+            Some(ip) if anonymize => {
+                out.push_str(&crate::api::auth::anonymize_ip(&ip.to_string()));
+                out.push_str(&token[address.len()..]);
+            }
+            Some(_) => {
+                out.push_str(REDACTED);
+                out.push_str(&token[address.len()..]);
+            }
+            None => out.push_str(token),
+        }
+        rest = &rest[start + token.len()..];
+    }
+    out.push_str(rest);
+}
+
+/// The IP address in `token`, an address or `address:port`, unless it is a
+/// loopback or unspecified address.
+fn client_ip(token: &str) -> Option<IpAddr> {
+    let ip = token
+        .parse::<IpAddr>()
+        .or_else(|_| token.parse::<SocketAddr>().map(|a| a.ip()))
+        .ok()?;
+    (!ip.is_loopback() && !ip.is_unspecified()).then_some(ip)
+}
+
+/// `line` with the value of every `user_agent` field replaced, in the JSON
+/// (`"user_agent":"..."`) and text (`user_agent=...`) formats.
+fn redact_user_agents(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(i) = rest.find(USER_AGENT_FIELD) {
+        let (before, after) = rest.split_at(i + USER_AGENT_FIELD.len());
+        out.push_str(before);
+        rest = after;
+        let starts_field = trim_ansi_end(&before[..i])
+            .chars()
+            .next_back()
+            .is_none_or(|c| !(c.is_ascii_alphanumeric() || c == '_'));
+        if !starts_field {
+            continue;
+        }
+        let Some(value) = value_start(rest) else {
+            continue;
+        };
+        out.push_str(&rest[..value]);
+        rest = &rest[value..];
+        if rest.starts_with('"') {
+            out.push('"');
+            out.push_str(REDACTED);
+            out.push('"');
+            rest = &rest[quoted_end(rest)..];
+        } else {
+            out.push_str(REDACTED);
+            rest = &rest[unquoted_end(rest)..];
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Offset in `after`, the text following a field name, where the field's
+/// value starts: after `":` in JSON, or after `=` and any ANSI codes in text.
+fn value_start(after: &str) -> Option<usize> {
+    if let Some(value) = after.strip_prefix("\":") {
+        let value = value.trim_start();
+        return value.starts_with('"').then_some(after.len() - value.len());
+    }
+    let mut rest = skip_ansi(after);
+    rest = rest.strip_prefix('=')?;
+    rest = skip_ansi(rest);
+    Some(after.len() - rest.len())
+}
+
+/// `text` without the ANSI codes at its end.
+fn trim_ansi_end(text: &str) -> &str {
+    match text.rfind('\x1b') {
+        Some(esc)
+            if text[esc + 1..]
+                .strip_suffix('m')
+                .is_some_and(|code| code.chars().all(|c| matches!(c, '[' | ';' | '0'..='9'))) =>
+        {
+            trim_ansi_end(&text[..esc])
+        }
+        _ => text,
+    }
+}
+
+/// `text` without the ANSI codes at its start.
+fn skip_ansi(mut text: &str) -> &str {
+    while text.starts_with('\x1b') {
+        text = text.find('m').map_or("", |i| &text[i + 1..]);
+    }
+    text
+}
+
+/// Length of the quoted string at the start of `text`, with its quotes.
+fn quoted_end(text: &str) -> usize {
+    let mut escaped = false;
+    for (i, c) in text.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return i + 1,
+            _ => {}
+        }
+    }
+    text.len()
+}
+
+/// Length of the unquoted text value at the start of `text`: up to the next
+/// ` name=` field, ANSI code or line end.
+fn unquoted_end(text: &str) -> usize {
+    let line_end = text.find(['\n', '\x1b']).unwrap_or(text.len());
+    let value = &text[..line_end];
+    value
+        .match_indices(' ')
+        .map(|(i, _)| i)
+        .find(|&i| {
+            let next = &value[i + 1..];
+            let name_len = next
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(next.len());
+            name_len > 0 && next[name_len..].starts_with('=')
+        })
+        .unwrap_or(line_end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_ips() {
+        let line = "2024-01-15T10:00:00.123Z  INFO mallard_metrics::api::auth: Login ip=203.0.113.7 peer=[2001:db8::1]:443 via 198.51.100.2:5678.\n";
+        assert_eq!(
+            redact(line, false),
+            "2024-01-15T10:00:00.123Z  INFO mallard_metrics::api::auth: Login ip=[redacted] peer=[[redacted]]:443 via [redacted].\n"
+        );
+        assert_eq!(
+            redact(line, true),
+            "2024-01-15T10:00:00.123Z  INFO mallard_metrics::api::auth: Login ip=203.0.113.x peer=[2001:...]:443 via 198.51.100.x.\n"
+        );
+
+        // The listen address, anonymized prefixes and ANSI codes stay.
+        let line = "\x1b[3maddr\x1b[0m\x1b[2m=\x1b[0m0.0.0.0:8000 ip_prefix=203.0.113.x \x1b[2m203.0.113.7\x1b[0m";
+        assert_eq!(
+            redact(line, false),
+            "\x1b[3maddr\x1b[0m\x1b[2m=\x1b[0m0.0.0.0:8000 ip_prefix=203.0.113.x \x1b[2m[redacted]\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn test_redact_user_agents() {
+        let json = r#"{"fields":{"user_agent":"Mozilla/5.0 (X11; \"Linux\")","site_id":"a.com"}}"#;
+        assert_eq!(
+            redact(json, true),
+            r#"{"fields":{"user_agent":"[redacted]","site_id":"a.com"}}"#
+        );
+        assert_eq!(
+            redact("user_agent=\"curl/8.5.0\" site_id=a.com", true),
+            "user_agent=\"[redacted]\" site_id=a.com"
+        );
+        assert_eq!(
+            redact(
+                "Dropped user_agent=Mozilla/5.0 (X11; Linux x86_64) site_id=a.com\n",
+                true
+            ),
+            "Dropped user_agent=[redacted] site_id=a.com\n"
+        );
+        assert_eq!(
+            redact(
+                "\x1b[3muser_agent\x1b[0m\x1b[2m=\x1b[0mcurl/8.5.0 (x) \x1b[3msite_id\x1b[0m",
+                true
+            ),
+            "\x1b[3muser_agent\x1b[0m\x1b[2m=\x1b[0m[redacted]\x1b[3msite_id\x1b[0m"
+        );
+        // Other fields and words are left alone.
+        assert_eq!(
+            redact("suppress_user_agent=true no user_agent here", true),
+            "suppress_user_agent=true no user_agent here"
+        );
+    }
+
+    #[test]
+    fn test_ingest_sampling() {
+        let writer = LogWriter::new();
+        writer.sample_every.store(3, Ordering::Relaxed);
+        let kept = (0..9)
+            .filter(|_| writer.keep("mallard_metrics::ingest::handler", Level::DEBUG))
+            .count();
+        assert_eq!(kept, 3);
+        assert!((0..9).all(|_| writer.keep("mallard_metrics::ingest::buffer", Level::WARN)));
+        assert!((0..9).all(|_| writer.keep("mallard_metrics::api::auth", Level::INFO)));
+    }
+}
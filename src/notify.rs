@@ -0,0 +1,497 @@
+//! Notifications posted to Slack and Discord incoming webhooks.
+//!
+//! Channels come from the `[notifications]` tables of the config file and
+//! from `POST /api/notifications`.  Each channel subscribes to some of four
+//! events: `anomaly` (hours flagged by the anomaly detector), `goal` (hourly
+//! conversions of the channel's goals), `digest` (the weekly traffic digest
+//! of each site) and `flush` (periodic flushes failing in a row, and their
+//! recovery).  A message holds every notification of one run
+//! that the channel wants, worded by the channel's template for the event or
+//! by the built-in wording.
+//!
+//! Failed posts are retried with exponential backoff, then logged and
+//! dropped.
+
+use crate::config::NotificationChannel;
+use crate::query::anomalies::Anomaly;
+use crate::query::digest::{self, Digest};
+use crate::query::QueryConn;
+use std::time::Duration;
+
+/// Days each digest covers.
+pub const DIGEST_DAYS: u64 = 7;
+
+/// Longest wait for a webhook to answer.
+const POST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Retries of a failed post.
+const MAX_RETRIES: u32 = 3;
+
+/// Wait before the first retry; doubled for each further one.
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest message Discord accepts, in characters.
+const DISCORD_MAX_CHARS: usize = 2000;
+
+/// Conversions of a goal on one site in one hour.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GoalConversions {
+    pub site_id: String,
+    /// The goal in funnel step syntax, as configured.
+    pub goal: String,
+    /// Start of the hour, formatted `YYYY-MM-DD HH:00`.
+    pub hour: String,
+    pub conversions: u64,
+}
+
+/// Periodic flushes failing in a row, or succeeding again after an alert.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlushAlert {
+    /// Flushes that failed in a row, each after its retries.
+    pub consecutive_failures: u64,
+    /// The last error; `None` once a flush succeeded again.
+    pub error: Option<String>,
+}
+
+/// Something a channel can be notified about.
+#[derive(Debug, Clone, Copy)]
+pub enum Notification<'a> {
+    Anomaly(&'a Anomaly),
+    Goal(&'a GoalConversions),
+    Digest(&'a Digest),
+    Flush(&'a FlushAlert),
+}
+
+impl<'a> Notification<'a> {
+    /// The event of [`crate::config::NOTIFICATION_EVENTS`] this belongs to.
+    const fn event(self) -> &'static str {
+        match self {
+            Self::Anomaly(_) => "anomaly",
+            Self::Goal(_) => "goal",
+            Self::Digest(_) => "digest",
+            Self::Flush(_) => "flush",
+        }
+    }
+
+    /// The site this is about; `None` for the whole server.
+    fn site_id(self) -> Option<&'a str> {
+        match self {
+            Self::Anomaly(a) => Some(&a.site_id),
+            Self::Goal(g) => Some(&g.site_id),
+            Self::Digest(d) => Some(&d.site_id),
+            Self::Flush(_) => None,
+        }
+    }
+
+    /// Whether `channel` subscribed to this notification.  Notifications
+    /// about the whole server go to channels of any sites.
+    fn wanted_by(self, channel: &NotificationChannel) -> bool {
+        channel.events.iter().any(|e| e == self.event())
+            && self.site_id().is_none_or(|site_id| {
+                channel.sites.is_empty() || channel.sites.iter().any(|s| s == site_id)
+            })
+            && match self {
+                Self::Goal(g) => channel.goals.contains(&g.goal),
+                _ => true,
+            }
+    }
+
+    /// Values of the `{placeholders}` of a template for this event.
+    fn variables(self) -> Vec<(&'static str, String)> {
+        let mut variables: Vec<_> = self
+            .site_id()
+            .map(|site_id| ("site_id", site_id.to_string()))
+            .into_iter()
+            .collect();
+        match self {
+            Self::Anomaly(a) => variables.extend([
+                ("hour", a.hour.clone()),
+                ("visitors", a.visitors.to_string()),
+                ("baseline", a.baseline.to_string()),
+                ("deviation_pct", a.deviation_pct.to_string()),
+            ]),
+            Self::Goal(g) => variables.extend([
+                ("goal", g.goal.clone()),
+                ("hour", g.hour.clone()),
+                ("conversions", g.conversions.to_string()),
+            ]),
+            Self::Digest(d) => variables.extend([
+                ("start_date", d.start_date.clone()),
+                ("end_date", d.end_date.clone()),
+                ("visitors", d.visitors.to_string()),
+                ("pageviews", d.pageviews.to_string()),
+                (
+                    "visitor_change",
+                    d.visitor_change
+                        .map_or_else(|| "n/a".to_string(), |c| c.to_string()),
+                ),
+                ("headline", d.headline.clone()),
+                ("highlights", d.highlights.join("\n")),
+                ("text", d.text()),
+            ]),
+            Self::Flush(f) => variables.extend([
+                ("consecutive_failures", f.consecutive_failures.to_string()),
+                ("error", f.error.clone().unwrap_or_default()),
+            ]),
+        }
+        variables
+    }
+
+    /// The built-in wording.
+    fn default_text(self) -> String {
+        match self {
+            Self::Anomaly(a) => {
+                let direction = if a.deviation_pct < 0.0 {
+                    "drop"
+                } else {
+                    "spike"
+                };
+                format!(
+                    "{}: traffic {direction} at {} UTC, {} visitors against a usual {} ({:+}%).",
+                    a.site_id, a.hour, a.visitors, a.baseline, a.deviation_pct
+                )
+            }
+            Self::Goal(g) => {
+                let noun = if g.conversions == 1 {
+                    "conversion"
+                } else {
+                    "conversions"
+                };
+                format!(
+                    "{}: {} {noun} of {} in the hour from {} UTC.",
+                    g.site_id, g.conversions, g.goal, g.hour
+                )
+            }
+            Self::Digest(d) => d.text(),
+            Self::Flush(FlushAlert {
+                consecutive_failures,
+                error: Some(error),
+            }) => format!(
+                "Flushing events to Parquet failed {consecutive_failures} times in a row; \
+                 unflushed events are kept until one succeeds. Last error: {error}"
+            ),
+            Self::Flush(FlushAlert {
+                consecutive_failures,
+                error: None,
+            }) => format!(
+                "Flushing events to Parquet works again after {consecutive_failures} failures \
+                 in a row."
+            ),
+        }
+    }
+
+    /// The message text for `channel`.
+    pub fn render(self, channel: &NotificationChannel) -> String {
+        let Some(template) = channel.templates.get(self.event()) else {
+            return self.default_text();
+        };
+        self.variables()
+            .into_iter()
+            .fold(template.clone(), |text, (name, value)| {
+                text.replace(&format!("{{{name}}}"), &value)
+            })
+    }
+}
+
+/// Post every notification each channel wants as one message per channel,
+/// in the background.
+pub fn notify(
+    client: &reqwest::Client,
+    channels: Vec<(String, NotificationChannel)>,
+    notifications: &[Notification<'_>],
+) {
+    for (id, channel) in channels {
+        let texts: Vec<String> = notifications
+            .iter()
+            .filter(|n| n.wanted_by(&channel))
+            .map(|n| n.render(&channel))
+            .collect();
+        if texts.is_empty() {
+            continue;
+        }
+        let client = client.clone();
+        let text = texts.join("\n\n");
+        tokio::spawn(async move {
+            deliver(&client, &id, &channel, &text).await;
+        });
+    }
+}
+
+/// Post `text` to `channel`, retrying failures with backoff.
+async fn deliver(client: &reqwest::Client, id: &str, channel: &NotificationChannel, text: &str) {
+    let mut attempt = 0;
+    loop {
+        match post(client, channel, text).await {
+            Ok(()) => {
+                tracing::debug!(channel = %id, "Notification posted");
+                return;
+            }
+            Err(error) if attempt < MAX_RETRIES => {
+                tracing::debug!(channel = %id, %error, attempt, "Notification post failed");
+                tokio::time::sleep(RETRY_BASE_DELAY * 2u32.pow(attempt)).await;
+                attempt += 1;
+            }
+            Err(error) => {
+                tracing::warn!(channel = %id, %error, "Notification dropped after retries");
+                return;
+            }
+        }
+    }
+}
+
+/// Escape the characters Slack reads as markup in message text.
+fn escape_slack(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// JSON body of a webhook message holding `text` for `channel`'s kind.
+fn message(channel: &NotificationChannel, text: &str) -> String {
+    if channel.kind == "discord" {
+        let content: String = text.chars().take(DISCORD_MAX_CHARS).collect();
+        serde_json::json!({ "content": content }).to_string()
+    } else {
+        serde_json::json!({ "text": escape_slack(text) }).to_string()
+    }
+}
+
+/// Post `text` to `channel` once.
+pub async fn post(
+    client: &reqwest::Client,
+    channel: &NotificationChannel,
+    text: &str,
+) -> Result<(), String> {
+    client
+        .post(&channel.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(message(channel, text))
+        .timeout(POST_TIMEOUT)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map(drop)
+        // The webhook URL is a secret; keep it out of logs.
+        .map_err(|e| e.without_url().to_string())
+}
+
+/// Conversions of the goal `condition`, a SQL boolean expression over event
+/// columns parsed from `goal`, per site in `[hour_start, hour_end)`.  Sites
+/// without conversions are absent.
+pub fn query_goal_conversions(
+    conn: &QueryConn<'_>,
+    goal: &str,
+    condition: &str,
+    hour_start: &str,
+    hour_end: &str,
+) -> Result<Vec<GoalConversions>, duckdb::Error> {
+    let sql = format!(
+        "SELECT site_id, COUNT(*)
+         FROM events_all
+         WHERE timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
+         AND ({condition})
+         GROUP BY site_id
+         ORDER BY site_id"
+    );
+    let mut stmt =
+        crate::query::prepare_in_range(conn, &sql, crate::query::ALL_SITES, hour_start, hour_end)?;
+    let hour = chrono::NaiveDateTime::parse_from_str(hour_start, "%Y-%m-%d %H:%M:%S").map_or_else(
+        |_| hour_start.to_string(),
+        |t| t.format("%Y-%m-%d %H:00").to_string(),
+    );
+    let rows = stmt
+        .query_map(duckdb::params![hour_start, hour_end], |row| {
+            Ok(GoalConversions {
+                site_id: row.get(0)?,
+                goal: goal.to_string(),
+                hour: hour.clone(),
+                conversions: row.get(1)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(rows)
+}
+
+/// Digests of the [`DIGEST_DAYS`] whole days before `today` for every site
+/// with traffic in them and every site in `sites`.
+pub fn query_digests(
+    conn: &QueryConn<'_>,
+    sites: &[String],
+    today: chrono::NaiveDate,
+) -> Result<Vec<Digest>, duckdb::Error> {
+    let end = today.to_string();
+    let start = (today - chrono::Days::new(DIGEST_DAYS)).to_string();
+    let mut all: Vec<String> = crate::query::metrics::query_site_summaries(conn, &start, &end)?
+        .into_iter()
+        .map(|summary| summary.site_id)
+        .collect();
+    for site in sites {
+        if !all.contains(site) {
+            all.push(site.clone());
+        }
+    }
+    all.iter()
+        .map(|site_id| digest::query_digest(conn, site_id, &start, &end))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use duckdb::Connection;
+
+    fn channel(kind: &str) -> NotificationChannel {
+        NotificationChannel {
+            kind: kind.to_string(),
+            url: "https://hooks.example.com/x".to_string(),
+            events: vec!["anomaly".to_string(), "goal".to_string()],
+            sites: vec!["a.com".to_string()],
+            goals: vec!["event:signup".to_string()],
+            ..NotificationChannel::default()
+        }
+    }
+
+    fn setup_test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::storage::schema::init_schema(&conn).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        crate::storage::schema::setup_query_view(&conn, dir.path()).unwrap();
+        conn
+    }
+
+    #[test]
+    #[allow(clippy::literal_string_with_formatting_args)]
+    fn test_render_and_filter() {
+        let anomaly = Anomaly {
+            site_id: "a.com".to_string(),
+            hour: "2024-01-15 10:00".to_string(),
+            visitors: 300,
+            baseline: 100.0,
+            deviation_pct: 200.0,
+        };
+        let goal = GoalConversions {
+            site_id: "a.com".to_string(),
+            goal: "event:signup".to_string(),
+            hour: "2024-01-15 10:00".to_string(),
+            conversions: 1,
+        };
+        let mut slack = channel("slack");
+        assert_eq!(
+            Notification::Anomaly(&anomaly).render(&slack),
+            "a.com: traffic spike at 2024-01-15 10:00 UTC, 300 visitors against a usual 100 (+200%)."
+        );
+        assert_eq!(
+            Notification::Goal(&goal).render(&slack),
+            "a.com: 1 conversion of event:signup in the hour from 2024-01-15 10:00 UTC."
+        );
+        slack.templates.insert(
+            "goal".to_string(),
+            "{goal} x{conversions} on {site_id} {unknown}".to_string(),
+        );
+        assert_eq!(
+            Notification::Goal(&goal).render(&slack),
+            "event:signup x1 on a.com {unknown}"
+        );
+
+        assert!(Notification::Anomaly(&anomaly).wanted_by(&slack));
+        assert!(Notification::Goal(&goal).wanted_by(&slack));
+        let other_goal = GoalConversions {
+            goal: "event:purchase".to_string(),
+            ..goal.clone()
+        };
+        assert!(!Notification::Goal(&other_goal).wanted_by(&slack));
+        let other_site = Anomaly {
+            site_id: "b.com".to_string(),
+            ..anomaly
+        };
+        assert!(!Notification::Anomaly(&other_site).wanted_by(&slack));
+        slack.events = vec!["digest".to_string()];
+        assert!(!Notification::Goal(&goal).wanted_by(&slack));
+
+        let failing = FlushAlert {
+            consecutive_failures: 3,
+            error: Some("disk full".to_string()),
+        };
+        slack.events = vec!["flush".to_string()];
+        assert!(Notification::Flush(&failing).wanted_by(&slack));
+        assert_eq!(
+            Notification::Flush(&failing).render(&slack),
+            "Flushing events to Parquet failed 3 times in a row; unflushed events are kept \
+             until one succeeds. Last error: disk full"
+        );
+        slack.templates.insert(
+            "flush".to_string(),
+            "{site_id} {consecutive_failures}: {error}".to_string(),
+        );
+        assert_eq!(
+            Notification::Flush(&failing).render(&slack),
+            "{site_id} 3: disk full"
+        );
+    }
+
+    #[test]
+    fn test_message() {
+        let body: serde_json::Value =
+            serde_json::from_str(&message(&channel("slack"), "<b> & c")).unwrap();
+        assert_eq!(body["text"], "&lt;b&gt; &amp; c");
+        let long = "x".repeat(3000);
+        let body: serde_json::Value =
+            serde_json::from_str(&message(&channel("discord"), &long)).unwrap();
+        assert_eq!(body["content"].as_str().unwrap().len(), DISCORD_MAX_CHARS);
+    }
+
+    #[test]
+    fn test_query_goal_conversions() {
+        let conn = setup_test_db();
+        conn.execute_batch(
+            "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+             VALUES ('a.com', 'v1', '2024-01-15 10:05:00', 'signup', '/'),
+                    ('a.com', 'v2', '2024-01-15 10:55:00', 'signup', '/'),
+                    ('a.com', 'v3', '2024-01-15 11:05:00', 'signup', '/'),
+                    ('b.com', 'v4', '2024-01-15 10:30:00', 'pageview', '/')",
+        )
+        .unwrap();
+        let rows = query_goal_conversions(
+            &QueryConn::new(&conn),
+            "event:signup",
+            "event_name = 'signup'",
+            "2024-01-15 10:00:00",
+            "2024-01-15 11:00:00",
+        )
+        .unwrap();
+        assert_eq!(
+            rows,
+            [GoalConversions {
+                site_id: "a.com".to_string(),
+                goal: "event:signup".to_string(),
+                hour: "2024-01-15 10:00".to_string(),
+                conversions: 2,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_query_digests() {
+        let conn = setup_test_db();
+        conn.execute_batch(
+            "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+             VALUES ('a.com', 'v1', '2024-01-10 10:00:00', 'pageview', '/'),
+                    ('b.com', 'v2', '2024-01-10 10:00:00', 'pageview', '/'),
+                    ('b.com', 'v3', '2024-01-15 10:00:00', 'pageview', '/')",
+        )
+        .unwrap();
+        let today = chrono::NaiveDate::from_ymd_opt(2024, 1, 15).unwrap();
+
+        let digests = query_digests(&QueryConn::new(&conn), &["c.com".to_string()], today).unwrap();
+        let sites: Vec<&str> = digests.iter().map(|d| d.site_id.as_str()).collect();
+        assert_eq!(sites, ["a.com", "b.com", "c.com"]);
+        // Today is not complete and is left out.
+        assert_eq!(digests[1].visitors, 1);
+        assert_eq!(digests[1].start_date, "2024-01-08");
+        assert_eq!(digests[1].end_date, "2024-01-14");
+        assert_eq!(
+            Notification::Digest(&digests[2]).render(&channel("slack")),
+            "c.com: no visitors from 2024-01-08 to 2024-01-14."
+        );
+    }
+}
@@ -0,0 +1,256 @@
+use super::{QueryConn, SiteScope};
+use duckdb::Connection;
+
+/// Number of previous weeks whose same hour forms the baseline.
+const BASELINE_WEEKS: u32 = 4;
+
+/// An hour whose unique visitors deviated sharply from the baseline.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct Anomaly {
+    pub site_id: String,
+    /// Start of the hour, formatted `YYYY-MM-DD HH:00`.
+    pub hour: String,
+    pub visitors: u64,
+    /// Median visitors in the same hour over the previous four weeks.
+    pub baseline: f64,
+    /// `(visitors - baseline) / baseline * 100`; negative for drops.
+    pub deviation_pct: f64,
+}
+
+/// Find anomalous hours among the 24 completed hours before `now`, across all
+/// sites.
+///
+/// The hour containing `now` is still in progress and is not checked.  Each
+/// hour's unique visitors are compared to the median of the same hour on the
+/// same weekday over the previous four weeks, counting hours without traffic
+/// as zero.  An hour is anomalous when the baseline is at least
+/// `min_baseline` and the visitors differ from it by `threshold_pct` percent
+/// or more in either direction.
+pub fn detect_anomalies(
+    conn: &QueryConn<'_>,
+    now: &str,
+    threshold_pct: f64,
+    min_baseline: f64,
+) -> Result<Vec<Anomaly>, duckdb::Error> {
+    let sql = format!(
+        "WITH bounds AS (
+             SELECT date_trunc('hour', CAST(? AS TIMESTAMP)) AS hour_now,
+                    date_trunc('hour', CAST(? AS TIMESTAMP)) - INTERVAL 24 HOUR AS window_start
+         ),
+         hourly AS (
+             SELECT site_id, date_trunc('hour', timestamp) AS hour,
+                    COUNT(DISTINCT visitor_id) AS visitors
+             FROM events_all, bounds
+             WHERE timestamp >= window_start - INTERVAL {days} DAY AND timestamp < hour_now
+             GROUP BY site_id, date_trunc('hour', timestamp)
+         ),
+         weeks AS (SELECT CAST(range AS INTEGER) AS n FROM range(1, {weeks_end})),
+         slots AS (
+             SELECT DISTINCT h.site_id, h.hour + to_days(7 * w.n) AS hour
+             FROM hourly h, weeks w, bounds b
+             WHERE h.hour + to_days(7 * w.n) >= b.window_start
+               AND h.hour + to_days(7 * w.n) < b.hour_now
+             UNION
+             SELECT h.site_id, h.hour
+             FROM hourly h, bounds b
+             WHERE h.hour >= b.window_start
+         ),
+         scored AS (
+             SELECT s.site_id, s.hour,
+                    COALESCE(ANY_VALUE(cur.visitors), 0) AS visitors,
+                    CAST(MEDIAN(COALESCE(prev.visitors, 0)) AS DOUBLE) AS baseline
+             FROM slots s
+             CROSS JOIN weeks w
+             LEFT JOIN hourly cur ON cur.site_id = s.site_id AND cur.hour = s.hour
+             LEFT JOIN hourly prev
+                 ON prev.site_id = s.site_id AND prev.hour = s.hour - to_days(7 * w.n)
+             GROUP BY s.site_id, s.hour
+         )
+         SELECT site_id, strftime(hour, '%Y-%m-%d %H:00'), visitors, baseline,
+                ROUND((visitors - baseline) / baseline * 100, 2) AS deviation_pct
+         FROM scored
+         WHERE baseline >= ? AND baseline > 0
+           AND ABS(visitors - baseline) / baseline * 100 >= ?
+         ORDER BY hour, site_id",
+        days = BASELINE_WEEKS * 7,
+        weeks_end = BASELINE_WEEKS + 1,
+    );
+
+    let mut stmt = super::prepare(conn, &sql, super::ALL_SITES)?;
+    let rows = stmt
+        .query_map(
+            duckdb::params![now, now, min_baseline, threshold_pct],
+            |row| {
+                Ok(Anomaly {
+                    site_id: row.get(0)?,
+                    hour: row.get(1)?,
+                    visitors: row.get(2)?,
+                    baseline: row.get(3)?,
+                    deviation_pct: row.get(4)?,
+                })
+            },
+        )?
+        .collect::<Result<_, _>>()?;
+
+    Ok(rows)
+}
+
+/// Store detected anomalies, ignoring hours already recorded.
+///
+/// Returns the anomalies that were not previously known, so callers alert on
+/// each anomalous hour once.
+pub fn record_anomalies(
+    conn: &Connection,
+    anomalies: &[Anomaly],
+) -> Result<Vec<Anomaly>, duckdb::Error> {
+    let mut stmt = conn.prepare(
+        "INSERT INTO anomalies (site_id, hour, visitors, baseline, deviation_pct)
+         VALUES (?, CAST(? AS TIMESTAMP), ?, ?, ?)
+         ON CONFLICT DO NOTHING",
+    )?;
+    let mut new = Vec::new();
+    for a in anomalies {
+        let inserted = stmt.execute(duckdb::params![
+            a.site_id,
+            format!("{}:00", a.hour),
+            a.visitors,
+            a.baseline,
+            a.deviation_pct
+        ])?;
+        if inserted > 0 {
+            new.push(a.clone());
+        }
+    }
+    Ok(new)
+}
+
+/// Recorded anomalies for a site whose hour falls within the date range.
+pub fn query_anomalies(
+    conn: &QueryConn<'_>,
+    site_id: &str,
+    start_date: &str,
+    end_date: &str,
+) -> Result<Vec<Anomaly>, duckdb::Error> {
+    let site = SiteScope::of(site_id).filter("site_id");
+    let mut stmt = super::prepare(
+        conn,
+        &format!(
+            "SELECT site_id, strftime(hour, '%Y-%m-%d %H:00'), visitors, baseline, deviation_pct
+             FROM anomalies
+             WHERE {site} AND hour >= CAST(? AS TIMESTAMP) AND hour < CAST(? AS TIMESTAMP)
+             ORDER BY hour DESC"
+        ),
+        site_id,
+    )?;
+    let rows = stmt
+        .query_map(duckdb::params![site_id, start_date, end_date], |row| {
+            Ok(Anomaly {
+                site_id: row.get(0)?,
+                hour: row.get(1)?,
+                visitors: row.get(2)?,
+                baseline: row.get(3)?,
+                deviation_pct: row.get(4)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup_test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::storage::schema::init_schema(&conn).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        crate::storage::schema::setup_query_view(&conn, dir.path()).unwrap();
+        drop(dir);
+        conn
+    }
+
+    /// Insert `count` distinct visitors into the hour starting at `hour`.
+    fn insert_visitors(conn: &Connection, hour: &str, count: usize) {
+        for i in 0..count {
+            conn.execute(
+                "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+                 VALUES ('test.com', ?, CAST(? AS TIMESTAMP) + INTERVAL 1 MINUTE, 'pageview', '/')",
+                duckdb::params![format!("{hour}-v{i}"), hour],
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_detects_spike_and_drop() {
+        let conn = setup_test_db();
+        // Baseline of 20 visitors at 09:00 and 10:00 on the four previous Mondays.
+        for day in ["2024-01-01", "2024-01-08", "2024-01-15", "2024-01-22"] {
+            insert_visitors(&conn, &format!("{day} 09:00:00"), 20);
+            insert_visitors(&conn, &format!("{day} 10:00:00"), 20);
+        }
+        // Today: 09:00 spikes to 50; 10:00 has no traffic at all.
+        insert_visitors(&conn, "2024-01-29 09:00:00", 50);
+
+        let found =
+            detect_anomalies(&QueryConn::new(&conn), "2024-01-29 11:30:00", 50.0, 10.0).unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].hour, "2024-01-29 09:00");
+        assert_eq!(found[0].visitors, 50);
+        assert!((found[0].baseline - 20.0).abs() < f64::EPSILON);
+        assert!((found[0].deviation_pct - 150.0).abs() < f64::EPSILON);
+        assert_eq!(found[1].hour, "2024-01-29 10:00");
+        assert!((found[1].deviation_pct + 100.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_ignores_small_baselines_and_current_hour() {
+        let conn = setup_test_db();
+        for day in ["2024-01-01", "2024-01-08", "2024-01-15", "2024-01-22"] {
+            insert_visitors(&conn, &format!("{day} 09:00:00"), 2);
+            insert_visitors(&conn, &format!("{day} 11:00:00"), 20);
+        }
+        insert_visitors(&conn, "2024-01-29 09:00:00", 10);
+
+        // 09:00 baseline is below min_baseline; 11:00 is still in progress.
+        let found =
+            detect_anomalies(&QueryConn::new(&conn), "2024-01-29 11:30:00", 50.0, 10.0).unwrap();
+        assert!(found.is_empty());
+    }
+
+    #[test]
+    fn test_record_and_query_anomalies() {
+        let conn = setup_test_db();
+        let anomaly = Anomaly {
+            site_id: "test.com".to_string(),
+            hour: "2024-01-29 09:00".to_string(),
+            visitors: 50,
+            baseline: 20.0,
+            deviation_pct: 150.0,
+        };
+
+        let new = record_anomalies(&conn, std::slice::from_ref(&anomaly)).unwrap();
+        assert_eq!(new.len(), 1);
+        // Recording the same hour again is a no-op.
+        let again = record_anomalies(&conn, std::slice::from_ref(&anomaly)).unwrap();
+        assert!(again.is_empty());
+
+        let stored = query_anomalies(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-29",
+            "2024-01-30",
+        )
+        .unwrap();
+        assert_eq!(stored, vec![anomaly]);
+        let other = query_anomalies(
+            &QueryConn::new(&conn),
+            "other.com",
+            "2024-01-29",
+            "2024-01-30",
+        )
+        .unwrap();
+        assert!(other.is_empty());
+    }
+}
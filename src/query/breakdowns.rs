@@ -1,7 +1,9 @@
-use duckdb::Connection;
+use super::{countries, QueryConn, SiteScope};
+use std::borrow::Cow;
+use std::collections::HashMap;
 
 /// A breakdown row: dimension value + count.
-#[derive(Debug, Clone, serde::Serialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
 pub struct BreakdownRow {
     pub value: String,
     pub visitors: u64,
@@ -17,10 +19,19 @@ pub enum Dimension {
     Browser,
     Os,
     DeviceType,
+    UtmSource,
+    UtmMedium,
+    UtmCampaign,
+    /// A site-declared custom dimension, by 1-based storage slot.
+    Custom(usize),
 }
 
+/// Storage columns backing custom dimension slots 1..=`MAX_CUSTOM_DIMENSIONS`.
+const CUSTOM_DIMENSION_COLUMNS: [&str; crate::config::MAX_CUSTOM_DIMENSIONS] =
+    ["dim_1", "dim_2", "dim_3", "dim_4", "dim_5"];
+
 impl Dimension {
-    const fn column_name(self) -> &'static str {
+    pub fn column_name(self) -> &'static str {
         match self {
             Self::Page => "pathname",
             Self::ReferrerSource => "referrer_source",
@@ -28,34 +39,67 @@ impl Dimension {
             Self::Browser => "browser",
             Self::Os => "os",
             Self::DeviceType => "device_type",
+            Self::UtmSource => "utm_source",
+            Self::UtmMedium => "utm_medium",
+            Self::UtmCampaign => "utm_campaign",
+            // An out-of-range slot groups everything under "(unknown)" rather
+            // than interpolating an arbitrary column name.
+            Self::Custom(slot) => slot
+                .checked_sub(1)
+                .and_then(|i| CUSTOM_DIMENSION_COLUMNS.get(i))
+                .copied()
+                .unwrap_or("NULL"),
         }
     }
 }
 
-/// Query a breakdown of events by a given dimension.
+/// The events of `site_id` in `[start, end)`, as what follows `FROM`,
+/// binding the site ID, start and end.  With a goal condition, such as those
+/// parsed from funnel steps, only the events of visitors with an event
+/// matching it in the range.
+fn ranged_events(site_id: &str, goal_condition: Option<&str>) -> String {
+    let in_range = format!(
+        "{} AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)",
+        SiteScope::of(site_id).filter("site_id")
+    );
+    goal_condition.map_or_else(
+        || format!("events_all WHERE {in_range}"),
+        |goal| {
+            format!(
+                "(WITH ranged AS (SELECT * FROM events_all WHERE {in_range})
+                  SELECT * FROM ranged
+                  WHERE visitor_id IN (SELECT visitor_id FROM ranged WHERE {goal}))"
+            )
+        },
+    )
+}
+
+/// Query a breakdown of events by a given dimension, of the visitors who
+/// reached the goal when a `goal_condition` is given.
 pub fn query_breakdown(
-    conn: &Connection,
+    conn: &QueryConn<'_>,
     site_id: &str,
     start_date: &str,
     end_date: &str,
     dimension: Dimension,
+    goal_condition: Option<&str>,
     limit: usize,
 ) -> Result<Vec<BreakdownRow>, duckdb::Error> {
     let col = dimension.column_name();
+    let events = ranged_events(site_id, goal_condition);
 
     // Using format! for column name is safe here since it comes from a fixed enum
     let sql = format!(
         "SELECT COALESCE({col}, '(unknown)') AS dim_value,
                 COUNT(DISTINCT visitor_id) AS visitors,
                 COUNT(*) FILTER (WHERE event_name = 'pageview') AS pageviews
-         FROM events_all
-         WHERE site_id = ? AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
+         FROM {events}
          GROUP BY dim_value
-         ORDER BY visitors DESC
+         ORDER BY visitors DESC, dim_value
          LIMIT ?"
     );
 
-    let mut stmt = conn.prepare(&sql)?;
+    let mut stmt = super::prepare_in_range(conn, &sql, site_id, start_date, end_date)?;
     let limit_i64 = i64::try_from(limit).unwrap_or(i64::MAX);
     let rows = stmt
         .query_map(
@@ -68,15 +112,846 @@ pub fn query_breakdown(
                 })
             },
         )?
-        .filter_map(Result::ok)
-        .collect();
+        .collect::<Result<_, _>>()?;
 
     Ok(rows)
 }
 
+/// Value of the row aggregating everything beyond a breakdown's limit.
+pub const OTHER: &str = "(other)";
+
+/// Visitors and pageviews of a whole breakdown, whatever its limit.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+    utoipa::ToSchema,
+)]
+pub struct BreakdownTotal {
+    pub visitors: u64,
+    pub pageviews: u64,
+    /// Distinct values, including those folded into the [`OTHER`] row.
+    pub values: u64,
+}
+
+/// A breakdown whose values beyond the limit are folded into a last
+/// [`OTHER`] row, so that row pageviews add up to the total.
+///
+/// Visitors do not add up when a visitor has several values, such as several
+/// pages.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct BreakdownCoverage<R> {
+    pub rows: Vec<R>,
+    pub total: BreakdownTotal,
+}
+
+impl<R> BreakdownCoverage<R> {
+    /// Convert every row, e.g. into [`CountryRow`]s.
+    pub fn map<S>(self, f: impl FnMut(R) -> S) -> BreakdownCoverage<S> {
+        BreakdownCoverage {
+            rows: self.rows.into_iter().map(f).collect(),
+            total: self.total,
+        }
+    }
+}
+
+/// A grouping set of [`query_breakdown_coverage`]: whether it is the
+/// [`OTHER`] row (`None` for the total), the value, visitors, pageviews and
+/// distinct values.
+type CoverageGroup = (Option<bool>, Option<String>, u64, u64, u64);
+
+/// [`query_breakdown`] with the values beyond `limit` folded into an
+/// [`OTHER`] row, and the totals of the whole breakdown.
+pub fn query_breakdown_coverage(
+    conn: &QueryConn<'_>,
+    site_id: &str,
+    start_date: &str,
+    end_date: &str,
+    dimension: Dimension,
+    goal_condition: Option<&str>,
+    limit: usize,
+) -> Result<BreakdownCoverage<BreakdownRow>, duckdb::Error> {
+    let col = dimension.column_name();
+    let events = ranged_events(site_id, goal_condition);
+
+    // One pass ranks the values, and groups the events by top value, the
+    // rest, and all together; `is_other` is NULL only on the grand total.
+    let sql = format!(
+        "WITH base AS (
+             SELECT COALESCE({col}, '(unknown)') AS dim_value, visitor_id, event_name
+             FROM {events}
+         ),
+         top AS (
+             SELECT dim_value
+             FROM base
+             GROUP BY dim_value
+             ORDER BY COUNT(DISTINCT visitor_id) DESC, dim_value
+             LIMIT ?
+         ),
+         bucketed AS (
+             SELECT base.*, top.dim_value IS NULL AS is_other, top.dim_value AS bucket
+             FROM base LEFT JOIN top ON top.dim_value = base.dim_value
+         )
+         SELECT is_other, bucket,
+                COUNT(DISTINCT visitor_id),
+                COUNT(*) FILTER (WHERE event_name = 'pageview'),
+                COUNT(DISTINCT dim_value)
+         FROM bucketed
+         GROUP BY GROUPING SETS ((is_other, bucket), ())"
+    );
+
+    let mut stmt = super::prepare_in_range(conn, &sql, site_id, start_date, end_date)?;
+    let limit_i64 = i64::try_from(limit).unwrap_or(i64::MAX);
+    let groups: Vec<CoverageGroup> = stmt
+        .query_map(
+            duckdb::params![site_id, start_date, end_date, limit_i64],
+            |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            },
+        )?
+        .collect::<Result<_, _>>()?;
+
+    let mut coverage = BreakdownCoverage {
+        rows: Vec::new(),
+        total: BreakdownTotal::default(),
+    };
+    let mut other = None;
+    for (is_other, bucket, visitors, pageviews, values) in groups {
+        match is_other {
+            None => {
+                coverage.total = BreakdownTotal {
+                    visitors,
+                    pageviews,
+                    values,
+                };
+            }
+            Some(true) => {
+                other = Some(BreakdownRow {
+                    value: OTHER.to_string(),
+                    visitors,
+                    pageviews,
+                });
+            }
+            Some(false) => coverage.rows.push(BreakdownRow {
+                value: bucket.unwrap_or_default(),
+                visitors,
+                pageviews,
+            }),
+        }
+    }
+    coverage.rows.sort_by(|a, b| {
+        b.visitors
+            .cmp(&a.visitors)
+            .then_with(|| a.value.cmp(&b.value))
+    });
+    coverage.rows.extend(other);
+    Ok(coverage)
+}
+
+/// The period of equal length just before `[start_date, end_date)`, both
+/// `YYYY-MM-DD` dates.
+pub fn previous_range(start_date: &str, end_date: &str) -> Option<(String, String)> {
+    let start = chrono::NaiveDate::parse_from_str(start_date, "%Y-%m-%d").ok()?;
+    let end = chrono::NaiveDate::parse_from_str(end_date, "%Y-%m-%d").ok()?;
+    let previous_start = start.checked_sub_signed(end - start)?;
+    Some((previous_start.to_string(), start.to_string()))
+}
+
+/// Visitors in the [previous period](previous_range) of each of the top
+/// `limit` values of `[start_date, end_date)`.
+///
+/// Values are ranked as [`query_breakdown`] and [`query_breakdown_coverage`]
+/// rank them.  Visitors of the other values are counted under [`OTHER`].
+/// Values without visitors are absent.
+pub fn query_previous_visitors(
+    conn: &QueryConn<'_>,
+    site_id: &str,
+    start_date: &str,
+    end_date: &str,
+    dimension: Dimension,
+    goal_condition: Option<&str>,
+    limit: usize,
+) -> Result<HashMap<String, u64>, duckdb::Error> {
+    let Some((previous_start, previous_end)) = previous_range(start_date, end_date) else {
+        return Ok(HashMap::new());
+    };
+    let col = dimension.column_name();
+    let events = ranged_events(site_id, goal_condition);
+
+    let sql = format!(
+        "WITH top AS (
+             SELECT COALESCE({col}, '(unknown)') AS dim_value
+             FROM {events}
+             GROUP BY dim_value
+             ORDER BY COUNT(DISTINCT visitor_id) DESC, dim_value
+             LIMIT ?
+         ),
+         previous AS (
+             SELECT COALESCE({col}, '(unknown)') AS dim_value, visitor_id
+             FROM {events}
+         )
+         SELECT COALESCE(top.dim_value, '{OTHER}') AS bucket, COUNT(DISTINCT visitor_id)
+         FROM previous LEFT JOIN top ON top.dim_value = previous.dim_value
+         GROUP BY bucket"
+    );
+
+    let mut stmt = super::prepare_in_range(conn, &sql, site_id, &previous_start, end_date)?;
+    let limit_i64 = i64::try_from(limit).unwrap_or(i64::MAX);
+    let previous = stmt
+        .query_map(
+            duckdb::params![
+                site_id,
+                start_date,
+                end_date,
+                limit_i64,
+                site_id,
+                previous_start,
+                previous_end
+            ],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?
+        .collect::<Result<_, _>>()?;
+    Ok(previous)
+}
+
+/// A breakdown row with its value's visitors in the previous period.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ComparedRow<R> {
+    #[serde(flatten)]
+    pub row: R,
+    pub previous_visitors: u64,
+    /// Percent change in visitors from the previous period, `None` when the
+    /// value had none then.
+    pub change: Option<f64>,
+}
+
+impl<R: From<BreakdownRow>> ComparedRow<R> {
+    /// Compare `row` with the visitors `previous` holds for its value, as
+    /// returned by [`query_previous_visitors`].
+    pub fn new(row: BreakdownRow, previous: &HashMap<String, u64>) -> Self {
+        let previous_visitors = previous.get(&row.value).copied().unwrap_or(0);
+        let change = (previous_visitors > 0).then(|| {
+            #[allow(clippy::cast_precision_loss)]
+            let pct =
+                (row.visitors as f64 - previous_visitors as f64) / previous_visitors as f64 * 100.0;
+            (pct * 100.0).round() / 100.0
+        });
+        Self {
+            row: row.into(),
+            previous_visitors,
+            change,
+        }
+    }
+}
+
+/// A country breakdown row, with the country's name, flag and continent
+/// when its code is known.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct CountryRow {
+    #[serde(flatten)]
+    pub row: BreakdownRow,
+    pub name: Option<Cow<'static, str>>,
+    pub flag: Option<String>,
+    pub continent: Option<Cow<'static, str>>,
+}
+
+impl From<BreakdownRow> for CountryRow {
+    fn from(row: BreakdownRow) -> Self {
+        Self {
+            name: countries::country_name(&row.value).map(Cow::Borrowed),
+            flag: countries::flag(&row.value),
+            continent: countries::continent_of(&row.value).map(Cow::Borrowed),
+            row,
+        }
+    }
+}
+
+/// A continent breakdown row, with the continent's name when known.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct ContinentRow {
+    #[serde(flatten)]
+    pub row: BreakdownRow,
+    pub name: Option<Cow<'static, str>>,
+}
+
+/// Query visitors and pageviews per continent, from the events' country
+/// codes.  Events without a known country are grouped as `(unknown)`.
+pub fn query_continent_breakdown(
+    conn: &QueryConn<'_>,
+    site_id: &str,
+    start_date: &str,
+    end_date: &str,
+) -> Result<Vec<ContinentRow>, duckdb::Error> {
+    let site = SiteScope::of(site_id).filter("site_id");
+    let continent = countries::continent_sql("country_code");
+    let sql = format!(
+        "SELECT COALESCE({continent}, '(unknown)') AS dim_value,
+                COUNT(DISTINCT visitor_id) AS visitors,
+                COUNT(*) FILTER (WHERE event_name = 'pageview') AS pageviews
+         FROM events_all
+         WHERE {site} AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
+         GROUP BY dim_value
+         ORDER BY visitors DESC, dim_value"
+    );
+
+    let mut stmt = super::prepare_in_range(conn, &sql, site_id, start_date, end_date)?;
+    let rows = stmt
+        .query_map(duckdb::params![site_id, start_date, end_date], |row| {
+            let value: String = row.get(0)?;
+            Ok(ContinentRow {
+                name: countries::continent_name(&value).map(Cow::Borrowed),
+                row: BreakdownRow {
+                    value,
+                    visitors: row.get(1)?,
+                    pageviews: row.get(2)?,
+                },
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+
+    Ok(rows)
+}
+
+/// A device model breakdown row, with the device's brand when known.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct DeviceModelRow {
+    #[serde(flatten)]
+    pub row: BreakdownRow,
+    pub brand: Option<String>,
+}
+
+/// Query visitors and pageviews per mobile device brand and model.  Events
+/// without a model, such as those from desktops, are grouped as `(unknown)`.
+pub fn query_device_model_breakdown(
+    conn: &QueryConn<'_>,
+    site_id: &str,
+    start_date: &str,
+    end_date: &str,
+    limit: usize,
+) -> Result<Vec<DeviceModelRow>, duckdb::Error> {
+    let site = SiteScope::of(site_id).filter("site_id");
+    let sql = format!(
+        "SELECT device_brand,
+                COALESCE(device_model, '(unknown)') AS dim_value,
+                COUNT(DISTINCT visitor_id) AS visitors,
+                COUNT(*) FILTER (WHERE event_name = 'pageview') AS pageviews
+         FROM events_all
+         WHERE {site} AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
+         GROUP BY device_brand, dim_value
+         ORDER BY visitors DESC, dim_value
+         LIMIT ?"
+    );
+
+    let mut stmt = super::prepare_in_range(conn, &sql, site_id, start_date, end_date)?;
+    let limit_i64 = i64::try_from(limit).unwrap_or(i64::MAX);
+    let rows = stmt
+        .query_map(
+            duckdb::params![site_id, start_date, end_date, limit_i64],
+            |row| {
+                Ok(DeviceModelRow {
+                    brand: row.get(0)?,
+                    row: BreakdownRow {
+                        value: row.get(1)?,
+                        visitors: row.get(2)?,
+                        pageviews: row.get(3)?,
+                    },
+                })
+            },
+        )?
+        .collect::<Result<_, _>>()?;
+
+    Ok(rows)
+}
+
+/// How a visitor is credited to the sources of their sessions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Attribution {
+    /// All credit to the visitor's first session in the range.
+    FirstTouch,
+    /// All credit to the visitor's last session in the range.
+    LastTouch,
+    /// Credit split evenly across the visitor's sessions in the range.
+    Linear,
+}
+
+impl Attribution {
+    /// SQL share of a visitor credited to session `v.session_id` (1-based),
+    /// given the visitor's session count `t.sessions`.
+    const fn weight_sql(self) -> &'static str {
+        match self {
+            Self::FirstTouch => "CASE WHEN v.session_id = 1 THEN 1.0 ELSE 0.0 END",
+            Self::LastTouch => "CASE WHEN v.session_id = t.sessions THEN 1.0 ELSE 0.0 END",
+            Self::Linear => "1.0 / CAST(t.sessions AS DOUBLE)",
+        }
+    }
+}
+
+/// An attributed breakdown: the model, and optionally a goal event whose
+/// occurrences are credited as conversions.
+#[derive(Debug, Clone, Copy)]
+pub struct AttributionQuery<'a> {
+    pub model: Attribution,
+    pub goal: Option<&'a str>,
+}
+
+/// A breakdown row under an attribution model.  Counts are fractional under
+/// [`Attribution::Linear`].
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct AttributedRow {
+    pub value: String,
+    pub visitors: f64,
+    pub pageviews: f64,
+    /// Goal events credited to this value.  Omitted when no goal was given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conversions: Option<f64>,
+}
+
+/// Query a breakdown that credits each visitor, with all of their pageviews
+/// and goal events in the range, to the `dimension` values their sessions
+/// started with.
+///
+/// Sessions split after 30 minutes of inactivity, and a session's value is
+/// that of its first event (normally the landing pageview, which carries the
+/// referrer and UTM tags).
+pub fn query_attributed_breakdown(
+    conn: &QueryConn<'_>,
+    site_id: &str,
+    start_date: &str,
+    end_date: &str,
+    dimension: Dimension,
+    attribution: AttributionQuery<'_>,
+    limit: usize,
+) -> Result<Vec<AttributedRow>, duckdb::Error> {
+    let site = SiteScope::of(site_id).filter("site_id");
+    let col = dimension.column_name();
+    let weight = attribution.model.weight_sql();
+
+    // Column name and weight come from fixed enums.
+    let sql = format!(
+        "WITH marks AS (
+             SELECT visitor_id, timestamp, event_name,
+                    COALESCE({col}, '(unknown)') AS touch,
+                    CASE
+                        WHEN timestamp - LAG(timestamp) OVER (PARTITION BY visitor_id ORDER BY timestamp)
+                            <= INTERVAL '30 minutes' THEN 0
+                        ELSE 1
+                    END AS new_session
+             FROM events_all
+             WHERE {site} AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
+         ),
+         numbered AS (
+             SELECT *,
+                 SUM(new_session) OVER (
+                     PARTITION BY visitor_id ORDER BY timestamp
+                     ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW
+                 ) AS session_id
+             FROM marks
+         ),
+         visits AS (
+             SELECT visitor_id, session_id, arg_min(touch, timestamp) AS touch
+             FROM numbered
+             GROUP BY visitor_id, session_id
+         ),
+         totals AS (
+             SELECT visitor_id,
+                    MAX(session_id) AS sessions,
+                    COUNT(*) FILTER (WHERE event_name = 'pageview') AS pageviews,
+                    COUNT(*) FILTER (WHERE event_name = CAST(? AS VARCHAR)) AS conversions
+             FROM numbered
+             GROUP BY visitor_id
+         ),
+         credits AS (
+             SELECT v.touch, t.pageviews, t.conversions, CAST({weight} AS DOUBLE) AS weight
+             FROM visits v
+             JOIN totals t USING (visitor_id)
+         )
+         SELECT touch,
+                SUM(weight) AS visitors,
+                SUM(weight * pageviews) AS pageviews,
+                SUM(weight * conversions) AS conversions
+         FROM credits
+         WHERE weight > 0
+         GROUP BY touch
+         ORDER BY visitors DESC, touch
+         LIMIT ?"
+    );
+
+    let mut stmt = super::prepare_in_range(conn, &sql, site_id, start_date, end_date)?;
+    let limit_i64 = i64::try_from(limit).unwrap_or(i64::MAX);
+    let has_goal = attribution.goal.is_some();
+    let rows = stmt
+        .query_map(
+            duckdb::params![site_id, start_date, end_date, attribution.goal, limit_i64],
+            |row| {
+                let conversions: f64 = row.get(3)?;
+                Ok(AttributedRow {
+                    value: row.get(0)?,
+                    visitors: row.get(1)?,
+                    pageviews: row.get(2)?,
+                    conversions: has_goal.then_some(conversions),
+                })
+            },
+        )?
+        .collect::<Result<_, _>>()?;
+
+    Ok(rows)
+}
+
+/// Goal conversions among the visitors acquired through one channel.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct ConversionRow {
+    pub value: String,
+    pub visitors: u64,
+    /// Visitors who reached the goal at least once.
+    pub converted_visitors: u64,
+    /// Goal completions by those visitors.
+    pub conversions: u64,
+    /// `converted_visitors` as a percentage of `visitors`.
+    pub conversion_rate: f64,
+}
+
+/// Query goal conversions per acquisition channel.
+///
+/// Each visitor belongs to the `dimension` value of their first event in the
+/// range, so a goal event, which rarely carries a referrer itself, counts for
+/// the channel that brought the visitor in.  `goal_condition` is a SQL boolean
+/// expression over event columns, built by the caller from a fixed grammar.
+pub fn query_source_conversions(
+    conn: &QueryConn<'_>,
+    site_id: &str,
+    start_date: &str,
+    end_date: &str,
+    dimension: Dimension,
+    goal_condition: &str,
+    limit: usize,
+) -> Result<Vec<ConversionRow>, duckdb::Error> {
+    let site = SiteScope::of(site_id).filter("site_id");
+    let col = dimension.column_name();
+    let sql = format!(
+        "WITH acquired AS (
+             SELECT visitor_id,
+                    arg_min(COALESCE({col}, '(unknown)'), timestamp) AS channel,
+                    COUNT(*) FILTER (WHERE {goal_condition}) AS conversions
+             FROM events_all
+             WHERE {site} AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
+             GROUP BY visitor_id
+         )
+         SELECT channel,
+                COUNT(*) AS visitors,
+                COUNT(*) FILTER (WHERE conversions > 0) AS converted_visitors,
+                CAST(SUM(conversions) AS UBIGINT) AS conversions
+         FROM acquired
+         GROUP BY channel
+         ORDER BY converted_visitors DESC, visitors DESC, channel
+         LIMIT ?"
+    );
+
+    let mut stmt = super::prepare_in_range(conn, &sql, site_id, start_date, end_date)?;
+    let limit_i64 = i64::try_from(limit).unwrap_or(i64::MAX);
+    let rows = stmt
+        .query_map(
+            duckdb::params![site_id, start_date, end_date, limit_i64],
+            |row| {
+                let visitors: u64 = row.get(1)?;
+                let converted_visitors: u64 = row.get(2)?;
+                #[allow(clippy::cast_precision_loss)]
+                let conversion_rate = if visitors == 0 {
+                    0.0
+                } else {
+                    converted_visitors as f64 / visitors as f64 * 100.0
+                };
+                Ok(ConversionRow {
+                    value: row.get(0)?,
+                    visitors,
+                    converted_visitors,
+                    conversions: row.get(3)?,
+                    conversion_rate,
+                })
+            },
+        )?
+        .collect::<Result<_, _>>()?;
+
+    Ok(rows)
+}
+
+/// Query the most common site-search terms: values of the site's
+/// `search_param` query parameter, kept in stored pathnames at ingest.
+///
+/// Terms are grouped case-insensitively and percent-decoded for display.
+pub fn query_search_terms(
+    conn: &QueryConn<'_>,
+    site_id: &str,
+    start_date: &str,
+    end_date: &str,
+    search_param: &str,
+    limit: usize,
+) -> Result<Vec<BreakdownRow>, duckdb::Error> {
+    let site = SiteScope::of(site_id).filter("site_id");
+    // The parameter name is validated at config load; escaping makes `.`
+    // match literally.
+    let pattern = format!("[?&]{}=([^&]+)", regex::escape(search_param)).replace('\'', "''");
+    let sql = format!(
+        "SELECT lower(regexp_extract(pathname, '{pattern}', 1)) AS term,
+                COUNT(DISTINCT visitor_id) AS visitors,
+                COUNT(*) FILTER (WHERE event_name = 'pageview') AS pageviews
+         FROM events_all
+         WHERE {site} AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
+         GROUP BY term
+         HAVING term <> ''
+         ORDER BY visitors DESC, term
+         LIMIT ?"
+    );
+
+    let mut stmt = super::prepare_in_range(conn, &sql, site_id, start_date, end_date)?;
+    let limit_i64 = i64::try_from(limit).unwrap_or(i64::MAX);
+    let rows = stmt
+        .query_map(
+            duckdb::params![site_id, start_date, end_date, limit_i64],
+            |row| {
+                let term: String = row.get(0)?;
+                Ok(BreakdownRow {
+                    value: percent_decode(&term),
+                    visitors: row.get(1)?,
+                    pageviews: row.get(2)?,
+                })
+            },
+        )?
+        .collect::<Result<_, _>>()?;
+
+    Ok(rows)
+}
+
+/// Occurrences of one custom event.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct EventCountRow {
+    pub name: String,
+    pub visitors: u64,
+    pub events: u64,
+}
+
+/// Query custom events (everything except pageviews and heartbeats) by name,
+/// most frequent first.
+pub fn query_event_counts(
+    conn: &QueryConn<'_>,
+    site_id: &str,
+    start_date: &str,
+    end_date: &str,
+    limit: usize,
+) -> Result<Vec<EventCountRow>, duckdb::Error> {
+    let site = SiteScope::of(site_id).filter("site_id");
+    let sql = format!(
+        "SELECT event_name,
+                COUNT(DISTINCT visitor_id) AS visitors,
+                COUNT(*) AS events
+         FROM events_all
+         WHERE {site} AND event_name NOT IN ('pageview', 'heartbeat')
+           AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
+         GROUP BY event_name
+         ORDER BY events DESC, event_name
+         LIMIT ?"
+    );
+
+    let mut stmt = super::prepare_in_range(conn, &sql, site_id, start_date, end_date)?;
+    let limit_i64 = i64::try_from(limit).unwrap_or(i64::MAX);
+    let rows = stmt
+        .query_map(
+            duckdb::params![site_id, start_date, end_date, limit_i64],
+            |row| {
+                Ok(EventCountRow {
+                    name: row.get(0)?,
+                    visitors: row.get(1)?,
+                    events: row.get(2)?,
+                })
+            },
+        )?
+        .collect::<Result<_, _>>()?;
+
+    Ok(rows)
+}
+
+/// A property key sent with a custom event.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct EventPropKey {
+    pub key: String,
+    /// Events of the name whose props carry the key.
+    pub events: u64,
+}
+
+/// A custom event with its counts and the property keys it is sent with.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct CustomEventRow {
+    pub name: String,
+    pub visitors: u64,
+    pub events: u64,
+    /// Top-level keys of the event's props, most frequent first.
+    pub props: Vec<EventPropKey>,
+}
+
+/// Most distinct props values read to discover property keys.  Keys only
+/// ever sent with the rarest values past this cap are not listed.
+const MAX_DISTINCT_PROPS: i64 = 10_000;
+
+/// Query custom events by name, most frequent first, like
+/// [`query_event_counts`], with the keys found in their props.
+pub fn query_custom_events(
+    conn: &QueryConn<'_>,
+    site_id: &str,
+    start_date: &str,
+    end_date: &str,
+    limit: usize,
+) -> Result<Vec<CustomEventRow>, duckdb::Error> {
+    let counts = query_event_counts(conn, site_id, start_date, end_date, limit)?;
+    if counts.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let site = SiteScope::of(site_id).filter("site_id");
+    let sql = format!(
+        "SELECT event_name, props, COUNT(*) AS events
+         FROM events_all
+         WHERE {site} AND event_name NOT IN ('pageview', 'heartbeat')
+           AND props IS NOT NULL
+           AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
+         GROUP BY event_name, props
+         ORDER BY events DESC
+         LIMIT ?"
+    );
+
+    let mut stmt = super::prepare_in_range(conn, &sql, site_id, start_date, end_date)?;
+    let mut keys: HashMap<String, HashMap<String, u64>> = HashMap::new();
+    let rows = stmt.query_map(
+        duckdb::params![site_id, start_date, end_date, MAX_DISTINCT_PROPS],
+        |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, u64>(2)?,
+            ))
+        },
+    )?;
+    for row in rows {
+        let (name, props, events) = row?;
+        let Ok(serde_json::Value::Object(map)) = serde_json::from_str(&props) else {
+            continue;
+        };
+        let event_keys = keys.entry(name).or_default();
+        for key in map.keys() {
+            *event_keys.entry(key.clone()).or_default() += events;
+        }
+    }
+
+    Ok(counts
+        .into_iter()
+        .map(|row| {
+            let mut props: Vec<EventPropKey> = keys
+                .remove(&row.name)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(key, events)| EventPropKey { key, events })
+                .collect();
+            props.sort_by(|a, b| b.events.cmp(&a.events).then_with(|| a.key.cmp(&b.key)));
+            CustomEventRow {
+                name: row.name,
+                visitors: row.visitors,
+                events: row.events,
+                props,
+            }
+        })
+        .collect())
+}
+
+/// Email opens of one campaign, recorded by the email open pixel.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct EmailOpenRow {
+    pub campaign: String,
+    pub opens: u64,
+    /// Distinct visitors (mail clients) that opened the email.
+    pub unique_opens: u64,
+}
+
+/// Query email opens per campaign, most opened first.
+pub fn query_email_opens(
+    conn: &QueryConn<'_>,
+    site_id: &str,
+    start_date: &str,
+    end_date: &str,
+    limit: usize,
+) -> Result<Vec<EmailOpenRow>, duckdb::Error> {
+    let site = SiteScope::of(site_id).filter("site_id");
+    let sql = format!(
+        "SELECT COALESCE(utm_campaign, '(unknown)') AS campaign,
+                COUNT(*) AS opens,
+                COUNT(DISTINCT visitor_id) AS unique_opens
+         FROM events_all
+         WHERE {site} AND event_name = ?
+           AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
+         GROUP BY campaign
+         ORDER BY opens DESC, campaign
+         LIMIT ?"
+    );
+
+    let mut stmt = super::prepare_in_range(conn, &sql, site_id, start_date, end_date)?;
+    let limit_i64 = i64::try_from(limit).unwrap_or(i64::MAX);
+    let rows = stmt
+        .query_map(
+            duckdb::params![
+                site_id,
+                crate::ingest::handler::EMAIL_OPEN_EVENT,
+                start_date,
+                end_date,
+                limit_i64
+            ],
+            |row| {
+                let campaign: String = row.get(0)?;
+                Ok(EmailOpenRow {
+                    campaign: percent_decode(&campaign),
+                    opens: row.get(1)?,
+                    unique_opens: row.get(2)?,
+                })
+            },
+        )?
+        .collect::<Result<_, _>>()?;
+
+    Ok(rows)
+}
+
+/// Decode `%XX` escapes, replacing invalid UTF-8 with U+FFFD.
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| s.get(i + 1..i + 3))
+            .flatten()
+            .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        if let Some(byte) = escaped {
+            out.push(byte);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use duckdb::Connection;
 
     fn setup_test_db() -> Connection {
         let conn = Connection::open_in_memory().unwrap();
@@ -104,11 +979,12 @@ mod tests {
         insert_event(&conn, "v2", "/", None);
 
         let rows = query_breakdown(
-            &conn,
+            &QueryConn::new(&conn),
             "test.com",
             "2024-01-01",
             "2024-02-01",
             Dimension::Page,
+            None,
             10,
         )
         .unwrap();
@@ -127,11 +1003,12 @@ mod tests {
         insert_event(&conn, "v3", "/", Some("Chrome"));
 
         let rows = query_breakdown(
-            &conn,
+            &QueryConn::new(&conn),
             "test.com",
             "2024-01-01",
             "2024-02-01",
             Dimension::Browser,
+            None,
             10,
         )
         .unwrap();
@@ -141,6 +1018,42 @@ mod tests {
         assert_eq!(rows[0].visitors, 2);
     }
 
+    #[test]
+    fn test_breakdown_by_custom_dimension() {
+        let conn = setup_test_db();
+        for (vid, plan) in [("v1", Some("pro")), ("v2", Some("pro")), ("v3", None)] {
+            conn.execute(
+                "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname, dim_2)
+                 VALUES ('test.com', ?, '2024-01-15 10:00:00', 'pageview', '/', ?)",
+                duckdb::params![vid, plan],
+            )
+            .unwrap();
+        }
+
+        let rows = query_breakdown(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            Dimension::Custom(2),
+            None,
+            10,
+        )
+        .unwrap();
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].value, "pro");
+        assert_eq!(rows[0].visitors, 2);
+        assert_eq!(rows[1].value, "(unknown)");
+    }
+
+    #[test]
+    fn test_custom_dimension_out_of_range_slot() {
+        assert_eq!(Dimension::Custom(0).column_name(), "NULL");
+        assert_eq!(Dimension::Custom(99).column_name(), "NULL");
+        assert_eq!(Dimension::Custom(1).column_name(), "dim_1");
+    }
+
     #[test]
     fn test_breakdown_limit() {
         let conn = setup_test_db();
@@ -149,11 +1062,12 @@ mod tests {
         insert_event(&conn, "v3", "/c", None);
 
         let rows = query_breakdown(
-            &conn,
+            &QueryConn::new(&conn),
             "test.com",
             "2024-01-01",
             "2024-02-01",
             Dimension::Page,
+            None,
             2,
         )
         .unwrap();
@@ -161,15 +1075,166 @@ mod tests {
         assert_eq!(rows.len(), 2);
     }
 
+    #[test]
+    fn test_breakdown_coverage_folds_other_values() {
+        let conn = setup_test_db();
+        insert_event(&conn, "v1", "/a", None);
+        insert_event(&conn, "v2", "/a", None);
+        insert_event(&conn, "v1", "/b", None);
+        insert_event(&conn, "v3", "/c", None);
+        insert_event(&conn, "v3", "/d", None);
+
+        let coverage = query_breakdown_coverage(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            Dimension::Page,
+            None,
+            2,
+        )
+        .unwrap();
+        let rows: Vec<(&str, u64, u64)> = coverage
+            .rows
+            .iter()
+            .map(|r| (r.value.as_str(), r.visitors, r.pageviews))
+            .collect();
+        assert_eq!(rows, [("/a", 2, 2), ("/b", 1, 1), (OTHER, 1, 2)]);
+        assert_eq!(
+            coverage.total,
+            BreakdownTotal {
+                visitors: 3,
+                pageviews: 5,
+                values: 4,
+            }
+        );
+
+        // Nothing to fold.
+        let coverage = query_breakdown_coverage(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            Dimension::Page,
+            None,
+            10,
+        )
+        .unwrap();
+        assert_eq!(coverage.rows.len(), 4);
+        assert!(coverage.rows.iter().all(|r| r.value != OTHER));
+
+        let empty = query_breakdown_coverage(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2025-01-01",
+            "2025-02-01",
+            Dimension::Page,
+            None,
+            10,
+        )
+        .unwrap();
+        assert!(empty.rows.is_empty());
+        assert_eq!(empty.total, BreakdownTotal::default());
+    }
+
+    #[test]
+    fn test_previous_visitors_and_change() {
+        assert_eq!(
+            previous_range("2024-01-10", "2024-01-20"),
+            Some(("2023-12-31".to_string(), "2024-01-10".to_string()))
+        );
+        assert_eq!(previous_range("2024-01-10", "soon"), None);
+
+        let conn = setup_test_db();
+        for (visitor, source) in [
+            ("v1", "Google"),
+            ("v2", "Google"),
+            ("v3", "Bing"),
+            ("v4", "X"),
+        ] {
+            insert_touch(
+                &QueryConn::new(&conn),
+                visitor,
+                "2024-01-15 10:00:00",
+                "pageview",
+                Some(source),
+            );
+        }
+        for (visitor, source) in [
+            ("v5", "Google"),
+            ("v6", "Bing"),
+            ("v7", "Bing"),
+            ("v8", "X"),
+            ("v9", "Reddit"),
+        ] {
+            insert_touch(
+                &QueryConn::new(&conn),
+                visitor,
+                "2024-01-05 10:00:00",
+                "pageview",
+                Some(source),
+            );
+        }
+
+        // The top two values now are Google and Bing, which wins the tie
+        // with X by name.
+        let previous = query_previous_visitors(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-10",
+            "2024-01-20",
+            Dimension::ReferrerSource,
+            None,
+            2,
+        )
+        .unwrap();
+        assert_eq!(previous.len(), 3);
+        assert_eq!(previous["Google"], 1);
+        assert_eq!(previous["Bing"], 2);
+        assert_eq!(previous[OTHER], 2);
+
+        let rows = query_breakdown(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-10",
+            "2024-01-20",
+            Dimension::ReferrerSource,
+            None,
+            2,
+        )
+        .unwrap();
+        let compared: Vec<ComparedRow<BreakdownRow>> = rows
+            .into_iter()
+            .map(|row| ComparedRow::new(row, &previous))
+            .collect();
+        assert_eq!(compared[0].row.value, "Google");
+        assert_eq!(compared[0].previous_visitors, 1);
+        assert_eq!(compared[0].change, Some(100.0));
+        assert_eq!(compared[1].row.value, "Bing");
+        assert_eq!(compared[1].change, Some(-50.0));
+
+        let new_value = ComparedRow::<BreakdownRow>::new(
+            BreakdownRow {
+                value: "Mastodon".to_string(),
+                visitors: 3,
+                pageviews: 3,
+            },
+            &previous,
+        );
+        assert_eq!(new_value.previous_visitors, 0);
+        assert_eq!(new_value.change, None);
+    }
+
     #[test]
     fn test_breakdown_empty() {
         let conn = setup_test_db();
         let rows = query_breakdown(
-            &conn,
+            &QueryConn::new(&conn),
             "test.com",
             "2024-01-01",
             "2024-02-01",
             Dimension::Page,
+            None,
             10,
         )
         .unwrap();
@@ -177,17 +1242,322 @@ mod tests {
         assert!(rows.is_empty());
     }
 
+    fn insert_touch(
+        conn: &QueryConn<'_>,
+        visitor_id: &str,
+        ts: &str,
+        name: &str,
+        source: Option<&str>,
+    ) {
+        conn.execute(
+            "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname, referrer_source)
+             VALUES ('test.com', ?, CAST(? AS TIMESTAMP), ?, '/', ?)",
+            duckdb::params![visitor_id, ts, name, source],
+        )
+        .unwrap();
+    }
+
+    fn attributed(
+        conn: &QueryConn<'_>,
+        model: Attribution,
+        goal: Option<&str>,
+    ) -> Vec<AttributedRow> {
+        query_attributed_breakdown(
+            conn,
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            Dimension::ReferrerSource,
+            AttributionQuery { model, goal },
+            10,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_attributed_breakdown() {
+        let conn = setup_test_db();
+        // v1 arrives from Google, returns from Twitter two hours later and signs up.
+        insert_touch(
+            &QueryConn::new(&conn),
+            "v1",
+            "2024-01-15 10:00:00",
+            "pageview",
+            Some("Google"),
+        );
+        insert_touch(
+            &QueryConn::new(&conn),
+            "v1",
+            "2024-01-15 12:00:00",
+            "pageview",
+            Some("Twitter"),
+        );
+        insert_touch(
+            &QueryConn::new(&conn),
+            "v1",
+            "2024-01-15 12:05:00",
+            "signup",
+            None,
+        );
+        insert_touch(
+            &QueryConn::new(&conn),
+            "v2",
+            "2024-01-15 10:00:00",
+            "pageview",
+            Some("Twitter"),
+        );
+
+        let rows = attributed(
+            &QueryConn::new(&conn),
+            Attribution::FirstTouch,
+            Some("signup"),
+        );
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].value, "Google");
+        assert!((rows[0].visitors - 1.0).abs() < f64::EPSILON);
+        assert!((rows[0].pageviews - 2.0).abs() < f64::EPSILON);
+        assert_eq!(rows[0].conversions, Some(1.0));
+        assert_eq!(rows[1].value, "Twitter");
+        assert_eq!(rows[1].conversions, Some(0.0));
+
+        let rows = attributed(
+            &QueryConn::new(&conn),
+            Attribution::LastTouch,
+            Some("signup"),
+        );
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].value, "Twitter");
+        assert!((rows[0].visitors - 2.0).abs() < f64::EPSILON);
+        assert!((rows[0].pageviews - 3.0).abs() < f64::EPSILON);
+        assert_eq!(rows[0].conversions, Some(1.0));
+
+        let rows = attributed(&QueryConn::new(&conn), Attribution::Linear, None);
+        assert_eq!(rows[0].value, "Twitter");
+        assert!((rows[0].visitors - 1.5).abs() < f64::EPSILON);
+        assert!((rows[0].pageviews - 2.0).abs() < f64::EPSILON);
+        assert_eq!(rows[0].conversions, None);
+        assert_eq!(rows[1].value, "Google");
+        assert!((rows[1].visitors - 0.5).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_source_conversions() {
+        let conn = setup_test_db();
+        // v1 arrives from Google and signs up twice; the signups have no referrer.
+        insert_touch(
+            &QueryConn::new(&conn),
+            "v1",
+            "2024-01-15 10:00:00",
+            "pageview",
+            Some("Google"),
+        );
+        insert_touch(
+            &QueryConn::new(&conn),
+            "v1",
+            "2024-01-15 10:02:00",
+            "signup",
+            None,
+        );
+        insert_touch(
+            &QueryConn::new(&conn),
+            "v1",
+            "2024-01-15 10:03:00",
+            "signup",
+            None,
+        );
+        insert_touch(
+            &QueryConn::new(&conn),
+            "v2",
+            "2024-01-15 10:00:00",
+            "pageview",
+            Some("Google"),
+        );
+        insert_touch(
+            &QueryConn::new(&conn),
+            "v3",
+            "2024-01-15 10:00:00",
+            "pageview",
+            Some("Twitter"),
+        );
+
+        let rows = query_source_conversions(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            Dimension::ReferrerSource,
+            "event_name = 'signup'",
+            10,
+        )
+        .unwrap();
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].value, "Google");
+        assert_eq!(rows[0].visitors, 2);
+        assert_eq!(rows[0].converted_visitors, 1);
+        assert_eq!(rows[0].conversions, 2);
+        assert!((rows[0].conversion_rate - 50.0).abs() < f64::EPSILON);
+        assert_eq!(rows[1].value, "Twitter");
+        assert_eq!(rows[1].conversions, 0);
+        assert!(rows[1].conversion_rate.abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_search_terms() {
+        let conn = setup_test_db();
+        insert_event(&conn, "v1", "/search?q=red%20shoes", None);
+        insert_event(&conn, "v2", "/search?page=2&q=Red%20Shoes", None);
+        insert_event(&conn, "v3", "/search?q=caf%C3%A9", None);
+        insert_event(&conn, "v4", "/search", None);
+        insert_event(&conn, "v5", "/other?query=nope", None);
+
+        let rows = query_search_terms(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            "q",
+            10,
+        )
+        .unwrap();
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].value, "red shoes");
+        assert_eq!(rows[0].visitors, 2);
+        assert_eq!(rows[1].value, "café");
+    }
+
+    #[test]
+    fn test_email_opens() {
+        let conn = setup_test_db();
+        for (vid, campaign) in [
+            ("v1", Some("spring%20sale")),
+            ("v1", Some("spring%20sale")),
+            ("v2", Some("spring%20sale")),
+            ("v3", None),
+        ] {
+            conn.execute(
+                "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname, utm_campaign)
+                 VALUES ('test.com', ?, '2024-01-15 10:00:00', 'email_open', '/', ?)",
+                duckdb::params![vid, campaign],
+            )
+            .unwrap();
+        }
+        insert_event(&conn, "v4", "/", None);
+
+        let rows = query_email_opens(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            10,
+        )
+        .unwrap();
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].campaign, "spring sale");
+        assert_eq!(rows[0].opens, 3);
+        assert_eq!(rows[0].unique_opens, 2);
+        assert_eq!(rows[1].campaign, "(unknown)");
+    }
+
+    #[test]
+    fn test_event_counts() {
+        let conn = setup_test_db();
+        for (vid, name) in [
+            ("v1", "signup"),
+            ("v1", "download"),
+            ("v2", "download"),
+            ("v2", "download"),
+            ("v2", "heartbeat"),
+        ] {
+            conn.execute(
+                "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+                 VALUES ('test.com', ?, '2024-01-15 10:00:00', ?, '/')",
+                duckdb::params![vid, name],
+            )
+            .unwrap();
+        }
+        insert_event(&conn, "v3", "/", None);
+
+        let rows = query_event_counts(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            10,
+        )
+        .unwrap();
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].name, "download");
+        assert_eq!(rows[0].events, 3);
+        assert_eq!(rows[0].visitors, 2);
+        assert_eq!(rows[1].name, "signup");
+    }
+
+    #[test]
+    fn test_custom_events() {
+        let conn = setup_test_db();
+        for (vid, name, props) in [
+            ("v1", "signup", Some(r#"{"plan":"pro"}"#)),
+            ("v2", "signup", Some(r#"{"plan":"free","coupon":"x"}"#)),
+            ("v3", "signup", Some(r#"{"plan":"pro"}"#)),
+            ("v3", "download", None),
+            ("v3", "download", Some("not json")),
+        ] {
+            conn.execute(
+                "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname, props)
+                 VALUES ('test.com', ?, '2024-01-15 10:00:00', ?, '/', ?)",
+                duckdb::params![vid, name, props],
+            )
+            .unwrap();
+        }
+        insert_event(&conn, "v4", "/", None);
+
+        let rows = query_custom_events(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            10,
+        )
+        .unwrap();
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].name, "signup");
+        assert_eq!(rows[0].events, 3);
+        assert_eq!(rows[0].visitors, 3);
+        let keys: Vec<(&str, u64)> = rows[0]
+            .props
+            .iter()
+            .map(|p| (p.key.as_str(), p.events))
+            .collect();
+        assert_eq!(keys, [("plan", 3), ("coupon", 1)]);
+        assert_eq!(rows[1].name, "download");
+        assert!(rows[1].props.is_empty());
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a%20b%2Fc"), "a b/c");
+        // Malformed escapes are left as they are.
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%+1"), "%zz%+1");
+    }
+
     #[test]
     fn test_breakdown_null_values() {
         let conn = setup_test_db();
         insert_event(&conn, "v1", "/", None); // browser is NULL
 
         let rows = query_breakdown(
-            &conn,
+            &QueryConn::new(&conn),
             "test.com",
             "2024-01-01",
             "2024-02-01",
             Dimension::Browser,
+            None,
             10,
         )
         .unwrap();
@@ -195,4 +1565,103 @@ mod tests {
         assert_eq!(rows.len(), 1);
         assert_eq!(rows[0].value, "(unknown)");
     }
+
+    #[test]
+    fn test_continent_breakdown() {
+        let conn = setup_test_db();
+        for (visitor, country) in [
+            ("v1", Some("DE")),
+            ("v2", Some("FR")),
+            ("v3", Some("JP")),
+            ("v4", None),
+        ] {
+            conn.execute(
+                "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname, country_code)
+                 VALUES ('test.com', ?, '2024-01-15 10:00:00', 'pageview', '/', ?)",
+                duckdb::params![visitor, country],
+            )
+            .unwrap();
+        }
+
+        let rows = query_continent_breakdown(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+        )
+        .unwrap();
+        let summary: Vec<_> = rows
+            .iter()
+            .map(|r| (r.row.value.as_str(), r.name.as_deref(), r.row.visitors))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("EU", Some("Europe"), 2),
+                ("(unknown)", None, 1),
+                ("AS", Some("Asia"), 1),
+            ]
+        );
+
+        let row = CountryRow::from(BreakdownRow {
+            value: "DE".to_string(),
+            visitors: 1,
+            pageviews: 1,
+        });
+        assert_eq!(row.name.as_deref(), Some("Germany"));
+        assert_eq!(row.continent.as_deref(), Some("EU"));
+        assert!(row.flag.is_some());
+        let unknown = CountryRow::from(BreakdownRow {
+            value: "(unknown)".to_string(),
+            visitors: 1,
+            pageviews: 1,
+        });
+        assert_eq!(
+            (
+                unknown.name.as_deref(),
+                unknown.flag,
+                unknown.continent.as_deref()
+            ),
+            (None, None, None)
+        );
+    }
+
+    #[test]
+    fn test_device_model_breakdown() {
+        let conn = setup_test_db();
+        for (visitor, brand, model) in [
+            ("v1", Some("Apple"), Some("iPhone")),
+            ("v2", Some("Apple"), Some("iPhone")),
+            ("v3", Some("Google"), Some("Pixel 8")),
+            ("v4", None, None),
+        ] {
+            conn.execute(
+                "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname, device_brand, device_model)
+                 VALUES ('test.com', ?, '2024-01-15 10:00:00', 'pageview', '/', ?, ?)",
+                duckdb::params![visitor, brand, model],
+            )
+            .unwrap();
+        }
+
+        let rows = query_device_model_breakdown(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            10,
+        )
+        .unwrap();
+        let summary: Vec<_> = rows
+            .iter()
+            .map(|r| (r.brand.as_deref(), r.row.value.as_str(), r.row.visitors))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (Some("Apple"), "iPhone", 2),
+                (None, "(unknown)", 1),
+                (Some("Google"), "Pixel 8", 1),
+            ]
+        );
+    }
 }
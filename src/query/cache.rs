@@ -1,5 +1,5 @@
 use parking_lot::Mutex;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
@@ -35,6 +35,11 @@ impl QueryCache {
         }
     }
 
+    /// How long entries are kept.
+    pub const fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
     /// Look up a cached value by key. Returns `None` if missing or expired.
     pub fn get(&self, key: &str) -> Option<String> {
         if self.ttl.is_zero() {
@@ -90,6 +95,18 @@ impl QueryCache {
         entries.retain(|_, entry| entry.inserted_at.elapsed() <= self.ttl);
     }
 
+    /// Remove the entries for any of `site_ids`, and for site groups and
+    /// all-sites queries, which may include them.  Keys are
+    /// `<kind>:<site_id>:...`.
+    pub fn invalidate_sites(&self, site_ids: &HashSet<&str>) {
+        self.entries.lock().retain(|key, _| {
+            let site_id = key.split(':').nth(1).unwrap_or_default();
+            !(site_ids.contains(site_id)
+                || site_id.starts_with(super::GROUP_PREFIX)
+                || site_id == super::ALL_SITES)
+        });
+    }
+
     /// Returns the number of entries currently in the cache.
     pub fn len(&self) -> usize {
         self.entries.lock().len()
@@ -126,6 +143,22 @@ mod tests {
         assert_eq!(cache.len(), 0);
     }
 
+    #[test]
+    fn test_cache_invalidate_sites() {
+        let cache = QueryCache::new(60, 0);
+        for key in [
+            "main:a.com:2024-01-01:2024-01-31",
+            "ts:a.com:2024-01-01:2024-01-31:Day",
+            "main:b.com:2024-01-01:2024-01-31",
+            "main:@brands:2024-01-01:2024-01-31",
+        ] {
+            cache.insert(key.to_string(), "{}".to_string());
+        }
+        cache.invalidate_sites(&HashSet::from(["a.com"]));
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get("main:b.com:2024-01-01:2024-01-31").is_some());
+    }
+
     #[test]
     fn test_cache_len() {
         let cache = QueryCache::new(60, 0);
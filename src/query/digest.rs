@@ -0,0 +1,279 @@
+use super::{QueryConn, SiteScope};
+
+/// A compact summary of a site's traffic over a period, with its highlights
+/// already worded for chat integrations.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct Digest {
+    pub site_id: String,
+    pub start_date: String,
+    /// Last day of the period, inclusive.
+    pub end_date: String,
+    pub visitors: u64,
+    pub pageviews: u64,
+    /// Visitors in the previous period of equal length.
+    pub previous_visitors: u64,
+    /// Percent change in visitors from the previous period, `None` when it
+    /// had none.
+    pub visitor_change: Option<f64>,
+    /// The referrer source bringing the most visitors that brought none in
+    /// the previous period.
+    pub top_new_referrer: Option<DigestReferrer>,
+    /// The day with the most visitors.
+    pub best_day: Option<DigestDay>,
+    /// One sentence on visitors and their change.
+    pub headline: String,
+    /// One sentence per highlight.
+    pub highlights: Vec<String>,
+}
+
+/// A referrer source of a [`Digest`].
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct DigestReferrer {
+    pub source: String,
+    pub visitors: u64,
+}
+
+/// A day of a [`Digest`].
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct DigestDay {
+    /// `YYYY-MM-DD`.
+    pub date: String,
+    pub visitors: u64,
+}
+
+impl Digest {
+    /// The headline and highlights as one plain-text message.
+    pub fn text(&self) -> String {
+        let mut text = self.headline.clone();
+        for highlight in &self.highlights {
+            text.push_str("\n• ");
+            text.push_str(highlight);
+        }
+        text
+    }
+}
+
+/// Build the digest of a site for `[start_date, end_date)`, both `YYYY-MM-DD`
+/// dates, compared with the previous period of equal length.
+pub fn query_digest(
+    conn: &QueryConn<'_>,
+    site_id: &str,
+    start_date: &str,
+    end_date: &str,
+) -> Result<Digest, duckdb::Error> {
+    let site = SiteScope::of(site_id).filter("site_id");
+    let (previous_start, _) = super::breakdowns::previous_range(start_date, end_date)
+        .unwrap_or_else(|| (start_date.to_string(), start_date.to_string()));
+    let visitors = super::metrics::query_unique_visitors(conn, site_id, start_date, end_date)?;
+    let pageviews = super::metrics::query_total_pageviews(conn, site_id, start_date, end_date)?;
+    let previous_visitors =
+        super::metrics::query_unique_visitors(conn, site_id, &previous_start, start_date)?;
+    #[allow(clippy::cast_precision_loss)]
+    let visitor_change = super::metrics::percent_change(previous_visitors as f64, visitors as f64);
+
+    let mut stmt = super::prepare_in_range(
+        conn,
+        &format!(
+            "SELECT referrer_source, COUNT(DISTINCT visitor_id) AS visitors
+             FROM events_all
+             WHERE {site} AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
+             AND referrer_source IS NOT NULL
+             AND referrer_source NOT IN (
+                 SELECT referrer_source FROM events_all
+                 WHERE {site} AND timestamp >= CAST(? AS TIMESTAMP)
+                 AND timestamp < CAST(? AS TIMESTAMP) AND referrer_source IS NOT NULL
+             )
+             GROUP BY referrer_source
+             ORDER BY visitors DESC, referrer_source
+             LIMIT 1"
+        ),
+        site_id,
+        &previous_start,
+        end_date,
+    )?;
+    let top_new_referrer = stmt
+        .query_map(
+            duckdb::params![
+                site_id,
+                start_date,
+                end_date,
+                site_id,
+                previous_start,
+                start_date
+            ],
+            |row| {
+                Ok(DigestReferrer {
+                    source: row.get(0)?,
+                    visitors: row.get(1)?,
+                })
+            },
+        )?
+        .next()
+        .transpose()?;
+
+    let mut stmt = super::prepare_in_range(
+        conn,
+        &format!(
+            "SELECT strftime(CAST(timestamp AS DATE), '%Y-%m-%d') AS day,
+                    COUNT(DISTINCT visitor_id) AS visitors
+             FROM events_all
+             WHERE {site} AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
+             GROUP BY day
+             ORDER BY visitors DESC, day
+             LIMIT 1"
+        ),
+        site_id,
+        start_date,
+        end_date,
+    )?;
+    let best_day = stmt
+        .query_map(duckdb::params![site_id, start_date, end_date], |row| {
+            Ok(DigestDay {
+                date: row.get(0)?,
+                visitors: row.get(1)?,
+            })
+        })?
+        .next()
+        .transpose()?;
+
+    let last_day = chrono::NaiveDate::parse_from_str(end_date, "%Y-%m-%d")
+        .ok()
+        .and_then(|end| end.pred_opt())
+        .map_or_else(|| end_date.to_string(), |day| day.to_string());
+    let mut digest = Digest {
+        site_id: site_id.to_string(),
+        start_date: start_date.to_string(),
+        end_date: last_day,
+        visitors,
+        pageviews,
+        previous_visitors,
+        visitor_change,
+        top_new_referrer,
+        best_day,
+        headline: String::new(),
+        highlights: Vec::new(),
+    };
+    digest.headline = headline(&digest);
+    digest.highlights = highlights(&digest);
+    Ok(digest)
+}
+
+/// `1 visitor` or `N visitors`.
+fn visitors_text(visitors: u64) -> String {
+    if visitors == 1 {
+        "1 visitor".to_string()
+    } else {
+        format!("{visitors} visitors")
+    }
+}
+
+fn headline(digest: &Digest) -> String {
+    let period = format!("from {} to {}", digest.start_date, digest.end_date);
+    let change = match digest.visitor_change {
+        None if digest.visitors == 0 => {
+            return format!("{}: no visitors {period}.", digest.site_id);
+        }
+        None => "none in the previous period".to_string(),
+        Some(change) if change > 0.0 => format!("up {change}% from the previous period"),
+        Some(change) if change < 0.0 => format!("down {}% from the previous period", -change),
+        Some(_) => "unchanged from the previous period".to_string(),
+    };
+    format!(
+        "{}: {} {period}, {change}.",
+        digest.site_id,
+        visitors_text(digest.visitors)
+    )
+}
+
+fn highlights(digest: &Digest) -> Vec<String> {
+    let mut highlights = Vec::new();
+    if let Some(referrer) = &digest.top_new_referrer {
+        highlights.push(format!(
+            "Top new referrer: {} ({}).",
+            referrer.source,
+            visitors_text(referrer.visitors)
+        ));
+    }
+    if let Some(day) = &digest.best_day {
+        let weekday = chrono::NaiveDate::parse_from_str(&day.date, "%Y-%m-%d")
+            .map(|date| format!("{} ", date.format("%A")))
+            .unwrap_or_default();
+        highlights.push(format!(
+            "Best day: {weekday}{} ({}).",
+            day.date,
+            visitors_text(day.visitors)
+        ));
+    }
+    highlights
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use duckdb::Connection;
+
+    #[test]
+    fn test_query_digest() {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::storage::schema::init_schema(&conn).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        crate::storage::schema::setup_query_view(&conn, dir.path()).unwrap();
+        // Google brought a visitor in both weeks; Hacker News only in the
+        // second, where Wednesday is the busiest day.
+        conn.execute_batch(
+            "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname,
+                                 referrer_source)
+             VALUES ('test.com', 'p1', '2024-01-02 10:00:00', 'pageview', '/', 'Google'),
+                    ('test.com', 'p2', '2024-01-03 10:00:00', 'pageview', '/', NULL),
+                    ('test.com', 'v1', '2024-01-08 10:00:00', 'pageview', '/', 'Google'),
+                    ('test.com', 'v2', '2024-01-10 10:00:00', 'pageview', '/', 'Hacker News'),
+                    ('test.com', 'v3', '2024-01-10 11:00:00', 'pageview', '/', 'Hacker News'),
+                    ('test.com', 'v3', '2024-01-10 11:01:00', 'pageview', '/about', NULL)",
+        )
+        .unwrap();
+
+        let digest = query_digest(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-08",
+            "2024-01-15",
+        )
+        .unwrap();
+        assert_eq!(digest.visitors, 3);
+        assert_eq!(digest.pageviews, 4);
+        assert_eq!(digest.previous_visitors, 2);
+        assert_eq!(digest.visitor_change, Some(50.0));
+        assert_eq!(
+            digest.top_new_referrer,
+            Some(DigestReferrer {
+                source: "Hacker News".to_string(),
+                visitors: 2,
+            })
+        );
+        assert_eq!(
+            digest.best_day,
+            Some(DigestDay {
+                date: "2024-01-10".to_string(),
+                visitors: 2,
+            })
+        );
+        assert_eq!(
+            digest.text(),
+            "test.com: 3 visitors from 2024-01-08 to 2024-01-14, up 50% from the previous period.\n\
+             • Top new referrer: Hacker News (2 visitors).\n\
+             • Best day: Wednesday 2024-01-10 (2 visitors)."
+        );
+
+        let empty = query_digest(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-02-01",
+            "2024-02-08",
+        )
+        .unwrap();
+        assert_eq!(
+            empty.text(),
+            "test.com: no visitors from 2024-02-01 to 2024-02-07."
+        );
+    }
+}
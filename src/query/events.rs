@@ -0,0 +1,213 @@
+use super::{QueryConn, SiteScope};
+use crate::storage::schema::EVENT_COLUMNS;
+use serde_json::{Map, Value};
+
+/// Which raw events to read.
+#[derive(Debug, Clone, Copy)]
+pub struct EventFilter<'a> {
+    pub site_id: &'a str,
+    /// Inclusive lower bound, `YYYY-MM-DD`.
+    pub start_date: &'a str,
+    /// Exclusive upper bound, `YYYY-MM-DD`.
+    pub end_date: &'a str,
+    pub event_name: Option<&'a str>,
+    pub country_code: Option<&'a str>,
+}
+
+/// One page of raw events, oldest first.
+#[derive(Debug)]
+pub struct EventPage {
+    /// Each event as a JSON object keyed by column name.
+    pub events: Vec<Map<String, Value>>,
+    /// Cursor for the next page: pass it as `after`.  `None` on the last page.
+    pub next: Option<String>,
+}
+
+/// Read up to roughly `limit` events after the `after` cursor.
+///
+/// Pages end on a timestamp boundary: every event sharing the last timestamp
+/// is included, even past `limit`, so paging by timestamp neither skips nor
+/// repeats events that share one.
+pub fn query_events_page(
+    conn: &QueryConn<'_>,
+    filter: &EventFilter<'_>,
+    after: Option<&str>,
+    limit: usize,
+) -> Result<EventPage, duckdb::Error> {
+    let site = SiteScope::of(filter.site_id).filter("site_id");
+    let columns = EVENT_COLUMNS
+        .iter()
+        .map(|(name, ty)| match *ty {
+            "TIMESTAMP" => format!("CAST({name} AS VARCHAR) AS {name}"),
+            ty if ty.starts_with("DECIMAL") => format!("CAST({name} AS DOUBLE) AS {name}"),
+            _ => (*name).to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    let base = format!(
+        "SELECT {columns} FROM events_all
+         WHERE {site} AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
+           AND (CAST(? AS VARCHAR) IS NULL OR event_name = ?)
+           AND (CAST(? AS VARCHAR) IS NULL OR country_code = ?)"
+    );
+    // Only the files overlapping the range are read.
+    let base = super::prune_to_range(conn, &base, filter.start_date, filter.end_date)?;
+    let sql = format!(
+        "{base} AND (CAST(? AS VARCHAR) IS NULL OR timestamp > CAST(? AS TIMESTAMP))
+         ORDER BY timestamp LIMIT ?"
+    );
+    let mut stmt = super::prepare(conn, &sql, filter.site_id)?;
+    let limit_i64 = i64::try_from(limit).unwrap_or(i64::MAX);
+    let mut events = stmt
+        .query_map(
+            duckdb::params![
+                filter.site_id,
+                filter.start_date,
+                filter.end_date,
+                filter.event_name,
+                filter.event_name,
+                filter.country_code,
+                filter.country_code,
+                after,
+                after,
+                limit_i64
+            ],
+            event_from_row,
+        )?
+        .collect::<Result<Vec<_>, _>>()?;
+    if events.len() < limit {
+        return Ok(EventPage { events, next: None });
+    }
+
+    // The page may have cut a run of equal timestamps short: drop that run
+    // and read all of it.
+    let Some(last) = events
+        .last()
+        .and_then(|e| e.get("timestamp"))
+        .and_then(Value::as_str)
+        .map(str::to_string)
+    else {
+        return Ok(EventPage { events, next: None });
+    };
+    events.retain(|e| e.get("timestamp").and_then(Value::as_str) != Some(last.as_str()));
+    let sql = format!("{base} AND timestamp = CAST(? AS TIMESTAMP)");
+    let mut stmt = super::prepare(conn, &sql, filter.site_id)?;
+    let rows = stmt.query_map(
+        duckdb::params![
+            filter.site_id,
+            filter.start_date,
+            filter.end_date,
+            filter.event_name,
+            filter.event_name,
+            filter.country_code,
+            filter.country_code,
+            last
+        ],
+        event_from_row,
+    )?;
+    for event in rows {
+        events.push(event?);
+    }
+    Ok(EventPage {
+        events,
+        next: Some(last),
+    })
+}
+
+fn event_from_row(row: &duckdb::Row<'_>) -> Result<Map<String, Value>, duckdb::Error> {
+    let mut event = Map::new();
+    for (i, (name, ty)) in EVENT_COLUMNS.iter().enumerate() {
+        let value = if ty.starts_with("DECIMAL") {
+            row.get::<_, Option<f64>>(i)?
+                .map_or(Value::Null, Into::into)
+        } else {
+            row.get::<_, Option<String>>(i)?
+                .map_or(Value::Null, Into::into)
+        };
+        event.insert((*name).to_string(), value);
+    }
+    Ok(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use duckdb::Connection;
+
+    fn setup_test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::storage::schema::init_schema(&conn).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        crate::storage::schema::setup_query_view(&conn, dir.path()).unwrap();
+        drop(dir);
+        conn
+    }
+
+    fn insert_event(conn: &Connection, visitor_id: &str, timestamp: &str, event_name: &str) {
+        conn.execute(
+            "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname, country_code)
+             VALUES ('test.com', ?, ?, ?, '/', 'DE')",
+            duckdb::params![visitor_id, timestamp, event_name],
+        )
+        .unwrap();
+    }
+
+    const FILTER: EventFilter<'static> = EventFilter {
+        site_id: "test.com",
+        start_date: "2024-01-01",
+        end_date: "2024-02-01",
+        event_name: None,
+        country_code: None,
+    };
+
+    #[test]
+    fn test_pages_keep_equal_timestamps_together() {
+        let conn = setup_test_db();
+        insert_event(&conn, "v1", "2024-01-15 10:00:00", "pageview");
+        insert_event(&conn, "v2", "2024-01-15 10:00:01", "pageview");
+        insert_event(&conn, "v3", "2024-01-15 10:00:01", "signup");
+        insert_event(&conn, "v4", "2024-01-15 10:00:02", "pageview");
+
+        let first = query_events_page(&QueryConn::new(&conn), &FILTER, None, 2).unwrap();
+        assert_eq!(first.events.len(), 3);
+        assert_eq!(first.next.as_deref(), Some("2024-01-15 10:00:01"));
+
+        let second =
+            query_events_page(&QueryConn::new(&conn), &FILTER, first.next.as_deref(), 2).unwrap();
+        assert_eq!(second.events.len(), 1);
+        assert_eq!(second.events[0]["visitor_id"], "v4");
+        assert_eq!(second.next, None);
+    }
+
+    #[test]
+    fn test_filters_and_columns() {
+        let conn = setup_test_db();
+        insert_event(&conn, "v1", "2024-01-15 10:00:00", "pageview");
+        insert_event(&conn, "v2", "2024-01-15 11:00:00", "signup");
+        insert_event(&conn, "v3", "2024-03-01 10:00:00", "signup");
+
+        let filter = EventFilter {
+            event_name: Some("signup"),
+            country_code: Some("DE"),
+            ..FILTER
+        };
+        let page = query_events_page(&QueryConn::new(&conn), &filter, None, 100).unwrap();
+        assert_eq!(page.events.len(), 1);
+        let event = &page.events[0];
+        assert_eq!(event["visitor_id"], "v2");
+        assert_eq!(event["timestamp"], "2024-01-15 11:00:00");
+        assert_eq!(event["revenue_amount"], Value::Null);
+        assert_eq!(event.len(), EVENT_COLUMNS.len());
+
+        let filter = EventFilter {
+            country_code: Some("FR"),
+            ..FILTER
+        };
+        assert!(
+            query_events_page(&QueryConn::new(&conn), &filter, None, 100)
+                .unwrap()
+                .events
+                .is_empty()
+        );
+    }
+}
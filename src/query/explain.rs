@@ -0,0 +1,110 @@
+//! Query plans for `GET /api/admin/explain`.
+//!
+//! A report run on an [explained](super::QueryConn::explained) connection
+//! has every statement prepared through [`super::prepare`] and its variants
+//! explained with `EXPLAIN` (or `EXPLAIN ANALYZE`) just before it runs, with
+//! the same parameters bound, and the plan kept in the connection's
+//! [`Explain`].  The SQL kept is the statement as sent to DuckDB, after site
+//! scoping and Parquet file pruning, so it shows which files a query reads.
+
+use duckdb::{Connection, Params};
+use serde::Serialize;
+use std::cell::RefCell;
+
+/// One statement run by a report, with its plan.
+#[derive(Debug, Clone, Serialize)]
+pub struct ExplainedQuery {
+    pub sql: String,
+    /// `EXPLAIN` output, or with `analyze` the profiled plan including
+    /// timings and row counts.
+    pub plan: String,
+}
+
+/// Plans of the statements a report has run so far.
+#[derive(Debug, Default)]
+pub struct Explain {
+    /// Also execute each statement once more to profile it.
+    analyze: bool,
+    queries: RefCell<Vec<ExplainedQuery>>,
+}
+
+impl Explain {
+    /// Keep plans, profiled with `analyze`.
+    pub const fn new(analyze: bool) -> Self {
+        Self {
+            analyze,
+            queries: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Explain `sql` with `params` bound and keep the plan.
+    pub fn record<P: Params>(
+        &self,
+        conn: &Connection,
+        sql: &str,
+        params: P,
+    ) -> Result<(), duckdb::Error> {
+        let keyword = if self.analyze {
+            "EXPLAIN ANALYZE"
+        } else {
+            "EXPLAIN"
+        };
+        let mut stmt = conn.prepare(&format!("{keyword} {sql}"))?;
+        let plan = stmt
+            .query_map(params, |row| row.get::<_, String>(1))?
+            .collect::<Result<Vec<_>, _>>()?
+            .join("\n");
+        self.queries.borrow_mut().push(ExplainedQuery {
+            sql: sql.to_string(),
+            plan,
+        });
+        Ok(())
+    }
+
+    /// The plans kept so far, in the order their statements ran, leaving
+    /// none kept.
+    pub fn take(&self) -> Vec<ExplainedQuery> {
+        self.queries.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::QueryConn;
+
+    #[test]
+    fn test_explain_captures_statements() {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::storage::schema::init_schema(&conn).unwrap();
+        // A filter no row can match is planned as an empty result, without
+        // a scan.
+        conn.execute(
+            "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+             VALUES ('a.com', 'v1', current_timestamp, 'pageview', '/')",
+            [],
+        )
+        .unwrap();
+        let count = |conn: &QueryConn<'_>| {
+            let mut stmt = crate::query::prepare(
+                conn,
+                "SELECT COUNT(*) FROM events WHERE site_id = ?",
+                "a.com",
+            )
+            .unwrap();
+            stmt.query_row(duckdb::params!["a.com"], |row| row.get::<_, i64>(0))
+                .unwrap()
+        };
+        let explained = QueryConn::new(&conn).explained(false);
+        let counted = count(&explained);
+        let queries = explained.explained_queries();
+        drop(explained);
+        assert_eq!(counted, 1);
+        assert_eq!(queries.len(), 1);
+        assert_eq!(
+            queries[0].sql,
+            "SELECT COUNT(*) FROM events WHERE site_id = ?"
+        );
+        assert!(queries[0].plan.contains("events"), "{}", queries[0].plan);
+    }
+}
@@ -1,7 +1,7 @@
-use duckdb::Connection;
+use super::{QueryConn, SiteScope};
 
 /// A flow analysis result node showing the next page and visitor count.
-#[derive(Debug, Clone, serde::Serialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
 pub struct FlowNode {
     pub next_page: String,
     pub visitors: u64,
@@ -9,46 +9,88 @@ pub struct FlowNode {
 
 /// Query the most common next pages after visiting a given page.
 ///
-/// Uses `sequence_next_node` from the behavioral extension.
-/// The `target_page` is escaped to prevent SQL injection — single quotes are doubled.
-/// Requires the behavioral extension to be loaded.
+/// Client-side (SPA) navigations carry the page they came from in
+/// `prev_pathname`, so a visitor with such a navigation away from
+/// `target_page` is counted by its first one.  Other visitors are counted by
+/// event order: `sequence_next_node` from the behavioral extension when
+/// loaded, otherwise the page following their first view of `target_page` via
+/// `LEAD`.  The `target_page` is escaped to prevent SQL injection — single
+/// quotes are doubled.
 pub fn query_flow(
-    conn: &Connection,
+    conn: &QueryConn<'_>,
     site_id: &str,
     start_date: &str,
     end_date: &str,
     target_page: &str,
 ) -> Result<Vec<FlowNode>, duckdb::Error> {
+    let site = SiteScope::of(site_id).filter("site_id");
     // Escape single quotes in target_page to prevent SQL injection.
     // sequence_next_node's condition argument does not support parameterized queries,
     // so we must interpolate — but we sanitize first.
     let escaped_page = target_page.replace('\'', "''");
 
-    let sql = format!(
-        "SELECT next_page, COUNT(*) AS visitors
+    let build = |ordered: &str| {
+        format!(
+            "WITH explicit AS (
+                 SELECT visitor_id, arg_min(pathname, timestamp) AS next_page
+                 FROM events_all
+                 WHERE {site} AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
+                   AND event_name = 'pageview' AND prev_pathname = '{escaped_page}'
+                 GROUP BY visitor_id
+             )
+             SELECT next_page, COUNT(*) AS visitors
+             FROM (
+                 SELECT visitor_id, next_page FROM explicit
+                 UNION ALL
+                 SELECT visitor_id, next_page FROM ({ordered})
+                 WHERE visitor_id NOT IN (SELECT visitor_id FROM explicit)
+             )
+             WHERE next_page IS NOT NULL
+             GROUP BY next_page ORDER BY visitors DESC LIMIT 10"
+        )
+    };
+
+    let sql = build(&format!(
+        "SELECT visitor_id,
+             sequence_next_node('forward', 'first_match', timestamp, pathname,
+                 TRUE, pathname = '{escaped_page}'
+             ) AS next_page
+         FROM events_all
+         WHERE {site} AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
+         GROUP BY visitor_id"
+    ));
+
+    let fallback_sql = build(&format!(
+        "SELECT visitor_id, arg_min(next_page, timestamp) AS next_page
          FROM (
-             SELECT visitor_id,
-                 sequence_next_node('forward', 'first_match', timestamp, pathname,
-                     TRUE, pathname = '{escaped_page}'
-                 ) AS next_page
+             SELECT visitor_id, timestamp, pathname,
+                 LEAD(pathname) OVER (PARTITION BY visitor_id ORDER BY timestamp) AS next_page
              FROM events_all
-             WHERE site_id = ? AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
-             GROUP BY visitor_id
+             WHERE {site} AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
          )
-         WHERE next_page IS NOT NULL
-         GROUP BY next_page ORDER BY visitors DESC LIMIT 10"
-    );
+         WHERE pathname = '{escaped_page}'
+         GROUP BY visitor_id"
+    ));
 
-    let mut stmt = conn.prepare(&sql)?;
+    let mut stmt = super::sessions::prepare_with_fallback(
+        conn,
+        site_id,
+        start_date,
+        end_date,
+        &sql,
+        &fallback_sql,
+    )?;
     let rows = stmt
-        .query_map(duckdb::params![site_id, start_date, end_date], |row| {
-            Ok(FlowNode {
-                next_page: row.get(0)?,
-                visitors: row.get(1)?,
-            })
-        })?
-        .filter_map(Result::ok)
-        .collect();
+        .query_map(
+            duckdb::params![site_id, start_date, end_date, site_id, start_date, end_date],
+            |row| {
+                Ok(FlowNode {
+                    next_page: row.get(0)?,
+                    visitors: row.get(1)?,
+                })
+            },
+        )?
+        .collect::<Result<_, _>>()?;
 
     Ok(rows)
 }
@@ -56,6 +98,7 @@ pub fn query_flow(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use duckdb::Connection;
 
     fn setup_test_db() -> Connection {
         let conn = Connection::open_in_memory().unwrap();
@@ -69,11 +112,83 @@ mod tests {
     #[test]
     fn test_query_flow_no_extension() {
         let conn = setup_test_db();
-        // Without behavioral extension, this will fail gracefully
-        let result = query_flow(&conn, "test.com", "2024-01-01", "2024-02-01", "/pricing");
-        if let Ok(nodes) = result {
-            assert!(nodes.is_empty());
+        let nodes = query_flow(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            "/pricing",
+        )
+        .unwrap();
+        assert!(nodes.is_empty());
+    }
+
+    #[test]
+    fn test_query_flow_fallback_next_pages() {
+        let conn = setup_test_db();
+        for (vid, path, ts) in [
+            ("v1", "/pricing", "2024-01-15 10:00:00"),
+            ("v1", "/signup", "2024-01-15 10:01:00"),
+            ("v2", "/pricing", "2024-01-15 10:00:00"),
+            ("v2", "/signup", "2024-01-15 10:02:00"),
+            ("v3", "/pricing", "2024-01-15 10:00:00"),
+            ("v3", "/docs", "2024-01-15 10:03:00"),
+            ("v4", "/pricing", "2024-01-15 10:00:00"),
+        ] {
+            conn.execute(
+                "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+                 VALUES ('test.com', ?, ?, 'pageview', ?)",
+                duckdb::params![vid, ts, path],
+            )
+            .unwrap();
+        }
+
+        let nodes = query_flow(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            "/pricing",
+        )
+        .unwrap();
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0].next_page, "/signup");
+        assert_eq!(nodes[0].visitors, 2);
+        assert_eq!(nodes[1].next_page, "/docs");
+        assert_eq!(nodes[1].visitors, 1);
+    }
+
+    #[test]
+    fn test_query_flow_prefers_spa_transitions() {
+        let conn = setup_test_db();
+        for (vid, path, prev, ts) in [
+            // v1 browsed in two tabs; event order alone would pick /blog.
+            ("v1", "/pricing", None, "2024-01-15 10:00:00"),
+            ("v1", "/blog", None, "2024-01-15 10:00:30"),
+            ("v1", "/signup", Some("/pricing"), "2024-01-15 10:01:00"),
+            // v2 has no SPA navigations and falls back to event order.
+            ("v2", "/pricing", None, "2024-01-15 10:00:00"),
+            ("v2", "/signup", None, "2024-01-15 10:02:00"),
+        ] {
+            conn.execute(
+                "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname, prev_pathname)
+                 VALUES ('test.com', ?, ?, 'pageview', ?, ?)",
+                duckdb::params![vid, ts, path, prev],
+            )
+            .unwrap();
         }
+
+        let nodes = query_flow(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            "/pricing",
+        )
+        .unwrap();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].next_page, "/signup");
+        assert_eq!(nodes[0].visitors, 2);
     }
 
     #[test]
@@ -81,13 +196,12 @@ mod tests {
         let conn = setup_test_db();
         // This should not cause a SQL error from unbalanced quotes
         let result = query_flow(
-            &conn,
+            &QueryConn::new(&conn),
             "test.com",
             "2024-01-01",
             "2024-02-01",
             "/it's-a-page",
         );
-        // Will fail due to missing extension, but should not panic from injection
-        assert!(result.is_err() || result.unwrap().is_empty());
+        assert!(result.unwrap().is_empty());
     }
 }
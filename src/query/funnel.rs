@@ -1,63 +1,321 @@
-use duckdb::Connection;
+use crate::query::breakdowns::Dimension;
+use crate::query::{Identity, QueryConn, SiteScope};
+use std::collections::BTreeMap;
+use std::fmt::Write;
 
 /// Funnel step result showing how many visitors reached each step.
-#[derive(Debug, Clone, serde::Serialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
 pub struct FunnelStep {
     pub step: u32,
+    /// Visitors who reached at least this step within the window.
     pub visitors: u64,
+    /// Percentage of the previous step's visitors who did not reach this step.
+    /// Always 0 for the first step.
+    pub drop_off_pct: f64,
+    /// Median seconds between reaching the previous step and this one.
+    /// `None` for the first step, or when nobody reached this step.
+    pub median_secs_from_previous: Option<f64>,
+    /// Visitors reaching this step grouped by their first-touch value of the
+    /// requested breakdown dimension. Omitted when no breakdown was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub breakdown: Option<Vec<FunnelBreakdownRow>>,
 }
 
-/// Build and execute a funnel query using `window_funnel` from the behavioral extension.
+/// One value of a funnel step breakdown.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct FunnelBreakdownRow {
+    pub value: String,
+    pub visitors: u64,
+}
+
+/// Build and execute a funnel query using `window_funnel` from the behavioral extension,
+/// or the step-chain SQL fallback when the extension is not loaded.
 ///
 /// `steps` defines the funnel conditions as SQL boolean expressions.
 /// `window_interval` is the maximum time between first and last step (e.g., "1 day").
+/// When `breakdown` is set, each step also reports its visitors grouped by the
+/// first value of that dimension the visitor was seen with in the range.
+/// `identity` picks whether visitors or logged-in users move through the steps.
+#[allow(clippy::too_many_arguments)]
 pub fn query_funnel(
-    conn: &Connection,
+    conn: &QueryConn<'_>,
     site_id: &str,
     start_date: &str,
     end_date: &str,
     window_interval: &str,
     steps: &[&str],
+    breakdown: Option<Dimension>,
+    identity: Identity,
 ) -> Result<Vec<FunnelStep>, duckdb::Error> {
     if steps.is_empty() {
         return Ok(Vec::new());
     }
 
+    let site = SiteScope::of(site_id).filter("site_id");
     let step_conditions = steps.join(", ");
+    // Column names come from the fixed Dimension enum, never from user input.
+    let dim_expr = breakdown.map_or_else(
+        || "NULL".to_string(),
+        |d| format!("arg_min({}, timestamp)", d.column_name()),
+    );
 
     // Note: step conditions are SQL expressions defined by the application,
     // not user input. User-provided values (site_id, dates) are parameterized.
+    // window_funnel yields the deepest step each visitor reached; visitors who
+    // matched no step (level 0) are excluded.
     let sql = format!(
-        "SELECT steps, COUNT(*) AS visitors
+        "SELECT steps, COALESCE(dim_value, '(unknown)'), COUNT(*) AS visitors
          FROM (
              SELECT visitor_id,
                  window_funnel(INTERVAL '{window_interval}', timestamp,
                      {step_conditions}
-                 ) AS steps
+                 ) AS steps,
+                 {dim_expr} AS dim_value
              FROM events_all
-             WHERE site_id = ? AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
+             WHERE {site} AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
              GROUP BY visitor_id
          )
-         GROUP BY steps ORDER BY steps"
+         WHERE steps > 0
+         GROUP BY steps, dim_value ORDER BY steps"
     );
 
-    let mut stmt = conn.prepare(&sql)?;
-    let rows = stmt
+    let fallback_sql =
+        build_fallback_levels_sql(SiteScope::of(site_id), steps, window_interval, breakdown);
+    let mut stmt = super::sessions::prepare_with_fallback(
+        conn,
+        site_id,
+        start_date,
+        end_date,
+        &super::with_identity(&sql, identity),
+        &super::with_identity(&fallback_sql, identity),
+    )?;
+    let levels: Vec<(u32, String, u64)> = stmt
         .query_map(duckdb::params![site_id, start_date, end_date], |row| {
-            Ok(FunnelStep {
-                step: row.get(0)?,
-                visitors: row.get(1)?,
-            })
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
         })?
-        .filter_map(Result::ok)
+        .collect::<Result<_, _>>()?;
+
+    let medians = query_step_medians(
+        conn,
+        site_id,
+        start_date,
+        end_date,
+        window_interval,
+        steps,
+        identity,
+    )?;
+
+    Ok(build_steps(
+        &levels,
+        &medians,
+        steps.len(),
+        breakdown.is_some(),
+    ))
+}
+
+/// Turn per-visitor deepest-level counts into cumulative per-step results.
+///
+/// A visitor whose deepest level is `k` counts towards steps `1..=k`.
+fn build_steps(
+    levels: &[(u32, String, u64)],
+    medians: &[Option<f64>],
+    num_steps: usize,
+    with_breakdown: bool,
+) -> Vec<FunnelStep> {
+    let mut reached = vec![0u64; num_steps];
+    let mut by_value: Vec<BTreeMap<&str, u64>> = vec![BTreeMap::new(); num_steps];
+    for (level, value, count) in levels {
+        let depth = usize::try_from(*level).map_or(num_steps, |l| l.min(num_steps));
+        for (total, values) in reached.iter_mut().zip(by_value.iter_mut()).take(depth) {
+            *total += count;
+            if with_breakdown {
+                *values.entry(value.as_str()).or_default() += count;
+            }
+        }
+    }
+
+    let mut out = Vec::with_capacity(num_steps);
+    for (k, visitors) in reached.iter().copied().enumerate() {
+        let drop_off_pct = match k.checked_sub(1).map(|prev| reached[prev]) {
+            Some(prev) if prev > 0 => {
+                #[allow(clippy::cast_precision_loss)]
+                let pct = (prev - visitors) as f64 / prev as f64 * 100.0;
+                (pct * 100.0).round() / 100.0
+            }
+            _ => 0.0,
+        };
+        let breakdown = with_breakdown.then(|| {
+            let mut rows: Vec<FunnelBreakdownRow> = by_value[k]
+                .iter()
+                .map(|(value, visitors)| FunnelBreakdownRow {
+                    value: (*value).to_string(),
+                    visitors: *visitors,
+                })
+                .collect();
+            rows.sort_by(|a, b| {
+                b.visitors
+                    .cmp(&a.visitors)
+                    .then_with(|| a.value.cmp(&b.value))
+            });
+            rows
+        });
+        out.push(FunnelStep {
+            step: u32::try_from(k + 1).unwrap_or(u32::MAX),
+            visitors,
+            drop_off_pct,
+            median_secs_from_previous: medians.get(k).copied().flatten(),
+            breakdown,
+        });
+    }
+    out
+}
+
+/// Median seconds between consecutive funnel steps, indexed like `steps`.
+///
+/// Each visitor's step `k` time is the first matching event at or after their
+/// step `k - 1` time and within `window_interval` of their first step.  Plain
+/// SQL, so this works without the behavioral extension.  Index 0 is always `None`.
+pub fn query_step_medians(
+    conn: &QueryConn<'_>,
+    site_id: &str,
+    start_date: &str,
+    end_date: &str,
+    window_interval: &str,
+    steps: &[&str],
+    identity: Identity,
+) -> Result<Vec<Option<f64>>, duckdb::Error> {
+    let mut medians = vec![None; steps.len()];
+    if steps.len() < 2 {
+        return Ok(medians);
+    }
+
+    let ctes = step_chain_ctes(SiteScope::of(site_id), steps, Some(window_interval), "NULL");
+    let selects: Vec<String> = (2..=steps.len())
+        .map(|k| {
+            format!(
+                "SELECT {k} AS step, CAST(MEDIAN(date_diff('second', prev_t, t)) AS DOUBLE) FROM t{k}"
+            )
+        })
+        .collect();
+    let sql = format!(
+        "WITH {} {} ORDER BY step",
+        ctes.join(", "),
+        selects.join(" UNION ALL ")
+    );
+
+    let sql = super::with_identity(&sql, identity);
+    let mut stmt = super::prepare_in_range(conn, &sql, site_id, start_date, end_date)?;
+    let rows = stmt.query_map(duckdb::params![site_id, start_date, end_date], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, Option<f64>>(1)?))
+    })?;
+    for row in rows {
+        let (step, median) = row?;
+        if let Some(slot) = usize::try_from(step - 1)
+            .ok()
+            .and_then(|i| medians.get_mut(i))
+        {
+            *slot = median;
+        }
+    }
+    Ok(medians)
+}
+
+/// CTEs that walk each visitor through `steps` in order.
+///
+/// `base` holds the site's events in range with one boolean flag per step
+/// (`s1..sN`) plus `dim_source`.  `t1` is each visitor's first step-1 event;
+/// `tK` is their first step-K event at or after their step `K - 1` time and,
+/// when `window_interval` is set, within that interval of their step-1 time.
+/// `tK` carries `t`, `prev_t` and the step-1 time `t0`.  `base` reads the
+/// sites in `scope`.  Binds site_id, start and end dates.
+pub fn step_chain_ctes(
+    scope: SiteScope,
+    steps: &[&str],
+    window_interval: Option<&str>,
+    dim_source: &str,
+) -> Vec<String> {
+    let flags: Vec<String> = steps
+        .iter()
+        .enumerate()
+        .map(|(i, cond)| format!("({cond}) AS s{}", i + 1))
         .collect();
+    let window_cond = window_interval
+        .map(|w| format!("AND b.timestamp <= p.t0 + INTERVAL '{w}'"))
+        .unwrap_or_default();
+    let site = scope.filter("site_id");
+    let mut ctes = vec![
+        format!(
+            "base AS (SELECT visitor_id, timestamp, {dim_source} AS dim_source, {}
+              FROM events_all
+              WHERE {site} AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP))",
+            flags.join(", ")
+        ),
+        "t1 AS (SELECT visitor_id, MIN(timestamp) AS t, MIN(timestamp) AS t0
+              FROM base WHERE s1 GROUP BY visitor_id)"
+            .to_string(),
+    ];
+    for k in 2..=steps.len() {
+        let p = k - 1;
+        ctes.push(format!(
+            "t{k} AS (SELECT b.visitor_id, MIN(b.timestamp) AS t, p.t AS prev_t, p.t0
+              FROM base b JOIN t{p} p ON b.visitor_id = p.visitor_id
+              WHERE b.s{k} AND b.timestamp >= p.t {window_cond}
+              GROUP BY b.visitor_id, p.t, p.t0)"
+        ));
+    }
+    ctes
+}
 
-    Ok(rows)
+/// Pure-SQL stand-in for the `window_funnel` level query, returning the same
+/// `(steps, dim_value, visitors)` rows.
+///
+/// A visitor's level is the deepest step in their [`step_chain_ctes`] chain.
+/// The chain anchors on the first step-1 event, so a visitor who only
+/// completes the funnel from a later start counts at a shallower level than
+/// `window_funnel` would report.
+fn build_fallback_levels_sql(
+    scope: SiteScope,
+    steps: &[&str],
+    window_interval: &str,
+    breakdown: Option<Dimension>,
+) -> String {
+    let dim_source = breakdown.map_or_else(
+        || "CAST(NULL AS VARCHAR)".to_string(),
+        |d| d.column_name().to_string(),
+    );
+    let ctes = step_chain_ctes(scope, steps, Some(window_interval), &dim_source);
+    let mut joins = String::new();
+    let mut level_cases = String::new();
+    for k in 2..=steps.len() {
+        let _ = write!(joins, " LEFT JOIN t{k} ON t{k}.visitor_id = t1.visitor_id");
+    }
+    for k in (2..=steps.len()).rev() {
+        let _ = write!(level_cases, "WHEN t{k}.visitor_id IS NOT NULL THEN {k} ");
+    }
+    let level_expr = if level_cases.is_empty() {
+        "1".to_string()
+    } else {
+        format!("CASE {level_cases}ELSE 1 END")
+    };
+    format!(
+        "WITH {},
+         dims AS (SELECT visitor_id, arg_min(dim_source, timestamp) AS dim_value
+                  FROM base GROUP BY visitor_id)
+         SELECT steps, COALESCE(dim_value, '(unknown)'), COUNT(*) AS visitors
+         FROM (
+             SELECT t1.visitor_id, {level_expr} AS steps, dims.dim_value
+             FROM t1{joins}
+             LEFT JOIN dims ON dims.visitor_id = t1.visitor_id
+         )
+         GROUP BY steps, dim_value ORDER BY steps",
+        ctes.join(", ")
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use duckdb::Connection;
 
     fn setup_test_db() -> Connection {
         let conn = Connection::open_in_memory().unwrap();
@@ -71,8 +329,170 @@ mod tests {
     #[test]
     fn test_funnel_empty_steps() {
         let conn = setup_test_db();
-        let result =
-            query_funnel(&conn, "test.com", "2024-01-01", "2024-02-01", "1 day", &[]).unwrap();
+        let result = query_funnel(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            "1 day",
+            &[],
+            None,
+            Identity::Visitor,
+        )
+        .unwrap();
         assert!(result.is_empty());
     }
+
+    #[test]
+    fn test_build_steps_cumulative_and_drop_off() {
+        // 6 visitors stopped at step 1, 3 at step 2, 1 completed all 3 steps.
+        let levels = vec![
+            (1, "Google".to_string(), 6),
+            (2, "Google".to_string(), 2),
+            (2, "Bing".to_string(), 1),
+            (3, "Bing".to_string(), 1),
+        ];
+        let steps = build_steps(&levels, &[None, Some(30.0), None], 3, true);
+
+        assert_eq!(steps.len(), 3);
+        assert_eq!(steps[0].visitors, 10);
+        assert_eq!(steps[1].visitors, 4);
+        assert_eq!(steps[2].visitors, 1);
+        assert!(steps[0].drop_off_pct.abs() < f64::EPSILON);
+        assert!((steps[1].drop_off_pct - 60.0).abs() < f64::EPSILON);
+        assert!((steps[2].drop_off_pct - 75.0).abs() < f64::EPSILON);
+        assert_eq!(steps[1].median_secs_from_previous, Some(30.0));
+
+        let last = steps[2].breakdown.as_ref().unwrap();
+        assert_eq!(last.len(), 1);
+        assert_eq!(last[0].value, "Bing");
+        // Bing and Google both reached step 2 twice; ties sort by value.
+        let second = steps[1].breakdown.as_ref().unwrap();
+        assert_eq!(second[0].value, "Bing");
+        assert_eq!(second[0].visitors, 2);
+        assert_eq!(second[1].value, "Google");
+        assert_eq!(second[1].visitors, 2);
+    }
+
+    #[test]
+    fn test_build_steps_without_breakdown() {
+        let steps = build_steps(&[(2, "(unknown)".to_string(), 5)], &[], 2, false);
+        assert!(steps.iter().all(|s| s.breakdown.is_none()));
+        assert_eq!(steps[1].visitors, 5);
+        assert!(steps[1].median_secs_from_previous.is_none());
+    }
+
+    #[test]
+    fn test_step_medians() {
+        let conn = setup_test_db();
+        // v1: / at 10:00, /pricing at 10:01 (60s). v2: / at 10:00, /pricing at 10:03 (180s).
+        // v3: / then /pricing two days later — outside the 1 day window.
+        for (vid, path, ts) in [
+            ("v1", "/", "2024-01-15 10:00:00"),
+            ("v1", "/pricing", "2024-01-15 10:01:00"),
+            ("v2", "/", "2024-01-15 10:00:00"),
+            ("v2", "/pricing", "2024-01-15 10:03:00"),
+            ("v2", "/pricing", "2024-01-15 10:05:00"),
+            ("v3", "/", "2024-01-15 10:00:00"),
+            ("v3", "/pricing", "2024-01-17 10:00:00"),
+        ] {
+            conn.execute(
+                "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+                 VALUES ('test.com', ?, ?, 'pageview', ?)",
+                duckdb::params![vid, ts, path],
+            )
+            .unwrap();
+        }
+
+        let medians = query_step_medians(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            "1 day",
+            &["pathname = '/'", "pathname = '/pricing'"],
+            Identity::Visitor,
+        )
+        .unwrap();
+
+        assert_eq!(medians.len(), 2);
+        assert!(medians[0].is_none());
+        assert_eq!(medians[1], Some(120.0));
+    }
+
+    #[test]
+    fn test_funnel_without_extension() {
+        let conn = setup_test_db();
+        // v1 completes both steps, v2 only the first, v3 neither.
+        for (vid, path, ts) in [
+            ("v1", "/", "2024-01-15 10:00:00"),
+            ("v1", "/pricing", "2024-01-15 10:02:00"),
+            ("v2", "/", "2024-01-15 11:00:00"),
+            ("v3", "/about", "2024-01-15 12:00:00"),
+        ] {
+            conn.execute(
+                "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname, device_type)
+                 VALUES ('test.com', ?, ?, 'pageview', ?, 'desktop')",
+                duckdb::params![vid, ts, path],
+            )
+            .unwrap();
+        }
+
+        let steps = query_funnel(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            "1 day",
+            &["pathname = '/'", "pathname = '/pricing'"],
+            Some(Dimension::DeviceType),
+            Identity::Visitor,
+        )
+        .unwrap();
+
+        assert_eq!(steps.len(), 2);
+        assert_eq!(steps[0].visitors, 2);
+        assert_eq!(steps[1].visitors, 1);
+        assert!((steps[1].drop_off_pct - 50.0).abs() < f64::EPSILON);
+        assert_eq!(steps[1].median_secs_from_previous, Some(120.0));
+        let breakdown = steps[0].breakdown.as_ref().unwrap();
+        assert_eq!(breakdown[0].value, "desktop");
+        assert_eq!(breakdown[0].visitors, 2);
+    }
+
+    #[test]
+    fn test_funnel_by_user_spans_devices() {
+        let conn = setup_test_db();
+        // u1 lands on / on one device and reaches /pricing on another.
+        for (vid, path, ts) in [
+            ("v1", "/", "2024-01-15 10:00:00"),
+            ("v2", "/pricing", "2024-01-15 10:05:00"),
+        ] {
+            conn.execute(
+                "INSERT INTO events (site_id, visitor_id, user_id, timestamp, event_name, pathname)
+                 VALUES ('test.com', ?, 'u1', ?, 'pageview', ?)",
+                duckdb::params![vid, ts, path],
+            )
+            .unwrap();
+        }
+
+        let query = |identity| {
+            query_funnel(
+                &QueryConn::new(&conn),
+                "test.com",
+                "2024-01-01",
+                "2024-02-01",
+                "1 day",
+                &["pathname = '/'", "pathname = '/pricing'"],
+                None,
+                identity,
+            )
+            .unwrap()
+        };
+        assert_eq!(query(Identity::Visitor)[1].visitors, 0);
+        let steps = query(Identity::User);
+        assert_eq!(steps[0].visitors, 1);
+        assert_eq!(steps[1].visitors, 1);
+        assert_eq!(steps[1].median_secs_from_previous, Some(300.0));
+    }
 }
@@ -0,0 +1,403 @@
+//! Materialized sessions.
+//!
+//! Session metrics and the bounce rate sessionize every event in their range
+//! on each query.  With `materialize_sessions` enabled, a background job
+//! instead writes each site's sessions of each day, as flushed to Parquet,
+//! to `data_dir/sessions/site_id=<site>/date=<date>/sessions.parquet`: one
+//! row per session with its visitor, start, end, duration, pageviews,
+//! events, and entry and exit pages.  Every run rebuilds only the days whose
+//! flushed events changed since their file was written, found by comparing
+//! the event count recorded in `session_partitions` with the Parquet file
+//! registry, and removes files whose events are gone.
+//!
+//! A query over whole days reads the files of the leading days that are up
+//! to date and have no buffered events, and sessionizes only the rest of the
+//! range from events, usually just today.  Sessions are split at midnight
+//! UTC, as they already are at the start of a queried range.
+
+use crate::storage::schema;
+use duckdb::Connection;
+use std::fs;
+use std::path::Path;
+
+/// Days the background job materializes per hold of the connection, so a
+/// backfill does not hold up flushes and queries.
+pub const DAYS_PER_BATCH: usize = 8;
+
+/// Files and flushed event counts of each site and day in the Parquet file
+/// registry.  A day with a file of unknown row count has no count.
+const PARTITIONS_CTE: &str = r"
+    partition_files AS (
+        SELECT
+            path,
+            row_count,
+            regexp_extract(path, 'site_id=([^/\\]+)', 1) AS site_id,
+            regexp_extract(path, 'date=([0-9]{4}-[0-9]{2}-[0-9]{2})', 1) AS date
+        FROM parquet_files
+    ),
+    partitions AS (
+        SELECT
+            site_id,
+            date,
+            CASE WHEN COUNT(row_count) = COUNT(*) THEN CAST(SUM(row_count) AS BIGINT) END
+                AS event_rows
+        FROM partition_files
+        WHERE site_id <> '' AND date <> ''
+        GROUP BY site_id, date
+    )";
+
+/// Materialized sessions usable for a query, from [`stored_sessions`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredSessions {
+    /// Start of the part of the range to sessionize from events; the end of
+    /// the range when every day is materialized.
+    pub live_start: String,
+    /// Relation reading the sessions of the range before `live_start`.
+    pub source: String,
+}
+
+/// Rebuild the session files of up to `max_partitions` days whose flushed
+/// events changed since they were written, newest first, under `dir`.
+///
+/// Returns the number of days rebuilt.  A day whose file cannot be written
+/// is logged and left out of date.
+pub fn materialize(
+    conn: &Connection,
+    dir: &Path,
+    max_partitions: usize,
+) -> Result<usize, duckdb::Error> {
+    let mut stmt = conn.prepare(&format!(
+        "WITH {PARTITIONS_CTE}
+         SELECT p.site_id, p.date, p.event_rows
+         FROM partitions p
+         LEFT JOIN session_partitions s ON s.site_id = p.site_id AND s.date = p.date
+         WHERE p.event_rows IS NOT NULL AND s.event_rows IS DISTINCT FROM p.event_rows
+         ORDER BY p.date DESC, p.site_id
+         LIMIT ?"
+    ))?;
+    let limit = i64::try_from(max_partitions).unwrap_or(i64::MAX);
+    let stale: Vec<(String, String, i64)> = stmt
+        .query_map([limit], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<Result<_, _>>()?;
+
+    let mut built = 0;
+    for (site_id, date, event_rows) in stale {
+        let partition_dir = dir
+            .join(format!("site_id={site_id}"))
+            .join(format!("date={date}"));
+        if let Err(e) = fs::create_dir_all(&partition_dir) {
+            tracing::warn!(
+                path = %partition_dir.display(),
+                error = %e,
+                "Failed to create sessions directory"
+            );
+            continue;
+        }
+        let path = partition_dir.join("sessions.parquet");
+        let tmp = partition_dir.join("sessions.parquet.tmp");
+        write_sessions(conn, &site_id, &date, &tmp)?;
+        if let Err(e) = fs::rename(&tmp, &path) {
+            tracing::warn!(path = %path.display(), error = %e, "Failed to write sessions file");
+            continue;
+        }
+        conn.execute(
+            "INSERT OR REPLACE INTO session_partitions (site_id, date, event_rows, path, built_at)
+             VALUES (?, ?, ?, ?, current_timestamp)",
+            duckdb::params![site_id, date, event_rows, path.to_string_lossy()],
+        )?;
+        built += 1;
+    }
+    Ok(built)
+}
+
+/// Write the sessions of `site_id` on `date` from its Parquet files to
+/// `path`.  Buffered events of the day are included too; the day is not read
+/// from its file while they are buffered, and rebuilt once they are flushed.
+///
+/// COPY takes no parameters, so the sessions are collected into a temporary
+/// table by a parameterized query first, and the table is copied.
+fn write_sessions(
+    conn: &Connection,
+    site_id: &str,
+    date: &str,
+    path: &Path,
+) -> Result<(), duckdb::Error> {
+    let next_day = chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|e| duckdb::Error::ToSqlConversionFailure(Box::new(e)))?
+        + chrono::Days::new(1);
+    let mut stmt = conn.prepare(&format!(
+        "WITH {PARTITIONS_CTE}
+         SELECT path FROM partition_files WHERE site_id = ? AND date = ? ORDER BY path"
+    ))?;
+    let paths: Vec<String> = stmt
+        .query_map([site_id, date], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+    let source = schema::events_in(conn, &paths)?;
+
+    conn.execute(
+        &format!(
+            "CREATE OR REPLACE TEMP TABLE day_sessions AS
+             WITH events_all AS NOT MATERIALIZED {source}, {}
+             SELECT
+                 visitor_id,
+                 MIN(timestamp) AS session_start,
+                 MAX(timestamp) AS session_end,
+                 EXTRACT(EPOCH FROM (MAX(timestamp) - MIN(timestamp))) AS duration_secs,
+                 COUNT(*) FILTER (WHERE event_name = 'pageview') AS pageviews,
+                 COUNT(*) AS events,
+                 arg_min(pathname, timestamp) FILTER (WHERE event_name = 'pageview') AS entry_page,
+                 arg_max(pathname, timestamp) FILTER (WHERE event_name = 'pageview') AS exit_page
+             FROM sessions
+             GROUP BY visitor_id, session_id
+             ORDER BY session_start, visitor_id",
+            super::sessions::sessions_cte_sql(super::SiteScope::Site)
+        ),
+        duckdb::params![site_id, date, next_day.to_string()],
+    )?;
+    let copied = conn.execute_batch(&format!(
+        "COPY day_sessions TO '{}' (FORMAT PARQUET)",
+        path.to_string_lossy().replace('\'', "''")
+    ));
+    conn.execute_batch("DROP TABLE day_sessions")?;
+    copied
+}
+
+/// Remove the session files of days without flushed events any more, after
+/// retention or erasure.  Returns the number of days removed.
+pub fn prune(conn: &Connection) -> Result<usize, duckdb::Error> {
+    let mut stmt = conn.prepare(&format!(
+        "WITH {PARTITIONS_CTE}
+         SELECT s.site_id, s.date, s.path
+         FROM session_partitions s
+         WHERE NOT EXISTS (
+             SELECT 1 FROM partitions p WHERE p.site_id = s.site_id AND p.date = s.date
+         )"
+    ))?;
+    let gone: Vec<(String, String, String)> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<Result<_, _>>()?;
+    for (site_id, date, path) in &gone {
+        let path = Path::new(path);
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                tracing::warn!(
+                    path = %path.display(),
+                    error = %e,
+                    "Failed to remove sessions file"
+                );
+                continue;
+            }
+            _ => {}
+        }
+        if let Some(parent) = path.parent() {
+            let _ = fs::remove_dir(parent);
+        }
+        conn.execute(
+            "DELETE FROM session_partitions WHERE site_id = ? AND date = ?",
+            [site_id, date],
+        )?;
+    }
+    Ok(gone.len())
+}
+
+/// Materialized sessions covering the start of `[start, end)` for `site_id`,
+/// if any.
+///
+/// These are the files of the leading days that are up to date, up to the
+/// first day that is not or has buffered events.  `None` for ranges that are
+/// not whole days, and for all sites or a group.
+pub fn stored_sessions(
+    conn: &Connection,
+    site_id: &str,
+    start: &str,
+    end: &str,
+) -> Result<Option<StoredSessions>, duckdb::Error> {
+    let is_date = |s: &str| chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d").is_ok();
+    if super::SiteScope::of(site_id) != super::SiteScope::Site || !is_date(start) || !is_date(end) {
+        return Ok(None);
+    }
+
+    let buffered: Option<String> = conn.query_row(
+        "SELECT MIN(STRFTIME(CAST(timestamp AS DATE), '%Y-%m-%d')) FROM events
+         WHERE site_id = ? AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)",
+        [site_id, start, end],
+        |row| row.get(0),
+    )?;
+    let mut live_start = buffered.unwrap_or_else(|| end.to_string());
+
+    let mut stmt = conn.prepare(&format!(
+        "WITH {PARTITIONS_CTE}
+         SELECT p.date, COALESCE(p.event_rows = s.event_rows, false), s.path
+         FROM partitions p
+         LEFT JOIN session_partitions s ON s.site_id = p.site_id AND s.date = p.date
+         WHERE p.site_id = ? AND p.date >= ? AND p.date < ?
+         ORDER BY p.date"
+    ))?;
+    let days: Vec<(String, bool, Option<String>)> = stmt
+        .query_map([site_id, start, end], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?
+        .collect::<Result<_, _>>()?;
+    let mut files = Vec::new();
+    for (date, current, path) in days {
+        if date >= live_start {
+            break;
+        }
+        let Some(path) = path.filter(|path| current && Path::new(path).exists()) else {
+            live_start = date;
+            break;
+        };
+        files.push(path);
+    }
+    if files.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(StoredSessions {
+        live_start,
+        source: format!(
+            "read_parquet({})",
+            schema::sql_string_list(files.iter().map(String::as_str))
+        ),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::sessions::{query_session_metrics, session_sources, SessionSql};
+    use crate::query::QueryConn;
+    use crate::storage::parquet::ParquetStorage;
+
+    fn insert_event(conn: &Connection, visitor_id: &str, timestamp: &str, pathname: &str) {
+        conn.execute(
+            "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+             VALUES ('test.com', ?, ?, 'pageview', ?)",
+            duckdb::params![visitor_id, timestamp, pathname],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_write_sessions_rejects_invalid_date() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = Connection::open_in_memory().unwrap();
+        schema::init_schema(&conn).unwrap();
+        let path = dir.path().join("sessions.parquet");
+        assert!(write_sessions(&conn, "test.com", "2024-13-45", &path).is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_materialize_and_read_sessions() {
+        let dir = tempfile::tempdir().unwrap();
+        let events_dir = dir.path().join("events");
+        let sessions_dir = dir.path().join("sessions");
+        let conn = Connection::open_in_memory().unwrap();
+        schema::init_schema(&conn).unwrap();
+        schema::setup_query_view(&conn, &events_dir).unwrap();
+        let storage = ParquetStorage::new(&events_dir);
+
+        // v1: two pages in one session on the 15th; v2 bounces on the 16th.
+        insert_event(&conn, "v1", "2024-01-15 10:00:00", "/");
+        insert_event(&conn, "v1", "2024-01-15 10:02:00", "/pricing");
+        insert_event(&conn, "v2", "2024-01-16 09:00:00", "/blog");
+        storage.flush_events(&conn).unwrap();
+
+        assert!(
+            stored_sessions(&conn, "test.com", "2024-01-15", "2024-01-17")
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(materialize(&conn, &sessions_dir, 1).unwrap(), 1);
+        assert_eq!(materialize(&conn, &sessions_dir, 10).unwrap(), 1);
+        assert_eq!(materialize(&conn, &sessions_dir, 10).unwrap(), 0);
+
+        let file = sessions_dir.join("site_id=test.com/date=2024-01-15/sessions.parquet");
+        let (duration, pageviews, entry, exit): (f64, i64, String, String) = conn
+            .query_row(
+                &format!(
+                    "SELECT duration_secs, pageviews, entry_page, exit_page
+                     FROM read_parquet('{}')",
+                    file.display()
+                ),
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert!((duration - 120.0).abs() < f64::EPSILON);
+        assert_eq!(pageviews, 2);
+        assert_eq!((entry.as_str(), exit.as_str()), ("/", "/pricing"));
+
+        let stored = stored_sessions(&conn, "test.com", "2024-01-15", "2024-01-17")
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.live_start, "2024-01-17");
+        assert!(
+            stored_sessions(&conn, crate::query::ALL_SITES, "2024-01-15", "2024-01-17")
+                .unwrap()
+                .is_none()
+        );
+
+        // A buffered event on the 16th leaves that day to be sessionized.
+        insert_event(&conn, "v3", "2024-01-16 12:00:00", "/");
+        let stored = stored_sessions(&conn, "test.com", "2024-01-15", "2024-01-17")
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.live_start, "2024-01-16");
+
+        // Once flushed, the 16th is out of date until rebuilt.
+        storage.flush_events(&conn).unwrap();
+        let stored = stored_sessions(&conn, "test.com", "2024-01-15", "2024-01-17")
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.live_start, "2024-01-16");
+        assert_eq!(materialize(&conn, &sessions_dir, 10).unwrap(), 1);
+        let stored = stored_sessions(&conn, "test.com", "2024-01-15", "2024-01-17")
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.live_start, "2024-01-17");
+
+        // Read from the files, metrics match sessionizing the events.
+        let metrics = query_session_metrics(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-15",
+            "2024-01-17",
+            SessionSql::Primary,
+        )
+        .unwrap();
+        assert_eq!(metrics.total_sessions, 3);
+        assert!((metrics.avg_session_duration_secs - 40.0).abs() < f64::EPSILON);
+        let bounce_rate = crate::query::metrics::query_bounce_rate(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-15",
+            "2024-01-17",
+            SessionSql::Primary,
+        )
+        .unwrap();
+        assert!((bounce_rate - 2.0 / 3.0).abs() < 1e-6);
+
+        // A shadow run sessionizes every day from the events instead.
+        let (live_start, stored) = session_sources(
+            &conn,
+            "test.com",
+            "2024-01-15",
+            "2024-01-17",
+            SessionSql::Shadow,
+        )
+        .unwrap();
+        assert_eq!((live_start.as_str(), stored), ("2024-01-15", None));
+
+        // Days whose events are gone lose their sessions.
+        conn.execute(
+            "DELETE FROM parquet_files WHERE path LIKE '%date=2024-01-15%'",
+            [],
+        )
+        .unwrap();
+        assert_eq!(prune(&conn).unwrap(), 1);
+        assert!(!file.exists());
+        assert_eq!(prune(&conn).unwrap(), 0);
+    }
+}
@@ -1,7 +1,12 @@
-use duckdb::Connection;
+use super::sessions::SessionSql;
+use super::{QueryConn, SiteScope};
+use crate::storage::schema;
+use crate::storage::summary::{self, VisitorSketch};
+use std::collections::BTreeMap;
+use std::path::Path;
 
 /// Core metric results for a given time range.
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
 pub struct CoreMetrics {
     pub unique_visitors: u64,
     pub total_pageviews: u64,
@@ -10,18 +15,136 @@ pub struct CoreMetrics {
     pub pages_per_visit: f64,
 }
 
-/// Query core metrics for a site within a date range.
+/// Traffic totals for one site, as listed by [`query_site_summaries`].
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct SiteSummary {
+    pub site_id: String,
+    pub visitors: u64,
+    pub pageviews: u64,
+    /// Events of every kind except heartbeats.
+    pub events: u64,
+}
+
+/// Per-site visitors, pageviews and total events for every site with traffic
+/// in the date range, busiest first.
+pub fn query_site_summaries(
+    conn: &QueryConn<'_>,
+    start_date: &str,
+    end_date: &str,
+) -> Result<Vec<SiteSummary>, duckdb::Error> {
+    let mut stmt = super::prepare_in_range(
+        conn,
+        "SELECT site_id,
+                COUNT(DISTINCT visitor_id) AS visitors,
+                COUNT(*) FILTER (WHERE event_name = 'pageview') AS pageviews,
+                COUNT(*) FILTER (WHERE event_name <> 'heartbeat') AS events
+         FROM events_all
+         WHERE timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
+         GROUP BY site_id
+         ORDER BY visitors DESC, site_id",
+        super::ALL_SITES,
+        start_date,
+        end_date,
+    )?;
+    let rows = stmt
+        .query_map(duckdb::params![start_date, end_date], |row| {
+            Ok(SiteSummary {
+                site_id: row.get(0)?,
+                visitors: row.get(1)?,
+                pageviews: row.get(2)?,
+                events: row.get(3)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(rows)
+}
+
+/// [`query_site_summaries`] from the [summary sidecars](crate::storage::summary)
+/// of the Parquet files in the range.
+///
+/// Only buffered events and the files without a usable sidecar are read.
+/// Visitors are estimated from merged sketches, within about 2%; pageviews
+/// and events are exact.
+pub fn query_site_summary_estimates(
+    conn: &QueryConn<'_>,
+    start_date: &str,
+    end_date: &str,
+) -> Result<Vec<SiteSummary>, duckdb::Error> {
+    let mut sites: BTreeMap<String, (VisitorSketch, u64, u64)> = BTreeMap::new();
+    let mut scanned = Vec::new();
+    for file in schema::files_between(conn, start_date, end_date)? {
+        let usable = summary::load(Path::new(&file.path))
+            .filter(|s| file.within && Some(s.rows) == file.row_count);
+        let Some(file_summary) = usable else {
+            scanned.push(file.path);
+            continue;
+        };
+        let site = sites.entry(file_summary.site_id).or_default();
+        site.0.merge(&file_summary.visitors);
+        site.1 += file_summary.pageviews;
+        site.2 += file_summary.events;
+    }
+
+    let scanned: Vec<&str> = scanned.iter().map(String::as_str).collect();
+    let sql = format!(
+        "SELECT site_id, visitor_id,
+                COUNT(*) FILTER (WHERE event_name = 'pageview'),
+                COUNT(*) FILTER (WHERE event_name <> 'heartbeat')
+         FROM {}
+         WHERE timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
+         GROUP BY site_id, visitor_id",
+        schema::events_in(conn, &scanned)?
+    );
+    let mut stmt = super::prepare(conn, &sql, super::ALL_SITES)?;
+    let visitors = stmt.query_map(duckdb::params![start_date, end_date], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, Option<String>>(1)?,
+            row.get::<_, u64>(2)?,
+            row.get::<_, u64>(3)?,
+        ))
+    })?;
+    for visitor in visitors {
+        let (site_id, visitor_id, pageviews, events) = visitor?;
+        let site = sites.entry(site_id).or_default();
+        if let Some(visitor_id) = visitor_id {
+            site.0.insert(&visitor_id);
+        }
+        site.1 += pageviews;
+        site.2 += events;
+    }
+
+    let mut summaries: Vec<SiteSummary> = sites
+        .into_iter()
+        .map(|(site_id, (visitors, pageviews, events))| SiteSummary {
+            site_id,
+            visitors: visitors.estimate(),
+            pageviews,
+            events,
+        })
+        .collect();
+    summaries.sort_by(|a, b| {
+        b.visitors
+            .cmp(&a.visitors)
+            .then_with(|| a.site_id.cmp(&b.site_id))
+    });
+    Ok(summaries)
+}
+
+/// Query core metrics for a site within a date range, reading sessions as
+/// `sessions` says.
 pub fn query_core_metrics(
-    conn: &Connection,
+    conn: &QueryConn<'_>,
     site_id: &str,
     start_date: &str,
     end_date: &str,
+    sessions: SessionSql,
 ) -> Result<CoreMetrics, duckdb::Error> {
     let unique_visitors = query_unique_visitors(conn, site_id, start_date, end_date)?;
     let total_pageviews = query_total_pageviews(conn, site_id, start_date, end_date)?;
-    // bounce_rate requires the behavioral extension (sessionize).
-    // Gracefully return 0.0 if the extension is not loaded.
-    let bounce_rate = query_bounce_rate(conn, site_id, start_date, end_date).unwrap_or(0.0);
+    // Session-based metrics fall back to SQL sessionization when the
+    // behavioral extension is not loaded.
+    let bounce_rate = query_bounce_rate(conn, site_id, start_date, end_date, sessions)?;
 
     let pages_per_visit = if unique_visitors > 0 {
         #[allow(clippy::cast_precision_loss)]
@@ -31,12 +154,9 @@ pub fn query_core_metrics(
         0.0
     };
 
-    // avg_visit_duration_secs requires the behavioral extension (sessionize).
-    // Gracefully return 0.0 if the extension is not loaded.
     let avg_visit_duration_secs =
-        super::sessions::query_session_metrics(conn, site_id, start_date, end_date)
-            .map(|s| s.avg_session_duration_secs)
-            .unwrap_or(0.0);
+        super::sessions::query_session_metrics(conn, site_id, start_date, end_date, sessions)?
+            .avg_session_duration_secs;
 
     Ok(CoreMetrics {
         unique_visitors,
@@ -49,14 +169,21 @@ pub fn query_core_metrics(
 
 /// Count unique visitors in a date range.
 pub fn query_unique_visitors(
-    conn: &Connection,
+    conn: &QueryConn<'_>,
     site_id: &str,
     start_date: &str,
     end_date: &str,
 ) -> Result<u64, duckdb::Error> {
-    let mut stmt = conn.prepare(
-        "SELECT COUNT(DISTINCT visitor_id) FROM events_all
-         WHERE site_id = ? AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)",
+    let site = SiteScope::of(site_id).filter("site_id");
+    let mut stmt = super::prepare_in_range(
+        conn,
+        &format!(
+            "SELECT COUNT(DISTINCT visitor_id) FROM events_all
+             WHERE {site} AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)"
+        ),
+        site_id,
+        start_date,
+        end_date,
     )?;
     let count: u64 = stmt.query_row(duckdb::params![site_id, start_date, end_date], |row| {
         row.get(0)
@@ -66,15 +193,22 @@ pub fn query_unique_visitors(
 
 /// Count total pageviews in a date range.
 pub fn query_total_pageviews(
-    conn: &Connection,
+    conn: &QueryConn<'_>,
     site_id: &str,
     start_date: &str,
     end_date: &str,
 ) -> Result<u64, duckdb::Error> {
-    let mut stmt = conn.prepare(
-        "SELECT COUNT(*) FROM events_all
-         WHERE site_id = ? AND event_name = 'pageview'
-         AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)",
+    let site = SiteScope::of(site_id).filter("site_id");
+    let mut stmt = super::prepare_in_range(
+        conn,
+        &format!(
+            "SELECT COUNT(*) FROM events_all
+             WHERE {site} AND event_name = 'pageview'
+             AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)"
+        ),
+        site_id,
+        start_date,
+        end_date,
     )?;
     let count: u64 = stmt.query_row(duckdb::params![site_id, start_date, end_date], |row| {
         row.get(0)
@@ -82,52 +216,185 @@ pub fn query_total_pageviews(
     Ok(count)
 }
 
-/// Calculate bounce rate using sessionize from the behavioral extension.
+/// Calculate bounce rate: the share of sessions with exactly one pageview.
 ///
-/// Returns a value between 0.0 and 1.0, or 0.0 if no sessions exist.
+/// Uses `sessionize` from the behavioral extension when loaded, otherwise the
+/// pure-SQL sessionization, and reads days with
+/// [materialized sessions](super::materialized_sessions) from them.  Returns
+/// a value between 0.0 and 1.0, or 0.0 if no sessions exist.
 pub fn query_bounce_rate(
-    conn: &Connection,
+    conn: &QueryConn<'_>,
     site_id: &str,
     start_date: &str,
     end_date: &str,
+    sessions: SessionSql,
 ) -> Result<f64, duckdb::Error> {
-    let sql = r"
-        WITH sessions AS (
+    let (live_start, stored) =
+        super::sessions::session_sources(conn, site_id, start_date, end_date, sessions)?;
+    let stored = stored.map_or_else(String::new, |source| {
+        format!("UNION ALL SELECT pageviews FROM {source}")
+    });
+    let build = |sessions_cte: &str| {
+        format!(
+            "WITH {sessions_cte}
             SELECT
-                visitor_id,
-                sessionize(timestamp, INTERVAL '30 minutes') OVER (
-                    PARTITION BY visitor_id ORDER BY timestamp
-                ) AS session_id,
-                event_name
-            FROM events_all
-            WHERE site_id = ? AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
-        )
-        SELECT
-            COALESCE(
-                COUNT(DISTINCT CASE WHEN page_count = 1 THEN session_key END)::FLOAT
-                / NULLIF(COUNT(DISTINCT session_key), 0),
-                0.0
-            ) AS bounce_rate
-        FROM (
-            SELECT
-                visitor_id || '-' || CAST(session_id AS VARCHAR) AS session_key,
-                COUNT(*) FILTER (WHERE event_name = 'pageview') AS page_count
-            FROM sessions
-            GROUP BY visitor_id, session_id
+                COALESCE(
+                    CAST(COUNT(*) FILTER (WHERE page_count = 1) AS FLOAT) / NULLIF(COUNT(*), 0),
+                    0.0
+                ) AS bounce_rate
+            FROM (
+                SELECT COUNT(*) FILTER (WHERE event_name = 'pageview') AS page_count
+                FROM sessions
+                GROUP BY visitor_id, session_id
+                {stored}
+            )"
         )
-    ";
+    };
 
-    let mut stmt = conn.prepare(sql)?;
+    let mut stmt = super::sessions::prepare_with_fallback(
+        conn,
+        site_id,
+        &live_start,
+        end_date,
+        &build(&super::sessions::sessions_cte_behavioral(SiteScope::of(
+            site_id,
+        ))),
+        &build(&super::sessions::sessions_cte_sql(SiteScope::of(site_id))),
+    )?;
     let bounce_rate: f64 = stmt
-        .query_row(duckdb::params![site_id, start_date, end_date], |row| {
+        .query_row(duckdb::params![site_id, live_start, end_date], |row| {
             row.get(0)
         })?;
     Ok(bounce_rate)
 }
 
+/// Core metrics of two segments of a site's visitors side by side.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct SegmentComparison {
+    pub a: CoreMetrics,
+    pub b: CoreMetrics,
+    /// Percent change of each metric from segment `a` to segment `b`.
+    pub change: MetricChanges,
+}
+
+/// Percent change of each core metric, `None` where the base is zero.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct MetricChanges {
+    pub unique_visitors: Option<f64>,
+    pub total_pageviews: Option<f64>,
+    pub bounce_rate: Option<f64>,
+    pub avg_visit_duration_secs: Option<f64>,
+    pub pages_per_visit: Option<f64>,
+}
+
+impl SegmentComparison {
+    pub fn new(a: CoreMetrics, b: CoreMetrics) -> Self {
+        #[allow(clippy::cast_precision_loss)]
+        let change = MetricChanges {
+            unique_visitors: percent_change(a.unique_visitors as f64, b.unique_visitors as f64),
+            total_pageviews: percent_change(a.total_pageviews as f64, b.total_pageviews as f64),
+            bounce_rate: percent_change(a.bounce_rate, b.bounce_rate),
+            avg_visit_duration_secs: percent_change(
+                a.avg_visit_duration_secs,
+                b.avg_visit_duration_secs,
+            ),
+            pages_per_visit: percent_change(a.pages_per_visit, b.pages_per_visit),
+        };
+        Self { a, b, change }
+    }
+}
+
+/// Change from `base` to `value` in percent, rounded to two decimals.
+pub fn percent_change(base: f64, value: f64) -> Option<f64> {
+    (base != 0.0).then(|| ((value - base) / base * 10_000.0).round() / 100.0)
+}
+
+/// Query the core metrics of one segment of a site's visitors.
+///
+/// The segment is the visitors with an event in the range matching
+/// `condition`, a SQL boolean expression over event columns built by the
+/// caller from a fixed grammar.  All events of those visitors in the range
+/// count, so a visitor who arrived from a source brings their later pageviews
+/// along.
+///
+/// Sessions are always computed from events, never read from
+/// [materialized sessions](super::materialized_sessions), which hold every
+/// visitor.
+pub fn query_segment_metrics(
+    conn: &QueryConn<'_>,
+    site_id: &str,
+    start_date: &str,
+    end_date: &str,
+    condition: &str,
+) -> Result<CoreMetrics, duckdb::Error> {
+    let site = SiteScope::of(site_id).filter("site_id");
+    let build = |sessions_cte: &str| {
+        let sessions_cte = sessions_cte.replace("FROM events_all", "FROM segment");
+        format!(
+            "WITH segment AS (
+                SELECT * FROM events_all
+                WHERE visitor_id IN (
+                    SELECT visitor_id FROM events_all
+                    WHERE {site} AND timestamp >= CAST(? AS TIMESTAMP)
+                    AND timestamp < CAST(? AS TIMESTAMP) AND ({condition})
+                )
+            ),
+            {sessions_cte},
+            session_stats AS (
+                SELECT
+                    COUNT(*) FILTER (WHERE event_name = 'pageview') AS page_count,
+                    EXTRACT(EPOCH FROM (MAX(timestamp) - MIN(timestamp))) AS duration_secs
+                FROM sessions
+                GROUP BY visitor_id, session_id
+            )
+            SELECT
+                (SELECT COUNT(DISTINCT visitor_id) FROM sessions) AS unique_visitors,
+                CAST(COALESCE(SUM(page_count), 0) AS UBIGINT) AS total_pageviews,
+                COALESCE(
+                    CAST(COUNT(*) FILTER (WHERE page_count = 1) AS FLOAT) / NULLIF(COUNT(*), 0),
+                    0.0
+                ) AS bounce_rate,
+                COALESCE(AVG(duration_secs), 0) AS avg_duration
+            FROM session_stats"
+        )
+    };
+
+    let mut stmt = super::sessions::prepare_with_fallback(
+        conn,
+        site_id,
+        start_date,
+        end_date,
+        &build(&super::sessions::sessions_cte_behavioral(SiteScope::of(
+            site_id,
+        ))),
+        &build(&super::sessions::sessions_cte_sql(SiteScope::of(site_id))),
+    )?;
+    stmt.query_row(
+        duckdb::params![site_id, start_date, end_date, site_id, start_date, end_date],
+        |row| {
+            let unique_visitors: u64 = row.get(0)?;
+            let total_pageviews: u64 = row.get(1)?;
+            #[allow(clippy::cast_precision_loss)]
+            let pages_per_visit = if unique_visitors > 0 {
+                total_pageviews as f64 / unique_visitors as f64
+            } else {
+                0.0
+            };
+            Ok(CoreMetrics {
+                unique_visitors,
+                total_pageviews,
+                bounce_rate: row.get(2)?,
+                avg_visit_duration_secs: row.get(3)?,
+                pages_per_visit,
+            })
+        },
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use duckdb::Connection;
 
     fn setup_test_db() -> Connection {
         let conn = Connection::open_in_memory().unwrap();
@@ -150,7 +417,13 @@ mod tests {
     #[test]
     fn test_unique_visitors_empty() {
         let conn = setup_test_db();
-        let count = query_unique_visitors(&conn, "test.com", "2024-01-01", "2024-02-01").unwrap();
+        let count = query_unique_visitors(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+        )
+        .unwrap();
         assert_eq!(count, 0);
     }
 
@@ -161,7 +434,13 @@ mod tests {
         insert_pageview(&conn, "v1", "2024-01-15 10:05:00", "/about");
         insert_pageview(&conn, "v2", "2024-01-15 11:00:00", "/");
 
-        let count = query_unique_visitors(&conn, "test.com", "2024-01-01", "2024-02-01").unwrap();
+        let count = query_unique_visitors(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+        )
+        .unwrap();
         assert_eq!(count, 2);
     }
 
@@ -172,7 +451,13 @@ mod tests {
         insert_pageview(&conn, "v2", "2024-02-15 10:00:00", "/");
 
         // Only January
-        let count = query_unique_visitors(&conn, "test.com", "2024-01-01", "2024-02-01").unwrap();
+        let count = query_unique_visitors(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+        )
+        .unwrap();
         assert_eq!(count, 1);
     }
 
@@ -183,7 +468,13 @@ mod tests {
         insert_pageview(&conn, "v1", "2024-01-15 10:05:00", "/about");
         insert_pageview(&conn, "v2", "2024-01-15 11:00:00", "/");
 
-        let count = query_total_pageviews(&conn, "test.com", "2024-01-01", "2024-02-01").unwrap();
+        let count = query_total_pageviews(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+        )
+        .unwrap();
         assert_eq!(count, 3);
     }
 
@@ -198,14 +489,27 @@ mod tests {
         )
         .unwrap();
 
-        let count = query_total_pageviews(&conn, "test.com", "2024-01-01", "2024-02-01").unwrap();
+        let count = query_total_pageviews(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+        )
+        .unwrap();
         assert_eq!(count, 1);
     }
 
     #[test]
     fn test_core_metrics_empty() {
         let conn = setup_test_db();
-        let metrics = query_core_metrics(&conn, "test.com", "2024-01-01", "2024-02-01").unwrap();
+        let metrics = query_core_metrics(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            SessionSql::Primary,
+        )
+        .unwrap();
         assert_eq!(metrics.unique_visitors, 0);
         assert_eq!(metrics.total_pageviews, 0);
         assert!(metrics.pages_per_visit.abs() < f64::EPSILON);
@@ -218,9 +522,180 @@ mod tests {
         insert_pageview(&conn, "v1", "2024-01-15 10:05:00", "/about");
         insert_pageview(&conn, "v2", "2024-01-15 11:00:00", "/");
 
-        let metrics = query_core_metrics(&conn, "test.com", "2024-01-01", "2024-02-01").unwrap();
+        let metrics = query_core_metrics(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            SessionSql::Primary,
+        )
+        .unwrap();
         assert_eq!(metrics.unique_visitors, 2);
         assert_eq!(metrics.total_pageviews, 3);
         assert!((metrics.pages_per_visit - 1.5).abs() < f64::EPSILON);
     }
+
+    #[test]
+    fn test_all_sites_groups_and_site_summaries() {
+        let conn = setup_test_db();
+        insert_pageview(&conn, "v1", "2024-01-15 10:00:00", "/");
+        insert_pageview(&conn, "v2", "2024-01-15 10:00:00", "/");
+        conn.execute(
+            "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+             VALUES ('other.com', 'v3', '2024-01-15 10:00:00', 'signup', '/')",
+            [],
+        )
+        .unwrap();
+
+        let all = query_unique_visitors(
+            &QueryConn::new(&conn),
+            crate::query::ALL_SITES,
+            "2024-01-15",
+            "2024-01-16",
+        )
+        .unwrap();
+        assert_eq!(all, 3);
+        let one = query_unique_visitors(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-15",
+            "2024-01-16",
+        )
+        .unwrap();
+        assert_eq!(one, 2);
+
+        crate::storage::schema::sync_site_groups(&conn, [("other.com", "acme")]).unwrap();
+        let group =
+            query_unique_visitors(&QueryConn::new(&conn), "@acme", "2024-01-15", "2024-01-16")
+                .unwrap();
+        assert_eq!(group, 1);
+
+        let sites =
+            query_site_summaries(&QueryConn::new(&conn), "2024-01-15", "2024-01-16").unwrap();
+        assert_eq!(
+            sites,
+            vec![
+                SiteSummary {
+                    site_id: "test.com".to_string(),
+                    visitors: 2,
+                    pageviews: 2,
+                    events: 2,
+                },
+                SiteSummary {
+                    site_id: "other.com".to_string(),
+                    visitors: 1,
+                    pageviews: 0,
+                    events: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_site_summary_estimates_read_sidecars() {
+        let conn = setup_test_db();
+        let dir = tempfile::tempdir().unwrap();
+        let storage = crate::storage::parquet::ParquetStorage::new(dir.path());
+        insert_pageview(&conn, "v1", "2024-01-15 10:00:00", "/");
+        insert_pageview(&conn, "v2", "2024-01-15 11:00:00", "/");
+        storage.flush_events(&conn).unwrap();
+        // Buffered events are counted alongside the summarized file.
+        insert_pageview(&conn, "v1", "2024-01-15 12:00:00", "/about");
+        insert_pageview(&conn, "v3", "2024-01-15 12:00:00", "/");
+
+        let exact =
+            query_site_summaries(&QueryConn::new(&conn), "2024-01-15", "2024-01-16").unwrap();
+        let estimated =
+            query_site_summary_estimates(&QueryConn::new(&conn), "2024-01-15", "2024-01-16")
+                .unwrap();
+        assert_eq!(estimated, exact);
+        assert_eq!(estimated[0].visitors, 3);
+        assert_eq!(estimated[0].pageviews, 4);
+
+        // A file whose sidecar is gone is scanned instead.
+        let file = &storage.list_files(&conn, None).unwrap()[0].path;
+        crate::storage::summary::remove(Path::new(file)).unwrap();
+        let estimated =
+            query_site_summary_estimates(&QueryConn::new(&conn), "2024-01-15", "2024-01-16")
+                .unwrap();
+        assert_eq!(estimated, exact);
+        assert!(
+            query_site_summary_estimates(&QueryConn::new(&conn), "2024-01-16", "2024-01-17")
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_bounce_rate_without_extension() {
+        let conn = setup_test_db();
+        // v1 views two pages in one session; v2 bounces.
+        insert_pageview(&conn, "v1", "2024-01-15 10:00:00", "/");
+        insert_pageview(&conn, "v1", "2024-01-15 10:05:00", "/about");
+        insert_pageview(&conn, "v2", "2024-01-15 11:00:00", "/");
+
+        let rate = query_bounce_rate(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            SessionSql::Primary,
+        )
+        .unwrap();
+        assert!((rate - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_segment_metrics_and_comparison() {
+        let conn = setup_test_db();
+        // v1 arrives from google and views two pages; v2 and v3 bounce from
+        // twitter.
+        conn.execute_batch(
+            "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname,
+                                 referrer_source)
+             VALUES ('test.com', 'v1', '2024-01-15 10:00:00', 'pageview', '/', 'google'),
+                    ('test.com', 'v1', '2024-01-15 10:01:00', 'pageview', '/about', NULL),
+                    ('test.com', 'v2', '2024-01-15 11:00:00', 'pageview', '/', 'twitter'),
+                    ('test.com', 'v3', '2024-01-15 12:00:00', 'pageview', '/', 'twitter')",
+        )
+        .unwrap();
+
+        let google = query_segment_metrics(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            "referrer_source = 'google'",
+        )
+        .unwrap();
+        assert_eq!(google.unique_visitors, 1);
+        assert_eq!(google.total_pageviews, 2);
+        assert!(google.bounce_rate.abs() < 1e-6);
+        assert!((google.avg_visit_duration_secs - 60.0).abs() < 1e-6);
+        assert!((google.pages_per_visit - 2.0).abs() < 1e-6);
+
+        let twitter = query_segment_metrics(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            "referrer_source = 'twitter'",
+        )
+        .unwrap();
+        assert_eq!(twitter.unique_visitors, 2);
+        assert_eq!(twitter.total_pageviews, 2);
+        assert!((twitter.bounce_rate - 1.0).abs() < 1e-6);
+
+        let comparison = SegmentComparison::new(google, twitter);
+        assert_eq!(
+            comparison.change,
+            MetricChanges {
+                unique_visitors: Some(100.0),
+                total_pageviews: Some(0.0),
+                bounce_rate: None,
+                avg_visit_duration_secs: Some(-100.0),
+                pages_per_visit: Some(-50.0),
+            }
+        );
+    }
 }
@@ -1,9 +1,368 @@
+pub mod anomalies;
 pub mod breakdowns;
 pub mod cache;
+pub mod countries;
+pub mod digest;
+pub mod events;
+pub mod explain;
 pub mod flow;
 pub mod funnel;
+pub mod materialized_sessions;
 pub mod metrics;
+pub mod paths;
 pub mod retention;
 pub mod sequences;
 pub mod sessions;
+pub mod shadow;
+pub mod slow;
+pub mod stitching;
 pub mod timeseries;
+pub mod visitor;
+
+use duckdb::Connection;
+use explain::{Explain, ExplainedQuery};
+use parking_lot::{Mutex, MutexGuard};
+use slow::{SlowQueryLog, TimedStatement};
+use std::borrow::Cow;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// `site_id` value that selects every site.  Restricted to admins by
+/// `require_auth`.
+pub const ALL_SITES: &str = "__all__";
+
+/// Prefix of a `site_id` that selects every site in a group, e.g. `@acme`.
+pub const GROUP_PREFIX: char = '@';
+
+/// Sites a report reads, from its `site_id` parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SiteScope {
+    /// One site.
+    Site,
+    /// Every site: [`ALL_SITES`].
+    All,
+    /// Every site in a group: `@<group>`.
+    Group,
+}
+
+impl SiteScope {
+    /// The scope of a `site_id` parameter.
+    pub fn of(site_id: &str) -> Self {
+        if site_id == ALL_SITES {
+            Self::All
+        } else if site_id.starts_with(GROUP_PREFIX) {
+            Self::Group
+        } else {
+            Self::Site
+        }
+    }
+
+    /// Predicate restricting `column` to the scope.  It binds the `site_id`
+    /// parameter as one `?` in every scope, so queries bind the same
+    /// parameters whichever sites they read.
+    pub fn filter(self, column: &str) -> String {
+        match self {
+            Self::Site => format!("{column} = ?"),
+            Self::All => format!("({column} IS NOT NULL AND CAST(? AS VARCHAR) IS NOT NULL)"),
+            Self::Group => {
+                format!("{column} IN (SELECT site_id FROM site_groups WHERE '@' || group_name = ?)")
+            }
+        }
+    }
+}
+
+/// Make a query over `[start, end)` read only the Parquet files whose
+/// timestamps overlap the range, instead of every file behind `events_all`.
+///
+/// The pruned relation is added as an `events_all` CTE, which shadows the
+/// view for the whole statement, so the query text itself is not rewritten.
+/// It is inlined like the view, keeping filter pushdown into each read.
+pub fn prune_to_range(
+    conn: &Connection,
+    sql: &str,
+    start: &str,
+    end: &str,
+) -> Result<String, duckdb::Error> {
+    let source = crate::storage::schema::events_between(conn, start, end)?;
+    let cte = format!("events_all AS NOT MATERIALIZED {source}");
+    let trimmed = sql.trim_start();
+    let has_with = trimmed
+        .get(..4)
+        .is_some_and(|word| word.eq_ignore_ascii_case("with"))
+        && trimmed[4..].starts_with(char::is_whitespace);
+    Ok(if has_with {
+        format!("WITH {cte},{}", &trimmed[4..])
+    } else {
+        format!("WITH {cte} {sql}")
+    })
+}
+
+/// A connection reports run on: the statements they prepare are timed into
+/// its [slow query log](slow), if any, and [explained](explain) when asked
+/// to.
+pub struct QueryConn<'a> {
+    conn: Handle<'a>,
+    slow_queries: Option<Arc<SlowQueryLog>>,
+    explain: Option<Explain>,
+}
+
+enum Handle<'a> {
+    Locked(MutexGuard<'a, Connection>),
+    Borrowed(&'a Connection),
+}
+
+impl<'a> QueryConn<'a> {
+    /// `conn`, with statements neither timed nor explained.
+    pub const fn new(conn: &'a Connection) -> Self {
+        Self {
+            conn: Handle::Borrowed(conn),
+            slow_queries: None,
+            explain: None,
+        }
+    }
+
+    /// Lock a server's connection, timing statements into its `log`.
+    pub fn lock(conn: &'a Mutex<Connection>, log: &Arc<SlowQueryLog>) -> Self {
+        Self {
+            conn: Handle::Locked(conn.lock()),
+            slow_queries: Some(Arc::clone(log)),
+            explain: None,
+        }
+    }
+
+    /// Explain every statement before it runs, with `EXPLAIN ANALYZE` when
+    /// `analyze` is set, keeping the plans for [`Self::explained_queries`].
+    #[must_use]
+    pub fn explained(mut self, analyze: bool) -> Self {
+        self.explain = Some(Explain::new(analyze));
+        self
+    }
+
+    /// Plans of the statements explained since the last call, in the order
+    /// they ran.
+    pub fn explained_queries(&self) -> Vec<ExplainedQuery> {
+        self.explain.as_ref().map(Explain::take).unwrap_or_default()
+    }
+
+    /// Prepare `prepared`, the query `sql` as sent to DuckDB, timed for
+    /// `site_id` from `started` and explained if asked to.
+    pub fn prepare_timed(
+        &self,
+        prepared: &str,
+        sql: &str,
+        site_id: &str,
+        started: Instant,
+    ) -> Result<TimedStatement<'_>, duckdb::Error> {
+        let stmt = TimedStatement::new(
+            self.prepare(prepared)?,
+            sql,
+            site_id,
+            started,
+            self.slow_queries.as_deref(),
+        );
+        Ok(match &self.explain {
+            Some(explain) => stmt.explained_as(explain, self, prepared),
+            None => stmt,
+        })
+    }
+}
+
+impl Deref for QueryConn<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Self::Target {
+        match &self.conn {
+            Handle::Locked(conn) => conn,
+            Handle::Borrowed(conn) => conn,
+        }
+    }
+}
+
+/// Prepare a query, timed for the [slow query log](slow) and
+/// [explained](explain) on request.  Its site filters come from
+/// [`SiteScope::filter`].
+pub fn prepare<'c>(
+    conn: &'c QueryConn<'_>,
+    sql: &str,
+    site_id: &str,
+) -> Result<TimedStatement<'c>, duckdb::Error> {
+    conn.prepare_timed(sql, sql, site_id, Instant::now())
+}
+
+/// Prepare a query whose reads of `events_all` all fall within `[start, end)`,
+/// pruned with [`prune_to_range`], timed for the [slow query log](slow) and
+/// [explained](explain) on request.
+pub fn prepare_in_range<'c>(
+    conn: &'c QueryConn<'_>,
+    sql: &str,
+    site_id: &str,
+    start: &str,
+    end: &str,
+) -> Result<TimedStatement<'c>, duckdb::Error> {
+    let started = Instant::now();
+    let pruned = prune_to_range(conn, sql, start, end)?;
+    conn.prepare_timed(&pruned, sql, site_id, started)
+}
+
+/// Whom retention and funnel reports count.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Identity {
+    /// The daily-rotating visitor hash.
+    #[default]
+    Visitor,
+    /// The hashed `uid` sent by logged-in products, falling back to the
+    /// visitor hash for events without one.
+    User,
+    /// Like [`Identity::User`], but events without a `uid` are attributed to
+    /// the user their visitor was mapped to by the stitching job.
+    Stitched,
+}
+
+/// Make a query count users by reading `events_all` with `visitor_id`
+/// replaced by the user hash where there is one.
+pub fn with_identity(sql: &str, identity: Identity) -> Cow<'_, str> {
+    match identity {
+        Identity::Visitor => Cow::Borrowed(sql),
+        Identity::User => Cow::Owned(sql.replace(
+            "events_all",
+            "(SELECT * REPLACE (COALESCE(user_id, visitor_id) AS visitor_id) FROM events_all)",
+        )),
+        Identity::Stitched => Cow::Owned(sql.replace(
+            "events_all",
+            "(SELECT ev.* REPLACE (COALESCE(ev.user_id, vu.user_id, ev.visitor_id) AS visitor_id)
+              FROM events_all ev
+              LEFT JOIN visitor_users vu
+                  ON vu.site_id = ev.site_id AND vu.visitor_id = ev.visitor_id)",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::breakdowns::{Attribution, AttributionQuery, Dimension};
+    use crate::query::sessions::SessionSql;
+
+    /// Run every report for `site_id`, returning the SQL of each statement.
+    fn report_statements(conn: &Connection, site_id: &str) -> Vec<String> {
+        let (start, end) = ("2024-01-01", "2024-01-08");
+        let steps = ["pathname = '/'", "event_name = 'signup'"];
+        let conn = &QueryConn::new(conn).explained(false);
+        let run = || {
+            metrics::query_core_metrics(conn, site_id, start, end, SessionSql::Primary)?;
+            metrics::query_segment_metrics(conn, site_id, start, end, "browser = 'Firefox'")?;
+            timeseries::query_timeseries(conn, site_id, start, end, timeseries::Granularity::Day)?;
+            breakdowns::query_breakdown(conn, site_id, start, end, Dimension::Page, None, 10)?;
+            breakdowns::query_breakdown_coverage(
+                conn,
+                site_id,
+                start,
+                end,
+                Dimension::Page,
+                Some("event_name = 'signup'"),
+                10,
+            )?;
+            breakdowns::query_previous_visitors(
+                conn,
+                site_id,
+                start,
+                end,
+                Dimension::Page,
+                None,
+                10,
+            )?;
+            breakdowns::query_continent_breakdown(conn, site_id, start, end)?;
+            breakdowns::query_device_model_breakdown(conn, site_id, start, end, 10)?;
+            breakdowns::query_attributed_breakdown(
+                conn,
+                site_id,
+                start,
+                end,
+                Dimension::ReferrerSource,
+                AttributionQuery {
+                    model: Attribution::FirstTouch,
+                    goal: None,
+                },
+                10,
+            )?;
+            breakdowns::query_source_conversions(
+                conn,
+                site_id,
+                start,
+                end,
+                Dimension::ReferrerSource,
+                "event_name = 'signup'",
+                10,
+            )?;
+            breakdowns::query_search_terms(conn, site_id, start, end, "q", 10)?;
+            breakdowns::query_custom_events(conn, site_id, start, end, 10)?;
+            breakdowns::query_email_opens(conn, site_id, start, end, 10)?;
+            sessions::query_session_metrics(conn, site_id, start, end, SessionSql::Primary)?;
+            sessions::query_time_on_page(conn, site_id, start, end, 10)?;
+            paths::query_paths(conn, site_id, start, end, None, 3, 10)?;
+            flow::query_flow(conn, site_id, start, end, "/")?;
+            funnel::query_funnel(
+                conn,
+                site_id,
+                start,
+                end,
+                "1 day",
+                &steps,
+                None,
+                Identity::Visitor,
+            )?;
+            funnel::query_step_medians(conn, site_id, start, end, "1 day", &steps, Identity::User)?;
+            retention::query_retention(
+                conn,
+                site_id,
+                start,
+                end,
+                retention::CohortGranularity::Day,
+                3,
+                Identity::Stitched,
+            )?;
+            sequences::execute_sequence_match(conn, site_id, start, end, &steps)?;
+            events::query_events_page(
+                conn,
+                &events::EventFilter {
+                    site_id,
+                    start_date: start,
+                    end_date: end,
+                    event_name: None,
+                    country_code: None,
+                },
+                None,
+                10,
+            )?;
+            visitor::query_visitor_profile(conn, site_id, "v1", 10)?;
+            anomalies::query_anomalies(conn, site_id, start, end)?;
+            digest::query_digest(conn, site_id, start, end)?;
+            Ok::<_, duckdb::Error>(())
+        };
+        run().unwrap();
+        conn.explained_queries()
+            .into_iter()
+            .map(|q| q.sql)
+            .collect()
+    }
+
+    #[test]
+    fn test_reports_filter_to_their_scope() {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::storage::schema::init_schema(&conn).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        crate::storage::schema::setup_query_view(&conn, dir.path()).unwrap();
+        crate::storage::schema::sync_site_groups(&conn, [("a.com", "acme")]).unwrap();
+
+        for site_id in [ALL_SITES, "@acme"] {
+            let scope = SiteScope::of(site_id);
+            let statements = report_statements(&conn, site_id);
+            assert!(statements.len() > 20, "{statements:?}");
+            for sql in statements {
+                assert!(sql.contains(&scope.filter("site_id")), "{site_id}: {sql}");
+                assert!(!sql.contains("site_id = ?"), "{site_id}: {sql}");
+            }
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use super::sessions::{prepare_with_fallback, sessions_cte_behavioral, sessions_cte_sql};
+use super::{QueryConn, SiteScope};
+
+/// Separator used to aggregate a path into one string column.  Unit separator
+/// (U+001F) cannot appear in a URL path.
+const PATH_SEPARATOR: char = '\u{1f}';
+
+/// A multi-step visitor path and how often it was taken.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct PathRow {
+    /// Pages in the order they were viewed, at most `depth` long.
+    pub steps: Vec<String>,
+    pub sessions: u64,
+    pub visitors: u64,
+}
+
+/// Query the most common page sequences within sessions.
+///
+/// Pageviews are ordered within each 30-minute session and consecutive
+/// repeats of the same page (reloads) are collapsed.  A path starts at the
+/// session's entry page, or at the first view of `start_page` when given, and
+/// runs for up to `depth` pages.  Single-page paths are excluded.
+pub fn query_paths(
+    conn: &QueryConn<'_>,
+    site_id: &str,
+    start_date: &str,
+    end_date: &str,
+    start_page: Option<&str>,
+    depth: u32,
+    limit: u32,
+) -> Result<Vec<PathRow>, duckdb::Error> {
+    let build = |sessions_cte: &str| {
+        format!(
+            "WITH {sessions_cte},
+            pv AS (
+                SELECT visitor_id, session_id, timestamp, pathname,
+                    LAG(pathname) OVER (
+                        PARTITION BY visitor_id, session_id ORDER BY timestamp
+                    ) AS prev_pathname
+                FROM sessions
+                WHERE event_name = 'pageview'
+            ),
+            seq AS (
+                SELECT visitor_id, session_id, pathname,
+                    ROW_NUMBER() OVER (
+                        PARTITION BY visitor_id, session_id ORDER BY timestamp
+                    ) AS pos
+                FROM pv
+                WHERE prev_pathname IS NULL OR prev_pathname <> pathname
+            ),
+            anchor AS (
+                SELECT visitor_id, session_id, MIN(pos) AS start_pos
+                FROM seq, (SELECT CAST(? AS VARCHAR) AS start_page) sp
+                WHERE (sp.start_page IS NULL AND pos = 1) OR pathname = sp.start_page
+                GROUP BY visitor_id, session_id
+            ),
+            session_paths AS (
+                SELECT s.visitor_id, s.session_id,
+                    string_agg(s.pathname, chr(31) ORDER BY s.pos) AS path,
+                    COUNT(*) AS len
+                FROM seq s
+                JOIN anchor a ON s.visitor_id = a.visitor_id AND s.session_id = a.session_id
+                WHERE s.pos >= a.start_pos AND s.pos < a.start_pos + {depth}
+                GROUP BY s.visitor_id, s.session_id
+            )
+            SELECT path, COUNT(*) AS sessions, COUNT(DISTINCT visitor_id) AS visitors
+            FROM session_paths
+            WHERE len >= 2
+            GROUP BY path
+            ORDER BY sessions DESC, path
+            LIMIT {limit}"
+        )
+    };
+
+    let mut stmt = prepare_with_fallback(
+        conn,
+        site_id,
+        start_date,
+        end_date,
+        &build(&sessions_cte_behavioral(SiteScope::of(site_id))),
+        &build(&sessions_cte_sql(SiteScope::of(site_id))),
+    )?;
+    let rows = stmt
+        .query_map(
+            duckdb::params![site_id, start_date, end_date, start_page],
+            |row| {
+                let path: String = row.get(0)?;
+                Ok(PathRow {
+                    steps: path.split(PATH_SEPARATOR).map(str::to_string).collect(),
+                    sessions: row.get(1)?,
+                    visitors: row.get(2)?,
+                })
+            },
+        )?
+        .collect::<Result<_, _>>()?;
+
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use duckdb::Connection;
+
+    fn setup_test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::storage::schema::init_schema(&conn).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        crate::storage::schema::setup_query_view(&conn, dir.path()).unwrap();
+        drop(dir);
+        conn
+    }
+
+    fn insert_views(conn: &Connection, rows: &[(&str, &str, &str)]) {
+        for (vid, path, ts) in rows {
+            conn.execute(
+                "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+                 VALUES ('test.com', ?, CAST(? AS TIMESTAMP), 'pageview', ?)",
+                duckdb::params![vid, ts, path],
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_paths_from_entry_pages() {
+        let conn = setup_test_db();
+        insert_views(
+            &conn,
+            &[
+                ("v1", "/", "2024-01-15 10:00:00"),
+                ("v1", "/", "2024-01-15 10:00:30"), // reload, collapsed
+                ("v1", "/pricing", "2024-01-15 10:01:00"),
+                ("v1", "/signup", "2024-01-15 10:02:00"),
+                ("v1", "/done", "2024-01-15 10:03:00"), // beyond depth 3
+                ("v2", "/", "2024-01-15 11:00:00"),
+                ("v2", "/pricing", "2024-01-15 11:01:00"),
+                ("v2", "/signup", "2024-01-15 11:02:00"),
+                ("v3", "/blog", "2024-01-15 12:00:00"), // single page, excluded
+            ],
+        );
+
+        let paths = query_paths(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            None,
+            3,
+            10,
+        )
+        .unwrap();
+        assert_eq!(paths.len(), 1);
+        assert_eq!(paths[0].steps, vec!["/", "/pricing", "/signup"]);
+        assert_eq!(paths[0].sessions, 2);
+        assert_eq!(paths[0].visitors, 2);
+    }
+
+    #[test]
+    fn test_paths_from_specific_page() {
+        let conn = setup_test_db();
+        insert_views(
+            &conn,
+            &[
+                ("v1", "/", "2024-01-15 10:00:00"),
+                ("v1", "/pricing", "2024-01-15 10:01:00"),
+                ("v1", "/signup", "2024-01-15 10:02:00"),
+                ("v2", "/blog", "2024-01-15 11:00:00"),
+                ("v2", "/pricing", "2024-01-15 11:01:00"),
+                ("v2", "/docs", "2024-01-15 11:02:00"),
+            ],
+        );
+
+        let paths = query_paths(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            Some("/pricing"),
+            2,
+            10,
+        )
+        .unwrap();
+        assert_eq!(paths.len(), 2);
+        assert!(paths.iter().all(|p| p.steps[0] == "/pricing"));
+        assert!(paths.iter().any(|p| p.steps == vec!["/pricing", "/docs"]));
+    }
+
+    #[test]
+    fn test_paths_empty() {
+        let conn = setup_test_db();
+        let paths = query_paths(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            None,
+            3,
+            10,
+        )
+        .unwrap();
+        assert!(paths.is_empty());
+    }
+}
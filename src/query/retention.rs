@@ -1,85 +1,155 @@
-use duckdb::Connection;
+use super::{Identity, QueryConn, SiteScope};
 
 /// Retention cohort row.
-#[derive(Debug, Clone, serde::Serialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
 pub struct RetentionCohort {
     pub cohort_date: String,
+    /// Visitors first seen during the cohort period.
+    pub cohort_size: u64,
+    /// Whether any cohort visitor was active in each period (index 0 is the
+    /// cohort period itself).
     pub retained: Vec<bool>,
+    /// Distinct cohort visitors active in each period.
+    pub counts: Vec<u64>,
+    /// `counts` as a percentage of `cohort_size`, rounded to 2 decimals.
+    pub percentages: Vec<f64>,
 }
 
-/// Query retention cohorts using the `retention` function from the behavioral extension.
+/// Cohort bucket size for retention analysis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CohortGranularity {
+    Day,
+    Week,
+    Month,
+}
+
+impl CohortGranularity {
+    /// DuckDB date part name, safe to interpolate into SQL.
+    const fn unit(self) -> &'static str {
+        match self {
+            Self::Day => "day",
+            Self::Week => "week",
+            Self::Month => "month",
+        }
+    }
+
+    /// Largest number of periods accepted for this granularity.
+    pub const fn max_periods(self) -> u32 {
+        match self {
+            Self::Day => 90,
+            Self::Week => 52,
+            Self::Month => 24,
+        }
+    }
+}
+
+/// Query retention cohorts for visitors first seen within the date range.
 ///
-/// Returns weekly cohorts with retention flags for each subsequent week.
-/// Requires the behavioral extension to be loaded.
+/// Each visitor belongs to the cohort of the period (day/week/month) of their
+/// first ever event.  For every cohort, `counts[i]` is the number of its
+/// visitors active `i` periods later.  Periods that end after `end_date` are
+/// not yet observable and are omitted, producing the usual retention triangle.
+/// `identity` picks whether visitors or logged-in users are counted.
 pub fn query_retention(
-    conn: &Connection,
+    conn: &QueryConn<'_>,
     site_id: &str,
     start_date: &str,
     end_date: &str,
-    num_weeks: u32,
+    granularity: CohortGranularity,
+    num_periods: u32,
+    identity: Identity,
 ) -> Result<Vec<RetentionCohort>, duckdb::Error> {
-    if num_weeks == 0 {
+    if num_periods == 0 {
         return Ok(Vec::new());
     }
 
-    // Build retention conditions for each week
-    let mut conditions = Vec::new();
-    for i in 0..num_weeks {
-        conditions.push(format!(
-            "DATE_TRUNC('week', timestamp) = DATE_TRUNC('week', first_seen) + INTERVAL '{i} weeks'"
-        ));
-    }
-    let retention_args = conditions.join(", ");
-
+    let unit = granularity.unit();
+    let scope = SiteScope::of(site_id);
+    let site = scope.filter("site_id");
+    let event_site = scope.filter("e.site_id");
     let sql = format!(
-        "SELECT STRFTIME(DATE_TRUNC('week', first_seen), '%Y-%m-%d') AS cohort_week,
-            retention({retention_args}) AS retained
-         FROM events_all e
-         JOIN (
+        "WITH firsts AS (
              SELECT visitor_id, MIN(timestamp) AS first_seen
-             FROM events_all WHERE site_id = ?
+             FROM events_all WHERE {site}
              GROUP BY visitor_id
-         ) f ON e.visitor_id = f.visitor_id
-         WHERE e.site_id = ?
-           AND e.timestamp >= CAST(? AS TIMESTAMP) AND e.timestamp < CAST(? AS TIMESTAMP)
-         GROUP BY cohort_week
-         ORDER BY cohort_week"
+         ),
+         activity AS (
+             SELECT DISTINCT e.visitor_id,
+                 DATE_TRUNC('{unit}', f.first_seen) AS cohort,
+                 date_diff('{unit}', DATE_TRUNC('{unit}', f.first_seen), DATE_TRUNC('{unit}', e.timestamp)) AS period
+             FROM events_all e
+             JOIN firsts f ON e.visitor_id = f.visitor_id
+             WHERE {event_site}
+               AND e.timestamp >= CAST(? AS TIMESTAMP) AND e.timestamp < CAST(? AS TIMESTAMP)
+               AND f.first_seen >= CAST(? AS TIMESTAMP)
+         )
+         SELECT STRFTIME(cohort, '%Y-%m-%d') AS cohort_date,
+             date_diff('{unit}', cohort, DATE_TRUNC('{unit}', CAST(? AS TIMESTAMP) - INTERVAL 1 SECOND)) + 1 AS observable,
+             period,
+             COUNT(*) AS visitors
+         FROM activity
+         WHERE period < {num_periods}
+         GROUP BY cohort, period
+         ORDER BY cohort, period"
     );
 
-    let mut stmt = conn.prepare(&sql)?;
-    let rows = stmt
+    let sql = super::with_identity(&sql, identity);
+    let mut stmt = super::prepare(conn, &sql, site_id)?;
+    let cells: Vec<(String, i64, i64, u64)> = stmt
         .query_map(
-            duckdb::params![site_id, site_id, start_date, end_date],
-            |row| {
-                let cohort_date: String = row.get(0)?;
-                // The retention() function returns a BOOLEAN[] array.
-                // DuckDB Rust bindings return this as a string representation.
-                let retained_raw: String = row.get(1)?;
-                let retained = parse_bool_array(&retained_raw);
-                Ok(RetentionCohort {
-                    cohort_date,
-                    retained,
-                })
-            },
+            duckdb::params![site_id, site_id, start_date, end_date, start_date, end_date],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
         )?
-        .filter_map(Result::ok)
-        .collect();
+        .collect::<Result<_, _>>()?;
 
-    Ok(rows)
+    Ok(build_cohorts(&cells, num_periods))
 }
 
-/// Parse a DuckDB BOOLEAN[] array string like "[true, false, true]" into `Vec<bool>`.
-fn parse_bool_array(s: &str) -> Vec<bool> {
-    let trimmed = s.trim().trim_start_matches('[').trim_end_matches(']');
-    if trimmed.is_empty() {
-        return Vec::new();
+/// Assemble `(cohort_date, observable_periods, period, visitors)` cells, sorted
+/// by cohort, into one row per cohort.
+fn build_cohorts(cells: &[(String, i64, i64, u64)], num_periods: u32) -> Vec<RetentionCohort> {
+    let mut cohorts: Vec<RetentionCohort> = Vec::new();
+    for (cohort_date, observable, period, visitors) in cells {
+        if cohorts.last().is_none_or(|c| &c.cohort_date != cohort_date) {
+            let width =
+                usize::try_from((*observable).clamp(1, i64::from(num_periods))).unwrap_or(1);
+            cohorts.push(RetentionCohort {
+                cohort_date: cohort_date.clone(),
+                cohort_size: 0,
+                retained: vec![false; width],
+                counts: vec![0; width],
+                percentages: vec![0.0; width],
+            });
+        }
+        let Some(cohort) = cohorts.last_mut() else {
+            continue;
+        };
+        if let Some(count) = usize::try_from(*period)
+            .ok()
+            .and_then(|p| cohort.counts.get_mut(p))
+        {
+            *count = *visitors;
+        }
     }
-    trimmed.split(',').map(|v| v.trim() == "true").collect()
+
+    for cohort in &mut cohorts {
+        cohort.cohort_size = cohort.counts.first().copied().unwrap_or(0);
+        for (i, count) in cohort.counts.iter().enumerate() {
+            cohort.retained[i] = *count > 0;
+            if cohort.cohort_size > 0 {
+                #[allow(clippy::cast_precision_loss)]
+                let pct = *count as f64 / cohort.cohort_size as f64 * 100.0;
+                cohort.percentages[i] = (pct * 100.0).round() / 100.0;
+            }
+        }
+    }
+    cohorts
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use duckdb::Connection;
 
     fn setup_test_db() -> Connection {
         let conn = Connection::open_in_memory().unwrap();
@@ -90,30 +160,142 @@ mod tests {
         conn
     }
 
+    fn insert_event(conn: &Connection, visitor_id: &str, timestamp: &str) {
+        conn.execute(
+            "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+             VALUES ('test.com', ?, ?, 'pageview', '/')",
+            duckdb::params![visitor_id, timestamp],
+        )
+        .unwrap();
+    }
+
     #[test]
     fn test_retention_empty() {
         let conn = setup_test_db();
-        // Without behavioral extension, this will fail gracefully
-        let result = query_retention(&conn, "test.com", "2024-01-01", "2024-03-01", 4);
-        if let Ok(cohorts) = result {
-            assert!(cohorts.is_empty());
-        }
+        let result = query_retention(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-03-01",
+            CohortGranularity::Week,
+            4,
+            Identity::Visitor,
+        )
+        .unwrap();
+        assert!(result.is_empty());
     }
 
     #[test]
     fn test_retention_zero_weeks() {
         let conn = setup_test_db();
-        let result = query_retention(&conn, "test.com", "2024-01-01", "2024-03-01", 0).unwrap();
+        let result = query_retention(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-03-01",
+            CohortGranularity::Week,
+            0,
+            Identity::Visitor,
+        )
+        .unwrap();
         assert!(result.is_empty());
     }
 
     #[test]
-    fn test_parse_bool_array() {
-        assert_eq!(
-            parse_bool_array("[true, false, true]"),
-            vec![true, false, true]
-        );
-        assert_eq!(parse_bool_array("[true]"), vec![true]);
-        assert_eq!(parse_bool_array("[]"), Vec::<bool>::new());
+    fn test_retention_daily_counts_and_percentages() {
+        let conn = setup_test_db();
+        // Cohort 2024-01-01: v1, v2, v3, v4. v1 and v2 return on day +1, v1 on day +2.
+        for vid in ["v1", "v2", "v3", "v4"] {
+            insert_event(&conn, vid, "2024-01-01 09:00:00");
+        }
+        insert_event(&conn, "v1", "2024-01-02 09:00:00");
+        insert_event(&conn, "v2", "2024-01-02 18:00:00");
+        insert_event(&conn, "v1", "2024-01-03 09:00:00");
+        // Cohort 2024-01-02: v5.
+        insert_event(&conn, "v5", "2024-01-02 10:00:00");
+
+        let cohorts = query_retention(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-01-04",
+            CohortGranularity::Day,
+            7,
+            Identity::Visitor,
+        )
+        .unwrap();
+
+        assert_eq!(cohorts.len(), 2);
+        let first = &cohorts[0];
+        assert_eq!(first.cohort_date, "2024-01-01");
+        assert_eq!(first.cohort_size, 4);
+        // Only 3 days are observable before end_date.
+        assert_eq!(first.counts, vec![4, 2, 1]);
+        assert_eq!(first.percentages, vec![100.0, 50.0, 25.0]);
+        assert_eq!(first.retained, vec![true, true, true]);
+
+        let second = &cohorts[1];
+        assert_eq!(second.cohort_date, "2024-01-02");
+        assert_eq!(second.counts, vec![1, 0]);
+        assert_eq!(second.retained, vec![true, false]);
+    }
+
+    #[test]
+    fn test_retention_monthly_excludes_earlier_visitors() {
+        let conn = setup_test_db();
+        // v0 was first seen before the range and must not form a cohort.
+        insert_event(&conn, "v0", "2023-12-15 09:00:00");
+        insert_event(&conn, "v0", "2024-01-15 09:00:00");
+        insert_event(&conn, "v1", "2024-01-10 09:00:00");
+        insert_event(&conn, "v1", "2024-02-10 09:00:00");
+
+        let cohorts = query_retention(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-03-01",
+            CohortGranularity::Month,
+            3,
+            Identity::Visitor,
+        )
+        .unwrap();
+
+        assert_eq!(cohorts.len(), 1);
+        assert_eq!(cohorts[0].cohort_date, "2024-01-01");
+        assert_eq!(cohorts[0].counts, vec![1, 1]);
+    }
+
+    #[test]
+    fn test_retention_by_user_merges_devices() {
+        let conn = setup_test_db();
+        // One user on a laptop (v1) and, a day later, a phone (v2).
+        for (vid, uid, ts) in [
+            ("v1", "u1", "2024-01-01 09:00:00"),
+            ("v2", "u1", "2024-01-02 09:00:00"),
+        ] {
+            conn.execute(
+                "INSERT INTO events (site_id, visitor_id, user_id, timestamp, event_name, pathname)
+                 VALUES ('test.com', ?, ?, ?, 'pageview', '/')",
+                duckdb::params![vid, uid, ts],
+            )
+            .unwrap();
+        }
+
+        let query = |identity| {
+            query_retention(
+                &QueryConn::new(&conn),
+                "test.com",
+                "2024-01-01",
+                "2024-01-03",
+                CohortGranularity::Day,
+                2,
+                identity,
+            )
+            .unwrap()
+        };
+        assert_eq!(query(Identity::Visitor).len(), 2);
+        let cohorts = query(Identity::User);
+        assert_eq!(cohorts.len(), 1);
+        assert_eq!(cohorts[0].counts, vec![1, 1]);
     }
 }
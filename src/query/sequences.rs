@@ -1,4 +1,4 @@
-use duckdb::Connection;
+use super::{QueryConn, SiteScope};
 
 /// Result of a sequence match query.
 #[derive(Debug, Clone, serde::Serialize)]
@@ -21,7 +21,8 @@ fn build_pattern(num_conditions: usize) -> String {
 /// Build the sequence_match SQL query.
 ///
 /// `conditions` are safe SQL boolean expressions (e.g., `pathname = '/pricing'`).
-fn build_sequence_match_sql(conditions: &[&str]) -> String {
+fn build_sequence_match_sql(scope: SiteScope, conditions: &[&str]) -> String {
+    let site = scope.filter("site_id");
     let pattern = build_pattern(conditions.len());
     let conds = conditions.join(", ");
     format!(
@@ -33,19 +34,43 @@ fn build_sequence_match_sql(conditions: &[&str]) -> String {
              SELECT visitor_id,
                  sequence_match('{pattern}', timestamp, {conds}) AS matched
              FROM events_all
-             WHERE site_id = ? AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
+             WHERE {site} AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
              GROUP BY visitor_id
          )"
     )
 }
 
+/// Pure-SQL equivalent of [`build_sequence_match_sql`] for when the behavioral
+/// extension is not loaded.
+///
+/// A visitor converts when the funnel step chain reaches the last condition,
+/// with no time window.
+fn build_sequence_fallback_sql(scope: SiteScope, conditions: &[&str]) -> String {
+    let ctes = super::funnel::step_chain_ctes(scope, conditions, None, "NULL");
+    let last = conditions.len();
+    format!(
+        "WITH {}
+         SELECT
+            converting AS converting_visitors,
+            total AS total_visitors,
+            COALESCE(converting::FLOAT / NULLIF(total, 0), 0) AS conversion_rate
+         FROM (
+             SELECT
+                 (SELECT COUNT(*) FROM t{last}) AS converting,
+                 (SELECT COUNT(DISTINCT visitor_id) FROM base) AS total
+         )",
+        ctes.join(", ")
+    )
+}
+
 /// Execute a sequence match query and return results.
 ///
 /// `conditions` must be safe SQL boolean expressions generated by the API layer
 /// (e.g., `pathname = '/pricing'`, `event_name = 'signup'`).
-/// Requires the behavioral extension to be loaded.
+/// Uses `sequence_match` when the behavioral extension is loaded and a plain
+/// SQL equivalent otherwise.
 pub fn execute_sequence_match(
-    conn: &Connection,
+    conn: &QueryConn<'_>,
     site_id: &str,
     start_date: &str,
     end_date: &str,
@@ -59,8 +84,14 @@ pub fn execute_sequence_match(
         });
     }
 
-    let sql = build_sequence_match_sql(conditions);
-    let mut stmt = conn.prepare(&sql)?;
+    let mut stmt = super::sessions::prepare_with_fallback(
+        conn,
+        site_id,
+        start_date,
+        end_date,
+        &build_sequence_match_sql(SiteScope::of(site_id), conditions),
+        &build_sequence_fallback_sql(SiteScope::of(site_id), conditions),
+    )?;
     stmt.query_row(duckdb::params![site_id, start_date, end_date], |row| {
         Ok(SequenceMatchResult {
             converting_visitors: row.get(0)?,
@@ -73,10 +104,14 @@ pub fn execute_sequence_match(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use duckdb::Connection;
 
     #[test]
     fn test_build_sequence_match_sql() {
-        let sql = build_sequence_match_sql(&["pathname = '/pricing'", "event_name = 'signup'"]);
+        let sql = build_sequence_match_sql(
+            SiteScope::Site,
+            &["pathname = '/pricing'", "event_name = 'signup'"],
+        );
         assert!(sql.contains("sequence_match("));
         assert!(sql.contains("(?1).*(?2)"));
         assert!(sql.contains("pathname = '/pricing'"));
@@ -102,8 +137,14 @@ mod tests {
     #[test]
     fn test_execute_empty_conditions() {
         let conn = setup_test_db();
-        let result =
-            execute_sequence_match(&conn, "test.com", "2024-01-01", "2024-02-01", &[]).unwrap();
+        let result = execute_sequence_match(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            &[],
+        )
+        .unwrap();
         assert_eq!(result.converting_visitors, 0);
         assert_eq!(result.total_visitors, 0);
     }
@@ -111,15 +152,45 @@ mod tests {
     #[test]
     fn test_execute_sequence_match_no_extension() {
         let conn = setup_test_db();
-        // Without behavioral extension, this will fail — expected behavior
         let result = execute_sequence_match(
-            &conn,
+            &QueryConn::new(&conn),
             "test.com",
             "2024-01-01",
             "2024-02-01",
             &["pathname = '/'", "event_name = 'signup'"],
-        );
-        // Graceful: the caller wraps this in unwrap_or
-        assert!(result.is_err() || result.unwrap().total_visitors == 0);
+        )
+        .unwrap();
+        assert_eq!(result.total_visitors, 0);
+    }
+
+    #[test]
+    fn test_sequence_fallback_requires_order() {
+        let conn = setup_test_db();
+        // v1 visits / then signs up; v2 signs up before ever visiting /.
+        for (vid, event, path, ts) in [
+            ("v1", "pageview", "/", "2024-01-15 10:00:00"),
+            ("v1", "signup", "/join", "2024-01-15 10:10:00"),
+            ("v2", "signup", "/join", "2024-01-15 10:00:00"),
+            ("v2", "pageview", "/", "2024-01-15 10:10:00"),
+        ] {
+            conn.execute(
+                "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+                 VALUES ('test.com', ?, ?, ?, ?)",
+                duckdb::params![vid, ts, event, path],
+            )
+            .unwrap();
+        }
+
+        let result = execute_sequence_match(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            &["pathname = '/'", "event_name = 'signup'"],
+        )
+        .unwrap();
+        assert_eq!(result.converting_visitors, 1);
+        assert_eq!(result.total_visitors, 2);
+        assert!((result.conversion_rate - 0.5).abs() < 1e-6);
     }
 }
@@ -1,52 +1,195 @@
+use super::slow::TimedStatement;
+use super::{QueryConn, SiteScope};
 use duckdb::Connection;
 
-/// Session-level metrics derived using the `sessionize` behavioral extension function.
-#[derive(Debug, Clone, serde::Serialize)]
+/// Session-level metrics derived from 30-minute inactivity sessionization.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
 pub struct SessionMetrics {
     pub total_sessions: u64,
     pub avg_session_duration_secs: f64,
     pub avg_pages_per_session: f64,
 }
 
-/// Query session metrics using the `sessionize` function from the behavioral extension.
+/// `sessions` CTE of the sites in `scope`, built on the behavioral
+/// extension's `sessionize` window function.
 ///
-/// Requires the behavioral extension to be loaded.
-pub fn query_session_metrics(
+/// Binds three parameters: site_id, start date, end date.
+pub fn sessions_cte_behavioral(scope: SiteScope) -> String {
+    let site = scope.filter("site_id");
+    format!(
+        "
+    sessions AS (
+        SELECT
+            visitor_id,
+            sessionize(timestamp, INTERVAL '30 minutes') OVER (
+                PARTITION BY visitor_id ORDER BY timestamp
+            ) AS session_id,
+            timestamp,
+            event_name,
+            pathname
+        FROM events_all
+        WHERE {site} AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
+    )"
+    )
+}
+
+/// Pure-SQL equivalent of [`sessions_cte_behavioral`].
+///
+/// An event starts a new session when it is the visitor's first event or more
+/// than 30 minutes after their previous one (LAG); a running SUM of those
+/// markers numbers the sessions.  Same columns and parameters.
+pub fn sessions_cte_sql(scope: SiteScope) -> String {
+    let site = scope.filter("site_id");
+    format!(
+        "
+    session_marks AS (
+        SELECT
+            visitor_id,
+            timestamp,
+            event_name,
+            pathname,
+            CASE
+                WHEN timestamp - LAG(timestamp) OVER (PARTITION BY visitor_id ORDER BY timestamp)
+                    <= INTERVAL '30 minutes' THEN 0
+                ELSE 1
+            END AS new_session
+        FROM events_all
+        WHERE {site} AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
+    ),
+    sessions AS (
+        SELECT
+            visitor_id,
+            SUM(new_session) OVER (
+                PARTITION BY visitor_id ORDER BY timestamp
+                ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW
+            ) AS session_id,
+            timestamp,
+            event_name,
+            pathname
+        FROM session_marks
+    )"
+    )
+}
+
+/// Prepare `behavioral_sql`, falling back to `fallback_sql` when it cannot be
+/// prepared — in practice because the behavioral extension is not loaded and
+/// its functions are unknown to the binder.
+///
+/// A behavioral query that fails with the extension loaded is a real error
+/// and is returned rather than papered over by the fallback.
+///
+/// Both statements must bind the same parameters in the same order, and read
+/// `events_all` only within `[start_date, end_date)`.  They are prepared with
+/// [`super::prepare_in_range`].
+pub fn prepare_with_fallback<'c>(
+    conn: &'c QueryConn<'_>,
+    site_id: &str,
+    start_date: &str,
+    end_date: &str,
+    behavioral_sql: &str,
+    fallback_sql: &str,
+) -> Result<TimedStatement<'c>, duckdb::Error> {
+    super::prepare_in_range(conn, behavioral_sql, site_id, start_date, end_date).or_else(|e| {
+        if behavioral_extension_loaded(conn) {
+            tracing::error!(error = %e, "Behavioral query failed with the extension loaded");
+            return Err(e);
+        }
+        tracing::debug!(error = %e, "Behavioral query unavailable; using SQL fallback");
+        super::prepare_in_range(conn, fallback_sql, site_id, start_date, end_date)
+    })
+}
+
+/// Whether the behavioral extension is loaded on `conn`.
+fn behavioral_extension_loaded(conn: &Connection) -> bool {
+    conn.query_row(
+        "SELECT loaded FROM duckdb_extensions() WHERE extension_name = 'behavioral'",
+        [],
+        |row| row.get(0),
+    )
+    .unwrap_or(false)
+}
+
+/// How session reports read their sessions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SessionSql {
+    /// Days with [materialized sessions](super::materialized_sessions) are
+    /// read from them, the rest sessionized from events.
+    #[default]
+    Primary,
+    /// Every day is sessionized from events, for the
+    /// [`LiveSessions`](super::shadow::Variant::LiveSessions) shadow variant.
+    Shadow,
+}
+
+/// Where [`query_session_metrics`] and the bounce rate read the sessions of
+/// `[start_date, end_date)`.
+///
+/// Returns the start of the part to sessionize from events, and the relation
+/// of [materialized sessions](super::materialized_sessions) before it, if any.
+///
+/// With [`SessionSql::Shadow`] every day is sessionized from events.
+pub fn session_sources(
     conn: &Connection,
     site_id: &str,
     start_date: &str,
     end_date: &str,
+    sql: SessionSql,
+) -> Result<(String, Option<String>), duckdb::Error> {
+    if sql == SessionSql::Shadow {
+        return Ok((start_date.to_string(), None));
+    }
+    Ok(
+        match super::materialized_sessions::stored_sessions(conn, site_id, start_date, end_date)? {
+            Some(stored) => (stored.live_start, Some(stored.source)),
+            None => (start_date.to_string(), None),
+        },
+    )
+}
+
+/// Query session metrics, using `sessionize` from the behavioral extension when
+/// loaded and the LAG-based SQL sessionization otherwise.
+///
+/// Days with [materialized sessions](super::materialized_sessions) are read
+/// from them unless `sql` is [`SessionSql::Shadow`].
+pub fn query_session_metrics(
+    conn: &QueryConn<'_>,
+    site_id: &str,
+    start_date: &str,
+    end_date: &str,
+    sql: SessionSql,
 ) -> Result<SessionMetrics, duckdb::Error> {
-    let sql = r"
-        WITH sessions AS (
-            SELECT
-                visitor_id,
-                sessionize(timestamp, INTERVAL '30 minutes') OVER (
-                    PARTITION BY visitor_id ORDER BY timestamp
-                ) AS session_id,
-                timestamp,
-                event_name
-            FROM events_all
-            WHERE site_id = ? AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
-        ),
-        session_stats AS (
+    let (live_start, stored) = session_sources(conn, site_id, start_date, end_date, sql)?;
+    let stored = stored.map_or_else(String::new, |source| {
+        format!("UNION ALL SELECT pageviews, duration_secs FROM {source}")
+    });
+    let build = |sessions_cte: &str| {
+        format!(
+            "WITH {sessions_cte},
+            session_stats AS (
+                SELECT
+                    COUNT(*) FILTER (WHERE event_name = 'pageview') AS page_count,
+                    EXTRACT(EPOCH FROM (MAX(timestamp) - MIN(timestamp))) AS duration_secs
+                FROM sessions
+                GROUP BY visitor_id, session_id
+                {stored}
+            )
             SELECT
-                visitor_id,
-                session_id,
-                COUNT(*) FILTER (WHERE event_name = 'pageview') AS page_count,
-                EXTRACT(EPOCH FROM (MAX(timestamp) - MIN(timestamp))) AS duration_secs
-            FROM sessions
-            GROUP BY visitor_id, session_id
+                COUNT(*) AS total_sessions,
+                COALESCE(AVG(duration_secs), 0) AS avg_duration,
+                COALESCE(AVG(page_count), 0) AS avg_pages
+            FROM session_stats"
         )
-        SELECT
-            COUNT(*) AS total_sessions,
-            COALESCE(AVG(duration_secs), 0) AS avg_duration,
-            COALESCE(AVG(page_count), 0) AS avg_pages
-        FROM session_stats
-    ";
-
-    let mut stmt = conn.prepare(sql)?;
-    stmt.query_row(duckdb::params![site_id, start_date, end_date], |row| {
+    };
+
+    let mut stmt = prepare_with_fallback(
+        conn,
+        site_id,
+        &live_start,
+        end_date,
+        &build(&sessions_cte_behavioral(SiteScope::of(site_id))),
+        &build(&sessions_cte_sql(SiteScope::of(site_id))),
+    )?;
+    stmt.query_row(duckdb::params![site_id, live_start, end_date], |row| {
         Ok(SessionMetrics {
             total_sessions: row.get(0)?,
             avg_session_duration_secs: row.get(1)?,
@@ -55,6 +198,96 @@ pub fn query_session_metrics(
     })
 }
 
+/// Average time visitors spent on one page.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct PageTime {
+    pub pathname: String,
+    /// Pageviews of the page.
+    pub visits: u64,
+    pub avg_time_on_page_secs: f64,
+}
+
+/// Query the average time on each page, most visited first.
+///
+/// A page visit lasts from its pageview until the visitor's next pageview in
+/// the same session or, on the last page of a session, until the visit's last
+/// event.  Heartbeat events sent while the page is open make the last page's
+/// time accurate; without them it counts as 0.
+pub fn query_time_on_page(
+    conn: &QueryConn<'_>,
+    site_id: &str,
+    start_date: &str,
+    end_date: &str,
+    limit: usize,
+) -> Result<Vec<PageTime>, duckdb::Error> {
+    let build = |sessions_cte: &str| {
+        format!(
+            "WITH {sessions_cte},
+            numbered AS (
+                SELECT
+                    visitor_id,
+                    session_id,
+                    timestamp,
+                    event_name,
+                    pathname,
+                    SUM(CASE WHEN event_name = 'pageview' THEN 1 ELSE 0 END) OVER (
+                        PARTITION BY visitor_id, session_id ORDER BY timestamp
+                        ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW
+                    ) AS page_seq
+                FROM sessions
+            ),
+            page_visits AS (
+                SELECT
+                    visitor_id,
+                    session_id,
+                    MIN(pathname) FILTER (WHERE event_name = 'pageview') AS pathname,
+                    MIN(timestamp) AS started,
+                    MAX(timestamp) AS last_event
+                FROM numbered
+                WHERE page_seq > 0
+                GROUP BY visitor_id, session_id, page_seq
+            ),
+            timed AS (
+                SELECT
+                    pathname,
+                    EXTRACT(EPOCH FROM (COALESCE(
+                        LEAD(started) OVER (PARTITION BY visitor_id, session_id ORDER BY started),
+                        last_event
+                    ) - started)) AS secs
+                FROM page_visits
+            )
+            SELECT pathname, COUNT(*) AS visits, COALESCE(AVG(secs), 0) AS avg_secs
+            FROM timed
+            GROUP BY pathname
+            ORDER BY visits DESC, pathname
+            LIMIT ?"
+        )
+    };
+
+    let mut stmt = prepare_with_fallback(
+        conn,
+        site_id,
+        start_date,
+        end_date,
+        &build(&sessions_cte_behavioral(SiteScope::of(site_id))),
+        &build(&sessions_cte_sql(SiteScope::of(site_id))),
+    )?;
+    let limit_i64 = i64::try_from(limit).unwrap_or(i64::MAX);
+    let rows = stmt
+        .query_map(
+            duckdb::params![site_id, start_date, end_date, limit_i64],
+            |row| {
+                Ok(PageTime {
+                    pathname: row.get(0)?,
+                    visits: row.get(1)?,
+                    avg_time_on_page_secs: row.get(2)?,
+                })
+            },
+        )?
+        .collect::<Result<_, _>>()?;
+    Ok(rows)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -80,12 +313,16 @@ mod tests {
     #[test]
     fn test_session_metrics_empty() {
         let conn = setup_test_db();
-        // sessionize requires the behavioral extension; the query will fail
-        // if behavioral is not available — that's expected in unit tests.
-        let result = query_session_metrics(&conn, "test.com", "2024-01-01", "2024-02-01");
-        if let Ok(metrics) = result {
-            assert_eq!(metrics.total_sessions, 0);
-        }
+        // Unit tests run without the behavioral extension, exercising the SQL fallback.
+        let metrics = query_session_metrics(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            SessionSql::Primary,
+        )
+        .unwrap();
+        assert_eq!(metrics.total_sessions, 0);
     }
 
     #[test]
@@ -94,11 +331,96 @@ mod tests {
         insert_pageview(&conn, "v1", "2024-01-15 10:00:00", "/");
         insert_pageview(&conn, "v1", "2024-01-15 10:05:00", "/about");
         insert_pageview(&conn, "v2", "2024-01-15 11:00:00", "/");
-        // Without behavioral extension, this will fail gracefully
-        let result = query_session_metrics(&conn, "test.com", "2024-01-01", "2024-02-01");
-        // We expect an error without the extension; the API handler wraps with unwrap_or
-        if let Ok(metrics) = result {
-            assert!(metrics.total_sessions > 0);
-        }
+        let metrics = query_session_metrics(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            SessionSql::Primary,
+        )
+        .unwrap();
+        assert_eq!(metrics.total_sessions, 2);
+        // v1: 300s session, v2: 0s session.
+        assert!((metrics.avg_session_duration_secs - 150.0).abs() < f64::EPSILON);
+        assert!((metrics.avg_pages_per_session - 1.5).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_sql_sessionization_splits_on_30_minute_gap() {
+        let conn = setup_test_db();
+        insert_pageview(&conn, "v1", "2024-01-15 10:00:00", "/");
+        insert_pageview(&conn, "v1", "2024-01-15 10:29:00", "/a"); // < 30 min: same session
+        insert_pageview(&conn, "v1", "2024-01-15 11:00:00", "/b"); // > 30 min: new session
+        let metrics = query_session_metrics(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            SessionSql::Primary,
+        )
+        .unwrap();
+        assert_eq!(metrics.total_sessions, 2);
+    }
+
+    fn insert_heartbeat(conn: &QueryConn<'_>, visitor_id: &str, timestamp: &str, pathname: &str) {
+        conn.execute(
+            "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+             VALUES ('test.com', ?, CAST(? AS TIMESTAMP), 'heartbeat', ?)",
+            duckdb::params![visitor_id, timestamp, pathname],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_heartbeats_extend_visit_duration() {
+        let conn = setup_test_db();
+        // A single-page visit is 0s long until heartbeats show the page open.
+        insert_pageview(&conn, "v1", "2024-01-15 10:00:00", "/");
+        insert_heartbeat(&QueryConn::new(&conn), "v1", "2024-01-15 10:00:30", "/");
+        insert_heartbeat(&QueryConn::new(&conn), "v1", "2024-01-15 10:01:00", "/");
+        let metrics = query_session_metrics(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            SessionSql::Primary,
+        )
+        .unwrap();
+        assert_eq!(metrics.total_sessions, 1);
+        assert!((metrics.avg_session_duration_secs - 60.0).abs() < f64::EPSILON);
+        assert!((metrics.avg_pages_per_session - 1.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_time_on_page() {
+        let conn = setup_test_db();
+        // v1: / for 120s, then /pricing with heartbeats for 60s.
+        insert_pageview(&conn, "v1", "2024-01-15 10:00:00", "/");
+        insert_heartbeat(&QueryConn::new(&conn), "v1", "2024-01-15 10:01:00", "/");
+        insert_pageview(&conn, "v1", "2024-01-15 10:02:00", "/pricing");
+        insert_heartbeat(
+            &QueryConn::new(&conn),
+            "v1",
+            "2024-01-15 10:03:00",
+            "/pricing",
+        );
+        // v2: / with no further events, 0s.
+        insert_pageview(&conn, "v2", "2024-01-15 11:00:00", "/");
+
+        let pages = query_time_on_page(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-01",
+            "2024-02-01",
+            10,
+        )
+        .unwrap();
+
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].pathname, "/");
+        assert_eq!(pages[0].visits, 2);
+        assert!((pages[0].avg_time_on_page_secs - 60.0).abs() < f64::EPSILON);
+        assert_eq!(pages[1].pathname, "/pricing");
+        assert!((pages[1].avg_time_on_page_secs - 60.0).abs() < f64::EPSILON);
     }
 }
@@ -0,0 +1,282 @@
+//! Shadow queries.
+//!
+//! A stats report listed in `[shadow_queries] reports` is, after being
+//! served, run once more with an alternative implementation ([`Variant`])
+//! passed to its queries.  Its result is compared with the
+//! response already served, and mismatches are logged at warn level and kept
+//! in the server's [`ShadowQueries`] for `GET /api/admin/shadow-queries`
+//! along with run counts and timings, so a redesigned query can be checked
+//! against production data before it replaces the current one.
+//!
+//! Results are compared as JSON, with floats equal when within a relative
+//! tolerance, since a different plan may sum in a different order.  Events
+//! arriving between the response and the shadow run, or a response served
+//! from the query cache, can also make them differ.
+
+use super::sessions::SessionSql;
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Mismatches kept for the admin endpoint.
+const MAX_MISMATCHES: usize = 100;
+
+/// Relative difference under which two floats are considered equal.
+const FLOAT_TOLERANCE: f64 = 1e-9;
+
+/// An alternative query implementation a shadow run switches on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Variant {
+    /// Sessionize every day of the range from events instead of reading
+    /// [materialized sessions](super::materialized_sessions).
+    #[default]
+    LiveSessions,
+}
+
+impl Variant {
+    /// Stats endpoints whose result the variant changes how to compute.
+    pub const fn reports(self) -> &'static [&'static str] {
+        match self {
+            Self::LiveSessions => &["main", "sessions"],
+        }
+    }
+
+    /// How the variant's reports read sessions.
+    pub const fn session_sql(self) -> SessionSql {
+        match self {
+            Self::LiveSessions => SessionSql::Shadow,
+        }
+    }
+}
+
+/// Comparison counters of one report.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ShadowCounts {
+    /// Comparisons made.
+    pub runs: u64,
+    /// Comparisons whose results differed.
+    pub mismatches: u64,
+    /// Runs where either implementation failed.
+    pub errors: u64,
+    /// Total time of serving the report, in milliseconds.
+    pub primary_ms: u64,
+    /// Total time of the alternative implementation, in milliseconds.
+    pub shadow_ms: u64,
+}
+
+/// A report whose two implementations disagreed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ShadowMismatch {
+    pub at: DateTime<Utc>,
+    /// Stats endpoint, e.g. `main` or `breakdown/pages`.
+    pub report: String,
+    pub site_id: String,
+    /// JSON pointer to the first value that differs, `""` for the whole
+    /// result.
+    pub path: String,
+}
+
+/// Shadow runs of one server: which reports are shadowed, and their
+/// counters and mismatches since startup.
+pub struct ShadowQueries {
+    reports: Vec<String>,
+    variant: Variant,
+    sample_every: u64,
+    /// Requests seen for shadowed reports, for sampling.
+    seen: AtomicU64,
+    /// Counters per report.
+    counts: Mutex<BTreeMap<String, ShadowCounts>>,
+    /// Most recent mismatches, oldest first.
+    mismatches: Mutex<VecDeque<ShadowMismatch>>,
+}
+
+impl Default for ShadowQueries {
+    fn default() -> Self {
+        Self::new(Vec::new(), Variant::default(), 1)
+    }
+}
+
+impl ShadowQueries {
+    /// Shadow `reports` (stats endpoints such as `main`) with `variant`, on
+    /// one in `sample_every` of their requests.
+    pub fn new(reports: Vec<String>, variant: Variant, sample_every: u64) -> Self {
+        Self {
+            reports,
+            variant,
+            sample_every: sample_every.max(1),
+            seen: AtomicU64::new(0),
+            counts: Mutex::new(BTreeMap::new()),
+            mismatches: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// The variant to shadow `report` with on this request, if it is
+    /// shadowed and sampled.
+    pub fn sample(&self, report: &str) -> Option<Variant> {
+        if !self.reports.iter().any(|r| r == report) {
+            return None;
+        }
+        let seen = self.seen.fetch_add(1, Ordering::Relaxed);
+        seen.is_multiple_of(self.sample_every)
+            .then_some(self.variant)
+    }
+
+    /// The configured variant.
+    pub const fn variant(&self) -> Variant {
+        self.variant
+    }
+
+    /// Record the outcome of serving `report` for `site_id` and of its
+    /// shadow run, logging a mismatch.
+    pub fn record(
+        &self,
+        report: &str,
+        site_id: &str,
+        served: (&Value, Duration),
+        shadow: (Result<Value, duckdb::Error>, Duration),
+    ) {
+        let millis = |d: Duration| u64::try_from(d.as_millis()).unwrap_or(u64::MAX);
+        let difference = match shadow.0 {
+            Ok(result) => first_difference(served.0, &result).map(Ok),
+            Err(e) => Some(Err(e.to_string())),
+        };
+        let mut all_counts = self.counts.lock();
+        let counts = all_counts.entry(report.to_string()).or_default();
+        counts.runs += 1;
+        counts.primary_ms = counts.primary_ms.saturating_add(millis(served.1));
+        counts.shadow_ms = counts.shadow_ms.saturating_add(millis(shadow.1));
+        match &difference {
+            Some(Ok(_)) => counts.mismatches += 1,
+            Some(Err(_)) => counts.errors += 1,
+            None => {}
+        }
+        drop(all_counts);
+        match difference {
+            None => {}
+            Some(Err(error)) => {
+                tracing::warn!(report, site_id, %error, "Shadow query failed");
+            }
+            Some(Ok(path)) => {
+                tracing::warn!(report, site_id, path = %path, "Shadow query result differs");
+                let mut mismatches = self.mismatches.lock();
+                if mismatches.len() >= MAX_MISMATCHES {
+                    mismatches.pop_front();
+                }
+                mismatches.push_back(ShadowMismatch {
+                    at: Utc::now(),
+                    report: report.to_string(),
+                    site_id: site_id.to_string(),
+                    path,
+                });
+            }
+        }
+    }
+
+    /// Counters of every report compared so far.
+    pub fn counts(&self) -> BTreeMap<String, ShadowCounts> {
+        self.counts.lock().clone()
+    }
+
+    /// The kept mismatches, most recent first.
+    pub fn recent_mismatches(&self) -> Vec<ShadowMismatch> {
+        self.mismatches.lock().iter().rev().cloned().collect()
+    }
+}
+
+/// JSON pointer to the first place where `a` and `b` differ, or `None` when
+/// they match.
+pub fn first_difference(a: &Value, b: &Value) -> Option<String> {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => match (x.as_f64(), y.as_f64()) {
+            _ if x == y => None,
+            (Some(x), Some(y)) if (x - y).abs() <= FLOAT_TOLERANCE * x.abs().max(y.abs()) => None,
+            _ => Some(String::new()),
+        },
+        (Value::Array(xs), Value::Array(ys)) => {
+            if xs.len() != ys.len() {
+                return Some(String::new());
+            }
+            xs.iter()
+                .zip(ys)
+                .enumerate()
+                .find_map(|(i, (x, y))| first_difference(x, y).map(|path| format!("/{i}{path}")))
+        }
+        (Value::Object(xs), Value::Object(ys)) => {
+            if xs.len() != ys.len() {
+                return Some(String::new());
+            }
+            xs.iter().find_map(|(key, x)| {
+                ys.get(key).map_or_else(
+                    || Some(format!("/{key}")),
+                    |y| first_difference(x, y).map(|path| format!("/{key}{path}")),
+                )
+            })
+        }
+        _ => (a != b).then(String::new),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_first_difference() {
+        let a = json!([{"value": "/", "visitors": 2, "bounce_rate": 0.1 + 0.2}]);
+        assert_eq!(first_difference(&a, &a), None);
+        assert_eq!(
+            first_difference(
+                &a,
+                &json!([{"value": "/", "visitors": 2, "bounce_rate": 0.3}])
+            ),
+            None
+        );
+        assert_eq!(
+            first_difference(
+                &a,
+                &json!([{"value": "/", "visitors": 3, "bounce_rate": 0.3}])
+            ),
+            Some("/0/visitors".to_string())
+        );
+        assert_eq!(first_difference(&a, &json!([])), Some(String::new()));
+        assert_eq!(
+            first_difference(&json!({"a": 1}), &json!({"b": 1})),
+            Some("/a".to_string())
+        );
+    }
+
+    #[test]
+    fn test_record_counts_mismatches() {
+        let shadow = ShadowQueries::default();
+        let ms = Duration::from_millis(3);
+        shadow.record("test/match", "a.com", (&json!(1), ms), (Ok(json!(1)), ms));
+        shadow.record(
+            "test/mismatch",
+            "a.com",
+            (&json!(1), ms),
+            (Ok(json!(2)), ms),
+        );
+        let counts = shadow.counts();
+        assert_eq!(counts["test/match"].runs, 1);
+        assert_eq!(counts["test/match"].mismatches, 0);
+        assert_eq!(counts["test/match"].primary_ms, 3);
+        assert_eq!(counts["test/mismatch"].mismatches, 1);
+        assert_eq!(shadow.recent_mismatches().len(), 1);
+        assert!(shadow.recent_mismatches()[0].path.is_empty());
+    }
+
+    #[test]
+    fn test_sample() {
+        let shadow = ShadowQueries::new(vec!["main".to_string()], Variant::LiveSessions, 2);
+        assert_eq!(shadow.sample("sessions"), None);
+        assert_eq!(shadow.sample("main"), Some(Variant::LiveSessions));
+        assert_eq!(shadow.sample("main"), None);
+        assert_eq!(shadow.sample("main"), Some(Variant::LiveSessions));
+    }
+}
@@ -0,0 +1,305 @@
+//! Slow query log.
+//!
+//! Analytics queries are prepared through [`super::prepare`] and its
+//! variants, which hand out a [`TimedStatement`]: the statement plus a clock
+//! started before preparing it.  When the statement is dropped, once its rows
+//! have been read, the elapsed time is compared with the threshold of the
+//! server's [`SlowQueryLog`].  Queries at or over it are logged at warn level
+//! with their SQL shape and site, and the most recent ones are kept for
+//! `GET /api/admin/slow-queries`.
+//!
+//! Each server has its own log.  Statements are timed into the log of the
+//! [`QueryConn`](super::QueryConn) they are prepared on: one
+//! [locked](super::QueryConn::lock) with a server's log times into it, and
+//! statements prepared on one without a log are not timed.
+//!
+//! The shape is the SQL with string and number literals replaced by `?` and
+//! whitespace collapsed, so entries never carry the values a query was run
+//! with and repeats of one query look alike.
+
+use super::explain::Explain;
+use chrono::{DateTime, Utc};
+use duckdb::{Connection, MappedRows, Params, Row, Statement};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::ops::{Deref, DerefMut};
+use std::time::{Duration, Instant};
+
+/// Longest SQL shape kept; longer ones are cut at a character boundary.
+const MAX_SHAPE_LEN: usize = 4096;
+
+/// A query that took at least the threshold.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SlowQuery {
+    /// When the query finished.
+    pub finished_at: DateTime<Utc>,
+    pub duration_ms: u64,
+    /// Site the query was scoped to: a site ID, `@<group>`, or `__all__`.
+    pub site_id: String,
+    /// The SQL with literals replaced by `?`.
+    pub sql: String,
+}
+
+/// The slow queries of one server.
+pub struct SlowQueryLog {
+    /// Threshold in milliseconds; 0 disables the log.
+    threshold_ms: u64,
+    /// Number of entries kept.
+    capacity: usize,
+    /// Most recent slow queries, oldest first.
+    recent: Mutex<VecDeque<SlowQuery>>,
+}
+
+impl Default for SlowQueryLog {
+    fn default() -> Self {
+        Self::new(1000, 100)
+    }
+}
+
+impl SlowQueryLog {
+    /// Log queries taking at least `threshold_ms` (0 = never) and keep the
+    /// last `capacity` of them.
+    pub const fn new(threshold_ms: u64, capacity: usize) -> Self {
+        Self {
+            threshold_ms,
+            capacity,
+            recent: parking_lot::const_mutex(VecDeque::new()),
+        }
+    }
+
+    /// Threshold in milliseconds; 0 when the log is disabled.
+    pub const fn threshold_ms(&self) -> u64 {
+        self.threshold_ms
+    }
+
+    /// The kept slow queries, most recent first.
+    pub fn recent(&self) -> Vec<SlowQuery> {
+        self.recent.lock().iter().rev().cloned().collect()
+    }
+
+    /// Record that `sql`, scoped to `site_id`, took `elapsed`.
+    pub fn record(&self, sql: &str, site_id: &str, elapsed: Duration) {
+        let duration_ms = u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX);
+        if self.threshold_ms == 0 || duration_ms < self.threshold_ms || self.capacity == 0 {
+            return;
+        }
+        let entry = SlowQuery {
+            finished_at: Utc::now(),
+            duration_ms,
+            site_id: site_id.to_string(),
+            sql: shape(sql),
+        };
+        tracing::warn!(
+            duration_ms,
+            site_id = %entry.site_id,
+            sql = %entry.sql,
+            "Slow query"
+        );
+        let mut recent = self.recent.lock();
+        if recent.len() >= self.capacity {
+            recent.pop_front();
+        }
+        recent.push_back(entry);
+    }
+}
+
+/// A prepared statement that records how long it was in use into a
+/// [`SlowQueryLog`] when dropped.
+///
+/// When its report is being [explained](super::explain), its `query_map` and
+/// `query_row` also explain the statement before running it.
+pub struct TimedStatement<'c> {
+    stmt: Statement<'c>,
+    sql: String,
+    site_id: String,
+    started: Instant,
+    /// Log to record into; `None` when not timed.
+    log: Option<&'c SlowQueryLog>,
+    /// Where to keep the plan, and the connection and SQL as prepared; kept
+    /// only while explaining.
+    explain: Option<(&'c Explain, &'c Connection, String)>,
+}
+
+impl<'c> TimedStatement<'c> {
+    /// `stmt`, prepared from `sql` for `site_id` after `started`, timed into
+    /// `log` if any.  `sql` is the query as written, before site scoping and
+    /// range pruning.
+    pub fn new(
+        stmt: Statement<'c>,
+        sql: &str,
+        site_id: &str,
+        started: Instant,
+        log: Option<&'c SlowQueryLog>,
+    ) -> Self {
+        Self {
+            stmt,
+            sql: sql.to_string(),
+            site_id: site_id.to_string(),
+            started,
+            log,
+            explain: None,
+        }
+    }
+
+    /// Explain this statement, prepared on `conn` as `prepared`, into
+    /// `explain` when it runs.
+    #[must_use]
+    pub fn explained_as(
+        mut self,
+        explain: &'c Explain,
+        conn: &'c Connection,
+        prepared: &str,
+    ) -> Self {
+        self.explain = Some((explain, conn, prepared.to_string()));
+        self
+    }
+
+    /// [`Statement::query_map`], explaining the statement first if asked to.
+    pub fn query_map<T, P, F>(&mut self, params: P, f: F) -> duckdb::Result<MappedRows<'_, F>>
+    where
+        P: Params + Copy,
+        F: FnMut(&Row<'_>) -> duckdb::Result<T>,
+    {
+        if let Some((explain, conn, prepared)) = &self.explain {
+            explain.record(conn, prepared, params)?;
+        }
+        self.stmt.query_map(params, f)
+    }
+
+    /// [`Statement::query_row`], explaining the statement first if asked to.
+    pub fn query_row<T, P, F>(&mut self, params: P, f: F) -> duckdb::Result<T>
+    where
+        P: Params + Copy,
+        F: FnOnce(&Row<'_>) -> duckdb::Result<T>,
+    {
+        if let Some((explain, conn, prepared)) = &self.explain {
+            explain.record(conn, prepared, params)?;
+        }
+        self.stmt.query_row(params, f)
+    }
+}
+
+impl<'c> Deref for TimedStatement<'c> {
+    type Target = Statement<'c>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.stmt
+    }
+}
+
+impl DerefMut for TimedStatement<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.stmt
+    }
+}
+
+impl Drop for TimedStatement<'_> {
+    fn drop(&mut self) {
+        if let Some(log) = self.log {
+            log.record(&self.sql, &self.site_id, self.started.elapsed());
+        }
+    }
+}
+
+/// `sql` with string and number literals replaced by `?` and runs of
+/// whitespace collapsed to one space.
+pub fn shape(sql: &str) -> String {
+    let mut out = String::with_capacity(sql.len().min(MAX_SHAPE_LEN));
+    let mut chars = sql.trim().chars().peekable();
+    while let Some(c) = chars.next() {
+        if out.len() >= MAX_SHAPE_LEN {
+            out.push_str("...");
+            break;
+        }
+        if c.is_whitespace() {
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            out.push(' ');
+        } else if c == '\'' {
+            // A string literal; '' is an escaped quote inside it.
+            while let Some(c) = chars.next() {
+                if c == '\'' && chars.next_if_eq(&'\'').is_none() {
+                    break;
+                }
+            }
+            out.push('?');
+        } else if c.is_ascii_digit()
+            && !out
+                .chars()
+                .next_back()
+                .is_some_and(|p| p.is_alphanumeric() || p == '_')
+        {
+            while chars.next_if(|c| c.is_ascii_digit() || *c == '.').is_some() {}
+            out.push('?');
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::QueryConn;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_shape() {
+        assert_eq!(
+            shape(
+                "SELECT  pathname, COUNT(*)\n   FROM events_all\n WHERE site_id = 'a.com' AND name <> 'it''s'\n LIMIT 10"
+            ),
+            "SELECT pathname, COUNT(*) FROM events_all WHERE site_id = ? AND name <> ? LIMIT ?"
+        );
+        assert_eq!(
+            shape("SELECT utm_v2, x * 1.5 FROM t1"),
+            "SELECT utm_v2, x * ? FROM t1"
+        );
+        assert!(shape(&"x ".repeat(5000)).ends_with("..."));
+    }
+
+    #[test]
+    fn test_slow_queries_kept() {
+        let log = SlowQueryLog::new(60_000, 2);
+        log.record("SELECT 1", "a.com", Duration::from_secs(10));
+        for site in ["a.com", "b.com", "c.com"] {
+            log.record(
+                "SELECT * FROM events_all WHERE site_id = ?",
+                site,
+                Duration::from_secs(75),
+            );
+        }
+        let recent = log.recent();
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].site_id, "c.com");
+        assert_eq!(recent[1].site_id, "b.com");
+        assert_eq!(recent[0].duration_ms, 75_000);
+        assert_eq!(recent[0].sql, "SELECT * FROM events_all WHERE site_id = ?");
+
+        let disabled = SlowQueryLog::new(0, 2);
+        disabled.record("SELECT 1", "d.com", Duration::from_secs(100));
+        assert!(disabled.recent().is_empty());
+    }
+
+    #[test]
+    fn test_statements_timed_into_locked_connection_log() {
+        let conn = Mutex::new(Connection::open_in_memory().unwrap());
+        let first = Arc::new(SlowQueryLog::new(1, 10));
+        let second = Arc::new(SlowQueryLog::new(1, 10));
+        let run = |conn: &QueryConn<'_>, sql: &str| {
+            let started = Instant::now().checked_sub(Duration::from_secs(1)).unwrap();
+            drop(conn.prepare_timed(sql, sql, "a.com", started).unwrap());
+        };
+
+        run(&QueryConn::lock(&conn, &first), "SELECT 1");
+        run(&QueryConn::lock(&conn, &second), "SELECT 2");
+        // Without a log: not timed.
+        run(&QueryConn::new(&conn.lock()), "SELECT 3");
+
+        let sql = |log: &SlowQueryLog| log.recent().into_iter().map(|q| q.sql).collect::<Vec<_>>();
+        assert_eq!(sql(&first), ["SELECT ?"]);
+        assert_eq!(sql(&second), ["SELECT ?"]);
+        assert_eq!(first.recent()[0].duration_ms / 1000, 1);
+    }
+}
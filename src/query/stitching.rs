@@ -0,0 +1,119 @@
+use duckdb::Connection;
+
+/// Record which user each visitor seen with a `user_id` since `since` belongs
+/// to, in the `visitor_users` table.
+///
+/// A visitor seen with several users (a shared device) is mapped to the most
+/// recent one.  Returns the number of mappings written.
+pub fn stitch_visitors(conn: &Connection, since: &str) -> Result<usize, duckdb::Error> {
+    conn.execute(
+        "INSERT OR REPLACE INTO visitor_users (site_id, visitor_id, user_id, last_seen)
+         SELECT site_id, visitor_id, arg_max(user_id, timestamp), MAX(timestamp)
+         FROM events_all
+         WHERE user_id IS NOT NULL AND timestamp >= CAST(? AS TIMESTAMP)
+         GROUP BY site_id, visitor_id",
+        [since],
+    )
+}
+
+/// Drop mappings last seen before `before`, so the table does not outlive the
+/// events it was built from.
+pub fn prune_visitor_users(conn: &Connection, before: &str) -> Result<usize, duckdb::Error> {
+    conn.execute(
+        "DELETE FROM visitor_users WHERE last_seen < CAST(? AS TIMESTAMP)",
+        [before],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::{Identity, QueryConn};
+
+    fn setup_test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::storage::schema::init_schema(&conn).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        crate::storage::schema::setup_query_view(&conn, dir.path()).unwrap();
+        drop(dir);
+        conn
+    }
+
+    fn insert_event(conn: &Connection, visitor_id: &str, user_id: Option<&str>, timestamp: &str) {
+        conn.execute(
+            "INSERT INTO events (site_id, visitor_id, user_id, timestamp, event_name, pathname)
+             VALUES ('test.com', ?, ?, ?, 'pageview', '/')",
+            duckdb::params![visitor_id, user_id, timestamp],
+        )
+        .unwrap();
+    }
+
+    fn mapping(conn: &Connection, visitor_id: &str) -> Option<String> {
+        conn.query_row(
+            "SELECT user_id FROM visitor_users WHERE visitor_id = ?",
+            [visitor_id],
+            |row| row.get(0),
+        )
+        .ok()
+    }
+
+    #[test]
+    fn test_stitch_visitors() {
+        let conn = setup_test_db();
+        // v1 browses anonymously, then logs in as u1; a shared device v2 is
+        // used by u1 and later u2; v3 never logs in.
+        insert_event(&conn, "v1", None, "2024-01-15 09:00:00");
+        insert_event(&conn, "v1", Some("u1"), "2024-01-15 09:10:00");
+        insert_event(&conn, "v2", Some("u1"), "2024-01-15 10:00:00");
+        insert_event(&conn, "v2", Some("u2"), "2024-01-15 11:00:00");
+        insert_event(&conn, "v3", None, "2024-01-15 12:00:00");
+
+        assert_eq!(stitch_visitors(&conn, "2024-01-15").unwrap(), 2);
+        assert_eq!(mapping(&conn, "v1").as_deref(), Some("u1"));
+        assert_eq!(mapping(&conn, "v2").as_deref(), Some("u2"));
+        assert_eq!(mapping(&conn, "v3"), None);
+
+        // Re-running is idempotent.
+        stitch_visitors(&conn, "2024-01-15").unwrap();
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM visitor_users", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+
+        assert_eq!(
+            prune_visitor_users(&conn, "2024-01-15 10:30:00").unwrap(),
+            1
+        );
+        assert_eq!(mapping(&conn, "v1"), None);
+    }
+
+    #[test]
+    fn test_stitched_retention_counts_anonymous_events() {
+        let conn = setup_test_db();
+        // u1 browses anonymously as v1 before logging in, then returns on a
+        // second device the next day.
+        insert_event(&conn, "v1", None, "2024-01-01 09:00:00");
+        insert_event(&conn, "v1", Some("u1"), "2024-01-01 09:30:00");
+        insert_event(&conn, "v2", Some("u1"), "2024-01-02 09:00:00");
+        stitch_visitors(&conn, "2024-01-01").unwrap();
+
+        let query = |identity| {
+            crate::query::retention::query_retention(
+                &QueryConn::new(&conn),
+                "test.com",
+                "2024-01-01",
+                "2024-01-03",
+                crate::query::retention::CohortGranularity::Day,
+                2,
+                identity,
+            )
+            .unwrap()
+        };
+        // Without stitching the anonymous event is a separate cohort member.
+        assert_eq!(query(Identity::User)[0].cohort_size, 2);
+        let cohorts = query(Identity::Stitched);
+        assert_eq!(cohorts.len(), 1);
+        assert_eq!(cohorts[0].cohort_size, 1);
+        assert_eq!(cohorts[0].counts, vec![1, 1]);
+    }
+}
@@ -1,7 +1,7 @@
-use duckdb::Connection;
+use super::{QueryConn, SiteScope};
 
 /// A single time bucket with visitor and pageview counts.
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
 pub struct TimeBucket {
     pub date: String,
     pub visitors: u64,
@@ -33,12 +33,13 @@ impl Granularity {
 
 /// Query time-series data for a site within a date range.
 pub fn query_timeseries(
-    conn: &Connection,
+    conn: &QueryConn<'_>,
     site_id: &str,
     start_date: &str,
     end_date: &str,
     granularity: Granularity,
 ) -> Result<Vec<TimeBucket>, duckdb::Error> {
+    let site = SiteScope::of(site_id).filter("site_id");
     let trunc = granularity.trunc_unit();
     let fmt = granularity.format_str();
 
@@ -47,12 +48,12 @@ pub fn query_timeseries(
                 COUNT(DISTINCT visitor_id) AS visitors,
                 COUNT(*) FILTER (WHERE event_name = 'pageview') AS pageviews
          FROM events_all
-         WHERE site_id = ? AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
+         WHERE {site} AND timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
          GROUP BY bucket
          ORDER BY bucket"
     );
 
-    let mut stmt = conn.prepare(&sql)?;
+    let mut stmt = super::prepare_in_range(conn, &sql, site_id, start_date, end_date)?;
     let rows = stmt
         .query_map(duckdb::params![site_id, start_date, end_date], |row| {
             Ok(TimeBucket {
@@ -61,8 +62,7 @@ pub fn query_timeseries(
                 pageviews: row.get(2)?,
             })
         })?
-        .filter_map(Result::ok)
-        .collect();
+        .collect::<Result<_, _>>()?;
 
     Ok(rows)
 }
@@ -70,6 +70,7 @@ pub fn query_timeseries(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use duckdb::Connection;
 
     fn setup_test_db() -> Connection {
         let conn = Connection::open_in_memory().unwrap();
@@ -89,6 +90,50 @@ mod tests {
         .unwrap();
     }
 
+    #[test]
+    fn test_timeseries_reads_only_files_in_range() {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::storage::schema::init_schema(&conn).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        crate::storage::schema::setup_query_view(&conn, dir.path()).unwrap();
+        let storage = crate::storage::parquet::ParquetStorage::new(dir.path());
+        insert_pageview(&conn, "2024-01-15 10:00:00");
+        insert_pageview(&conn, "2024-01-16 10:00:00");
+        storage.flush_events(&conn).unwrap();
+        // Still buffered: the hot tier is always read.
+        insert_pageview(&conn, "2024-01-16 11:00:00");
+
+        let count_sql = "SELECT COUNT(*) FROM events_all";
+        let sql =
+            crate::query::prune_to_range(&conn, count_sql, "2024-01-16", "2024-01-17").unwrap();
+        assert!(sql.contains("date=2024-01-16"));
+        assert!(!sql.contains("date=2024-01-15"));
+        let count: i64 = conn.query_row(&sql, [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 2);
+
+        // An existing WITH clause is kept.
+        let sql = crate::query::prune_to_range(
+            &conn,
+            "WITH one AS (SELECT 1 AS n) SELECT COUNT(*) FROM events_all, one",
+            "2024-01-16",
+            "2024-01-17",
+        )
+        .unwrap();
+        let count: i64 = conn.query_row(&sql, [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 2);
+
+        let buckets = query_timeseries(
+            &QueryConn::new(&conn),
+            "test.com",
+            "2024-01-16",
+            "2024-01-17",
+            Granularity::Day,
+        )
+        .unwrap();
+        assert_eq!(buckets.len(), 1);
+        assert_eq!(buckets[0].pageviews, 2);
+    }
+
     #[test]
     fn test_daily_timeseries() {
         let conn = setup_test_db();
@@ -97,7 +142,7 @@ mod tests {
         insert_pageview(&conn, "2024-01-16 10:00:00");
 
         let buckets = query_timeseries(
-            &conn,
+            &QueryConn::new(&conn),
             "test.com",
             "2024-01-01",
             "2024-02-01",
@@ -120,7 +165,7 @@ mod tests {
         insert_pageview(&conn, "2024-01-15 14:00:00");
 
         let buckets = query_timeseries(
-            &conn,
+            &QueryConn::new(&conn),
             "test.com",
             "2024-01-01",
             "2024-02-01",
@@ -138,7 +183,7 @@ mod tests {
     fn test_empty_timeseries() {
         let conn = setup_test_db();
         let buckets = query_timeseries(
-            &conn,
+            &QueryConn::new(&conn),
             "test.com",
             "2024-01-01",
             "2024-02-01",
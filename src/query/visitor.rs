@@ -0,0 +1,356 @@
+use super::{QueryConn, SiteScope};
+
+/// Events numbered into 30-minute inactivity sessions, for one visitor of the
+/// sites in `scope`.
+///
+/// Binds two parameters: site_id, visitor_id.
+fn visitor_events_cte(scope: SiteScope) -> String {
+    let site = scope.filter("site_id");
+    format!(
+        "
+    marks AS (
+        SELECT *,
+            CASE
+                WHEN timestamp - LAG(timestamp) OVER (ORDER BY timestamp)
+                    <= INTERVAL '30 minutes' THEN 0
+                ELSE 1
+            END AS new_session
+        FROM events_all
+        WHERE {site} AND visitor_id = ?
+    ),
+    numbered AS (
+        SELECT *,
+            SUM(new_session) OVER (
+                ORDER BY timestamp ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW
+            ) AS session
+        FROM marks
+    )"
+    )
+}
+
+/// One event in a visitor's timeline.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct VisitorEvent {
+    pub timestamp: String,
+    /// 1-based number of the session the event belongs to.
+    pub session: u64,
+    pub event_name: String,
+    pub pathname: String,
+    pub referrer_source: Option<String>,
+    pub utm_source: Option<String>,
+    pub utm_medium: Option<String>,
+    pub utm_campaign: Option<String>,
+    pub browser: Option<String>,
+    pub os: Option<String>,
+    pub device_type: Option<String>,
+    pub country_code: Option<String>,
+    pub props: Option<String>,
+    pub revenue_amount: Option<f64>,
+    pub revenue_currency: Option<String>,
+}
+
+/// One 30-minute inactivity session of a visitor.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct VisitorSession {
+    pub session: u64,
+    pub start: String,
+    pub end: String,
+    pub duration_secs: f64,
+    pub events: u64,
+    pub pageviews: u64,
+    pub entry_page: String,
+    pub exit_page: String,
+}
+
+/// Where the visitor first came from.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct VisitorAttribution {
+    pub referrer_source: Option<String>,
+    pub utm_source: Option<String>,
+    pub utm_medium: Option<String>,
+    pub utm_campaign: Option<String>,
+    pub landing_page: String,
+}
+
+/// Everything stored about one visitor of one site.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct VisitorProfile {
+    pub site_id: String,
+    pub visitor_id: String,
+    pub first_seen: String,
+    pub last_seen: String,
+    pub total_events: u64,
+    /// First-touch attribution, from the visitor's earliest event.
+    pub attribution: VisitorAttribution,
+    pub sessions: Vec<VisitorSession>,
+    /// The most recent `event_limit` events, oldest first.
+    pub events: Vec<VisitorEvent>,
+    /// Whether older events were left out of `events`.
+    pub events_truncated: bool,
+}
+
+/// Build the profile of `visitor_id` on `site_id` from every retained event.
+///
+/// Returns `None` when the visitor has no events.  Visitor IDs rotate daily
+/// unless the salt is fixed, so a profile normally spans at most one day.
+pub fn query_visitor_profile(
+    conn: &QueryConn<'_>,
+    site_id: &str,
+    visitor_id: &str,
+    event_limit: usize,
+) -> Result<Option<VisitorProfile>, duckdb::Error> {
+    let scope = SiteScope::of(site_id);
+    let site = scope.filter("site_id");
+    let visitor_events = visitor_events_cte(scope);
+    let mut stmt = super::prepare(
+        conn,
+        &format!(
+            "WITH {visitor_events}
+         SELECT CAST(session AS UBIGINT),
+                strftime(MIN(timestamp), '%Y-%m-%d %H:%M:%S'),
+                strftime(MAX(timestamp), '%Y-%m-%d %H:%M:%S'),
+                CAST(epoch(MAX(timestamp)) - epoch(MIN(timestamp)) AS DOUBLE),
+                COUNT(*),
+                COUNT(*) FILTER (WHERE event_name = 'pageview'),
+                arg_min(pathname, timestamp),
+                arg_max(pathname, timestamp)
+         FROM numbered
+         GROUP BY session
+         ORDER BY session"
+        ),
+        site_id,
+    )?;
+    let sessions: Vec<VisitorSession> = stmt
+        .query_map(duckdb::params![site_id, visitor_id], |row| {
+            Ok(VisitorSession {
+                session: row.get(0)?,
+                start: row.get(1)?,
+                end: row.get(2)?,
+                duration_secs: row.get(3)?,
+                events: row.get(4)?,
+                pageviews: row.get(5)?,
+                entry_page: row.get(6)?,
+                exit_page: row.get(7)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    // Stop its clock before the next queries run.
+    drop(stmt);
+    let (Some(first), Some(last)) = (sessions.first(), sessions.last()) else {
+        return Ok(None);
+    };
+    let first_seen = first.start.clone();
+    let last_seen = last.end.clone();
+    let total_events = sessions.iter().map(|s| s.events).sum::<u64>();
+
+    let attribution = super::prepare(
+        conn,
+        &format!(
+            "SELECT referrer_source, utm_source, utm_medium, utm_campaign, pathname
+             FROM events_all
+             WHERE {site} AND visitor_id = ?
+             ORDER BY timestamp
+             LIMIT 1"
+        ),
+        site_id,
+    )?
+    .query_row(duckdb::params![site_id, visitor_id], |row| {
+        Ok(VisitorAttribution {
+            referrer_source: row.get(0)?,
+            utm_source: row.get(1)?,
+            utm_medium: row.get(2)?,
+            utm_campaign: row.get(3)?,
+            landing_page: row.get(4)?,
+        })
+    })?;
+
+    let events = recent_events(conn, site_id, visitor_id, &visitor_events, event_limit)?;
+
+    Ok(Some(VisitorProfile {
+        site_id: site_id.to_string(),
+        visitor_id: visitor_id.to_string(),
+        first_seen,
+        last_seen,
+        total_events,
+        attribution,
+        events_truncated: (events.len() as u64) < total_events,
+        sessions,
+        events,
+    }))
+}
+
+/// The last `event_limit` events of `visitor_id`, oldest first.
+fn recent_events(
+    conn: &QueryConn<'_>,
+    site_id: &str,
+    visitor_id: &str,
+    visitor_events: &str,
+    event_limit: usize,
+) -> Result<Vec<VisitorEvent>, duckdb::Error> {
+    let mut stmt = super::prepare(
+        conn,
+        &format!(
+            "WITH {visitor_events}
+         SELECT strftime(timestamp, '%Y-%m-%d %H:%M:%S'), CAST(session AS UBIGINT),
+                event_name, pathname, referrer_source, utm_source, utm_medium,
+                utm_campaign, browser, os, device_type, country_code, props,
+                CAST(revenue_amount AS DOUBLE), revenue_currency
+         FROM numbered
+         ORDER BY timestamp DESC
+         LIMIT ?"
+        ),
+        site_id,
+    )?;
+    let limit_i64 = i64::try_from(event_limit).unwrap_or(i64::MAX);
+    let mut events: Vec<VisitorEvent> = stmt
+        .query_map(duckdb::params![site_id, visitor_id, limit_i64], |row| {
+            Ok(VisitorEvent {
+                timestamp: row.get(0)?,
+                session: row.get(1)?,
+                event_name: row.get(2)?,
+                pathname: row.get(3)?,
+                referrer_source: row.get(4)?,
+                utm_source: row.get(5)?,
+                utm_medium: row.get(6)?,
+                utm_campaign: row.get(7)?,
+                browser: row.get(8)?,
+                os: row.get(9)?,
+                device_type: row.get(10)?,
+                country_code: row.get(11)?,
+                props: row.get(12)?,
+                revenue_amount: row.get(13)?,
+                revenue_currency: row.get(14)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    events.reverse();
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use duckdb::Connection;
+
+    fn setup_test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::storage::schema::init_schema(&conn).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        crate::storage::schema::setup_query_view(&conn, dir.path()).unwrap();
+        drop(dir);
+        conn
+    }
+
+    fn insert(
+        conn: &Connection,
+        vid: &str,
+        ts: &str,
+        name: &str,
+        path: &str,
+        source: Option<&str>,
+    ) {
+        conn.execute(
+            "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname, referrer_source)
+             VALUES ('test.com', ?, CAST(? AS TIMESTAMP), ?, ?, ?)",
+            duckdb::params![vid, ts, name, path, source],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_visitor_profile() {
+        let conn = setup_test_db();
+        insert(
+            &conn,
+            "v1",
+            "2024-01-15 10:00:00",
+            "pageview",
+            "/",
+            Some("Google"),
+        );
+        insert(
+            &conn,
+            "v1",
+            "2024-01-15 10:05:00",
+            "pageview",
+            "/pricing",
+            None,
+        );
+        insert(
+            &conn,
+            "v1",
+            "2024-01-15 10:06:00",
+            "signup",
+            "/pricing",
+            None,
+        );
+        // More than 30 minutes later: a second session.
+        insert(
+            &conn,
+            "v1",
+            "2024-01-15 12:00:00",
+            "pageview",
+            "/docs",
+            None,
+        );
+        insert(&conn, "v2", "2024-01-15 10:00:00", "pageview", "/", None);
+
+        let profile = query_visitor_profile(&QueryConn::new(&conn), "test.com", "v1", 100)
+            .unwrap()
+            .unwrap();
+        assert_eq!(profile.total_events, 4);
+        assert_eq!(profile.first_seen, "2024-01-15 10:00:00");
+        assert_eq!(profile.last_seen, "2024-01-15 12:00:00");
+        assert_eq!(
+            profile.attribution.referrer_source.as_deref(),
+            Some("Google")
+        );
+        assert_eq!(profile.attribution.landing_page, "/");
+
+        assert_eq!(profile.sessions.len(), 2);
+        assert_eq!(profile.sessions[0].events, 3);
+        assert_eq!(profile.sessions[0].pageviews, 2);
+        assert!((profile.sessions[0].duration_secs - 360.0).abs() < f64::EPSILON);
+        assert_eq!(profile.sessions[0].exit_page, "/pricing");
+        assert_eq!(profile.sessions[1].entry_page, "/docs");
+
+        assert_eq!(profile.events.len(), 4);
+        assert_eq!(profile.events[0].pathname, "/");
+        assert_eq!(profile.events[3].session, 2);
+        assert!(!profile.events_truncated);
+    }
+
+    #[test]
+    fn test_visitor_profile_truncates_to_recent_events() {
+        let conn = setup_test_db();
+        insert(&conn, "v1", "2024-01-15 10:00:00", "pageview", "/a", None);
+        insert(&conn, "v1", "2024-01-15 10:01:00", "pageview", "/b", None);
+        insert(&conn, "v1", "2024-01-15 10:02:00", "pageview", "/c", None);
+
+        let profile = query_visitor_profile(&QueryConn::new(&conn), "test.com", "v1", 2)
+            .unwrap()
+            .unwrap();
+        assert!(profile.events_truncated);
+        let paths: Vec<_> = profile.events.iter().map(|e| e.pathname.as_str()).collect();
+        assert_eq!(paths, vec!["/b", "/c"]);
+        // Sessions and attribution still cover every event.
+        assert_eq!(profile.sessions[0].events, 3);
+        assert_eq!(profile.attribution.landing_page, "/a");
+    }
+
+    #[test]
+    fn test_unknown_visitor() {
+        let conn = setup_test_db();
+        insert(&conn, "v1", "2024-01-15 10:00:00", "pageview", "/", None);
+        assert!(
+            query_visitor_profile(&QueryConn::new(&conn), "other.com", "v1", 100)
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            query_visitor_profile(&QueryConn::new(&conn), "test.com", "v2", 100)
+                .unwrap()
+                .is_none()
+        );
+    }
+}
@@ -1,47 +1,126 @@
+use crate::api::admin;
 use crate::api::auth;
+use crate::api::branding;
+use crate::api::capabilities;
+use crate::api::dashboards;
+use crate::api::explain;
+use crate::api::export;
+use crate::api::export_jobs;
+use crate::api::import_jobs;
+use crate::api::locale;
+use crate::api::notifications;
+use crate::api::openapi;
+use crate::api::public;
+use crate::api::query;
+use crate::api::script;
+use crate::api::shadow;
 use crate::api::stats;
 use crate::dashboard;
-use crate::ingest::handler::{ingest_event, AppState};
+use crate::ingest::handler::{ingest_event, record_body_size, validate_event, AppState};
+use crate::storage::resources::ResourceUsage;
 use axum::extract::DefaultBodyLimit;
 use axum::extract::State;
 use axum::http::{header, HeaderValue, Method, Request, StatusCode};
 use axum::middleware::{self, Next};
-use axum::response::Response;
-use axum::routing::{delete, get, post};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post, put};
 use axum::Router;
 use std::sync::Arc;
 use tower_http::compression::CompressionLayer;
-use tower_http::cors::{Any, CorsLayer};
-use tower_http::timeout::TimeoutLayer;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use tower_http::decompression::RequestDecompressionLayer;
 use tower_http::trace::TraceLayer;
 use tracing::Instrument;
 
 /// Build the Axum router with all routes.
+#[allow(clippy::too_many_lines)]
 pub fn build_router(state: Arc<AppState>) -> Router {
     // Permissive CORS for ingestion (tracking script runs on any origin)
     let ingestion_cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods([Method::POST])
-        .allow_headers([header::CONTENT_TYPE]);
+        .allow_headers([header::CONTENT_TYPE, header::CONTENT_ENCODING]);
 
     // Restrictive CORS for dashboard/stats/admin routes
-    let dashboard_cors = build_dashboard_cors(state.dashboard_origin.as_deref());
+    let dashboard_cors = build_dashboard_cors(&state.allowed_origins());
 
-    // Auth routes — always accessible (needed to log in)
+    // Auth routes — always accessible (needed to log in), with the dashboard's
+    // CORS so a cross-origin dashboard can sign in
     let auth_routes = Router::new()
         .route("/auth/setup", post(auth::auth_setup))
         .route("/auth/login", post(auth::auth_login))
         .route("/auth/logout", post(auth::auth_logout))
-        .route("/auth/status", get(auth::auth_status));
+        .route("/auth/status", get(auth::auth_status))
+        .route("/meta/branding", get(branding::get_branding))
+        .route("/meta/script-integrity", get(script::get_script_integrity))
+        .layer(dashboard_cors.clone());
+
+    // Per-caller request limit for /api/stats/*; runs inside the auth layers.
+    let stats_rate_limit =
+        middleware::from_fn_with_state(Arc::clone(&state), auth::rate_limit_stats);
 
     // API key management and GDPR data management routes — require admin scope + CSRF protection
     let key_routes = Router::new()
         .route("/keys", post(auth::create_api_key))
         .route("/keys", get(auth::list_api_keys))
         .route("/keys/{key_hash}", delete(auth::revoke_api_key_handler))
+        .route("/auth/sessions", get(auth::list_sessions))
+        .route("/auth/sessions/{id}", delete(auth::revoke_session_handler))
         // GDPR right-to-erasure endpoint: permanently deletes analytics data for a
         // site + date range from both DuckDB and on-disk Parquet partitions.
         .route("/gdpr/erase", delete(stats::gdpr_erase))
+        // Ad-hoc read-only SQL against events_all.
+        .route("/query", post(query::run_query))
+        // Raw events, including visitor and user IDs, so admin only.
+        .route(
+            "/export/ndjson",
+            get(export::export_ndjson).route_layer(stats_rate_limit.clone()),
+        )
+        // Uploads go to disk as they arrive, so the body limit only caps
+        // the file size.
+        .route(
+            "/imports",
+            post(import_jobs::create_import_job)
+                .layer(DefaultBodyLimit::max(import_jobs::MAX_UPLOAD_BYTES)),
+        )
+        .route("/imports/{id}", get(import_jobs::get_import_job))
+        .route(
+            "/imports/{id}/confirm",
+            post(import_jobs::confirm_import_job),
+        )
+        .route("/admin/partitions", get(admin::get_partitions))
+        .route("/admin/verify", get(admin::verify_data))
+        .route("/admin/slow-queries", get(admin::get_slow_queries))
+        .route("/admin/shadow-queries", get(shadow::get_shadow_queries))
+        .route("/admin/ingest-stats", get(admin::get_ingest_stats))
+        .route("/admin/explain", get(explain::explain_report))
+        .route("/admin/drain", post(admin::drain))
+        .route(
+            "/stats/sites",
+            get(stats::get_sites).route_layer(stats_rate_limit.clone()),
+        )
+        // Single-visitor timeline; more sensitive than aggregates, so admin only.
+        .route(
+            "/stats/visitor/{visitor_id}",
+            get(stats::get_visitor_profile).route_layer(stats_rate_limit.clone()),
+        )
+        .route("/dashboards", post(dashboards::create_dashboard))
+        .route(
+            "/notifications",
+            get(notifications::list_notifications).post(notifications::create_notification),
+        )
+        .route(
+            "/notifications/{id}",
+            delete(notifications::delete_notification),
+        )
+        .route(
+            "/notifications/{id}/test",
+            post(notifications::test_notification),
+        )
+        .route(
+            "/dashboards/{id}",
+            put(dashboards::update_dashboard).delete(dashboards::delete_dashboard),
+        )
         .layer(middleware::from_fn_with_state(
             Arc::clone(&state),
             auth::require_admin_auth,
@@ -51,11 +130,17 @@ pub fn build_router(state: Arc<AppState>) -> Router {
     let stats_routes = Router::new()
         .route("/stats/main", get(stats::get_main_stats))
         .route("/stats/timeseries", get(stats::get_timeseries))
+        .route("/stats/compare", get(stats::get_compare))
+        .route("/stats/digest", get(stats::get_digest))
         .route("/stats/breakdown/pages", get(stats::get_pages_breakdown))
         .route(
             "/stats/breakdown/sources",
             get(stats::get_sources_breakdown),
         )
+        .route(
+            "/stats/breakdown/campaigns",
+            get(stats::get_campaigns_breakdown),
+        )
         .route(
             "/stats/breakdown/browsers",
             get(stats::get_browsers_breakdown),
@@ -65,16 +150,62 @@ pub fn build_router(state: Arc<AppState>) -> Router {
             "/stats/breakdown/devices",
             get(stats::get_devices_breakdown),
         )
+        .route(
+            "/stats/breakdown/devices_models",
+            get(stats::get_device_models_breakdown),
+        )
         .route(
             "/stats/breakdown/countries",
             get(stats::get_countries_breakdown),
         )
+        .route(
+            "/stats/breakdown/continents",
+            get(stats::get_continents_breakdown),
+        )
+        .route("/stats/breakdown/custom", get(stats::get_custom_breakdown))
+        .route(
+            "/stats/breakdown/search_terms",
+            get(stats::get_search_terms_breakdown),
+        )
+        .route(
+            "/stats/breakdown/email_opens",
+            get(stats::get_email_opens_breakdown),
+        )
+        .route("/stats/events", get(stats::get_custom_events))
+        .route(
+            "/stats/sources/conversions",
+            get(stats::get_source_conversions),
+        )
         .route("/stats/export", get(stats::get_export))
         .route("/stats/sessions", get(stats::get_sessions))
+        .route("/stats/time_on_page", get(stats::get_time_on_page))
         .route("/stats/funnel", get(stats::get_funnel))
         .route("/stats/retention", get(stats::get_retention))
         .route("/stats/sequences", get(stats::get_sequences))
-        .route("/stats/flow", get(stats::get_flow));
+        .route("/stats/flow", get(stats::get_flow))
+        .route("/stats/paths", get(stats::get_paths))
+        .route("/stats/anomalies", get(stats::get_anomalies))
+        .route_layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            shadow::shadow_report,
+        ))
+        .route_layer(stats_rate_limit.clone())
+        // Export jobs; only queueing one counts against the stats limit, so
+        // polling for the result does not use it up.
+        .route(
+            "/exports",
+            post(export_jobs::create_export_job).route_layer(stats_rate_limit),
+        )
+        .route("/exports/{id}", get(export_jobs::get_export_job))
+        .route("/event/validate", post(validate_event))
+        .route("/capabilities", get(capabilities::get_capabilities))
+        .route("/openapi.json", get(openapi::get_openapi))
+        .route("/docs", get(openapi::get_docs))
+        .route("/docs/", get(openapi::get_docs_file))
+        .route("/docs/{*file}", get(openapi::get_docs_file))
+        .route("/meta/locale", get(locale::get_locale))
+        .route("/dashboards", get(dashboards::list_dashboards))
+        .route("/dashboards/{id}", get(dashboards::get_dashboard));
 
     // Protected routes — stats + key management, guarded by auth middleware
     let protected_routes = stats_routes
@@ -87,17 +218,36 @@ pub fn build_router(state: Arc<AppState>) -> Router {
 
     // Ingestion with permissive CORS and 64 KB body limit (max valid event ~12 KB).
     // GET /api/event is included for pixel / <img> tracker compatibility.
+    //
+    // gzip and brotli bodies are decoded as they are read, so the body limit
+    // caps the decompressed size and a small compressed bomb cannot inflate
+    // past 64 KB.  Other encodings get 415.  The size as sent is recorded
+    // first, since decompression drops `Content-Length`.
     let ingestion_routes = Router::new()
         .route("/event", post(ingest_event))
         .route("/event", get(pixel_track))
+        .route("/pixel/{file}", get(email_pixel))
         .layer(DefaultBodyLimit::max(65_536))
+        .layer(RequestDecompressionLayer::new())
+        .layer(middleware::map_request(record_body_size))
         .layer(ingestion_cors);
 
+    // Public summaries for CMS embeds, readable from any origin without auth
+    let public_cors = CorsLayer::new()
+        .allow_origin(Any)
+        .allow_methods([Method::GET]);
+    let public_routes = Router::new()
+        .route("/public/summary", get(public::get_public_summary))
+        .layer(public_cors);
+
     let api_routes = Router::new()
         .merge(ingestion_routes)
         .merge(auth_routes)
+        .merge(public_routes)
         .merge(protected_routes);
 
+    let security_headers = Arc::new(SecurityHeaders::new(&state));
+
     Router::new()
         .route("/health", get(health_check))
         .route("/health/ready", get(readiness_check))
@@ -105,22 +255,186 @@ pub fn build_router(state: Arc<AppState>) -> Router {
         .route("/metrics", get(prometheus_metrics))
         .route("/robots.txt", get(robots_txt))
         .route("/.well-known/security.txt", get(security_txt))
+        .route(script::SCRIPT_PATH, get(script::serve_script))
         .nest("/api", api_routes)
         .route("/", get(dashboard::serve_index))
         .route("/{*path}", get(dashboard::serve_asset))
-        .layer(middleware::from_fn(request_id_middleware))
-        .layer(axum::middleware::map_response(add_security_headers))
-        .layer(CompressionLayer::new())
-        .layer(TimeoutLayer::with_status_code(
-            axum::http::StatusCode::REQUEST_TIMEOUT,
-            std::time::Duration::from_secs(30),
+        .layer(axum::middleware::map_response_with_state(
+            security_headers,
+            add_security_headers,
+        ))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            close_when_draining,
         ))
+        .layer(CompressionLayer::new())
+        .layer(middleware::from_fn(request_timeout))
         .layer(TraceLayer::new_for_http())
+        // Outermost, so the request span also covers the trace layer's
+        // events and timeouts.
+        .layer(middleware::from_fn(request_id_middleware))
         .with_state(state)
 }
 
+/// Placeholder in dashboard HTML replaced by the page's CSP nonce.
+pub const NONCE_PLACEHOLDER: &str = "{{csp_nonce}}";
+
+/// Content-Security-Policy of dashboard pages when nothing is configured.
+const DEFAULT_CSP: &str = "default-src 'self'; script-src 'self'; style-src 'self'";
+
+/// Headers added by [`add_security_headers`], built once from the config.
+#[derive(Debug)]
+struct SecurityHeaders {
+    csp: DashboardCsp,
+    hsts: Option<HeaderValue>,
+    permissions_policy: Option<HeaderValue>,
+    cross_origin_opener_policy: Option<HeaderValue>,
+    /// `X-Frame-Options: DENY`, unless frame ancestors are configured.
+    deny_framing: bool,
+}
+
+impl SecurityHeaders {
+    fn new(state: &AppState) -> Self {
+        let config = &state.security_headers;
+        // Empty values were chosen to omit the header.
+        let value = |v: &str| {
+            if v.is_empty() {
+                None
+            } else {
+                HeaderValue::from_str(v).ok()
+            }
+        };
+        Self {
+            // The dashboard may show a logo from another origin.
+            csp: DashboardCsp::new(
+                &state.csp,
+                state.branding.logo_origin(),
+                &config.frame_ancestors,
+            ),
+            hsts: config.hsts_value().as_deref().and_then(value),
+            permissions_policy: value(&config.permissions_policy),
+            cross_origin_opener_policy: value(&config.cross_origin_opener_policy),
+            deny_framing: config.frame_ancestors.is_empty(),
+        }
+    }
+}
+
+/// Content-Security-Policy of dashboard pages: `'self'`, the `[csp]`
+/// sources, the branding logo's origin and the allowed frame ancestors.
+#[derive(Debug)]
+struct DashboardCsp {
+    script_src: Vec<String>,
+    style_src: Vec<String>,
+    img_src: Vec<String>,
+    connect_src: Vec<String>,
+    frame_ancestors: Vec<String>,
+    nonce: bool,
+}
+
+impl DashboardCsp {
+    fn new(
+        csp: &crate::config::CspConfig,
+        logo_origin: Option<&str>,
+        frame_ancestors: &[String],
+    ) -> Self {
+        Self {
+            script_src: csp.script_src.clone(),
+            style_src: csp.style_src.clone(),
+            img_src: csp
+                .img_src
+                .iter()
+                .cloned()
+                .chain(logo_origin.map(str::to_string))
+                .collect(),
+            connect_src: csp.connect_src.clone(),
+            frame_ancestors: frame_ancestors.to_vec(),
+            nonce: csp.nonce,
+        }
+    }
+
+    /// The header value, allowing inline scripts and styles that carry
+    /// `nonce`.  `img-src` and `connect-src` fall back to `default-src`
+    /// unless sources are added; `frame-ancestors` is only sent with
+    /// sources.
+    fn header(&self, nonce: Option<&str>) -> HeaderValue {
+        use std::fmt::Write;
+
+        let mut policy = "default-src 'self'".to_string();
+        for (directive, sources, nonced) in [
+            ("script-src", &self.script_src, true),
+            ("style-src", &self.style_src, true),
+            ("img-src", &self.img_src, false),
+            ("connect-src", &self.connect_src, false),
+            ("frame-ancestors", &self.frame_ancestors, false),
+        ] {
+            if !nonced && sources.is_empty() {
+                continue;
+            }
+            policy.push_str("; ");
+            policy.push_str(directive);
+            policy.push_str(" 'self'");
+            if let Some(nonce) = nonce.filter(|_| nonced) {
+                let _ = write!(policy, " 'nonce-{nonce}'");
+            }
+            for source in sources {
+                policy.push(' ');
+                policy.push_str(source);
+            }
+        }
+        HeaderValue::from_str(&policy).unwrap_or(HeaderValue::from_static(DEFAULT_CSP))
+    }
+}
+
+/// Replace [`NONCE_PLACEHOLDER`] in the HTML of `response` with `nonce`.
+async fn fill_nonce(response: Response, nonce: &str) -> Response {
+    let (mut parts, body) = response.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, usize::MAX).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let html = String::from_utf8_lossy(&bytes).replace(NONCE_PLACEHOLDER, nonce);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, axum::body::Body::from(html))
+}
+
+/// How long a request may take before it gets 408.
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Answer 408 to requests taking longer than [`REQUEST_TIMEOUT`].  Import
+/// uploads get [`import_jobs::UPLOAD_TIMEOUT`] instead, as large files take
+/// longer to arrive; their handler only stores the file.
+async fn request_timeout(request: Request<axum::body::Body>, next: Next) -> Response {
+    let limit = if request.method() == Method::POST && request.uri().path() == "/api/imports" {
+        import_jobs::UPLOAD_TIMEOUT
+    } else {
+        REQUEST_TIMEOUT
+    };
+    tokio::time::timeout(limit, next.run(request))
+        .await
+        .unwrap_or_else(|_| StatusCode::REQUEST_TIMEOUT.into_response())
+}
+
+/// Close HTTP/1 keep-alive connections after their next response while
+/// draining, so clients reconnect to the server taking over.
+async fn close_when_draining(
+    State(state): State<Arc<AppState>>,
+    request: Request<axum::body::Body>,
+    next: Next,
+) -> Response {
+    let keep_alive = request.version() <= axum::http::Version::HTTP_11;
+    let mut response = next.run(request).await;
+    if keep_alive && state.drain.is_draining() {
+        response
+            .headers_mut()
+            .insert(header::CONNECTION, HeaderValue::from_static("close"));
+    }
+    response
+}
+
 /// Inject OWASP-recommended security headers and Cache-Control on every HTTP response.
-async fn add_security_headers(mut response: Response) -> Response {
+async fn add_security_headers(
+    State(security): State<Arc<SecurityHeaders>>,
+    mut response: Response,
+) -> Response {
     // Snapshot status BEFORE taking a mutable reference to headers so both
     // borrows do not coexist (the borrow checker forbids mixed &/&mut on the
     // same value through different fields when they alias through the struct).
@@ -130,24 +444,28 @@ async fn add_security_headers(mut response: Response) -> Response {
         header::X_CONTENT_TYPE_OPTIONS,
         HeaderValue::from_static("nosniff"),
     );
-    headers.insert(header::X_FRAME_OPTIONS, HeaderValue::from_static("DENY"));
+    // Embedding deployments allow framing through CSP `frame-ancestors`.
+    if security.deny_framing {
+        headers.insert(header::X_FRAME_OPTIONS, HeaderValue::from_static("DENY"));
+    }
     headers.insert(
         "referrer-policy",
         HeaderValue::from_static("strict-origin-when-cross-origin"),
     );
-    headers.insert(
-        "permissions-policy",
-        HeaderValue::from_static("geolocation=(), microphone=(), camera=()"),
-    );
-    // HSTS: instruct browsers to enforce HTTPS for 1 year.
+    if let Some(value) = &security.permissions_policy {
+        headers.insert("permissions-policy", value.clone());
+    }
+    if let Some(value) = &security.cross_origin_opener_policy {
+        headers.insert("cross-origin-opener-policy", value.clone());
+    }
+    // HSTS: instruct browsers to enforce HTTPS (1 year with `preload` by default).
     // Safe to include on HTTP deployments — browsers only process this header
     // when received over HTTPS, so it is a no-op on plain HTTP.
     // `preload` opts in to browser HSTS preload lists (hstspreload.org):
     // requires max-age ≥ 31536000, includeSubDomains, and preload all present.
-    headers.insert(
-        "strict-transport-security",
-        HeaderValue::from_static("max-age=31536000; includeSubDomains; preload"),
-    );
+    if let Some(value) = &security.hsts {
+        headers.insert("strict-transport-security", value.clone());
+    }
     // Add Retry-After: 1 to any 429 response that does not already carry the
     // header (the login endpoint sets its own value based on the lockout period).
     if status == StatusCode::TOO_MANY_REQUESTS && !headers.contains_key("retry-after") {
@@ -163,47 +481,80 @@ async fn add_security_headers(mut response: Response) -> Response {
     let is_html = content_type.contains("text/html");
     let is_json = content_type.contains("application/json");
 
-    if is_html {
+    // Prevent CDN/browser caches from serving stale or cross-user analytics
+    // data.  Public summaries set their own caching.
+    if is_json && !headers.contains_key(header::CACHE_CONTROL) {
         headers.insert(
-            "content-security-policy",
-            HeaderValue::from_static("default-src 'self'; script-src 'self'; style-src 'self'"),
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("no-store, no-cache"),
         );
     }
 
-    // Prevent CDN/browser caches from serving stale or cross-user analytics data.
-    if is_json {
-        headers.insert(
-            header::CACHE_CONTROL,
-            HeaderValue::from_static("no-store, no-cache"),
+    if is_html {
+        // A fresh nonce for every page, so an injected script cannot reuse one.
+        let nonce = security
+            .csp
+            .nonce
+            .then(|| format!("{:032x}", rand::random::<u128>()));
+        if let Some(nonce) = &nonce {
+            response = fill_nonce(response, nonce).await;
+        }
+        response.headers_mut().insert(
+            "content-security-policy",
+            security.csp.header(nonce.as_deref()),
         );
     }
 
     response
 }
 
+/// Longest inbound `X-Request-ID` that is kept.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
 /// Middleware that assigns an X-Request-ID to every request and records it in
 /// the tracing span so that all log lines emitted during request processing
-/// carry the same `request_id` field.
+/// carry the same `request_id` field.  Error bodies repeat it as
+/// `request_id` (see [`crate::api::errors::REQUEST_ID`]).
 ///
 /// If the upstream proxy already set an `X-Request-ID` header, the existing
-/// value is used (enabling end-to-end correlation through the proxy tier).
-async fn request_id_middleware(request: Request<axum::body::Body>, next: Next) -> Response {
+/// value is used (enabling end-to-end correlation through the proxy tier),
+/// unless it is longer than [`MAX_REQUEST_ID_LEN`] or has characters other
+/// than ASCII letters, digits and `-_.:`, which could forge or bloat log lines.
+async fn request_id_middleware(mut request: Request<axum::body::Body>, next: Next) -> Response {
     let id = request
         .headers()
         .get("x-request-id")
         .and_then(|v| v.to_str().ok())
+        .filter(|id| is_valid_request_id(id))
         .map_or_else(|| uuid::Uuid::new_v4().to_string(), String::from);
+    // Only ever ASCII, so always a valid header value.
+    let header_value = HeaderValue::from_str(&id).ok();
+    if let Some(val) = &header_value {
+        // Handlers reading the header see the same ID as the logs.
+        request.headers_mut().insert("x-request-id", val.clone());
+    }
 
     // Run the handler inside a span that carries request_id so all log events
     // emitted during processing are correlated to this request.
     let span = tracing::info_span!("http_request", request_id = %id);
-    let mut response = next.run(request).instrument(span).await;
-    if let Ok(val) = HeaderValue::from_str(&id) {
+    let mut response = crate::api::errors::REQUEST_ID
+        .scope(id, next.run(request))
+        .instrument(span)
+        .await;
+    if let Some(val) = header_value {
         response.headers_mut().insert("x-request-id", val);
     }
     response
 }
 
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+}
+
 /// GET /robots.txt — Prevent search engines from indexing the dashboard or API.
 async fn robots_txt() -> impl axum::response::IntoResponse {
     (
@@ -249,6 +600,15 @@ const TRANSPARENT_GIF_1X1: &[u8] = &[
 ///
 /// Revenue and custom-property fields are deliberately excluded because they
 /// cannot be validated or sanitised reliably in a plain query string.
+#[utoipa::path(
+    get,
+    path = "/api/event",
+    tag = openapi::INGESTION,
+    summary = "Record an event from an image request",
+    params(crate::ingest::handler::PixelParams),
+    responses((status = 200, description = "A 1×1 GIF", content_type = "image/gif")),
+    security(()),
+)]
 async fn pixel_track(
     State(state): State<Arc<AppState>>,
     headers: axum::http::HeaderMap,
@@ -264,28 +624,63 @@ async fn pixel_track(
     )
 }
 
-/// Build CORS layer for dashboard routes based on configured origin.
-fn build_dashboard_cors(dashboard_origin: Option<&str>) -> CorsLayer {
-    dashboard_origin.map_or_else(
-        || {
-            // No dashboard origin configured — allow all origins.
-            // Set `dashboard_origin` in config to restrict cross-origin access.
-            CorsLayer::new()
-                .allow_origin(Any)
-                .allow_methods(Any)
-                .allow_headers(Any)
-        },
-        |origin| {
-            let allowed_origin = origin
-                .parse::<axum::http::HeaderValue>()
-                .unwrap_or_else(|_| axum::http::HeaderValue::from_static("*"));
-            CorsLayer::new()
-                .allow_origin(allowed_origin)
-                .allow_methods([Method::GET, Method::POST, Method::DELETE])
-                .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION, header::COOKIE])
-                .allow_credentials(true)
-        },
+/// GET /api/pixel/{site_id}.gif — Email open pixel.
+///
+/// Records an `email_open` event for the site, with the optional `c` query
+/// parameter as its campaign, and returns the same 1×1 GIF as `GET /api/event`.
+/// Meant for HTML emails, where no script runs and no page URL exists.
+#[utoipa::path(
+    get,
+    path = "/api/pixel/{file}",
+    tag = openapi::INGESTION,
+    summary = "Record an email open",
+    params(
+        ("file" = String, Path, description = "`<site_id>.gif`"),
+        crate::ingest::handler::EmailPixelParams,
+    ),
+    responses((status = 200, description = "A 1×1 GIF", content_type = "image/gif")),
+    security(()),
+)]
+async fn email_pixel(
+    State(state): State<Arc<AppState>>,
+    axum::extract::Path(file): axum::extract::Path<String>,
+    headers: axum::http::HeaderMap,
+    axum::extract::Query(params): axum::extract::Query<crate::ingest::handler::EmailPixelParams>,
+) -> Response {
+    let Some(site_id) = file.strip_suffix(".gif") else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    crate::ingest::handler::process_pixel_event(
+        &state,
+        &headers,
+        params.into_pixel_params(site_id),
+    )
+    .await;
+
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "image/gif")],
+        TRANSPARENT_GIF_1X1,
     )
+        .into_response()
+}
+
+/// CORS for the dashboard, stats and admin routes.  Only the configured
+/// dashboard origins may call them cross-origin, with credentials; the
+/// matching origin is echoed back, never `*`.  Without any, no CORS headers
+/// are sent and browsers keep the routes same-origin.
+fn build_dashboard_cors(origins: &[&str]) -> CorsLayer {
+    // Origins were validated at startup.
+    let origins: Vec<HeaderValue> = origins.iter().filter_map(|o| o.parse().ok()).collect();
+    if origins.is_empty() {
+        return CorsLayer::new();
+    }
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::list(origins))
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+        .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION])
+        .allow_credentials(true)
+        .max_age(std::time::Duration::from_secs(600))
 }
 
 /// GET /health — Simple liveness probe. Always returns "ok" if the process is alive.
@@ -298,7 +693,20 @@ async fn health_check() -> &'static str {
 /// Returns 200 when the DuckDB connection is alive and the events_all view is
 /// queryable.  Returns 503 if the database is not reachable, so Kubernetes can
 /// hold traffic until the instance is ready.
+///
+/// While the data volume is low on space the body is `degraded: low disk
+/// space`, still with 200 since queries are served; once the event buffer
+/// is full as a result, ingestion is refused and the probe returns 503.
+/// Once a drain started it returns 503 `draining`.
 async fn readiness_check(State(state): State<Arc<AppState>>) -> Response {
+    if state.drain.is_draining() {
+        return axum::response::IntoResponse::into_response((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "draining",
+        ));
+    }
+    let disk_low = state.buffer.disk_guard().is_some_and(|disk| disk.is_low());
+    let buffer_full = state.buffer.is_full();
     let ok = tokio::task::spawn_blocking(move || {
         let conn = state.buffer.conn().lock();
         conn.execute_batch("SELECT 1 FROM events_all LIMIT 0")
@@ -307,7 +715,14 @@ async fn readiness_check(State(state): State<Arc<AppState>>) -> Response {
     .await
     .unwrap_or(false);
 
-    if ok {
+    if ok && buffer_full {
+        axum::response::IntoResponse::into_response((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "degraded: event buffer full",
+        ))
+    } else if ok && disk_low {
+        axum::response::IntoResponse::into_response((StatusCode::OK, "degraded: low disk space"))
+    } else if ok {
         axum::response::IntoResponse::into_response((StatusCode::OK, "ready"))
     } else {
         axum::response::IntoResponse::into_response((
@@ -318,6 +733,9 @@ async fn readiness_check(State(state): State<Arc<AppState>>) -> Response {
 }
 
 /// GET /health/detailed — Detailed health check with system info.
+///
+/// Degraded while the data volume is low on space, the event buffer is full
+/// or a background task is down waiting to be restarted.
 async fn detailed_health_check(
     State(state): State<Arc<AppState>>,
 ) -> axum::Json<serde_json::Value> {
@@ -325,29 +743,63 @@ async fn detailed_health_check(
     let buffer_empty = state.buffer.is_empty();
     let auth_configured = state.admin_password_hash.lock().is_some();
     let geoip_loaded = state.geoip.is_loaded();
+    let duckdb = duckdb_usage(&state).await;
+    let disk = state.buffer.disk_guard();
+    let disk_low = disk.is_some_and(|disk| disk.is_low());
+    let degraded = disk_low || state.buffer.is_full() || state.tasks.any_restarting();
 
     axum::Json(serde_json::json!({
-        "status": "ok",
+        "status": if degraded { "degraded" } else { "ok" },
         "version": env!("CARGO_PKG_VERSION"),
         "buffered_events": buffered_events,
         "buffer_empty": buffer_empty,
+        "buffer_full": state.buffer.is_full(),
+        "disk_low": disk_low,
+        "disk_free_bytes": disk.and_then(|disk| disk.free_bytes()),
         "auth_configured": auth_configured,
         "geoip_loaded": geoip_loaded,
         "behavioral_extension_loaded": state.behavioral_extension_loaded,
         "filter_bots": state.filter_bots,
         "cache_entries": state.query_cache.len(),
         "cache_empty": state.query_cache.is_empty(),
+        "flush_consecutive_failures": state
+            .flush_consecutive_failures
+            .load(std::sync::atomic::Ordering::Relaxed),
+        "duckdb": duckdb,
+        "background_tasks": state.tasks.snapshot(),
     }))
 }
 
+/// DuckDB's memory use and limits, read off the async runtime.
+async fn duckdb_usage(state: &Arc<AppState>) -> Option<ResourceUsage> {
+    let state = Arc::clone(state);
+    tokio::task::spawn_blocking(move || state.duckdb_usage.usage(state.buffer.conn()))
+        .await
+        .ok()
+        .flatten()
+}
+
 /// GET /metrics — Prometheus-compatible metrics endpoint.
 ///
 /// If MALLARD_METRICS_TOKEN is set at startup, requires Authorization: Bearer <token>.
-fn build_metrics_body(state: &AppState) -> String {
+#[allow(clippy::too_many_lines)]
+fn build_metrics_body(state: &AppState, duckdb: Option<&ResourceUsage>) -> String {
     use std::fmt::Write;
     use std::sync::atomic::Ordering;
 
     let buffered = state.buffer.len();
+    let buffer_dropped = state.buffer.dropped_total();
+    let buffer_age = state
+        .buffer
+        .oldest_age()
+        .map_or(0.0, |age| age.as_secs_f64());
+    let disk = state.buffer.disk_guard();
+    let disk_low = u8::from(disk.is_some_and(|disk| disk.is_low()));
+    let disk_free = disk.and_then(|disk| disk.free_bytes());
+    let (ingest_queue_depth, ingest_queue_rejections, ingest_shed_events) =
+        state.ingest_queue.as_ref().map_or((0, 0, 0), |queue| {
+            (queue.depth(), queue.rejected_total(), queue.shed_total())
+        });
     let cache_entries = state.query_cache.len();
     let auth_configured = u8::from(state.admin_password_hash.lock().is_some());
     let geoip_loaded = u8::from(state.geoip.is_loaded());
@@ -355,8 +807,24 @@ fn build_metrics_body(state: &AppState) -> String {
     let filter_bots = u8::from(state.filter_bots);
     let events_ingested = state.events_ingested_total.load(Ordering::Relaxed);
     let flush_failures = state.flush_failures_total.load(Ordering::Relaxed);
+    let flush_consecutive_failures = state.flush_consecutive_failures.load(Ordering::Relaxed);
     let rate_limit_rejections = state.rate_limit_rejections_total.load(Ordering::Relaxed);
+    let stats_rate_limit_rejections = state
+        .stats_rate_limit_rejections_total
+        .load(Ordering::Relaxed);
+    let duplicate_events = state.duplicate_events_total.load(Ordering::Relaxed);
+    let spam_events = state.spam_blocklist.dropped_total();
+    let geo_blocked_events = state.geo_blocked_events_total.load(Ordering::Relaxed);
     let login_failures = state.login_failures_total.load(Ordering::Relaxed);
+    let (forwarded_events, forward_dropped_events, forward_dead_letter_events) =
+        state.forwarder.as_ref().map_or((0, 0, 0), |forwarder| {
+            let counts = &forwarder.counts;
+            (
+                counts.forwarded.load(Ordering::Relaxed),
+                counts.dropped.load(Ordering::Relaxed),
+                counts.dead_lettered.load(Ordering::Relaxed),
+            )
+        });
     let cache_hits = state.query_cache.hits.load(Ordering::Relaxed);
     let cache_misses = state.query_cache.misses.load(Ordering::Relaxed);
 
@@ -367,6 +835,56 @@ fn build_metrics_body(state: &AppState) -> String {
     );
     let _ = writeln!(out, "# TYPE mallard_buffered_events gauge");
     let _ = writeln!(out, "mallard_buffered_events {buffered}");
+    let _ = writeln!(
+        out,
+        "# HELP mallard_buffer_oldest_event_age_seconds Time the oldest buffered event has waited to be flushed"
+    );
+    let _ = writeln!(out, "# TYPE mallard_buffer_oldest_event_age_seconds gauge");
+    let _ = writeln!(
+        out,
+        "mallard_buffer_oldest_event_age_seconds {buffer_age:.3}"
+    );
+    let _ = writeln!(
+        out,
+        "# HELP mallard_buffer_dropped_events_total Total events refused or shed because the event buffer was full"
+    );
+    let _ = writeln!(out, "# TYPE mallard_buffer_dropped_events_total counter");
+    let _ = writeln!(out, "mallard_buffer_dropped_events_total {buffer_dropped}");
+    let _ = writeln!(
+        out,
+        "# HELP mallard_disk_low Whether flushing is paused because the data volume is low on space"
+    );
+    let _ = writeln!(out, "# TYPE mallard_disk_low gauge");
+    let _ = writeln!(out, "mallard_disk_low {disk_low}");
+    if let Some(disk_free) = disk_free {
+        let _ = writeln!(
+            out,
+            "# HELP mallard_disk_free_bytes Free space on the data volume at the last check"
+        );
+        let _ = writeln!(out, "# TYPE mallard_disk_free_bytes gauge");
+        let _ = writeln!(out, "mallard_disk_free_bytes {disk_free}");
+    }
+    let _ = writeln!(
+        out,
+        "# HELP mallard_ingest_queue_depth Number of events waiting for the ingest workers"
+    );
+    let _ = writeln!(out, "# TYPE mallard_ingest_queue_depth gauge");
+    let _ = writeln!(out, "mallard_ingest_queue_depth {ingest_queue_depth}");
+    let _ = writeln!(
+        out,
+        "# HELP mallard_ingest_queue_rejections_total Total ingest requests rejected because the ingest queue was full"
+    );
+    let _ = writeln!(out, "# TYPE mallard_ingest_queue_rejections_total counter");
+    let _ = writeln!(
+        out,
+        "mallard_ingest_queue_rejections_total {ingest_queue_rejections}"
+    );
+    let _ = writeln!(
+        out,
+        "# HELP mallard_ingest_shed_events_total Total low-priority events dropped because the ingest queue was nearly full"
+    );
+    let _ = writeln!(out, "# TYPE mallard_ingest_shed_events_total counter");
+    let _ = writeln!(out, "mallard_ingest_shed_events_total {ingest_shed_events}");
     let _ = writeln!(
         out,
         "# HELP mallard_cache_entries Number of cached query results"
@@ -409,6 +927,15 @@ fn build_metrics_body(state: &AppState) -> String {
     );
     let _ = writeln!(out, "# TYPE mallard_flush_failures_total counter");
     let _ = writeln!(out, "mallard_flush_failures_total {flush_failures}");
+    let _ = writeln!(
+        out,
+        "# HELP mallard_flush_consecutive_failures Periodic flushes that failed in a row after their retries"
+    );
+    let _ = writeln!(out, "# TYPE mallard_flush_consecutive_failures gauge");
+    let _ = writeln!(
+        out,
+        "mallard_flush_consecutive_failures {flush_consecutive_failures}"
+    );
     let _ = writeln!(
         out,
         "# HELP mallard_rate_limit_rejections_total Total ingest requests rejected by rate limiter"
@@ -418,6 +945,63 @@ fn build_metrics_body(state: &AppState) -> String {
         out,
         "mallard_rate_limit_rejections_total {rate_limit_rejections}"
     );
+    let _ = writeln!(
+        out,
+        "# HELP mallard_stats_rate_limit_rejections_total Total stats API requests rejected by the per-caller rate limiter"
+    );
+    let _ = writeln!(
+        out,
+        "# TYPE mallard_stats_rate_limit_rejections_total counter"
+    );
+    let _ = writeln!(
+        out,
+        "mallard_stats_rate_limit_rejections_total {stats_rate_limit_rejections}"
+    );
+    let _ = writeln!(
+        out,
+        "# HELP mallard_duplicate_events_total Total events dropped as duplicates of a recent event ID"
+    );
+    let _ = writeln!(out, "# TYPE mallard_duplicate_events_total counter");
+    let _ = writeln!(out, "mallard_duplicate_events_total {duplicate_events}");
+    let _ = writeln!(
+        out,
+        "# HELP mallard_spam_events_total Total events dropped for a referral spam referrer"
+    );
+    let _ = writeln!(out, "# TYPE mallard_spam_events_total counter");
+    let _ = writeln!(out, "mallard_spam_events_total {spam_events}");
+    let _ = writeln!(
+        out,
+        "# HELP mallard_geo_blocked_events_total Total events dropped or segregated by a site's geographic blocking rules"
+    );
+    let _ = writeln!(out, "# TYPE mallard_geo_blocked_events_total counter");
+    let _ = writeln!(out, "mallard_geo_blocked_events_total {geo_blocked_events}");
+    let _ = writeln!(
+        out,
+        "# HELP mallard_forwarded_events_total Total events delivered to the forwarding sink"
+    );
+    let _ = writeln!(out, "# TYPE mallard_forwarded_events_total counter");
+    let _ = writeln!(out, "mallard_forwarded_events_total {forwarded_events}");
+    let _ = writeln!(
+        out,
+        "# HELP mallard_forward_dropped_events_total Total events not forwarded because the forwarding queue was full"
+    );
+    let _ = writeln!(out, "# TYPE mallard_forward_dropped_events_total counter");
+    let _ = writeln!(
+        out,
+        "mallard_forward_dropped_events_total {forward_dropped_events}"
+    );
+    let _ = writeln!(
+        out,
+        "# HELP mallard_forward_dead_letter_events_total Total events written to the forwarding dead-letter file"
+    );
+    let _ = writeln!(
+        out,
+        "# TYPE mallard_forward_dead_letter_events_total counter"
+    );
+    let _ = writeln!(
+        out,
+        "mallard_forward_dead_letter_events_total {forward_dead_letter_events}"
+    );
     let _ = writeln!(
         out,
         "# HELP mallard_login_failures_total Total failed login attempts since startup"
@@ -436,6 +1020,39 @@ fn build_metrics_body(state: &AppState) -> String {
     );
     let _ = writeln!(out, "# TYPE mallard_cache_misses_total counter");
     let _ = writeln!(out, "mallard_cache_misses_total {cache_misses}");
+    if let Some(duckdb) = duckdb {
+        let _ = writeln!(
+            out,
+            "# HELP mallard_duckdb_memory_bytes Memory held by the DuckDB buffer manager"
+        );
+        let _ = writeln!(out, "# TYPE mallard_duckdb_memory_bytes gauge");
+        let _ = writeln!(
+            out,
+            "mallard_duckdb_memory_bytes {}",
+            duckdb.memory_usage_bytes
+        );
+        let _ = writeln!(
+            out,
+            "# HELP mallard_duckdb_temp_storage_bytes Data DuckDB has spilled to temporary files"
+        );
+        let _ = writeln!(out, "# TYPE mallard_duckdb_temp_storage_bytes gauge");
+        let _ = writeln!(
+            out,
+            "mallard_duckdb_temp_storage_bytes {}",
+            duckdb.temporary_storage_bytes
+        );
+        let _ = writeln!(out, "# HELP mallard_duckdb_threads DuckDB worker threads");
+        let _ = writeln!(out, "# TYPE mallard_duckdb_threads gauge");
+        let _ = writeln!(out, "mallard_duckdb_threads {}", duckdb.threads);
+        if let Some(free) = duckdb.temp_directory_free_bytes {
+            let _ = writeln!(
+                out,
+                "# HELP mallard_duckdb_temp_free_bytes Free disk space where DuckDB spills"
+            );
+            let _ = writeln!(out, "# TYPE mallard_duckdb_temp_free_bytes gauge");
+            let _ = writeln!(out, "mallard_duckdb_temp_free_bytes {free}");
+        }
+    }
 
     out
 }
@@ -467,7 +1084,7 @@ async fn prometheus_metrics(
 
     axum::response::IntoResponse::into_response((
         [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
-        build_metrics_body(&state),
+        build_metrics_body(&state, duckdb_usage(&state).await.as_ref()),
     ))
 }
 
@@ -502,13 +1119,30 @@ mod tests {
             sessions: SessionStore::new(3600),
             api_keys: ApiKeyStore::default(),
             admin_password_hash: Mutex::new(None),
+            admin_password_path: None,
+            locale: crate::api::locale::Locale::default(),
+            branding: crate::config::BrandingConfig::default(),
+            csp: crate::config::CspConfig::default(),
+            security_headers: crate::config::SecurityHeadersConfig::default(),
+            dashboard_dir: None,
             dashboard_origin: None,
+            dashboard_origins: Vec::new(),
             query_cache: crate::query::cache::QueryCache::new(0, 0),
+            cache_warming: std::sync::atomic::AtomicBool::new(false),
+            public_summary_cache: crate::query::cache::QueryCache::new(0, 0),
+            slow_queries: Arc::new(crate::query::slow::SlowQueryLog::default()),
+            duckdb_usage: crate::storage::resources::ResourceMonitor::default(),
+            shadow_queries: crate::query::shadow::ShadowQueries::default(),
+            forwarder: None,
+            tasks: crate::watchdog::TaskRegistry::default(),
+            drain: crate::upgrade::Drain::default(),
             rate_limiter: crate::ingest::ratelimit::RateLimiter::new(0),
             login_attempt_tracker: crate::api::auth::LoginAttemptTracker::new(0, 300),
             events_ingested_total: Arc::new(std::sync::atomic::AtomicU64::new(0)),
             flush_failures_total: Arc::new(std::sync::atomic::AtomicU64::new(0)),
+            flush_consecutive_failures: Arc::new(std::sync::atomic::AtomicU64::new(0)),
             rate_limit_rejections_total: Arc::new(std::sync::atomic::AtomicU64::new(0)),
+            ingest_stats: crate::ingest::stats::IngestStats::new(),
             login_failures_total: Arc::new(std::sync::atomic::AtomicU64::new(0)),
             metrics_token: None,
             query_semaphore: Arc::new(tokio::sync::Semaphore::new(10)),
@@ -522,6 +1156,32 @@ mod tests {
             suppress_screen_size: false,
             geoip_precision: "city".to_string(),
             events_dir,
+            sites: std::collections::HashMap::new(),
+            dashboards: dashboards::DashboardStore::default(),
+            notifications: notifications::NotificationStore::default(),
+            export_jobs: export_jobs::ExportJobs::new(
+                dir.path().join("exports"),
+                std::time::Duration::from_secs(3600),
+            ),
+            import_jobs: import_jobs::ImportJobs::new(
+                dir.path().join("imports"),
+                std::time::Duration::from_secs(3600),
+            ),
+            stats_rate_limiter: crate::ingest::ratelimit::RateLimiter::per_minute(0),
+            stats_rate_limit_rejections_total: std::sync::Arc::new(
+                std::sync::atomic::AtomicU64::new(0),
+            ),
+            debug_ingest: false,
+            transforms: crate::ingest::transform::IngestTransforms::default(),
+            ingest_queue: None,
+            spam_blocklist: Arc::new(crate::ingest::spam::SpamBlocklist::default()),
+            geo_blocked_events_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+            dedupe: crate::ingest::dedupe::Deduplicator::new(std::time::Duration::from_secs(60)),
+            max_event_time_skew: chrono::TimeDelta::hours(48),
+            idempotency: crate::ingest::idempotency::IdempotencyKeys::new(
+                std::time::Duration::from_secs(3600),
+            ),
+            duplicate_events_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
         });
         (state, dir)
     }
@@ -572,52 +1232,20 @@ mod tests {
         let body = response.into_body().collect().await.unwrap().to_bytes();
         let text = std::str::from_utf8(&body).unwrap();
         assert!(text.contains("mallard_buffered_events 0"));
+        assert!(text.contains("mallard_buffer_oldest_event_age_seconds 0.000"));
+        assert!(text.contains("mallard_ingest_queue_depth 0"));
+        assert!(text.contains("mallard_ingest_shed_events_total 0"));
         assert!(text.contains("mallard_cache_entries 0"));
         assert!(text.contains("mallard_auth_configured 0"));
         assert!(text.contains("mallard_geoip_loaded 0"));
         assert!(text.contains("mallard_filter_bots 0"));
+        assert!(text.contains("mallard_flush_consecutive_failures 0"));
     }
 
     #[tokio::test]
     async fn test_metrics_token_auth() {
-        let conn = Connection::open_in_memory().unwrap();
-        crate::storage::schema::init_schema(&conn).unwrap();
-        let dir = tempfile::tempdir().unwrap();
-        crate::storage::schema::setup_query_view(&conn, dir.path()).unwrap();
-        let storage = ParquetStorage::new(dir.path());
-        let conn = Arc::new(Mutex::new(conn));
-        let buffer = EventBuffer::new(1000, conn, storage);
-        let state = Arc::new(AppState {
-            buffer,
-            secret: "test-secret".to_string(),
-            allowed_sites: Vec::new(),
-            geoip: crate::ingest::geoip::GeoIpReader::open(None),
-            filter_bots: false,
-            sessions: SessionStore::new(3600),
-            api_keys: ApiKeyStore::default(),
-            admin_password_hash: Mutex::new(None),
-            dashboard_origin: None,
-            query_cache: crate::query::cache::QueryCache::new(0, 0),
-            rate_limiter: crate::ingest::ratelimit::RateLimiter::new(0),
-            login_attempt_tracker: crate::api::auth::LoginAttemptTracker::new(0, 300),
-            events_ingested_total: Arc::new(std::sync::atomic::AtomicU64::new(0)),
-            flush_failures_total: Arc::new(std::sync::atomic::AtomicU64::new(0)),
-            rate_limit_rejections_total: Arc::new(std::sync::atomic::AtomicU64::new(0)),
-            login_failures_total: Arc::new(std::sync::atomic::AtomicU64::new(0)),
-            metrics_token: Some("secret-token".to_string()),
-            query_semaphore: Arc::new(tokio::sync::Semaphore::new(10)),
-            secure_cookies: false,
-            behavioral_extension_loaded: false,
-            strip_referrer_query: false,
-            round_timestamps: false,
-            suppress_visitor_id: false,
-            suppress_browser_version: false,
-            suppress_os_version: false,
-            suppress_screen_size: false,
-            geoip_precision: "city".to_string(),
-            events_dir: dir.path().to_path_buf(),
-        });
-        let _dir = dir;
+        let (mut state, _dir) = make_test_state();
+        Arc::get_mut(&mut state).unwrap().metrics_token = Some("secret-token".to_string());
 
         // No token -> 401
         let app = build_router(Arc::clone(&state));
@@ -734,6 +1362,68 @@ mod tests {
         assert_eq!(response.status(), StatusCode::BAD_REQUEST);
     }
 
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        use std::io::Write;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn compressed_event(encoding: &str, body: Vec<u8>) -> Request<Body> {
+        Request::builder()
+            .method("POST")
+            .uri("/api/event")
+            .header("content-type", "application/json")
+            .header("content-encoding", encoding)
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_ingest_event_gzip() {
+        let (state, _dir) = make_test_state();
+        let app = build_router(Arc::clone(&state));
+
+        let payload = serde_json::json!({
+            "d": "example.com",
+            "n": "pageview",
+            "u": "https://example.com/",
+        });
+        let body = gzip(serde_json::to_string(&payload).unwrap().as_bytes());
+        let response = app
+            .clone()
+            .oneshot(compressed_event("gzip", body))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert_eq!(state.buffer.len(), 1);
+
+        // 1 MB of JSON whitespace compresses to about 1 KB but must not be
+        // inflated past the body limit.
+        let mut bomb = b"{\"d\": \"example.com\",".to_vec();
+        bomb.resize(1024 * 1024, b' ');
+        let response = app
+            .clone()
+            .oneshot(compressed_event("gzip", gzip(&bomb)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let response = app
+            .clone()
+            .oneshot(compressed_event("gzip", b"not gzip".to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = app
+            .oneshot(compressed_event("zstd", b"{}".to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(state.buffer.len(), 1);
+    }
+
     #[tokio::test]
     async fn test_stats_main_empty() {
         let (state, _dir) = make_test_state();
@@ -765,6 +1455,65 @@ mod tests {
         assert_eq!(response.status(), StatusCode::OK);
     }
 
+    #[test]
+    fn test_dashboard_csp_header() {
+        let default = DashboardCsp::new(&crate::config::CspConfig::default(), None, &[]);
+        assert_eq!(default.header(None), DEFAULT_CSP);
+
+        let config = crate::config::CspConfig {
+            script_src: vec!["https://cdn.example.com".to_string()],
+            connect_src: vec!["https://api.example.com".to_string()],
+            nonce: true,
+            ..crate::config::CspConfig::default()
+        };
+        let csp = DashboardCsp::new(&config, Some("https://logo.example.com"), &[]);
+        assert_eq!(
+            csp.header(Some("abc")),
+            "default-src 'self'; \
+             script-src 'self' 'nonce-abc' https://cdn.example.com; \
+             style-src 'self' 'nonce-abc'; \
+             img-src 'self' https://logo.example.com; \
+             connect-src 'self' https://api.example.com"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dashboard_csp_nonce() {
+        let dashboard = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dashboard.path().join("index.html"),
+            "<script nonce=\"{{csp_nonce}}\">boot()</script>",
+        )
+        .unwrap();
+        let (mut state, _dir) = make_test_state();
+        let state_mut = Arc::get_mut(&mut state).unwrap();
+        state_mut.dashboard_dir = Some(dashboard.path().to_path_buf());
+        state_mut.csp.nonce = true;
+
+        let mut nonces = Vec::new();
+        for _ in 0..2 {
+            let response = build_router(Arc::clone(&state))
+                .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            let policy = response.headers()["content-security-policy"]
+                .to_str()
+                .unwrap()
+                .to_string();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let html = String::from_utf8(body.to_vec()).unwrap();
+            let nonce = html
+                .strip_prefix("<script nonce=\"")
+                .and_then(|rest| rest.split('"').next())
+                .unwrap()
+                .to_string();
+            assert_eq!(nonce.len(), 32);
+            assert!(policy.contains(&format!("script-src 'self' 'nonce-{nonce}'")));
+            nonces.push(nonce);
+        }
+        assert_ne!(nonces[0], nonces[1]);
+    }
+
     #[tokio::test]
     async fn test_detailed_health_check() {
         let (state, _dir) = make_test_state();
@@ -790,6 +1539,35 @@ mod tests {
         assert_eq!(json["auth_configured"], false);
         assert_eq!(json["geoip_loaded"], false);
         assert_eq!(json["filter_bots"], false);
+        assert!(json["background_tasks"].is_object());
+    }
+
+    #[tokio::test]
+    async fn test_capabilities_report_fallbacks() {
+        let (state, _dir) = make_test_state();
+        let app = build_router(state);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/capabilities")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["behavioral_extension_loaded"], false);
+        let features = json["features"].as_array().unwrap();
+        let sessions = features.iter().find(|f| f["name"] == "sessions").unwrap();
+        assert_eq!(sessions["available"], true);
+        assert_eq!(sessions["degraded"], true);
+        assert!(sessions["reason"].is_string());
+        let geo = features.iter().find(|f| f["name"] == "geo").unwrap();
+        assert_eq!(geo["available"], false);
     }
 
     #[tokio::test]
@@ -810,6 +1588,23 @@ mod tests {
         assert_eq!(response.status(), StatusCode::OK);
     }
 
+    #[tokio::test]
+    async fn test_draining_fails_readiness_and_closes_connections() {
+        let (state, _dir) = make_test_state();
+        let app = build_router(Arc::clone(&state));
+        let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+        let response = app.clone().oneshot(get("/health")).await.unwrap();
+        assert!(response.headers().get(header::CONNECTION).is_none());
+
+        state.drain.start();
+        let response = app.clone().oneshot(get("/health/ready")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let response = app.oneshot(get("/health")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONNECTION], "close");
+    }
+
     #[tokio::test]
     async fn test_not_found() {
         let (state, _dir) = make_test_state();
@@ -851,6 +1646,113 @@ mod tests {
             .contains_key("access-control-allow-origin"));
     }
 
+    #[tokio::test]
+    async fn test_dashboard_cors_preflight() {
+        let preflight = |origin: &str| {
+            Request::builder()
+                .method("OPTIONS")
+                .uri("/api/stats/main?site_id=a.com")
+                .header("origin", origin)
+                .header("access-control-request-method", "GET")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // No dashboard origins: same-origin only.
+        let (state, _dir) = make_test_state();
+        let response = build_router(state)
+            .oneshot(preflight("https://evil.example.com"))
+            .await
+            .unwrap();
+        assert!(!response
+            .headers()
+            .contains_key("access-control-allow-origin"));
+
+        let (mut state, _dir) = make_test_state();
+        let state_mut = Arc::get_mut(&mut state).unwrap();
+        state_mut.dashboard_origin = Some("https://analytics.example.com".to_string());
+        state_mut.dashboard_origins = vec!["https://admin.example.com".to_string()];
+        let app = build_router(state);
+
+        let response = app
+            .clone()
+            .oneshot(preflight("https://admin.example.com"))
+            .await
+            .unwrap();
+        let headers = response.headers();
+        assert_eq!(
+            headers["access-control-allow-origin"],
+            "https://admin.example.com"
+        );
+        assert_eq!(headers["access-control-allow-credentials"], "true");
+
+        let login = Request::builder()
+            .method("OPTIONS")
+            .uri("/api/auth/login")
+            .header("origin", "https://analytics.example.com")
+            .header("access-control-request-method", "POST")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(login).await.unwrap();
+        assert_eq!(
+            response.headers()["access-control-allow-origin"],
+            "https://analytics.example.com"
+        );
+
+        let response = app
+            .oneshot(preflight("https://evil.example.com"))
+            .await
+            .unwrap();
+        assert!(!response
+            .headers()
+            .contains_key("access-control-allow-origin"));
+    }
+
+    #[tokio::test]
+    async fn test_tracking_script_integrity() {
+        let (state, _dir) = make_test_state();
+        let app = build_router(state);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/mallard.js")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["access-control-allow-origin"], "*");
+        let script = response.into_body().collect().await.unwrap().to_bytes();
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/meta/script-integrity?site_id=a.com")
+                    .header("host", "analytics.example.com")
+                    .header("x-forwarded-proto", "https")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let integrity = script::integrity(&script);
+        assert_eq!(json["integrity"], integrity.as_str());
+        assert_eq!(json["src"], "https://analytics.example.com/mallard.js");
+        assert_eq!(
+            json["snippet"],
+            format!(
+                "<script async defer src=\"https://analytics.example.com/mallard.js\" \
+                 integrity=\"{integrity}\" crossorigin=\"anonymous\" data-domain=\"a.com\"></script>"
+            )
+        );
+    }
+
     #[tokio::test]
     async fn test_security_headers_present() {
         let (state, _dir) = make_test_state();
@@ -872,9 +1774,61 @@ mod tests {
         assert!(headers.contains_key("referrer-policy"));
         assert!(headers.contains_key("permissions-policy"));
         assert!(headers.contains_key("strict-transport-security"));
+        assert_eq!(headers["cross-origin-opener-policy"], "same-origin");
         assert!(headers.contains_key("x-request-id"));
     }
 
+    #[tokio::test]
+    async fn test_request_id() {
+        let (state, _dir) = make_test_state();
+        let app = build_router(state);
+        let request = |id: &str| {
+            Request::builder()
+                .uri("/api/stats/main")
+                .header("x-request-id", id)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(request("edge-1a2b:7")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.headers()["x-request-id"], "edge-1a2b:7");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["request_id"], "edge-1a2b:7");
+
+        // Unusable inbound IDs are replaced.
+        for id in ["forged id=1", &"x".repeat(MAX_REQUEST_ID_LEN + 1)] {
+            let response = app.clone().oneshot(request(id)).await.unwrap();
+            let generated = response.headers()["x-request-id"].to_str().unwrap();
+            assert!(uuid::Uuid::parse_str(generated).is_ok(), "{id}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_security_headers_configured() {
+        let (mut state, _dir) = make_test_state();
+        let config = &mut Arc::get_mut(&mut state).unwrap().security_headers;
+        config.hsts = false;
+        config.permissions_policy = String::new();
+        config.frame_ancestors = vec!["https://intranet.example.com".to_string()];
+        let app = build_router(state);
+
+        let response = app
+            .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        let headers = response.headers();
+        assert!(!headers.contains_key("x-frame-options"));
+        assert!(!headers.contains_key("strict-transport-security"));
+        assert!(!headers.contains_key("permissions-policy"));
+        assert!(headers["content-security-policy"]
+            .to_str()
+            .unwrap()
+            .ends_with("; frame-ancestors 'self' https://intranet.example.com"));
+    }
+
     #[tokio::test]
     async fn test_cache_control_on_json_api_response() {
         let (state, _dir) = make_test_state();
@@ -972,6 +1926,39 @@ mod tests {
         assert_eq!(&body[..6], b"GIF89a");
     }
 
+    #[tokio::test]
+    async fn test_email_pixel_records_open() {
+        let (state, _dir) = make_test_state();
+        let app = build_router(Arc::clone(&state));
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/pixel/example.com.gif?c=spring%20sale")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..6], b"GIF89a");
+        assert_eq!(state.buffer.len(), 1);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/pixel/example.com.png")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(state.buffer.len(), 1);
+    }
+
     #[tokio::test]
     async fn test_hsts_header_present() {
         let (state, _dir) = make_test_state();
@@ -1023,13 +2010,30 @@ mod tests {
             sessions: crate::api::auth::SessionStore::new(3600),
             api_keys: crate::api::auth::ApiKeyStore::default(),
             admin_password_hash: Mutex::new(None),
+            admin_password_path: None,
+            locale: crate::api::locale::Locale::default(),
+            branding: crate::config::BrandingConfig::default(),
+            csp: crate::config::CspConfig::default(),
+            security_headers: crate::config::SecurityHeadersConfig::default(),
+            dashboard_dir: None,
             dashboard_origin: None,
+            dashboard_origins: Vec::new(),
             query_cache: crate::query::cache::QueryCache::new(0, 0),
+            cache_warming: std::sync::atomic::AtomicBool::new(false),
+            public_summary_cache: crate::query::cache::QueryCache::new(0, 0),
+            slow_queries: Arc::new(crate::query::slow::SlowQueryLog::default()),
+            duckdb_usage: crate::storage::resources::ResourceMonitor::default(),
+            shadow_queries: crate::query::shadow::ShadowQueries::default(),
+            forwarder: None,
+            tasks: crate::watchdog::TaskRegistry::default(),
+            drain: crate::upgrade::Drain::default(),
             rate_limiter: crate::ingest::ratelimit::RateLimiter::new(0),
             login_attempt_tracker: crate::api::auth::LoginAttemptTracker::new(0, 300),
             events_ingested_total: Arc::new(std::sync::atomic::AtomicU64::new(0)),
             flush_failures_total: Arc::new(std::sync::atomic::AtomicU64::new(0)),
+            flush_consecutive_failures: Arc::new(std::sync::atomic::AtomicU64::new(0)),
             rate_limit_rejections_total: Arc::new(std::sync::atomic::AtomicU64::new(0)),
+            ingest_stats: crate::ingest::stats::IngestStats::new(),
             login_failures_total: Arc::new(std::sync::atomic::AtomicU64::new(0)),
             metrics_token: None,
             query_semaphore: Arc::new(tokio::sync::Semaphore::new(0)), // 0 permits → always 429
@@ -1043,6 +2047,32 @@ mod tests {
             suppress_screen_size: false,
             geoip_precision: "city".to_string(),
             events_dir: dir.path().to_path_buf(),
+            sites: std::collections::HashMap::new(),
+            dashboards: dashboards::DashboardStore::default(),
+            notifications: notifications::NotificationStore::default(),
+            export_jobs: export_jobs::ExportJobs::new(
+                dir.path().join("exports"),
+                std::time::Duration::from_secs(3600),
+            ),
+            import_jobs: import_jobs::ImportJobs::new(
+                dir.path().join("imports"),
+                std::time::Duration::from_secs(3600),
+            ),
+            stats_rate_limiter: crate::ingest::ratelimit::RateLimiter::per_minute(0),
+            stats_rate_limit_rejections_total: std::sync::Arc::new(
+                std::sync::atomic::AtomicU64::new(0),
+            ),
+            debug_ingest: false,
+            transforms: crate::ingest::transform::IngestTransforms::default(),
+            ingest_queue: None,
+            spam_blocklist: Arc::new(crate::ingest::spam::SpamBlocklist::default()),
+            geo_blocked_events_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+            dedupe: crate::ingest::dedupe::Deduplicator::new(std::time::Duration::from_secs(60)),
+            max_event_time_skew: chrono::TimeDelta::hours(48),
+            idempotency: crate::ingest::idempotency::IdempotencyKeys::new(
+                std::time::Duration::from_secs(3600),
+            ),
+            duplicate_events_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
         });
         let _dir = dir;
         let app = build_router(state);
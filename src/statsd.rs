@@ -0,0 +1,212 @@
+use duckdb::Connection;
+use std::net::{ToSocketAddrs, UdpSocket};
+
+/// Largest UDP payload sent in one packet; fits a 1500-byte MTU with headers.
+const MAX_PACKET_BYTES: usize = 1432;
+
+/// Today's traffic totals for one site.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SiteCounts {
+    pub site_id: String,
+    pub visitors: u64,
+    pub pageviews: u64,
+}
+
+/// Per-site unique visitors and pageviews for events in `[start, end)`.
+pub fn query_site_counts(
+    conn: &Connection,
+    start: &str,
+    end: &str,
+) -> Result<Vec<SiteCounts>, duckdb::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT site_id,
+                COUNT(DISTINCT visitor_id) AS visitors,
+                COUNT(*) FILTER (WHERE event_name = 'pageview') AS pageviews
+         FROM events_all
+         WHERE timestamp >= CAST(? AS TIMESTAMP) AND timestamp < CAST(? AS TIMESTAMP)
+         GROUP BY site_id
+         ORDER BY site_id",
+    )?;
+    let rows = stmt
+        .query_map(duckdb::params![start, end], |row| {
+            Ok(SiteCounts {
+                site_id: row.get(0)?,
+                visitors: row.get(1)?,
+                pageviews: row.get(2)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(rows)
+}
+
+/// Replace characters StatsD treats as separators (`.`, `:`, `|`, `@`, `#`)
+/// so a site ID like `example.com` stays a single path segment.
+fn metric_segment(site_id: &str) -> String {
+    site_id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Format counts as StatsD gauge lines, e.g.
+/// `mallard.example_com.visitors_today:42|g`.
+///
+/// Gauges of running daily totals (rather than counters of new events) mean a
+/// lost packet or a late flush is corrected by the next push instead of being
+/// dropped or double-counted.
+pub fn format_gauges(prefix: &str, counts: &[SiteCounts]) -> Vec<String> {
+    counts
+        .iter()
+        .flat_map(|c| {
+            let site = metric_segment(&c.site_id);
+            [
+                format!("{prefix}.{site}.visitors_today:{}|g", c.visitors),
+                format!("{prefix}.{site}.pageviews_today:{}|g", c.pageviews),
+            ]
+        })
+        .collect()
+}
+
+/// Group lines into newline-separated packets no larger than
+/// [`MAX_PACKET_BYTES`].  A single oversized line gets a packet of its own.
+fn pack_lines(lines: &[String]) -> Vec<String> {
+    let mut packets = Vec::new();
+    let mut current = String::new();
+    for line in lines {
+        if !current.is_empty() && current.len() + 1 + line.len() > MAX_PACKET_BYTES {
+            packets.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(line);
+    }
+    if !current.is_empty() {
+        packets.push(current);
+    }
+    packets
+}
+
+/// Fire-and-forget StatsD client.
+pub struct StatsdClient {
+    socket: UdpSocket,
+}
+
+impl StatsdClient {
+    /// Resolve `addr` (`host:port`) and bind a local UDP socket for it.
+    pub fn connect(addr: &str) -> std::io::Result<Self> {
+        let target = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("statsd address {addr:?} did not resolve"),
+            )
+        })?;
+        let bind = if target.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind)?;
+        socket.connect(target)?;
+        Ok(Self { socket })
+    }
+
+    /// Send the lines, batched into as few packets as fit.
+    pub fn send(&self, lines: &[String]) -> std::io::Result<()> {
+        for packet in pack_lines(lines) {
+            self.socket.send(packet.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_gauges_sanitizes_site_id() {
+        let lines = format_gauges(
+            "mallard",
+            &[SiteCounts {
+                site_id: "example.com:8080".to_string(),
+                visitors: 42,
+                pageviews: 100,
+            }],
+        );
+        assert_eq!(
+            lines,
+            vec![
+                "mallard.example_com_8080.visitors_today:42|g",
+                "mallard.example_com_8080.pageviews_today:100|g",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pack_lines_respects_packet_size() {
+        let line = "x".repeat(600);
+        let lines = vec![line.clone(), line.clone(), line];
+        let packets = pack_lines(&lines);
+        assert_eq!(packets.len(), 2);
+        assert!(packets.iter().all(|p| p.len() <= MAX_PACKET_BYTES));
+        assert_eq!(packets[0].matches('\n').count(), 1);
+    }
+
+    #[test]
+    fn test_query_site_counts() {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::storage::schema::init_schema(&conn).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        crate::storage::schema::setup_query_view(&conn, dir.path()).unwrap();
+        for (site, vid, event) in [
+            ("a.com", "v1", "pageview"),
+            ("a.com", "v1", "pageview"),
+            ("a.com", "v2", "signup"),
+            ("b.com", "v3", "pageview"),
+        ] {
+            conn.execute(
+                "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+                 VALUES (?, ?, '2024-01-15 10:00:00', ?, '/')",
+                duckdb::params![site, vid, event],
+            )
+            .unwrap();
+        }
+
+        let counts = query_site_counts(&conn, "2024-01-15", "2024-01-16").unwrap();
+        assert_eq!(
+            counts,
+            vec![
+                SiteCounts {
+                    site_id: "a.com".to_string(),
+                    visitors: 2,
+                    pageviews: 2,
+                },
+                SiteCounts {
+                    site_id: "b.com".to_string(),
+                    visitors: 1,
+                    pageviews: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_client_sends_udp() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = StatsdClient::connect(&server.local_addr().unwrap().to_string()).unwrap();
+        client
+            .send(&["a:1|g".to_string(), "b:2|g".to_string()])
+            .unwrap();
+
+        let mut buf = [0u8; 64];
+        let n = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"a:1|g\nb:2|g");
+    }
+}
@@ -0,0 +1,1045 @@
+//! One-off storage operations run from the command line: compaction, import,
+//! export and verification.
+//!
+//! They take the DuckDB connection of a stopped server.  DuckDB locks the
+//! database file, so opening it while the server runs fails instead of racing
+//! with the server's own flushes.  Verification only reads, and the server
+//! also runs it for `GET /api/admin/verify`.  Imports only insert into the
+//! `events` table, as ingestion does, and the server also runs them for
+//! `POST /api/imports`.  Retention is the server's own: its daily task runs
+//! [`apply_retention`] while holding the connection.
+
+use crate::storage::manifest::{self, Manifest};
+use crate::storage::parquet::{
+    read_footers, remove_partition, ExpiredPartition, ParquetStorage, PartitionFile,
+};
+use crate::storage::schema::{
+    events_between, parquet_select, parquet_write_columns, setup_query_view, EVENT_COLUMNS,
+    EVENT_SCHEMA_VERSION, SCHEMA_VERSION_KEY,
+};
+use crate::storage::summary;
+use duckdb::Connection;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, thiserror::Error)]
+pub enum MaintenanceError {
+    #[error("Database error: {0}")]
+    Database(#[from] duckdb::Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Unsupported file type {0:?}; expected .csv, .json, .ndjson or .parquet")]
+    UnsupportedFormat(String),
+    #[error("{0} has no event columns")]
+    NoEventColumns(String),
+}
+
+/// File formats accepted by [`import_events`] and [`export_events`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FileFormat {
+    Csv,
+    Json,
+    Parquet,
+}
+
+impl FileFormat {
+    fn from_path(path: &Path) -> Result<Self, MaintenanceError> {
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        match ext.as_str() {
+            "csv" => Ok(Self::Csv),
+            "json" | "jsonl" | "ndjson" => Ok(Self::Json),
+            "parquet" => Ok(Self::Parquet),
+            _ => Err(MaintenanceError::UnsupportedFormat(ext)),
+        }
+    }
+}
+
+/// `path` as a quoted SQL string literal.
+fn sql_path(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', "''"))
+}
+
+/// Merge the Parquet files of each partition into one file.
+///
+/// Partitions with a single file are left alone, unless the site has since
+/// joined a tenant and the file is moved into its directory.  Each merged
+/// file is written under a temporary name, renamed into place and listed in
+/// the site's manifest in place of the files it replaces, which are deleted
+/// last.  A crash leaves either the old files or the merged one listed, so
+/// readers do not see duplicates, except for a file moved into a tenant's
+/// directory, which another manifest lists until the move completes.  Files
+/// the manifests do not list are not compacted.  Merged files are written
+/// with the storage's write options, and a [summary sidecar](summary) that
+/// replaces those of the merged files.  Returns the number of partitions
+/// compacted.
+pub fn compact_partitions(
+    conn: &Connection,
+    storage: &ParquetStorage,
+    site_filter: Option<&str>,
+) -> Result<usize, MaintenanceError> {
+    let mut partitions: BTreeMap<(String, String), Vec<PartitionFile>> = BTreeMap::new();
+    for file in storage.list_files(conn, site_filter)? {
+        if !file.listed {
+            continue;
+        }
+        partitions
+            .entry((file.site_id.clone(), file.date.clone()))
+            .or_default()
+            .push(file);
+    }
+
+    let columns = parquet_write_columns();
+    let write_options = storage.write_options().copy_options();
+    let mut compacted = 0;
+    for ((site_id, date), files) in partitions {
+        // A single file is rewritten only to move it into its tenant's
+        // directory.
+        let dir = storage.partition_dir(&site_id, &date);
+        if files.len() < 2
+            && files
+                .iter()
+                .all(|f| Path::new(&f.path).parent() == Some(dir.as_path()))
+        {
+            continue;
+        }
+        let paths: Vec<&str> = files.iter().map(|f| f.path.as_str()).collect();
+        fs::create_dir_all(&dir)?;
+        let tmp = dir.join("compact.tmp");
+        conn.execute_batch(&format!(
+            "COPY (SELECT {columns} FROM ({}) ORDER BY timestamp) TO {} \
+             (FORMAT PARQUET, {write_options}, KV_METADATA {{{SCHEMA_VERSION_KEY}: '{EVENT_SCHEMA_VERSION}'}})",
+            parquet_select(conn, &paths)?,
+            sql_path(&tmp),
+        ))?;
+        let merged = storage.next_file_path(&site_id, &date);
+        fs::rename(&tmp, &merged)?;
+        summary::write(
+            conn,
+            &merged,
+            &site_id,
+            &format!(
+                "SELECT * FROM read_parquet({}, hive_partitioning=false)",
+                sql_path(&merged)
+            ),
+        );
+
+        let entry = manifest::describe(conn, &merged)?;
+        let replaced: Vec<_> = paths
+            .iter()
+            .filter_map(|p| manifest::locate(Path::new(p)))
+            .collect();
+        let site_dir = dir.parent().unwrap_or(dir.as_path());
+        manifest::update(site_dir, |m| {
+            m.files.retain(|f| {
+                !replaced
+                    .iter()
+                    .any(|(d, rel)| d == site_dir && *rel == f.path)
+            });
+            m.files.push(entry);
+        })?;
+        // Files moved in from another directory, e.g. into a tenant's.
+        for (old_dir, rel) in replaced.iter().filter(|(d, _)| d != site_dir) {
+            manifest::unlist(old_dir, |f| f.path == *rel)?;
+        }
+        for path in &paths {
+            fs::remove_file(path)?;
+            summary::remove(Path::new(path))?;
+        }
+        tracing::info!(site_id, date, files = paths.len(), "Compacted partition");
+        compacted += 1;
+    }
+
+    if compacted > 0 {
+        setup_query_view(conn, storage.base_dir())?;
+    }
+    Ok(compacted)
+}
+
+/// Delete the partitions past their retention period and refresh
+/// `events_all` to match.
+///
+/// Hold the connection's lock across the call: no query then runs between
+/// the files going and the view dropping them, which would fail reading a
+/// missing file.  Returns the partitions removed, so the caller can drop
+/// cached results for their sites.
+pub fn apply_retention(
+    conn: &Connection,
+    storage: &ParquetStorage,
+    retention_days: u32,
+) -> Result<Vec<ExpiredPartition>, MaintenanceError> {
+    let expired = storage.expired_partitions(retention_days)?;
+    for partition in &expired {
+        remove_partition(&partition.path)?;
+    }
+    if !expired.is_empty() {
+        setup_query_view(conn, storage.base_dir())?;
+    }
+    Ok(expired)
+}
+
+/// Extensions of the files [`import_events`] reads.
+pub const IMPORT_EXTENSIONS: [&str; 5] = ["csv", "json", "jsonl", "ndjson", "parquet"];
+
+/// Event columns an imported file must have, as they may not be null.
+const REQUIRED_COLUMNS: [&str; 5] = [
+    "site_id",
+    "visitor_id",
+    "timestamp",
+    "event_name",
+    "pathname",
+];
+
+/// The DuckDB table function reading the file at `path`.
+fn import_source(path: &Path) -> Result<String, MaintenanceError> {
+    Ok(match FileFormat::from_path(path)? {
+        FileFormat::Csv => format!("read_csv_auto({})", sql_path(path)),
+        FileFormat::Json => format!("read_json_auto({})", sql_path(path)),
+        FileFormat::Parquet => {
+            format!("read_parquet({}, hive_partitioning=false)", sql_path(path))
+        }
+    })
+}
+
+/// The columns of `source`, in file order.
+fn source_columns(conn: &Connection, source: &str) -> Result<Vec<String>, MaintenanceError> {
+    let mut stmt = conn.prepare(&format!("DESCRIBE SELECT * FROM {source}"))?;
+    let columns = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<_, _>>()?;
+    Ok(columns)
+}
+
+/// The event columns among `present`, joined for a column list.
+fn event_columns(path: &Path, present: &[String]) -> Result<String, MaintenanceError> {
+    let columns: Vec<&str> = EVENT_COLUMNS
+        .iter()
+        .map(|(name, _)| *name)
+        .filter(|name| present.iter().any(|p| p == name))
+        .collect();
+    if columns.is_empty() {
+        return Err(MaintenanceError::NoEventColumns(path.display().to_string()));
+    }
+    Ok(columns.join(", "))
+}
+
+/// Insert the events in a CSV, JSON (one object per line) or Parquet file
+/// into the `events` table, matching columns by name.
+///
+/// Columns that are not event columns, such as `schema_version` in exported
+/// Parquet files, are ignored.  Flush afterwards to write the events to
+/// Parquet.  Returns the number of events inserted.
+pub fn import_events(conn: &Connection, path: &Path) -> Result<usize, MaintenanceError> {
+    insert_events(conn, path, "")
+}
+
+/// Like [`import_events`], for the `limit` rows of the file after the first
+/// `offset`, so a large file can be imported in parts.
+pub fn import_events_part(
+    conn: &Connection,
+    path: &Path,
+    offset: u64,
+    limit: u64,
+) -> Result<usize, MaintenanceError> {
+    insert_events(conn, path, &format!(" LIMIT {limit} OFFSET {offset}"))
+}
+
+fn insert_events(conn: &Connection, path: &Path, clause: &str) -> Result<usize, MaintenanceError> {
+    let source = import_source(path)?;
+    let columns = event_columns(path, &source_columns(conn, &source)?)?;
+    let inserted = conn.execute(
+        &format!("INSERT INTO events ({columns}) SELECT {columns} FROM {source}{clause}"),
+        [],
+    )?;
+    Ok(inserted)
+}
+
+/// What importing a file would do, from [`preview_import`].
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct ImportPreview {
+    pub rows: u64,
+    /// Event columns found in the file, which are imported.
+    pub columns: Vec<String>,
+    /// Other columns, which are ignored.
+    pub ignored_columns: Vec<String>,
+    /// Distinct site IDs of the rows.
+    pub sites: Vec<String>,
+    /// Problems that would make the import fail.  Empty when it can go
+    /// ahead.
+    pub errors: Vec<String>,
+}
+
+/// Check a file for [`import_events`] without inserting anything: count its
+/// rows, sort its columns and report missing required columns and rows
+/// whose required values are missing or invalid.
+///
+/// A file DuckDB cannot read fails with the reader's error.
+pub fn preview_import(conn: &Connection, path: &Path) -> Result<ImportPreview, MaintenanceError> {
+    let source = import_source(path)?;
+    let present = source_columns(conn, &source)?;
+    let (columns, ignored_columns): (Vec<String>, Vec<String>) = present
+        .into_iter()
+        .partition(|name| EVENT_COLUMNS.iter().any(|(c, _)| c == name));
+
+    let mut errors: Vec<String> = REQUIRED_COLUMNS
+        .iter()
+        .filter(|name| !columns.iter().any(|c| c == *name))
+        .map(|name| format!("Missing required column: {name}"))
+        .collect();
+
+    // Checks of the required columns present, as (description, condition).
+    let mut checks: Vec<(String, String)> = REQUIRED_COLUMNS
+        .iter()
+        .filter(|name| columns.iter().any(|c| c == *name))
+        .map(|name| (format!("no {name}"), format!("{name} IS NULL")))
+        .collect();
+    if columns.iter().any(|c| c == "timestamp") {
+        checks.push((
+            "an invalid timestamp".to_string(),
+            "timestamp IS NOT NULL AND TRY_CAST(timestamp AS TIMESTAMP) IS NULL".to_string(),
+        ));
+    }
+    if columns.iter().any(|c| c == "site_id") {
+        checks.push((
+            "an invalid site_id".to_string(),
+            "NOT regexp_full_match(CAST(site_id AS VARCHAR), '[A-Za-z0-9._:-]{1,256}')".to_string(),
+        ));
+    }
+    let mut counts = String::new();
+    for (_, condition) in &checks {
+        let _ = write!(counts, ", COUNT(*) FILTER (WHERE {condition})");
+    }
+    let (rows, failing) = conn.query_row(
+        &format!("SELECT COUNT(*){counts} FROM {source}"),
+        [],
+        |row| {
+            let failing = (1..=checks.len())
+                .map(|i| row.get::<_, u64>(i))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok((row.get::<_, u64>(0)?, failing))
+        },
+    )?;
+    if rows == 0 {
+        errors.push("The file has no rows".to_string());
+    }
+    for ((description, _), count) in checks.iter().zip(failing) {
+        if count > 0 {
+            errors.push(format!("Rows with {description}: {count}"));
+        }
+    }
+
+    let mut sites = Vec::new();
+    if columns.iter().any(|c| c == "site_id") {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT DISTINCT CAST(site_id AS VARCHAR) AS s FROM {source}
+             WHERE site_id IS NOT NULL ORDER BY s"
+        ))?;
+        for site in stmt.query_map([], |row| row.get::<_, String>(0))? {
+            sites.push(site?);
+        }
+    }
+
+    Ok(ImportPreview {
+        rows,
+        columns,
+        ignored_columns,
+        sites,
+        errors,
+    })
+}
+
+/// Which events [`export_events`] writes.
+#[derive(Debug, Default)]
+pub struct ExportFilter<'a> {
+    pub site_id: Option<&'a str>,
+    /// Only events of these sites, e.g. one tenant's.
+    pub site_ids: Option<&'a [String]>,
+    /// First day included.
+    pub start_date: Option<chrono::NaiveDate>,
+    /// Last day included.
+    pub end_date: Option<chrono::NaiveDate>,
+}
+
+/// Write events from `events_all` to a CSV, JSON (one object per line) or
+/// Parquet file, ordered by timestamp.  Returns the number of events written.
+pub fn export_events(
+    conn: &Connection,
+    path: &Path,
+    filter: &ExportFilter<'_>,
+) -> Result<usize, MaintenanceError> {
+    let options = match FileFormat::from_path(path)? {
+        FileFormat::Csv => "FORMAT CSV, HEADER",
+        FileFormat::Json => "FORMAT JSON",
+        FileFormat::Parquet => "FORMAT PARQUET, COMPRESSION ZSTD",
+    };
+
+    // COPY does not take bound parameters; the site ID is quoted and the
+    // dates are formatted from parsed values.
+    let mut conditions = vec!["TRUE".to_string()];
+    if let Some(site_id) = filter.site_id {
+        conditions.push(format!("site_id = '{}'", site_id.replace('\'', "''")));
+    }
+    if let Some(site_ids) = filter.site_ids {
+        let list = site_ids
+            .iter()
+            .map(|s| format!("'{}'", s.replace('\'', "''")))
+            .collect::<Vec<_>>();
+        conditions.push(if list.is_empty() {
+            "FALSE".to_string()
+        } else {
+            format!("site_id IN ({})", list.join(", "))
+        });
+    }
+    if let Some(start) = filter.start_date {
+        conditions.push(format!("timestamp >= DATE '{start}'"));
+    }
+    if let Some(end) = filter.end_date {
+        conditions.push(format!("timestamp < DATE '{end}' + INTERVAL 1 DAY"));
+    }
+    let columns = EVENT_COLUMNS
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(", ");
+    // With dates, only the files overlapping them are read.
+    let source = if filter.start_date.is_some() || filter.end_date.is_some() {
+        let start = filter
+            .start_date
+            .map_or_else(|| "-infinity".to_string(), |d| d.to_string());
+        let end = filter
+            .end_date
+            .and_then(|d| d.succ_opt())
+            .map_or_else(|| "infinity".to_string(), |d| d.to_string());
+        events_between(conn, &start, &end)?
+    } else {
+        "events_all".to_string()
+    };
+    // COPY returns a single row holding the number of rows written.
+    let written: u64 = conn.query_row(
+        &format!(
+            "COPY (SELECT {columns} FROM {source} WHERE {} ORDER BY timestamp) TO {} ({options})",
+            conditions.join(" AND "),
+            sql_path(path),
+        ),
+        [],
+        |row| row.get(0),
+    )?;
+    Ok(usize::try_from(written).unwrap_or(usize::MAX))
+}
+
+/// What [`verify_partitions`] found wrong with a file or partition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProblemKind {
+    /// The footer or data cannot be read.
+    Corrupt,
+    /// Written by a newer release, or columns that do not match the schema
+    /// version the file claims.
+    SchemaDrift,
+    /// Rows whose site or date does not match the partition.
+    Misplaced,
+    /// Identical rows in more than one file of a partition, as left behind by
+    /// an interrupted compaction.
+    Duplicates,
+    /// Rows timestamped in the future or before 2000.
+    TimestampOutliers,
+    /// A file the site's manifest does not list, which queries ignore, as
+    /// left behind by an interrupted compaction or retention run.
+    Unlisted,
+    /// A file the site's manifest lists that is missing on disk.
+    Missing,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct VerifyProblem {
+    pub kind: ProblemKind,
+    /// The file, or for duplicates the partition directory.
+    pub path: String,
+    pub detail: String,
+}
+
+impl std::fmt::Display for VerifyProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.detail)
+    }
+}
+
+/// Result of [`verify_partitions`].
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct VerifyReport {
+    pub files_checked: usize,
+    pub rows_checked: u64,
+    pub problems: Vec<VerifyProblem>,
+}
+
+/// Check the Parquet files under `events_dir` and the site manifests.
+///
+/// Every file is checked for damage, schema drift, misplaced rows and
+/// timestamp outliers, every partition with several files for rows
+/// duplicated across them, and the manifests against the files on disk.
+///
+/// Read-only, so it is also safe against a running server's database.
+#[allow(clippy::too_many_lines)]
+pub fn verify_partitions(
+    conn: &Connection,
+    events_dir: &Path,
+) -> Result<VerifyReport, MaintenanceError> {
+    let storage = ParquetStorage::new(events_dir);
+    let files = storage.list_files(conn, None)?;
+    let mut report = VerifyReport {
+        files_checked: files.len(),
+        ..VerifyReport::default()
+    };
+    let mut problem = |kind, path: &str, detail: String| {
+        report.problems.push(VerifyProblem {
+            kind,
+            path: path.to_string(),
+            detail,
+        });
+    };
+    let latest = (chrono::Utc::now().naive_utc() + chrono::Duration::days(1))
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string();
+
+    let mut readable: BTreeMap<(&str, &str), Vec<&PartitionFile>> = BTreeMap::new();
+    let mut rows_checked = 0;
+    for file in &files {
+        let version = match read_footers(conn, std::slice::from_ref(file)) {
+            Ok(footers) => footers.get(&file.path).and_then(|(_, version)| *version),
+            Err(e) => {
+                problem(
+                    ProblemKind::Corrupt,
+                    &file.path,
+                    format!("unreadable footer: {e}"),
+                );
+                continue;
+            }
+        };
+        if version.is_some_and(|v| v > EVENT_SCHEMA_VERSION) {
+            problem(
+                ProblemKind::SchemaDrift,
+                &file.path,
+                format!(
+                    "schema version {} is newer than this release ({EVENT_SCHEMA_VERSION})",
+                    version.unwrap_or_default()
+                ),
+            );
+        }
+        match schema_drift(conn, file, version) {
+            Ok(drift) => {
+                for detail in drift {
+                    problem(ProblemKind::SchemaDrift, &file.path, detail);
+                }
+            }
+            Err(e) => {
+                problem(
+                    ProblemKind::Corrupt,
+                    &file.path,
+                    format!("unreadable schema: {e}"),
+                );
+                continue;
+            }
+        }
+
+        let counts = conn.query_row(
+            &format!(
+                "SELECT COUNT(*),
+                        COUNT(*) FILTER (WHERE site_id IS DISTINCT FROM ?
+                            OR STRFTIME(CAST(timestamp AS DATE), '%Y-%m-%d') IS DISTINCT FROM ?),
+                        COUNT(*) FILTER (WHERE timestamp > CAST(? AS TIMESTAMP)
+                            OR timestamp < TIMESTAMP '2000-01-01')
+                 FROM read_parquet({}, hive_partitioning=false)",
+                sql_path(Path::new(&file.path))
+            ),
+            duckdb::params![file.site_id, file.date, latest],
+            |row| {
+                Ok((
+                    row.get::<_, u64>(0)?,
+                    row.get::<_, u64>(1)?,
+                    row.get::<_, u64>(2)?,
+                ))
+            },
+        );
+        let (rows, misplaced, outliers) = match counts {
+            Ok(counts) => counts,
+            Err(e) => {
+                problem(
+                    ProblemKind::Corrupt,
+                    &file.path,
+                    format!("unreadable data: {e}"),
+                );
+                continue;
+            }
+        };
+        rows_checked += rows;
+        if misplaced > 0 {
+            problem(
+                ProblemKind::Misplaced,
+                &file.path,
+                format!(
+                    "{misplaced} rows outside partition site_id={}/date={}",
+                    file.site_id, file.date
+                ),
+            );
+        }
+        if outliers > 0 {
+            problem(
+                ProblemKind::TimestampOutliers,
+                &file.path,
+                format!("{outliers} rows timestamped in the future or before 2000"),
+            );
+        }
+        if !file.listed {
+            problem(
+                ProblemKind::Unlisted,
+                &file.path,
+                "not listed in the site's manifest".to_string(),
+            );
+            continue;
+        }
+        readable
+            .entry((&file.site_id, &file.date))
+            .or_default()
+            .push(file);
+    }
+
+    for site in storage.site_dirs()? {
+        let listed = match Manifest::load(&site.path) {
+            Ok(manifest) => manifest.map(|m| m.files).unwrap_or_default(),
+            Err(e) => {
+                problem(
+                    ProblemKind::Corrupt,
+                    &site.path.join(manifest::MANIFEST_FILE).to_string_lossy(),
+                    format!("unreadable manifest: {e}"),
+                );
+                continue;
+            }
+        };
+        for file in listed {
+            let path = site.path.join(&file.path);
+            if !path.exists() {
+                problem(
+                    ProblemKind::Missing,
+                    &path.to_string_lossy(),
+                    "listed in the site's manifest but missing".to_string(),
+                );
+            }
+        }
+    }
+
+    let columns = EVENT_COLUMNS
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(", ");
+    for partition in readable.values().filter(|files| files.len() > 1) {
+        let list = partition
+            .iter()
+            .map(|f| sql_path(Path::new(&f.path)))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let path = Path::new(&partition[0].path)
+            .parent()
+            .map_or_else(String::new, |p| p.display().to_string());
+        let duplicated = conn.query_row(
+            &format!(
+                "SELECT COUNT(*) FROM (
+                     SELECT 1 FROM read_parquet([{list}], hive_partitioning=false,
+                                                union_by_name=true, filename=true)
+                     GROUP BY {columns}
+                     HAVING COUNT(DISTINCT filename) > 1)"
+            ),
+            [],
+            |row| row.get::<_, u64>(0),
+        );
+        match duplicated {
+            Ok(0) => {}
+            Ok(n) => problem(
+                ProblemKind::Duplicates,
+                &path,
+                format!("{n} rows appear in more than one of its files"),
+            ),
+            Err(e) => problem(
+                ProblemKind::Corrupt,
+                &path,
+                format!("cannot compare files: {e}"),
+            ),
+        }
+    }
+    report.rows_checked = rows_checked;
+    Ok(report)
+}
+
+/// Differences between a file's columns and [`EVENT_COLUMNS`].
+///
+/// Files from older releases may lack later columns, which `events_all` fills
+/// with NULL, so missing columns and other types are only reported for files
+/// that claim the current schema version.
+fn schema_drift(
+    conn: &Connection,
+    file: &PartitionFile,
+    version: Option<u32>,
+) -> Result<Vec<String>, duckdb::Error> {
+    let mut stmt = conn.prepare(&format!(
+        "DESCRIBE SELECT * FROM read_parquet({}, hive_partitioning=false)",
+        sql_path(Path::new(&file.path))
+    ))?;
+    let actual: BTreeMap<String, String> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+
+    let mut drift = Vec::new();
+    let unknown: Vec<&str> = actual
+        .keys()
+        .map(String::as_str)
+        .filter(|name| {
+            *name != "schema_version" && !EVENT_COLUMNS.iter().any(|(col, _)| col == name)
+        })
+        .collect();
+    if !unknown.is_empty() {
+        drift.push(format!("unknown columns: {}", unknown.join(", ")));
+    }
+    if version != Some(EVENT_SCHEMA_VERSION) {
+        return Ok(drift);
+    }
+    let missing: Vec<&str> = EVENT_COLUMNS
+        .iter()
+        .map(|(name, _)| *name)
+        .filter(|name| !actual.contains_key(*name))
+        .collect();
+    if !missing.is_empty() {
+        drift.push(format!("missing columns: {}", missing.join(", ")));
+    }
+    for (name, expected) in EVENT_COLUMNS {
+        if let Some(ty) = actual.get(*name) {
+            if !ty.eq_ignore_ascii_case(expected) {
+                drift.push(format!("column {name} is {ty}, expected {expected}"));
+            }
+        }
+    }
+    Ok(drift)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::schema::init_schema;
+
+    fn setup() -> (Connection, tempfile::TempDir) {
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        setup_query_view(&conn, dir.path()).unwrap();
+        (conn, dir)
+    }
+
+    fn insert_event(conn: &Connection, site_id: &str, timestamp: &str) {
+        conn.execute(
+            "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+             VALUES (?, 'v1', CAST(? AS TIMESTAMP), 'pageview', '/')",
+            duckdb::params![site_id, timestamp],
+        )
+        .unwrap();
+    }
+
+    fn count(conn: &Connection, sql: &str) -> i64 {
+        conn.query_row(sql, [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn test_compact_merges_partition_files() {
+        let (conn, dir) = setup();
+        let storage = ParquetStorage::new(dir.path());
+        for hour in ["10", "11", "12"] {
+            insert_event(&conn, "test.com", &format!("2024-01-15 {hour}:00:00"));
+            storage.flush_events(&conn).unwrap();
+        }
+        insert_event(&conn, "other.com", "2024-01-15 10:00:00");
+        storage.flush_events(&conn).unwrap();
+
+        assert_eq!(compact_partitions(&conn, &storage, None).unwrap(), 1);
+        let files = storage.list_files(&conn, Some("test.com")).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].row_count, Some(3));
+        // The manifest lists the merged file alone.
+        let (site_dir, relative) = manifest::locate(Path::new(&files[0].path)).unwrap();
+        let listed = Manifest::load(&site_dir).unwrap().unwrap().files;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].path, relative);
+        assert_eq!(listed[0].rows, 3);
+        assert_eq!(
+            listed[0].min_timestamp.as_deref(),
+            Some("2024-01-15 10:00:00")
+        );
+        assert_eq!(
+            listed[0].max_timestamp.as_deref(),
+            Some("2024-01-15 12:00:00")
+        );
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM events_all"), 4);
+        // The merged file's summary replaces those of the files it merged.
+        let merged = Path::new(&files[0].path);
+        assert_eq!(summary::load(merged).unwrap().rows, 3);
+        let sidecars = fs::read_dir(merged.parent().unwrap())
+            .unwrap()
+            .filter(|e| {
+                e.as_ref()
+                    .unwrap()
+                    .path()
+                    .to_string_lossy()
+                    .ends_with(".summary.json")
+            })
+            .count();
+        assert_eq!(sidecars, 1);
+        let report = verify_partitions(&conn, dir.path()).unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        assert_eq!(report.files_checked, 2);
+        assert_eq!(report.rows_checked, 4);
+
+        // Nothing left to merge.
+        assert_eq!(compact_partitions(&conn, &storage, None).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_compact_moves_files_into_tenant_dir() {
+        let (conn, dir) = setup();
+        insert_event(&conn, "test.com", "2024-01-15 10:00:00");
+        ParquetStorage::new(dir.path()).flush_events(&conn).unwrap();
+
+        let tenants = crate::storage::parquet::Tenants::new(
+            std::collections::HashMap::from([("test.com".to_string(), "acme".to_string())]),
+            std::collections::HashMap::new(),
+        );
+        let storage = ParquetStorage::new(dir.path()).with_tenants(std::sync::Arc::new(tenants));
+        assert_eq!(compact_partitions(&conn, &storage, None).unwrap(), 1);
+        let files = storage.list_files(&conn, None).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].tenant_id.as_deref(), Some("acme"));
+        assert_eq!(files[0].row_count, Some(1));
+        assert_eq!(compact_partitions(&conn, &storage, None).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_apply_retention_refreshes_view() {
+        let (conn, dir) = setup();
+        let storage = ParquetStorage::new(dir.path());
+        let recent = chrono::Utc::now().format("%Y-%m-%d 10:00:00").to_string();
+        insert_event(&conn, "test.com", "2020-01-15 10:00:00");
+        insert_event(&conn, "test.com", &recent);
+        insert_event(&conn, "other.com", "2020-01-15 10:00:00");
+        storage.flush_events(&conn).unwrap();
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM events_all"), 3);
+
+        let expired = apply_retention(&conn, &storage, 30).unwrap();
+        let mut sites: Vec<_> = expired.iter().map(|p| p.site_id.as_str()).collect();
+        sites.sort_unstable();
+        assert_eq!(sites, ["other.com", "test.com"]);
+        assert!(expired
+            .iter()
+            .all(|p| p.date == "2020-01-15" && !p.path.exists()));
+        // The view no longer lists the deleted files, so it can be queried
+        // straight away.
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM events_all"), 1);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM parquet_files"), 1);
+
+        assert!(apply_retention(&conn, &storage, 30).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_export_then_import_round_trips() {
+        let (conn, dir) = setup();
+        insert_event(&conn, "test.com", "2024-01-15 10:00:00");
+        insert_event(&conn, "test.com", "2024-01-16 10:00:00");
+        insert_event(&conn, "other.com", "2024-01-15 10:00:00");
+
+        let names = ["events.csv", "events.ndjson", "events.parquet"];
+        let filter = ExportFilter {
+            site_id: Some("test.com"),
+            site_ids: None,
+            start_date: chrono::NaiveDate::from_ymd_opt(2024, 1, 15),
+            end_date: chrono::NaiveDate::from_ymd_opt(2024, 1, 15),
+        };
+        for name in names {
+            let path = dir.path().join(name);
+            assert_eq!(export_events(&conn, &path, &filter).unwrap(), 1, "{name}");
+        }
+        for name in names {
+            let path = dir.path().join(name);
+            assert_eq!(import_events(&conn, &path).unwrap(), 1, "{name}");
+        }
+        assert_eq!(
+            count(
+                &conn,
+                "SELECT COUNT(*) FROM events WHERE site_id = 'test.com'"
+            ),
+            5
+        );
+
+        let err = import_events(&conn, &dir.path().join("events.xlsx")).unwrap_err();
+        assert!(matches!(err, MaintenanceError::UnsupportedFormat(_)));
+
+        // A tenant's export covers exactly its sites.
+        let tenant_sites = ["other.com".to_string(), "none.com".to_string()];
+        let filter = ExportFilter {
+            site_ids: Some(&tenant_sites),
+            ..ExportFilter::default()
+        };
+        let path = dir.path().join("tenant.csv");
+        assert_eq!(export_events(&conn, &path, &filter).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_preview_and_import_in_parts() {
+        let (conn, dir) = setup();
+        let path = dir.path().join("events.csv");
+        fs::write(
+            &path,
+            "site_id,visitor_id,timestamp,event_name,pathname,note\n\
+             a.com,v1,2024-01-15 10:00:00,pageview,/,x\n\
+             b.com,v2,2024-01-15 11:00:00,pageview,/about,y\n\
+             b.com,v3,2024-01-15 12:00:00,signup,/about,z\n",
+        )
+        .unwrap();
+        let preview = preview_import(&conn, &path).unwrap();
+        assert_eq!(preview.rows, 3);
+        assert_eq!(preview.ignored_columns, vec!["note".to_string()]);
+        assert_eq!(
+            preview.sites,
+            vec!["a.com".to_string(), "b.com".to_string()]
+        );
+        assert!(preview.errors.is_empty(), "{:?}", preview.errors);
+
+        assert_eq!(import_events_part(&conn, &path, 0, 2).unwrap(), 2);
+        assert_eq!(import_events_part(&conn, &path, 2, 2).unwrap(), 1);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM events"), 3);
+
+        let bad = dir.path().join("bad.csv");
+        fs::write(
+            &bad,
+            "site_id,timestamp,event_name,pathname\n\
+             a.com,2024-01-15 10:00:00,pageview,/\n\
+             a com,2024-01-15 10:00:00,pageview,/\n",
+        )
+        .unwrap();
+        let preview = preview_import(&conn, &bad).unwrap();
+        assert_eq!(
+            preview.errors,
+            vec![
+                "Missing required column: visitor_id".to_string(),
+                "Rows with an invalid site_id: 1".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verify_reports_misplaced_rows() {
+        let (conn, dir) = setup();
+        let storage = ParquetStorage::new(dir.path());
+        insert_event(&conn, "test.com", "2024-01-15 10:00:00");
+        storage.flush_events(&conn).unwrap();
+
+        let wrong = storage.partition_dir("other.com", "2024-01-15");
+        fs::create_dir_all(&wrong).unwrap();
+        let from = storage.list_files(&conn, Some("test.com")).unwrap()[0]
+            .path
+            .clone();
+        fs::copy(&from, wrong.join("0001.parquet")).unwrap();
+
+        let problems = verify_partitions(&conn, dir.path()).unwrap().problems;
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].kind, ProblemKind::Misplaced);
+        assert!(problems[0].to_string().contains("outside partition"));
+    }
+
+    #[test]
+    fn test_verify_reports_duplicates_and_outliers() {
+        let (conn, dir) = setup();
+        let storage = ParquetStorage::new(dir.path());
+        insert_event(&conn, "test.com", "2024-01-15 10:00:00");
+        storage.flush_events(&conn).unwrap();
+        // A second listed copy of the file, as if a move into a tenant's
+        // directory stopped before unlisting its input.
+        let file = storage.list_files(&conn, Some("test.com")).unwrap()[0]
+            .path
+            .clone();
+        let copy = Path::new(&file).with_file_name("9999.parquet");
+        fs::copy(&file, &copy).unwrap();
+        let (site_dir, relative) = manifest::locate(&copy).unwrap();
+        manifest::update(&site_dir, |m| {
+            let mut entry = m.files[0].clone();
+            entry.path = relative;
+            m.files.push(entry);
+        })
+        .unwrap();
+        insert_event(&conn, "future.com", "2999-01-01 00:00:00");
+        storage.flush_events(&conn).unwrap();
+
+        let report = verify_partitions(&conn, dir.path()).unwrap();
+        assert_eq!(report.files_checked, 3);
+        let kinds: Vec<_> = report.problems.iter().map(|p| p.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![ProblemKind::TimestampOutliers, ProblemKind::Duplicates],
+            "{:?}",
+            report.problems
+        );
+        assert!(report.problems[1].detail.starts_with("1 rows"));
+    }
+
+    #[test]
+    fn test_verify_checks_manifests() {
+        let (conn, dir) = setup();
+        let storage = ParquetStorage::new(dir.path());
+        insert_event(&conn, "test.com", "2024-01-15 10:00:00");
+        storage.flush_events(&conn).unwrap();
+        let file = storage.list_files(&conn, Some("test.com")).unwrap()[0]
+            .path
+            .clone();
+        // An unlisted copy, as left by an interrupted compaction, and a listed
+        // file that is gone.
+        fs::copy(&file, Path::new(&file).with_file_name("9999.parquet")).unwrap();
+        let (site_dir, _) = manifest::locate(Path::new(&file)).unwrap();
+        manifest::update(&site_dir, |m| {
+            let mut entry = m.files[0].clone();
+            entry.path = "date=2024-01-16/0001.parquet".to_string();
+            m.files.push(entry);
+        })
+        .unwrap();
+
+        let files = storage.list_files(&conn, None).unwrap();
+        assert_eq!(
+            files.iter().map(|f| f.listed).collect::<Vec<_>>(),
+            vec![true, false]
+        );
+        let report = verify_partitions(&conn, dir.path()).unwrap();
+        let kinds: Vec<_> = report.problems.iter().map(|p| p.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![ProblemKind::Unlisted, ProblemKind::Missing],
+            "{:?}",
+            report.problems
+        );
+
+        // Queries see neither; compaction leaves the unlisted copy alone.
+        setup_query_view(&conn, dir.path()).unwrap();
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM events_all"), 1);
+        assert_eq!(compact_partitions(&conn, &storage, None).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_verify_reports_corrupt_files() {
+        let (conn, dir) = setup();
+        let storage = ParquetStorage::new(dir.path());
+        let partition = storage.partition_dir("test.com", "2024-01-15");
+        fs::create_dir_all(&partition).unwrap();
+        fs::write(partition.join("0001.parquet"), b"not parquet").unwrap();
+
+        let problems = verify_partitions(&conn, dir.path()).unwrap().problems;
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].kind, ProblemKind::Corrupt);
+    }
+}
@@ -0,0 +1,306 @@
+//! Per-site manifests of the Parquet files that make up the data lake.
+//!
+//! Every `site_id=` directory holds a `_manifest.json` listing the site's
+//! files with their row counts and timestamp ranges:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "site_id": "example.com",
+//!   "files": [
+//!     {
+//!       "path": "date=2024-01-15/0001.parquet",
+//!       "rows": 1042,
+//!       "min_timestamp": "2024-01-15 00:00:03",
+//!       "max_timestamp": "2024-01-15 23:59:41",
+//!       "size_bytes": 48213
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! A file is listed only once it is completely written, and unlisted before
+//! it is deleted.  Each change writes a whole new manifest under a temporary
+//! name and renames it over the old one, so readers that follow the manifest
+//! instead of the directory tree never open a half-written file, nor count a
+//! partition twice while compaction replaces its files.
+
+use duckdb::Connection;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the manifest in each `site_id=` directory.
+pub const MANIFEST_FILE: &str = "_manifest.json";
+
+/// Version of the manifest format.
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Serialises read-modify-write cycles: flushes, retention and GDPR erasure
+/// update manifests from different threads.
+static UPDATE_LOCK: parking_lot::Mutex<()> = parking_lot::const_mutex(());
+
+/// The files of one site.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub site_id: String,
+    pub files: Vec<ManifestFile>,
+}
+
+/// One Parquet file listed in a [`Manifest`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestFile {
+    /// Path relative to the site directory, e.g. `date=2024-01-15/0001.parquet`.
+    pub path: String,
+    pub rows: u64,
+    /// Earliest event timestamp, `YYYY-MM-DD HH:MM:SS`.  `None` for a file
+    /// without rows.
+    pub min_timestamp: Option<String>,
+    /// Latest event timestamp.
+    pub max_timestamp: Option<String>,
+    pub size_bytes: u64,
+}
+
+impl Manifest {
+    pub const fn new(site_id: String) -> Self {
+        Self {
+            version: MANIFEST_VERSION,
+            site_id,
+            files: Vec::new(),
+        }
+    }
+
+    /// The manifest of `site_dir`, or `None` if it has none.
+    pub fn load(site_dir: &Path) -> io::Result<Option<Self>> {
+        match fs::read(site_dir.join(MANIFEST_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Replace the manifest of `site_dir` with this one, atomically.
+    pub fn save(&self, site_dir: &Path) -> io::Result<()> {
+        let tmp = site_dir.join(format!("{MANIFEST_FILE}.tmp"));
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec_pretty(self)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, site_dir.join(MANIFEST_FILE))
+    }
+
+    pub fn contains(&self, path: &str) -> bool {
+        self.files.iter().any(|f| f.path == path)
+    }
+}
+
+/// The site directory holding a data file, and the file's path relative to
+/// it: `.../site_id=S/date=D/0001.parquet` gives `.../site_id=S` and
+/// `date=D/0001.parquet`.
+pub fn locate(file: &Path) -> Option<(PathBuf, String)> {
+    let partition = file.parent()?;
+    let site_dir = partition.parent()?;
+    let relative = format!(
+        "{}/{}",
+        partition.file_name()?.to_str()?,
+        file.file_name()?.to_str()?
+    );
+    Some((site_dir.to_path_buf(), relative))
+}
+
+/// Apply `change` to the manifest of `site_dir` and save it, starting an
+/// empty one if the site has none yet.
+pub fn update(site_dir: &Path, change: impl FnOnce(&mut Manifest)) -> io::Result<()> {
+    let _guard = UPDATE_LOCK.lock();
+    let mut manifest =
+        Manifest::load(site_dir)?.unwrap_or_else(|| Manifest::new(site_id_of(site_dir)));
+    change(&mut manifest);
+    manifest.save(site_dir)
+}
+
+/// Unlist the files of `site_dir` matching `remove`.  A site without a
+/// manifest is left alone: it is rebuilt from the files on disk at startup.
+pub fn unlist(site_dir: &Path, remove: impl Fn(&ManifestFile) -> bool) -> io::Result<()> {
+    let _guard = UPDATE_LOCK.lock();
+    let Some(mut manifest) = Manifest::load(site_dir)? else {
+        return Ok(());
+    };
+    let before = manifest.files.len();
+    manifest.files.retain(|f| !remove(f));
+    if manifest.files.len() == before {
+        return Ok(());
+    }
+    manifest.save(site_dir)
+}
+
+/// Read the manifest entry for `file` from the file itself.
+pub fn describe(conn: &Connection, file: &Path) -> Result<ManifestFile, duckdb::Error> {
+    let relative = locate(file).map(|(_, path)| path).unwrap_or_default();
+    let (rows, min_timestamp, max_timestamp) = conn.query_row(
+        &format!(
+            "SELECT COUNT(*), CAST(MIN(timestamp) AS VARCHAR), CAST(MAX(timestamp) AS VARCHAR)
+             FROM read_parquet('{}', hive_partitioning=false)",
+            file.to_string_lossy().replace('\'', "''")
+        ),
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+    Ok(ManifestFile {
+        path: relative,
+        rows,
+        min_timestamp,
+        max_timestamp,
+        size_bytes: fs::metadata(file).map_or(0, |m| m.len()),
+    })
+}
+
+/// Build the manifest of a site that has none, or whose manifest cannot be
+/// read, from the Parquet files in its `date=` directories, and save it.
+///
+/// Files that cannot be read are left out and logged; `verify-data` reports
+/// them.  A manifest that cannot be saved is logged, and rebuilt again at
+/// the next startup.  Fails, saving nothing, when the files cannot be
+/// listed, so an unreadable directory never leaves an empty manifest over
+/// files that still exist.
+pub fn rebuild(conn: &Connection, site_dir: &Path) -> io::Result<Manifest> {
+    let mut manifest = Manifest::new(site_id_of(site_dir));
+    for file in data_files(site_dir)? {
+        match describe(conn, &file) {
+            Ok(entry) => manifest.files.push(entry),
+            Err(e) => {
+                tracing::warn!(path = %file.display(), error = %e, "Leaving unreadable Parquet file out of the manifest");
+            }
+        }
+    }
+    let _guard = UPDATE_LOCK.lock();
+    if let Err(e) = manifest.save(site_dir) {
+        tracing::warn!(path = %site_dir.display(), error = %e, "Could not save rebuilt manifest");
+    }
+    Ok(manifest)
+}
+
+/// Every `*.parquet` file in the `date=` directories of `site_dir`, sorted.
+fn data_files(site_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for date_entry in fs::read_dir(site_dir)?.flatten() {
+        let name = date_entry.file_name();
+        if !name.to_string_lossy().starts_with("date=") || !date_entry.path().is_dir() {
+            continue;
+        }
+        for file_entry in fs::read_dir(date_entry.path())?.flatten() {
+            let path = file_entry.path();
+            if path.extension().and_then(|e| e.to_str()) == Some("parquet") {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// The site ID in a `site_id=` directory name.
+fn site_id_of(site_dir: &Path) -> String {
+    site_dir
+        .file_name()
+        .and_then(|n| n.to_str())
+        .and_then(|n| n.strip_prefix("site_id="))
+        .unwrap_or_default()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str) -> ManifestFile {
+        ManifestFile {
+            path: path.to_string(),
+            rows: 1,
+            min_timestamp: Some("2024-01-15 10:00:00".to_string()),
+            max_timestamp: Some("2024-01-15 10:00:00".to_string()),
+            size_bytes: 10,
+        }
+    }
+
+    #[test]
+    fn test_locate() {
+        let (site_dir, relative) = locate(Path::new(
+            "/data/site_id=a.com/date=2024-01-15/0001.parquet",
+        ))
+        .unwrap();
+        assert_eq!(site_dir, Path::new("/data/site_id=a.com"));
+        assert_eq!(relative, "date=2024-01-15/0001.parquet");
+    }
+
+    #[test]
+    fn test_update_and_unlist() {
+        let dir = tempfile::tempdir().unwrap();
+        let site_dir = dir.path().join("site_id=a.com");
+        fs::create_dir_all(&site_dir).unwrap();
+        assert_eq!(Manifest::load(&site_dir).unwrap(), None);
+
+        update(&site_dir, |m| {
+            m.files.push(entry("date=2024-01-15/0001.parquet"));
+            m.files.push(entry("date=2024-01-16/0001.parquet"));
+        })
+        .unwrap();
+        let manifest = Manifest::load(&site_dir).unwrap().unwrap();
+        assert_eq!(manifest.site_id, "a.com");
+        assert_eq!(manifest.files.len(), 2);
+        assert!(!site_dir.join(format!("{MANIFEST_FILE}.tmp")).exists());
+
+        unlist(&site_dir, |f| f.path.starts_with("date=2024-01-15/")).unwrap();
+        let manifest = Manifest::load(&site_dir).unwrap().unwrap();
+        assert!(!manifest.contains("date=2024-01-15/0001.parquet"));
+        assert!(manifest.contains("date=2024-01-16/0001.parquet"));
+
+        // Unlisting never creates a manifest.
+        let other = dir.path().join("site_id=b.com");
+        fs::create_dir_all(&other).unwrap();
+        unlist(&other, |_| true).unwrap();
+        assert_eq!(Manifest::load(&other).unwrap(), None);
+    }
+
+    #[test]
+    fn test_rebuild_from_disk() {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::storage::schema::init_schema(&conn).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let site_dir = dir.path().join("site_id=a.com");
+        let part = site_dir.join("date=2024-01-15");
+        fs::create_dir_all(&part).unwrap();
+        conn.execute_batch(
+            "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname) VALUES
+             ('a.com', 'v1', '2024-01-15 09:00:00', 'pageview', '/'),
+             ('a.com', 'v2', '2024-01-15 17:30:00', 'pageview', '/')",
+        )
+        .unwrap();
+        conn.execute_batch(&format!(
+            "COPY events TO '{}' (FORMAT PARQUET)",
+            part.join("0001.parquet").to_string_lossy()
+        ))
+        .unwrap();
+        fs::write(part.join("0002.parquet"), b"not parquet").unwrap();
+
+        let manifest = rebuild(&conn, &site_dir).unwrap();
+        assert_eq!(manifest.files.len(), 1);
+        let file = &manifest.files[0];
+        assert_eq!(file.path, "date=2024-01-15/0001.parquet");
+        assert_eq!(file.rows, 2);
+        assert_eq!(file.min_timestamp.as_deref(), Some("2024-01-15 09:00:00"));
+        assert_eq!(file.max_timestamp.as_deref(), Some("2024-01-15 17:30:00"));
+        assert_eq!(Manifest::load(&site_dir).unwrap(), Some(manifest));
+    }
+
+    #[test]
+    fn test_rebuild_fails_without_saving_when_files_cannot_be_listed() {
+        let conn = Connection::open_in_memory().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let site_dir = dir.path().join("site_id=a.com");
+        assert!(rebuild(&conn, &site_dir).is_err());
+        assert!(!site_dir.exists());
+    }
+}
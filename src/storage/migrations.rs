@@ -1,6 +1,7 @@
 use duckdb::Connection;
 
-const CURRENT_VERSION: u32 = 1;
+/// Schema version this release migrates databases to.
+pub const CURRENT_VERSION: u32 = 11;
 
 /// Initialize the schema version tracking table and run any pending migrations.
 pub fn run_migrations(conn: &Connection) -> Result<(), duckdb::Error> {
@@ -10,15 +11,53 @@ pub fn run_migrations(conn: &Connection) -> Result<(), duckdb::Error> {
     )?;
 
     let current = get_current_version(conn)?;
+    if current > CURRENT_VERSION {
+        tracing::warn!(
+            db_version = current,
+            supported = CURRENT_VERSION,
+            "Database schema is newer than this binary supports"
+        );
+    }
 
     if current < 1 {
         migrate_v1(conn)?;
     }
+    if current < 2 {
+        migrate_v2(conn)?;
+    }
+    if current < 3 {
+        migrate_v3(conn)?;
+    }
+    if current < 4 {
+        migrate_v4(conn)?;
+    }
+    if current < 5 {
+        migrate_v5(conn)?;
+    }
+    if current < 6 {
+        migrate_v6(conn)?;
+    }
+    if current < 7 {
+        migrate_v7(conn)?;
+    }
+    if current < 8 {
+        migrate_v8(conn)?;
+    }
+    if current < 9 {
+        migrate_v9(conn)?;
+    }
+    if current < 10 {
+        migrate_v10(conn)?;
+    }
+    if current < 11 {
+        migrate_v11(conn)?;
+    }
 
     Ok(())
 }
 
-fn get_current_version(conn: &Connection) -> Result<u32, duckdb::Error> {
+/// Schema version of the database behind `conn`; 0 before any migration.
+pub fn get_current_version(conn: &Connection) -> Result<u32, duckdb::Error> {
     let mut stmt = conn.prepare("SELECT COALESCE(MAX(version), 0) FROM schema_version")?;
     stmt.query_row([], |row| row.get(0))
 }
@@ -26,10 +65,95 @@ fn get_current_version(conn: &Connection) -> Result<u32, duckdb::Error> {
 fn migrate_v1(conn: &Connection) -> Result<(), duckdb::Error> {
     // V1: Initial schema — events table
     crate::storage::schema::init_schema(conn)?;
-    conn.execute(
-        "INSERT INTO schema_version (version) VALUES (?)",
-        [CURRENT_VERSION],
+    conn.execute("INSERT INTO schema_version (version) VALUES (?)", [1])?;
+    Ok(())
+}
+
+fn migrate_v2(conn: &Connection) -> Result<(), duckdb::Error> {
+    // V2: custom dimension slot columns.  Fresh databases already have them
+    // from init_schema, hence IF NOT EXISTS.
+    for slot in 1..=crate::config::MAX_CUSTOM_DIMENSIONS {
+        conn.execute_batch(&format!(
+            "ALTER TABLE events ADD COLUMN IF NOT EXISTS dim_{slot} VARCHAR"
+        ))?;
+    }
+    conn.execute("INSERT INTO schema_version (version) VALUES (?)", [2])?;
+    Ok(())
+}
+
+fn migrate_v3(conn: &Connection) -> Result<(), duckdb::Error> {
+    // V3: anomalies table for the hourly anomaly detector.
+    conn.execute_batch(crate::storage::schema::CREATE_ANOMALIES_TABLE)?;
+    conn.execute("INSERT INTO schema_version (version) VALUES (?)", [3])?;
+    Ok(())
+}
+
+fn migrate_v4(conn: &Connection) -> Result<(), duckdb::Error> {
+    // V4: registry of Parquet files behind events_all.  Existing files are
+    // registered by the setup_query_view call at startup.
+    conn.execute_batch(crate::storage::schema::CREATE_PARQUET_FILES_TABLE)?;
+    conn.execute("INSERT INTO schema_version (version) VALUES (?)", [4])?;
+    Ok(())
+}
+
+fn migrate_v5(conn: &Connection) -> Result<(), duckdb::Error> {
+    // V5: previous pathname sent with client-side (SPA) navigations.
+    conn.execute_batch("ALTER TABLE events ADD COLUMN IF NOT EXISTS prev_pathname VARCHAR")?;
+    conn.execute("INSERT INTO schema_version (version) VALUES (?)", [5])?;
+    Ok(())
+}
+
+fn migrate_v6(conn: &Connection) -> Result<(), duckdb::Error> {
+    // V6: hashed user ID sent by logged-in products.
+    conn.execute_batch("ALTER TABLE events ADD COLUMN IF NOT EXISTS user_id VARCHAR")?;
+    conn.execute("INSERT INTO schema_version (version) VALUES (?)", [6])?;
+    Ok(())
+}
+
+fn migrate_v7(conn: &Connection) -> Result<(), duckdb::Error> {
+    // V7: visitor → user mapping for the identity stitching job.
+    conn.execute_batch(crate::storage::schema::CREATE_VISITOR_USERS_TABLE)?;
+    conn.execute("INSERT INTO schema_version (version) VALUES (?)", [7])?;
+    Ok(())
+}
+
+fn migrate_v8(conn: &Connection) -> Result<(), duckdb::Error> {
+    // V8: log of flushes in progress, for crash reconciliation.
+    conn.execute_batch(crate::storage::schema::CREATE_PENDING_FLUSHES_TABLE)?;
+    conn.execute("INSERT INTO schema_version (version) VALUES (?)", [8])?;
+    Ok(())
+}
+
+fn migrate_v9(conn: &Connection) -> Result<(), duckdb::Error> {
+    // V9: row counts and timestamp ranges of registered Parquet files, filled
+    // in from the site manifests by the setup_query_view call at startup.
+    for column in [
+        "row_count BIGINT",
+        "min_timestamp TIMESTAMP",
+        "max_timestamp TIMESTAMP",
+    ] {
+        conn.execute_batch(&format!(
+            "ALTER TABLE parquet_files ADD COLUMN IF NOT EXISTS {column}"
+        ))?;
+    }
+    conn.execute("INSERT INTO schema_version (version) VALUES (?)", [9])?;
+    Ok(())
+}
+
+fn migrate_v10(conn: &Connection) -> Result<(), duckdb::Error> {
+    // V10: mobile device brand and model parsed from the User-Agent.
+    conn.execute_batch(
+        "ALTER TABLE events ADD COLUMN IF NOT EXISTS device_brand VARCHAR;
+         ALTER TABLE events ADD COLUMN IF NOT EXISTS device_model VARCHAR;",
     )?;
+    conn.execute("INSERT INTO schema_version (version) VALUES (?)", [10])?;
+    Ok(())
+}
+
+fn migrate_v11(conn: &Connection) -> Result<(), duckdb::Error> {
+    // V11: registry of materialized session files.
+    conn.execute_batch(crate::storage::schema::CREATE_SESSION_PARTITIONS_TABLE)?;
+    conn.execute("INSERT INTO schema_version (version) VALUES (?)", [11])?;
     Ok(())
 }
 
@@ -56,6 +180,35 @@ mod tests {
         assert_eq!(version, CURRENT_VERSION);
     }
 
+    #[test]
+    fn test_migrate_v2_adds_dimension_columns() {
+        let conn = Connection::open_in_memory().unwrap();
+        // Simulate a v1 database created before the dimension columns existed.
+        conn.execute_batch(
+            "CREATE TABLE schema_version (version INTEGER NOT NULL, applied_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP);
+             INSERT INTO schema_version (version) VALUES (1);
+             CREATE TABLE events (site_id VARCHAR NOT NULL, visitor_id VARCHAR NOT NULL);",
+        )
+        .unwrap();
+
+        run_migrations(&conn).unwrap();
+
+        assert_eq!(get_current_version(&conn).unwrap(), CURRENT_VERSION);
+        // prepare() fails if either column is missing.
+        conn.prepare(
+            "SELECT dim_1, dim_5, prev_pathname, user_id, device_brand, device_model FROM events",
+        )
+        .unwrap();
+        conn.prepare("SELECT site_id, hour FROM anomalies").unwrap();
+        conn.prepare("SELECT visitor_id, user_id FROM visitor_users")
+            .unwrap();
+        conn.prepare("SELECT path FROM pending_flushes").unwrap();
+        conn.prepare("SELECT event_rows, path FROM session_partitions")
+            .unwrap();
+        conn.prepare("SELECT row_count, min_timestamp, max_timestamp FROM parquet_files")
+            .unwrap();
+    }
+
     #[test]
     fn test_events_table_exists_after_migration() {
         let conn = Connection::open_in_memory().unwrap();
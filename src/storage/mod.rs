@@ -1,3 +1,7 @@
+pub mod maintenance;
+pub mod manifest;
 pub mod migrations;
 pub mod parquet;
+pub mod resources;
 pub mod schema;
+pub mod summary;
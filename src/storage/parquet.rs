@@ -1,16 +1,190 @@
+use crate::storage::manifest::{self, Manifest, ManifestFile};
+use crate::storage::schema::{
+    parquet_write_columns, EVENT_COLUMNS, EVENT_SCHEMA_VERSION, SCHEMA_VERSION_KEY,
+};
+use crate::storage::summary;
 use duckdb::Connection;
+use std::collections::HashMap;
+use std::fmt::Write;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A partition of the buffered events: site, date, row count, and first and
+/// last timestamps.
+type BufferedPartition = (String, String, usize, Option<String>, Option<String>);
 
 /// Manages Parquet file storage with date-partitioned layout.
 ///
 /// Storage layout:
 /// ```text
 /// data/events/site_id=example.com/date=2024-01-15/0001.parquet
+/// data/events/tenant_id=acme/site_id=acme.com/date=2024-01-15/0001.parquet
 /// ```
+///
+/// Sites that belong to a tenant (see [`Tenants`]) are stored under its
+/// `tenant_id=` directory.  Reads cover both layouts, so files written before
+/// a site joined a tenant stay visible until compaction moves them.
 #[derive(Clone)]
 pub struct ParquetStorage {
     base_dir: PathBuf,
+    write_options: ParquetWriteOptions,
+    tenants: Arc<Tenants>,
+}
+
+/// Which tenant each site belongs to, and per-tenant retention.
+#[derive(Debug, Clone, Default)]
+pub struct Tenants {
+    /// site_id → tenant ID.
+    sites: HashMap<String, String>,
+    /// Tenant ID → days to keep events, overriding the global retention.
+    retention_days: HashMap<String, u32>,
+}
+
+impl Tenants {
+    pub const fn new(sites: HashMap<String, String>, retention_days: HashMap<String, u32>) -> Self {
+        Self {
+            sites,
+            retention_days,
+        }
+    }
+
+    /// The tenant `site_id` belongs to, if any.
+    pub fn tenant_of(&self, site_id: &str) -> Option<&str> {
+        self.sites.get(site_id).map(String::as_str)
+    }
+
+    /// Days to keep the events of `tenant`: its override, else `default`.
+    /// 0 means forever.
+    pub fn retention_days(&self, tenant: Option<&str>, default: u32) -> u32 {
+        tenant
+            .and_then(|t| self.retention_days.get(t))
+            .copied()
+            .unwrap_or(default)
+    }
+
+    /// Whether any tenant limits how long its events are kept.
+    pub fn any_retention(&self) -> bool {
+        self.retention_days.values().any(|&days| days > 0)
+    }
+}
+
+/// A `site_id=` directory found by [`ParquetStorage::site_dirs`].
+pub struct SiteDir {
+    pub tenant_id: Option<String>,
+    pub site_id: String,
+    pub path: PathBuf,
+}
+
+/// Compression codec for written Parquet files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ParquetCompression {
+    Uncompressed,
+    Snappy,
+    Gzip,
+    #[default]
+    Zstd,
+    Brotli,
+    Lz4,
+}
+
+impl ParquetCompression {
+    /// Every supported codec.
+    pub const ALL: [Self; 6] = [
+        Self::Uncompressed,
+        Self::Snappy,
+        Self::Gzip,
+        Self::Zstd,
+        Self::Brotli,
+        Self::Lz4,
+    ];
+
+    /// Codec name as written in configuration.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Uncompressed => "uncompressed",
+            Self::Snappy => "snappy",
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+            Self::Brotli => "brotli",
+            Self::Lz4 => "lz4",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|c| c.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// How Parquet files are encoded when events are flushed or partitions
+/// compacted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParquetWriteOptions {
+    pub compression: ParquetCompression,
+    /// ZSTD level, 1 (fastest) to 22 (smallest); 0 keeps the codec default.
+    pub compression_level: u32,
+    /// Rows per row group.  Smaller groups let queries skip more data at the
+    /// cost of larger footers.
+    pub row_group_size: u64,
+    /// Dictionary-encode columns with few distinct values.
+    pub dictionary: bool,
+}
+
+/// DuckDB's own default row group size.
+pub const DEFAULT_ROW_GROUP_SIZE: u64 = 122_880;
+
+impl Default for ParquetWriteOptions {
+    fn default() -> Self {
+        Self {
+            compression: ParquetCompression::Zstd,
+            compression_level: 0,
+            row_group_size: DEFAULT_ROW_GROUP_SIZE,
+            dictionary: true,
+        }
+    }
+}
+
+impl ParquetWriteOptions {
+    /// The options as a `COPY ... TO` option list, without the format and
+    /// metadata options.
+    pub fn copy_options(&self) -> String {
+        let mut options = format!(
+            "COMPRESSION {}, ROW_GROUP_SIZE {}",
+            self.compression.name().to_ascii_uppercase(),
+            self.row_group_size
+        );
+        if self.compression_level != 0 {
+            let _ = write!(options, ", COMPRESSION_LEVEL {}", self.compression_level);
+        }
+        if !self.dictionary {
+            // A zero dictionary budget makes every column fall back to plain
+            // encoding.
+            options.push_str(", DICTIONARY_SIZE_LIMIT 0");
+        }
+        options
+    }
+}
+
+/// A Parquet file in the data lake, as reported by [`ParquetStorage::list_files`].
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct PartitionFile {
+    /// Tenant directory the file is stored under, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant_id: Option<String>,
+    pub site_id: String,
+    /// Partition date, `YYYY-MM-DD`.
+    pub date: String,
+    pub path: String,
+    pub size_bytes: u64,
+    pub row_count: Option<u64>,
+    /// Event schema version from the file metadata; `None` for files written
+    /// before the version was recorded.
+    pub schema_version: Option<u32>,
+    /// Whether the site's manifest lists the file.  Queries ignore files it
+    /// does not list, such as those left behind by an interrupted compaction.
+    pub listed: bool,
 }
 
 /// Validate that a site_id is safe for use in filesystem paths.
@@ -30,22 +204,104 @@ impl ParquetStorage {
     pub fn new(base_dir: &Path) -> Self {
         Self {
             base_dir: base_dir.to_path_buf(),
+            write_options: ParquetWriteOptions::default(),
+            tenants: Arc::default(),
         }
     }
 
-    /// Returns the partition directory for a given site and date.
+    /// Store each tenant's sites under its own directory, and apply its
+    /// retention override.
+    #[must_use]
+    pub fn with_tenants(mut self, tenants: Arc<Tenants>) -> Self {
+        self.tenants = tenants;
+        self
+    }
+
+    pub fn tenants(&self) -> &Tenants {
+        &self.tenants
+    }
+
+    pub fn base_dir(&self) -> &Path {
+        &self.base_dir
+    }
+
+    /// Write files with `options` instead of the defaults.
+    #[must_use]
+    pub const fn with_write_options(mut self, options: ParquetWriteOptions) -> Self {
+        self.write_options = options;
+        self
+    }
+
+    pub const fn write_options(&self) -> &ParquetWriteOptions {
+        &self.write_options
+    }
+
+    /// Returns the partition directory new files for a given site and date
+    /// are written to.
     pub fn partition_dir(&self, site_id: &str, date: &str) -> PathBuf {
-        self.base_dir
-            .join(format!("site_id={site_id}"))
+        let base = self.tenants.tenant_of(site_id).map_or_else(
+            || self.base_dir.clone(),
+            |tenant| self.base_dir.join(format!("tenant_id={tenant}")),
+        );
+        base.join(format!("site_id={site_id}"))
             .join(format!("date={date}"))
     }
 
+    /// Every existing directory holding files for a given site and date: the
+    /// top-level one and those under any tenant, whatever the site's tenant
+    /// is now.
+    pub fn existing_partition_dirs(&self, site_id: &str, date: &str) -> Vec<PathBuf> {
+        self.site_dirs()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|dir| dir.site_id == site_id)
+            .map(|dir| dir.path.join(format!("date={date}")))
+            .filter(|dir| dir.is_dir())
+            .collect()
+    }
+
+    /// Every `site_id=` directory, at the top level and under `tenant_id=`
+    /// directories.
+    pub fn site_dirs(&self) -> std::io::Result<Vec<SiteDir>> {
+        let mut dirs = Vec::new();
+        let mut parents = vec![(None, self.base_dir.clone())];
+        while let Some((tenant_id, parent)) = parents.pop() {
+            let entries = match fs::read_dir(&parent) {
+                Ok(e) => e,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            for entry in entries.flatten() {
+                if !entry.path().is_dir() {
+                    continue;
+                }
+                let name = entry.file_name();
+                let Some(name) = name.to_str() else {
+                    continue;
+                };
+                if let Some(site_id) = name.strip_prefix("site_id=") {
+                    dirs.push(SiteDir {
+                        tenant_id: tenant_id.clone(),
+                        site_id: site_id.to_string(),
+                        path: entry.path(),
+                    });
+                } else if let Some(tenant) = name.strip_prefix("tenant_id=") {
+                    // Tenants do not nest.
+                    if tenant_id.is_none() {
+                        parents.push((Some(tenant.to_string()), entry.path()));
+                    }
+                }
+            }
+        }
+        Ok(dirs)
+    }
+
     /// Generates the next available Parquet file path in the partition.
     ///
     /// Uses a single `read_dir` call to find the maximum existing file number,
     /// rather than an O(n) loop of `path.exists()` stat syscalls.  After K
     /// flushes this reduces K stat syscalls per flush to 1 directory read.
-    fn next_file_path(&self, site_id: &str, date: &str) -> PathBuf {
+    pub fn next_file_path(&self, site_id: &str, date: &str) -> PathBuf {
         let dir = self.partition_dir(site_id, date);
         if let Err(e) = fs::create_dir_all(&dir) {
             tracing::warn!(path = %dir.display(), error = %e, "Failed to create partition directory");
@@ -67,33 +323,38 @@ impl ParquetStorage {
 
     /// Flush events from the in-memory DuckDB table to partitioned Parquet files.
     ///
-    /// Groups events by (site_id, date) and writes each partition to its own file.
+    /// Groups events by (site_id, date) and writes each partition to its own
+    /// file, with a [summary sidecar](summary) beside it.
     pub fn flush_events(&self, conn: &Connection) -> Result<usize, FlushError> {
         // Get distinct partitions with counts
         let mut stmt = conn
             .prepare(
-                "SELECT site_id, STRFTIME(CAST(timestamp AS DATE), '%Y-%m-%d') AS d, COUNT(*) AS cnt FROM events GROUP BY site_id, d ORDER BY site_id, d",
+                "SELECT site_id, STRFTIME(CAST(timestamp AS DATE), '%Y-%m-%d') AS d, COUNT(*) AS cnt,
+                        CAST(MIN(timestamp) AS VARCHAR), CAST(MAX(timestamp) AS VARCHAR)
+                 FROM events GROUP BY site_id, d ORDER BY site_id, d",
             )
             .map_err(FlushError::Query)?;
 
-        let partitions: Vec<(String, String, usize)> = stmt
+        let partitions: Vec<BufferedPartition> = stmt
             .query_map([], |row| {
                 let site_id: String = row.get(0)?;
                 let date: String = row.get(1)?;
                 let count: usize = row.get(2)?;
-                Ok((site_id, date, count))
+                Ok((site_id, date, count, row.get(3)?, row.get(4)?))
             })
             .map_err(FlushError::Query)?
-            .filter_map(Result::ok)
-            .collect();
+            .collect::<Result<_, _>>()
+            .map_err(FlushError::Query)?;
 
         if partitions.is_empty() {
             return Ok(0);
         }
 
         let mut total_flushed = 0usize;
+        let columns = parquet_write_columns();
+        let write_options = self.write_options.copy_options();
 
-        for (site_id, date, count) in &partitions {
+        for (site_id, date, count, min_timestamp, max_timestamp) in &partitions {
             // Validate site_id to prevent path traversal in filesystem operations
             if !is_safe_path_component(site_id) {
                 tracing::warn!(site_id, "Skipping flush for invalid site_id");
@@ -106,62 +367,262 @@ impl ParquetStorage {
             // site_id and date are internal values from the events table, not user input.
             let escaped_site = site_id.replace('\'', "''");
 
+            let select = format!(
+                "SELECT {columns} FROM events WHERE site_id = '{escaped_site}' AND STRFTIME(CAST(timestamp AS DATE), '%Y-%m-%d') = '{date}'"
+            );
             let copy_sql = format!(
-                "COPY (SELECT * FROM events WHERE site_id = '{escaped_site}' AND STRFTIME(CAST(timestamp AS DATE), '%Y-%m-%d') = '{date}') TO '{file_path_str}' (FORMAT PARQUET, COMPRESSION ZSTD)"
+                "COPY ({select}) TO '{file_path_str}' (FORMAT PARQUET, {write_options}, KV_METADATA {{{SCHEMA_VERSION_KEY}: '{EVENT_SCHEMA_VERSION}'}})"
             );
 
-            conn.execute_batch(&copy_sql).map_err(FlushError::Write)?;
+            // Log the file before writing it, so that a crash before the
+            // events are deleted below leaves a trace for reconcile_flushes.
+            conn.execute(
+                "INSERT INTO pending_flushes (path) VALUES (?)",
+                [&*file_path_str],
+            )
+            .map_err(FlushError::Write)?;
+            if let Err(e) = conn.execute_batch(&copy_sql) {
+                discard_flush(conn, &file_path_str);
+                return Err(FlushError::Write(e));
+            }
+            summary::write(conn, &file_path, site_id, &select);
 
-            total_flushed += count;
+            // List the complete file in the site's manifest.  An interrupted
+            // flush is unlisted again by discard_flush.
+            let Some((site_dir, relative)) = manifest::locate(&file_path) else {
+                discard_flush(conn, &file_path_str);
+                continue;
+            };
+            let entry = ManifestFile {
+                path: relative,
+                rows: *count as u64,
+                min_timestamp: min_timestamp.clone(),
+                max_timestamp: max_timestamp.clone(),
+                size_bytes: fs::metadata(&file_path).map_or(0, |m| m.len()),
+            };
+            if let Err(e) = manifest::update(&site_dir, |m| m.files.push(entry.clone())) {
+                discard_flush(conn, &file_path_str);
+                return Err(FlushError::Manifest(e));
+            }
 
-            // Delete flushed events from the in-memory events table.
-            // The events_all view unions this table with the Parquet files,
-            // so deleted events remain visible to queries via the cold tier.
-            conn.execute_batch(&format!(
-                "DELETE FROM events WHERE site_id = '{escaped_site}' AND STRFTIME(CAST(timestamp AS DATE), '%Y-%m-%d') = '{date}'"
-            ))
-            .map_err(FlushError::Delete)?;
+            // Register the file, delete its events from the in-memory table
+            // and clear the log entry in one transaction.  The events_all view
+            // unions this table with the Parquet files, so deleted events
+            // remain visible to queries via the cold tier.
+            let committed = conn.execute_batch("BEGIN TRANSACTION").and_then(|()| {
+                crate::storage::schema::register_parquet_file(conn, &file_path_str, &entry)?;
+                conn.execute_batch(&format!(
+                    "DELETE FROM events WHERE site_id = '{escaped_site}' AND STRFTIME(CAST(timestamp AS DATE), '%Y-%m-%d') = '{date}'"
+                ))?;
+                conn.execute(
+                    "DELETE FROM pending_flushes WHERE path = ?",
+                    [&*file_path_str],
+                )?;
+                conn.execute_batch("COMMIT")
+            });
+            if let Err(e) = committed {
+                let _ = conn.execute_batch("ROLLBACK");
+                discard_flush(conn, &file_path_str);
+                return Err(FlushError::Delete(e));
+            }
+
+            total_flushed += count;
         }
 
         // Only refresh the events_all view when new Parquet files were written.
-        // If nothing was flushed (partitions were empty or all were skipped) the
-        // glob-based view is already up-to-date and recreating it is wasteful —
-        // particularly at high flush-rates where the view would otherwise be
-        // recreated on every periodic tick even with no new data.
+        // The refresh reads the file registry rather than the directory tree,
+        // so its cost does not grow with the number of partitions on disk.
         if total_flushed > 0 {
-            let _ = crate::storage::schema::setup_query_view(conn, &self.base_dir);
+            let _ = crate::storage::schema::refresh_query_view(conn);
         }
 
         Ok(total_flushed)
     }
 
-    /// Delete Parquet partition directories older than the given number of days.
+    /// Undo flushes interrupted by a crash, whose events may be both in a
+    /// Parquet file and still in the `events` table.  Run at startup, before
+    /// [`crate::storage::schema::setup_query_view`] picks up the files.
     ///
-    /// Returns the number of partition directories removed.
-    pub fn cleanup_old_partitions(&self, retention_days: u32) -> std::io::Result<usize> {
-        if retention_days == 0 {
-            return Ok(0); // Unlimited retention
+    /// Files logged in `pending_flushes` are removed; their events are still
+    /// in `events` and the next flush writes them again.  For databases that
+    /// crashed before the log existed, a partition's newest file is removed
+    /// when it holds exactly the partition's buffered events.  Returns the
+    /// number of files removed.
+    pub fn reconcile_flushes(&self, conn: &Connection) -> Result<usize, FlushError> {
+        let mut stmt = conn
+            .prepare("SELECT path FROM pending_flushes ORDER BY path")
+            .map_err(FlushError::Query)?;
+        let pending: Vec<String> = stmt
+            .query_map([], |row| row.get(0))
+            .map_err(FlushError::Query)?
+            .collect::<Result<_, _>>()
+            .map_err(FlushError::Query)?;
+        let mut removed = 0;
+        for path in &pending {
+            if Path::new(path).exists() {
+                removed += 1;
+            }
+            discard_flush(conn, path);
         }
 
-        let cutoff =
-            chrono::Utc::now().date_naive() - chrono::Duration::days(i64::from(retention_days));
-        let cutoff_str = cutoff.format("%Y-%m-%d").to_string();
-        let mut removed = 0usize;
+        let mut stmt = conn
+            .prepare(
+                "SELECT site_id, STRFTIME(CAST(timestamp AS DATE), '%Y-%m-%d') AS d, COUNT(*) FROM events GROUP BY site_id, d ORDER BY site_id, d",
+            )
+            .map_err(FlushError::Query)?;
+        let partitions: Vec<(String, String, u64)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .map_err(FlushError::Query)?
+            .collect::<Result<_, _>>()
+            .map_err(FlushError::Query)?;
+        let columns = EVENT_COLUMNS
+            .iter()
+            .map(|(name, ty)| format!("CAST({name} AS {ty})"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        for (site_id, date, count) in &partitions {
+            if !is_safe_path_component(site_id) {
+                continue;
+            }
+            let Some(newest) = newest_file(&self.partition_dir(site_id, date)) else {
+                continue;
+            };
+            let path = newest.to_string_lossy();
+            let escaped_path = path.replace('\'', "''");
+            // A file that cannot be read, or lacks a column, is left for
+            // verify-data to report.
+            let duplicate = conn.query_row(
+                &format!(
+                    "SELECT (SELECT COUNT(*) FROM read_parquet('{escaped_path}', hive_partitioning=false)) = ?
+                        AND NOT EXISTS (
+                            SELECT {columns} FROM events
+                            WHERE site_id = ? AND STRFTIME(CAST(timestamp AS DATE), '%Y-%m-%d') = ?
+                            EXCEPT ALL
+                            SELECT {columns} FROM read_parquet('{escaped_path}', hive_partitioning=false))"
+                ),
+                duckdb::params![count, site_id, date],
+                |row| row.get::<_, bool>(0),
+            );
+            if matches!(duplicate, Ok(true)) {
+                tracing::warn!(path = %path, "Removing Parquet file duplicating buffered events");
+                discard_flush(conn, &path);
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
 
-        // Iterate site_id=* directories
-        let entries = match fs::read_dir(&self.base_dir) {
-            Ok(e) => e,
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
-            Err(e) => return Err(e),
-        };
+    /// List every Parquet file under the base directory, optionally for one site.
+    ///
+    /// Paths come from a directory walk; row counts and schema versions are read
+    /// from the Parquet footers via DuckDB.  Files whose footer cannot be read
+    /// are still listed, with `row_count` and `schema_version` unset.
+    pub fn list_files(
+        &self,
+        conn: &Connection,
+        site_filter: Option<&str>,
+    ) -> std::io::Result<Vec<PartitionFile>> {
+        let mut files = Vec::new();
+        for site in self.site_dirs()? {
+            if site_filter.is_some_and(|f| f != site.site_id) {
+                continue;
+            }
+            // Without a manifest every file counts as listed: one is rebuilt
+            // from them at startup.
+            let manifest = Manifest::load(&site.path).ok().flatten();
+            for date_entry in fs::read_dir(&site.path)?.flatten() {
+                let name = date_entry.file_name();
+                let Some(date) = name.to_str().and_then(|n| n.strip_prefix("date=")) else {
+                    continue;
+                };
+                if !date_entry.path().is_dir() {
+                    continue;
+                }
+                for file_entry in fs::read_dir(date_entry.path())?.flatten() {
+                    let path = file_entry.path();
+                    if path.extension().and_then(|e| e.to_str()) != Some("parquet") {
+                        continue;
+                    }
+                    let listed = manifest.as_ref().is_none_or(|m| {
+                        m.contains(&format!(
+                            "date={date}/{}",
+                            file_entry.file_name().to_string_lossy()
+                        ))
+                    });
+                    files.push(PartitionFile {
+                        tenant_id: site.tenant_id.clone(),
+                        site_id: site.site_id.clone(),
+                        date: date.to_string(),
+                        path: path.to_string_lossy().into_owned(),
+                        size_bytes: file_entry.metadata().map_or(0, |m| m.len()),
+                        row_count: None,
+                        schema_version: None,
+                        listed,
+                    });
+                }
+            }
+        }
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        if !files.is_empty() {
+            match read_footers(conn, &files) {
+                Ok(footers) => {
+                    for file in &mut files {
+                        if let Some((rows, version)) = footers.get(&file.path) {
+                            file.row_count = Some(*rows);
+                            file.schema_version = *version;
+                        }
+                    }
+                }
+                Err(e) => tracing::warn!(error = %e, "Could not read Parquet footers"),
+            }
+        }
+        Ok(files)
+    }
 
-        for site_entry in entries.flatten() {
-            let site_path = site_entry.path();
-            if !site_path.is_dir() {
+    /// Delete Parquet partition directories older than the given number of
+    /// days, or than their tenant's own retention.  0 keeps partitions
+    /// forever.
+    ///
+    /// Returns the number of partition directories removed.  The server uses
+    /// [`crate::storage::maintenance::apply_retention`] instead, which also
+    /// keeps `events_all` from listing the removed files.
+    // Kept for library users; the binary no longer calls it.
+    #[allow(dead_code)]
+    pub fn cleanup_old_partitions(&self, retention_days: u32) -> std::io::Result<usize> {
+        let expired = self.expired_partitions(retention_days)?;
+        for partition in &expired {
+            remove_partition(&partition.path)?;
+        }
+        Ok(expired.len())
+    }
+
+    /// Partition directories older than the given number of days, or than
+    /// their tenant's own retention.  0 keeps partitions forever.
+    pub fn expired_partitions(
+        &self,
+        retention_days: u32,
+    ) -> std::io::Result<Vec<ExpiredPartition>> {
+        if retention_days == 0 && !self.tenants.any_retention() {
+            return Ok(Vec::new()); // Unlimited retention
+        }
+
+        let today = chrono::Utc::now().date_naive();
+        let mut expired = Vec::new();
+        for site in self.site_dirs()? {
+            let tenant = site
+                .tenant_id
+                .as_deref()
+                .or_else(|| self.tenants.tenant_of(&site.site_id));
+            let days = self.tenants.retention_days(tenant, retention_days);
+            if days == 0 {
                 continue;
             }
+            let cutoff = (today - chrono::Duration::days(i64::from(days)))
+                .format("%Y-%m-%d")
+                .to_string();
             // Iterate date=* directories inside each site
-            for date_entry in fs::read_dir(&site_path)?.flatten() {
+            for date_entry in fs::read_dir(&site.path)?.flatten() {
                 let date_path = date_entry.path();
                 if !date_path.is_dir() {
                     continue;
@@ -169,36 +630,132 @@ impl ParquetStorage {
                 let dir_name = date_entry.file_name();
                 let dir_name = dir_name.to_string_lossy();
                 if let Some(date_str) = dir_name.strip_prefix("date=") {
-                    if date_str < cutoff_str.as_str() {
-                        fs::remove_dir_all(&date_path)?;
-                        removed += 1;
+                    if date_str < cutoff.as_str() {
+                        expired.push(ExpiredPartition {
+                            site_id: site.site_id.clone(),
+                            date: date_str.to_string(),
+                            path: date_path,
+                        });
                     }
                 }
             }
         }
 
-        Ok(removed)
+        Ok(expired)
     }
 }
 
-#[derive(Debug)]
-pub enum FlushError {
-    Query(duckdb::Error),
-    Write(duckdb::Error),
-    Delete(duckdb::Error),
+/// A partition directory past its site's retention period.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpiredPartition {
+    pub site_id: String,
+    /// `YYYY-MM-DD`.
+    pub date: String,
+    pub path: PathBuf,
 }
 
-impl std::fmt::Display for FlushError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Query(e) => write!(f, "Query error: {e}"),
-            Self::Write(e) => write!(f, "Write error: {e}"),
-            Self::Delete(e) => write!(f, "Delete error: {e}"),
+/// Delete a `date=` partition directory, unlisting its files from the site's
+/// manifest first so that readers never see a listed file disappear.
+pub fn remove_partition(partition_dir: &Path) -> std::io::Result<()> {
+    if let (Some(site_dir), Some(name)) = (
+        partition_dir.parent(),
+        partition_dir.file_name().and_then(|n| n.to_str()),
+    ) {
+        let prefix = format!("{name}/");
+        manifest::unlist(site_dir, |f| f.path.starts_with(&prefix))?;
+    }
+    fs::remove_dir_all(partition_dir)
+}
+
+/// Row counts and schema versions keyed by file path.
+pub fn read_footers(
+    conn: &Connection,
+    files: &[PartitionFile],
+) -> Result<HashMap<String, (u64, Option<u32>)>, duckdb::Error> {
+    let list = files
+        .iter()
+        .map(|f| format!("'{}'", f.path.replace('\'', "''")))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let sql = format!(
+        "SELECT m.file_name, m.num_rows, kv.version
+         FROM (SELECT file_name, CAST(SUM(num_rows) AS BIGINT) AS num_rows
+               FROM parquet_file_metadata([{list}]) GROUP BY file_name) m
+         LEFT JOIN (SELECT file_name, TRY_CAST(CAST(value AS VARCHAR) AS UINTEGER) AS version
+                    FROM parquet_kv_metadata([{list}])
+                    WHERE CAST(key AS VARCHAR) = '{SCHEMA_VERSION_KEY}') kv
+           ON kv.file_name = m.file_name"
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                (row.get::<_, u64>(1)?, row.get::<_, Option<u32>>(2)?),
+            ))
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(rows)
+}
+
+/// Remove a file written by a flush that did not complete, along with its
+/// summary sidecar and its manifest, registry and `pending_flushes` entries.  Best effort: a failure
+/// is logged and the entry kept, so the next
+/// [`ParquetStorage::reconcile_flushes`] tries again.
+fn discard_flush(conn: &Connection, path: &str) {
+    if let Some((site_dir, relative)) = manifest::locate(Path::new(path)) {
+        if let Err(e) = manifest::unlist(&site_dir, |f| f.path == relative) {
+            tracing::error!(path, error = %e, "Failed to unlist Parquet file of an incomplete flush");
+            return;
+        }
+    }
+    match fs::remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => {
+            tracing::error!(path, error = %e, "Failed to remove Parquet file of an incomplete flush");
+            return;
+        }
+    }
+    if let Err(e) = summary::remove(Path::new(path)) {
+        tracing::warn!(path, error = %e, "Failed to remove summary sidecar of an incomplete flush");
+    }
+    for table in ["parquet_files", "pending_flushes"] {
+        if let Err(e) = conn.execute(&format!("DELETE FROM {table} WHERE path = ?"), [path]) {
+            tracing::warn!(path, table, error = %e, "Failed to clear incomplete flush entry");
         }
     }
 }
 
-impl std::error::Error for FlushError {}
+/// The highest-numbered Parquet file in a partition directory.
+fn newest_file(dir: &Path) -> Option<PathBuf> {
+    fs::read_dir(dir)
+        .ok()?
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name();
+            let num = name
+                .to_str()?
+                .strip_suffix(".parquet")?
+                .parse::<u32>()
+                .ok()?;
+            Some((num, entry.path()))
+        })
+        .max_by_key(|(num, _)| *num)
+        .map(|(_, path)| path)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FlushError {
+    #[error("Query error: {0}")]
+    Query(#[source] duckdb::Error),
+    #[error("Write error: {0}")]
+    Write(#[source] duckdb::Error),
+    #[error("Delete error: {0}")]
+    Delete(#[source] duckdb::Error),
+    #[error("Manifest error: {0}")]
+    Manifest(#[source] std::io::Error),
+}
 
 #[cfg(test)]
 mod tests {
@@ -219,6 +776,135 @@ mod tests {
         .unwrap();
     }
 
+    fn count_rows(conn: &Connection, sql: &str) -> i64 {
+        conn.query_row(sql, [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn test_flush_applies_write_options() {
+        let conn = setup_test_db();
+        let dir = tempfile::tempdir().unwrap();
+        let storage = ParquetStorage::new(dir.path()).with_write_options(ParquetWriteOptions {
+            compression: ParquetCompression::Gzip,
+            compression_level: 0,
+            row_group_size: 4096,
+            dictionary: false,
+        });
+        for minute in 0..5 {
+            insert_test_event(
+                &conn,
+                "example.com",
+                &format!("2024-01-15 10:0{minute}:00"),
+                "/",
+            );
+        }
+        assert_eq!(storage.flush_events(&conn).unwrap(), 5);
+
+        let path = storage
+            .partition_dir("example.com", "2024-01-15")
+            .join("0001.parquet");
+        let sql = |expr: &str| {
+            conn.query_row(
+                &format!(
+                    "SELECT {expr} FROM parquet_metadata('{}') WHERE path_in_schema = 'pathname'",
+                    path.display()
+                ),
+                [],
+                |row| row.get::<_, String>(0),
+            )
+            .unwrap()
+        };
+        assert_eq!(sql("ANY_VALUE(compression)"), "GZIP");
+        assert!(!sql("STRING_AGG(encodings, ',')").contains("DICTIONARY"));
+    }
+
+    #[test]
+    fn test_copy_options() {
+        assert_eq!(
+            ParquetWriteOptions::default().copy_options(),
+            "COMPRESSION ZSTD, ROW_GROUP_SIZE 122880"
+        );
+        let options = ParquetWriteOptions {
+            compression_level: 9,
+            dictionary: false,
+            ..ParquetWriteOptions::default()
+        };
+        assert_eq!(
+            options.copy_options(),
+            "COMPRESSION ZSTD, ROW_GROUP_SIZE 122880, COMPRESSION_LEVEL 9, DICTIONARY_SIZE_LIMIT 0"
+        );
+        assert_eq!(
+            ParquetCompression::from_name("LZ4"),
+            Some(ParquetCompression::Lz4)
+        );
+        assert_eq!(ParquetCompression::from_name("lzo"), None);
+    }
+
+    #[test]
+    fn test_flush_clears_pending_log() {
+        let conn = setup_test_db();
+        let dir = tempfile::tempdir().unwrap();
+        let storage = ParquetStorage::new(dir.path());
+        insert_test_event(&conn, "example.com", "2024-01-15 10:00:00", "/");
+
+        assert_eq!(storage.flush_events(&conn).unwrap(), 1);
+        assert_eq!(count_rows(&conn, "SELECT COUNT(*) FROM pending_flushes"), 0);
+        assert_eq!(storage.reconcile_flushes(&conn).unwrap(), 0);
+        assert_eq!(storage.list_files(&conn, None).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_reconcile_removes_interrupted_flush() {
+        let conn = setup_test_db();
+        let dir = tempfile::tempdir().unwrap();
+        let storage = ParquetStorage::new(dir.path());
+        insert_test_event(&conn, "example.com", "2024-01-15 10:00:00", "/");
+        insert_test_event(&conn, "example.com", "2024-01-15 11:00:00", "/about");
+        storage.flush_events(&conn).unwrap();
+        let path = storage.list_files(&conn, None).unwrap()[0].path.clone();
+
+        // A crash after COPY but before the delete commits: the events are
+        // back in the table and the log entry is still there.
+        insert_test_event(&conn, "example.com", "2024-01-15 10:00:00", "/");
+        insert_test_event(&conn, "example.com", "2024-01-15 11:00:00", "/about");
+        conn.execute("INSERT INTO pending_flushes (path) VALUES (?)", [&path])
+            .unwrap();
+
+        assert_eq!(storage.reconcile_flushes(&conn).unwrap(), 1);
+        assert!(!Path::new(&path).exists());
+        assert_eq!(count_rows(&conn, "SELECT COUNT(*) FROM pending_flushes"), 0);
+        assert_eq!(count_rows(&conn, "SELECT COUNT(*) FROM parquet_files"), 0);
+        assert_eq!(count_rows(&conn, "SELECT COUNT(*) FROM events"), 2);
+        // Idempotent.
+        assert_eq!(storage.reconcile_flushes(&conn).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_reconcile_detects_unlogged_duplicates() {
+        let conn = setup_test_db();
+        let dir = tempfile::tempdir().unwrap();
+        let storage = ParquetStorage::new(dir.path());
+        insert_test_event(&conn, "example.com", "2024-01-15 10:00:00", "/");
+        storage.flush_events(&conn).unwrap();
+        insert_test_event(&conn, "other.com", "2024-01-15 10:00:00", "/");
+        storage.flush_events(&conn).unwrap();
+
+        // example.com's events are back in the table, as after a crash in a
+        // release without the flush log; other.com has a new, different event.
+        insert_test_event(&conn, "example.com", "2024-01-15 10:00:00", "/");
+        insert_test_event(&conn, "other.com", "2024-01-15 10:00:00", "/pricing");
+
+        assert_eq!(storage.reconcile_flushes(&conn).unwrap(), 1);
+        assert!(storage
+            .list_files(&conn, Some("example.com"))
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            storage.list_files(&conn, Some("other.com")).unwrap().len(),
+            1
+        );
+    }
+
     #[test]
     fn test_partition_dir() {
         let storage = ParquetStorage::new(Path::new("/data/events"));
@@ -229,6 +915,85 @@ mod tests {
         );
     }
 
+    fn acme_tenants(retention_days: &[(&str, u32)]) -> Arc<Tenants> {
+        Arc::new(Tenants::new(
+            HashMap::from([("acme.com".to_string(), "acme".to_string())]),
+            retention_days
+                .iter()
+                .map(|(t, d)| ((*t).to_string(), *d))
+                .collect(),
+        ))
+    }
+
+    #[test]
+    fn test_tenant_layout() {
+        let conn = setup_test_db();
+        let dir = tempfile::tempdir().unwrap();
+        // A file written before acme.com joined its tenant.
+        let legacy = ParquetStorage::new(dir.path());
+        insert_test_event(&conn, "acme.com", "2024-01-15 09:00:00", "/");
+        legacy.flush_events(&conn).unwrap();
+
+        let storage = ParquetStorage::new(dir.path()).with_tenants(acme_tenants(&[]));
+        assert_eq!(
+            storage.partition_dir("acme.com", "2024-01-15"),
+            dir.path()
+                .join("tenant_id=acme")
+                .join("site_id=acme.com")
+                .join("date=2024-01-15")
+        );
+        insert_test_event(&conn, "acme.com", "2024-01-15 10:00:00", "/");
+        insert_test_event(&conn, "other.com", "2024-01-15 10:00:00", "/");
+        storage.flush_events(&conn).unwrap();
+
+        let files = storage.list_files(&conn, Some("acme.com")).unwrap();
+        let tenants: Vec<_> = files.iter().map(|f| f.tenant_id.as_deref()).collect();
+        assert_eq!(tenants, vec![None, Some("acme")]);
+        assert_eq!(storage.list_files(&conn, None).unwrap().len(), 3);
+        assert_eq!(
+            storage
+                .existing_partition_dirs("acme.com", "2024-01-15")
+                .len(),
+            2
+        );
+
+        crate::storage::schema::setup_query_view(&conn, dir.path()).unwrap();
+        assert_eq!(
+            count_rows(
+                &conn,
+                "SELECT COUNT(*) FROM events_all WHERE site_id = 'acme.com'"
+            ),
+            2
+        );
+    }
+
+    #[test]
+    fn test_cleanup_applies_tenant_retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = ParquetStorage::new(dir.path()).with_tenants(acme_tenants(&[("acme", 30)]));
+        let recent = (chrono::Utc::now().date_naive() - chrono::Duration::days(60))
+            .format("%Y-%m-%d")
+            .to_string();
+        let acme = storage.partition_dir("acme.com", &recent);
+        let other = storage.partition_dir("other.com", &recent);
+        for dir in [&acme, &other] {
+            fs::create_dir_all(dir).unwrap();
+            fs::write(dir.join("0001.parquet"), b"fake").unwrap();
+        }
+
+        // Only the tenant's override applies when retention is otherwise off.
+        assert_eq!(storage.cleanup_old_partitions(0).unwrap(), 1);
+        assert!(!acme.exists());
+        assert!(other.exists());
+
+        // A tenant can also keep its events longer than everyone else.
+        let storage = ParquetStorage::new(dir.path()).with_tenants(acme_tenants(&[("acme", 0)]));
+        fs::create_dir_all(&acme).unwrap();
+        assert_eq!(storage.cleanup_old_partitions(30).unwrap(), 1);
+        assert!(acme.exists());
+        assert!(!other.exists());
+    }
+
     #[test]
     fn test_flush_empty_table() {
         let conn = setup_test_db();
@@ -261,6 +1026,107 @@ mod tests {
         assert!(parquet_dir.join("0001.parquet").exists());
     }
 
+    #[test]
+    fn test_flush_writes_summary_sidecar() {
+        let conn = setup_test_db();
+        let dir = tempfile::tempdir().unwrap();
+        let storage = ParquetStorage::new(dir.path());
+        conn.execute_batch(
+            "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname) VALUES
+                ('example.com', 'v1', '2024-01-15 10:00:00', 'pageview', '/'),
+                ('example.com', 'v1', '2024-01-15 10:01:00', 'pageview', '/about'),
+                ('example.com', 'v2', '2024-01-15 10:02:00', 'pageview', '/'),
+                ('example.com', 'v2', '2024-01-15 10:03:00', 'signup', '/'),
+                ('example.com', 'v3', '2024-01-15 10:04:00', 'heartbeat', '/'),
+                ('example.com', 'v3', '2024-01-15 10:05:00', 'pageview', '/')",
+        )
+        .unwrap();
+        storage.flush_events(&conn).unwrap();
+
+        // The sidecar beside the file agrees with the file's contents.
+        let file = storage
+            .partition_dir("example.com", "2024-01-15")
+            .join("0001.parquet");
+        let summary = summary::load(&file).unwrap();
+        let count = |condition: &str| -> u64 {
+            let sql = format!(
+                "SELECT COUNT(*) FROM read_parquet('{}') WHERE {condition}",
+                file.display()
+            );
+            conn.query_row(&sql, [], |row| row.get(0)).unwrap()
+        };
+        assert_eq!(summary.site_id, "example.com");
+        assert_eq!(summary.rows, count("true"));
+        assert_eq!(summary.pageviews, count("event_name = 'pageview'"));
+        assert_eq!(summary.events, count("event_name <> 'heartbeat'"));
+        assert_eq!(summary.rows, 6);
+        assert_eq!(summary.pageviews, 4);
+        assert_eq!(summary.events, 5);
+        assert_eq!(summary.visitors.estimate(), 3);
+        let pages: Vec<(&str, u64)> = summary
+            .top_pages
+            .iter()
+            .map(|page| (page.pathname.as_str(), page.pageviews))
+            .collect();
+        assert_eq!(
+            pages,
+            [
+                ("/", count("event_name = 'pageview' AND pathname = '/'")),
+                (
+                    "/about",
+                    count("event_name = 'pageview' AND pathname = '/about'")
+                ),
+            ]
+        );
+        assert_eq!(pages[0].1, 3);
+    }
+
+    #[test]
+    fn test_flush_writes_manifest() {
+        let conn = setup_test_db();
+        let dir = tempfile::tempdir().unwrap();
+        let storage = ParquetStorage::new(dir.path());
+        insert_test_event(&conn, "example.com", "2024-01-15 10:00:00", "/");
+        insert_test_event(&conn, "example.com", "2024-01-15 11:00:00", "/about");
+        storage.flush_events(&conn).unwrap();
+
+        // The site's manifest lists the file with its row count and time
+        // range.
+        let parquet_dir = storage.partition_dir("example.com", "2024-01-15");
+        let site_dir = parquet_dir.parent().unwrap();
+        let manifest = Manifest::load(site_dir).unwrap().unwrap();
+        assert_eq!(manifest.site_id, "example.com");
+        assert_eq!(manifest.files.len(), 1);
+        let file = &manifest.files[0];
+        assert_eq!(file.path, "date=2024-01-15/0001.parquet");
+        assert_eq!(file.rows, 2);
+        assert_eq!(file.min_timestamp.as_deref(), Some("2024-01-15 10:00:00"));
+        assert_eq!(file.max_timestamp.as_deref(), Some("2024-01-15 11:00:00"));
+
+        // A data directory that cannot be listed keeps its files registered
+        // at startup, rather than dropping their history from the view.
+        // Putting a file in its place makes listing fail even for root; the
+        // view then cannot read the files either, but they stay registered.
+        let registered = "SELECT COUNT(*) FROM parquet_files";
+        assert_eq!(count_rows(&conn, registered), 1);
+        let moved = tempfile::tempdir().unwrap();
+        let data_dir = moved.path().join("data");
+        fs::rename(dir.path(), &data_dir).unwrap();
+        fs::write(dir.path(), "not a directory").unwrap();
+        assert!(crate::storage::schema::setup_query_view(&conn, dir.path()).is_err());
+        assert_eq!(count_rows(&conn, registered), 1);
+        fs::remove_file(dir.path()).unwrap();
+        fs::rename(&data_dir, dir.path()).unwrap();
+        crate::storage::schema::setup_query_view(&conn, dir.path()).unwrap();
+        assert_eq!(count_rows(&conn, registered), 1);
+        assert_eq!(count_rows(&conn, "SELECT COUNT(*) FROM events_all"), 2);
+
+        // Removing the partition unlists it first.
+        remove_partition(&parquet_dir).unwrap();
+        assert!(!parquet_dir.exists());
+        assert!(Manifest::load(site_dir).unwrap().unwrap().files.is_empty());
+    }
+
     #[test]
     fn test_flush_multiple_sites() {
         let conn = setup_test_db();
@@ -324,6 +1190,37 @@ mod tests {
         assert!(partition.join("0002.parquet").exists());
     }
 
+    #[test]
+    fn test_list_files_reports_rows_and_schema_version() {
+        let conn = setup_test_db();
+        let dir = tempfile::tempdir().unwrap();
+        let storage = ParquetStorage::new(dir.path());
+
+        insert_test_event(&conn, "example.com", "2024-01-15 10:00:00", "/");
+        insert_test_event(&conn, "example.com", "2024-01-15 11:00:00", "/about");
+        insert_test_event(&conn, "other.com", "2024-01-16 10:00:00", "/");
+        storage.flush_events(&conn).unwrap();
+
+        let files = storage.list_files(&conn, None).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].site_id, "example.com");
+        assert_eq!(files[0].date, "2024-01-15");
+        assert_eq!(files[0].row_count, Some(2));
+        assert_eq!(files[0].schema_version, Some(EVENT_SCHEMA_VERSION));
+        assert!(files[0].size_bytes > 0);
+
+        let other = storage.list_files(&conn, Some("other.com")).unwrap();
+        assert_eq!(other.len(), 1);
+        assert_eq!(other[0].row_count, Some(1));
+    }
+
+    #[test]
+    fn test_list_files_empty_dir() {
+        let conn = setup_test_db();
+        let storage = ParquetStorage::new(Path::new("/nonexistent/path/events"));
+        assert!(storage.list_files(&conn, None).unwrap().is_empty());
+    }
+
     #[test]
     fn test_cleanup_zero_retention_is_noop() {
         let dir = tempfile::tempdir().unwrap();
@@ -0,0 +1,385 @@
+//! DuckDB memory and thread limits.
+//!
+//! DuckDB defaults to 80% of the host's RAM and one thread per host core,
+//! which in a container can be far more than the container is allowed: a
+//! large query then gets the process killed instead of spilling to disk.
+//! [`apply_limits`] sets both from the config, defaulting to 75% of the
+//! cgroup memory limit and to the CPUs the process may actually use.
+//!
+//! Queries needing more memory than the limit spill to
+//! [`apply_temp_directory`]'s directory, under `data_dir` by default, so a
+//! container's writable volume rather than its memory absorbs them.
+//!
+//! [`DiskGuard`] checks the data volume's free space before each flush, so
+//! a full disk pauses flushing instead of failing it over and over.
+
+use duckdb::Connection;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Free space below which startup warns that DuckDB has little room to
+/// spill to.
+const MIN_TEMP_SPACE_BYTES: u64 = 1 << 30;
+
+/// Share of a container's memory limit given to DuckDB when
+/// `duckdb_memory_limit` is not set, in percent.  The rest is left for the
+/// server itself, the query cache and the ingest queue.
+const CONTAINER_MEMORY_PCT: u64 = 75;
+
+/// cgroup v2 and v1 files holding the memory limit, in bytes.
+const CGROUP_MEMORY_LIMIT_FILES: [&str; 2] = [
+    "/sys/fs/cgroup/memory.max",
+    "/sys/fs/cgroup/memory/memory.limit_in_bytes",
+];
+
+/// cgroup v1 reports "no limit" as a page-rounded `i64::MAX`; anything this
+/// large is treated as unlimited.
+const UNLIMITED_BYTES: u64 = 1 << 60;
+
+/// DuckDB's memory use and limits.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ResourceUsage {
+    /// Memory held by the buffer manager.
+    pub memory_usage_bytes: u64,
+    /// Data spilled to temporary files on disk.
+    pub temporary_storage_bytes: u64,
+    /// Effective `memory_limit`, as DuckDB reports it, e.g. `"3.0 GiB"`.
+    pub memory_limit: String,
+    pub threads: u64,
+    /// Directory DuckDB spills to.
+    pub temp_directory: String,
+    /// Space left on the temporary directory's file system, when known.
+    pub temp_directory_free_bytes: Option<u64>,
+}
+
+/// Set `memory_limit` and `threads` on `conn`.
+///
+/// `memory_limit` is a DuckDB size such as `"2GB"`; `None` uses
+/// [`default_memory_limit`], or DuckDB's own default outside a container.
+/// `threads` of 0 uses the CPUs available to the process.
+pub fn apply_limits(
+    conn: &Connection,
+    memory_limit: Option<&str>,
+    threads: usize,
+) -> Result<(), duckdb::Error> {
+    let threads = if threads == 0 {
+        std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+    } else {
+        threads
+    };
+    conn.execute_batch(&format!("SET threads = {threads}"))?;
+    let memory_limit = memory_limit
+        .map(str::to_string)
+        .or_else(default_memory_limit);
+    if let Some(limit) = memory_limit {
+        conn.execute_batch(&format!(
+            "SET memory_limit = '{}'",
+            limit.replace('\'', "''")
+        ))?;
+    }
+    Ok(())
+}
+
+/// Spill to `dir`, creating it, and allow at most `max_size` (a DuckDB size
+/// such as `"20GB"`) there; `None` keeps DuckDB's default of 90% of the
+/// free space.
+///
+/// Warns when the file system has less than 1 GiB free, since a query that
+/// cannot spill fails with an out-of-memory error instead.
+pub fn apply_temp_directory(
+    conn: &Connection,
+    dir: &Path,
+    max_size: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    std::fs::create_dir_all(dir)?;
+    conn.execute_batch(&format!(
+        "SET temp_directory = '{}'",
+        dir.display().to_string().replace('\'', "''")
+    ))?;
+    if let Some(size) = max_size {
+        conn.execute_batch(&format!(
+            "SET max_temp_directory_size = '{}'",
+            size.replace('\'', "''")
+        ))?;
+    }
+    match available_space(dir) {
+        Some(free) if free < MIN_TEMP_SPACE_BYTES => tracing::warn!(
+            dir = %dir.display(),
+            free_bytes = free,
+            "Little disk space for DuckDB to spill to; large queries may fail"
+        ),
+        Some(free) => {
+            tracing::debug!(dir = %dir.display(), free_bytes = free, "DuckDB temp directory");
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+/// Bytes available to the process on the file system holding `path`.
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> Option<u64> {
+    let stat = rustix::fs::statvfs(path).ok()?;
+    Some(stat.f_bavail.saturating_mul(stat.f_frsize))
+}
+
+/// Bytes available to the process on the file system holding `path`.
+#[cfg(not(unix))]
+pub fn available_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Free-space check on the volume events are flushed to.
+pub struct DiskGuard {
+    dir: PathBuf,
+    min_free_bytes: u64,
+    low: AtomicBool,
+    /// Last reading; `u64::MAX` until one succeeds.
+    free_bytes: AtomicU64,
+}
+
+/// [`DiskGuard::check`] found less free space than required.
+#[derive(Debug, thiserror::Error)]
+#[error("only {free_bytes} bytes free on the data volume, below the {min_free_bytes} required")]
+pub struct LowDiskSpace {
+    pub free_bytes: u64,
+    pub min_free_bytes: u64,
+}
+
+impl DiskGuard {
+    /// Guard the file system holding `dir`, requiring `min_free_bytes`
+    /// (0 = never low).
+    pub fn new(dir: impl Into<PathBuf>, min_free_bytes: u64) -> Self {
+        Self {
+            dir: dir.into(),
+            min_free_bytes,
+            low: AtomicBool::new(false),
+            free_bytes: AtomicU64::new(u64::MAX),
+        }
+    }
+
+    /// Measure the free space, failing when it is below the minimum.
+    ///
+    /// Logs once when space runs low and once when it recovers.  When the
+    /// free space cannot be read the check passes.
+    pub fn check(&self) -> Result<(), LowDiskSpace> {
+        if self.min_free_bytes == 0 {
+            return Ok(());
+        }
+        let Some(free_bytes) = available_space(&self.dir) else {
+            return Ok(());
+        };
+        self.free_bytes.store(free_bytes, Ordering::Relaxed);
+        let low = free_bytes < self.min_free_bytes;
+        if self.low.swap(low, Ordering::Relaxed) != low {
+            if low {
+                tracing::error!(
+                    dir = %self.dir.display(),
+                    free_bytes,
+                    min_free_bytes = self.min_free_bytes,
+                    "Disk space low; flushing paused until space is freed"
+                );
+            } else {
+                tracing::info!(free_bytes, "Disk space recovered; flushing resumed");
+            }
+        }
+        if low {
+            Err(LowDiskSpace {
+                free_bytes,
+                min_free_bytes: self.min_free_bytes,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Whether the last [`check`](Self::check) found space low.
+    pub fn is_low(&self) -> bool {
+        self.low.load(Ordering::Relaxed)
+    }
+
+    /// Free space at the last [`check`](Self::check), if it could be read.
+    pub fn free_bytes(&self) -> Option<u64> {
+        Some(self.free_bytes.load(Ordering::Relaxed)).filter(|&free| free != u64::MAX)
+    }
+}
+
+/// [`CONTAINER_MEMORY_PCT`] of the container's memory limit, or `None` when
+/// the process has no cgroup memory limit.
+pub fn default_memory_limit() -> Option<String> {
+    let limit = CGROUP_MEMORY_LIMIT_FILES
+        .iter()
+        .find_map(|path| read_memory_limit(Path::new(path)))?;
+    Some(format!(
+        "{}MiB",
+        limit / 100 * CONTAINER_MEMORY_PCT / (1024 * 1024)
+    ))
+}
+
+/// The limit in a cgroup memory limit file, unless it is unlimited.
+fn read_memory_limit(path: &Path) -> Option<u64> {
+    let content = std::fs::read_to_string(path).ok()?;
+    let limit = content.trim().parse::<u64>().ok()?;
+    (limit < UNLIMITED_BYTES).then_some(limit)
+}
+
+/// Reads DuckDB's memory use and limits for one server.
+#[derive(Default)]
+pub struct ResourceMonitor {
+    /// Last usage read, reported while the connection is busy.
+    last: Mutex<Option<ResourceUsage>>,
+}
+
+impl ResourceMonitor {
+    /// DuckDB's current memory use and limits.
+    ///
+    /// Read without waiting for the connection: while a query holds it, the
+    /// last usage read is returned instead, or `None` if there is none yet.
+    pub fn usage(&self, conn: &Mutex<Connection>) -> Option<ResourceUsage> {
+        if let Some(conn) = conn.try_lock() {
+            match read_usage(&conn) {
+                Ok(usage) => *self.last.lock() = Some(usage),
+                Err(e) => tracing::debug!(error = %e, "Could not read DuckDB memory usage"),
+            }
+        }
+        self.last.lock().clone()
+    }
+}
+
+/// DuckDB's current memory use and limits, read from `conn`.
+pub fn read_usage(conn: &Connection) -> Result<ResourceUsage, duckdb::Error> {
+    conn.query_row(
+        "SELECT CAST(COALESCE(SUM(memory_usage_bytes), 0) AS UBIGINT),
+                CAST(COALESCE(SUM(temporary_storage_bytes), 0) AS UBIGINT),
+                current_setting('memory_limit'),
+                CAST(current_setting('threads') AS UBIGINT),
+                current_setting('temp_directory')
+         FROM duckdb_memory()",
+        [],
+        |row| {
+            let temp_directory: String = row.get(4)?;
+            Ok(ResourceUsage {
+                memory_usage_bytes: row.get(0)?,
+                temporary_storage_bytes: row.get(1)?,
+                memory_limit: row.get(2)?,
+                threads: row.get(3)?,
+                temp_directory_free_bytes: available_space(Path::new(&temp_directory)),
+                temp_directory,
+            })
+        },
+    )
+}
+
+/// Whether `size` is a size DuckDB accepts for `memory_limit` and
+/// `max_temp_directory_size`: a number followed by a unit such as `MB`,
+/// `GiB` or `TB`.
+pub fn is_valid_size(size: &str) -> bool {
+    let size = size.trim();
+    let unit_at = size
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(unit_at);
+    let units = [
+        "b", "kb", "mb", "gb", "tb", "kib", "mib", "gib", "tib", "bytes",
+    ];
+    number.parse::<f64>().is_ok_and(|n| n > 0.0)
+        && units.contains(&unit.trim().to_ascii_lowercase().as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_limits() {
+        let conn = Connection::open_in_memory().unwrap();
+        apply_limits(&conn, Some("512MiB"), 2).unwrap();
+        let conn = Mutex::new(conn);
+        let monitor = ResourceMonitor::default();
+        let usage = monitor.usage(&conn).unwrap();
+        assert!(usage.memory_limit.contains("512"), "{}", usage.memory_limit);
+        assert_eq!(usage.threads, 2);
+
+        let conn = conn.into_inner();
+        apply_limits(&conn, Some("1GB"), 0).unwrap();
+        let threads: u64 = conn
+            .query_row(
+                "SELECT CAST(current_setting('threads') AS UBIGINT)",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(threads >= 1);
+    }
+
+    #[test]
+    fn test_read_memory_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("memory.max");
+        std::fs::write(&path, "max\n").unwrap();
+        assert_eq!(read_memory_limit(&path), None);
+        std::fs::write(&path, "9223372036854771712\n").unwrap();
+        assert_eq!(read_memory_limit(&path), None);
+        std::fs::write(&path, "2147483648\n").unwrap();
+        assert_eq!(read_memory_limit(&path), Some(2_147_483_648));
+        assert_eq!(read_memory_limit(&dir.path().join("missing")), None);
+    }
+
+    #[test]
+    fn test_is_valid_size() {
+        for size in ["2GB", "512MiB", "1.5 GB", "4gib", "100000000 bytes"] {
+            assert!(is_valid_size(size), "{size}");
+        }
+        for size in ["", "GB", "2", "0GB", "-1GB", "2 parsecs", "1GB'; DROP"] {
+            assert!(!is_valid_size(size), "{size}");
+        }
+    }
+
+    #[test]
+    fn test_disk_guard() {
+        let dir = tempfile::tempdir().unwrap();
+        let guard = DiskGuard::new(dir.path(), 0);
+        assert!(guard.check().is_ok());
+        assert!(!guard.is_low());
+        assert_eq!(guard.free_bytes(), None);
+
+        let guard = DiskGuard::new(dir.path(), 1);
+        assert!(guard.check().is_ok());
+        #[cfg(unix)]
+        assert!(guard.free_bytes().is_some_and(|free| free > 0));
+
+        #[cfg(unix)]
+        {
+            let guard = DiskGuard::new(dir.path(), u64::MAX);
+            let err = guard.check().unwrap_err();
+            assert_eq!(err.min_free_bytes, u64::MAX);
+            assert!(guard.is_low());
+        }
+    }
+
+    #[test]
+    fn test_apply_temp_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let temp_dir = dir.path().join("duckdb_tmp");
+        let conn = Connection::open(dir.path().join("test.duckdb")).unwrap();
+        apply_temp_directory(&conn, &temp_dir, Some("2GiB")).unwrap();
+        assert!(temp_dir.is_dir());
+
+        let conn = Mutex::new(conn);
+        let usage = ResourceMonitor::default().usage(&conn).unwrap();
+        assert_eq!(usage.temp_directory, temp_dir.display().to_string());
+        #[cfg(unix)]
+        assert!(usage.temp_directory_free_bytes.is_some());
+        let max_size: String = conn
+            .lock()
+            .query_row(
+                "SELECT current_setting('max_temp_directory_size')",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(max_size.starts_with('2'), "{max_size}");
+    }
+}
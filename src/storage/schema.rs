@@ -1,5 +1,100 @@
+use crate::storage::manifest::{self, Manifest, ManifestFile};
+use crate::storage::parquet::ParquetStorage;
 use duckdb::Connection;
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Version of the event column layout written to Parquet files.
+///
+/// Stored both as the `schema_version` column and in each file's key/value
+/// metadata under [`SCHEMA_VERSION_KEY`], so external readers can tell layouts
+/// apart without inspecting columns.
+/// - 1: the original 25 columns.
+/// - 2: adds `dim_1` … `dim_5`.
+/// - 3: adds the `schema_version` column; every column is written with the
+///   type listed in [`EVENT_COLUMNS`].
+/// - 4: adds `prev_pathname`.
+/// - 5: adds `user_id`.
+/// - 6: adds `device_brand` and `device_model`.
+pub const EVENT_SCHEMA_VERSION: u32 = 6;
+
+/// Parquet key/value metadata key holding [`EVENT_SCHEMA_VERSION`].
+pub const SCHEMA_VERSION_KEY: &str = "mallard_schema_version";
+
+/// Canonical event columns and their DuckDB types, in table order.
+///
+/// Must match [`CREATE_EVENTS_TABLE`].  Parquet files are written with exactly
+/// these types and `events_all` casts every file back to them, so a file
+/// written by an older release cannot change the view's column types.
+pub const EVENT_COLUMNS: &[(&str, &str)] = &[
+    ("site_id", "VARCHAR"),
+    ("visitor_id", "VARCHAR"),
+    ("timestamp", "TIMESTAMP"),
+    ("event_name", "VARCHAR"),
+    ("pathname", "VARCHAR"),
+    ("hostname", "VARCHAR"),
+    ("referrer", "VARCHAR"),
+    ("referrer_source", "VARCHAR"),
+    ("utm_source", "VARCHAR"),
+    ("utm_medium", "VARCHAR"),
+    ("utm_campaign", "VARCHAR"),
+    ("utm_content", "VARCHAR"),
+    ("utm_term", "VARCHAR"),
+    ("browser", "VARCHAR"),
+    ("browser_version", "VARCHAR"),
+    ("os", "VARCHAR"),
+    ("os_version", "VARCHAR"),
+    ("device_type", "VARCHAR"),
+    ("screen_size", "VARCHAR"),
+    ("country_code", "VARCHAR"),
+    ("region", "VARCHAR"),
+    ("city", "VARCHAR"),
+    ("props", "VARCHAR"),
+    ("revenue_amount", "DECIMAL(12,2)"),
+    ("revenue_currency", "VARCHAR"),
+    ("dim_1", "VARCHAR"),
+    ("dim_2", "VARCHAR"),
+    ("dim_3", "VARCHAR"),
+    ("dim_4", "VARCHAR"),
+    ("dim_5", "VARCHAR"),
+    ("prev_pathname", "VARCHAR"),
+    ("user_id", "VARCHAR"),
+    ("device_brand", "VARCHAR"),
+    ("device_model", "VARCHAR"),
+];
+
+/// Select list used when writing events to Parquet: every column cast to its
+/// canonical type, followed by the `schema_version` column.
+pub fn parquet_write_columns() -> String {
+    let mut cols: Vec<String> = EVENT_COLUMNS
+        .iter()
+        .map(|(name, ty)| format!("CAST({name} AS {ty}) AS {name}"))
+        .collect();
+    cols.push(format!(
+        "CAST({EVENT_SCHEMA_VERSION} AS UINTEGER) AS schema_version"
+    ));
+    cols.join(", ")
+}
+
+/// Select list reading Parquet rows into the canonical event layout.
+///
+/// `present` holds the column names found in at least one file.  Columns
+/// present in only some files already read as NULL for the others through
+/// `union_by_name`; columns absent from every file (files older than the
+/// column) are filled with a typed NULL, since referencing them would fail.
+fn parquet_read_columns(present: &HashSet<String>) -> String {
+    EVENT_COLUMNS
+        .iter()
+        .map(|(name, ty)| {
+            if present.contains(*name) {
+                format!("CAST({name} AS {ty}) AS {name}")
+            } else {
+                format!("CAST(NULL AS {ty}) AS {name}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
 
 /// SQL statement to create the events table.
 pub const CREATE_EVENTS_TABLE: &str = r"
@@ -28,13 +123,129 @@ CREATE TABLE IF NOT EXISTS events (
     city            VARCHAR,
     props           VARCHAR,
     revenue_amount  DECIMAL(12,2),
-    revenue_currency VARCHAR(3)
+    revenue_currency VARCHAR(3),
+    dim_1           VARCHAR,
+    dim_2           VARCHAR,
+    dim_3           VARCHAR,
+    dim_4           VARCHAR,
+    dim_5           VARCHAR,
+    prev_pathname   VARCHAR,
+    user_id         VARCHAR,
+    device_brand    VARCHAR,
+    device_model    VARCHAR
+)
+";
+
+/// SQL statement to create the table of detected traffic anomalies.
+///
+/// Holds hourly aggregates only, never per-event data.
+pub const CREATE_ANOMALIES_TABLE: &str = r"
+CREATE TABLE IF NOT EXISTS anomalies (
+    site_id         VARCHAR NOT NULL,
+    hour            TIMESTAMP NOT NULL,
+    visitors        BIGINT NOT NULL,
+    baseline        DOUBLE NOT NULL,
+    deviation_pct   DOUBLE NOT NULL,
+    detected_at     TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    PRIMARY KEY (site_id, hour)
+)
+";
+
+/// SQL statement to create the visitor → user mapping built by the identity
+/// stitching job.
+///
+/// Maps each visitor hash seen with a `user_id` to that user, so the visitor's
+/// events without one can be attributed to the user as well.
+pub const CREATE_VISITOR_USERS_TABLE: &str = r"
+CREATE TABLE IF NOT EXISTS visitor_users (
+    site_id         VARCHAR NOT NULL,
+    visitor_id      VARCHAR NOT NULL,
+    user_id         VARCHAR NOT NULL,
+    last_seen       TIMESTAMP NOT NULL,
+    PRIMARY KEY (site_id, visitor_id)
+)
+";
+
+/// SQL statement to create the registry of Parquet files behind `events_all`.
+///
+/// `columns` is the comma-separated column list of the file, so the view can
+/// be rebuilt without reading any file footers.  Row counts and timestamp
+/// ranges come from the site manifests, and let [`events_between`] skip files
+/// outside a query's range.
+pub const CREATE_PARQUET_FILES_TABLE: &str = r"
+CREATE TABLE IF NOT EXISTS parquet_files (
+    path            VARCHAR PRIMARY KEY,
+    columns         VARCHAR NOT NULL,
+    registered_at   TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    row_count       BIGINT,
+    min_timestamp   TIMESTAMP,
+    max_timestamp   TIMESTAMP
+)
+";
+
+/// SQL statement to create the registry of materialized session files.
+///
+/// One row per site and day, written by the [session materialization
+/// job](crate::query::materialized_sessions).  `event_rows` is the number of
+/// flushed events the file was built from; a file whose partition now holds
+/// a different number is out of date.
+pub const CREATE_SESSION_PARTITIONS_TABLE: &str = r"
+CREATE TABLE IF NOT EXISTS session_partitions (
+    site_id         VARCHAR NOT NULL,
+    date            VARCHAR NOT NULL,
+    event_rows      BIGINT NOT NULL,
+    path            VARCHAR NOT NULL,
+    built_at        TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    PRIMARY KEY (site_id, date)
+)
+";
+
+/// SQL statement to create the log of flushes in progress.
+///
+/// A flush records the file it is about to write, and removes the entry in
+/// the transaction that deletes the flushed events from `events`.  An entry
+/// left behind by a crash marks a file whose events may still be in `events`.
+pub const CREATE_PENDING_FLUSHES_TABLE: &str = r"
+CREATE TABLE IF NOT EXISTS pending_flushes (
+    path            VARCHAR PRIMARY KEY,
+    started_at      TIMESTAMP NOT NULL DEFAULT current_timestamp
+)
+";
+
+/// SQL statement to create the site → group mapping from the site registry.
+///
+/// A temporary table: it is derived from the config file and rebuilt by
+/// [`sync_site_groups`] on every start.
+pub const CREATE_SITE_GROUPS_TABLE: &str = r"
+CREATE TEMP TABLE IF NOT EXISTS site_groups (
+    site_id         VARCHAR NOT NULL,
+    group_name      VARCHAR NOT NULL
 )
 ";
 
 /// Initialize the database schema.
 pub fn init_schema(conn: &Connection) -> Result<(), duckdb::Error> {
     conn.execute_batch(CREATE_EVENTS_TABLE)?;
+    conn.execute_batch(CREATE_ANOMALIES_TABLE)?;
+    conn.execute_batch(CREATE_PARQUET_FILES_TABLE)?;
+    conn.execute_batch(CREATE_VISITOR_USERS_TABLE)?;
+    conn.execute_batch(CREATE_PENDING_FLUSHES_TABLE)?;
+    conn.execute_batch(CREATE_SESSION_PARTITIONS_TABLE)?;
+    conn.execute_batch(CREATE_SITE_GROUPS_TABLE)?;
+    Ok(())
+}
+
+/// Replace the contents of `site_groups` with `(site_id, group)` pairs.
+pub fn sync_site_groups<'a>(
+    conn: &Connection,
+    groups: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> Result<(), duckdb::Error> {
+    conn.execute_batch(CREATE_SITE_GROUPS_TABLE)?;
+    conn.execute_batch("DELETE FROM site_groups")?;
+    let mut stmt = conn.prepare("INSERT INTO site_groups (site_id, group_name) VALUES (?, ?)")?;
+    for (site_id, group) in groups {
+        stmt.execute(duckdb::params![site_id, group])?;
+    }
     Ok(())
 }
 
@@ -44,56 +255,294 @@ pub fn load_behavioral_extension(conn: &Connection) -> Result<(), duckdb::Error>
     Ok(())
 }
 
-/// Create or refresh the `events_all` view that unions the hot in-memory events
-/// table with the persisted Parquet files on disk.
+/// Synchronise the Parquet file registry with the site manifests, then
+/// rebuild the `events_all` view that unions the hot in-memory events table
+/// with the listed files.
 ///
 /// ## Two-tier design
 /// - **Hot tier** (`events` table): events received in the current session that
 ///   have not yet been flushed to Parquet.  Always up-to-date.
-/// - **Cold tier** (Parquet glob): events flushed in this and previous sessions.
-///   Provides durability and enables queries across server restarts.
+/// - **Cold tier** (registered Parquet files): events flushed in this and
+///   previous sessions.  Provides durability and enables queries across server
+///   restarts.
 ///
 /// ## View lifecycle
-/// - Called once at startup so historical data is immediately queryable.
-/// - Called again after every `flush_events()` so freshly written Parquet files
-///   are included (DuckDB re-evaluates the glob on each query, but creating the
-///   union view for the first time requires at least one matching file).
-/// - If no Parquet files exist yet the function silently falls back to a
-///   passthrough view over the `events` table only.  The view is upgraded to the
-///   full union on the next call (after the first flush writes files to disk).
+/// - Called at startup, and after anything that deletes Parquet files
+///   (retention cleanup, GDPR erasure, compaction).  Only the manifests are
+///   read, never the directory tree; footers are read for new files alone.
+///   A site without a readable manifest has one rebuilt from its files, and
+///   listed files that are missing on disk are skipped.  Files of sites that
+///   cannot be listed stay registered, so a failing disk or mount does not
+///   hide their history.
+/// - Flushes call [`register_parquet_file`] and [`refresh_query_view`] instead.
+/// - If no Parquet files exist the view is a passthrough over the `events`
+///   table only.
 pub fn setup_query_view(conn: &Connection, parquet_dir: &Path) -> Result<(), duckdb::Error> {
-    // Build the glob pattern that covers all partitioned Parquet files.
-    // Single quotes in the path are escaped to prevent SQL injection.
-    let glob = format!(
-        "{}/site_id=*/date=*/*.parquet",
-        parquet_dir.to_string_lossy()
-    );
-    let escaped_glob = glob.replace('\'', "''");
-
-    // Attempt the union view first.  DuckDB ≥1.2 returns zero rows for an
-    // unmatched glob, but older patch-level builds may raise an error; we
-    // handle both cases by falling back to the events-only view.
-    // hive_partitioning=false is required because the Parquet files already
-    // contain site_id/timestamp columns; the Hive-style directory names
-    // (site_id=.../date=...) are for human navigation and retention cleanup
-    // only. With hive_partitioning=true (the default), DuckDB would add
-    // duplicate site_id/date columns from the path, breaking the UNION ALL.
-    let union_sql = format!(
-        "CREATE OR REPLACE VIEW events_all AS \
-         SELECT * FROM events \
-         UNION ALL \
-         SELECT * FROM read_parquet('{escaped_glob}', union_by_name=true, hive_partitioning=false)"
-    );
+    let storage = ParquetStorage::new(parquet_dir);
+    let mut listed: HashMap<String, ManifestFile> = HashMap::new();
+    // Directories whose files could not be listed; their files stay registered.
+    let mut unlisted: Vec<PathBuf> = Vec::new();
+    let sites = storage.site_dirs().unwrap_or_else(|e| {
+        tracing::warn!(path = %parquet_dir.display(), error = %e, "Could not list site directories; keeping registered Parquet files");
+        unlisted.push(parquet_dir.to_path_buf());
+        Vec::new()
+    });
+    for site in sites {
+        let manifest = match Manifest::load(&site.path) {
+            Ok(Some(manifest)) => Ok(manifest),
+            Ok(None) => manifest::rebuild(conn, &site.path),
+            Err(e) => {
+                tracing::warn!(path = %site.path.display(), error = %e, "Rebuilding unreadable manifest");
+                manifest::rebuild(conn, &site.path)
+            }
+        };
+        let manifest = match manifest {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                tracing::warn!(path = %site.path.display(), error = %e, "Could not list Parquet files; keeping registered ones");
+                unlisted.push(site.path);
+                continue;
+            }
+        };
+        for file in manifest.files {
+            let path = site.path.join(&file.path);
+            if !path.exists() {
+                tracing::warn!(path = %path.display(), "Skipping listed Parquet file missing on disk");
+                continue;
+            }
+            listed.insert(path.to_string_lossy().into_owned(), file);
+        }
+    }
 
-    if conn.execute_batch(&union_sql).is_ok() {
-        return Ok(());
+    let registered = registered_files(conn)?;
+    let unregistered = registered.keys().filter(|p| {
+        !listed.contains_key(*p) && !unlisted.iter().any(|dir| Path::new(p).starts_with(dir))
+    });
+    for path in unregistered {
+        conn.execute("DELETE FROM parquet_files WHERE path = ?", [path])?;
     }
+    // Files registered before the registry held timestamp ranges.
+    for (path, has_range) in &registered {
+        if let (false, Some(file)) = (has_range, listed.get(path)) {
+            conn.execute(
+                "UPDATE parquet_files
+                 SET row_count = ?, min_timestamp = CAST(? AS TIMESTAMP), max_timestamp = CAST(? AS TIMESTAMP)
+                 WHERE path = ?",
+                duckdb::params![file.rows, file.min_timestamp, file.max_timestamp, path],
+            )?;
+        }
+    }
+
+    let mut new_files: Vec<&String> = listed
+        .keys()
+        .filter(|p| !registered.contains_key(*p))
+        .collect();
+    new_files.sort();
+    if !new_files.is_empty() {
+        let list = sql_string_list(new_files.iter().map(|p| p.as_str()));
+        let mut stmt = conn.prepare(&format!(
+            "SELECT file_name, string_agg(name, ',')
+             FROM parquet_schema({list})
+             GROUP BY file_name"
+        ))?;
+        let schemas: Vec<(String, String)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        for (path, columns) in schemas {
+            if let Some(file) = listed.get(&path) {
+                insert_parquet_file(conn, &path, &columns, file)?;
+            }
+        }
+    }
+
+    refresh_query_view(conn)
+}
+
+/// Add a Parquet file written with the current [`EVENT_COLUMNS`] layout to the
+/// registry.
+///
+/// The row count and timestamps come from its manifest entry.  Call
+/// [`refresh_query_view`] afterwards to make it queryable.
+pub fn register_parquet_file(
+    conn: &Connection,
+    path: &str,
+    file: &ManifestFile,
+) -> Result<(), duckdb::Error> {
+    let mut columns: Vec<&str> = EVENT_COLUMNS.iter().map(|(name, _)| *name).collect();
+    columns.push("schema_version");
+    insert_parquet_file(conn, path, &columns.join(","), file)
+}
 
-    // No Parquet files yet — create a passthrough view so queries compile.
-    conn.execute_batch("CREATE OR REPLACE VIEW events_all AS SELECT * FROM events")?;
+fn insert_parquet_file(
+    conn: &Connection,
+    path: &str,
+    columns: &str,
+    file: &ManifestFile,
+) -> Result<(), duckdb::Error> {
+    conn.execute(
+        "INSERT INTO parquet_files (path, columns, row_count, min_timestamp, max_timestamp)
+         VALUES (?, ?, ?, CAST(? AS TIMESTAMP), CAST(? AS TIMESTAMP))
+         ON CONFLICT DO NOTHING",
+        duckdb::params![
+            path,
+            columns,
+            file.rows,
+            file.min_timestamp,
+            file.max_timestamp
+        ],
+    )?;
     Ok(())
 }
 
+/// Rebuild `events_all` from the registered Parquet files.
+///
+/// The file list is embedded in the view, because `read_parquet` only accepts
+/// constant arguments.  hive_partitioning=false is required because the
+/// Parquet files already contain site_id/timestamp columns; the Hive-style
+/// directory names (site_id=.../date=...) are for human navigation and
+/// retention cleanup only.  With hive_partitioning=true (the default), DuckDB
+/// would add duplicate site_id/date columns from the path, breaking the
+/// UNION ALL.
+pub fn refresh_query_view(conn: &Connection) -> Result<(), duckdb::Error> {
+    let mut stmt = conn.prepare("SELECT path, columns FROM parquet_files ORDER BY path")?;
+    let files: Vec<(String, String)> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+    conn.execute_batch(&format!(
+        "CREATE OR REPLACE VIEW events_all AS {}",
+        events_relation(&files)
+    ))
+}
+
+/// `events_all` restricted to the Parquet files whose timestamps overlap
+/// `[start, end)`, as a parenthesised subquery to use in its place.  Files
+/// registered without a timestamp range are always read.
+pub fn events_between(conn: &Connection, start: &str, end: &str) -> Result<String, duckdb::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT path, columns FROM parquet_files
+         WHERE min_timestamp IS NULL
+            OR (min_timestamp < CAST(? AS TIMESTAMP) AND max_timestamp >= CAST(? AS TIMESTAMP))
+         ORDER BY path",
+    )?;
+    let files: Vec<(String, String)> = stmt
+        .query_map([end, start], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+    Ok(format!("({})", events_relation(&files)))
+}
+
+/// A Parquet file in the registry, as listed by [`files_between`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegisteredFile {
+    pub path: String,
+    pub row_count: Option<u64>,
+    /// Whether every event in the file falls within the queried range.
+    pub within: bool,
+}
+
+/// Registered Parquet files whose timestamps overlap `[start, end)`, as
+/// [`events_between`] reads them.  Files registered without a timestamp
+/// range are included, and not counted as within the range.
+pub fn files_between(
+    conn: &Connection,
+    start: &str,
+    end: &str,
+) -> Result<Vec<RegisteredFile>, duckdb::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT path, row_count,
+                COALESCE(min_timestamp >= CAST(? AS TIMESTAMP) AND max_timestamp < CAST(? AS TIMESTAMP), false)
+         FROM parquet_files
+         WHERE min_timestamp IS NULL
+            OR (min_timestamp < CAST(? AS TIMESTAMP) AND max_timestamp >= CAST(? AS TIMESTAMP))
+         ORDER BY path",
+    )?;
+    let files = stmt
+        .query_map([start, end, end, start], |row| {
+            Ok(RegisteredFile {
+                path: row.get(0)?,
+                row_count: row.get(1)?,
+                within: row.get(2)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(files)
+}
+
+/// `events_all` restricted to the registered Parquet files among `paths`, as
+/// a parenthesised subquery to use in its place.
+pub fn events_in(conn: &Connection, paths: &[&str]) -> Result<String, duckdb::Error> {
+    if paths.is_empty() {
+        return Ok(format!("({})", events_relation(&[])));
+    }
+    let mut stmt = conn.prepare(&format!(
+        "SELECT path, columns FROM parquet_files
+         WHERE list_contains({}, path)
+         ORDER BY path",
+        sql_string_list(paths.iter().copied())
+    ))?;
+    let files: Vec<(String, String)> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+    Ok(format!("({})", events_relation(&files)))
+}
+
+/// `SELECT` unioning the `events` table with `files`, given as
+/// `(path, columns)` pairs from the registry.
+fn events_relation(files: &[(String, String)]) -> String {
+    let table_columns = EVENT_COLUMNS
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    if files.is_empty() {
+        return format!("SELECT {table_columns} FROM events");
+    }
+
+    let present: HashSet<String> = files
+        .iter()
+        .flat_map(|(_, columns)| columns.split(','))
+        .map(str::to_string)
+        .collect();
+    let list = sql_string_list(files.iter().map(|(path, _)| path.as_str()));
+    format!(
+        "SELECT {table_columns} FROM events \
+         UNION ALL \
+         SELECT {} FROM read_parquet({list}, union_by_name=true, hive_partitioning=false)",
+        parquet_read_columns(&present)
+    )
+}
+
+/// `SELECT` reading `paths` into the canonical event layout, whatever
+/// release wrote them.
+pub fn parquet_select(conn: &Connection, paths: &[&str]) -> Result<String, duckdb::Error> {
+    let list = sql_string_list(paths.iter().copied());
+    let mut stmt = conn.prepare(&format!("SELECT DISTINCT name FROM parquet_schema({list})"))?;
+    let present: HashSet<String> = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<_, _>>()?;
+    Ok(format!(
+        "SELECT {} FROM read_parquet({list}, union_by_name=true, hive_partitioning=false)",
+        parquet_read_columns(&present)
+    ))
+}
+
+/// Registered paths, and whether each has a timestamp range.
+fn registered_files(conn: &Connection) -> Result<HashMap<String, bool>, duckdb::Error> {
+    let mut stmt = conn.prepare("SELECT path, row_count IS NOT NULL FROM parquet_files")?;
+    let paths = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+    Ok(paths)
+}
+
+/// Format paths as a DuckDB list literal, e.g. `['a.parquet', 'b.parquet']`.
+pub fn sql_string_list<'a>(items: impl Iterator<Item = &'a str>) -> String {
+    let quoted: Vec<String> = items
+        .map(|s| format!("'{}'", s.replace('\'', "''")))
+        .collect();
+    format!("[{}]", quoted.join(", "))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -113,6 +562,146 @@ mod tests {
         assert_eq!(count, 0);
     }
 
+    #[test]
+    fn test_event_columns_match_table() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn).unwrap();
+
+        let mut stmt = conn
+            .prepare("SELECT column_name, data_type FROM information_schema.columns WHERE table_name = 'events' ORDER BY ordinal_position")
+            .unwrap();
+        let columns: Vec<(String, String)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .filter_map(Result::ok)
+            .collect();
+        let expected: Vec<(String, String)> = EVENT_COLUMNS
+            .iter()
+            .map(|(n, t)| ((*n).to_string(), (*t).to_string()))
+            .collect();
+        assert_eq!(columns, expected);
+    }
+
+    #[test]
+    fn test_setup_query_view_reads_older_parquet_layouts() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let part = dir.path().join("site_id=a.com").join("date=2024-01-15");
+        std::fs::create_dir_all(&part).unwrap();
+
+        // A version 1 file: no dimension or schema_version columns, and the
+        // revenue column stored as DOUBLE.
+        conn.execute_batch(&format!(
+            "COPY (SELECT 'a.com' AS site_id, 'v1' AS visitor_id,
+                          TIMESTAMP '2024-01-15 10:00:00' AS timestamp,
+                          'pageview' AS event_name, '/' AS pathname,
+                          CAST(9.99 AS DOUBLE) AS revenue_amount)
+             TO '{}' (FORMAT PARQUET)",
+            part.join("0001.parquet").to_string_lossy()
+        ))
+        .unwrap();
+        setup_query_view(&conn, dir.path()).unwrap();
+
+        let (dim, revenue): (Option<String>, String) = conn
+            .query_row(
+                "SELECT dim_1, CAST(revenue_amount AS VARCHAR) FROM events_all",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(dim, None);
+        assert_eq!(revenue, "9.99");
+
+        // A current file alongside it unions cleanly.
+        conn.execute(
+            "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname, dim_1)
+             VALUES ('a.com', 'v2', '2024-01-15 11:00:00', 'pageview', '/', 'x')",
+            [],
+        )
+        .unwrap();
+        conn.execute_batch(&format!(
+            "COPY (SELECT {} FROM events) TO '{}' (FORMAT PARQUET)",
+            parquet_write_columns(),
+            part.join("0002.parquet").to_string_lossy()
+        ))
+        .unwrap();
+        conn.execute_batch("DELETE FROM events").unwrap();
+        // Files written by hand are picked up once the manifest is rebuilt.
+        setup_query_view(&conn, dir.path()).unwrap();
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM events_all", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+        std::fs::remove_file(
+            dir.path()
+                .join("site_id=a.com")
+                .join(manifest::MANIFEST_FILE),
+        )
+        .unwrap();
+        setup_query_view(&conn, dir.path()).unwrap();
+
+        let count: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM events_all WHERE dim_1 = 'x' OR dim_1 IS NULL",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(count, 2);
+        // schema_version is a storage detail and is not exposed by the view.
+        assert!(conn
+            .prepare("SELECT schema_version FROM events_all")
+            .is_err());
+    }
+
+    #[test]
+    fn test_flush_registers_files_incrementally() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        setup_query_view(&conn, dir.path()).unwrap();
+        let storage = crate::storage::parquet::ParquetStorage::new(dir.path());
+
+        for ts in ["2024-01-15 10:00:00", "2024-01-16 10:00:00"] {
+            conn.execute(
+                "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+                 VALUES ('a.com', 'v1', CAST(? AS TIMESTAMP), 'pageview', '/')",
+                [ts],
+            )
+            .unwrap();
+            storage.flush_events(&conn).unwrap();
+        }
+
+        let registered: i64 = conn
+            .query_row("SELECT COUNT(*) FROM parquet_files", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(registered, 2);
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM events_all", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+
+        // A range query reads only the file overlapping it.
+        let source = events_between(&conn, "2024-01-16", "2024-01-17").unwrap();
+        assert!(source.contains("date=2024-01-16"));
+        assert!(!source.contains("date=2024-01-15"));
+        let count: i64 = conn
+            .query_row(&format!("SELECT COUNT(*) FROM {source}"), [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(count, 1);
+
+        // Deleting a partition and re-syncing drops it from the registry.
+        std::fs::remove_dir_all(dir.path().join("site_id=a.com").join("date=2024-01-15")).unwrap();
+        setup_query_view(&conn, dir.path()).unwrap();
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM events_all", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
     #[test]
     fn test_init_schema() {
         let conn = Connection::open_in_memory().unwrap();
@@ -0,0 +1,286 @@
+//! Per-file summary sidecars.
+//!
+//! Each Parquet file written by a flush or a compaction gets a small JSON
+//! file beside it, `0001.parquet` → `0001.summary.json`, holding the file's
+//! pageview and event counts, its top pages and a HyperLogLog sketch of its
+//! visitors.  A partition's summary is the merge of its files' summaries, so
+//! reports that only need these totals, like the multi-site overview, can
+//! read a few kilobytes per file instead of the events themselves.
+//!
+//! Sidecars are written on a best-effort basis: a file without one, or
+//! whose sidecar's row count no longer matches the file's, is scanned as
+//! before.
+
+use base64::Engine;
+use duckdb::Connection;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Bits of the visitor hash that pick a sketch register.  4,096 registers
+/// give a standard error of about 1.6%.
+const PRECISION: u32 = 12;
+
+const REGISTERS: usize = 1 << PRECISION;
+
+/// Pages kept per file, by pageviews.
+pub const TOP_PAGES: usize = 100;
+
+/// Summary of one Parquet file, as stored in its sidecar.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileSummary {
+    pub site_id: String,
+    /// Rows in the file, to tell a sidecar left over from an earlier file.
+    pub rows: u64,
+    pub pageviews: u64,
+    /// Events other than heartbeats.
+    pub events: u64,
+    pub visitors: VisitorSketch,
+    /// The most viewed pages, busiest first.
+    pub top_pages: Vec<PageCount>,
+}
+
+/// Pageviews of one page.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PageCount {
+    pub pathname: String,
+    pub pageviews: u64,
+}
+
+/// HyperLogLog sketch of visitor IDs.  Stored as its registers, base64
+/// encoded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct VisitorSketch {
+    registers: Vec<u8>,
+}
+
+impl Default for VisitorSketch {
+    fn default() -> Self {
+        Self {
+            registers: vec![0; REGISTERS],
+        }
+    }
+}
+
+impl VisitorSketch {
+    pub fn insert(&mut self, visitor_id: &str) {
+        let digest = Sha256::digest(visitor_id.as_bytes());
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&digest[..8]);
+        let hash = u64::from_be_bytes(bytes);
+        let index = usize::try_from(hash >> (64 - PRECISION)).unwrap_or(0);
+        // Position of the first set bit after the index bits, capped for a
+        // hash whose remaining bits are all zero.
+        let rank = (hash << PRECISION).leading_zeros().min(64 - PRECISION) + 1;
+        let rank = u8::try_from(rank).unwrap_or(u8::MAX);
+        if self.registers[index] < rank {
+            self.registers[index] = rank;
+        }
+    }
+
+    /// Add the visitors of `other`.
+    pub fn merge(&mut self, other: &Self) {
+        for (mine, theirs) in self.registers.iter_mut().zip(&other.registers) {
+            *mine = (*mine).max(*theirs);
+        }
+    }
+
+    /// Estimated number of distinct visitors inserted.
+    #[allow(
+        clippy::cast_precision_loss,
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss
+    )]
+    pub fn estimate(&self) -> u64 {
+        let m = REGISTERS as f64;
+        let (sum, zeros) = self
+            .registers
+            .iter()
+            .fold((0.0, 0_usize), |(sum, zeros), &r| {
+                (sum + (-f64::from(r)).exp2(), zeros + usize::from(r == 0))
+            });
+        let raw = 0.7213 / (1.0 + 1.079 / m) * m * m / sum;
+        // Linear counting is more accurate while many registers are empty.
+        let estimate = if raw <= 2.5 * m && zeros > 0 {
+            m * (m / zeros as f64).ln()
+        } else {
+            raw
+        };
+        estimate.round() as u64
+    }
+}
+
+impl From<VisitorSketch> for String {
+    fn from(sketch: VisitorSketch) -> Self {
+        base64::engine::general_purpose::STANDARD.encode(sketch.registers)
+    }
+}
+
+impl TryFrom<String> for VisitorSketch {
+    type Error = String;
+
+    fn try_from(encoded: String) -> Result<Self, Self::Error> {
+        let registers = base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .map_err(|e| e.to_string())?;
+        if registers.len() != REGISTERS {
+            return Err(format!(
+                "expected {REGISTERS} registers, found {}",
+                registers.len()
+            ));
+        }
+        Ok(Self { registers })
+    }
+}
+
+/// The sidecar path of a Parquet file.
+pub fn sidecar_path(parquet: &Path) -> PathBuf {
+    parquet.with_extension("summary.json")
+}
+
+/// Summarize the events selected by `select`, a `SELECT` returning at least
+/// `visitor_id`, `event_name` and `pathname`.
+pub fn summarize(
+    conn: &Connection,
+    site_id: &str,
+    select: &str,
+) -> Result<FileSummary, duckdb::Error> {
+    let (rows, pageviews, events) = conn.query_row(
+        &format!(
+            "SELECT COUNT(*),
+                    COUNT(*) FILTER (WHERE event_name = 'pageview'),
+                    COUNT(*) FILTER (WHERE event_name <> 'heartbeat')
+             FROM ({select})"
+        ),
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+
+    let mut visitors = VisitorSketch::default();
+    let mut stmt = conn.prepare(&format!(
+        "SELECT DISTINCT visitor_id FROM ({select}) WHERE visitor_id IS NOT NULL"
+    ))?;
+    for visitor_id in stmt.query_map([], |row| row.get::<_, String>(0))? {
+        visitors.insert(&visitor_id?);
+    }
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT pathname, COUNT(*) AS pageviews
+         FROM ({select})
+         WHERE event_name = 'pageview'
+         GROUP BY pathname
+         ORDER BY pageviews DESC, pathname
+         LIMIT {TOP_PAGES}"
+    ))?;
+    let top_pages = stmt
+        .query_map([], |row| {
+            Ok(PageCount {
+                pathname: row.get(0)?,
+                pageviews: row.get(1)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+
+    Ok(FileSummary {
+        site_id: site_id.to_string(),
+        rows,
+        pageviews,
+        events,
+        visitors,
+        top_pages,
+    })
+}
+
+/// Summarize the events selected by `select` into the sidecar of `parquet`.
+/// Best effort: a failure is logged, and leaves the file without a sidecar.
+pub fn write(conn: &Connection, parquet: &Path, site_id: &str, select: &str) {
+    let written = summarize(conn, site_id, select)
+        .map_err(|e| e.to_string())
+        .and_then(|summary| serde_json::to_vec(&summary).map_err(|e| e.to_string()))
+        .and_then(|json| fs::write(sidecar_path(parquet), json).map_err(|e| e.to_string()));
+    if let Err(error) = written {
+        tracing::warn!(path = %parquet.display(), %error, "Failed to write summary sidecar");
+    }
+}
+
+/// The summary of `parquet`, if it has a readable sidecar.
+pub fn load(parquet: &Path) -> Option<FileSummary> {
+    let json = fs::read(sidecar_path(parquet)).ok()?;
+    serde_json::from_slice(&json).ok()
+}
+
+/// Remove the sidecar of `parquet`, if any.
+pub fn remove(parquet: &Path) -> std::io::Result<()> {
+    match fs::remove_file(sidecar_path(parquet)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sketch_estimates_and_merges() {
+        let mut a = VisitorSketch::default();
+        let mut b = VisitorSketch::default();
+        for i in 0..6000 {
+            a.insert(&format!("visitor-{i}"));
+        }
+        for i in 4000..10_000 {
+            b.insert(&format!("visitor-{i}"));
+        }
+        a.merge(&b);
+        let estimate = a.estimate();
+        assert!((9_500..=10_500).contains(&estimate), "{estimate}");
+        assert_eq!(VisitorSketch::default().estimate(), 0);
+
+        let encoded = String::from(a.clone());
+        assert_eq!(VisitorSketch::try_from(encoded).unwrap(), a);
+        assert!(VisitorSketch::try_from("AAAA".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_summarize_and_sidecar() {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::storage::schema::init_schema(&conn).unwrap();
+        for (visitor, event, path) in [
+            ("v1", "pageview", "/"),
+            ("v1", "pageview", "/about"),
+            ("v2", "pageview", "/"),
+            ("v2", "heartbeat", "/"),
+            ("v2", "signup", "/"),
+        ] {
+            conn.execute(
+                "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+                 VALUES ('a.com', ?, '2024-01-15 10:00:00', ?, ?)",
+                duckdb::params![visitor, event, path],
+            )
+            .unwrap();
+        }
+        let summary = summarize(&conn, "a.com", "SELECT * FROM events").unwrap();
+        assert_eq!(summary.rows, 5);
+        assert_eq!(summary.pageviews, 3);
+        assert_eq!(summary.events, 4);
+        assert_eq!(summary.visitors.estimate(), 2);
+        assert_eq!(
+            summary.top_pages[0],
+            PageCount {
+                pathname: "/".to_string(),
+                pageviews: 2
+            }
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let parquet = dir.path().join("0001.parquet");
+        write(&conn, &parquet, "a.com", "SELECT * FROM events");
+        assert!(dir.path().join("0001.summary.json").exists());
+        assert_eq!(load(&parquet), Some(summary));
+        remove(&parquet).unwrap();
+        assert_eq!(load(&parquet), None);
+        remove(&parquet).unwrap();
+    }
+}
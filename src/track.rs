@@ -0,0 +1,310 @@
+//! Server-side pageview tracking for other Axum apps (`tracking-layer`
+//! feature).
+//!
+//! Mounted with a [`Tracker`], [`track_pageviews`] records a `pageview` for
+//! every `GET` request the app answers with a 2xx status, with the request's
+//! path, `Referer`, User-Agent and client IP, so a backend-only Rust app is
+//! tracked without the tracking script:
+//!
+//! ```text
+//! let tracker = Tracker::endpoint("example.com", "https://analytics.example.com/api/event")
+//!     .exclude("/health");
+//! let app = Router::new()
+//!     .route("/", get(index))
+//!     .layer(axum::middleware::from_fn_with_state(tracker, track_pageviews));
+//! ```
+//!
+//! Events are posted to a Mallard Metrics server's `POST /api/event`, or
+//! submitted straight to a server embedded with
+//! [`crate::embed::MallardServer`].  Embedded events come from a trusted
+//! producer like those of the event bus: origin checks,
+//! `require_ingest_key` and the per-site rate limit do not apply.
+//!
+//! Recording happens in the background and never delays or fails the app's
+//! response.  Events that cannot be recorded are dropped and logged at
+//! `DEBUG`.
+
+use crate::embed::MallardServer;
+use crate::ingest::handler::{stats_site, submit, validate_payload, AppState, EventPayload};
+use crate::ingest::queue::RawEvent;
+use crate::ingest::stats::IngestOutcome;
+use axum::extract::{ConnectInfo, State};
+use axum::http::{header, Method, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Longest wait for the analytics server to accept an event.
+const POST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where a [`Tracker`] records pageviews.
+#[derive(Clone)]
+enum Sink {
+    Endpoint {
+        client: reqwest::Client,
+        url: String,
+        api_key: Option<String>,
+    },
+    Embedded(Arc<AppState>),
+}
+
+#[derive(Clone)]
+struct Settings {
+    site_id: String,
+    sink: Sink,
+    /// Path prefixes not tracked.
+    excluded: Vec<String>,
+}
+
+/// State of [`track_pageviews`]: the site pageviews are recorded for, and
+/// where.
+#[derive(Clone)]
+pub struct Tracker {
+    settings: Arc<Settings>,
+}
+
+impl Tracker {
+    fn new(site_id: impl Into<String>, sink: Sink) -> Self {
+        Self {
+            settings: Arc::new(Settings {
+                site_id: site_id.into(),
+                sink,
+                excluded: Vec::new(),
+            }),
+        }
+    }
+
+    /// Post pageviews of `site_id` to the ingestion endpoint at `url`, e.g.
+    /// `https://analytics.example.com/api/event`.
+    pub fn endpoint(site_id: impl Into<String>, url: impl Into<String>) -> Self {
+        Self::new(
+            site_id,
+            Sink::Endpoint {
+                client: reqwest::Client::new(),
+                url: url.into(),
+                api_key: None,
+            },
+        )
+    }
+
+    /// Submit pageviews of `site_id` to `server`, running in this process.
+    pub fn embedded(site_id: impl Into<String>, server: &MallardServer) -> Self {
+        Self::new(site_id, Sink::Embedded(Arc::clone(server.state())))
+    }
+
+    /// Send `key` as a write API key, for sites with `require_ingest_key`.
+    /// Not needed for an embedded server.
+    #[must_use]
+    pub fn api_key(mut self, key: impl Into<String>) -> Self {
+        if let Sink::Endpoint { api_key, .. } = &mut Arc::make_mut(&mut self.settings).sink {
+            *api_key = Some(key.into());
+        }
+        self
+    }
+
+    /// Leave paths starting with `prefix` untracked, e.g. `/health` or
+    /// `/assets/`.
+    #[must_use]
+    pub fn exclude(mut self, prefix: impl Into<String>) -> Self {
+        Arc::make_mut(&mut self.settings)
+            .excluded
+            .push(prefix.into());
+        self
+    }
+
+    /// The pageview `request` makes, unless it is not tracked.
+    fn pageview<B>(&self, request: &Request<B>) -> Option<RawEvent> {
+        let path = request.uri().path();
+        if request.method() != Method::GET
+            || self
+                .settings
+                .excluded
+                .iter()
+                .any(|prefix| path.starts_with(prefix.as_str()))
+        {
+            return None;
+        }
+        let headers = request.headers();
+        let path_and_query = request.uri().path_and_query().map_or(path, |p| p.as_str());
+        let payload = EventPayload {
+            domain: self.settings.site_id.clone(),
+            name: "pageview".to_string(),
+            url: format!("https://{}{path_and_query}", self.settings.site_id),
+            referrer: headers
+                .get(header::REFERER)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string),
+            screen_width: None,
+            props: None,
+            revenue_amount: None,
+            revenue_currency: None,
+            event_id: None,
+            prev_pathname: None,
+            user_id: None,
+            timestamp: None,
+        };
+        let mut raw = RawEvent::new(payload, headers);
+        if raw.ip == "unknown" {
+            if let Some(ConnectInfo(addr)) = request.extensions().get::<ConnectInfo<SocketAddr>>() {
+                raw.ip = addr.ip().to_string();
+            }
+        }
+        Some(raw)
+    }
+
+    /// Record `raw` in the background.
+    fn record(&self, raw: RawEvent) {
+        let settings = Arc::clone(&self.settings);
+        tokio::spawn(async move {
+            let result = match &settings.sink {
+                Sink::Endpoint {
+                    client,
+                    url,
+                    api_key,
+                } => post(client, url, api_key.as_deref(), &raw).await,
+                Sink::Embedded(state) => ingest(state, raw).await,
+            };
+            if let Err(error) = result {
+                tracing::debug!(site_id = %settings.site_id, %error, "Pageview not recorded");
+            }
+        });
+    }
+}
+
+/// Record a pageview for each `GET` request answered with a 2xx status;
+/// mount with `axum::middleware::from_fn_with_state(tracker, track_pageviews)`.
+pub async fn track_pageviews(
+    State(tracker): State<Tracker>,
+    request: Request<axum::body::Body>,
+    next: Next,
+) -> Response {
+    let pageview = tracker.pageview(&request);
+    let response = next.run(request).await;
+    if let Some(raw) = pageview.filter(|_| response.status().is_success()) {
+        tracker.record(raw);
+    }
+    response
+}
+
+/// Post `raw` to the ingestion endpoint at `url`, on behalf of its client.
+async fn post(
+    client: &reqwest::Client,
+    url: &str,
+    api_key: Option<&str>,
+    raw: &RawEvent,
+) -> Result<(), String> {
+    let body = serde_json::to_string(&raw.payload).map_err(|e| e.to_string())?;
+    let mut request = client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(reqwest::header::USER_AGENT, &raw.user_agent)
+        .body(body)
+        .timeout(POST_TIMEOUT);
+    if raw.ip != "unknown" {
+        request = request.header("x-forwarded-for", &raw.ip);
+    }
+    if let Some(key) = api_key {
+        request = request.bearer_auth(key);
+    }
+    request
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map(drop)
+        .map_err(|e| e.to_string())
+}
+
+/// Validate `raw` and submit it to the embedded server's ingest pipeline.
+async fn ingest(state: &Arc<AppState>, raw: RawEvent) -> Result<(), String> {
+    let errors = validate_payload(&raw.payload);
+    let site_id = stats_site(&raw.payload, &errors).to_string();
+    if !errors.is_empty() {
+        state.ingest_stats.record(&site_id, IngestOutcome::Rejected);
+        return Err(format!("invalid event: {errors:?}"));
+    }
+    submit(state, raw)
+        .await
+        .map_err(|status| format!("event refused with {status}"))?;
+    state.ingest_stats.record(&site_id, IngestOutcome::Accepted);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::routing::get;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_tracks_successful_get_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = crate::config::Config {
+            data_dir: dir.path().to_path_buf(),
+            ..crate::config::Config::default()
+        };
+        let server = MallardServer::builder()
+            .config(config)
+            .build()
+            .await
+            .unwrap();
+        let tracker = Tracker::embedded("shop.example", &server).exclude("/health");
+        let app = Router::new()
+            .route(
+                "/products",
+                get(|| async { "products" }).post(|| async { "ok" }),
+            )
+            .route("/health", get(|| async { "ok" }))
+            .layer(axum::middleware::from_fn_with_state(
+                tracker,
+                track_pageviews,
+            ));
+
+        let request = |method: &str, uri: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(
+                    header::USER_AGENT,
+                    "Mozilla/5.0 (X11; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0",
+                )
+                .header("x-forwarded-for", "203.0.113.7")
+                .body(Body::empty())
+                .unwrap()
+        };
+        for (method, uri) in [
+            ("GET", "/products?page=2"),
+            ("POST", "/products"),
+            ("GET", "/health"),
+            ("GET", "/missing"),
+        ] {
+            app.clone().oneshot(request(method, uri)).await.unwrap();
+        }
+
+        let state = server.state();
+        for _ in 0..500 {
+            if !state.buffer.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        server.shutdown().await;
+        let (count, pathname, browser): (i64, String, String) = state
+            .buffer
+            .conn()
+            .lock()
+            .query_row(
+                "SELECT COUNT(*), ANY_VALUE(pathname), ANY_VALUE(browser)
+                 FROM events_all WHERE site_id = 'shop.example'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(pathname, "/products");
+        assert_eq!(browser, "Firefox");
+    }
+}
@@ -0,0 +1,201 @@
+//! Zero-downtime binary upgrades.
+//!
+//! Two ways hand the listening port from a running server to a new binary
+//! without refusing connections:
+//!
+//! - systemd socket activation: systemd owns the socket and passes it to
+//!   each start of the service, queuing connections while it restarts.
+//! - `reuse_port`: the new binary binds the port with `SO_REUSEPORT` while
+//!   the old one still serves, and waits for the old one to release the
+//!   database before it starts answering.
+//!
+//! Either way the old server is told to go with [`Drain`], by
+//! `POST /api/admin/drain` or a signal: it fails readiness, closes
+//! keep-alive connections, flushes buffered events and exits.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::Notify;
+
+/// How long a server binding with `reuse_port` waits for the server it
+/// replaces to release the database.
+pub const DATABASE_WAIT: Duration = Duration::from_secs(120);
+
+/// Wait between attempts to open a database held by another process.
+const DATABASE_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// First file descriptor passed by systemd socket activation.
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// Whether the socket passed by systemd has been taken.
+#[cfg(unix)]
+static SYSTEMD_SOCKET_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Whether the server is draining so a new binary can take over.
+#[derive(Default)]
+pub struct Drain {
+    draining: AtomicBool,
+    notify: Notify,
+}
+
+impl Drain {
+    /// Start draining.  Returns `false` when already draining.
+    pub fn start(&self) -> bool {
+        let started = !self.draining.swap(true, Ordering::SeqCst);
+        if started {
+            self.notify.notify_waiters();
+        }
+        started
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once draining starts.
+    pub async fn wait(&self) {
+        let notified = self.notify.notified();
+        if self.is_draining() {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// The socket passed by systemd socket activation, or `addr` bound, with
+/// `SO_REUSEPORT` when `reuse_port` is set.
+pub async fn bind(addr: &str, reuse_port: bool) -> std::io::Result<TcpListener> {
+    #[cfg(unix)]
+    if let Some(listener) = systemd_listener()? {
+        tracing::info!("Using the socket passed by systemd");
+        return Ok(listener);
+    }
+    if !reuse_port {
+        return TcpListener::bind(addr).await;
+    }
+    bind_reuse_port(addr).await
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+async fn bind_reuse_port(addr: &str) -> std::io::Result<TcpListener> {
+    let mut last_error = None;
+    for addr in tokio::net::lookup_host(addr).await? {
+        let socket = if addr.is_ipv4() {
+            tokio::net::TcpSocket::new_v4()?
+        } else {
+            tokio::net::TcpSocket::new_v6()?
+        };
+        socket.set_reuseaddr(true)?;
+        socket.set_reuseport(true)?;
+        if let Err(e) = socket.bind(addr) {
+            last_error = Some(e);
+            continue;
+        }
+        return socket.listen(1024);
+    }
+    Err(last_error.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "address resolved to nothing",
+        )
+    }))
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+async fn bind_reuse_port(addr: &str) -> std::io::Result<TcpListener> {
+    tracing::warn!("reuse_port is not supported on this platform; binding without it");
+    TcpListener::bind(addr).await
+}
+
+/// The listening socket passed by systemd, if it started this process with
+/// one (`LISTEN_PID` and `LISTEN_FDS`) and it has not been taken yet.
+///
+/// The variables are left in place: removing them would race with other
+/// threads reading the environment.  A child process ignores them, since
+/// `LISTEN_PID` names this one.
+#[cfg(unix)]
+fn systemd_listener() -> std::io::Result<Option<TcpListener>> {
+    use std::os::fd::FromRawFd;
+
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    let fds = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse::<u32>().ok())
+        .unwrap_or(0);
+    if !for_us || fds == 0 || SYSTEMD_SOCKET_TAKEN.swap(true, Ordering::SeqCst) {
+        return Ok(None);
+    }
+    if fds > 1 {
+        tracing::warn!(fds, "systemd passed several sockets; using the first");
+    }
+    // SAFETY: systemd passes its sockets as open descriptors from
+    // SD_LISTEN_FDS_START, owned by the process named in LISTEN_PID, which
+    // was checked above.  SYSTEMD_SOCKET_TAKEN makes this the only place the
+    // descriptor is taken, once, so nothing else in the process owns it.
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(SD_LISTEN_FDS_START) };
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener).map(Some)
+}
+
+/// Open the DuckDB database at `path`, retrying for up to `wait` while
+/// another process holds it, as the server being replaced does until it
+/// exits.
+pub async fn open_database(
+    path: &std::path::Path,
+    wait: Duration,
+) -> duckdb::Result<duckdb::Connection> {
+    let deadline = tokio::time::Instant::now() + wait;
+    let mut logged = false;
+    loop {
+        match duckdb::Connection::open(path) {
+            Ok(conn) => return Ok(conn),
+            Err(e) if tokio::time::Instant::now() < deadline => {
+                if !logged {
+                    tracing::info!(error = %e, "Waiting for the database to be released");
+                    logged = true;
+                }
+                tokio::time::sleep(DATABASE_RETRY_DELAY).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drain() {
+        let drain = std::sync::Arc::new(Drain::default());
+        let waiter = tokio::spawn({
+            let drain = std::sync::Arc::clone(&drain);
+            async move { drain.wait().await }
+        });
+        assert!(!drain.is_draining());
+        assert!(drain.start());
+        assert!(!drain.start());
+        assert!(drain.is_draining());
+        tokio::time::timeout(Duration::from_secs(5), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+        // Already draining: resolves at once.
+        drain.wait().await;
+    }
+
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    #[tokio::test]
+    async fn test_bind_reuse_port_twice() {
+        let first = bind("127.0.0.1:0", true).await.unwrap();
+        let addr = first.local_addr().unwrap().to_string();
+        let second = bind(&addr, true).await.unwrap();
+        assert_eq!(second.local_addr().unwrap().to_string(), addr);
+        assert!(bind(&addr, false).await.is_err());
+    }
+}
@@ -0,0 +1,372 @@
+//! Supervision of the periodic background tasks.
+//!
+//! A task spawned with [`supervise`] reports a heartbeat on every run through
+//! its [`Heartbeat`], and its last successful run.  A supervisor aborts and
+//! restarts the task when no heartbeat arrives for three of its intervals
+//! plus ten minutes.  The state of every task is kept in a [`TaskRegistry`]
+//! and shown by `/health/detailed`.  [`TaskRegistry::abort_all`] stops every
+//! task, as on shutdown.
+//!
+//! The release profile builds with `panic = "abort"`, so there a panicking
+//! task ends the process.  Only builds that unwind, such as debug builds,
+//! tests and embedders with the default profile, see the panic here and
+//! restart the task as if it were stuck.
+
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::{AbortHandle, JoinHandle};
+// Tokio's clock, so paused time in tests also pauses stall detection.
+use tokio::time::Instant;
+
+/// Intervals without a heartbeat after which a task counts as stuck.
+const STALL_INTERVALS: u32 = 3;
+/// Added to the stall timeout, so runs longer than a short interval, such
+/// as a first backfill, are not taken for stuck.
+const STALL_GRACE: Duration = Duration::from_secs(10 * 60);
+/// Delay before the first restart; doubled for each further restart, up to
+/// [`MAX_RESTART_DELAY`].
+const RESTART_BASE_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
+/// What a supervised task is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskState {
+    Running,
+    /// Died or got stuck, and waits to be restarted.
+    Restarting,
+    /// Ended by itself, e.g. because the server is shutting down.
+    Stopped,
+}
+
+/// Liveness of one background task.
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskStatus {
+    pub state: TaskState,
+    pub interval_secs: u64,
+    /// Start of the task's last run, or of the task itself.
+    pub last_heartbeat: DateTime<Utc>,
+    /// End of the task's last run without errors.
+    pub last_success: Option<DateTime<Utc>>,
+    /// Times the task was restarted since startup.
+    pub restarts: u64,
+    /// Why the task was last restarted.
+    pub last_failure: Option<String>,
+    #[serde(skip)]
+    beat_at: Instant,
+}
+
+/// Shared liveness of the supervised background tasks, by task name.
+#[derive(Clone, Default)]
+pub struct TaskRegistry {
+    tasks: Arc<Mutex<BTreeMap<&'static str, TaskStatus>>>,
+    /// Supervisors and other long-running tasks to stop on
+    /// [`TaskRegistry::abort_all`].
+    handles: Arc<Mutex<Vec<AbortHandle>>>,
+}
+
+impl TaskRegistry {
+    /// The status of every task, by name.
+    pub fn snapshot(&self) -> BTreeMap<&'static str, TaskStatus> {
+        self.tasks.lock().clone()
+    }
+
+    /// Whether any task is down and waiting to be restarted.
+    pub fn any_restarting(&self) -> bool {
+        self.tasks
+            .lock()
+            .values()
+            .any(|task| task.state == TaskState::Restarting)
+    }
+
+    /// Stop every supervised task, and every task passed to
+    /// [`TaskRegistry::track`].  Runs in progress are cancelled at their
+    /// next `.await`; blocking work already handed to a thread finishes.
+    pub fn abort_all(&self) {
+        for handle in self.handles.lock().drain(..) {
+            handle.abort();
+        }
+        for task in self.tasks.lock().values_mut() {
+            task.state = TaskState::Stopped;
+        }
+    }
+
+    /// Stop `task` on [`TaskRegistry::abort_all`], although it is not
+    /// supervised.
+    pub fn track(&self, task: AbortHandle) {
+        self.handles.lock().push(task);
+    }
+
+    fn register(&self, name: &'static str, interval: Duration) {
+        self.tasks.lock().insert(
+            name,
+            TaskStatus {
+                state: TaskState::Running,
+                interval_secs: interval.as_secs(),
+                last_heartbeat: Utc::now(),
+                last_success: None,
+                restarts: 0,
+                last_failure: None,
+                beat_at: Instant::now(),
+            },
+        );
+    }
+
+    fn update(&self, name: &str, f: impl FnOnce(&mut TaskStatus)) {
+        if let Some(task) = self.tasks.lock().get_mut(name) {
+            f(task);
+        }
+    }
+
+    fn beat(&self, name: &str) {
+        self.update(name, |task| {
+            task.last_heartbeat = Utc::now();
+            task.beat_at = Instant::now();
+        });
+    }
+
+    fn since_beat(&self, name: &str) -> Duration {
+        self.tasks
+            .lock()
+            .get(name)
+            .map_or(Duration::ZERO, |task| task.beat_at.elapsed())
+    }
+}
+
+/// A supervised task's handle for reporting liveness.
+#[derive(Clone)]
+pub struct Heartbeat {
+    registry: TaskRegistry,
+    name: &'static str,
+}
+
+impl Heartbeat {
+    /// Report that the task is alive; call at the start of every run.
+    pub fn beat(&self) {
+        self.registry.beat(self.name);
+    }
+
+    /// Report a run that completed without errors.
+    pub fn success(&self) {
+        self.registry.update(self.name, |task| {
+            task.last_success = Some(Utc::now());
+        });
+    }
+}
+
+/// Spawn the task made by `task`, which runs every `interval`, and restart
+/// it whenever it stops reporting heartbeats, or panics in a build that
+/// unwinds.  A task that returns is not restarted.
+pub fn supervise<F, Fut>(registry: &TaskRegistry, name: &'static str, interval: Duration, task: F)
+where
+    F: Fn(Heartbeat) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let stall_after = interval
+        .saturating_mul(STALL_INTERVALS)
+        .saturating_add(STALL_GRACE);
+    registry.register(name, interval);
+    let supervisor = tokio::spawn(run(
+        registry.clone(),
+        name,
+        stall_after,
+        RESTART_BASE_DELAY,
+        task,
+    ));
+    registry.track(supervisor.abort_handle());
+}
+
+/// Aborts the task when dropped, so aborting its supervisor stops it too.
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+async fn run<F, Fut>(
+    registry: TaskRegistry,
+    name: &'static str,
+    stall_after: Duration,
+    restart_delay: Duration,
+    task: F,
+) where
+    F: Fn(Heartbeat) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let check_every = (stall_after / 4).clamp(Duration::from_millis(1), Duration::from_secs(60));
+    let mut restarts: u32 = 0;
+    loop {
+        registry.beat(name);
+        registry.update(name, |status| status.state = TaskState::Running);
+        let mut handle = AbortOnDrop(tokio::spawn(task(Heartbeat {
+            registry: registry.clone(),
+            name,
+        })));
+        let mut check = tokio::time::interval(check_every);
+        let failure = loop {
+            tokio::select! {
+                result = &mut handle.0 => match result {
+                    Ok(()) => {
+                        tracing::info!(task = name, "Background task stopped");
+                        registry.update(name, |status| status.state = TaskState::Stopped);
+                        return;
+                    }
+                    // Only reached in builds that unwind; with `panic =
+                    // "abort"` the process is already gone.
+                    Err(e) if e.is_panic() => break format!("panicked: {}", panic_message(e)),
+                    Err(e) => break e.to_string(),
+                },
+                _ = check.tick() => {
+                    let silent = registry.since_beat(name);
+                    if silent > stall_after {
+                        handle.0.abort();
+                        break format!("no heartbeat for {}s", silent.as_secs());
+                    }
+                }
+            }
+        };
+        let delay = restart_delay
+            .saturating_mul(2u32.saturating_pow(restarts))
+            .min(MAX_RESTART_DELAY);
+        restarts = restarts.saturating_add(1);
+        tracing::error!(
+            task = name,
+            reason = %failure,
+            restart_in_secs = delay.as_secs(),
+            "Background task died; restarting it"
+        );
+        registry.update(name, |status| {
+            status.state = TaskState::Restarting;
+            status.restarts += 1;
+            status.last_failure = Some(failure);
+        });
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// The message a task panicked with.
+fn panic_message(error: tokio::task::JoinError) -> String {
+    let payload = error.into_panic();
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| (*s).to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    // The tests run with Tokio's clock paused, so every wait below passes in
+    // virtual time: the runtime jumps ahead whenever all tasks are idle, and
+    // the outcome does not depend on how loaded the machine is.
+    async fn wait_for(registry: &TaskRegistry, name: &str, f: impl Fn(&TaskStatus) -> bool) {
+        for _ in 0..500 {
+            if registry.snapshot().get(name).is_some_and(&f) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("task {name} never reached the expected state");
+    }
+
+    // Tests unwind; under the release profile's `panic = "abort"` the
+    // process would end instead.
+    #[tokio::test(start_paused = true)]
+    async fn test_restarts_panicked_task() {
+        let registry = TaskRegistry::default();
+        registry.register("flaky", Duration::from_secs(1));
+        let runs = Arc::new(AtomicU32::new(0));
+        let task_runs = Arc::clone(&runs);
+        tokio::spawn(run(
+            registry.clone(),
+            "flaky",
+            Duration::from_secs(60),
+            Duration::from_millis(1),
+            move |heartbeat| {
+                let runs = Arc::clone(&task_runs);
+                async move {
+                    heartbeat.beat();
+                    assert!(runs.fetch_add(1, Ordering::SeqCst) > 0, "first run fails");
+                    heartbeat.success();
+                }
+            },
+        ));
+
+        wait_for(&registry, "flaky", |t| t.state == TaskState::Stopped).await;
+        let status = &registry.snapshot()["flaky"];
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        assert_eq!(status.restarts, 1);
+        assert!(status.last_success.is_some());
+        assert!(status
+            .last_failure
+            .as_deref()
+            .is_some_and(|f| f.starts_with("panicked: first run fails")));
+        assert!(!registry.any_restarting());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_restarts_stuck_task() {
+        let registry = TaskRegistry::default();
+        registry.register("stuck", Duration::from_secs(1));
+        let runs = Arc::new(AtomicU32::new(0));
+        let task_runs = Arc::clone(&runs);
+        tokio::spawn(run(
+            registry.clone(),
+            "stuck",
+            Duration::from_millis(50),
+            Duration::from_millis(1),
+            move |_| {
+                let runs = Arc::clone(&task_runs);
+                async move {
+                    if runs.fetch_add(1, Ordering::SeqCst) == 0 {
+                        std::future::pending::<()>().await;
+                    }
+                }
+            },
+        ));
+
+        wait_for(&registry, "stuck", |t| t.state == TaskState::Stopped).await;
+        let status = &registry.snapshot()["stuck"];
+        assert_eq!(status.restarts, 1);
+        assert!(status
+            .last_failure
+            .as_deref()
+            .is_some_and(|f| f.starts_with("no heartbeat for")));
+        assert!(status.last_success.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_abort_all_stops_tasks() {
+        let registry = TaskRegistry::default();
+        let (ticks, mut ticked) = tokio::sync::mpsc::channel::<()>(1);
+        supervise(&registry, "ticker", Duration::from_millis(1), move |_| {
+            let ticks = ticks.clone();
+            async move {
+                while ticks.send(()).await.is_ok() {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            }
+        });
+        ticked.recv().await.unwrap();
+
+        registry.abort_all();
+        assert_eq!(registry.snapshot()["ticker"].state, TaskState::Stopped);
+        // The channel closes once the task and its supervisor, which holds
+        // the task's factory, are both gone.
+        let drained = tokio::time::timeout(Duration::from_secs(5), async {
+            while ticked.recv().await.is_some() {}
+        })
+        .await;
+        assert!(drained.is_ok(), "task kept running after abort_all");
+    }
+}
@@ -2,10 +2,13 @@ use axum::body::Body;
 use axum::http::{Request, StatusCode};
 use duckdb::Connection;
 use http_body_util::BodyExt;
-use mallard_metrics::api::auth::{ApiKeyStore, SessionStore};
+use mallard_metrics::api::auth::{ApiKeyLimits, ApiKeyStore, SessionStore};
+use mallard_metrics::config::Config;
+use mallard_metrics::embed::MallardServer;
 use mallard_metrics::ingest::buffer::EventBuffer;
 use mallard_metrics::ingest::geoip::GeoIpReader;
 use mallard_metrics::ingest::handler::AppState;
+use mallard_metrics::ingest::queue::{IngestQueue, OverloadPolicy};
 use mallard_metrics::server::build_router;
 use mallard_metrics::storage::parquet::ParquetStorage;
 use mallard_metrics::storage::schema;
@@ -14,13 +17,26 @@ use std::sync::Arc;
 use tower::ServiceExt;
 
 fn make_test_state() -> (Arc<AppState>, tempfile::TempDir) {
+    make_test_state_with_queue(None)
+}
+
+fn make_test_state_with_queue(
+    ingest_queue: Option<IngestQueue>,
+) -> (Arc<AppState>, tempfile::TempDir) {
+    make_test_state_with_buffer(ingest_queue, |buffer| buffer)
+}
+
+fn make_test_state_with_buffer(
+    ingest_queue: Option<IngestQueue>,
+    configure: impl FnOnce(EventBuffer) -> EventBuffer,
+) -> (Arc<AppState>, tempfile::TempDir) {
     let conn = Connection::open_in_memory().unwrap();
     schema::init_schema(&conn).unwrap();
     let dir = tempfile::tempdir().unwrap();
     schema::setup_query_view(&conn, dir.path()).unwrap();
     let storage = ParquetStorage::new(dir.path());
     let conn = Arc::new(Mutex::new(conn));
-    let buffer = EventBuffer::new(1000, conn, storage);
+    let buffer = configure(EventBuffer::new(1000, conn, storage));
     let events_dir = dir.path().to_path_buf();
     let state = Arc::new(AppState {
         buffer,
@@ -31,13 +47,30 @@ fn make_test_state() -> (Arc<AppState>, tempfile::TempDir) {
         sessions: SessionStore::new(3600),
         api_keys: ApiKeyStore::default(),
         admin_password_hash: Mutex::new(None),
+        admin_password_path: None,
+        locale: mallard_metrics::api::locale::Locale::default(),
+        branding: mallard_metrics::config::BrandingConfig::default(),
+        csp: mallard_metrics::config::CspConfig::default(),
+        security_headers: mallard_metrics::config::SecurityHeadersConfig::default(),
+        dashboard_dir: None,
         dashboard_origin: None,
+        dashboard_origins: Vec::new(),
         query_cache: mallard_metrics::query::cache::QueryCache::new(0, 0),
+        cache_warming: std::sync::atomic::AtomicBool::new(false),
+        public_summary_cache: mallard_metrics::query::cache::QueryCache::new(0, 0),
+        slow_queries: Arc::new(mallard_metrics::query::slow::SlowQueryLog::default()),
+        duckdb_usage: mallard_metrics::storage::resources::ResourceMonitor::default(),
+        shadow_queries: mallard_metrics::query::shadow::ShadowQueries::default(),
+        forwarder: None,
+        tasks: mallard_metrics::watchdog::TaskRegistry::default(),
+        drain: mallard_metrics::upgrade::Drain::default(),
         rate_limiter: mallard_metrics::ingest::ratelimit::RateLimiter::new(0),
         login_attempt_tracker: mallard_metrics::api::auth::LoginAttemptTracker::new(0, 300),
         events_ingested_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
         flush_failures_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        flush_consecutive_failures: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
         rate_limit_rejections_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        ingest_stats: mallard_metrics::ingest::stats::IngestStats::new(),
         login_failures_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
         metrics_token: None,
         query_semaphore: std::sync::Arc::new(tokio::sync::Semaphore::new(10)),
@@ -51,6 +84,34 @@ fn make_test_state() -> (Arc<AppState>, tempfile::TempDir) {
         suppress_screen_size: false,
         geoip_precision: "city".to_string(),
         events_dir,
+        sites: std::collections::HashMap::new(),
+        dashboards: mallard_metrics::api::dashboards::DashboardStore::default(),
+        notifications: mallard_metrics::api::notifications::NotificationStore::default(),
+        export_jobs: mallard_metrics::api::export_jobs::ExportJobs::new(
+            dir.path().join("exports"),
+            std::time::Duration::from_secs(3600),
+        ),
+        import_jobs: mallard_metrics::api::import_jobs::ImportJobs::new(
+            dir.path().join("imports"),
+            std::time::Duration::from_secs(3600),
+        ),
+        stats_rate_limiter: mallard_metrics::ingest::ratelimit::RateLimiter::per_minute(0),
+        stats_rate_limit_rejections_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(
+            0,
+        )),
+        debug_ingest: false,
+        transforms: mallard_metrics::ingest::transform::IngestTransforms::default(),
+        ingest_queue,
+        spam_blocklist: Arc::new(mallard_metrics::ingest::spam::SpamBlocklist::default()),
+        geo_blocked_events_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        dedupe: mallard_metrics::ingest::dedupe::Deduplicator::new(std::time::Duration::from_secs(
+            60,
+        )),
+        max_event_time_skew: chrono::TimeDelta::hours(48),
+        idempotency: mallard_metrics::ingest::idempotency::IdempotencyKeys::new(
+            std::time::Duration::from_secs(3600),
+        ),
+        duplicate_events_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
     });
     (state, dir)
 }
@@ -178,6 +239,383 @@ async fn test_ingest_validation_oversized_url() {
     assert_eq!(response.status(), StatusCode::BAD_REQUEST);
 }
 
+#[tokio::test]
+async fn test_ingest_drops_duplicate_event_id() {
+    let (state, _dir) = make_test_state();
+
+    let payload = serde_json::json!({
+        "d": "example.com",
+        "n": "pageview",
+        "u": "https://example.com/",
+        "eid": "b7c1e0c2-5d3a-4f7e-9a61-3f0a2d8e4c19"
+    });
+
+    for _ in 0..2 {
+        let app = build_router(Arc::clone(&state));
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/event")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&payload).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+    }
+
+    assert_eq!(state.buffer.len(), 1);
+    assert_eq!(
+        state
+            .duplicate_events_total
+            .load(std::sync::atomic::Ordering::Relaxed),
+        1
+    );
+}
+
+fn post_event(payload: &serde_json::Value) -> Request<Body> {
+    Request::builder()
+        .method("POST")
+        .uri("/api/event")
+        .header("content-type", "application/json")
+        .header(
+            "user-agent",
+            "Mozilla/5.0 (X11; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0",
+        )
+        .body(Body::from(serde_json::to_string(payload).unwrap()))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_ingest_queue_workers_buffer_events() {
+    let queue = IngestQueue::new(100, OverloadPolicy::Reject);
+    let (state, _dir) = make_test_state_with_queue(Some(queue.clone()));
+    queue.spawn_workers(&state, 2);
+
+    for i in 0..5 {
+        let app = build_router(Arc::clone(&state));
+        let payload = serde_json::json!({
+            "d": "example.com",
+            "n": "pageview",
+            "u": format!("https://example.com/page-{i}"),
+        });
+        let response = app.oneshot(post_event(&payload)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+    }
+
+    queue.wait_idle();
+    assert_eq!(state.buffer.len(), 5);
+    assert_eq!(queue.depth(), 0);
+
+    // The workers parsed the User-Agent.
+    state.buffer.flush().unwrap();
+    let browser: Option<String> = state
+        .buffer
+        .conn()
+        .lock()
+        .query_row("SELECT DISTINCT browser FROM events_all", [], |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert_eq!(browser.as_deref(), Some("Firefox"));
+}
+
+#[tokio::test]
+async fn test_embedded_server_ingests_and_flushes_on_shutdown() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config {
+        data_dir: dir.path().to_path_buf(),
+        ..Config::default()
+    };
+    let server = MallardServer::builder()
+        .config(config)
+        .build()
+        .await
+        .unwrap();
+    assert!(server.state().tasks.snapshot().contains_key("flush"));
+
+    let payload = serde_json::json!({
+        "d": "example.com",
+        "n": "pageview",
+        "u": "https://example.com/",
+    });
+    let response = server.router().oneshot(post_event(&payload)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+    server.shutdown().await;
+    assert!(server.state().drain.is_draining());
+    assert!(server.state().buffer.is_empty());
+    let count: i64 = server
+        .state()
+        .buffer
+        .conn()
+        .lock()
+        .query_row("SELECT COUNT(*) FROM events_all", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 1);
+}
+
+#[tokio::test]
+async fn test_embedded_servers_keep_separate_state() {
+    let (dir_a, dir_b) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+    let mut config = Config {
+        data_dir: dir_a.path().to_path_buf(),
+        ..Config::default()
+    };
+    config.logging.slow_query_ms = 1;
+    config.shadow_queries.reports = vec!["main".to_string()];
+    let a = MallardServer::builder()
+        .config(config)
+        .build()
+        .await
+        .unwrap();
+    let b = MallardServer::builder()
+        .config(Config {
+            data_dir: dir_b.path().to_path_buf(),
+            ..Config::default()
+        })
+        .build()
+        .await
+        .unwrap();
+
+    assert_eq!(a.state().slow_queries.threshold_ms(), 1);
+    assert_eq!(
+        b.state().slow_queries.threshold_ms(),
+        Config::default().logging.slow_query_ms
+    );
+    assert!(a.state().shadow_queries.sample("main").is_some());
+    assert!(b.state().shadow_queries.sample("main").is_none());
+
+    a.shutdown().await;
+    b.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_event_time_override() {
+    let (state, dir) = make_test_state();
+    let app = build_router(Arc::clone(&state));
+    let yesterday = chrono::Utc::now() - chrono::TimeDelta::hours(24);
+
+    let payload = serde_json::json!({
+        "d": "example.com",
+        "n": "pageview",
+        "u": "https://example.com/",
+        "t": yesterday.to_rfc3339(),
+    });
+    let response = app.clone().oneshot(post_event(&payload)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+    let too_old = serde_json::json!({
+        "d": "example.com",
+        "n": "pageview",
+        "u": "https://example.com/",
+        "t": (yesterday - chrono::TimeDelta::days(30)).to_rfc3339(),
+    });
+    let response = app.oneshot(post_event(&too_old)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["errors"][0]["field"], "t");
+
+    state.buffer.flush().unwrap();
+    let stored: String = state
+        .buffer
+        .conn()
+        .lock()
+        .query_row(
+            "SELECT strftime(timestamp, '%Y-%m-%d %H:%M:%S') FROM events_all",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(stored, yesterday.format("%Y-%m-%d %H:%M:%S").to_string());
+    let date = yesterday.format("%Y-%m-%d").to_string();
+    assert!(ParquetStorage::new(dir.path())
+        .partition_dir("example.com", &date)
+        .exists());
+}
+
+#[tokio::test]
+async fn test_idempotency_key_replays_original_result() {
+    let (state, _dir) = make_test_state();
+    let app = build_router(Arc::clone(&state));
+    let payload = serde_json::json!({
+        "d": "example.com",
+        "n": "import",
+        "u": "https://example.com/",
+    });
+    let request = |key: &str| {
+        let mut request = post_event(&payload);
+        request
+            .headers_mut()
+            .insert("idempotency-key", key.parse().unwrap());
+        request
+    };
+
+    let response = app.clone().oneshot(request("import-42")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    assert!(response.headers().get("idempotent-replayed").is_none());
+
+    let response = app.clone().oneshot(request("import-42")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    assert_eq!(response.headers()["idempotent-replayed"], "true");
+    assert_eq!(state.buffer.len(), 1, "a replay must not ingest again");
+
+    let response = app.clone().oneshot(request("import-43")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    assert_eq!(state.buffer.len(), 2);
+
+    let response = app.oneshot(request(&"k".repeat(256))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_ingest_queue_full_returns_503() {
+    // No workers, so the single slot stays taken.
+    let (state, _dir) =
+        make_test_state_with_queue(Some(IngestQueue::new(1, OverloadPolicy::Reject)));
+    let payload = serde_json::json!({
+        "d": "example.com",
+        "n": "pageview",
+        "u": "https://example.com/",
+        "eid": "c4a9d2e1-0b7f-4c3a-8e5d-2f1b6a9c7e30"
+    });
+
+    let app = build_router(Arc::clone(&state));
+    let response = app.oneshot(post_event(&payload)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+    let app = build_router(Arc::clone(&state));
+    let response = app.oneshot(post_event(&payload)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    // The retry above was a duplicate; a new event finds the queue full.
+    let payload = serde_json::json!({
+        "d": "example.com",
+        "n": "pageview",
+        "u": "https://example.com/",
+        "eid": "9e2f4b61-7c0d-4a58-b3e9-1d6c8f2a5b47"
+    });
+    let app = build_router(Arc::clone(&state));
+    let response = app.oneshot(post_event(&payload)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()["retry-after"], "1");
+
+    // Its event ID is forgotten, so the client's retry is not a duplicate.
+    let app = build_router(Arc::clone(&state));
+    let response = app.oneshot(post_event(&payload)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(
+        state
+            .duplicate_events_total
+            .load(std::sync::atomic::Ordering::Relaxed),
+        1
+    );
+    assert_eq!(state.ingest_queue.as_ref().unwrap().rejected_total(), 2);
+}
+
+#[tokio::test]
+async fn test_ingest_queue_sheds_pageviews() {
+    // No workers; pageviews are shed from 9 queued events on.
+    let (state, _dir) =
+        make_test_state_with_queue(Some(IngestQueue::new(10, OverloadPolicy::Shed)));
+    for name in ["pageview"; 10].into_iter().chain(["signup"]) {
+        let payload = serde_json::json!({
+            "d": "example.com",
+            "n": name,
+            "u": "https://example.com/",
+        });
+        let app = build_router(Arc::clone(&state));
+        let response = app.oneshot(post_event(&payload)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+    }
+
+    let queue = state.ingest_queue.as_ref().unwrap();
+    assert_eq!(queue.shed_total(), 1);
+    assert_eq!(queue.depth(), 10);
+    assert_eq!(queue.rejected_total(), 0);
+}
+
+/// Status and body of `GET uri`.
+async fn get_text(state: &Arc<AppState>, uri: &str) -> (StatusCode, String) {
+    let app = build_router(Arc::clone(state));
+    let response = app
+        .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn test_full_event_buffer_returns_503() {
+    let (state, _dir) = make_test_state_with_buffer(None, |buffer| {
+        buffer.with_capacity(2, OverloadPolicy::Reject)
+    });
+    for path in ["/a", "/b", "/c"] {
+        let payload = serde_json::json!({
+            "d": "example.com",
+            "n": "pageview",
+            "u": format!("https://example.com{path}"),
+        });
+        let app = build_router(Arc::clone(&state));
+        let response = app.oneshot(post_event(&payload)).await.unwrap();
+        if path == "/c" {
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(response.headers()["retry-after"], "1");
+        } else {
+            assert_eq!(response.status(), StatusCode::ACCEPTED);
+        }
+    }
+    assert_eq!(state.buffer.len(), 2);
+
+    let (status, body) = get_text(&state, "/health/ready").await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body, "degraded: event buffer full");
+    let (_, metrics) = get_text(&state, "/metrics").await;
+    assert!(metrics.contains("mallard_buffer_dropped_events_total 1"));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_low_disk_space_degrades_health() {
+    use mallard_metrics::storage::resources::DiskGuard;
+
+    // No file system has u64::MAX bytes free.
+    let disk = Arc::new(DiskGuard::new(std::env::temp_dir(), u64::MAX));
+    let (state, _dir) =
+        make_test_state_with_buffer(None, |buffer| buffer.with_disk_guard(Arc::clone(&disk)));
+    assert!(disk.check().is_err());
+
+    let (status, body) = get_text(&state, "/health/ready").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "degraded: low disk space");
+
+    let (_, detailed) = get_text(&state, "/health/detailed").await;
+    let json: serde_json::Value = serde_json::from_str(&detailed).unwrap();
+    assert_eq!(json["status"], "degraded");
+    assert_eq!(json["disk_low"], true);
+    assert!(json["disk_free_bytes"].is_u64());
+
+    let (_, metrics) = get_text(&state, "/metrics").await;
+    assert!(metrics.contains("mallard_disk_low 1"));
+    assert!(metrics.contains("mallard_disk_free_bytes "));
+
+    // Events are still accepted and held until space is freed.
+    let payload = serde_json::json!({
+        "d": "example.com",
+        "n": "pageview",
+        "u": "https://example.com/",
+    });
+    let app = build_router(Arc::clone(&state));
+    let response = app.oneshot(post_event(&payload)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    assert!(state.buffer.flush().is_err());
+    assert_eq!(state.buffer.len(), 1);
+}
+
 #[tokio::test]
 async fn test_stats_after_ingest() {
     let (state, _dir) = make_test_state();
@@ -259,6 +697,93 @@ async fn test_breakdown_after_ingest() {
     assert_eq!(rows.len(), 2);
 }
 
+#[tokio::test]
+async fn test_breakdown_compare_previous_period() {
+    let (state, _dir) = make_test_state();
+    {
+        // 30d covers today and the 29 days before it; the previous period
+        // is the 30 days before that.
+        let conn = state.buffer.conn().lock();
+        conn.execute(
+            "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname, browser)
+             VALUES ('test.com', 'v1', CURRENT_TIMESTAMP, 'pageview', '/', 'Chrome'),
+                    ('test.com', 'v2', CURRENT_TIMESTAMP, 'pageview', '/', 'Chrome'),
+                    ('test.com', 'v3', CURRENT_TIMESTAMP, 'pageview', '/', 'Firefox'),
+                    ('test.com', 'p1', CAST(CURRENT_TIMESTAMP AS TIMESTAMP) - INTERVAL 40 DAY, 'pageview', '/', 'Chrome'),
+                    ('test.com', 'p2', CAST(CURRENT_TIMESTAMP AS TIMESTAMP) - INTERVAL 40 DAY, 'pageview', '/', 'Safari'),
+                    ('test.com', 'old', CAST(CURRENT_TIMESTAMP AS TIMESTAMP) - INTERVAL 90 DAY, 'pageview', '/', 'Firefox')",
+            [],
+        )
+        .unwrap();
+    }
+
+    let response = build_router(Arc::clone(&state))
+        .oneshot(
+            Request::builder()
+                .uri("/api/stats/breakdown/browsers?site_id=test.com&period=30d&compare=true")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let rows: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+    // Only values of the current period are listed.
+    assert_eq!(rows.len(), 2);
+    let chrome = rows.iter().find(|r| r["value"] == "Chrome").unwrap();
+    assert_eq!(chrome["visitors"], 2);
+    assert_eq!(chrome["previous_visitors"], 1);
+    assert_eq!(chrome["change"], 100.0);
+    // Visits before the previous period do not count.
+    let firefox = rows.iter().find(|r| r["value"] == "Firefox").unwrap();
+    assert_eq!(firefox["previous_visitors"], 0);
+    assert!(firefox["change"].is_null());
+}
+
+#[tokio::test]
+async fn test_breakdown_other_row_and_totals() {
+    let (state, _dir) = make_test_state();
+    {
+        let conn = state.buffer.conn().lock();
+        conn.execute(
+            "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname, browser)
+             VALUES ('test.com', 'v1', CURRENT_TIMESTAMP, 'pageview', '/', 'Chrome'),
+                    ('test.com', 'v2', CURRENT_TIMESTAMP, 'pageview', '/', 'Chrome'),
+                    ('test.com', 'v3', CURRENT_TIMESTAMP, 'pageview', '/', 'Firefox'),
+                    ('test.com', 'v4', CURRENT_TIMESTAMP, 'pageview', '/', 'Safari')",
+            [],
+        )
+        .unwrap();
+    }
+
+    // Values beyond the limit are folded into "(other)", and the totals
+    // cover every value.
+    let response = build_router(Arc::clone(&state))
+        .oneshot(
+            Request::builder()
+                .uri(
+                    "/api/stats/breakdown/browsers?site_id=test.com&period=30d&limit=1&others=true",
+                )
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let coverage: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let rows = coverage["rows"].as_array().unwrap();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0]["value"], "Chrome");
+    assert_eq!(rows[0]["pageviews"], 2);
+    assert_eq!(rows[1]["value"], "(other)");
+    assert_eq!(rows[1]["pageviews"], 2);
+    assert_eq!(rows[1]["visitors"], 2);
+    assert_eq!(coverage["total"]["pageviews"], 4);
+    assert_eq!(coverage["total"]["values"], 3);
+}
+
 #[tokio::test]
 #[allow(clippy::significant_drop_tightening)]
 async fn test_ua_parsing_populates_browser_os_fields() {
@@ -316,42 +841,88 @@ async fn test_ua_parsing_populates_browser_os_fields() {
 }
 
 #[tokio::test]
-#[allow(clippy::significant_drop_tightening)]
-async fn test_ua_parsing_firefox_on_linux() {
-    let (state, dir) = make_test_state();
+async fn test_device_models_breakdown_after_ingest() {
+    let (state, _dir) = make_test_state();
     let app = build_router(Arc::clone(&state));
 
     let payload = serde_json::json!({
-        "d": "ua-test2.com",
+        "d": "test.com",
         "n": "pageview",
-        "u": "https://ua-test2.com/",
+        "u": "https://test.com/",
     });
-
-    let firefox_ua = "Mozilla/5.0 (X11; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0";
+    let pixel_ua = "Mozilla/5.0 (Linux; Android 14; Pixel 8) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.6099.144 Mobile Safari/537.36";
     let response = app
+        .clone()
         .oneshot(
             Request::builder()
                 .method("POST")
                 .uri("/api/event")
                 .header("content-type", "application/json")
-                .header("user-agent", firefox_ua)
+                .header("user-agent", pixel_ua)
                 .body(Body::from(serde_json::to_string(&payload).unwrap()))
                 .unwrap(),
         )
         .await
         .unwrap();
-
     assert_eq!(response.status(), StatusCode::ACCEPTED);
-
     state.buffer.flush().unwrap();
 
-    let (browser, os) = {
-        let conn = state.buffer.conn().lock();
-        let glob = format!(
-            "{}/site_id=ua-test2.com/date=*/**.parquet",
-            dir.path().display()
-        );
-        let sql = format!("SELECT browser, os FROM read_parquet('{glob}')");
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/api/stats/breakdown/devices_models?site_id=test.com&period=30d")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let rows: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0]["brand"], "Google");
+    assert_eq!(rows[0]["value"], "Pixel 8");
+    assert_eq!(rows[0]["visitors"], 1);
+}
+
+#[tokio::test]
+#[allow(clippy::significant_drop_tightening)]
+async fn test_ua_parsing_firefox_on_linux() {
+    let (state, dir) = make_test_state();
+    let app = build_router(Arc::clone(&state));
+
+    let payload = serde_json::json!({
+        "d": "ua-test2.com",
+        "n": "pageview",
+        "u": "https://ua-test2.com/",
+    });
+
+    let firefox_ua = "Mozilla/5.0 (X11; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0";
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/api/event")
+                .header("content-type", "application/json")
+                .header("user-agent", firefox_ua)
+                .body(Body::from(serde_json::to_string(&payload).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+    state.buffer.flush().unwrap();
+
+    let (browser, os) = {
+        let conn = state.buffer.conn().lock();
+        let glob = format!(
+            "{}/site_id=ua-test2.com/date=*/**.parquet",
+            dir.path().display()
+        );
+        let sql = format!("SELECT browser, os FROM read_parquet('{glob}')");
         let mut stmt = conn.prepare(&sql).unwrap();
         let mut rows = stmt.query([]).unwrap();
         let row = rows.next().unwrap().unwrap();
@@ -382,13 +953,30 @@ fn make_test_state_with_sites(sites: Vec<String>) -> (Arc<AppState>, tempfile::T
         sessions: SessionStore::new(3600),
         api_keys: ApiKeyStore::default(),
         admin_password_hash: Mutex::new(None),
+        admin_password_path: None,
+        locale: mallard_metrics::api::locale::Locale::default(),
+        branding: mallard_metrics::config::BrandingConfig::default(),
+        csp: mallard_metrics::config::CspConfig::default(),
+        security_headers: mallard_metrics::config::SecurityHeadersConfig::default(),
+        dashboard_dir: None,
         dashboard_origin: None,
+        dashboard_origins: Vec::new(),
         query_cache: mallard_metrics::query::cache::QueryCache::new(0, 0),
+        cache_warming: std::sync::atomic::AtomicBool::new(false),
+        public_summary_cache: mallard_metrics::query::cache::QueryCache::new(0, 0),
+        slow_queries: Arc::new(mallard_metrics::query::slow::SlowQueryLog::default()),
+        duckdb_usage: mallard_metrics::storage::resources::ResourceMonitor::default(),
+        shadow_queries: mallard_metrics::query::shadow::ShadowQueries::default(),
+        forwarder: None,
+        tasks: mallard_metrics::watchdog::TaskRegistry::default(),
+        drain: mallard_metrics::upgrade::Drain::default(),
         rate_limiter: mallard_metrics::ingest::ratelimit::RateLimiter::new(0),
         login_attempt_tracker: mallard_metrics::api::auth::LoginAttemptTracker::new(0, 300),
         events_ingested_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
         flush_failures_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        flush_consecutive_failures: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
         rate_limit_rejections_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        ingest_stats: mallard_metrics::ingest::stats::IngestStats::new(),
         login_failures_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
         metrics_token: None,
         query_semaphore: std::sync::Arc::new(tokio::sync::Semaphore::new(10)),
@@ -402,6 +990,34 @@ fn make_test_state_with_sites(sites: Vec<String>) -> (Arc<AppState>, tempfile::T
         suppress_screen_size: false,
         geoip_precision: "city".to_string(),
         events_dir,
+        sites: std::collections::HashMap::new(),
+        dashboards: mallard_metrics::api::dashboards::DashboardStore::default(),
+        notifications: mallard_metrics::api::notifications::NotificationStore::default(),
+        export_jobs: mallard_metrics::api::export_jobs::ExportJobs::new(
+            dir.path().join("exports"),
+            std::time::Duration::from_secs(3600),
+        ),
+        import_jobs: mallard_metrics::api::import_jobs::ImportJobs::new(
+            dir.path().join("imports"),
+            std::time::Duration::from_secs(3600),
+        ),
+        stats_rate_limiter: mallard_metrics::ingest::ratelimit::RateLimiter::per_minute(0),
+        stats_rate_limit_rejections_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(
+            0,
+        )),
+        debug_ingest: false,
+        transforms: mallard_metrics::ingest::transform::IngestTransforms::default(),
+        ingest_queue: None,
+        spam_blocklist: Arc::new(mallard_metrics::ingest::spam::SpamBlocklist::default()),
+        geo_blocked_events_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        dedupe: mallard_metrics::ingest::dedupe::Deduplicator::new(std::time::Duration::from_secs(
+            60,
+        )),
+        max_event_time_skew: chrono::TimeDelta::hours(48),
+        idempotency: mallard_metrics::ingest::idempotency::IdempotencyKeys::new(
+            std::time::Duration::from_secs(3600),
+        ),
+        duplicate_events_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
     });
     (state, dir)
 }
@@ -598,6 +1214,91 @@ async fn test_funnel_endpoint_rejects_invalid_window() {
     assert_eq!(response.status(), StatusCode::BAD_REQUEST);
 }
 
+#[tokio::test]
+async fn test_funnel_endpoint_rejects_invalid_breakdown() {
+    let (state, _dir) = make_test_state();
+    let app = build_router(Arc::clone(&state));
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/api/stats/funnel?site_id=test.com&period=30d&steps=page%3A%2F&breakdown=pathname")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_sources_breakdown_rejects_invalid_attribution() {
+    let (state, _dir) = make_test_state();
+    let app = build_router(Arc::clone(&state));
+
+    for uri in [
+        "/api/stats/breakdown/sources?site_id=test.com&period=30d&attribution=any_touch",
+        "/api/stats/breakdown/campaigns?site_id=test.com&period=30d&goal=signup",
+    ] {
+        let response = app
+            .clone()
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{uri}");
+    }
+}
+
+#[tokio::test]
+async fn test_breakdown_goal_filter() {
+    let (state, _dir) = make_test_state();
+    {
+        let conn = state.buffer.conn().lock();
+        for (visitor, event, path) in [
+            ("v1", "pageview", "/pricing"),
+            ("v1", "signup", "/signup"),
+            ("v2", "pageview", "/blog"),
+        ] {
+            conn.execute(
+                "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+                 VALUES ('test.com', ?, CURRENT_TIMESTAMP, ?, ?)",
+                duckdb::params![visitor, event, path],
+            )
+            .unwrap();
+        }
+    }
+    let app = build_router(Arc::clone(&state));
+
+    // Only the pages of the visitor who signed up.
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/api/stats/breakdown/pages?site_id=test.com&period=30d&goal=event%3Asignup")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let rows: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+    let pages: Vec<&str> = rows.iter().map(|r| r["value"].as_str().unwrap()).collect();
+    assert_eq!(pages, ["/pricing", "/signup"]);
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/api/stats/breakdown/pages?site_id=test.com&period=30d&goal=signup")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
 #[tokio::test]
 async fn test_retention_endpoint_returns_ok() {
     let (state, _dir) = make_test_state();
@@ -634,6 +1335,82 @@ async fn test_retention_endpoint_rejects_invalid_weeks() {
     assert_eq!(response.status(), StatusCode::BAD_REQUEST);
 }
 
+#[tokio::test]
+async fn test_retention_endpoint_monthly_cohorts() {
+    let (state, _dir) = make_test_state();
+    let app = build_router(Arc::clone(&state));
+
+    let ok = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/api/stats/retention?site_id=test.com&period=90d&cohort=month&periods=3")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(ok.status(), StatusCode::OK);
+
+    let too_many = app
+        .oneshot(
+            Request::builder()
+                .uri("/api/stats/retention?site_id=test.com&period=90d&cohort=month&periods=25")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(too_many.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_user_identity() {
+    let (state, _dir) = make_test_state();
+    let app = build_router(Arc::clone(&state));
+
+    let event = |uid: &str| {
+        let payload = serde_json::json!({
+            "d": "test.com",
+            "n": "pageview",
+            "u": "https://test.com/",
+            "uid": uid
+        });
+        Request::builder()
+            .method("POST")
+            .uri("/api/event")
+            .header("content-type", "application/json")
+            .body(Body::from(payload.to_string()))
+            .unwrap()
+    };
+    let response = app.clone().oneshot(event("user-42")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let response = app.clone().oneshot(event("")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(state.buffer.len(), 1);
+
+    for (identity, expected) in [
+        ("user", StatusCode::OK),
+        ("visitor", StatusCode::OK),
+        ("stitched", StatusCode::OK),
+        ("device", StatusCode::BAD_REQUEST),
+    ] {
+        for uri in [
+            format!("/api/stats/retention?site_id=test.com&period=30d&identity={identity}"),
+            format!(
+                "/api/stats/funnel?site_id=test.com&period=30d&steps=page:/,page:/pricing&identity={identity}"
+            ),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(Request::builder().uri(&uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected, "{uri}");
+        }
+    }
+}
+
 #[tokio::test]
 async fn test_sequences_endpoint_returns_ok() {
     let (state, _dir) = make_test_state();
@@ -711,60 +1488,15 @@ async fn test_flow_endpoint_rejects_empty_page() {
     assert_eq!(response.status(), StatusCode::BAD_REQUEST);
 }
 
-// --- Phase 4.2: Authentication integration tests ---
-
-fn make_test_state_with_password(password: &str) -> (Arc<AppState>, tempfile::TempDir) {
-    let conn = Connection::open_in_memory().unwrap();
-    schema::init_schema(&conn).unwrap();
-    let dir = tempfile::tempdir().unwrap();
-    schema::setup_query_view(&conn, dir.path()).unwrap();
-    let storage = ParquetStorage::new(dir.path());
-    let conn = Arc::new(Mutex::new(conn));
-    let buffer = EventBuffer::new(1000, conn, storage);
-    let hash = mallard_metrics::api::auth::hash_password(password).unwrap();
-    let events_dir = dir.path().to_path_buf();
-    let state = Arc::new(AppState {
-        buffer,
-        secret: "test-secret-integration".to_string(),
-        allowed_sites: Vec::new(),
-        geoip: GeoIpReader::open(None),
-        filter_bots: false,
-        sessions: SessionStore::new(3600),
-        api_keys: ApiKeyStore::default(),
-        admin_password_hash: Mutex::new(Some(hash)),
-        dashboard_origin: None,
-        query_cache: mallard_metrics::query::cache::QueryCache::new(0, 0),
-        rate_limiter: mallard_metrics::ingest::ratelimit::RateLimiter::new(0),
-        login_attempt_tracker: mallard_metrics::api::auth::LoginAttemptTracker::new(0, 300),
-        events_ingested_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
-        flush_failures_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
-        rate_limit_rejections_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
-        login_failures_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
-        metrics_token: None,
-        query_semaphore: std::sync::Arc::new(tokio::sync::Semaphore::new(10)),
-        secure_cookies: false,
-        behavioral_extension_loaded: false,
-        strip_referrer_query: false,
-        round_timestamps: false,
-        suppress_visitor_id: false,
-        suppress_browser_version: false,
-        suppress_os_version: false,
-        suppress_screen_size: false,
-        geoip_precision: "city".to_string(),
-        events_dir,
-    });
-    (state, dir)
-}
-
 #[tokio::test]
-async fn test_auth_status_no_password_configured() {
+async fn test_paths_endpoint_returns_ok() {
     let (state, _dir) = make_test_state();
-    let app = build_router(state);
+    let app = build_router(Arc::clone(&state));
 
     let response = app
         .oneshot(
             Request::builder()
-                .uri("/api/auth/status")
+                .uri("/api/stats/paths?site_id=test.com&period=30d&depth=4&page=/")
                 .body(Body::empty())
                 .unwrap(),
         )
@@ -772,405 +1504,596 @@ async fn test_auth_status_no_password_configured() {
         .unwrap();
 
     assert_eq!(response.status(), StatusCode::OK);
-    let body = response.into_body().collect().await.unwrap().to_bytes();
-    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
-    assert_eq!(json["setup_required"], true);
-    assert_eq!(json["authenticated"], true); // Open access when no password
 }
 
 #[tokio::test]
-async fn test_auth_setup_creates_password() {
+async fn test_paths_endpoint_rejects_bad_depth() {
     let (state, _dir) = make_test_state();
     let app = build_router(Arc::clone(&state));
 
-    let payload = serde_json::json!({ "password": "secure-password-123" });
     let response = app
         .oneshot(
             Request::builder()
-                .method("POST")
-                .uri("/api/auth/setup")
-                .header("content-type", "application/json")
-                .body(Body::from(serde_json::to_string(&payload).unwrap()))
+                .uri("/api/stats/paths?site_id=test.com&period=30d&depth=9")
+                .body(Body::empty())
                 .unwrap(),
         )
         .await
         .unwrap();
 
-    assert_eq!(response.status(), StatusCode::OK);
-
-    // Verify Set-Cookie header is present
-    let cookie = response
-        .headers()
-        .get("set-cookie")
-        .unwrap()
-        .to_str()
-        .unwrap();
-    assert!(cookie.contains("mm_session="));
-    assert!(cookie.contains("HttpOnly"));
-    assert!(cookie.contains("SameSite=Strict"));
-
-    // Verify password is now configured
-    assert!(state.admin_password_hash.lock().is_some());
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
 }
 
 #[tokio::test]
-async fn test_auth_setup_rejects_short_password() {
+async fn test_anomalies_endpoint_returns_ok() {
     let (state, _dir) = make_test_state();
-    let app = build_router(state);
+    let app = build_router(Arc::clone(&state));
 
-    let payload = serde_json::json!({ "password": "short" });
     let response = app
         .oneshot(
             Request::builder()
-                .method("POST")
-                .uri("/api/auth/setup")
-                .header("content-type", "application/json")
-                .body(Body::from(serde_json::to_string(&payload).unwrap()))
+                .uri("/api/stats/anomalies?site_id=test.com&period=7d")
+                .body(Body::empty())
                 .unwrap(),
         )
         .await
         .unwrap();
 
-    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json, serde_json::json!([]));
 }
 
 #[tokio::test]
-async fn test_auth_setup_rejects_second_setup() {
-    let (state, _dir) = make_test_state_with_password("existing-password");
-    let app = build_router(state);
+async fn test_sql_query_endpoint() {
+    let (state, _dir) = make_test_state();
+    let app = build_router(Arc::clone(&state));
 
-    let payload = serde_json::json!({ "password": "new-password-123" });
     let response = app
+        .clone()
         .oneshot(
             Request::builder()
                 .method("POST")
-                .uri("/api/auth/setup")
+                .uri("/api/query")
                 .header("content-type", "application/json")
-                .body(Body::from(serde_json::to_string(&payload).unwrap()))
+                .body(Body::from(
+                    r#"{"sql": "SELECT COUNT(*) AS n FROM events_all"}"#,
+                ))
                 .unwrap(),
         )
         .await
         .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["columns"], serde_json::json!(["n"]));
+    assert_eq!(json["rows"], serde_json::json!([[0]]));
 
-    assert_eq!(response.status(), StatusCode::CONFLICT);
-}
-
-#[tokio::test]
-async fn test_auth_login_success() {
-    let (state, _dir) = make_test_state_with_password("my-secure-password");
-    let app = build_router(state);
-
-    let payload = serde_json::json!({ "password": "my-secure-password" });
     let response = app
         .oneshot(
             Request::builder()
                 .method("POST")
-                .uri("/api/auth/login")
+                .uri("/api/query")
                 .header("content-type", "application/json")
-                .body(Body::from(serde_json::to_string(&payload).unwrap()))
+                .body(Body::from(r#"{"sql": "DROP TABLE events"}"#))
                 .unwrap(),
         )
         .await
         .unwrap();
-
-    assert_eq!(response.status(), StatusCode::OK);
-    let cookie = response
-        .headers()
-        .get("set-cookie")
-        .unwrap()
-        .to_str()
-        .unwrap();
-    assert!(cookie.contains("mm_session="));
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
 }
 
 #[tokio::test]
-async fn test_auth_login_wrong_password() {
-    let (state, _dir) = make_test_state_with_password("correct-password");
-    let app = build_router(state);
+async fn test_admin_partitions_endpoint() {
+    let (state, _dir) = make_test_state();
+    let app = build_router(Arc::clone(&state));
 
-    let payload = serde_json::json!({ "password": "wrong-password" });
+    let payload = serde_json::json!({
+        "d": "partitions-test.com",
+        "n": "pageview",
+        "u": "https://partitions-test.com/"
+    });
     let response = app
+        .clone()
         .oneshot(
             Request::builder()
                 .method("POST")
-                .uri("/api/auth/login")
+                .uri("/api/event")
                 .header("content-type", "application/json")
+                .header("user-agent", "Mozilla/5.0 Chrome/120.0")
+                .header("x-forwarded-for", "1.2.3.4")
                 .body(Body::from(serde_json::to_string(&payload).unwrap()))
                 .unwrap(),
         )
         .await
         .unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    state.buffer.flush().unwrap();
 
-    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
-}
-
-#[tokio::test]
-async fn test_auth_middleware_blocks_unauthenticated_stats() {
-    let (state, _dir) = make_test_state_with_password("admin-password");
-    let app = build_router(state);
-
-    // Stats route without session cookie should return 401
     let response = app
+        .clone()
         .oneshot(
             Request::builder()
-                .uri("/api/stats/main?site_id=test.com&period=30d")
+                .uri("/api/admin/partitions?site_id=partitions-test.com")
                 .body(Body::empty())
                 .unwrap(),
         )
         .await
         .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let files = json["files"].as_array().unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0]["site_id"], "partitions-test.com");
+    assert_eq!(files[0]["row_count"], 1);
+    assert_eq!(files[0]["schema_version"], json["schema_version"]);
 
-    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
-}
-
-#[tokio::test]
-async fn test_auth_middleware_allows_authenticated_stats() {
-    let (state, _dir) = make_test_state_with_password("admin-password");
-
-    // Create a session directly
-    let token = state.sessions.create_session("admin");
-
-    let app = build_router(Arc::clone(&state));
     let response = app
         .oneshot(
             Request::builder()
-                .uri("/api/stats/main?site_id=test.com&period=30d")
-                .header("cookie", format!("mm_session={token}"))
+                .uri("/api/admin/verify")
                 .body(Body::empty())
                 .unwrap(),
         )
         .await
         .unwrap();
-
     assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["files_checked"], 1);
+    assert_eq!(json["rows_checked"], 1);
+    assert_eq!(json["problems"], serde_json::json!([]));
 }
 
 #[tokio::test]
-async fn test_auth_middleware_allows_api_key() {
-    let (state, _dir) = make_test_state_with_password("admin-password");
-
-    // Add an API key
-    let key = mallard_metrics::api::auth::generate_api_key();
-    state.api_keys.add_key(
-        "test",
-        &key,
-        mallard_metrics::api::auth::ApiKeyScope::ReadOnly,
-    );
-
+async fn test_admin_slow_queries_endpoint() {
+    let (mut state, _dir) = make_test_state();
+    Arc::get_mut(&mut state).unwrap().slow_queries =
+        Arc::new(mallard_metrics::query::slow::SlowQueryLog::new(1, 10_000));
     let app = build_router(Arc::clone(&state));
+
     let response = app
+        .clone()
         .oneshot(
             Request::builder()
-                .uri("/api/stats/main?site_id=test.com&period=30d")
-                .header("authorization", format!("Bearer {key}"))
-                .body(Body::empty())
+                .method("POST")
+                .uri("/api/query")
+                .header("content-type", "application/json")
+                .body(Body::from(
+                    r#"{"sql": "SELECT SUM(i) FROM range(20000000) t(i) WHERE i % 7 = 3"}"#,
+                ))
                 .unwrap(),
         )
         .await
         .unwrap();
-
     assert_eq!(response.status(), StatusCode::OK);
-}
-
-#[tokio::test]
-async fn test_auth_middleware_allows_event_ingestion_without_auth() {
-    let (state, _dir) = make_test_state_with_password("admin-password");
-    let app = build_router(state);
-
-    // Event ingestion should work even when auth is configured
-    let payload = serde_json::json!({
-        "d": "example.com",
-        "n": "pageview",
-        "u": "https://example.com/",
-    });
 
     let response = app
         .oneshot(
             Request::builder()
-                .method("POST")
-                .uri("/api/event")
-                .header("content-type", "application/json")
-                .body(Body::from(serde_json::to_string(&payload).unwrap()))
+                .uri("/api/admin/slow-queries")
+                .body(Body::empty())
                 .unwrap(),
         )
         .await
         .unwrap();
-
-    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["threshold_ms"], 1);
+    let slow = json["queries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|q| q["sql"] == "SELECT SUM(i) FROM range(?) t(i) WHERE i % ? = ?")
+        .expect("ad-hoc query logged as slow");
+    assert_eq!(slow["site_id"], "__all__");
+    assert!(slow["duration_ms"].as_u64().unwrap() >= 1);
 }
 
 #[tokio::test]
-async fn test_auth_logout() {
-    let (state, _dir) = make_test_state_with_password("admin-password");
-    let token = state.sessions.create_session("admin");
-
+async fn test_shadow_queries_compare_reports() {
+    let (mut state, _dir) = make_test_state();
+    Arc::get_mut(&mut state).unwrap().shadow_queries =
+        mallard_metrics::query::shadow::ShadowQueries::new(
+            vec!["main".to_string()],
+            mallard_metrics::query::shadow::Variant::LiveSessions,
+            1,
+        );
+    {
+        let conn = state.buffer.conn().lock();
+        conn.execute(
+            "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname, country_code)
+             VALUES ('test.com', 'v1', CURRENT_TIMESTAMP, 'pageview', '/', 'DE')",
+            [],
+        )
+        .unwrap();
+    }
+    state.buffer.flush().unwrap();
     let app = build_router(Arc::clone(&state));
+
     let response = app
+        .clone()
         .oneshot(
             Request::builder()
-                .method("POST")
-                .uri("/api/auth/logout")
-                .header("cookie", format!("mm_session={token}"))
+                .uri("/api/stats/main?site_id=test.com&period=30d")
                 .body(Body::empty())
                 .unwrap(),
         )
         .await
         .unwrap();
-
     assert_eq!(response.status(), StatusCode::OK);
 
-    // Session should be invalidated
-    assert!(state.sessions.validate_session(&token).is_none());
+    // The comparison runs in the background after the response.
+    let mut report = serde_json::Value::Null;
+    for _ in 0..100 {
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/admin/shadow-queries")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        report = json["reports"]["main"].clone();
+        if !report.is_null() {
+            assert_eq!(json["variant"], "live_sessions");
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(report["runs"], 1);
+    assert_eq!(report["mismatches"], 0);
+    assert_eq!(report["errors"], 0);
 }
 
 #[tokio::test]
-async fn test_auth_status_with_password_configured() {
-    let (state, _dir) = make_test_state_with_password("admin-password");
-    let token = state.sessions.create_session("admin");
-
+async fn test_admin_ingest_stats_endpoint() {
+    let (state, _dir) = make_test_state();
     let app = build_router(Arc::clone(&state));
+
+    let valid = r#"{"d": "stats-test.com", "n": "pageview", "u": "https://stats-test.com/"}"#;
+    let invalid = r#"{"d": "stats-test.com", "n": "pageview", "u": ""}"#;
+    for (body, expected) in [
+        (valid, StatusCode::ACCEPTED),
+        (valid, StatusCode::ACCEPTED),
+        (invalid, StatusCode::BAD_REQUEST),
+    ] {
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/event")
+                    .header("content-type", "application/json")
+                    .header("content-length", body.len())
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), expected);
+    }
+
     let response = app
         .oneshot(
             Request::builder()
-                .uri("/api/auth/status")
-                .header("cookie", format!("mm_session={token}"))
+                .uri("/api/admin/ingest-stats")
                 .body(Body::empty())
                 .unwrap(),
         )
         .await
         .unwrap();
-
     assert_eq!(response.status(), StatusCode::OK);
     let body = response.into_body().collect().await.unwrap().to_bytes();
     let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
-    assert_eq!(json["setup_required"], false);
-    assert_eq!(json["authenticated"], true);
+    assert!(json["since"].is_string());
+    let site = &json["sites"][0];
+    assert_eq!(site["site_id"], "stats-test.com");
+    assert_eq!(site["accepted"], 2);
+    assert_eq!(site["rejected"], 1);
+    assert_eq!(site["rate_limited"], 0);
+    assert_eq!(site["bot_filtered"], 0);
+    assert_eq!(site["bytes"], 2 * valid.len() + invalid.len());
 }
 
-// --- Phase 4.4: API Key Management integration tests ---
+#[tokio::test]
+async fn test_admin_explain_endpoint() {
+    let (state, _dir) = make_test_state();
+    let app = build_router(Arc::clone(&state));
+
+    for analyze in [false, true] {
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri(format!(
+                        "/api/admin/explain?endpoint=breakdown/pages&site_id=test.com&period=7d&limit=5&analyze={analyze}"
+                    ))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["endpoint"], "breakdown/pages");
+        assert_eq!(json["analyze"], analyze);
+        let queries = json["queries"].as_array().unwrap();
+        assert_eq!(queries.len(), 1);
+        assert!(queries[0]["sql"].as_str().unwrap().contains("pathname"));
+        assert!(!queries[0]["plan"].as_str().unwrap().is_empty());
+    }
+
+    // The endpoint's own validation applies.
+    for uri in [
+        "/api/admin/explain?endpoint=funnel&site_id=test.com",
+        "/api/admin/explain?endpoint=main&site_id=bad%20site",
+        "/api/admin/explain?endpoint=breakdown/pages&site_id=test.com&limit=100000",
+    ] {
+        let response = app
+            .clone()
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{uri}");
+    }
+}
 
 #[tokio::test]
-async fn test_create_api_key() {
-    let (state, _dir) = make_test_state_with_password("admin-password");
-    let token = state.sessions.create_session("admin");
+async fn test_ndjson_export_streams_filtered_events() {
+    let (state, _dir) = make_test_state();
+    let insert = |from: i64, to: i64| {
+        let conn = state.buffer.conn().lock();
+        // Pairs of events share a timestamp, so chunk boundaries fall inside
+        // runs of equal timestamps.
+        conn.execute(
+            "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname, country_code)
+             SELECT 'test.com', 'v' || i,
+                    date_trunc('day', CAST(CURRENT_TIMESTAMP AS TIMESTAMP)) + to_seconds(i // 2),
+                    CASE WHEN i % 10 = 0 THEN 'signup' ELSE 'pageview' END,
+                    '/',
+                    CASE WHEN i % 2 = 0 THEN 'DE' ELSE 'US' END
+             FROM range(?, ?) t(i)",
+            duckdb::params![from, to],
+        )
+        .unwrap();
+    };
+    // Half the events in Parquet, half still buffered.
+    insert(0, 1200);
+    state.buffer.flush().unwrap();
+    insert(1200, 2500);
+    let app = build_router(Arc::clone(&state));
+
+    let get = |uri: &str| {
+        let app = app.clone();
+        let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        async move {
+            let response = app.oneshot(request).await.unwrap();
+            let status = response.status();
+            let content_type = response
+                .headers()
+                .get("content-type")
+                .map(|v| v.to_str().unwrap().to_string());
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (
+                status,
+                content_type,
+                String::from_utf8(body.to_vec()).unwrap(),
+            )
+        }
+    };
 
+    let (status, content_type, text) = get("/api/export/ndjson?site_id=test.com&period=7d").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(content_type.as_deref(), Some("application/x-ndjson"));
+    let events: Vec<serde_json::Value> = text
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(events.len(), 2500);
+    let visitors: std::collections::HashSet<_> =
+        events.iter().map(|e| e["visitor_id"].clone()).collect();
+    assert_eq!(visitors.len(), 2500);
+    assert!(events
+        .windows(2)
+        .all(|w| w[0]["timestamp"].as_str() <= w[1]["timestamp"].as_str()));
+
+    let (status, _, text) =
+        get("/api/export/ndjson?site_id=test.com&period=7d&event_name=signup&country=de").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(text.lines().count(), 250);
+    assert!(text.lines().all(|l| l.contains(r#""country_code":"DE""#)));
+
+    let (status, _, _) = get("/api/export/ndjson?site_id=test.com&country=Germany").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_dashboards_crud() {
+    let (state, _dir) = make_test_state();
     let app = build_router(Arc::clone(&state));
-    let payload = serde_json::json!({ "name": "test-key", "scope": "ReadOnly" });
+
     let response = app
+        .clone()
         .oneshot(
             Request::builder()
                 .method("POST")
-                .uri("/api/keys")
+                .uri("/api/dashboards")
                 .header("content-type", "application/json")
-                .header("cookie", format!("mm_session={token}"))
-                .body(Body::from(serde_json::to_string(&payload).unwrap()))
+                .body(Body::from(
+                    r#"{"name": "Marketing", "widgets": [{"metric": "visitors", "site_id": "example.com", "period": "30d", "breakdown": "sources"}]}"#,
+                ))
                 .unwrap(),
         )
         .await
         .unwrap();
-
     assert_eq!(response.status(), StatusCode::CREATED);
     let body = response.into_body().collect().await.unwrap().to_bytes();
-    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
-    assert!(json["key"].as_str().unwrap().starts_with("mm_"));
-    assert_eq!(json["name"], "test-key");
-    assert_eq!(json["scope"], "ReadOnly");
-}
-
-#[tokio::test]
-async fn test_list_api_keys() {
-    let (state, _dir) = make_test_state_with_password("admin-password");
-    let token = state.sessions.create_session("admin");
+    let created: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let id = created["id"].as_str().unwrap().to_string();
 
-    // Create a key
-    let key = mallard_metrics::api::auth::generate_api_key();
-    state.api_keys.add_key(
-        "my-key",
-        &key,
-        mallard_metrics::api::auth::ApiKeyScope::Admin,
-    );
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method("PUT")
+                .uri(format!("/api/dashboards/{id}"))
+                .header("content-type", "application/json")
+                .body(Body::from(r#"{"name": "Marketing view", "widgets": []}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
 
-    let app = build_router(Arc::clone(&state));
     let response = app
+        .clone()
         .oneshot(
             Request::builder()
-                .uri("/api/keys")
-                .header("cookie", format!("mm_session={token}"))
+                .uri("/api/dashboards")
                 .body(Body::empty())
                 .unwrap(),
         )
         .await
         .unwrap();
-
-    assert_eq!(response.status(), StatusCode::OK);
     let body = response.into_body().collect().await.unwrap().to_bytes();
-    let json: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
-    assert_eq!(json.len(), 1);
-    assert_eq!(json[0]["name"], "my-key");
-    assert_eq!(json[0]["scope"], "Admin");
-}
-
-#[tokio::test]
-async fn test_revoke_api_key() {
-    let (state, _dir) = make_test_state_with_password("admin-password");
-    let token = state.sessions.create_session("admin");
-
-    let key = mallard_metrics::api::auth::generate_api_key();
-    let key_hash = state.api_keys.add_key(
-        "revoke-me",
-        &key,
-        mallard_metrics::api::auth::ApiKeyScope::ReadOnly,
-    );
+    let list: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(list[0]["name"], "Marketing view");
+    assert_eq!(list[0]["widgets"], serde_json::json!([]));
 
-    let app = build_router(Arc::clone(&state));
     let response = app
+        .clone()
         .oneshot(
             Request::builder()
                 .method("DELETE")
-                .uri(format!("/api/keys/{key_hash}"))
-                .header("cookie", format!("mm_session={token}"))
+                .uri(format!("/api/dashboards/{id}"))
                 .body(Body::empty())
                 .unwrap(),
         )
         .await
         .unwrap();
-
     assert_eq!(response.status(), StatusCode::OK);
 
-    // Key should now be revoked
-    assert!(state.api_keys.validate_key(&key).is_none());
-}
-
-#[tokio::test]
-async fn test_api_key_endpoints_require_auth() {
-    let (state, _dir) = make_test_state_with_password("admin-password");
-    let app = build_router(state);
-
-    // Unauthenticated list should fail
     let response = app
         .oneshot(
             Request::builder()
-                .uri("/api/keys")
+                .uri(format!("/api/dashboards/{id}"))
                 .body(Body::empty())
                 .unwrap(),
         )
         .await
         .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
 
-    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
-}
+// --- Phase 4.2: Authentication integration tests ---
 
-// --- Phase 5: Operational Excellence integration tests ---
+fn make_test_state_with_password(password: &str) -> (Arc<AppState>, tempfile::TempDir) {
+    let conn = Connection::open_in_memory().unwrap();
+    schema::init_schema(&conn).unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    schema::setup_query_view(&conn, dir.path()).unwrap();
+    let storage = ParquetStorage::new(dir.path());
+    let conn = Arc::new(Mutex::new(conn));
+    let buffer = EventBuffer::new(1000, conn, storage);
+    let hash = mallard_metrics::api::auth::hash_password(password).unwrap();
+    let events_dir = dir.path().to_path_buf();
+    let state = Arc::new(AppState {
+        buffer,
+        secret: "test-secret-integration".to_string(),
+        allowed_sites: Vec::new(),
+        geoip: GeoIpReader::open(None),
+        filter_bots: false,
+        sessions: SessionStore::new(3600),
+        api_keys: ApiKeyStore::default(),
+        admin_password_hash: Mutex::new(Some(hash)),
+        admin_password_path: None,
+        locale: mallard_metrics::api::locale::Locale::default(),
+        branding: mallard_metrics::config::BrandingConfig::default(),
+        csp: mallard_metrics::config::CspConfig::default(),
+        security_headers: mallard_metrics::config::SecurityHeadersConfig::default(),
+        dashboard_dir: None,
+        dashboard_origin: None,
+        dashboard_origins: Vec::new(),
+        query_cache: mallard_metrics::query::cache::QueryCache::new(0, 0),
+        cache_warming: std::sync::atomic::AtomicBool::new(false),
+        public_summary_cache: mallard_metrics::query::cache::QueryCache::new(0, 0),
+        slow_queries: Arc::new(mallard_metrics::query::slow::SlowQueryLog::default()),
+        duckdb_usage: mallard_metrics::storage::resources::ResourceMonitor::default(),
+        shadow_queries: mallard_metrics::query::shadow::ShadowQueries::default(),
+        forwarder: None,
+        tasks: mallard_metrics::watchdog::TaskRegistry::default(),
+        drain: mallard_metrics::upgrade::Drain::default(),
+        rate_limiter: mallard_metrics::ingest::ratelimit::RateLimiter::new(0),
+        login_attempt_tracker: mallard_metrics::api::auth::LoginAttemptTracker::new(0, 300),
+        events_ingested_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        flush_failures_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        flush_consecutive_failures: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        rate_limit_rejections_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        ingest_stats: mallard_metrics::ingest::stats::IngestStats::new(),
+        login_failures_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        metrics_token: None,
+        query_semaphore: std::sync::Arc::new(tokio::sync::Semaphore::new(10)),
+        secure_cookies: false,
+        behavioral_extension_loaded: false,
+        strip_referrer_query: false,
+        round_timestamps: false,
+        suppress_visitor_id: false,
+        suppress_browser_version: false,
+        suppress_os_version: false,
+        suppress_screen_size: false,
+        geoip_precision: "city".to_string(),
+        events_dir,
+        sites: std::collections::HashMap::new(),
+        dashboards: mallard_metrics::api::dashboards::DashboardStore::default(),
+        notifications: mallard_metrics::api::notifications::NotificationStore::default(),
+        export_jobs: mallard_metrics::api::export_jobs::ExportJobs::new(
+            dir.path().join("exports"),
+            std::time::Duration::from_secs(3600),
+        ),
+        import_jobs: mallard_metrics::api::import_jobs::ImportJobs::new(
+            dir.path().join("imports"),
+            std::time::Duration::from_secs(3600),
+        ),
+        stats_rate_limiter: mallard_metrics::ingest::ratelimit::RateLimiter::per_minute(0),
+        stats_rate_limit_rejections_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(
+            0,
+        )),
+        debug_ingest: false,
+        transforms: mallard_metrics::ingest::transform::IngestTransforms::default(),
+        ingest_queue: None,
+        spam_blocklist: Arc::new(mallard_metrics::ingest::spam::SpamBlocklist::default()),
+        geo_blocked_events_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        dedupe: mallard_metrics::ingest::dedupe::Deduplicator::new(std::time::Duration::from_secs(
+            60,
+        )),
+        max_event_time_skew: chrono::TimeDelta::hours(48),
+        idempotency: mallard_metrics::ingest::idempotency::IdempotencyKeys::new(
+            std::time::Duration::from_secs(3600),
+        ),
+        duplicate_events_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+    });
+    (state, dir)
+}
 
 #[tokio::test]
-async fn test_detailed_health_check_endpoint() {
+async fn test_auth_status_no_password_configured() {
     let (state, _dir) = make_test_state();
     let app = build_router(state);
 
     let response = app
         .oneshot(
             Request::builder()
-                .uri("/health/detailed")
+                .uri("/api/auth/status")
                 .body(Body::empty())
                 .unwrap(),
         )
@@ -1180,159 +2103,168 @@ async fn test_detailed_health_check_endpoint() {
     assert_eq!(response.status(), StatusCode::OK);
     let body = response.into_body().collect().await.unwrap().to_bytes();
     let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
-    assert_eq!(json["status"], "ok");
-    assert!(json.get("version").is_some());
-    assert_eq!(json["buffered_events"], 0);
-    assert_eq!(json["auth_configured"], false);
-    assert_eq!(json["geoip_loaded"], false);
-    assert_eq!(json["filter_bots"], false);
+    assert_eq!(json["setup_required"], true);
+    assert_eq!(json["authenticated"], true); // Open access when no password
 }
 
 #[tokio::test]
-async fn test_export_csv_format() {
+async fn test_auth_setup_creates_password() {
     let (state, _dir) = make_test_state();
-    let app = build_router(state);
+    let app = build_router(Arc::clone(&state));
 
+    let payload = serde_json::json!({ "password": "secure-password-123" });
     let response = app
         .oneshot(
             Request::builder()
-                .uri("/api/stats/export?site_id=test.com&period=7d&format=csv")
-                .body(Body::empty())
+                .method("POST")
+                .uri("/api/auth/setup")
+                .header("content-type", "application/json")
+                .body(Body::from(serde_json::to_string(&payload).unwrap()))
                 .unwrap(),
         )
         .await
         .unwrap();
 
     assert_eq!(response.status(), StatusCode::OK);
-    let content_type = response
+
+    // Verify Set-Cookie header is present
+    let cookie = response
         .headers()
-        .get("content-type")
+        .get("set-cookie")
         .unwrap()
         .to_str()
         .unwrap();
-    assert_eq!(content_type, "text/csv");
-    let body = response.into_body().collect().await.unwrap().to_bytes();
-    let text = std::str::from_utf8(&body).unwrap();
-    assert!(text.starts_with("date,visitors,pageviews,top_page,top_source"));
+    assert!(cookie.contains("mm_session="));
+    assert!(cookie.contains("HttpOnly"));
+    assert!(cookie.contains("SameSite=Strict"));
+
+    // Verify password is now configured
+    assert!(state.admin_password_hash.lock().is_some());
 }
 
 #[tokio::test]
-async fn test_export_json_format() {
+async fn test_auth_setup_rejects_short_password() {
     let (state, _dir) = make_test_state();
     let app = build_router(state);
 
+    let payload = serde_json::json!({ "password": "short" });
     let response = app
         .oneshot(
             Request::builder()
-                .uri("/api/stats/export?site_id=test.com&period=7d&format=json")
-                .body(Body::empty())
+                .method("POST")
+                .uri("/api/auth/setup")
+                .header("content-type", "application/json")
+                .body(Body::from(serde_json::to_string(&payload).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_auth_setup_rejects_second_setup() {
+    let (state, _dir) = make_test_state_with_password("existing-password");
+    let app = build_router(state);
+
+    let payload = serde_json::json!({ "password": "new-password-123" });
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/api/auth/setup")
+                .header("content-type", "application/json")
+                .body(Body::from(serde_json::to_string(&payload).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn test_auth_login_success() {
+    let (state, _dir) = make_test_state_with_password("my-secure-password");
+    let app = build_router(state);
+
+    let payload = serde_json::json!({ "password": "my-secure-password" });
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/api/auth/login")
+                .header("content-type", "application/json")
+                .body(Body::from(serde_json::to_string(&payload).unwrap()))
                 .unwrap(),
         )
         .await
         .unwrap();
 
     assert_eq!(response.status(), StatusCode::OK);
-    let content_type = response
+    let cookie = response
         .headers()
-        .get("content-type")
+        .get("set-cookie")
         .unwrap()
         .to_str()
         .unwrap();
-    assert_eq!(content_type, "application/json");
-    let body = response.into_body().collect().await.unwrap().to_bytes();
-    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
-    assert!(json.is_array());
+    assert!(cookie.contains("mm_session="));
 }
 
 #[tokio::test]
-async fn test_rate_limiting() {
-    // Create state with rate limit of 2 per second
-    let conn = Connection::open_in_memory().unwrap();
-    schema::init_schema(&conn).unwrap();
-    let dir = tempfile::tempdir().unwrap();
-    let storage = ParquetStorage::new(dir.path());
-    let conn = Arc::new(Mutex::new(conn));
-    let buffer = EventBuffer::new(1000, conn, storage);
-    let state = Arc::new(AppState {
-        buffer,
-        secret: "test-secret".to_string(),
-        allowed_sites: Vec::new(),
-        geoip: GeoIpReader::open(None),
-        filter_bots: false,
-        sessions: SessionStore::new(3600),
-        api_keys: ApiKeyStore::default(),
-        admin_password_hash: Mutex::new(None),
-        dashboard_origin: None,
-        query_cache: mallard_metrics::query::cache::QueryCache::new(0, 0),
-        rate_limiter: mallard_metrics::ingest::ratelimit::RateLimiter::new(2),
-        login_attempt_tracker: mallard_metrics::api::auth::LoginAttemptTracker::new(0, 300),
-        events_ingested_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
-        flush_failures_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
-        rate_limit_rejections_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
-        login_failures_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
-        metrics_token: None,
-        query_semaphore: std::sync::Arc::new(tokio::sync::Semaphore::new(10)),
-        secure_cookies: false,
-        behavioral_extension_loaded: false,
-        strip_referrer_query: false,
-        round_timestamps: false,
-        suppress_visitor_id: false,
-        suppress_browser_version: false,
-        suppress_os_version: false,
-        suppress_screen_size: false,
-        geoip_precision: "city".to_string(),
-        events_dir: dir.path().to_path_buf(),
-    });
-
-    let payload = serde_json::json!({
-        "d": "example.com",
-        "n": "pageview",
-        "u": "https://example.com/",
-    });
-    let body_str = serde_json::to_string(&payload).unwrap();
-
-    // First two requests should succeed
-    for _ in 0..2 {
-        let app = build_router(Arc::clone(&state));
-        let response = app
-            .oneshot(
-                Request::builder()
-                    .method("POST")
-                    .uri("/api/event")
-                    .header("content-type", "application/json")
-                    .body(Body::from(body_str.clone()))
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
-        assert_eq!(response.status(), StatusCode::ACCEPTED);
-    }
+async fn test_auth_login_wrong_password() {
+    let (state, _dir) = make_test_state_with_password("correct-password");
+    let app = build_router(state);
 
-    // Third request should be rate limited
-    let app = build_router(Arc::clone(&state));
+    let payload = serde_json::json!({ "password": "wrong-password" });
     let response = app
         .oneshot(
             Request::builder()
                 .method("POST")
-                .uri("/api/event")
+                .uri("/api/auth/login")
                 .header("content-type", "application/json")
-                .body(Body::from(body_str))
+                .body(Body::from(serde_json::to_string(&payload).unwrap()))
                 .unwrap(),
         )
         .await
         .unwrap();
-    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
 }
 
 #[tokio::test]
-async fn test_detailed_health_check_with_auth() {
-    let (state, _dir) = make_test_state_with_password("admin-pass");
+async fn test_auth_middleware_blocks_unauthenticated_stats() {
+    let (state, _dir) = make_test_state_with_password("admin-password");
     let app = build_router(state);
 
+    // Stats route without session cookie should return 401
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/api/stats/main?site_id=test.com&period=30d")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_auth_middleware_allows_authenticated_stats() {
+    let (state, _dir) = make_test_state_with_password("admin-password");
+
+    // Create a session directly
+    let token = state.sessions.create_session("admin", "127.0.0.1", None);
+
+    let app = build_router(Arc::clone(&state));
     let response = app
         .oneshot(
             Request::builder()
-                .uri("/health/detailed")
+                .uri("/api/stats/main?site_id=test.com&period=30d")
+                .header("cookie", format!("mm_session={token}"))
                 .body(Body::empty())
                 .unwrap(),
         )
@@ -1340,15 +2272,955 @@ async fn test_detailed_health_check_with_auth() {
         .unwrap();
 
     assert_eq!(response.status(), StatusCode::OK);
-    let body = response.into_body().collect().await.unwrap().to_bytes();
-    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
-    assert_eq!(json["auth_configured"], true);
 }
 
-// --- New integration tests for production-readiness gaps ---
+#[tokio::test]
+async fn test_auth_middleware_allows_api_key() {
+    let (state, _dir) = make_test_state_with_password("admin-password");
 
-/// Make a state with password AND brute-force protection enabled (max 3 attempts, 300s lockout).
-fn make_test_state_with_lockout(password: &str) -> (Arc<AppState>, tempfile::TempDir) {
+    // Add an API key
+    let key = mallard_metrics::api::auth::generate_api_key();
+    state.api_keys.add_key(
+        "test",
+        &key,
+        mallard_metrics::api::auth::ApiKeyScope::ReadOnly,
+        ApiKeyLimits::default(),
+        None,
+    );
+
+    let app = build_router(Arc::clone(&state));
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/api/stats/main?site_id=test.com&period=30d")
+                .header("authorization", format!("Bearer {key}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_auth_middleware_allows_event_ingestion_without_auth() {
+    let (state, _dir) = make_test_state_with_password("admin-password");
+    let app = build_router(state);
+
+    // Event ingestion should work even when auth is configured
+    let payload = serde_json::json!({
+        "d": "example.com",
+        "n": "pageview",
+        "u": "https://example.com/",
+    });
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/api/event")
+                .header("content-type", "application/json")
+                .body(Body::from(serde_json::to_string(&payload).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+}
+
+#[tokio::test]
+async fn test_auth_logout() {
+    let (state, _dir) = make_test_state_with_password("admin-password");
+    let token = state.sessions.create_session("admin", "127.0.0.1", None);
+
+    let app = build_router(Arc::clone(&state));
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/api/auth/logout")
+                .header("cookie", format!("mm_session={token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Session should be invalidated
+    assert!(state.sessions.validate_session(&token).is_none());
+}
+
+#[tokio::test]
+async fn test_auth_status_with_password_configured() {
+    let (state, _dir) = make_test_state_with_password("admin-password");
+    let token = state.sessions.create_session("admin", "127.0.0.1", None);
+
+    let app = build_router(Arc::clone(&state));
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/api/auth/status")
+                .header("cookie", format!("mm_session={token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["setup_required"], false);
+    assert_eq!(json["authenticated"], true);
+}
+
+// --- Phase 4.4: API Key Management integration tests ---
+
+#[tokio::test]
+async fn test_create_api_key() {
+    let (state, _dir) = make_test_state_with_password("admin-password");
+    let token = state.sessions.create_session("admin", "127.0.0.1", None);
+
+    let app = build_router(Arc::clone(&state));
+    let payload = serde_json::json!({ "name": "test-key", "scope": "ReadOnly" });
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/api/keys")
+                .header("content-type", "application/json")
+                .header("cookie", format!("mm_session={token}"))
+                .body(Body::from(serde_json::to_string(&payload).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(json["key"].as_str().unwrap().starts_with("mm_"));
+    assert_eq!(json["name"], "test-key");
+    assert_eq!(json["scope"], "ReadOnly");
+}
+
+#[tokio::test]
+async fn test_list_api_keys() {
+    let (state, _dir) = make_test_state_with_password("admin-password");
+    let token = state.sessions.create_session("admin", "127.0.0.1", None);
+
+    // Create a key
+    let key = mallard_metrics::api::auth::generate_api_key();
+    state.api_keys.add_key(
+        "my-key",
+        &key,
+        mallard_metrics::api::auth::ApiKeyScope::Admin,
+        ApiKeyLimits::default(),
+        None,
+    );
+
+    let app = build_router(Arc::clone(&state));
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/api/keys")
+                .header("cookie", format!("mm_session={token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let json: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json.len(), 1);
+    assert_eq!(json[0]["name"], "my-key");
+    assert_eq!(json[0]["scope"], "Admin");
+}
+
+#[tokio::test]
+async fn test_revoke_api_key() {
+    let (state, _dir) = make_test_state_with_password("admin-password");
+    let token = state.sessions.create_session("admin", "127.0.0.1", None);
+
+    let key = mallard_metrics::api::auth::generate_api_key();
+    let key_hash = state.api_keys.add_key(
+        "revoke-me",
+        &key,
+        mallard_metrics::api::auth::ApiKeyScope::ReadOnly,
+        ApiKeyLimits::default(),
+        None,
+    );
+
+    let app = build_router(Arc::clone(&state));
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("DELETE")
+                .uri(format!("/api/keys/{key_hash}"))
+                .header("cookie", format!("mm_session={token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Key should now be revoked
+    assert!(state.api_keys.validate_key(&key).is_none());
+}
+
+#[tokio::test]
+async fn test_list_and_revoke_sessions() {
+    let (state, _dir) = make_test_state_with_password("admin-password");
+    let token = state
+        .sessions
+        .create_session("admin", "203.0.113.7", Some("Firefox"));
+    state.sessions.create_session("admin", "198.51.100.1", None);
+    state.sessions.create_session("admin", "198.51.100.2", None);
+
+    let app = build_router(Arc::clone(&state));
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/api/auth/sessions")
+                .header("cookie", format!("mm_session={token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let json: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json.len(), 3);
+    let current: Vec<_> = json.iter().filter(|s| s["current"] == true).collect();
+    assert_eq!(current.len(), 1);
+    assert_eq!(current[0]["ip_prefix"], "203.0.113.x");
+    assert_eq!(current[0]["user_agent"], "Firefox");
+    let other_id = json.iter().find(|s| s["current"] == false).unwrap()["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let revoke = |id: &str| {
+        Request::builder()
+            .method("DELETE")
+            .uri(format!("/api/auth/sessions/{id}"))
+            .header("cookie", format!("mm_session={token}"))
+            .body(Body::empty())
+            .unwrap()
+    };
+    let response = app.clone().oneshot(revoke(&other_id)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app.clone().oneshot(revoke(&other_id)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = app.oneshot(revoke("others")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["revoked"], 1);
+
+    let sessions = state.sessions.list_sessions(Some(&token));
+    assert_eq!(sessions.len(), 1);
+    assert!(sessions[0].current);
+}
+
+#[tokio::test]
+async fn test_revoke_other_sessions_requires_session() {
+    let (state, _dir) = make_test_state_with_password("admin-password");
+    state.sessions.create_session("admin", "198.51.100.1", None);
+    state.sessions.create_session("admin", "198.51.100.2", None);
+    let key = mallard_metrics::api::auth::generate_api_key();
+    state.api_keys.add_key(
+        "admin",
+        &key,
+        mallard_metrics::api::auth::ApiKeyScope::Admin,
+        ApiKeyLimits::default(),
+        None,
+    );
+
+    let app = build_router(Arc::clone(&state));
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("DELETE")
+                .uri("/api/auth/sessions/others")
+                .header("authorization", format!("Bearer {key}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(state.sessions.list_sessions(None).len(), 2);
+}
+
+#[tokio::test]
+async fn test_api_key_endpoints_require_auth() {
+    let (state, _dir) = make_test_state_with_password("admin-password");
+    let app = build_router(state);
+
+    // Unauthenticated list should fail
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/api/keys")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+// --- Phase 5: Operational Excellence integration tests ---
+
+#[tokio::test]
+async fn test_detailed_health_check_endpoint() {
+    let (state, _dir) = make_test_state();
+    let app = build_router(state);
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/health/detailed")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["status"], "ok");
+    assert!(json.get("version").is_some());
+    assert_eq!(json["buffered_events"], 0);
+    assert_eq!(json["auth_configured"], false);
+    assert_eq!(json["geoip_loaded"], false);
+    assert_eq!(json["filter_bots"], false);
+}
+
+#[tokio::test]
+async fn test_duckdb_limits_applied_and_reported() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config {
+        data_dir: dir.path().to_path_buf(),
+        duckdb_memory_limit: Some("256MB".to_string()),
+        duckdb_threads: 2,
+        ..Config::default()
+    };
+    let server = MallardServer::builder()
+        .config(config)
+        .build()
+        .await
+        .unwrap();
+
+    // The configured limits reach the connection.
+    let (memory_limit, threads) = server
+        .state()
+        .buffer
+        .conn()
+        .lock()
+        .query_row(
+            "SELECT current_setting('memory_limit'), current_setting('threads')",
+            [],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)),
+        )
+        .unwrap();
+    assert_eq!(threads, 2);
+    // DuckDB reports 256MB (decimal) in binary units.
+    assert_eq!(memory_limit, "244.1 MiB");
+
+    // And the detailed health check reports them.
+    let response = server
+        .router()
+        .oneshot(
+            Request::builder()
+                .uri("/health/detailed")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["duckdb"]["threads"], 2);
+    assert_eq!(json["duckdb"]["memory_limit"], memory_limit);
+    assert!(json["duckdb"]["memory_usage_bytes"].is_u64());
+    assert!(json["duckdb"]["temp_directory"]
+        .as_str()
+        .unwrap()
+        .starts_with(&dir.path().display().to_string()));
+
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_export_csv_format() {
+    let (state, _dir) = make_test_state();
+    let app = build_router(state);
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/api/stats/export?site_id=test.com&period=7d&format=csv")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let content_type = response
+        .headers()
+        .get("content-type")
+        .unwrap()
+        .to_str()
+        .unwrap();
+    assert_eq!(content_type, "text/csv");
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let text = std::str::from_utf8(&body).unwrap();
+    assert!(text.starts_with("date,visitors,pageviews,top_page,top_source"));
+}
+
+#[tokio::test]
+async fn test_export_json_format() {
+    let (state, _dir) = make_test_state();
+    let app = build_router(state);
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/api/stats/export?site_id=test.com&period=7d&format=json")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let content_type = response
+        .headers()
+        .get("content-type")
+        .unwrap()
+        .to_str()
+        .unwrap();
+    assert_eq!(content_type, "application/json");
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(json.is_array());
+}
+
+#[tokio::test]
+async fn test_export_breakdown_types() {
+    let (state, _dir) = make_test_state();
+    {
+        let conn = state.buffer.conn().lock();
+        for (vid, name, path) in [
+            ("v1", "pageview", "/"),
+            ("v2", "pageview", "/"),
+            ("v2", "pageview", "/pricing"),
+            ("v2", "signup", "/pricing"),
+        ] {
+            conn.execute(
+                "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+                 VALUES ('test.com', ?, date_trunc('day', CAST(CURRENT_TIMESTAMP AS TIMESTAMP)), ?, ?)",
+                duckdb::params![vid, name, path],
+            )
+            .unwrap();
+        }
+    }
+    let app = build_router(state);
+
+    let get = |uri: &str| {
+        let app = app.clone();
+        let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        async move {
+            let response = app.oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        }
+    };
+
+    let (status, text) = get("/api/stats/export?site_id=test.com&period=7d&type=pages").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        text,
+        "page,visitors,pageviews\n\"/\",2,2\n\"/pricing\",1,1\n"
+    );
+
+    let (status, text) =
+        get("/api/stats/export?site_id=test.com&period=7d&type=pages&limit=1&format=json").await;
+    assert_eq!(status, StatusCode::OK);
+    let json: serde_json::Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(json.as_array().unwrap().len(), 1);
+    assert_eq!(json[0]["value"], "/");
+
+    let (status, text) = get("/api/stats/export?site_id=test.com&period=7d&type=events").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(text, "event,visitors,events\n\"signup\",1,1\n");
+
+    let (status, _) = get("/api/stats/export?site_id=test.com&period=7d&type=cities").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = get("/api/stats/export?site_id=test.com&period=7d&limit=5000").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_export_csv_localized() {
+    let (state, _dir) = make_test_state();
+    {
+        let conn = state.buffer.conn().lock();
+        conn.execute(
+            "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+             VALUES ('test.com', 'v1', date_trunc('day', CAST(CURRENT_TIMESTAMP AS TIMESTAMP)), 'pageview', '/')",
+            [],
+        )
+        .unwrap();
+    }
+    let app = build_router(state);
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/api/stats/export?site_id=test.com&period=7d&format=csv")
+                .header("accept-language", "de-DE,de;q=0.9,en;q=0.8")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let text = std::str::from_utf8(&body).unwrap();
+    let mut lines = text.lines();
+    assert_eq!(
+        lines.next(),
+        Some("Datum;Besucher;Seitenaufrufe;Top-Seite;Top-Quelle")
+    );
+    let today = chrono::Utc::now()
+        .date_naive()
+        .format("%d.%m.%Y")
+        .to_string();
+    assert!(lines.any(|l| l.starts_with(&format!("{today};1;1;"))));
+
+    // An explicit locale parameter wins over Accept-Language.
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/api/stats/export?site_id=test.com&period=7d&format=csv&locale=en")
+                .header("accept-language", "de")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let text = std::str::from_utf8(&body).unwrap();
+    assert!(text.starts_with("date,visitors,pageviews,top_page,top_source\n"));
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/api/meta/locale")
+                .header("accept-language", "fr-CA, en;q=0.5")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["locale"], "fr");
+    assert_eq!(json["default_locale"], "en");
+    assert_eq!(json["date_format"], "%d/%m/%Y");
+    assert_eq!(json["csv_delimiter"], ";");
+}
+
+#[tokio::test]
+async fn test_report_downloads() {
+    let (state, _dir) = make_test_state();
+    {
+        let conn = state.buffer.conn().lock();
+        for (vid, name, path) in [
+            ("v1", "pageview", "/"),
+            ("v2", "pageview", "/"),
+            ("v2", "pageview", "/pricing"),
+            ("v2", "signup", "/pricing"),
+        ] {
+            conn.execute(
+                "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+                 VALUES ('test.com', ?, date_trunc('day', CAST(CURRENT_TIMESTAMP AS TIMESTAMP)), ?, ?)",
+                duckdb::params![vid, name, path],
+            )
+            .unwrap();
+        }
+    }
+    let app = build_router(state);
+
+    let get = |uri: &str| {
+        let app = app.clone();
+        let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        async move {
+            let response = app.oneshot(request).await.unwrap();
+            let status = response.status();
+            let disposition = response
+                .headers()
+                .get("content-disposition")
+                .map(|v| v.to_str().unwrap().to_string());
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (
+                status,
+                disposition,
+                String::from_utf8(body.to_vec()).unwrap(),
+            )
+        }
+    };
+
+    let (status, disposition, text) =
+        get("/api/stats/breakdown/pages?site_id=test.com&period=7d&format=csv").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        disposition.as_deref(),
+        Some("attachment; filename=\"breakdown.csv\"")
+    );
+    assert_eq!(
+        text,
+        "value,visitors,pageviews\n\"/\",2,2\n\"/pricing\",1,1\n"
+    );
+
+    let (status, disposition, text) = get(
+        "/api/stats/funnel?site_id=test.com&period=7d&steps=page%3A%2F%2Cevent%3Asignup&format=csv",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        disposition.as_deref(),
+        Some("attachment; filename=\"funnel.csv\"")
+    );
+    assert!(text.starts_with("step,visitors,drop_off_pct,median_secs_from_previous\n"));
+
+    let (status, _, text) =
+        get("/api/stats/retention?site_id=test.com&period=30d&weeks=2&format=csv").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(
+        text.starts_with("cohort_date,cohort_size,period_0,period_0_pct,period_1,period_1_pct\n")
+    );
+
+    let (status, disposition, text) =
+        get("/api/stats/retention?site_id=test.com&period=30d&format=json").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        disposition.as_deref(),
+        Some("attachment; filename=\"retention.json\"")
+    );
+    assert!(serde_json::from_str::<serde_json::Value>(&text)
+        .unwrap()
+        .is_array());
+
+    let (status, _, _) =
+        get("/api/stats/breakdown/pages?site_id=test.com&period=7d&format=xlsx").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _, _) = get(
+        "/api/stats/breakdown/sources?site_id=test.com&period=7d&attribution=linear&format=csv",
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_export_jobs() {
+    let (state, _dir) = make_test_state();
+    {
+        let conn = state.buffer.conn().lock();
+        conn.execute(
+            "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+             VALUES ('test.com', 'v1', '2024-03-10 10:00:00', 'pageview', '/')",
+            [],
+        )
+        .unwrap();
+    }
+    let app = build_router(state);
+
+    let send = |method: &str, uri: &str| {
+        let app = app.clone();
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+        async move {
+            let response = app.oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        }
+    };
+
+    // Two years: more than GET /api/stats/export allows.
+    let (status, text) = send(
+        "POST",
+        "/api/exports?site_id=test.com&start_date=2023-01-01&end_date=2025-01-01&type=pages",
+    )
+    .await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let job: serde_json::Value = serde_json::from_str(&text).unwrap();
+    let id = job["id"].as_str().unwrap().to_string();
+    assert_eq!(job["type"], "pages");
+
+    let mut ready = serde_json::Value::Null;
+    for _ in 0..100 {
+        let (status, text) = send("GET", &format!("/api/exports/{id}?site_id=test.com")).await;
+        assert_eq!(status, StatusCode::OK);
+        ready = serde_json::from_str(&text).unwrap();
+        if ready["status"] == "ready" || ready["status"] == "failed" {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(ready["status"], "ready", "{ready}");
+    assert!(ready["expires_at"].is_string());
+
+    let (status, text) = send("GET", ready["download_url"].as_str().unwrap()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(text, "page,visitors,pageviews\n\"/\",1,1\n");
+
+    // A job is only found with its own site.
+    let (status, _) = send("GET", &format!("/api/exports/{id}?site_id=other.com")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = send("GET", "/api/exports/missing?site_id=test.com").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, _) = send(
+        "POST",
+        "/api/exports?site_id=test.com&start_date=2015-01-01&end_date=2025-01-01",
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = send("POST", "/api/exports?site_id=test.com&type=cities").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+/// Poll import job `id` until it is no longer `status`, and return it.
+async fn wait_for_import(app: &axum::Router, id: &str, status: &str) -> serde_json::Value {
+    let mut job = serde_json::Value::Null;
+    for _ in 0..100 {
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/api/imports/{id}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        job = serde_json::from_slice(&body).unwrap();
+        if job["status"] != status {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    job
+}
+
+#[tokio::test]
+async fn test_import_jobs() {
+    let (state, _dir) = make_test_state();
+    let app = build_router(Arc::clone(&state));
+
+    let call = |request: Request<Body>| {
+        let app = app.clone();
+        async move {
+            let response = app.oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (
+                status,
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            )
+        }
+    };
+    let upload = |filename: &str, contents: &str| {
+        let body = format!(
+            "--BOUNDARY\r\n\
+             Content-Disposition: form-data; name=\"file\"; filename=\"{filename}\"\r\n\
+             Content-Type: text/csv\r\n\r\n\
+             {contents}\r\n\
+             --BOUNDARY--\r\n"
+        );
+        call(
+            Request::builder()
+                .method("POST")
+                .uri("/api/imports")
+                .header("content-type", "multipart/form-data; boundary=BOUNDARY")
+                .body(Body::from(body))
+                .unwrap(),
+        )
+    };
+    let send = |method: &str, uri: String| {
+        call(
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+
+    let (status, job) = upload(
+        "events.csv",
+        "site_id,visitor_id,timestamp,event_name,pathname,note\n\
+         test.com,v1,2024-01-15 10:00:00,pageview,/,a\n\
+         test.com,v2,2024-01-15 11:00:00,pageview,/about,b",
+    )
+    .await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert_eq!(job["status"], "validating", "{job}");
+    let id = job["id"].as_str().unwrap().to_string();
+    let job = wait_for_import(&app, &id, "validating").await;
+    assert_eq!(job["status"], "validated", "{job}");
+    assert_eq!(job["preview"]["rows"], 2);
+    assert_eq!(
+        job["preview"]["ignored_columns"],
+        serde_json::json!(["note"])
+    );
+    let count = |sql: &str| -> i64 {
+        state
+            .buffer
+            .conn()
+            .lock()
+            .query_row(sql, [], |row| row.get(0))
+            .unwrap()
+    };
+    // Nothing is inserted before confirming.
+    assert_eq!(count("SELECT COUNT(*) FROM events"), 0);
+
+    let (status, job) = send("POST", format!("/api/imports/{id}/confirm")).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert_eq!(job["status"], "importing");
+    let job = wait_for_import(&app, &id, "importing").await;
+    assert_eq!(job["status"], "completed", "{job}");
+    assert_eq!(job["imported"], 2);
+    assert_eq!(
+        count("SELECT COUNT(*) FROM events WHERE site_id = 'test.com'"),
+        2
+    );
+    let (status, _) = send("POST", format!("/api/imports/{id}/confirm")).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    // Missing required columns: reported, and cannot be confirmed.
+    let (status, job) = upload("events.csv", "site_id,pathname\ntest.com,/").await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let id = job["id"].as_str().unwrap().to_string();
+    let job = wait_for_import(&app, &id, "validating").await;
+    assert_eq!(job["status"], "invalid");
+    assert!(job["preview"]["errors"]
+        .as_array()
+        .unwrap()
+        .contains(&serde_json::json!("Missing required column: visitor_id")));
+    let (status, _) = send("POST", format!("/api/imports/{id}/confirm")).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    let (status, _) = upload("events.xlsx", "x").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = send("GET", "/api/imports/missing".to_string()).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_rate_limiting() {
+    // Create state with rate limit of 2 per second
+    let (mut state, _dir) = make_test_state();
+    Arc::get_mut(&mut state).unwrap().rate_limiter =
+        mallard_metrics::ingest::ratelimit::RateLimiter::new(2);
+
+    let payload = serde_json::json!({
+        "d": "example.com",
+        "n": "pageview",
+        "u": "https://example.com/",
+    });
+    let body_str = serde_json::to_string(&payload).unwrap();
+
+    // First two requests should succeed
+    for _ in 0..2 {
+        let app = build_router(Arc::clone(&state));
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/event")
+                    .header("content-type", "application/json")
+                    .body(Body::from(body_str.clone()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert_eq!(response.headers()["ratelimit-limit"], "2");
+    }
+
+    // Third request should be rate limited
+    let app = build_router(Arc::clone(&state));
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/api/event")
+                .header("content-type", "application/json")
+                .body(Body::from(body_str))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()["ratelimit-remaining"], "0");
+    assert_eq!(response.headers()["retry-after"], "1");
+}
+
+#[tokio::test]
+async fn test_detailed_health_check_with_auth() {
+    let (state, _dir) = make_test_state_with_password("admin-pass");
+    let app = build_router(state);
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/health/detailed")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["auth_configured"], true);
+}
+
+// --- New integration tests for production-readiness gaps ---
+
+/// Make a state with password AND brute-force protection enabled (max 3 attempts, 300s lockout).
+fn make_test_state_with_lockout(password: &str) -> (Arc<AppState>, tempfile::TempDir) {
     let conn = Connection::open_in_memory().unwrap();
     schema::init_schema(&conn).unwrap();
     let dir = tempfile::tempdir().unwrap();
@@ -1367,13 +3239,30 @@ fn make_test_state_with_lockout(password: &str) -> (Arc<AppState>, tempfile::Tem
         sessions: SessionStore::new(3600),
         api_keys: ApiKeyStore::default(),
         admin_password_hash: Mutex::new(Some(hash)),
+        admin_password_path: None,
+        locale: mallard_metrics::api::locale::Locale::default(),
+        branding: mallard_metrics::config::BrandingConfig::default(),
+        csp: mallard_metrics::config::CspConfig::default(),
+        security_headers: mallard_metrics::config::SecurityHeadersConfig::default(),
+        dashboard_dir: None,
         dashboard_origin: None,
+        dashboard_origins: Vec::new(),
         query_cache: mallard_metrics::query::cache::QueryCache::new(0, 0),
+        cache_warming: std::sync::atomic::AtomicBool::new(false),
+        public_summary_cache: mallard_metrics::query::cache::QueryCache::new(0, 0),
+        slow_queries: Arc::new(mallard_metrics::query::slow::SlowQueryLog::default()),
+        duckdb_usage: mallard_metrics::storage::resources::ResourceMonitor::default(),
+        shadow_queries: mallard_metrics::query::shadow::ShadowQueries::default(),
+        forwarder: None,
+        tasks: mallard_metrics::watchdog::TaskRegistry::default(),
+        drain: mallard_metrics::upgrade::Drain::default(),
         rate_limiter: mallard_metrics::ingest::ratelimit::RateLimiter::new(0),
         login_attempt_tracker: mallard_metrics::api::auth::LoginAttemptTracker::new(3, 300),
         events_ingested_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
         flush_failures_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        flush_consecutive_failures: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
         rate_limit_rejections_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        ingest_stats: mallard_metrics::ingest::stats::IngestStats::new(),
         login_failures_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
         metrics_token: None,
         query_semaphore: std::sync::Arc::new(tokio::sync::Semaphore::new(10)),
@@ -1387,222 +3276,1003 @@ fn make_test_state_with_lockout(password: &str) -> (Arc<AppState>, tempfile::Tem
         suppress_screen_size: false,
         geoip_precision: "city".to_string(),
         events_dir,
+        sites: std::collections::HashMap::new(),
+        dashboards: mallard_metrics::api::dashboards::DashboardStore::default(),
+        notifications: mallard_metrics::api::notifications::NotificationStore::default(),
+        export_jobs: mallard_metrics::api::export_jobs::ExportJobs::new(
+            dir.path().join("exports"),
+            std::time::Duration::from_secs(3600),
+        ),
+        import_jobs: mallard_metrics::api::import_jobs::ImportJobs::new(
+            dir.path().join("imports"),
+            std::time::Duration::from_secs(3600),
+        ),
+        stats_rate_limiter: mallard_metrics::ingest::ratelimit::RateLimiter::per_minute(0),
+        stats_rate_limit_rejections_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(
+            0,
+        )),
+        debug_ingest: false,
+        transforms: mallard_metrics::ingest::transform::IngestTransforms::default(),
+        ingest_queue: None,
+        spam_blocklist: Arc::new(mallard_metrics::ingest::spam::SpamBlocklist::default()),
+        geo_blocked_events_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        dedupe: mallard_metrics::ingest::dedupe::Deduplicator::new(std::time::Duration::from_secs(
+            60,
+        )),
+        max_event_time_skew: chrono::TimeDelta::hours(48),
+        idempotency: mallard_metrics::ingest::idempotency::IdempotencyKeys::new(
+            std::time::Duration::from_secs(3600),
+        ),
+        duplicate_events_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+    });
+    (state, dir)
+}
+
+#[tokio::test]
+async fn test_login_rate_limited_after_failures() {
+    let (state, _dir) = make_test_state_with_lockout("correct-password");
+
+    // Exhaust 3 attempts with wrong password
+    for _ in 0..3 {
+        let app = build_router(Arc::clone(&state));
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/auth/login")
+                    .header("content-type", "application/json")
+                    .header("x-forwarded-for", "10.0.0.5")
+                    .body(Body::from(r#"{"password":"wrong"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    // 4th attempt (even with correct password) should be 429
+    let app = build_router(Arc::clone(&state));
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/api/auth/login")
+                .header("content-type", "application/json")
+                .header("x-forwarded-for", "10.0.0.5")
+                .body(Body::from(r#"{"password":"correct-password"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn test_login_success_clears_failure_count() {
+    let (state, _dir) = make_test_state_with_lockout("my-password");
+
+    // 2 wrong attempts (below lockout threshold of 3)
+    for _ in 0..2 {
+        let app = build_router(Arc::clone(&state));
+        app.oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/api/auth/login")
+                .header("content-type", "application/json")
+                .header("x-forwarded-for", "10.0.0.6")
+                .body(Body::from(r#"{"password":"wrong"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    }
+
+    // Successful login clears the failure count
+    let app = build_router(Arc::clone(&state));
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/api/auth/login")
+                .header("content-type", "application/json")
+                .header("x-forwarded-for", "10.0.0.6")
+                .body(Body::from(r#"{"password":"my-password"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_ingest_rejects_oversized_body() {
+    let (state, _dir) = make_test_state();
+    let app = build_router(state);
+
+    // Build a payload larger than 64 KB
+    let big_props = "x".repeat(70_000);
+    let payload = format!(
+        r#"{{"d":"example.com","n":"pageview","u":"https://example.com/","p":{big_props:?}}}"#
+    );
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/api/event")
+                .header("content-type", "application/json")
+                .body(Body::from(payload))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    // Axum should reject with 413 Payload Too Large
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn test_security_headers_present() {
+    let (state, _dir) = make_test_state();
+    let app = build_router(state);
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/health")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers().get("x-content-type-options").unwrap(),
+        "nosniff"
+    );
+    assert_eq!(response.headers().get("x-frame-options").unwrap(), "DENY");
+    assert!(response.headers().contains_key("referrer-policy"));
+}
+
+#[tokio::test]
+async fn test_events_ingested_counter() {
+    let (state, _dir) = make_test_state();
+    let app = build_router(Arc::clone(&state));
+
+    let payload = serde_json::json!({
+        "d": "counter-test.com",
+        "n": "pageview",
+        "u": "https://counter-test.com/",
     });
-    (state, dir)
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/api/event")
+                .header("content-type", "application/json")
+                .body(Body::from(serde_json::to_string(&payload).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    assert_eq!(
+        state
+            .events_ingested_total
+            .load(std::sync::atomic::Ordering::Relaxed),
+        1
+    );
 }
 
 #[tokio::test]
-async fn test_login_rate_limited_after_failures() {
-    let (state, _dir) = make_test_state_with_lockout("correct-password");
+async fn test_prometheus_metrics_includes_counter() {
+    let (state, _dir) = make_test_state();
+    let app = build_router(state);
 
-    // Exhaust 3 attempts with wrong password
-    for _ in 0..3 {
-        let app = build_router(Arc::clone(&state));
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/metrics")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let text = std::str::from_utf8(&body).unwrap();
+    assert!(
+        text.contains("mallard_events_ingested_total"),
+        "Prometheus output must include events counter"
+    );
+    assert!(text.contains("mallard_duckdb_memory_bytes "));
+    assert!(text.contains("mallard_duckdb_threads "));
+}
+
+#[tokio::test]
+async fn test_api_key_scope_readonly_cannot_create_key() {
+    let (state, _dir) = make_test_state_with_password("admin-password");
+
+    // Add a ReadOnly API key
+    let ro_key = mallard_metrics::api::auth::generate_api_key();
+    state.api_keys.add_key(
+        "read-only",
+        &ro_key,
+        mallard_metrics::api::auth::ApiKeyScope::ReadOnly,
+        ApiKeyLimits::default(),
+        None,
+    );
+
+    let app = build_router(state);
+
+    // Attempt to create a new key with the ReadOnly key — should be 403
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/api/keys")
+                .header("content-type", "application/json")
+                .header("authorization", format!("Bearer {ro_key}"))
+                .body(Body::from(r#"{"name":"new-key","scope":"Admin"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_all_sites_rollup_requires_admin() {
+    let (state, _dir) = make_test_state_with_password("admin-password");
+    {
+        let conn = state.buffer.conn().lock();
+        for (site, vid) in [("a.com", "v1"), ("a.com", "v2"), ("b.com", "v3")] {
+            conn.execute(
+                "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+                 VALUES (?, ?, current_timestamp, 'pageview', '/')",
+                duckdb::params![site, vid],
+            )
+            .unwrap();
+        }
+    }
+    let ro_key = mallard_metrics::api::auth::generate_api_key();
+    state.api_keys.add_key(
+        "read-only",
+        &ro_key,
+        mallard_metrics::api::auth::ApiKeyScope::ReadOnly,
+        ApiKeyLimits::default(),
+        None,
+    );
+    let admin_key = mallard_metrics::api::auth::generate_api_key();
+    state.api_keys.add_key(
+        "admin",
+        &admin_key,
+        mallard_metrics::api::auth::ApiKeyScope::Admin,
+        ApiKeyLimits::default(),
+        None,
+    );
+    let app = build_router(Arc::clone(&state));
+
+    for uri in [
+        "/api/stats/main?site_id=__all__&period=day",
+        "/api/stats/main?site_id=%5F%5Fall%5F%5F&period=day",
+        "/api/stats/sites",
+        "/api/stats/visitor/v1?site_id=a.com",
+    ] {
         let response = app
+            .clone()
             .oneshot(
                 Request::builder()
-                    .method("POST")
-                    .uri("/api/auth/login")
-                    .header("content-type", "application/json")
-                    .header("x-forwarded-for", "10.0.0.5")
-                    .body(Body::from(r#"{"password":"wrong"}"#))
+                    .uri(uri)
+                    .header("authorization", format!("Bearer {ro_key}"))
+                    .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
-        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.status(), StatusCode::FORBIDDEN, "{uri}");
     }
 
-    // 4th attempt (even with correct password) should be 429
-    let app = build_router(Arc::clone(&state));
     let response = app
+        .clone()
         .oneshot(
             Request::builder()
-                .method("POST")
-                .uri("/api/auth/login")
-                .header("content-type", "application/json")
-                .header("x-forwarded-for", "10.0.0.5")
-                .body(Body::from(r#"{"password":"correct-password"}"#))
+                .uri("/api/stats/main?site_id=__all__&period=day")
+                .header("authorization", format!("Bearer {admin_key}"))
+                .body(Body::empty())
                 .unwrap(),
         )
         .await
         .unwrap();
-    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["unique_visitors"], 3);
+
+    // Estimates read the summary sidecars written by the flush, and match
+    // exact counts this small.
+    state.buffer.flush().unwrap();
+    for uri in [
+        "/api/stats/sites?period=day",
+        "/api/stats/sites?period=day&estimate=true",
+    ] {
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri(uri)
+                    .header("authorization", format!("Bearer {admin_key}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{uri}");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json[0]["site_id"], "a.com", "{uri}");
+        assert_eq!(json[0]["visitors"], 2, "{uri}");
+        assert_eq!(json[1]["site_id"], "b.com", "{uri}");
+    }
 }
 
 #[tokio::test]
-async fn test_login_success_clears_failure_count() {
-    let (state, _dir) = make_test_state_with_lockout("my-password");
+async fn test_validate_event_dry_run() {
+    let (state, _dir) = make_test_state_with_password("admin-password");
+    let key = mallard_metrics::api::auth::generate_api_key();
+    state.api_keys.add_key(
+        "ci",
+        &key,
+        mallard_metrics::api::auth::ApiKeyScope::ReadOnly,
+        ApiKeyLimits::default(),
+        None,
+    );
+    let app = build_router(Arc::clone(&state));
+    let payload = serde_json::json!({
+        "d": "example.com",
+        "n": "pageview",
+        "u": "https://example.com/pricing?utm_source=newsletter",
+        "w": 390,
+    });
+    let request = |key: Option<&str>| {
+        let mut builder = Request::builder()
+            .method("POST")
+            .uri("/api/event/validate")
+            .header("content-type", "application/json")
+            .header("user-agent", "Mozilla/5.0 Chrome/120.0");
+        if let Some(key) = key {
+            builder = builder.header("x-api-key", key);
+        }
+        builder.body(Body::from(payload.to_string())).unwrap()
+    };
 
-    // 2 wrong attempts (below lockout threshold of 3)
+    let response = app.clone().oneshot(request(None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = app.oneshot(request(Some(&key))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["event"]["pathname"], "/pricing");
+    assert_eq!(json["event"]["utm_source"], "newsletter");
+    assert_eq!(json["event"]["device_type"], "mobile");
+    assert_eq!(json["would_store"], true);
+    assert!(state.buffer.is_empty());
+}
+
+#[tokio::test]
+async fn test_validation_errors_are_structured() {
+    let (mut state, _dir) = make_test_state();
+    let app = build_router(Arc::clone(&state));
+    let post_event = |payload: serde_json::Value| {
+        Request::builder()
+            .method("POST")
+            .uri("/api/event")
+            .header("content-type", "application/json")
+            .body(Body::from(payload.to_string()))
+            .unwrap()
+    };
+    let bad_event = serde_json::json!({"d": "my site.com", "n": "", "u": "/"});
+
+    let response = app
+        .clone()
+        .oneshot(post_event(bad_event.clone()))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["code"], "invalid_field");
+    assert_eq!(json["errors"][0]["field"], "n");
+    assert_eq!(json["errors"][1]["field"], "d");
+    assert!(json.get("parsed").is_none());
+
+    // A missing required field names the field.
+    let response = app
+        .clone()
+        .oneshot(post_event(
+            serde_json::json!({"d": "example.com", "u": "/"}),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["errors"][0]["field"], "n");
+
+    // Stats parameters report the offending field.
+    for (uri, field) in [
+        ("/api/stats/main?period=30d", "site_id"),
+        ("/api/stats/main?site_id=test.com&period=1y", "period"),
+    ] {
+        let response = app
+            .clone()
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{uri}");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["errors"][0]["field"], field, "{uri}");
+    }
+
+    drop(app);
+    Arc::get_mut(&mut state).unwrap().debug_ingest = true;
+    let app = build_router(state);
+    let response = app.oneshot(post_event(bad_event)).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["parsed"]["d"], "my site.com");
+}
+
+#[tokio::test]
+async fn test_stats_rate_limit_per_api_key() {
+    let (mut state, _dir) = make_test_state_with_password("admin-password");
+    Arc::get_mut(&mut state).unwrap().stats_rate_limiter =
+        mallard_metrics::ingest::ratelimit::RateLimiter::per_minute(2);
+    let key_a = mallard_metrics::api::auth::generate_api_key();
+    let key_b = mallard_metrics::api::auth::generate_api_key();
+    state.api_keys.add_key(
+        "a",
+        &key_a,
+        mallard_metrics::api::auth::ApiKeyScope::ReadOnly,
+        ApiKeyLimits::default(),
+        None,
+    );
+    state.api_keys.add_key(
+        "b",
+        &key_b,
+        mallard_metrics::api::auth::ApiKeyScope::ReadOnly,
+        ApiKeyLimits::default(),
+        None,
+    );
+    let app = build_router(Arc::clone(&state));
+
+    let request = |key: &str| {
+        Request::builder()
+            .uri("/api/stats/main?site_id=test.com&period=30d")
+            .header("authorization", format!("Bearer {key}"))
+            .body(Body::empty())
+            .unwrap()
+    };
     for _ in 0..2 {
-        let app = build_router(Arc::clone(&state));
-        app.oneshot(
-            Request::builder()
-                .method("POST")
-                .uri("/api/auth/login")
-                .header("content-type", "application/json")
-                .header("x-forwarded-for", "10.0.0.6")
-                .body(Body::from(r#"{"password":"wrong"}"#))
-                .unwrap(),
+        let response = app.clone().oneshot(request(&key_a)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let response = app.clone().oneshot(request(&key_a)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()["retry-after"], "30");
+
+    // Another key has its own budget.
+    let response = app.clone().oneshot(request(&key_b)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        state
+            .stats_rate_limit_rejections_total
+            .load(std::sync::atomic::Ordering::Relaxed),
+        1
+    );
+}
+
+#[tokio::test]
+async fn test_group_limited_api_key() {
+    let (mut state, _dir) = make_test_state_with_password("admin-password");
+    {
+        let state = Arc::get_mut(&mut state).unwrap();
+        for (site, group) in [("a.com", "acme"), ("b.com", "acme"), ("c.com", "other")] {
+            state.sites.insert(
+                site.to_string(),
+                mallard_metrics::config::SiteConfig {
+                    custom_dimensions: vec![],
+                    group: Some(group.to_string()),
+                    ..mallard_metrics::config::SiteConfig::default()
+                },
+            );
+        }
+        let conn = state.buffer.conn().lock();
+        schema::sync_site_groups(
+            &conn,
+            [("a.com", "acme"), ("b.com", "acme"), ("c.com", "other")],
         )
-        .await
         .unwrap();
+        for (site, vid) in [("a.com", "v1"), ("b.com", "v2"), ("c.com", "v3")] {
+            conn.execute(
+                "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+                 VALUES (?, ?, current_timestamp, 'pageview', '/')",
+                duckdb::params![site, vid],
+            )
+            .unwrap();
+        }
     }
+    let key = mallard_metrics::api::auth::generate_api_key();
+    state.api_keys.add_key(
+        "acme-client",
+        &key,
+        mallard_metrics::api::auth::ApiKeyScope::ReadOnly,
+        ApiKeyLimits {
+            group: Some("acme"),
+            ..ApiKeyLimits::default()
+        },
+        None,
+    );
+    let app = build_router(state);
 
-    // Successful login clears the failure count
-    let app = build_router(Arc::clone(&state));
-    let response = app
-        .oneshot(
-            Request::builder()
-                .method("POST")
-                .uri("/api/auth/login")
-                .header("content-type", "application/json")
-                .header("x-forwarded-for", "10.0.0.6")
-                .body(Body::from(r#"{"password":"my-password"}"#))
-                .unwrap(),
-        )
-        .await
-        .unwrap();
-    assert_eq!(response.status(), StatusCode::OK);
+    for (uri, expected) in [
+        ("/api/stats/main?site_id=a.com&period=day", StatusCode::OK),
+        ("/api/stats/main?site_id=@acme&period=day", StatusCode::OK),
+        (
+            "/api/stats/main?site_id=c.com&period=day",
+            StatusCode::FORBIDDEN,
+        ),
+        (
+            "/api/stats/main?site_id=@other&period=day",
+            StatusCode::FORBIDDEN,
+        ),
+        (
+            "/api/stats/main?site_id=unknown.com&period=day",
+            StatusCode::FORBIDDEN,
+        ),
+    ] {
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri(uri)
+                    .header("x-api-key", &key)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), expected, "{uri}");
+        if uri.contains("@acme") {
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(json["unique_visitors"], 2);
+        }
+    }
 }
 
 #[tokio::test]
-async fn test_ingest_rejects_oversized_body() {
-    let (state, _dir) = make_test_state();
+async fn test_dashboards_hidden_from_limited_key() {
+    let (mut state, _dir) = make_test_state_with_password("admin-password");
+    {
+        let state = Arc::get_mut(&mut state).unwrap();
+        for (site, group) in [("a.com", "acme"), ("c.com", "other")] {
+            state.sites.insert(
+                site.to_string(),
+                mallard_metrics::config::SiteConfig {
+                    group: Some(group.to_string()),
+                    ..mallard_metrics::config::SiteConfig::default()
+                },
+            );
+        }
+    }
+    let widget = |site_id: &str| mallard_metrics::api::dashboards::Widget {
+        metric: "visitors".to_string(),
+        site_id: site_id.to_string(),
+        period: "30d".to_string(),
+        breakdown: None,
+    };
+    let acme = state
+        .dashboards
+        .create("Acme".to_string(), vec![widget("a.com"), widget("@acme")])
+        .unwrap();
+    let mixed = state
+        .dashboards
+        .create("Mixed".to_string(), vec![widget("a.com"), widget("c.com")])
+        .unwrap();
+    let token = state.sessions.create_session("admin", "127.0.0.1", None);
+    let key = mallard_metrics::api::auth::generate_api_key();
+    state.api_keys.add_key(
+        "acme-client",
+        &key,
+        mallard_metrics::api::auth::ApiKeyScope::ReadOnly,
+        ApiKeyLimits {
+            group: Some("acme"),
+            ..ApiKeyLimits::default()
+        },
+        None,
+    );
     let app = build_router(state);
 
-    // Build a payload larger than 64 KB
-    let big_props = "x".repeat(70_000);
-    let payload = format!(
-        r#"{{"d":"example.com","n":"pageview","u":"https://example.com/","p":{big_props:?}}}"#
-    );
+    let request = |uri: String, header: (&str, String)| {
+        Request::builder()
+            .uri(uri)
+            .header(header.0, header.1)
+            .body(Body::empty())
+            .unwrap()
+    };
+    let list = |header: (&'static str, String)| {
+        let app = app.clone();
+        async move {
+            let response = app
+                .oneshot(request("/api/dashboards".to_string(), header))
+                .await
+                .unwrap();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            json.as_array()
+                .unwrap()
+                .iter()
+                .map(|d| d["name"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        }
+    };
 
-    let response = app
-        .oneshot(
-            Request::builder()
-                .method("POST")
-                .uri("/api/event")
-                .header("content-type", "application/json")
-                .body(Body::from(payload))
-                .unwrap(),
-        )
-        .await
-        .unwrap();
+    assert_eq!(list(("x-api-key", key.clone())).await, ["Acme"]);
+    assert_eq!(
+        list(("cookie", format!("mm_session={token}"))).await,
+        ["Acme", "Mixed"]
+    );
 
-    // Axum should reject with 413 Payload Too Large
-    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    for (id, expected) in [
+        (&acme.id, StatusCode::OK),
+        (&mixed.id, StatusCode::NOT_FOUND),
+    ] {
+        let response = app
+            .clone()
+            .oneshot(request(
+                format!("/api/dashboards/{id}"),
+                ("x-api-key", key.clone()),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), expected);
+    }
 }
 
 #[tokio::test]
-async fn test_security_headers_present() {
-    let (state, _dir) = make_test_state();
+async fn test_tenant_limited_api_key() {
+    let (mut state, _dir) = make_test_state_with_password("admin-password");
+    {
+        let state = Arc::get_mut(&mut state).unwrap();
+        for (site, tenant, group) in [
+            ("a.com", Some("acme"), "mixed"),
+            ("b.com", Some("acme"), "acme-only"),
+            ("c.com", None, "mixed"),
+        ] {
+            state.sites.insert(
+                site.to_string(),
+                mallard_metrics::config::SiteConfig {
+                    tenant: tenant.map(str::to_string),
+                    group: Some(group.to_string()),
+                    ..mallard_metrics::config::SiteConfig::default()
+                },
+            );
+        }
+    }
+    let token = state.sessions.create_session("admin", "127.0.0.1", None);
+    let key = mallard_metrics::api::auth::generate_api_key();
+    state.api_keys.add_key(
+        "acme",
+        &key,
+        mallard_metrics::api::auth::ApiKeyScope::ReadOnly,
+        ApiKeyLimits {
+            tenant: Some("acme"),
+            ..ApiKeyLimits::default()
+        },
+        None,
+    );
     let app = build_router(state);
 
-    let response = app
-        .oneshot(
-            Request::builder()
-                .uri("/health")
-                .body(Body::empty())
-                .unwrap(),
-        )
-        .await
-        .unwrap();
+    for (uri, expected) in [
+        ("/api/stats/main?site_id=a.com&period=day", StatusCode::OK),
+        (
+            "/api/stats/main?site_id=@acme-only&period=day",
+            StatusCode::OK,
+        ),
+        (
+            "/api/stats/main?site_id=c.com&period=day",
+            StatusCode::FORBIDDEN,
+        ),
+        (
+            "/api/stats/main?site_id=@mixed&period=day",
+            StatusCode::FORBIDDEN,
+        ),
+        (
+            "/api/stats/main?site_id=__all__&period=day",
+            StatusCode::FORBIDDEN,
+        ),
+    ] {
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri(uri)
+                    .header("x-api-key", &key)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), expected, "{uri}");
+    }
 
-    assert_eq!(response.status(), StatusCode::OK);
-    assert_eq!(
-        response.headers().get("x-content-type-options").unwrap(),
-        "nosniff"
-    );
-    assert_eq!(response.headers().get("x-frame-options").unwrap(), "DENY");
-    assert!(response.headers().contains_key("referrer-policy"));
+    // Only read-only and write keys can be limited, and only to a tenant
+    // some site belongs to.
+    for (scope, tenant, expected) in [
+        ("ReadOnly", "acme", StatusCode::CREATED),
+        ("Admin", "acme", StatusCode::BAD_REQUEST),
+        ("ReadOnly", "nobody", StatusCode::BAD_REQUEST),
+    ] {
+        let payload = serde_json::json!({ "name": "k", "scope": scope, "tenant": tenant });
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/keys")
+                    .header("content-type", "application/json")
+                    .header("cookie", format!("mm_session={token}"))
+                    .body(Body::from(serde_json::to_string(&payload).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), expected, "{scope} {tenant}");
+        if expected == StatusCode::CREATED {
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(json["tenant"], "acme");
+        }
+    }
 }
 
 #[tokio::test]
-async fn test_events_ingested_counter() {
-    let (state, _dir) = make_test_state();
+async fn test_site_requiring_ingest_key() {
+    let (mut state, _dir) = make_test_state_with_password("admin-password");
+    for site in ["backend.com", "other.com"] {
+        Arc::get_mut(&mut state).unwrap().sites.insert(
+            site.to_string(),
+            mallard_metrics::config::SiteConfig {
+                require_ingest_key: true,
+                ..mallard_metrics::config::SiteConfig::default()
+            },
+        );
+    }
+    let (site_key, other_key, read_key) = (
+        mallard_metrics::api::auth::generate_api_key(),
+        mallard_metrics::api::auth::generate_api_key(),
+        mallard_metrics::api::auth::generate_api_key(),
+    );
+    state.api_keys.add_key(
+        "backend",
+        &site_key,
+        mallard_metrics::api::auth::ApiKeyScope::WriteEvents,
+        ApiKeyLimits {
+            site_id: Some("backend.com"),
+            ..ApiKeyLimits::default()
+        },
+        None,
+    );
+    state.api_keys.add_key(
+        "other",
+        &other_key,
+        mallard_metrics::api::auth::ApiKeyScope::WriteEvents,
+        ApiKeyLimits {
+            site_id: Some("other.com"),
+            ..ApiKeyLimits::default()
+        },
+        None,
+    );
+    state.api_keys.add_key(
+        "reader",
+        &read_key,
+        mallard_metrics::api::auth::ApiKeyScope::ReadOnly,
+        ApiKeyLimits::default(),
+        None,
+    );
     let app = build_router(Arc::clone(&state));
 
     let payload = serde_json::json!({
-        "d": "counter-test.com",
+        "d": "backend.com",
         "n": "pageview",
-        "u": "https://counter-test.com/",
+        "u": "https://backend.com/checkout"
     });
+    let event = |key: Option<&str>| {
+        let mut request = Request::builder()
+            .method("POST")
+            .uri("/api/event")
+            .header("content-type", "application/json");
+        if let Some(key) = key {
+            request = request.header("authorization", format!("Bearer {key}"));
+        }
+        request
+            .body(Body::from(serde_json::to_string(&payload).unwrap()))
+            .unwrap()
+    };
+    for (key, expected) in [
+        (None, StatusCode::UNAUTHORIZED),
+        (Some(other_key.as_str()), StatusCode::UNAUTHORIZED),
+        (Some(read_key.as_str()), StatusCode::UNAUTHORIZED),
+        (Some(site_key.as_str()), StatusCode::ACCEPTED),
+    ] {
+        let response = app.clone().oneshot(event(key)).await.unwrap();
+        assert_eq!(response.status(), expected, "{key:?}");
+    }
+    assert_eq!(state.buffer.len(), 1);
 
+    // Write keys cannot read stats.
     let response = app
         .oneshot(
             Request::builder()
-                .method("POST")
-                .uri("/api/event")
-                .header("content-type", "application/json")
-                .body(Body::from(serde_json::to_string(&payload).unwrap()))
+                .uri("/api/stats/main?site_id=backend.com&period=day")
+                .header("x-api-key", &site_key)
+                .body(Body::empty())
                 .unwrap(),
         )
         .await
         .unwrap();
-
-    assert_eq!(response.status(), StatusCode::ACCEPTED);
-    assert_eq!(
-        state
-            .events_ingested_total
-            .load(std::sync::atomic::Ordering::Relaxed),
-        1
-    );
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
 }
 
 #[tokio::test]
-async fn test_prometheus_metrics_includes_counter() {
-    let (state, _dir) = make_test_state();
-    let app = build_router(state);
+async fn test_public_summary() {
+    let (mut state, _dir) = make_test_state_with_password("admin-password");
+    {
+        let state = Arc::get_mut(&mut state).unwrap();
+        state.sites.insert(
+            "embed.com".to_string(),
+            mallard_metrics::config::SiteConfig {
+                public_summary: true,
+                ..mallard_metrics::config::SiteConfig::default()
+            },
+        );
+        state.sites.insert(
+            "private.com".to_string(),
+            mallard_metrics::config::SiteConfig::default(),
+        );
+        state.public_summary_cache = mallard_metrics::query::cache::QueryCache::new(3600, 0);
+    }
+    {
+        let conn = state.buffer.conn().lock();
+        for (site_id, visitor_id, timestamp) in [
+            ("embed.com", "v1", "current_timestamp"),
+            ("embed.com", "v2", "current_timestamp"),
+            ("embed.com", "v1", "TIMESTAMP '2020-01-15 10:00:00'"),
+            ("private.com", "v3", "current_timestamp"),
+        ] {
+            conn.execute(
+                &format!(
+                    "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+                     VALUES (?, ?, {timestamp}, 'pageview', '/')"
+                ),
+                duckdb::params![site_id, visitor_id],
+            )
+            .unwrap();
+        }
+        drop(conn);
+    }
+    let app = build_router(Arc::clone(&state));
+    let get = |uri: &str| {
+        let app = app.clone();
+        let request = Request::builder()
+            .uri(uri)
+            .header("origin", "https://blog.example.org")
+            .body(Body::empty())
+            .unwrap();
+        async move { app.oneshot(request).await.unwrap() }
+    };
 
-    let response = app
-        .oneshot(
-            Request::builder()
-                .uri("/metrics")
-                .body(Body::empty())
-                .unwrap(),
+    // No credentials needed, readable from any origin, and cacheable.
+    let response = get("/api/public/summary?site_id=embed.com").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let headers = response.headers();
+    assert_eq!(headers["access-control-allow-origin"], "*");
+    assert_eq!(headers["cache-control"], "public, max-age=3600");
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let summary: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(summary["site_id"], "embed.com");
+    assert_eq!(summary["visitors_this_month"], 2);
+    assert_eq!(summary["total_pageviews"], 3);
+    assert_eq!(
+        summary["month"],
+        chrono::Utc::now().format("%Y-%m").to_string()
+    );
+
+    // Served from the cache until it expires.
+    {
+        let conn = state.buffer.conn().lock();
+        conn.execute(
+            "INSERT INTO events (site_id, visitor_id, timestamp, event_name, pathname)
+             VALUES ('embed.com', 'v4', current_timestamp, 'pageview', '/')",
+            [],
         )
-        .await
         .unwrap();
-
+    }
+    let response = get("/api/public/summary?site_id=embed.com&callback=MallardEmbed.render").await;
     assert_eq!(response.status(), StatusCode::OK);
-    let body = response.into_body().collect().await.unwrap().to_bytes();
-    let text = std::str::from_utf8(&body).unwrap();
-    assert!(
-        text.contains("mallard_events_ingested_total"),
-        "Prometheus output must include events counter"
+    assert_eq!(
+        response.headers()["content-type"],
+        "application/javascript; charset=utf-8"
     );
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let script = String::from_utf8(body.to_vec()).unwrap();
+    assert!(script.starts_with("/**/MallardEmbed.render({"), "{script}");
+    assert!(script.ends_with("});"), "{script}");
+    assert!(script.contains("\"visitors_this_month\":2"), "{script}");
+
+    let response = get("/api/public/summary?site_id=embed.com&callback=alert(1)").await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // Sites without `public_summary`, and unknown sites, are not found.
+    for site_id in ["private.com", "unknown.com"] {
+        let response = get(&format!("/api/public/summary?site_id={site_id}")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    // The full stats API stays protected.
+    let response = get("/api/stats/main?site_id=embed.com").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
 }
 
 #[tokio::test]
-async fn test_api_key_scope_readonly_cannot_create_key() {
-    let (state, _dir) = make_test_state_with_password("admin-password");
-
-    // Add a ReadOnly API key
-    let ro_key = mallard_metrics::api::auth::generate_api_key();
-    state.api_keys.add_key(
-        "read-only",
-        &ro_key,
-        mallard_metrics::api::auth::ApiKeyScope::ReadOnly,
-    );
+async fn test_event_bus_ingest() {
+    use mallard_metrics::ingest::bus;
 
-    let app = build_router(state);
+    let (state, _dir) = make_test_state();
+    for message in [
+        r#"{"d": "bus.com", "n": "pageview", "u": "https://bus.com/", "eid": "e1",
+            "ip": "203.0.113.7", "ua": "Mozilla/5.0 Chrome/120.0"}"#,
+        // A redelivery of the same event.
+        r#"{"d": "bus.com", "n": "pageview", "u": "https://bus.com/", "eid": "e1",
+            "ip": "203.0.113.7", "ua": "Mozilla/5.0 Chrome/120.0"}"#,
+        r#"{"d": "bus.com", "n": "signup", "u": "https://bus.com/join",
+            "ip": "198.51.100.4", "ua": "Mozilla/5.0 Firefox/121.0"}"#,
+        // Invalid messages are skipped.
+        r#"{"d": "bus.com", "n": "", "u": "/"}"#,
+        "not json",
+    ] {
+        bus::ingest(&state, message.as_bytes()).await;
+    }
+    assert_eq!(state.buffer.len(), 2);
 
-    // Attempt to create a new key with the ReadOnly key — should be 403
-    let response = app
-        .oneshot(
-            Request::builder()
-                .method("POST")
-                .uri("/api/keys")
-                .header("content-type", "application/json")
-                .header("authorization", format!("Bearer {ro_key}"))
-                .body(Body::from(r#"{"name":"new-key","scope":"Admin"}"#))
-                .unwrap(),
+    state.buffer.flush().unwrap();
+    let conn = state.buffer.conn().lock();
+    let (events, visitors): (i64, i64) = conn
+        .query_row(
+            "SELECT COUNT(*), COUNT(DISTINCT visitor_id) FROM events_all WHERE site_id = 'bus.com'",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
         )
-        .await
         .unwrap();
-
-    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(events, 2);
+    assert_eq!(visitors, 2);
+    let pathname: String = conn
+        .query_row(
+            "SELECT pathname FROM events_all WHERE event_name = 'signup'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    drop(conn);
+    assert_eq!(pathname, "/join");
 }
 
 #[tokio::test]
@@ -1615,6 +4285,8 @@ async fn test_api_key_scope_admin_can_create_key() {
         "admin",
         &admin_key,
         mallard_metrics::api::auth::ApiKeyScope::Admin,
+        ApiKeyLimits::default(),
+        None,
     );
 
     let app = build_router(state);
@@ -1646,6 +4318,8 @@ async fn test_x_api_key_header_authentication() {
         "test-key",
         &key,
         mallard_metrics::api::auth::ApiKeyScope::Admin,
+        ApiKeyLimits::default(),
+        None,
     );
 
     let app = build_router(state);
@@ -1860,6 +4534,48 @@ async fn test_security_headers_on_html_response() {
     );
 }
 
+#[tokio::test]
+async fn test_branding_is_public_and_allows_logo_origin() {
+    let (mut state, _dir) = make_test_state();
+    Arc::get_mut(&mut state).unwrap().branding = mallard_metrics::config::BrandingConfig {
+        product_name: Some("Acme Analytics".to_string()),
+        logo_url: Some("https://cdn.acme.example/logo.svg".to_string()),
+        accent_color: Some("#e4572e".to_string()),
+        secondary_color: None,
+    };
+    *state.admin_password_hash.lock() = Some("not-a-real-hash".to_string());
+
+    let app = build_router(Arc::clone(&state));
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/api/meta/branding")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["product_name"], "Acme Analytics");
+    assert_eq!(json["accent_color"], "#e4572e");
+    assert!(json["secondary_color"].is_null());
+
+    let app = build_router(Arc::clone(&state));
+    let response = app
+        .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let csp = response.headers()["content-security-policy"]
+        .to_str()
+        .unwrap();
+    assert!(
+        csp.ends_with("img-src 'self' https://cdn.acme.example"),
+        "{csp}"
+    );
+}
+
 #[tokio::test]
 async fn test_csrf_blocks_session_auth_key_creation() {
     // When dashboard_origin is configured, a session-authenticated POST /api/keys
@@ -1881,13 +4597,30 @@ async fn test_csrf_blocks_session_auth_key_creation() {
         sessions: SessionStore::new(3600),
         api_keys: ApiKeyStore::default(),
         admin_password_hash: Mutex::new(Some(hash)),
+        admin_password_path: None,
+        locale: mallard_metrics::api::locale::Locale::default(),
+        branding: mallard_metrics::config::BrandingConfig::default(),
+        csp: mallard_metrics::config::CspConfig::default(),
+        security_headers: mallard_metrics::config::SecurityHeadersConfig::default(),
+        dashboard_dir: None,
         dashboard_origin: Some("https://analytics.example.com".to_string()),
+        dashboard_origins: Vec::new(),
         query_cache: mallard_metrics::query::cache::QueryCache::new(0, 0),
+        cache_warming: std::sync::atomic::AtomicBool::new(false),
+        public_summary_cache: mallard_metrics::query::cache::QueryCache::new(0, 0),
+        slow_queries: Arc::new(mallard_metrics::query::slow::SlowQueryLog::default()),
+        duckdb_usage: mallard_metrics::storage::resources::ResourceMonitor::default(),
+        shadow_queries: mallard_metrics::query::shadow::ShadowQueries::default(),
+        forwarder: None,
+        tasks: mallard_metrics::watchdog::TaskRegistry::default(),
+        drain: mallard_metrics::upgrade::Drain::default(),
         rate_limiter: mallard_metrics::ingest::ratelimit::RateLimiter::new(0),
         login_attempt_tracker: mallard_metrics::api::auth::LoginAttemptTracker::new(0, 300),
         events_ingested_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
         flush_failures_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        flush_consecutive_failures: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
         rate_limit_rejections_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        ingest_stats: mallard_metrics::ingest::stats::IngestStats::new(),
         login_failures_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
         metrics_token: None,
         query_semaphore: std::sync::Arc::new(tokio::sync::Semaphore::new(10)),
@@ -1901,10 +4634,38 @@ async fn test_csrf_blocks_session_auth_key_creation() {
         suppress_screen_size: false,
         geoip_precision: "city".to_string(),
         events_dir: dir.path().to_path_buf(),
+        sites: std::collections::HashMap::new(),
+        dashboards: mallard_metrics::api::dashboards::DashboardStore::default(),
+        notifications: mallard_metrics::api::notifications::NotificationStore::default(),
+        export_jobs: mallard_metrics::api::export_jobs::ExportJobs::new(
+            dir.path().join("exports"),
+            std::time::Duration::from_secs(3600),
+        ),
+        import_jobs: mallard_metrics::api::import_jobs::ImportJobs::new(
+            dir.path().join("imports"),
+            std::time::Duration::from_secs(3600),
+        ),
+        stats_rate_limiter: mallard_metrics::ingest::ratelimit::RateLimiter::per_minute(0),
+        stats_rate_limit_rejections_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(
+            0,
+        )),
+        debug_ingest: false,
+        transforms: mallard_metrics::ingest::transform::IngestTransforms::default(),
+        ingest_queue: None,
+        spam_blocklist: Arc::new(mallard_metrics::ingest::spam::SpamBlocklist::default()),
+        geo_blocked_events_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        dedupe: mallard_metrics::ingest::dedupe::Deduplicator::new(std::time::Duration::from_secs(
+            60,
+        )),
+        max_event_time_skew: chrono::TimeDelta::hours(48),
+        idempotency: mallard_metrics::ingest::idempotency::IdempotencyKeys::new(
+            std::time::Duration::from_secs(3600),
+        ),
+        duplicate_events_total: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
     });
 
     // Create a valid session directly (bypasses login)
-    let token = state.sessions.create_session("admin");
+    let token = state.sessions.create_session("admin", "127.0.0.1", None);
 
     let app = build_router(Arc::clone(&state));
 
@@ -1936,6 +4697,8 @@ async fn test_x_api_key_readonly_blocks_key_management() {
         "readonly-via-header",
         &ro_key,
         mallard_metrics::api::auth::ApiKeyScope::ReadOnly,
+        ApiKeyLimits::default(),
+        None,
     );
 
     let app = build_router(state);
@@ -2064,7 +4827,7 @@ async fn test_gdpr_erase_requires_auth() {
 async fn test_gdpr_erase_returns_ok_for_empty_date_range() {
     // Authenticated erase over a date range with no data should return 200 with 0 counts.
     let (state, _dir) = make_test_state_with_password("admin-password");
-    let token = state.sessions.create_session("admin");
+    let token = state.sessions.create_session("admin", "127.0.0.1", None);
     let app = build_router(state);
 
     let response = app
@@ -2094,7 +4857,7 @@ async fn test_gdpr_erase_returns_ok_for_empty_date_range() {
 async fn test_gdpr_erase_deletes_hot_events() {
     // Insert events into the hot DuckDB table, then erase them via the endpoint.
     let (state, _dir) = make_test_state_with_password("admin-password");
-    let token = state.sessions.create_session("admin");
+    let token = state.sessions.create_session("admin", "127.0.0.1", None);
     let app = build_router(Arc::clone(&state));
 
     // Ingest two events for the target site on today's date.
@@ -2140,7 +4903,7 @@ async fn test_gdpr_erase_deletes_hot_events() {
 async fn test_gdpr_erase_deletes_within_366_days() {
     // Insert events and erase within a valid date range covering today.
     let (state, _dir) = make_test_state_with_password("admin-password");
-    let token = state.sessions.create_session("admin");
+    let token = state.sessions.create_session("admin", "127.0.0.1", None);
     let app = build_router(Arc::clone(&state));
 
     // Ingest two events for the target site.
@@ -2187,7 +4950,7 @@ async fn test_gdpr_erase_deletes_within_366_days() {
 #[tokio::test]
 async fn test_gdpr_erase_rejects_invalid_site_id() {
     let (state, _dir) = make_test_state_with_password("admin-password");
-    let token = state.sessions.create_session("admin");
+    let token = state.sessions.create_session("admin", "127.0.0.1", None);
     let app = build_router(state);
 
     let response = app
@@ -2208,7 +4971,7 @@ async fn test_gdpr_erase_rejects_invalid_site_id() {
 #[tokio::test]
 async fn test_gdpr_erase_rejects_invalid_dates() {
     let (state, _dir) = make_test_state_with_password("admin-password");
-    let token = state.sessions.create_session("admin");
+    let token = state.sessions.create_session("admin", "127.0.0.1", None);
     let app = build_router(state);
 
     let response = app
@@ -2229,7 +4992,7 @@ async fn test_gdpr_erase_rejects_invalid_dates() {
 #[tokio::test]
 async fn test_gdpr_erase_rejects_end_before_start() {
     let (state, _dir) = make_test_state_with_password("admin-password");
-    let token = state.sessions.create_session("admin");
+    let token = state.sessions.create_session("admin", "127.0.0.1", None);
     let app = build_router(state);
 
     let response = app
@@ -2250,7 +5013,7 @@ async fn test_gdpr_erase_rejects_end_before_start() {
 #[tokio::test]
 async fn test_gdpr_erase_rejects_range_over_366_days() {
     let (state, _dir) = make_test_state_with_password("admin-password");
-    let token = state.sessions.create_session("admin");
+    let token = state.sessions.create_session("admin", "127.0.0.1", None);
     let app = build_router(state);
 
     let response = app
@@ -2278,6 +5041,8 @@ async fn test_gdpr_erase_accessible_via_admin_api_key() {
         "admin-key",
         &key,
         mallard_metrics::api::auth::ApiKeyScope::Admin,
+        ApiKeyLimits::default(),
+        None,
     );
     let app = build_router(state);
 
@@ -8,12 +8,17 @@ w:w.innerWidth};
 if(o.props)b.p=JSON.stringify(o.props);
 if(o.revenue!=null)b.ra=o.revenue;
 if(o.currency)b.rc=o.currency;
+if(o.eid)b.eid=o.eid;
+if(o.pt)b.pt=o.pt;
+if(o.uid)b.uid=o.uid;
 var x=new XMLHttpRequest();x.open('POST',u,true);
 x.setRequestHeader('Content-Type','application/json');
 x.send(JSON.stringify(b));if(o.callback)x.onload=o.callback}
-function p(){t('pageview')}
+var v;function p(){t('pageview',v?{pt:v}:0);v=w.location.href}
 w.mallard=t;
 var h=w.history;if(h.pushState){var o=h.pushState;
 h.pushState=function(){o.apply(this,arguments);p()};
 w.addEventListener('popstate',p)}
-p()})();
+p();
+var hb=+l.getAttribute('data-heartbeat');
+if(hb>0)setInterval(function(){if(d.visibilityState==='visible')t('heartbeat')},Math.max(hb,10)*1000)})();